@@ -0,0 +1,124 @@
+//! Per-key backlight.
+//!
+//! Some boards have an RGB LED under every key, on the same strip as the status LEDs.  The board
+//! info says where they are (see [`Backlight`]), and in the modes with layers, each key is lit
+//! with a color for what it does in the layer in use, so the layer can be read off the board.
+//! What each key does is given as a [`KeyClass`], by the layout (see
+//! [`crate::layout::LayoutActions::set_key_classes`]).
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use minicbor::{Decode, Encode};
+
+use crate::RGB8;
+
+/// Where the backlight LEDs are.  They are a run of LEDs along the strip, each under a single key.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+pub struct Backlight {
+    /// The index of the first backlight LED.  The status LEDs come before it.
+    #[n(0)]
+    pub first_led: u8,
+    /// The scan code of the key under each backlight LED, in order along the strip.
+    #[n(1)]
+    pub keys: Vec<u8>,
+}
+
+/// What a key does, for choosing its backlight color.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyClass {
+    /// Does nothing.
+    None,
+    Letter,
+    Digit,
+    /// Punctuation, and other symbols.
+    Symbol,
+    /// Space, enter, tab, escape, and the like.
+    Whitespace,
+    Modifier,
+    /// Arrows, and the keys that move by pages.
+    Navigation,
+    Function,
+    Mouse,
+    /// Changes layers.
+    Layer,
+    /// Anything else.
+    Other,
+}
+
+impl KeyClass {
+    /// The backlight color for this kind of key.
+    pub fn color(self) -> RGB8 {
+        match self {
+            KeyClass::None => RGB8::new(0, 0, 0),
+            KeyClass::Letter => RGB8::new(8, 8, 8),
+            KeyClass::Digit => RGB8::new(16, 8, 0),
+            KeyClass::Symbol => RGB8::new(8, 0, 16),
+            KeyClass::Whitespace => RGB8::new(4, 4, 4),
+            KeyClass::Modifier => RGB8::new(0, 16, 0),
+            KeyClass::Navigation => RGB8::new(0, 8, 16),
+            KeyClass::Function => RGB8::new(16, 0, 0),
+            KeyClass::Mouse => RGB8::new(16, 16, 0),
+            KeyClass::Layer => RGB8::new(0, 16, 16),
+            KeyClass::Other => RGB8::new(2, 2, 2),
+        }
+    }
+
+    /// The class of a key that types the given usage code.
+    pub fn of_usage(code: u8) -> KeyClass {
+        match code {
+            0x00 => KeyClass::None,
+            0x04..=0x1d => KeyClass::Letter,
+            0x1e..=0x27 => KeyClass::Digit,
+            0x28..=0x2c => KeyClass::Whitespace,
+            0x2d..=0x38 | 0x64 => KeyClass::Symbol,
+            0x3a..=0x45 | 0x68..=0x73 => KeyClass::Function,
+            0x49..=0x52 => KeyClass::Navigation,
+            0xe0..=0xe7 => KeyClass::Modifier,
+            _ => KeyClass::Other,
+        }
+    }
+}
+
+impl Backlight {
+    /// The LED under a key, by scan code.
+    pub fn led(&self, key: u8) -> Option<usize> {
+        self.keys.iter().position(|&k| k == key).map(|pos| self.first_led as usize + pos)
+    }
+
+    /// The color of each backlight LED, from the class of each key, by scan code.  Keys with no
+    /// class given are off.
+    pub fn colors(&self, classes: &[KeyClass]) -> Vec<RGB8> {
+        let mut colors = vec![KeyClass::None.color(); self.keys.len()];
+        for (color, &key) in colors.iter_mut().zip(&self.keys) {
+            if let Some(class) = classes.get(key as usize) {
+                *color = class.color();
+            }
+        }
+        colors
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backlight() {
+        let backlight = Backlight { first_led: 2, keys: vec![10, 11, 30] };
+        assert_eq!(backlight.led(10), Some(2));
+        assert_eq!(backlight.led(30), Some(4));
+        assert_eq!(backlight.led(12), None);
+
+        let classes = [KeyClass::Letter; 12];
+        assert_eq!(backlight.colors(&classes),
+                   vec![KeyClass::Letter.color(), KeyClass::Letter.color(), KeyClass::None.color()]);
+
+        assert_eq!(KeyClass::of_usage(0x04), KeyClass::Letter);
+        assert_eq!(KeyClass::of_usage(0x27), KeyClass::Digit);
+        assert_eq!(KeyClass::of_usage(0x2c), KeyClass::Whitespace);
+        assert_eq!(KeyClass::of_usage(0x4f), KeyClass::Navigation);
+        assert_eq!(KeyClass::of_usage(0xe1), KeyClass::Modifier);
+    }
+}
@@ -7,10 +7,16 @@
 extern crate alloc;
 
 use alloc::string::String;
+use alloc::vec::Vec;
 use minicbor::{Decode, Encode};
 
 use core::{fmt::Debug, slice::from_raw_parts};
 
+use crate::backlight::Backlight;
+use crate::debounce::{self, Algorithm, Debouncer};
+use crate::encoder::EncoderBinding;
+use crate::ser2::LinkKey;
+use crate::time::Duration;
 use crate::Side;
 use crate::log::warn;
 
@@ -39,6 +45,252 @@ pub struct BoardInfo {
     /// sides.
     #[n(2)]
     pub side: Option<Side>,
+
+    /// Should the mode be selected automatically based on host activity.
+    ///
+    /// `None` means the default, which is enabled.
+    #[n(3)]
+    pub auto_mode: Option<bool>,
+
+    /// Type critical alerts to the host, prefixed with this text.
+    ///
+    /// `None` means the default, which is disabled.  See [`crate::notify`].
+    #[n(4)]
+    pub notify: Option<String>,
+
+    /// Return to the default mode after the keyboard has been idle for this many seconds.
+    ///
+    /// `None` means the default, which is to never time out.
+    #[n(5)]
+    pub idle_timeout: Option<u32>,
+
+    /// Authenticate the link between the halves with this key.  Both halves of a split board
+    /// must be given the same key.
+    ///
+    /// `None` means the default, which is an unauthenticated link.  See [`crate::ser2::LinkAuth`].
+    #[n(6)]
+    #[cbor(with = "minicbor::bytes")]
+    pub link_key: Option<LinkKey>,
+
+    /// Limit typed output to this many HID reports per second.  Each character typed takes at
+    /// least two reports.
+    ///
+    /// `None` means the default, which is no limit.  See [`crate::output`].
+    #[n(7)]
+    pub output_rate: Option<u32>,
+
+    /// Briefly pulse the LED with this index when something just written in steno has a shorter
+    /// outline in the dictionary.
+    ///
+    /// `None` means the default, which is no brief suggestions.
+    #[n(8)]
+    pub brief_led: Option<u8>,
+
+    /// Use this scan code as the mode key.
+    ///
+    /// `None` means the default key.
+    #[n(9)]
+    pub mode_key: Option<u8>,
+
+    /// Additional chords, pressed with the mode key, that select a mode.
+    ///
+    /// `None` means only the built-in chords.
+    #[n(10)]
+    pub mode_chords: Option<Vec<ModeChord>>,
+
+    /// Text snippets, typed in place of their trigger in the keyboard modes.
+    ///
+    /// `None` means no text expansion.
+    #[n(11)]
+    pub snippets: Option<Vec<Snippet>>,
+
+    /// Debounce times for groups of keys, such as thumb keys, whose longer travel switches
+    /// chatter more than the others.
+    ///
+    /// `None`, or a key in no group, means the board's debounce time, [`BoardInfo::debounce_millis`].
+    #[n(12)]
+    pub debounce: Option<Vec<DebounceGroup>>,
+
+    /// Only offer a boot keyboard over USB, for a BIOS or KVM that can't cope with anything more.
+    /// Holding a key down while plugging in does the same, for that boot only.
+    ///
+    /// `None` means the full set of interfaces.
+    #[n(13)]
+    pub boot_keyboard: Option<bool>,
+
+    /// Send each steno stroke as soon as the first key of it is released, rather than once all of
+    /// them are.
+    ///
+    /// `None` means the default, which is first up.
+    #[n(14)]
+    pub steno_first_up: Option<bool>,
+
+    /// The per-key backlight LEDs, lit by what each key does in the qwerty layer in use.
+    ///
+    /// `None` means there is no backlight.  See [`crate::backlight`].
+    #[n(15)]
+    pub backlight: Option<Backlight>,
+
+    /// Settings for experimental layout modes, each named `mode.key`.
+    ///
+    /// `None` means no settings.  See [`crate::layout::LayoutManager::experiment_setting`].
+    #[n(16)]
+    pub settings: Option<Vec<Setting>>,
+
+    /// How keys are debounced.  See [`crate::debounce`].
+    ///
+    /// `None` means [`Algorithm::Deferred`].
+    #[n(17)]
+    pub debounce_algorithm: Option<Algorithm>,
+
+    /// The debounce time, in milliseconds, for keys not in a [`DebounceGroup`].
+    ///
+    /// `None` means [`debounce::DEFAULT_TIME`].
+    #[n(18)]
+    pub debounce_millis: Option<u8>,
+
+    /// What the rotary encoder does in each layout mode.
+    ///
+    /// `None`, or a mode with no binding, means volume up and down.  See [`crate::encoder`].
+    #[n(19)]
+    pub encoder: Option<Vec<EncoderBinding>>,
+
+    /// The steps of the encoder's signals for each detent.
+    ///
+    /// `None` means [`crate::encoder::DEFAULT_STEPS`].
+    #[n(20)]
+    pub encoder_steps: Option<u8>,
+
+    /// The HID usage code of the key the host uses as its compose key, for typing characters its
+    /// keyboard layout has no key for.
+    ///
+    /// `None` means no compose key, and those characters are skipped.  See [`crate::usb_typer`].
+    #[n(21)]
+    pub compose_key: Option<u8>,
+
+    /// Sleep after this many seconds on battery without a key being touched.
+    ///
+    /// `None` means [`crate::power::SLEEP_AFTER`], and zero never sleeps.  See [`crate::power`].
+    #[n(22)]
+    pub sleep_timeout: Option<u32>,
+
+    /// The rows of the key matrix on this side.  A matrix given more rows in the devicetree only
+    /// scans this many, so one firmware can be used on boards with a smaller matrix.
+    ///
+    /// `None` means all of the rows in the devicetree.
+    #[n(23)]
+    pub rows: Option<u8>,
+
+    /// The columns of the key matrix on this side, as with [`BoardInfo::rows`].
+    ///
+    /// `None` means all of the columns in the devicetree.
+    #[n(24)]
+    pub cols: Option<u8>,
+
+    /// The scan code of each key, by the code the matrix gives it, with 255 for a position with no
+    /// key.  The codes of the right half follow those of the left.  See [`BoardInfo::translate`].
+    ///
+    /// `None` means the table built into the firmware for the board's name.
+    #[n(25)]
+    #[cbor(with = "minicbor::bytes")]
+    pub translate: Option<Vec<u8>>,
+
+    /// How many LEDs the board has.  Any more the firmware finds are left off.
+    ///
+    /// `None` means all of the LEDs in the devicetree.
+    #[n(26)]
+    pub leds: Option<u8>,
+
+    /// What the board has, or lacks, that changes how it is used.
+    ///
+    /// `None` means the features known for the board's name.  See [`BoardInfo::is_two_row`].
+    #[n(27)]
+    pub features: Option<Features>,
+}
+
+/// Flags for what a board has.  Flags a firmware doesn't know about are ignored, so newer board
+/// info still works with it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode)]
+#[cbor(transparent)]
+pub struct Features(#[n(0)] pub u32);
+
+impl Features {
+    /// No features.
+    pub const NONE: Features = Features(0);
+
+    /// Only two rows of keys, which qwerty needs more of.  Taipo is used instead.
+    pub const TWO_ROW: Features = Features(1 << 0);
+
+    /// The features, by name, as given to bbq-tool.
+    pub const NAMES: &'static [(&'static str, Features)] = &[("two-row", Features::TWO_ROW)];
+
+    /// Does this have all of the other's features.
+    pub const fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// A feature by its name.
+    pub fn from_name(name: &str) -> Option<Features> {
+        Self::NAMES.iter().find(|(n, _)| *n == name).map(|(_, f)| *f)
+    }
+}
+
+impl core::ops::BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+/// A chord that selects a mode.  See [`crate::layout::LayoutManager::add_mode_chord`].
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct ModeChord {
+    /// The scan codes of the keys in the chord.
+    #[n(0)]
+    #[cbor(with = "minicbor::bytes")]
+    pub keys: Vec<u8>,
+
+    /// The name of the mode, as given by [`crate::layout::LayoutMode::name`].
+    #[n(1)]
+    pub mode: String,
+}
+
+/// A text snippet.  See [`crate::expand::Expander::add`].
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Snippet {
+    /// The text that, when typed, is replaced.
+    #[n(0)]
+    pub trigger: String,
+
+    /// The text typed in its place.
+    #[n(1)]
+    pub text: String,
+}
+
+/// A setting for an experimental layout mode.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Setting {
+    /// The mode's name, and the setting, such as "chorder.timeout".
+    #[n(0)]
+    pub key: String,
+
+    /// The value, which the mode interprets.
+    #[n(1)]
+    pub value: String,
+}
+
+/// The debounce time for a group of keys.  See [`BoardInfo::debounce_time`].
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct DebounceGroup {
+    /// The scan codes of the keys in the group.
+    #[n(0)]
+    #[cbor(with = "minicbor::bytes")]
+    pub keys: Vec<u8>,
+
+    /// How long, in milliseconds, a key must be stable for a press or release to be seen.
+    #[n(1)]
+    pub millis: u8,
 }
 
 pub const BOARDINFO_TAG: u64 = 0x626f617264696e66;
@@ -74,4 +326,41 @@ impl BoardInfo {
             }
         }
     }
+
+    /// The debounce time of a key, by scan code, or None for the default.  A key listed in more
+    /// than one group uses the last one.
+    pub fn debounce_time(&self, key: u8) -> Option<Duration> {
+        self.debounce
+            .iter()
+            .flatten()
+            .rev()
+            .find(|group| group.keys.contains(&key))
+            .map(|group| Duration::from_millis(group.millis as u64))
+    }
+
+    /// The debouncer for a key, by scan code, with the board's algorithm, and the key's debounce
+    /// time.
+    pub fn debouncer(&self, key: u8) -> Debouncer {
+        let time = self
+            .debounce_time(key)
+            .or(self.debounce_millis.map(|millis| Duration::from_millis(millis as u64)))
+            .unwrap_or(debounce::DEFAULT_TIME);
+        Debouncer::new(self.debounce_algorithm.unwrap_or_default(), time)
+    }
+
+    /// The scan code of a key, by the code the matrix gives it, with the board's translation
+    /// table, or None if the board doesn't give one.  Positions past the end of the table have no
+    /// key, and give 255.
+    pub fn translate(&self, code: u8) -> Option<u8> {
+        self.translate.as_ref().map(|table| table.get(code as usize).copied().unwrap_or(255))
+    }
+
+    /// Is this a two row board.  Boards without features given are known by name, for board info
+    /// written before there were features.
+    pub fn is_two_row(&self) -> bool {
+        match self.features {
+            Some(features) => features.contains(Features::TWO_ROW),
+            None => self.name == "proto4",
+        }
+    }
 }
@@ -0,0 +1,232 @@
+//! Key debouncing.
+//!
+//! Switch contacts bounce as they open and close, so a single press can read as several.  A
+//! [`Debouncer`] for each key turns the raw readings from each scan into clean presses and
+//! releases.  There is a choice of [`Algorithm`], trading latency against resistance to noise,
+//! and a debounce time, which can differ between keys (see
+//! [`BoardInfo::debouncer`](crate::boardinfo::BoardInfo::debouncer)).
+//!
+//! The scan rate varies (see [`crate::scanrate`]), so each reading is given the time since the
+//! previous scan, and the debounce time is a time, rather than a count of scans.
+
+use minicbor::{Decode, Encode};
+
+#[cfg(feature = "std")]
+use clap::ValueEnum;
+
+use crate::time::Duration;
+
+/// The debounce time, unless the board info gives another.
+pub const DEFAULT_TIME: Duration = Duration::from_millis(20);
+
+/// How a key's readings are turned into presses and releases.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(ValueEnum))]
+#[cbor(index_only)]
+pub enum Algorithm {
+    /// A change is only reported once the key has read the same for the debounce time.  Presses
+    /// and releases are both delayed by the debounce time, and a brief glitch is never seen.
+    #[default]
+    #[n(0)]
+    Deferred,
+    /// A change is reported as soon as it is read, and the key is then ignored for the debounce
+    /// time.  This adds no delay, but a glitch on a key that isn't touched reads as a tap.
+    #[n(1)]
+    Eager,
+    /// A counter moves towards each reading, by the time since the last scan, and the key changes
+    /// when it reaches either end.  Like deferred, but an occasional stray reading only sets it
+    /// back a little, rather than starting the wait over, which suits noisy switches.
+    #[n(2)]
+    Integrator,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Deferred => "deferred",
+            Algorithm::Eager => "eager",
+            Algorithm::Integrator => "integrator",
+        }
+    }
+}
+
+/// The debounce state of a single key.
+#[derive(Clone, Copy, Debug)]
+pub struct Debouncer {
+    algorithm: Algorithm,
+    /// The debounce time.
+    time: Duration,
+    /// The state last reported.
+    pressed: bool,
+    /// Waiting for a change to settle.
+    changing: bool,
+    /// For deferred, how long the new state has been read.  For eager, how long since the change
+    /// was reported.  For the integrator, the counter, from zero when released to the debounce
+    /// time when pressed.
+    elapsed: Duration,
+}
+
+impl Debouncer {
+    /// A debouncer for a key that starts released.
+    pub const fn new(algorithm: Algorithm, time: Duration) -> Debouncer {
+        Debouncer {
+            algorithm,
+            time,
+            pressed: false,
+            changing: false,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Is the key pressed, as far as has been reported.
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Is a change being debounced.
+    pub fn is_changing(&self) -> bool {
+        self.changing
+    }
+
+    /// Take a reading from a scan, `elapsed` after the previous one.  Returns the new state if the
+    /// key has been pressed or released.
+    pub fn react(&mut self, pressed: bool, elapsed: Duration) -> Option<bool> {
+        match self.algorithm {
+            Algorithm::Deferred => self.deferred(pressed, elapsed),
+            Algorithm::Eager => self.eager(pressed, elapsed),
+            Algorithm::Integrator => self.integrator(pressed, elapsed),
+        }
+    }
+
+    fn deferred(&mut self, pressed: bool, elapsed: Duration) -> Option<bool> {
+        if pressed == self.pressed {
+            // Back where it was, so whatever was seen was a glitch.
+            self.changing = false;
+            return None;
+        }
+        if !self.changing {
+            // The time is counted from the next scan that still sees the change.
+            self.changing = true;
+            self.elapsed = Duration::ZERO;
+            return None;
+        }
+        self.elapsed += elapsed;
+        if self.elapsed < self.time {
+            return None;
+        }
+        self.changing = false;
+        self.pressed = pressed;
+        Some(pressed)
+    }
+
+    fn eager(&mut self, pressed: bool, elapsed: Duration) -> Option<bool> {
+        if self.changing {
+            self.elapsed += elapsed;
+            if self.elapsed < self.time {
+                return None;
+            }
+            self.changing = false;
+        }
+        if pressed == self.pressed {
+            return None;
+        }
+        self.changing = true;
+        self.elapsed = Duration::ZERO;
+        self.pressed = pressed;
+        Some(pressed)
+    }
+
+    fn integrator(&mut self, pressed: bool, elapsed: Duration) -> Option<bool> {
+        self.elapsed = if pressed {
+            (self.elapsed + elapsed).min(self.time)
+        } else {
+            self.elapsed.saturating_sub(elapsed)
+        };
+        let settled = if self.pressed { self.time } else { Duration::ZERO };
+        self.changing = self.elapsed != settled;
+
+        let change = if !self.pressed && self.elapsed == self.time {
+            true
+        } else if self.pressed && self.elapsed == Duration::ZERO {
+            false
+        } else {
+            return None;
+        };
+        self.pressed = change;
+        self.changing = false;
+        Some(change)
+    }
+}
+
+impl Default for Debouncer {
+    fn default() -> Self {
+        Debouncer::new(Algorithm::default(), DEFAULT_TIME)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Feed readings, one a millisecond, giving the scans at which the key changed.
+    fn run(algorithm: Algorithm, readings: &[bool]) -> ([Option<usize>; 2], Debouncer) {
+        let mut key = Debouncer::new(algorithm, Duration::from_millis(5));
+        let mut changes = [None; 2];
+        for (scan, &reading) in readings.iter().enumerate() {
+            if let Some(pressed) = key.react(reading, MS) {
+                changes[if pressed { 0 } else { 1 }].get_or_insert(scan);
+            }
+        }
+        (changes, key)
+    }
+
+    /// A press with some bounce, held, and a release with some bounce.
+    fn bouncy() -> [bool; 30] {
+        let mut readings = [false; 30];
+        for (scan, reading) in readings.iter_mut().enumerate() {
+            *reading = matches!(scan, 2 | 4 | 6..=19 | 21);
+        }
+        readings
+    }
+
+    #[test]
+    fn test_deferred() {
+        let (changes, key) = run(Algorithm::Deferred, &bouncy());
+        // Stable from scan 6, and counted from scan 7.
+        assert_eq!(changes, [Some(11), Some(27)]);
+        assert!(!key.is_pressed() && !key.is_changing());
+
+        // A glitch is ignored, and doesn't leave the key changing.
+        let (changes, key) = run(Algorithm::Deferred, &[false, true, true, false, false]);
+        assert_eq!(changes, [None, None]);
+        assert!(!key.is_changing());
+    }
+
+    #[test]
+    fn test_eager() {
+        let (changes, key) = run(Algorithm::Eager, &bouncy());
+        // The first edge each way, with the bounce after each ignored.
+        assert_eq!(changes, [Some(2), Some(20)]);
+        assert!(!key.is_pressed());
+
+        // A glitch reads as a tap.
+        let (changes, _) = run(Algorithm::Eager, &[false, true, false, false, false, false, false]);
+        assert_eq!(changes, [Some(1), Some(6)]);
+    }
+
+    #[test]
+    fn test_integrator() {
+        let (changes, key) = run(Algorithm::Integrator, &bouncy());
+        assert_eq!(changes, [Some(10), Some(26)]);
+        assert!(!key.is_pressed() && !key.is_changing());
+
+        // An occasional stray reading only delays the press.
+        let mut readings = [true; 12];
+        readings[3] = false;
+        let (changes, key) = run(Algorithm::Integrator, &readings);
+        assert_eq!(changes, [Some(6), None]);
+        assert!(key.is_pressed());
+    }
+}
@@ -2,15 +2,32 @@
 
 extern crate alloc;
 
-use alloc::{string::ToString, vec::Vec};
+use alloc::{format, string::ToString, vec::Vec};
 
-use bbq_steno::{dict::{Joined, Joiner, Lookup}, memdict::MemDict, Stroke};
+use bbq_steno::{dict::{self, Joined, Joiner, Lookup, Strategy}, memdict::{self, GroupEntry, MemDict}, Stroke};
 use bbq_steno_macros::stroke;
-use crate::{log::info, Event, EventQueue};
+use minder::{partition::{self, Flash, Partition}, DictInfo, DictStatus, HashAlgorithm};
+use crate::log::info;
 
-use crate::Timable;
+use crate::time::{Clock, Duration, Instant};
+
+/// What translating a stroke has to tell the firmware, besides what to type.  These are called as
+/// the stroke is handled, rather than queued for the main loop.
+pub trait StenoEvents {
+    /// Raw mode was turned on or off.
+    fn raw_mode(&mut self, raw: bool);
+
+    /// The last thing written has a shorter outline in the dictionary.
+    fn brief_available(&mut self);
+}
 
 pub struct Dict {
+    // All of the dictionaries found, in priority order.
+    all: Vec<dict::Dict>,
+
+    // The dictionaries selected by the current profile.  None means all of them.
+    selected: Option<Vec<u8>>,
+
     // The translation engine.
     lookup: Lookup,
 
@@ -19,37 +36,157 @@ pub struct Dict {
 
     // Are we in "raw" mode.
     raw: bool,
+
+    // Should we look for shorter outlines after multi-stroke translations.
+    suggest: bool,
+
+    // When translations are given, and how long one is held waiting for a longer match.
+    strategy: Strategy,
+    hold_timeout: Duration,
+
+    // When the translation being held should be typed anyway.
+    deadline: Option<Instant>,
 }
 
 impl Dict {
-    pub fn new() -> Self {
+    /// Are there no dictionaries at all.
+    pub fn is_empty(&self) -> bool {
+        self.all.is_empty()
+    }
+
+    /// Load the dictionaries from the board's flash.
+    pub fn new(flash: &Flash) -> Self {
         let mut xlat = unsafe {
-            // MemDict::from_raw_ptr(0x10200000 as *const u8)
-            // With the 8MB devices, move the dictionary down to 1MB, as the
-            // dictionaries seem to be about 6.5MB.
-            MemDict::from_raw_ptr(0x10300000 as *const u8)
+            MemDict::from_raw_ptr(flash.address(partition::MAIN_DICT.offset) as *const u8)
         };
         let mut user = unsafe {
-            MemDict::from_raw_ptr(0x10200000 as *const u8)
+            MemDict::from_raw_ptr(flash.address(partition::USER_DICT.offset) as *const u8)
         };
         xlat.append(&mut user);
         info!("Found {} steno dictionaries", xlat.len());
-        let lookup = Lookup::new(xlat);
+
+        // A half that has never had dictionaries flashed can still write basic steno.
+        #[cfg(feature = "fallback-dict")]
+        if xlat.is_empty() {
+            info!("Using the built-in fallback dictionary");
+            xlat.push(fallback());
+        }
+        let lookup = Lookup::new(xlat.clone());
         let joiner = Joiner::new();
         Dict {
+            all: xlat,
+            selected: None,
             lookup,
             joiner,
             raw: false,
+            suggest: false,
+            strategy: Strategy::Eager,
+            hold_timeout: Duration::ZERO,
+            deadline: None,
+        }
+    }
+
+    /// Select which dictionaries are used, by their index in the order they were loaded.  `None`
+    /// selects all of them, which is the default profile.  Indices past the dictionaries present
+    /// are ignored.
+    ///
+    /// Changing the selection discards the undo history.
+    pub fn select(&mut self, dicts: Option<&[u8]>) {
+        if self.selected.as_deref() == dicts {
+            return;
         }
+        self.selected = dicts.map(|d| d.to_vec());
+
+        let active = match dicts {
+            None => self.all.clone(),
+            Some(dicts) => dicts
+                .iter()
+                .filter_map(|&i| self.all.get(i as usize).cloned())
+                .collect(),
+        };
+        info!("Using {} of {} steno dictionaries", active.len(), self.all.len());
+        self.lookup = Lookup::new(active);
+        self.lookup.set_strategy(self.strategy);
+        self.deadline = None;
+    }
+
+    /// Set when translations are given.  With [`Strategy::Longest`], a translation that could
+    /// still be extended is held, and typed anyway once `timeout` passes without another stroke.
+    pub fn set_strategy(&mut self, strategy: Strategy, timeout: Duration) {
+        self.strategy = strategy;
+        self.hold_timeout = timeout;
+        self.lookup.set_strategy(strategy);
     }
 
-    pub fn handle_stroke(&mut self, stroke: Stroke, events: &mut dyn EventQueue, timer: &dyn Timable) -> Vec<Joined> {
+    /// When [`Dict::flush`] should be called, if a translation is being held.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Type the translation being held, if there is one.
+    pub fn flush(&mut self) -> Vec<Joined> {
+        self.deadline = None;
+        for action in self.lookup.flush() {
+            self.joiner.add(action);
+        }
+        let mut result = Vec::new();
+        while let Some(action) = self.joiner.pop(0) {
+            info!("Key: {:?} (held)", action);
+            result.push(action);
+        }
+        result
+    }
+
+    /// Forget what has been typed, as the output didn't all reach the host.  See
+    /// [`Joiner::reset`].
+    pub fn reset(&mut self) {
+        self.joiner.reset();
+    }
+
+    /// Drop every dictionary, so that nothing refers to flash that is about to be rewritten.  Strokes
+    /// are still taken, but nothing translates them, until the dictionaries are loaded again with
+    /// [`Dict::new`].
+    pub fn unload(&mut self) {
+        self.all.clear();
+        self.selected = None;
+        self.lookup = Lookup::new(Vec::new());
+        self.lookup.set_strategy(self.strategy);
+        self.deadline = None;
+    }
+
+    /// Type text from outside of steno, in order with the translations, so that the joiner knows
+    /// it is there.  See [`Joiner::add_text`].
+    pub fn type_text(&mut self, text: &str) -> Vec<Joined> {
+        self.joiner.add_text(text);
+        let mut result = Vec::new();
+        while let Some(action) = self.joiner.pop(0) {
+            result.push(action);
+        }
+        result
+    }
+
+    /// Enable looking for briefs.  After each translation that took more than one stroke, the
+    /// dictionaries are searched for a shorter outline, and [`StenoEvents::brief_available`] is
+    /// called if there is one.  The search visits every entry, so this is off by default.
+    pub fn set_suggest(&mut self, enabled: bool) {
+        self.suggest = enabled;
+    }
+
+    /// Start looking up a stroke that is still being written, to reduce the work once it is
+    /// complete.
+    pub fn prepare(&mut self, stroke: Stroke) {
+        if !self.raw {
+            self.lookup.prepare(stroke);
+        }
+    }
+
+    pub fn handle_stroke(&mut self, stroke: Stroke, events: &mut dyn StenoEvents, clock: &dyn Clock) -> Vec<Joined> {
         let mut result = Vec::new();
 
         // Special check for the raw mode stroke.  Use it to toggle raw mode.
         if stroke == stroke!("RA*U") {
             self.raw = !self.raw;
-            events.push(Event::RawMode(self.raw));
+            events.raw_mode(self.raw);
             return result;
         }
 
@@ -66,15 +203,149 @@ impl Dict {
 
         // The xlat is always present as it will just do nothing if there
         // are no dictionaries present.
-        let start = timer.get_ticks();
-        let action = self.lookup.add(stroke);
-        self.joiner.add(action);
-        let stop = timer.get_ticks();
+        let start = clock.now();
+        for action in self.lookup.add(stroke) {
+            self.joiner.add(action);
+        }
+        let elapsed = clock.now() - start;
+        self.deadline = if self.lookup.is_holding() {
+            Some(start + self.hold_timeout)
+        } else {
+            None
+        };
         while let Some(action) = self.joiner.pop(0) {
-            info!("Key: {:?} {}us", action,
-            stop - start);
+            info!("Key: {:?} {}us", action, elapsed.as_micros());
             result.push(action);
         }
+
+        if self.suggest {
+            if let Some(outline) = self.lookup.shorter() {
+                let outline: Vec<_> = outline.iter().map(|s| s.to_string()).collect();
+                info!("Brief available: {}", outline.join("/"));
+                events.brief_available();
+            }
+        }
         result
     }
 }
+
+/// The partitions dictionaries are loaded from, in load order.
+const DICT_PARTITIONS: [Partition; 2] = [partition::MAIN_DICT, partition::USER_DICT];
+
+/// Describe the dictionaries in flash, in the same order, and with the same indices, that
+/// [`Dict::new`] loads them.  If an algorithm is given, each dictionary's data is hashed with it.
+///
+/// This reads the dictionary partitions directly, so is only meaningful on the device.
+pub fn list(flash: &Flash, algorithm: Option<HashAlgorithm>) -> Vec<DictInfo> {
+    let mut result = Vec::new();
+    let mut index = 0u8;
+
+    for part in DICT_PARTITIONS.iter().filter_map(|p| flash.fit(p)) {
+        let base = flash.address(part.offset) as *const u8;
+        // A partition is loaded entirely, or not at all.
+        let loaded = unsafe { !MemDict::from_raw_ptr(base).is_empty() };
+
+        for (pos, entry) in unsafe { MemDict::entries(base) }.into_iter().enumerate() {
+            let mut info = DictInfo {
+                index: loaded.then_some(index),
+                partition: part.name.to_string(),
+                ..DictInfo::default()
+            };
+            if loaded {
+                index += 1;
+            }
+
+            match entry {
+                GroupEntry::Memory(raw) => {
+                    let extent = raw.extent();
+                    info.name = raw.name.clone().unwrap_or_else(|| format!("{}/{}", part.name, pos));
+                    info.entries = raw.size;
+                    if extent.start <= extent.end && extent.end <= part.size {
+                        info.offset = part.offset + extent.start;
+                        info.size = extent.len() as u32;
+                        info.digest = algorithm.and_then(|algorithm| {
+                            let data = unsafe {
+                                core::slice::from_raw_parts(base.add(extent.start as usize),
+                                                            extent.len())
+                            };
+                            algorithm.digest(data)
+                        });
+                    }
+                }
+                GroupEntry::Builtin(name) => info.name = name,
+            }
+            result.push(info);
+        }
+    }
+
+    #[cfg(feature = "fallback-dict")]
+    if index == 0 {
+        result.push(DictInfo {
+            index: Some(0),
+            name: "fallback".to_string(),
+            partition: "firmware".to_string(),
+            entries: fallback().len() as u32,
+            ..DictInfo::default()
+        });
+    }
+
+    result
+}
+
+/// Summarize the dictionaries in flash for the status, with a CRC-32 of each, which is quick
+/// enough to compute even for the main dictionary.
+pub fn status(flash: &Flash) -> DictStatus {
+    let dicts = list(flash, Some(HashAlgorithm::Crc32));
+    DictStatus {
+        format: memdict::FORMAT_VERSION,
+        valid: dicts.iter().all(|dict| dict.index.is_some()),
+        dicts,
+    }
+}
+
+/// The built-in fallback dictionary.
+#[cfg(feature = "fallback-dict")]
+fn fallback() -> dict::Dict {
+    use alloc::rc::Rc;
+    use bbq_steno::dict::MapDictBuilder;
+    use bbq_steno_macros::embed_dict;
+
+    static FALLBACK: &[(&[Stroke], &str)] = embed_dict!("fallback-dict.txt");
+
+    let mut builder = MapDictBuilder::new();
+    for (strokes, text) in FALLBACK {
+        builder.insert(strokes.to_vec(), text.to_string());
+    }
+    Rc::new(builder.into_ram_dict())
+}
+
+#[cfg(all(test, feature = "fallback-dict"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fallback() {
+        let mut lookup = Lookup::new(vec![fallback()]);
+        let mut joiner = Joiner::new();
+        let mut typed = String::new();
+        for stroke in ["-T", "KR*", "A*", "T*", "TP-PL", "U"] {
+            for action in lookup.add(Stroke::from_text(stroke).unwrap()) {
+                joiner.add(action);
+            }
+            while let Some(action) = joiner.pop(0) {
+                match action {
+                    Joined::Type { remove, append } => {
+                        for _ in 0..remove {
+                            typed.pop();
+                        }
+                        typed.push_str(&append);
+                    }
+                    Joined::Raw(keys) => panic!("Unexpected raw keys: {}", keys),
+                    Joined::Command(command) => panic!("Unexpected command: {:?}", command),
+                    other => panic!("Unexpected action: {:?}", other),
+                }
+            }
+        }
+        assert_eq!(typed, "The cat. You");
+    }
+}
@@ -0,0 +1,150 @@
+//! Rotary encoders.
+//!
+//! An encoder gives two signals, A and B, a quarter of a cycle apart, which step through a gray
+//! code as the knob turns.  The order of the steps gives the direction, clockwise when A leads B.
+//! A [`Decoder`] follows the signals, read on each scan, and counts the detents (the clicks of the
+//! knob) turned, which are passed on to [`crate::engine::Engine::encoder`].
+//!
+//! What turning the knob does depends on the layout mode, through the board info's
+//! [`EncoderBinding`]s.  See [`crate::layout::LayoutManager::handle_encoder`].
+
+extern crate alloc;
+
+use alloc::string::String;
+
+use minicbor::{Decode, Encode};
+
+/// The steps for each detent, unless the board info gives another.  Most encoders go through a
+/// whole cycle of the gray code for each click.
+pub const DEFAULT_STEPS: u8 = 4;
+
+/// The movement for each change of state, indexed by the previous state, and the new one, each as
+/// `B << 1 | A`.  Impossible changes, where both signals change at once, have been missed steps,
+/// and count as nothing.
+const STEPS: [i8; 16] = [0, 1, -1, 0, -1, 0, 0, 1, 1, 0, 0, -1, 0, -1, 1, 0];
+
+/// Decodes the signals of an encoder into detents.
+#[derive(Clone, Copy, Debug)]
+pub struct Decoder {
+    /// The last state of the signals.
+    state: u8,
+    /// Steps since the last detent.
+    steps: i8,
+    /// Steps in a detent.
+    per_detent: i8,
+}
+
+impl Decoder {
+    /// A decoder for an encoder with `per_detent` steps for each click.
+    pub fn new(per_detent: u8) -> Decoder {
+        Decoder { state: 0, steps: 0, per_detent: per_detent.clamp(1, 4) as i8 }
+    }
+
+    /// Start from a reading, without counting it as movement, such as where the knob is resting
+    /// at startup.
+    pub fn start(&mut self, a: bool, b: bool) {
+        self.state = (b as u8) << 1 | a as u8;
+        self.steps = 0;
+    }
+
+    /// Take a reading of the signals.  Returns the detents turned, positive for clockwise.
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        let state = (b as u8) << 1 | a as u8;
+        self.steps += STEPS[(self.state << 2 | state) as usize];
+        self.state = state;
+
+        if self.steps >= self.per_detent {
+            self.steps = 0;
+            1
+        } else if self.steps <= -self.per_detent {
+            self.steps = 0;
+            -1
+        } else {
+            0
+        }
+    }
+
+    /// Is the knob part way between detents.
+    pub fn is_moving(&self) -> bool {
+        self.steps != 0
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new(DEFAULT_STEPS)
+    }
+}
+
+/// What turning the encoder does.
+#[derive(Clone, Debug, Default, Encode, Decode, Eq, PartialEq)]
+pub enum EncoderAction {
+    /// Nothing.
+    #[n(0)]
+    None,
+    /// Volume up, clockwise, and down, as media keys.
+    #[default]
+    #[n(1)]
+    Volume,
+    /// Tap a key for each detent, given as HID keyboard usage codes.
+    #[n(2)]
+    Keys {
+        #[n(0)]
+        cw: u8,
+        #[n(1)]
+        ccw: u8,
+    },
+    /// Change to the next layout mode, clockwise, or the previous one.
+    #[n(3)]
+    Modes,
+}
+
+/// The action for a layout mode.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct EncoderBinding {
+    /// The name of the mode, as given by [`crate::layout::LayoutMode::name`], or None for modes
+    /// that aren't given.
+    #[n(0)]
+    pub mode: Option<String>,
+
+    #[n(1)]
+    pub action: EncoderAction,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Turn through the gray code, a step at a time, giving the detents seen.
+    fn turn(decoder: &mut Decoder, states: &[u8]) -> i8 {
+        states.iter().map(|&s| decoder.update(s & 1 != 0, s & 2 != 0)).sum()
+    }
+
+    #[test]
+    fn test_decoder() {
+        let mut decoder = Decoder::default();
+        // One detent each way.
+        assert_eq!(turn(&mut decoder, &[1, 3, 2, 0]), 1);
+        assert!(!decoder.is_moving());
+        assert_eq!(turn(&mut decoder, &[2, 3, 1, 0]), -1);
+
+        // Bouncing back and forth on a step goes nowhere.
+        assert_eq!(turn(&mut decoder, &[1, 0, 1, 0, 1, 0]), 0);
+        assert!(!decoder.is_moving());
+
+        // Part of the way, and back again.
+        assert_eq!(turn(&mut decoder, &[1, 3]), 0);
+        assert!(decoder.is_moving());
+        assert_eq!(turn(&mut decoder, &[1, 0]), 0);
+
+        // Encoders with a detent on every step.
+        let mut decoder = Decoder::new(1);
+        assert_eq!(turn(&mut decoder, &[1, 3, 2, 0]), 4);
+        assert_eq!(turn(&mut decoder, &[2]), -1);
+
+        // Starting part way around the cycle.
+        let mut decoder = Decoder::default();
+        decoder.start(true, true);
+        assert_eq!(turn(&mut decoder, &[2, 0, 1, 3]), 1);
+    }
+}
@@ -0,0 +1,383 @@
+//! The main loop of the firmware.
+//!
+//! Every firmware receives the same inputs, and mostly does the same with them: keys go to the
+//! layout, or across to the other half when this one is secondary, the USB and BLE state picks
+//! where reports go and whether the keyboard is on its battery, and the LEDs show what is going on.
+//! The [`Engine`] decides all of that, and calls on the firmware, through a trait for each part of
+//! the hardware, to carry it out.  The firmware is left to gather its inputs, and give each to the
+//! engine's method for it: [`Engine::key`], [`Engine::encoder`], [`Engine::inter`],
+//! [`Engine::host`], and [`Engine::tick`].  Each kind of input is its own type, so the firmware
+//! can carry them however suits it, rather than all through one queue.
+//!
+//! The engine doesn't wait on anything itself, so it doesn't depend on an executor.  Shutting down,
+//! which does, is left to the firmware, after [`Engine::shutdown`].
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+
+use crate::log::{info, warn};
+use crate::time::Duration;
+use crate::{HostEvent, InterEvent, InterState, KeyEvent, UsbDeviceState};
+
+/// How often [`Engine::tick`] is called.
+pub const TICK: Duration = Duration::from_millis(1);
+
+/// The connections to hosts.
+pub trait Usb {
+    /// Send reports over USB, BLE, or, with neither up, nowhere.
+    fn select_transport(&mut self, usb: bool, ble: bool);
+
+    /// The keyboard is running on its battery, as USB isn't up, and it isn't the secondary of a
+    /// half that is.
+    fn set_battery(&mut self, battery: bool);
+}
+
+/// The indicator LEDs.
+pub trait Leds {
+    /// Show, or stop showing, that USB is suspended, or not yet up.  This overrides the mode.
+    fn set_suspended(&mut self, suspended: bool);
+
+    /// Step the LED patterns, every [`Timers::led_period`].
+    fn tick(&mut self);
+}
+
+/// The link to the other half of a split keyboard.
+pub trait Inter {
+    /// Send a key to the primary side.
+    fn add_key(&mut self, key: KeyEvent);
+
+    /// Tell the other half which side is driving.
+    fn set_state(&mut self, state: InterState);
+
+    /// This half is asleep, or has woken.
+    fn sleep(&mut self, asleep: bool);
+}
+
+/// The periodic work, which the firmware may slow down to save power.
+pub trait Timers {
+    /// How often to step the LEDs, and the power policy.
+    fn led_period(&self) -> Duration;
+
+    /// Update the power policy, returning whether the keyboard is now asleep.
+    fn update_power(&mut self) -> bool;
+}
+
+/// Where keys, and the encoder, go on this side.
+pub trait Keys {
+    /// Note that a key changed, from either half, for activity and power.
+    fn track(&mut self, key: KeyEvent);
+
+    /// Give a key to the layout.  Returns false if it was dropped.
+    fn layout(&mut self, key: KeyEvent) -> bool;
+
+    /// The rotary encoder turned this many detents.
+    fn encoder(&mut self, delta: i8);
+}
+
+/// The main loop, with each part of the hardware it drives.  Each part is `Send`, so the engine
+/// can be moved into the task that runs it.
+pub struct Engine {
+    usb: Box<dyn Usb + Send>,
+    leds: Box<dyn Leds + Send>,
+    inter: Option<Box<dyn Inter + Send>>,
+    timers: Box<dyn Timers + Send>,
+    keys: Box<dyn Keys + Send>,
+
+    state: InterState,
+    usb_up: bool,
+    ble_up: bool,
+    /// Showing that USB is suspended, which starts out shown, until USB comes up.
+    suspended: bool,
+    /// Whether the other half was last told this one is asleep.
+    asleep: bool,
+    /// Time since the LEDs were last stepped.
+    led_elapsed: Duration,
+}
+
+impl Engine {
+    /// The engine, with the inter link, if this is a split keyboard with one.  Until USB comes up,
+    /// the keyboard is assumed to be running from its battery.
+    pub fn new(
+        mut usb: Box<dyn Usb + Send>,
+        leds: Box<dyn Leds + Send>,
+        inter: Option<Box<dyn Inter + Send>>,
+        timers: Box<dyn Timers + Send>,
+        keys: Box<dyn Keys + Send>,
+    ) -> Engine {
+        usb.set_battery(true);
+        Engine {
+            usb,
+            leds,
+            inter,
+            timers,
+            keys,
+            state: InterState::Idle,
+            usb_up: false,
+            ble_up: false,
+            suspended: true,
+            asleep: false,
+            led_elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Which side is driving.
+    pub fn state(&self) -> InterState {
+        self.state
+    }
+
+    /// A key on this side's matrix.
+    pub fn key(&mut self, key: KeyEvent) {
+        self.keys.track(key);
+        match self.state {
+            InterState::Primary | InterState::Idle => self.layout(key),
+            InterState::Secondary => {
+                if let Some(inter) = &mut self.inter {
+                    if key.is_valid() {
+                        inter.add_key(key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The rotary encoder turned.  The link between the halves doesn't carry the encoder, so only
+    /// one on the primary side does anything.
+    pub fn encoder(&mut self, delta: i8) {
+        if self.state != InterState::Secondary {
+            self.keys.encoder(delta);
+        }
+    }
+
+    /// Something from the other half.
+    pub fn inter(&mut self, event: InterEvent) {
+        match event {
+            InterEvent::Key(key) => {
+                self.keys.track(key);
+                if self.state == InterState::Primary {
+                    self.layout(key);
+                }
+            }
+            InterEvent::BecomeState(state) => {
+                if state != self.state && state != InterState::Primary {
+                    self.set_suspended(false);
+                }
+                // The other half only drives the link while on USB, and the link powers this half
+                // as well.
+                let secondary = state == InterState::Secondary;
+                if secondary != (self.state == InterState::Secondary) {
+                    self.usb.set_battery(!secondary && !self.usb_up);
+                }
+                self.state = state;
+            }
+            InterEvent::Heartbeat => (),
+        }
+    }
+
+    /// A host connected or went away.
+    pub fn host(&mut self, event: HostEvent) {
+        match event {
+            HostEvent::Usb(UsbDeviceState::Configured) | HostEvent::Usb(UsbDeviceState::Resume) => {
+                self.usb_up = true;
+                self.usb.select_transport(self.usb_up, self.ble_up);
+                self.usb.set_battery(false);
+                self.set_suspended(false);
+                if let Some(inter) = &mut self.inter {
+                    inter.set_state(InterState::Primary);
+                }
+            }
+            HostEvent::Usb(UsbDeviceState::Suspend) => {
+                self.usb_up = false;
+                self.usb.select_transport(self.usb_up, self.ble_up);
+                self.usb.set_battery(true);
+                self.set_suspended(true);
+            }
+            HostEvent::Usb(_) => (),
+            HostEvent::Ble(connected) => {
+                info!("BLE host {}", if connected { "connected" } else { "disconnected" });
+                self.ble_up = connected;
+                self.usb.select_transport(self.usb_up, self.ble_up);
+            }
+        }
+    }
+
+    /// Step the LEDs, and the power policy, as often as the timers ask.  As secondary, the link
+    /// sleeps along with the keys.  A key wakes it on its own.
+    pub fn tick(&mut self) {
+        self.led_elapsed += TICK;
+        if self.led_elapsed < self.timers.led_period() {
+            return;
+        }
+        self.led_elapsed = Duration::ZERO;
+        self.leds.tick();
+        let asleep = self.timers.update_power();
+        if asleep != self.asleep {
+            self.asleep = asleep;
+            if self.state == InterState::Secondary {
+                if let Some(inter) = &mut self.inter {
+                    inter.sleep(asleep);
+                }
+            }
+        }
+    }
+
+    /// The keyboard is about to reboot.  Stop driving the other half, so it falls back to idle on
+    /// its own.
+    pub fn shutdown(&mut self) {
+        if let Some(inter) = &mut self.inter {
+            inter.set_state(InterState::Idle);
+        }
+    }
+
+    fn layout(&mut self, key: KeyEvent) {
+        if !self.keys.layout(key) {
+            warn!("Key event dropped {:?}", key);
+        }
+    }
+
+    fn set_suspended(&mut self, suspended: bool) {
+        if self.suspended != suspended {
+            self.suspended = suspended;
+            self.leds.set_suspended(suspended);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+    use alloc::string::{String, ToString};
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Every call made on the hardware, in order.
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    struct Mock {
+        calls: Calls,
+        asleep: Arc<Mutex<bool>>,
+    }
+
+    impl Mock {
+        fn call(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl Usb for Mock {
+        fn select_transport(&mut self, usb: bool, ble: bool) {
+            self.call(format!("transport {} {}", usb, ble));
+        }
+
+        fn set_battery(&mut self, battery: bool) {
+            self.call(format!("battery {}", battery));
+        }
+    }
+
+    impl Leds for Mock {
+        fn set_suspended(&mut self, suspended: bool) {
+            self.call(format!("suspended {}", suspended));
+        }
+
+        fn tick(&mut self) {
+            self.call("leds".to_string());
+        }
+    }
+
+    impl Inter for Mock {
+        fn add_key(&mut self, key: KeyEvent) {
+            self.call(format!("inter {:?}", key));
+        }
+
+        fn set_state(&mut self, state: InterState) {
+            self.call(format!("state {:?}", state));
+        }
+
+        fn sleep(&mut self, asleep: bool) {
+            self.call(format!("sleep {}", asleep));
+        }
+    }
+
+    impl Timers for Mock {
+        fn led_period(&self) -> Duration {
+            Duration::from_millis(3)
+        }
+
+        fn update_power(&mut self) -> bool {
+            *self.asleep.lock().unwrap()
+        }
+    }
+
+    impl Keys for Mock {
+        fn track(&mut self, _key: KeyEvent) {}
+
+        fn layout(&mut self, key: KeyEvent) -> bool {
+            self.call(format!("layout {:?}", key));
+            true
+        }
+
+        fn encoder(&mut self, delta: i8) {
+            self.call(format!("encoder {}", delta));
+        }
+    }
+
+    fn take(calls: &Calls) -> Vec<String> {
+        calls.lock().unwrap().drain(..).collect()
+    }
+
+    #[test]
+    fn test_engine() {
+        let calls = Calls::default();
+        let asleep = Arc::new(Mutex::new(false));
+        let mock = || Box::new(Mock { calls: calls.clone(), asleep: asleep.clone() });
+        let mut engine = Engine::new(mock(), mock(), Some(mock()), mock(), mock());
+        assert_eq!(take(&calls), ["battery true"]);
+
+        // Idle, keys go to the layout, and the other half's are ignored.
+        engine.key(KeyEvent::Press(1));
+        engine.inter(InterEvent::Key(KeyEvent::Press(30)));
+        engine.encoder(-1);
+        assert_eq!(take(&calls), ["layout Press(1)", "encoder -1"]);
+
+        // USB coming up makes this side primary.
+        engine.host(HostEvent::Usb(UsbDeviceState::Configured));
+        assert_eq!(take(&calls), ["transport true false", "battery false", "suspended false", "state Primary"]);
+        engine.inter(InterEvent::BecomeState(InterState::Primary));
+        engine.inter(InterEvent::Key(KeyEvent::Press(30)));
+        assert_eq!(take(&calls), ["layout Press(30)"]);
+
+        engine.host(HostEvent::Usb(UsbDeviceState::Suspend));
+        engine.host(HostEvent::Ble(true));
+        assert_eq!(take(&calls), ["transport false false", "battery true", "suspended true", "transport false true"]);
+
+        // As secondary, keys go across, and the LEDs are left to the other side.
+        // The other half is powering this one.
+        engine.inter(InterEvent::BecomeState(InterState::Secondary));
+        assert_eq!(engine.state(), InterState::Secondary);
+        engine.key(KeyEvent::Press(2));
+        engine.key(KeyEvent::Press(255));
+        engine.encoder(1);
+        assert_eq!(take(&calls), ["suspended false", "battery false", "inter Press(2)"]);
+
+        // The LEDs are stepped every period, and going to sleep is passed on.
+        for _ in 0..3 {
+            engine.tick();
+        }
+        assert_eq!(take(&calls), ["leds"]);
+        *asleep.lock().unwrap() = true;
+        for _ in 0..3 {
+            engine.tick();
+        }
+        assert_eq!(take(&calls), ["leds", "sleep true"]);
+
+        // Once the other half lets go, this one is on its battery again.
+        engine.inter(InterEvent::BecomeState(InterState::Idle));
+        assert_eq!(take(&calls), ["battery true"]);
+
+        engine.shutdown();
+        assert_eq!(take(&calls), ["state Idle"]);
+    }
+}
@@ -0,0 +1,233 @@
+//! Text expansion.
+//!
+//! In the keyboard modes (qwerty, taipo, artsey), the expander watches the keys sent, and when the
+//! text just typed ends with the trigger of a snippet, such as ";sig", the trigger is deleted, and
+//! the snippet's text typed in its place.
+//!
+//! Only a short tail of what was typed is kept, enough to match the longest trigger.  Backspace
+//! removes from it.  Anything that moves the cursor, or is a shortcut (a key with control held),
+//! forgets it, as there is no longer any way to know what is in front of the cursor.
+
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::usb_typer::{char_key, key_char};
+use crate::{KeyAction, Mods};
+
+/// The longest trigger, in characters.
+pub const MAX_TRIGGER: usize = 16;
+
+/// Replace the last `remove` characters typed with `text`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Expansion {
+    pub remove: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Expander {
+    /// The triggers, and their expansions.
+    snippets: Vec<(Vec<char>, String)>,
+
+    /// The tail of the text typed.
+    typed: Vec<char>,
+
+    /// The keys held in the last key set, to find the ones newly pressed.
+    held: Vec<Keyboard>,
+
+    /// An expansion waiting for the keys to be released.
+    pending: Option<Expansion>,
+}
+
+impl Expander {
+    pub fn new() -> Expander {
+        Expander::default()
+    }
+
+    /// Add a snippet.  Returns false if the trigger is empty, too long, or can't be typed, in
+    /// which case it is ignored.  A trigger that is already present gets the new expansion.
+    pub fn add(&mut self, trigger: &str, text: &str) -> bool {
+        let trigger: Vec<char> = trigger.chars().collect();
+        if trigger.is_empty()
+            || trigger.len() > MAX_TRIGGER
+            || trigger.iter().any(|&ch| ch == '\n' || char_key(ch).is_none())
+        {
+            return false;
+        }
+        self.snippets.retain(|(t, _)| *t != trigger);
+        self.snippets.push((trigger, text.to_string()));
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snippets.is_empty()
+    }
+
+    /// Forget what has been typed, such as when changing modes.
+    pub fn clear(&mut self) {
+        self.typed.clear();
+        self.held.clear();
+        self.pending = None;
+    }
+
+    /// Track a key action being sent.  Once a trigger has been typed, and the keys released, this
+    /// returns the expansion to type.
+    pub fn key(&mut self, action: &KeyAction) -> Option<Expansion> {
+        if self.snippets.is_empty() {
+            return None;
+        }
+
+        let released = match action {
+            KeyAction::KeyPress(key, mods) => {
+                self.press(*key, *mods);
+                false
+            }
+            KeyAction::KeySet(keys) => {
+                let mods = keys.iter().fold(Mods::empty(), |m, &k| m | modifier(k));
+                for &key in keys {
+                    if modifier(key).is_empty() && !self.held.contains(&key) {
+                        self.press(key, mods);
+                    }
+                }
+                self.held = keys.clone();
+                keys.is_empty()
+            }
+            KeyAction::KeyRelease => true,
+            KeyAction::ModOnly(_) | KeyAction::Stall => false,
+            // Clicking likely moves the cursor.
+            KeyAction::MousePress(_) => {
+                self.typed.clear();
+                self.pending = None;
+                false
+            }
+            KeyAction::MouseMove(..) | KeyAction::Consumer(_) => false,
+        };
+
+        if released {
+            self.pending.take()
+        } else {
+            None
+        }
+    }
+
+    fn press(&mut self, key: Keyboard, mods: Mods) {
+        // Anything after the trigger cancels it.
+        self.pending = None;
+
+        if mods.intersects(Mods::CONTROL | Mods::ALT | Mods::GUI) {
+            self.typed.clear();
+            return;
+        }
+        if key == Keyboard::DeleteBackspace {
+            self.typed.pop();
+            return;
+        }
+
+        match key_char(key, mods.contains(Mods::SHIFT)) {
+            Some(ch) if ch != '\n' => {
+                if self.typed.len() == MAX_TRIGGER {
+                    self.typed.remove(0);
+                }
+                self.typed.push(ch);
+            }
+            // Enter, tab, arrows, and the like.
+            _ => {
+                self.typed.clear();
+                return;
+            }
+        }
+
+        // The longest trigger wins.
+        let found = self
+            .snippets
+            .iter()
+            .filter(|(t, _)| self.typed.ends_with(t))
+            .max_by_key(|(t, _)| t.len());
+        if let Some((trigger, text)) = found {
+            self.pending = Some(Expansion { remove: trigger.len(), text: text.clone() });
+            self.typed.clear();
+        }
+    }
+}
+
+/// The modifier for a modifier key, empty for other keys.
+fn modifier(key: Keyboard) -> Mods {
+    match key {
+        Keyboard::LeftControl | Keyboard::RightControl => Mods::CONTROL,
+        Keyboard::LeftShift | Keyboard::RightShift => Mods::SHIFT,
+        Keyboard::LeftAlt | Keyboard::RightAlt => Mods::ALT,
+        Keyboard::LeftGUI | Keyboard::RightGUI => Mods::GUI,
+        _ => Mods::empty(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn tap(ex: &mut Expander, key: Keyboard, mods: Mods) -> Option<Expansion> {
+        assert_eq!(ex.key(&KeyAction::KeyPress(key, mods)), None);
+        ex.key(&KeyAction::KeyRelease)
+    }
+
+    fn type_text(ex: &mut Expander, text: &str) -> Option<Expansion> {
+        let mut result = None;
+        for ch in text.chars() {
+            let (key, shift) = char_key(ch).unwrap();
+            let mods = if shift { Mods::SHIFT } else { Mods::empty() };
+            result = tap(ex, key, mods);
+        }
+        result
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut ex = Expander::new();
+        assert!(ex.add(";sig", "Best,\nMe"));
+        assert!(ex.add("sig", "signature"));
+        assert!(!ex.add("", "empty"));
+        assert!(!ex.add("a\tb", "tab"));
+
+        let sig = Some(Expansion { remove: 4, text: "Best,\nMe".to_string() });
+        assert_eq!(type_text(&mut ex, "hello ;sig"), sig);
+        assert_eq!(type_text(&mut ex, "sig"), Some(Expansion { remove: 3, text: "signature".to_string() }));
+
+        // Backspace fixes a typo.
+        assert_eq!(type_text(&mut ex, ";sih"), None);
+        assert_eq!(tap(&mut ex, Keyboard::DeleteBackspace, Mods::empty()), None);
+        assert_eq!(type_text(&mut ex, "g"), sig);
+
+        // Moving the cursor, or a shortcut, forgets the text.
+        assert_eq!(type_text(&mut ex, ";s"), None);
+        assert_eq!(tap(&mut ex, Keyboard::LeftArrow, Mods::empty()), None);
+        assert_eq!(type_text(&mut ex, "ig"), None);
+        assert_eq!(type_text(&mut ex, ";si"), None);
+        assert_eq!(tap(&mut ex, Keyboard::C, Mods::CONTROL), None);
+        assert_eq!(type_text(&mut ex, "g"), None);
+    }
+
+    #[test]
+    fn test_keyset() {
+        let mut ex = Expander::new();
+        assert!(ex.add("Ab", "expanded"));
+
+        // Qwerty mode sends the set of keys held.  The expansion waits for them all to be up.
+        let steps = [
+            vec![Keyboard::LeftShift],
+            vec![Keyboard::LeftShift, Keyboard::A],
+            vec![Keyboard::A],
+            vec![Keyboard::A, Keyboard::B],
+            vec![Keyboard::B],
+        ];
+        for keys in steps {
+            assert_eq!(ex.key(&KeyAction::KeySet(keys)), None);
+        }
+        assert_eq!(ex.key(&KeyAction::KeySet(vec![])),
+                   Some(Expansion { remove: 2, text: "expanded".to_string() }));
+    }
+}
@@ -0,0 +1,152 @@
+//! HID keyboard reports.
+//!
+//! The boot keyboard report only has room for six keys, which is plenty for steno and taipo, which
+//! only ever send one key at a time, but not for qwerty mode, where any number of keys can be held.
+//! When the host is using the report protocol, the keyboard instead sends a report with a bit for
+//! every key (NKRO), described by [`NKRO_REPORT_DESC`].  Hosts that only understand the boot
+//! protocol (such as a BIOS) select it, and get the boot report instead.
+//!
+//! Both reports take the modifiers, and the usage codes of the other keys held.  Codes in the
+//! modifier range (0xe0 to 0xe7) are folded into the modifier byte.
+//!
+//! Media keys, such as volume and play/pause, are on the consumer page, which has its own report,
+//! described by [`CONSUMER_REPORT_DESC`], holding a single usage code.
+
+/// Usage codes below this get a bit in the NKRO report.  The rest are the modifiers, or unused.
+pub const NKRO_KEYS: usize = 0xe0;
+
+/// The size of the NKRO report: the modifiers, then the key bitmap.
+pub const NKRO_REPORT_SIZE: usize = 1 + NKRO_KEYS / 8;
+
+/// The size of the boot report.
+pub const BOOT_REPORT_SIZE: usize = 8;
+
+/// The first modifier usage code (left control).
+const FIRST_MOD: u8 = 0xe0;
+
+/// Usage code reported in every slot of the boot report when too many keys are held.
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Report descriptor for the NKRO keyboard.  The LED output report is the same as the boot one.
+pub static NKRO_REPORT_DESC: [u8; 47] = [
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    // Modifiers, one bit each.
+    0x05, 0x07, //     Usage Page (Keyboard)
+    0x19, 0xe0, //     Usage Minimum (Left Control)
+    0x29, 0xe7, //     Usage Maximum (Right GUI)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x08, //     Report Count (8)
+    0x81, 0x02, //     Input (Data, Var, Abs)
+    // The keys, one bit each.
+    0x05, 0x07, //     Usage Page (Keyboard)
+    0x19, 0x00, //     Usage Minimum (0)
+    0x29, 0xdf, //     Usage Maximum (0xdf)
+    0x95, 0xe0, //     Report Count (224)
+    0x81, 0x02, //     Input (Data, Var, Abs)
+    // LEDs, five bits and padding.
+    0x05, 0x08, //     Usage Page (LEDs)
+    0x19, 0x01, //     Usage Minimum (Num Lock)
+    0x29, 0x05, //     Usage Maximum (Kana)
+    0x95, 0x05, //     Report Count (5)
+    0x91, 0x02, //     Output (Data, Var, Abs)
+    0x95, 0x03, //     Report Count (3)
+    0x91, 0x01, //     Output (Const)
+    0xc0, // End Collection
+];
+
+/// The highest consumer usage code that can be reported.
+pub const CONSUMER_MAX: u16 = 0x3ff;
+
+/// Report descriptor for the media keys, a single consumer usage code, zero when none is held.
+pub static CONSUMER_REPORT_DESC: [u8; 23] = [
+    0x05, 0x0c, // Usage Page (Consumer)
+    0x09, 0x01, // Usage (Consumer Control)
+    0xa1, 0x01, // Collection (Application)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x26, 0xff, 0x03, //     Logical Maximum (0x3ff)
+    0x19, 0x00, //     Usage Minimum (0)
+    0x2a, 0xff, 0x03, //     Usage Maximum (0x3ff)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x00, //     Input (Data, Array, Abs)
+    0xc0, // End Collection
+];
+
+/// Build a consumer report, with the media key held, such as 0xe9 for volume up, or 0xcd for
+/// play/pause.  Codes past [`CONSUMER_MAX`] can't be reported, and release the key instead.
+pub fn consumer_report(code: u16) -> [u8; 2] {
+    let code = if code > CONSUMER_MAX { 0 } else { code };
+    code.to_le_bytes()
+}
+
+/// Build a boot keyboard report.  If more than six keys are held, the report says so, rather than
+/// picking six of them.
+pub fn boot_report(mods: u8, keys: &[u8]) -> [u8; BOOT_REPORT_SIZE] {
+    let mut report = [0u8; BOOT_REPORT_SIZE];
+    let mut mods = mods;
+    let mut count = 0;
+    for &key in keys {
+        if key >= FIRST_MOD {
+            mods |= 1 << (key - FIRST_MOD);
+        } else {
+            if count < 6 {
+                report[2 + count] = key;
+            }
+            count += 1;
+        }
+    }
+    if count > 6 {
+        report[2..].fill(ERROR_ROLL_OVER);
+    }
+    report[0] = mods;
+    report
+}
+
+/// Build an NKRO keyboard report.
+pub fn nkro_report(mods: u8, keys: &[u8]) -> [u8; NKRO_REPORT_SIZE] {
+    let mut report = [0u8; NKRO_REPORT_SIZE];
+    let mut mods = mods;
+    for &key in keys {
+        if key >= FIRST_MOD {
+            mods |= 1 << (key - FIRST_MOD);
+        } else {
+            report[1 + key as usize / 8] |= 1 << (key % 8);
+        }
+    }
+    report[0] = mods;
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reports() {
+        // 'a' through 'h', and right shift.
+        let keys = [0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0xe5];
+
+        assert_eq!(boot_report(0x01, &keys[..6]), [0x01, 0, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09]);
+        assert_eq!(boot_report(0x01, &keys), [0x21, 0, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(boot_report(0, &keys[6..]), [0x20, 0, 0x0a, 0x0b, 0, 0, 0, 0]);
+
+        let report = nkro_report(0x01, &keys);
+        assert_eq!(report[0], 0x21);
+        assert_eq!(report[1], 0xf0);
+        assert_eq!(report[2], 0x0f);
+        assert!(report[3..].iter().all(|&b| b == 0));
+
+        // The last key that fits.
+        let report = nkro_report(0, &[0xdf]);
+        assert_eq!(report[NKRO_REPORT_SIZE - 1], 0x80);
+
+        // Media keys.
+        assert_eq!(consumer_report(0xe9), [0xe9, 0x00]);
+        assert_eq!(consumer_report(0x223), [0x23, 0x02]);
+        assert_eq!(consumer_report(0x400), [0, 0]);
+    }
+}
@@ -0,0 +1,359 @@
+//! Keymaps stored in flash.
+//!
+//! The qwerty layers are compiled in, but can be replaced by a keymap stored in its own flash
+//! partition (see [`minder::partition::KEYMAP`]).  The firmware loads the stored keymap at boot,
+//! and can switch between it and the built-in one while running, so keys can be remapped without
+//! rebuilding the firmware.
+//!
+//! A keymap is a list of layers, the first being the one used when no layer key is held.  Each
+//! layer has an entry for each scan code, followed by an entry for each combo.  A layer key names
+//! the layer used while it is held.  As layers are only ever entered from the first one, a layer
+//! can't shift to itself or an earlier layer.
+//!
+//! A tap-hold key types a key when tapped, but acts as modifiers or a layer key when held past the
+//! tapping term, or while another key is both pressed and released.
+//!
+//! A layer can also be latched: a toggle key keeps its layer on until the same key is pressed again,
+//! and a one-shot key applies its layer to just the next key pressed.  Keys can be latched the same
+//! way: a lock key stays held after it is tapped, until it is pressed again, such as for holding
+//! shift, or an arrow key in a game.
+//!
+//! Keys can also record and play keyboard macros (see [`crate::macros`]), turn on caps word,
+//! which shifts the letters of the next word, or send media keys, such as volume and play/pause.
+//!
+//! A keymap can also have overlays: named sets of changes to its layers, such as moving copy and
+//! paste onto the thumb keys for one application.  The host agent that tracks the focused
+//! application selects an overlay by name along with the dictionary profile, and the overlay then
+//! applies until another is selected, or the profile expires.
+//!
+//! Key overrides send a different key when a key is pressed with certain modifiers held, such as
+//! delete for shift and backspace.  They apply to whatever the layers resolve the key to.
+//!
+//! The chorded layouts (taipo and artsey) aren't remappable this way.
+//!
+//! With the `std` feature, keymaps can also be serialized with serde, so host tools can show and
+//! edit them as JSON.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use minicbor::{Decode, Encode};
+#[cfg(feature = "std")]
+use serde::{Deserialize, Serialize};
+
+use crate::hid::CONSUMER_MAX;
+use crate::layout::LAYER_LEN;
+use crate::log::warn;
+use crate::macros::MAX_MACROS;
+
+/// The most layers a keymap can have.
+pub const MAX_LAYERS: usize = 16;
+
+/// The most overlays a keymap can have.
+pub const MAX_OVERLAYS: usize = 8;
+
+/// The most key overrides a keymap can have.
+pub const MAX_OVERRIDES: usize = 16;
+
+/// Tag to recognize a stored keymap.
+pub const KEYMAP_TAG: u64 = 0x6b65796d6170;
+
+/// A full set of qwerty layers.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+#[cbor(tag(0x6b65796d6170))]
+pub struct Keymap {
+    /// A name, only used for reporting.
+    #[n(0)]
+    pub name: String,
+    /// The layers, the first being the base layer.
+    #[n(1)]
+    pub layers: Vec<Layer>,
+    /// How long, in ms, a tap-hold key has to be held to act as held.  None for the default.
+    #[n(2)]
+    pub tapping_term: Option<u16>,
+    /// Changes to the layers that can be selected by name.
+    #[n(3)]
+    pub overlays: Option<Vec<Overlay>>,
+    /// Keys that send something else while modifiers are held.
+    #[n(4)]
+    pub overrides: Option<Vec<KeyOverride>>,
+}
+
+/// A named set of changes to the layers of a keymap.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct Overlay {
+    #[n(0)]
+    pub name: String,
+    #[n(1)]
+    pub changes: Vec<KeyChange>,
+}
+
+/// A key that an overlay changes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct KeyChange {
+    /// The layer, by index.
+    #[n(0)]
+    pub layer: u8,
+    /// The scan code, or combo, within the layer.
+    #[n(1)]
+    pub key: u8,
+    #[n(2)]
+    pub def: KeyDef,
+}
+
+/// A key that sends another while modifiers are held.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct KeyOverride {
+    /// The modifiers (see [`crate::Mods`]) that have to be held.  Others can be held as well.
+    #[n(0)]
+    pub mods: u8,
+    /// The key, as a HID usage code.
+    #[n(1)]
+    pub code: u8,
+    /// The key sent instead, as a HID usage code.
+    #[n(2)]
+    pub send_code: u8,
+    /// The modifiers sent with it.  The modifiers that triggered the override are released while
+    /// it is sent, unless they are also given here.
+    #[n(3)]
+    pub send_mods: u8,
+}
+
+/// A single layer.  Missing entries at the end do nothing.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct Layer {
+    #[n(0)]
+    pub keys: Vec<KeyDef>,
+}
+
+/// What a single key does.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub enum KeyDef {
+    /// Nothing.
+    #[n(0)]
+    Dead,
+    /// A key, as a HID usage code, with modifiers (see [`crate::Mods`]).  A code of zero with
+    /// modifiers is a modifier key.
+    #[n(1)]
+    Key {
+        #[n(0)]
+        code: u8,
+        #[n(1)]
+        mods: u8,
+    },
+    /// Use another layer while this key is held.
+    #[n(2)]
+    Layer(#[n(0)] u8),
+    /// Mouse buttons (see [`crate::MouseButtons`]), held while this key is.
+    #[n(3)]
+    MouseButton(#[n(0)] u8),
+    /// Move the mouse pointer this far each step while this key is held.
+    #[n(4)]
+    MouseMove {
+        #[n(0)]
+        x: i8,
+        #[n(1)]
+        y: i8,
+    },
+    /// A key, as for [`KeyDef::Key`], when tapped, and the `hold` modifiers when held.
+    #[n(5)]
+    ModTap {
+        #[n(0)]
+        code: u8,
+        #[n(1)]
+        mods: u8,
+        #[n(2)]
+        hold: u8,
+    },
+    /// A key, as for [`KeyDef::Key`], when tapped, and another layer when held.
+    #[n(6)]
+    LayerTap {
+        #[n(0)]
+        code: u8,
+        #[n(1)]
+        mods: u8,
+        #[n(2)]
+        layer: u8,
+    },
+    /// Use another layer until this key is pressed again.
+    #[n(7)]
+    LayerToggle(#[n(0)] u8),
+    /// Use another layer for the next key pressed, or while this key is held, if another key is
+    /// pressed before it is released.
+    #[n(8)]
+    OneShotLayer(#[n(0)] u8),
+    /// Start recording a macro into this slot, or stop a recording in progress.
+    #[n(9)]
+    MacroRecord(#[n(0)] u8),
+    /// Play the macro in this slot.
+    #[n(10)]
+    MacroPlay(#[n(0)] u8),
+    /// Turn caps word on, or off.  While it is on, the letters of the next word are shifted.
+    #[n(11)]
+    CapsWord,
+    /// A media key, as a usage code on the consumer page, such as 0xe9 for volume up.  See
+    /// [`crate::hid::consumer_report`].
+    #[n(12)]
+    Consumer(#[n(0)] u16),
+    /// A key, as for [`KeyDef::Key`], that stays held once tapped, until it is pressed again.
+    #[n(13)]
+    KeyLock {
+        #[n(0)]
+        code: u8,
+        #[n(1)]
+        mods: u8,
+    },
+}
+
+impl Keymap {
+    /// The keymap built into the firmware.
+    pub fn builtin() -> Keymap {
+        crate::layout::builtin_keymap()
+    }
+
+    /// Decode a stored keymap.  Anything that doesn't decode (such as erased flash) is None.
+    pub fn decode(data: &[u8]) -> Option<Keymap> {
+        match minicbor::decode(data) {
+            Ok(keymap) => Some(keymap),
+            Err(e) => {
+                warn!("No stored keymap: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Check that the keymap can be used.  Returns a description of the first problem found.
+    pub fn check(&self) -> Result<(), String> {
+        check_layers(&self.layers)?;
+        let overlays = self.overlays.as_deref().unwrap_or_default();
+        if overlays.len() > MAX_OVERLAYS {
+            return Err(format!("Keymap has {} overlays, at most {} are allowed",
+                               overlays.len(), MAX_OVERLAYS));
+        }
+        for overlay in overlays {
+            for change in &overlay.changes {
+                if change.layer as usize >= self.layers.len() || change.key as usize >= LAYER_LEN {
+                    return Err(format!("Overlay {:?} changes key {} of layer {}, which isn't there",
+                                       overlay.name, change.key, change.layer));
+                }
+            }
+            check_layers(&self.overlaid(overlay))
+                .map_err(|e| format!("Overlay {:?}: {}", overlay.name, e))?;
+        }
+        let overrides = self.overrides.as_deref().unwrap_or_default();
+        if overrides.len() > MAX_OVERRIDES {
+            return Err(format!("Keymap has {} key overrides, at most {} are allowed",
+                               overrides.len(), MAX_OVERRIDES));
+        }
+        for over in overrides {
+            if over.mods == 0 || is_modifier(over.code) || is_modifier(over.send_code) {
+                return Err(format!("Key override of {:#04x} needs modifiers, and keys that aren't modifiers",
+                                   over.code));
+            }
+        }
+        Ok(())
+    }
+
+    /// Find an overlay by name.
+    pub fn overlay(&self, name: &str) -> Option<&Overlay> {
+        self.overlays.as_deref().unwrap_or_default().iter().find(|o| o.name == name)
+    }
+
+    /// Does any key, in a layer or an overlay, play the macro in this slot.
+    pub fn plays_macro(&self, slot: u8) -> bool {
+        let overlays = self.overlays.as_deref().unwrap_or_default();
+        self.layers.iter().flat_map(|layer| &layer.keys)
+            .chain(overlays.iter().flat_map(|overlay| overlay.changes.iter().map(|change| &change.def)))
+            .any(|def| *def == KeyDef::MacroPlay(slot))
+    }
+
+    /// The layers with an overlay's changes made.
+    pub fn overlaid(&self, overlay: &Overlay) -> Vec<Layer> {
+        let mut layers = self.layers.clone();
+        for change in &overlay.changes {
+            let Some(layer) = layers.get_mut(change.layer as usize) else {
+                continue;
+            };
+            let key = change.key as usize;
+            if key >= layer.keys.len() {
+                layer.keys.resize(key + 1, KeyDef::Dead);
+            }
+            layer.keys[key] = change.def;
+        }
+        layers
+    }
+
+    /// Encode the keymap, for storing.
+    pub fn encode(&self) -> Vec<u8> {
+        minicbor::to_vec(self).unwrap()
+    }
+}
+
+impl KeyDef {
+    /// The layer this key goes to, for the layer keys.
+    pub fn target(&self) -> Option<u8> {
+        match *self {
+            KeyDef::Layer(target)
+            | KeyDef::LayerTap { layer: target, .. }
+            | KeyDef::LayerToggle(target)
+            | KeyDef::OneShotLayer(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    /// The macro slot this key records or plays.
+    pub fn macro_slot(&self) -> Option<u8> {
+        match *self {
+            KeyDef::MacroRecord(slot) | KeyDef::MacroPlay(slot) => Some(slot),
+            _ => None,
+        }
+    }
+}
+
+/// Is this the HID usage code of a modifier key, or no key at all?
+fn is_modifier(code: u8) -> bool {
+    code == 0 || (0xe0..=0xe7).contains(&code)
+}
+
+/// Check the layers of a keymap, or of one of its overlays.
+fn check_layers(layers: &[Layer]) -> Result<(), String> {
+    let count = layers.len();
+    if count == 0 || count > MAX_LAYERS {
+        return Err(format!("Keymap has {} layers, must have 1 to {}", count, MAX_LAYERS));
+    }
+    for (index, layer) in layers.iter().enumerate() {
+        if layer.keys.len() > LAYER_LEN {
+            return Err(format!("Layer {} has {} keys, at most {} are used",
+                               index, layer.keys.len(), LAYER_LEN));
+        }
+        for target in layer.keys.iter().filter_map(KeyDef::target) {
+            if target as usize <= index || target as usize >= count {
+                return Err(format!("Layer {} has a layer key to {}, which must be a later layer",
+                                   index, target));
+            }
+        }
+        for slot in layer.keys.iter().filter_map(KeyDef::macro_slot) {
+            if slot as usize >= MAX_MACROS {
+                return Err(format!("Layer {} has a key for macro {}, there are only {}",
+                                   index, slot, MAX_MACROS));
+            }
+        }
+        for key in &layer.keys {
+            if let KeyDef::Consumer(code) = *key {
+                if code > CONSUMER_MAX {
+                    return Err(format!("Layer {} has media key {:#x}, past the last, {:#x}",
+                                       index, code, CONSUMER_MAX));
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -4,19 +4,62 @@
 //! - Steno dictionary conversion
 //! - All of the interaction between these.
 
-use crate::KeyEvent;
+extern crate alloc;
 
+use alloc::vec::Vec;
+
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::encoder::{EncoderAction, EncoderBinding};
+use crate::time::Duration;
+use crate::{KeyAction, KeyEvent, Mods};
+
+use self::idle::{Idle, IdleTimer};
+#[cfg(feature = "qwerty")]
 use self::qwerty::QwertyManager;
+#[cfg(feature = "qwerty")]
+pub(crate) use self::qwerty::{builtin_keymap, LAYER_LEN};
+#[cfg(feature = "steno")]
 use self::steno::RawStenoHandler;
+#[cfg(feature = "steno")]
+pub(crate) use self::steno::builtin_steno_map;
+#[cfg(feature = "taipo")]
 use self::taipo::TaipoManager;
 
+#[cfg(feature = "artsey")]
 mod artsey;
+mod automode;
+#[cfg(any(feature = "qwerty", feature = "taipo"))]
+mod capsword;
+#[cfg(feature = "experimental")]
+pub mod experimental;
+mod idle;
+#[cfg(feature = "numpad")]
+mod numpad;
+#[cfg(feature = "qwerty")]
+mod overrides;
+#[cfg(feature = "qwerty")]
 mod qwerty;
+#[cfg(feature = "steno")]
 mod steno;
+#[cfg(feature = "taipo")]
 mod taipo;
 
+#[cfg(not(any(
+    feature = "steno",
+    feature = "artsey",
+    feature = "taipo",
+    feature = "qwerty",
+    feature = "numpad"
+)))]
+compile_error!("At least one layout mode feature must be enabled");
+
+/// The default mode key.  Held, it cycles through modes, and with the mode chords, selects one.
 const MODE_KEY: u8 = 2;
 
+/// Holding the mode key with qwerty 'a' and ';' kills output that is still being typed.
+const KILL_CHORD: u64 = (1 << 5) | (1 << 29);
+
 // Keyboards are complicated things, and small keyboards are even more
 // complicated. We support numerous different ways of seeing the keyboard, ways
 // that are traditionally called "layers" in keyboard firmware. That term isn't
@@ -66,6 +109,8 @@ mod async_traits {
 
     use bbq_steno::Stroke;
 
+    use crate::backlight::KeyClass;
+    use crate::macros::StoredMacros;
     use crate::{KeyAction, MinorMode};
 
     use super::LayoutMode;
@@ -95,23 +140,63 @@ mod async_traits {
 
         /// Send a RawSteno stroke.
         async fn send_raw_steno(&self, stroke: Stroke);
+
+        /// The keys of a steno stroke that is still being pressed.  This allows the lookup to be
+        /// started before the stroke is complete.  It is fine to ignore this.
+        async fn prepare_steno(&self, _stroke: Stroke) {}
+
+        /// Stop typing anything still queued, such as the rest of a long translation.  Sent when
+        /// the kill chord is pressed during mode select.
+        async fn kill_output(&self) {}
+
+        /// What each key does in the layer now in use, by scan code, for a per-key backlight.
+        /// Sent when the layer changes, and empty when leaving a mode with layers.
+        async fn set_key_classes(&self, _keys: &[KeyClass]) {}
+
+        /// Type some text, such as a result from the calculator.  This goes through the same
+        /// typer as steno output, so it is typed for the host's layout.
+        async fn type_text(&self, _text: &str) {}
+
+        /// A macro recording has finished.  These are all of the macros, to be stored so they can
+        /// be loaded again at boot.
+        async fn save_macros(&self, _macros: &StoredMacros) {}
     }
 }
 pub use async_traits::LayoutActions;
+pub use automode::AutoMode;
+
+/// The consumer usage codes of the volume keys.
+const CONSUMER_VOLUME_UP: u16 = 0xe9;
+const CONSUMER_VOLUME_DOWN: u16 = 0xea;
 
 /// The layout manager.
 ///
-/// Some of the entrypoints take an EventQueue.  In the process of gradually separating out the
-/// events, the LayoutManager only sends the following events:
+/// All of the layout modes report what they do through [`LayoutActions`]:
 /// - Mode
 /// - ModeSelect
 /// - KeyAction
+/// - SubMode
 /// - RawSteno
+/// - PrepareSteno
+/// - KillOutput
+/// - KeyClasses
+/// - TypeText
+///
+/// As these are async, a handler that can't keep up (such as a full HID queue) can apply
+/// backpressure by simply not completing until there is room.
 pub struct LayoutManager {
+    #[cfg(feature = "steno")]
     raw: steno::RawStenoHandler,
+    #[cfg(feature = "artsey")]
     artsey: artsey::ArtseyManager,
+    #[cfg(feature = "qwerty")]
     qwerty: qwerty::QwertyManager,
+    #[cfg(feature = "taipo")]
     taipo: taipo::TaipoManager,
+    #[cfg(feature = "numpad")]
+    numpad: numpad::NumpadManager,
+    #[cfg(feature = "experimental")]
+    experiments: experimental::Experiments,
 
     // Global mode.  This indicates what mode we are in.
     mode: ModeSelector,
@@ -121,62 +206,327 @@ pub struct LayoutManager {
 
     // Flag indicating this is a two-row keyboard.  Skips qwerty mode when selected.
     two_row: bool,
+
+    // A mode change requested from outside, applied once all keys are released.
+    requested: Option<LayoutMode>,
+
+    // The mode we start in, and return to after being idle.
+    default: LayoutMode,
+
+    // Tracks how long since a key was touched.
+    idle: IdleTimer,
+
+    // What the rotary encoder does in each mode.
+    encoder: Vec<EncoderBinding>,
+
+    // The qwerty layer last reported through `set_key_classes`, None outside of qwerty mode.
+    #[cfg(feature = "qwerty")]
+    shown_layer: Option<usize>,
 }
 
 impl LayoutManager {
     pub fn new(two_row: bool) -> Self {
+        let mode = ModeSelector::new(two_row);
         LayoutManager {
+            #[cfg(feature = "steno")]
             raw: RawStenoHandler::new(),
+            #[cfg(feature = "artsey")]
             artsey: artsey::ArtseyManager::default(),
-            mode: ModeSelector::new(two_row),
+            default: mode.get(),
+            mode,
+            #[cfg(feature = "qwerty")]
             qwerty: QwertyManager::default(),
+            #[cfg(feature = "taipo")]
             taipo: TaipoManager::default(),
+            #[cfg(feature = "numpad")]
+            numpad: numpad::NumpadManager::default(),
+            #[cfg(feature = "experimental")]
+            experiments: experimental::Experiments::default(),
             first_tick: true,
             two_row,
+            requested: None,
+            idle: IdleTimer::new(),
+            encoder: Vec::new(),
+            #[cfg(feature = "qwerty")]
+            shown_layer: None,
+        }
+    }
+
+    /// Return to the default mode after no keys have been touched for `timeout`.  None, or zero,
+    /// never times out.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle.set_timeout(timeout);
+    }
+
+    /// Use a keymap for qwerty mode, or the built-in one with None.  Returns false if the keymap
+    /// isn't valid, in which case the current one is kept.
+    #[cfg(feature = "qwerty")]
+    pub fn set_keymap(&mut self, keymap: Option<&crate::keymap::Keymap>) -> bool {
+        self.qwerty.set_keymap(keymap)
+    }
+
+    /// Select one of the qwerty keymap's overlays by name, or none.  The change happens once no
+    /// keys are held.
+    #[cfg(feature = "qwerty")]
+    pub fn set_overlay(&mut self, name: Option<&str>) {
+        self.qwerty.set_overlay(name)
+    }
+
+    /// Use macros stored in flash for qwerty mode.  Returns false if they can't be used.
+    #[cfg(feature = "qwerty")]
+    pub fn set_macros(&mut self, macros: &crate::macros::StoredMacros) -> bool {
+        self.qwerty.set_macros(macros)
+    }
+
+    /// Use a steno map for the steno modes, or the built-in one with None.  Returns false if the
+    /// map isn't valid, in which case the current one is kept.
+    #[cfg(feature = "steno")]
+    pub fn set_steno_map(&mut self, map: Option<&crate::stenomap::StenoMap>) -> bool {
+        self.raw.set_map(map)
+    }
+
+    /// Send each steno stroke as soon as the first key of it is released (first up, the default),
+    /// or only once all of them are (last up).
+    #[cfg(feature = "steno")]
+    pub fn set_steno_first_up(&mut self, first_up: bool) {
+        self.raw.set_first_up(first_up)
+    }
+
+    /// Use a different key as the mode key, for boards where the default key is awkward, or
+    /// missing.
+    pub fn set_mode_key(&mut self, key: u8) {
+        if key < 64 {
+            self.mode.mode_key = key;
+        }
+    }
+
+    /// Select `mode` when the `keys` (scan codes) are pressed along with the mode key.  These are
+    /// checked before the built-in chords, so can replace them.  Returns false, and ignores the
+    /// chord, if the keys aren't a usable chord, or the mode isn't built in.
+    pub fn add_mode_chord(&mut self, keys: &[u8], mode: LayoutMode) -> bool {
+        let mut mask = 0u64;
+        for &key in keys {
+            if key >= 64 || key == self.mode.mode_key {
+                return false;
+            }
+            mask |= 1 << key;
+        }
+        if mask == 0 || mask == KILL_CHORD || !mode.is_enabled() {
+            return false;
+        }
+        self.mode.chords.retain(|(k, _)| *k != mask);
+        self.mode.chords.push((mask, mode));
+        true
+    }
+
+    /// Add an experimental mode, as [`LayoutMode::Experimental`] with the given id.  Returns false
+    /// if the id isn't one reserved for experiments, or the name is already in use.  See
+    /// [`experimental`], and [`register_experiments`](crate::register_experiments), which checks
+    /// the ids when building.
+    #[cfg(feature = "experimental")]
+    pub fn register_experiment<E: experimental::Experiment + 'static>(
+        &mut self,
+        id: u8,
+        info: &'static experimental::ExperimentInfo,
+        experiment: E,
+    ) -> bool {
+        self.experiments.register(id, info, experiment)
+    }
+
+    /// Change a setting of an experimental mode, given as `name.key`, with the mode's name.
+    /// Returns false if there is no such mode, or it doesn't know the setting.
+    #[cfg(feature = "experimental")]
+    pub fn experiment_setting(&mut self, setting: &str, value: &str) -> bool {
+        self.experiments.set(setting, value)
+    }
+
+    /// Remove any chords added with [`add_mode_chord`](Self::add_mode_chord).
+    pub fn clear_mode_chords(&mut self) {
+        self.mode.chords.clear();
+    }
+
+    /// Set what the rotary encoder does in each mode, replacing any earlier bindings.  Modes
+    /// without a binding use the one without a mode, if given, and otherwise change the volume.
+    pub fn set_encoder(&mut self, bindings: &[EncoderBinding]) {
+        self.encoder = bindings.to_vec();
+    }
+
+    /// The encoder's action in the current mode.
+    fn encoder_action(&self) -> EncoderAction {
+        let name = self.mode.get().name();
+        let find = |mode: Option<&str>| {
+            self.encoder.iter().rev().find(|b| b.mode.as_deref() == mode)
+        };
+        find(Some(name)).or_else(|| find(None)).map(|b| b.action.clone()).unwrap_or_default()
+    }
+
+    /// Is this a two-row keyboard.
+    pub fn is_two_row(&self) -> bool {
+        self.two_row
+    }
+
+    /// Request a change to the given mode.  This is deferred until no keys are pressed, so that
+    /// nothing is left held down in the old mode.  Modes that aren't built in are ignored.
+    pub fn request_mode(&mut self, mode: LayoutMode) {
+        if mode.is_enabled() {
+            self.requested = Some(mode);
         }
     }
 
     // For now, just pass everything through.
-    pub async fn tick<ACT: LayoutActions>(&mut self, actions: &ACT, ticks: usize) {
-        self.raw.tick(ticks);
-        self.artsey.tick(actions, ticks).await;
-        self.qwerty.tick(actions, ticks).await;
-        self.taipo.tick(actions, ticks).await;
+    pub async fn tick<ACT: LayoutActions>(&mut self, actions: &ACT, elapsed: Duration) {
+        #[cfg(feature = "steno")]
+        self.raw.tick(elapsed);
+        #[cfg(feature = "artsey")]
+        self.artsey.tick(actions, elapsed).await;
+        #[cfg(feature = "qwerty")]
+        self.qwerty.tick(actions, elapsed).await;
+        #[cfg(feature = "taipo")]
+        self.taipo.tick(actions, elapsed).await;
+        #[cfg(feature = "experimental")]
+        self.experiments.tick(actions, elapsed).await;
 
         // Inform the upper layer what our initial mode is.
         if self.first_tick {
             actions.set_mode(self.mode.get()).await;
             self.first_tick = false;
         }
-    }
 
-    pub fn poll(&mut self) {
-        self.raw.poll();
-        self.artsey.poll();
-        self.taipo.poll();
+        if let Some(mode) = self.requested {
+            if self.mode.is_idle() {
+                self.requested = None;
+                if mode != self.mode.get() {
+                    self.mode.mode = mode;
+                    self.idle.activity();
+                    actions.set_mode(mode).await;
+                }
+            }
+        }
+
+        // Keys still held down (something resting on the keyboard) don't count as idle.
+        if self.mode.get() != self.default && self.mode.is_idle() {
+            match self.idle.tick(elapsed) {
+                Some(Idle::Warn) => actions.set_mode_select(self.default).await,
+                Some(Idle::Expire) => {
+                    self.mode.mode = self.default;
+                    actions.set_mode(self.default).await;
+                }
+                None => (),
+            }
+        }
+
+        #[cfg(feature = "qwerty")]
+        self.show_layer(actions).await;
     }
 
     /// Handle a single key event.
     pub async fn handle_event<ACT: LayoutActions>(&mut self, event: KeyEvent, actions: &ACT) {
+        // Touching a key during the idle warning keeps the current mode.
+        if self.idle.activity() {
+            actions.set_mode(self.mode.get()).await;
+        }
+
         if self.mode.event(event, actions, self.two_row).await {
+            // The mode selector never lands on a mode that isn't built in.
+            #[allow(unreachable_patterns)]
             match self.mode.get() {
+                #[cfg(feature = "artsey")]
                 LayoutMode::Artsey => {
                     self.artsey.handle_event(event, actions).await;
                 }
+                #[cfg(feature = "taipo")]
                 LayoutMode::Taipo => {
                     self.taipo.handle_event(event, actions).await;
                 }
+                #[cfg(feature = "steno")]
                 LayoutMode::Steno | LayoutMode::StenoDirect => {
                     self.raw.handle_event(event, actions).await;
                 }
+                #[cfg(feature = "qwerty")]
                 LayoutMode::Qwerty => {
                     self.qwerty.handle_event(event, actions, false).await;
                 }
+                #[cfg(feature = "qwerty")]
                 LayoutMode::NKRO => {
                     self.qwerty.handle_event(event, actions, true).await;
                 }
+                #[cfg(feature = "numpad")]
+                LayoutMode::Numpad => {
+                    self.numpad.handle_event(event, actions).await;
+                }
+                #[cfg(feature = "experimental")]
+                LayoutMode::Experimental(id) => {
+                    self.experiments.handle_event(id, event, actions).await;
+                }
+                _ => (),
             }
         }
+
+        // The calculator starts over each time the mode is entered.
+        #[cfg(feature = "numpad")]
+        if self.mode.get() != LayoutMode::Numpad {
+            self.numpad.clear();
+        }
+        #[cfg(feature = "experimental")]
+        self.experiments.reset_others(match self.mode.get() {
+            LayoutMode::Experimental(id) => Some(id),
+            _ => None,
+        });
+
+        #[cfg(feature = "qwerty")]
+        self.show_layer(actions).await;
+    }
+
+    /// Handle the rotary encoder being turned `delta` detents, positive for clockwise, with the
+    /// current mode's action.  Keys are tapped once per detent.  Mode changes are requested as with
+    /// [`request_mode`](Self::request_mode), so happen once no keys are held.
+    pub async fn handle_encoder<ACT: LayoutActions>(&mut self, delta: i8, actions: &ACT) {
+        if delta == 0 {
+            return;
+        }
+        if self.idle.activity() {
+            actions.set_mode(self.mode.get()).await;
+        }
+
+        // The press, and release, of the key to tap for each detent.
+        let (press, release) = match self.encoder_action() {
+            EncoderAction::None => return,
+            EncoderAction::Volume => {
+                let code = if delta > 0 { CONSUMER_VOLUME_UP } else { CONSUMER_VOLUME_DOWN };
+                (KeyAction::Consumer(code), KeyAction::Consumer(0))
+            }
+            EncoderAction::Keys { cw, ccw } => {
+                let key = Keyboard::from(if delta > 0 { cw } else { ccw });
+                (KeyAction::KeyPress(key, Mods::empty()), KeyAction::KeyRelease)
+            }
+            EncoderAction::Modes => {
+                let mut mode = self.requested.unwrap_or(self.mode.get());
+                for _ in 0..delta.unsigned_abs() {
+                    mode = if delta > 0 { mode.next(self.two_row) } else { mode.prev(self.two_row) };
+                }
+                self.request_mode(mode);
+                return;
+            }
+        };
+        for _ in 0..delta.unsigned_abs() {
+            actions.send_key(press.clone()).await;
+            actions.send_key(release.clone()).await;
+        }
+    }
+
+    /// Report the qwerty layer in use, when it changes, or when qwerty mode is entered or left.
+    #[cfg(feature = "qwerty")]
+    async fn show_layer<ACT: LayoutActions>(&mut self, actions: &ACT) {
+        let layer = (self.mode.get() == LayoutMode::Qwerty).then(|| self.qwerty.current_layer());
+        if layer == self.shown_layer {
+            return;
+        }
+        self.shown_layer = layer;
+        if layer.is_some() {
+            actions.set_key_classes(&self.qwerty.key_classes()).await;
+        } else {
+            actions.set_key_classes(&[]).await;
+        }
     }
 }
 
@@ -189,6 +539,9 @@ pub enum LayoutMode {
     Taipo,
     Qwerty,
     NKRO,
+    Numpad,
+    /// A mode registered by an experiment, by its id.  See [`experimental`].
+    Experimental(u8),
 }
 
 impl Default for LayoutMode {
@@ -211,16 +564,33 @@ struct ModeSelector {
 
     /// When we see the layout mode, pressed keys will register here.
     seen: u64,
+
+    /// The mode before selecting started, restored if the selection was the kill chord.
+    prior: LayoutMode,
+
+    /// The kill chord was pressed during this selection.
+    killed: bool,
+
+    /// The key that starts selecting.
+    mode_key: u8,
+
+    /// User chords, as a mask of keys, and the mode they select.
+    chords: Vec<(u64, LayoutMode)>,
 }
 
 impl ModeSelector {
     fn new(two_row: bool) -> Self {
         let mode = if two_row { LayoutMode::Taipo } else { LayoutMode::Qwerty };
+        let mode = if mode.is_enabled() { mode } else { mode.next(two_row) };
         ModeSelector {
             mode,
             selecting: false,
             pressed: 0,
             seen: 0,
+            prior: mode,
+            killed: false,
+            mode_key: MODE_KEY,
+            chords: Vec::new(),
         }
     }
 
@@ -229,6 +599,11 @@ impl ModeSelector {
         self.mode
     }
 
+    /// Are no keys pressed, and we aren't in the middle of selecting.
+    fn is_idle(&self) -> bool {
+        !self.selecting && self.pressed == 0
+    }
+
     /// Handle a keyevent, and return 'true' if the key even should be passed down to lower layers.
     async fn event<ACT: LayoutActions>(&mut self, event: KeyEvent, actions: &ACT, two_row: bool) -> bool {
         // Update the mask of keys that have been pressed.
@@ -238,11 +613,14 @@ impl ModeSelector {
         }
 
         // If we've pressed the mode selector, enter the funny mode.
-        if let KeyEvent::Press(MODE_KEY) = event {
+        if event == KeyEvent::Press(self.mode_key) {
             // Only do something here if either we are selecting, or no other
             // keys have been pressed.
-            if self.selecting || (self.pressed & !(1 << (MODE_KEY as usize))) == 0 {
+            if self.selecting || (self.pressed & !(1 << self.mode_key)) == 0 {
                 // Toggle the mode.
+                if !self.selecting {
+                    self.prior = self.mode;
+                }
                 self.mode = self.mode.next(two_row);
                 self.selecting = true;
                 actions.set_mode_select(self.mode).await;
@@ -254,9 +632,19 @@ impl ModeSelector {
             // Merge in any keys seen.
             self.seen |= self.pressed;
 
+            // The kill chord acts as soon as it is pressed, as the output may still be going.
+            if !self.killed && self.seen & !(1 << self.mode_key) == KILL_CHORD {
+                self.killed = true;
+                actions.kill_output().await;
+            }
+
             // When evertything is released, pick our next mode.
             if self.pressed == 0 {
-                if let Some(new_mode) = self.new_mode(two_row) {
+                if self.killed {
+                    // Killing the output leaves the mode as it was.
+                    self.mode = self.prior;
+                    self.killed = false;
+                } else if let Some(new_mode) = self.new_mode(two_row) {
                     self.mode = new_mode;
                 }
 
@@ -285,7 +673,12 @@ impl ModeSelector {
     /// Determine if there is a mode update based on pressed keys while selecting.
     /// TODO: These are based on the 3-row keyboard.
     fn new_mode(&self, two_row: bool) -> Option<LayoutMode> {
-        match self.seen & !(1 << (MODE_KEY)) {
+        let keys = self.seen & !(1 << self.mode_key);
+        if let Some(&(_, mode)) = self.chords.iter().find(|(k, _)| *k == keys) {
+            return Some(mode);
+        }
+
+        let mode = match keys {
             // qwerty 'f' or 'j' select qwerty.
             m if m == (1 << 17) || m == (1 << 41) => {
                 if two_row {
@@ -298,14 +691,101 @@ impl ModeSelector {
             m if m == (1 << 13) || m == (1 << 37) => Some(LayoutMode::StenoDirect),
             // qwerty 's' or 'l' select steno raw.
             m if m == (1 << 9) || m == (1 << 33) => Some(LayoutMode::Steno),
+            // qwerty 'g' or 'h' select the number pad.
+            m if m == (1 << 21) || m == (1 << 45) => Some(LayoutMode::Numpad),
             _ => None,
-        }
+        };
+        mode.filter(|m| m.is_enabled())
     }
 }
 
 impl LayoutMode {
-    /// Move to the next mode.
+    /// All of the built-in modes.
+    pub const ALL: [LayoutMode; 7] = [
+        LayoutMode::Steno,
+        LayoutMode::StenoDirect,
+        LayoutMode::Artsey,
+        LayoutMode::Taipo,
+        LayoutMode::Qwerty,
+        LayoutMode::NKRO,
+        LayoutMode::Numpad,
+    ];
+
+    /// A short name for the mode, used in reports and by the debug console.
+    pub fn name(self) -> &'static str {
+        match self {
+            LayoutMode::Steno => "steno",
+            LayoutMode::StenoDirect => "steno-direct",
+            LayoutMode::Artsey => "artsey",
+            LayoutMode::Taipo => "taipo",
+            LayoutMode::Qwerty => "qwerty",
+            LayoutMode::NKRO => "nkro",
+            LayoutMode::Numpad => "numpad",
+            #[cfg(feature = "experimental")]
+            LayoutMode::Experimental(id) => {
+                experimental::info(id).map_or("experimental", |info| info.name)
+            }
+            #[cfg(not(feature = "experimental"))]
+            LayoutMode::Experimental(_) => "experimental",
+        }
+    }
+
+    /// Look up a mode by its [`name`](Self::name).  Only modes built into this firmware are
+    /// found.
+    pub fn from_name(name: &str) -> Option<LayoutMode> {
+        #[cfg(feature = "experimental")]
+        if let Some(id) = experimental::find(name) {
+            return Some(LayoutMode::Experimental(id));
+        }
+        Self::ALL.into_iter().find(|m| m.name() == name && m.is_enabled())
+    }
+
+    /// Is the support for this mode built in.  Experimental modes also have to be registered.
+    pub fn is_enabled(self) -> bool {
+        match self {
+            LayoutMode::Steno | LayoutMode::StenoDirect => cfg!(feature = "steno"),
+            LayoutMode::Artsey => cfg!(feature = "artsey"),
+            LayoutMode::Taipo => cfg!(feature = "taipo"),
+            LayoutMode::Qwerty | LayoutMode::NKRO => cfg!(feature = "qwerty"),
+            LayoutMode::Numpad => cfg!(feature = "numpad"),
+            #[cfg(feature = "experimental")]
+            LayoutMode::Experimental(id) => experimental::info(id).is_some(),
+            #[cfg(not(feature = "experimental"))]
+            LayoutMode::Experimental(_) => false,
+        }
+    }
+
+    /// Move to the next mode, skipping any that aren't built in.
     fn next(self, two_row: bool) -> Self {
+        let mut mode = self;
+        for _ in 0..Self::ALL.len() {
+            mode = mode.cycle(two_row);
+            if mode.is_enabled() {
+                return mode;
+            }
+        }
+
+        // The cycle doesn't reach any built in mode (such as only artsey), just use the first one
+        // we have.
+        Self::ALL.into_iter().find(|m| m.is_enabled()).unwrap()
+    }
+
+    /// Move to the previous mode, the one that [`next`](Self::next) would move from to reach this
+    /// one.  Modes that are only entered directly have none, and move on as with `next`.
+    fn prev(self, two_row: bool) -> Self {
+        let mut mode = self.next(two_row);
+        for _ in 0..Self::ALL.len() {
+            let next = mode.next(two_row);
+            if next == self {
+                return mode;
+            }
+            mode = next;
+        }
+        self.next(two_row)
+    }
+
+    /// The next mode in the cycle, when all modes are present.
+    fn cycle(self, two_row: bool) -> Self {
         if two_row {
             match self {
                 // Direct cycling is between these modes.
@@ -317,6 +797,8 @@ impl LayoutMode {
                 LayoutMode::StenoDirect => LayoutMode::Taipo,
                 LayoutMode::Artsey => LayoutMode::Qwerty,
                 LayoutMode::NKRO => LayoutMode::Steno,
+                LayoutMode::Numpad => LayoutMode::Qwerty,
+                LayoutMode::Experimental(_) => LayoutMode::Steno,
             }
         } else {
             match self {
@@ -329,6 +811,8 @@ impl LayoutMode {
                 // These move to another mode, but can only be entered directly.
                 LayoutMode::Artsey => LayoutMode::Qwerty,
                 LayoutMode::NKRO => LayoutMode::Steno,
+                LayoutMode::Numpad => LayoutMode::Qwerty,
+                LayoutMode::Experimental(_) => LayoutMode::Qwerty,
             }
         }
     }
@@ -344,6 +828,94 @@ impl defmt::Format for LayoutMode {
             LayoutMode::Qwerty => defmt::write!(fmt, "qwerty"),
             LayoutMode::NKRO => defmt::write!(fmt, "nkro"),
             LayoutMode::Taipo => defmt::write!(fmt, "taipo"),
+            LayoutMode::Numpad => defmt::write!(fmt, "numpad"),
+            LayoutMode::Experimental(id) => defmt::write!(fmt, "experimental {}", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{run, Recorder};
+
+    /// Press the mode key, then the chord, and release them all.  Returns the mode selected.
+    fn select(layout: &mut LayoutManager, mode_key: u8, keys: &[u8]) -> Option<LayoutMode> {
+        let rec = Recorder::default();
+        run(layout.handle_event(KeyEvent::Press(mode_key), &rec));
+        for &key in keys {
+            run(layout.handle_event(KeyEvent::Press(key), &rec));
         }
+        for &key in keys {
+            run(layout.handle_event(KeyEvent::Release(key), &rec));
+        }
+        run(layout.handle_event(KeyEvent::Release(mode_key), &rec));
+        rec.mode.get()
+    }
+
+    #[test]
+    fn test_mode_chords() {
+        let mut layout = LayoutManager::new(false);
+
+        // The built-in chords.
+        assert_eq!(select(&mut layout, MODE_KEY, &[9]), Some(LayoutMode::Steno));
+        assert_eq!(select(&mut layout, MODE_KEY, &[17]), Some(LayoutMode::Qwerty));
+
+        // A new chord, and one replacing a built-in one.
+        assert!(layout.add_mode_chord(&[20, 21], LayoutMode::Taipo));
+        assert!(layout.add_mode_chord(&[9], LayoutMode::NKRO));
+        assert_eq!(select(&mut layout, MODE_KEY, &[20, 21]), Some(LayoutMode::Taipo));
+        assert_eq!(select(&mut layout, MODE_KEY, &[9]), Some(LayoutMode::NKRO));
+
+        // Chords that can't work.
+        assert!(!layout.add_mode_chord(&[], LayoutMode::Steno));
+        assert!(!layout.add_mode_chord(&[MODE_KEY, 9], LayoutMode::Steno));
+        assert!(!layout.add_mode_chord(&[64], LayoutMode::Steno));
+        assert!(!layout.add_mode_chord(&[5, 29], LayoutMode::Steno));
+
+        // Moving the mode key.
+        layout.set_mode_key(1);
+        assert_eq!(select(&mut layout, 1, &[13]), Some(LayoutMode::StenoDirect));
+
+        layout.clear_mode_chords();
+        assert_eq!(select(&mut layout, 1, &[9]), Some(LayoutMode::Steno));
+    }
+
+    #[test]
+    fn test_encoder() {
+        use crate::encoder::{EncoderAction, EncoderBinding};
+        use alloc::string::ToString;
+
+        let mut layout = LayoutManager::new(false);
+        let rec = Recorder::default();
+        let tap = |key| [KeyAction::KeyPress(key, Mods::empty()), KeyAction::KeyRelease];
+
+        // Volume, without any bindings.
+        run(layout.handle_encoder(2, &rec));
+        run(layout.handle_encoder(-1, &rec));
+        let volume = |code| [KeyAction::Consumer(code), KeyAction::Consumer(0)];
+        let expected = [volume(CONSUMER_VOLUME_UP), volume(CONSUMER_VOLUME_UP), volume(CONSUMER_VOLUME_DOWN)];
+        assert_eq!(*rec.keys.borrow(), expected.concat());
+
+        // A binding for the mode in use overrides the one for other modes.
+        rec.keys.borrow_mut().clear();
+        layout.set_encoder(&[
+            EncoderBinding { mode: None, action: EncoderAction::Modes },
+            EncoderBinding {
+                mode: Some(LayoutMode::Qwerty.name().to_string()),
+                action: EncoderAction::Keys { cw: 0x4f, ccw: 0x50 },
+            },
+        ]);
+        run(layout.handle_encoder(-1, &rec));
+        assert_eq!(*rec.keys.borrow(), tap(Keyboard::LeftArrow));
+
+        // Cycling modes, once no keys are held.
+        layout.set_encoder(&[EncoderBinding { mode: None, action: EncoderAction::Modes }]);
+        run(layout.handle_encoder(1, &rec));
+        run(layout.tick(&rec, Duration::from_millis(1)));
+        assert_eq!(rec.mode.get(), Some(LayoutMode::Steno));
+        run(layout.handle_encoder(-1, &rec));
+        run(layout.tick(&rec, Duration::from_millis(1)));
+        assert_eq!(rec.mode.get(), Some(LayoutMode::Qwerty));
     }
 }
@@ -28,16 +28,20 @@ use usbd_human_interface_device::page::Keyboard;
 
 // use crate::log::info;
 
+use crate::time::Duration;
 use crate::{KeyEvent, KeyAction, Mods, MinorMode};
 
 use super::LayoutActions;
 
+/// Keys that go down within this time of each other are pressed together.
+const CHORD_TIME: Duration = Duration::from_millis(50);
+
 pub struct ArtseyManager {
     // Keys that are currently down.
     pressed: u8,
 
     // Time since we last saw keys go down.
-    age: u32,
+    age: Duration,
 
     // Has a keydown been sent (regular, not locking).
     down: bool,
@@ -403,7 +407,7 @@ impl Default for ArtseyManager {
     fn default() -> Self {
         ArtseyManager {
             seen: 0,
-            age: 0,
+            age: Duration::ZERO,
             down: false,
             pressed: 0,
             oneshot: Mods::empty(),
@@ -419,20 +423,16 @@ impl Default for ArtseyManager {
 }
 
 impl ArtseyManager {
-    /// Poll doesn't do anything.
-    pub fn poll(&mut self) {
-    }
-
     /// Tick is needed to track time for determining time.
-    pub async fn tick<ACT: LayoutActions>(&mut self, actions: &ACT, ticks: usize) {
+    pub async fn tick<ACT: LayoutActions>(&mut self, actions: &ACT, elapsed: Duration) {
         // If we've seen keys, bump the age, and then when they have been down
         // sufficiently long to be considered together, process them as a send
         // event.
         if self.pressed != 0 {
-            self.age = self.age.saturating_add(ticks as u32);
+            self.age += elapsed;
         }
 
-        if self.seen != 0 && self.age >= 50 {
+        if self.seen != 0 && self.age >= CHORD_TIME {
             // If we have a 'seen' value, and suffient age, and we aren't in a
             // special mode, then activate the special mode.
             if self.hold_mode == 0 {
@@ -523,7 +523,7 @@ impl ArtseyManager {
                 let code = to_artsey(k);
                 self.pressed |= code;
                 self.seen |= code;
-                self.age = 0;
+                self.age = Duration::ZERO;
             }
             KeyEvent::Release(k) => {
                 let code = to_artsey(k);
@@ -0,0 +1,123 @@
+//! Automatic mode selection.
+//!
+//! Watch what the host is doing, and use that to pick a mode.  If the host has a steno program
+//! connected (Plover has the HID interface open, or something has asserted DTR on the Gemini
+//! serial port), we switch to StenoDirect.  When that goes away, we go back to the regular
+//! keyboard mode.
+//!
+//! The host state has to be stable for a while before we act on it, so that a program briefly
+//! opening and closing the port doesn't cause the mode to bounce around.  We also only act on
+//! changes in the host state, so a mode selected by hand sticks until the host does something
+//! different.
+//!
+//! Plover closing the HID interface can only be seen as reports going unread, so the firmware
+//! probes it with empty reports while no strokes are sent.  Strokes made in the moment between
+//! Plover closing and that being noticed, and during the settling time after, are still sent as
+//! Plover reports, and lost.
+
+use super::LayoutMode;
+use crate::time::Duration;
+
+/// How long the host state must be stable before we change modes.
+const SETTLE: Duration = Duration::from_millis(1500);
+
+pub struct AutoMode {
+    /// Is automatic selection enabled.
+    enabled: bool,
+
+    /// The host state that we've most recently acted on.  None until the first state settles.
+    current: Option<bool>,
+
+    /// The state we're seeing now, and how long it has been seen.
+    pending: bool,
+    pending_time: Duration,
+}
+
+impl AutoMode {
+    pub fn new(enabled: bool) -> Self {
+        AutoMode {
+            enabled,
+            current: None,
+            pending: false,
+            pending_time: Duration::ZERO,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Update with the current host state, `steno_host` indicating that a steno program seems to
+    /// be connected.  `elapsed` is the time since the last update.  Returns a mode to switch to,
+    /// if the settled host state has changed.
+    pub fn update(&mut self, steno_host: bool, elapsed: Duration, two_row: bool) -> Option<LayoutMode> {
+        if steno_host != self.pending {
+            self.pending = steno_host;
+            self.pending_time = Duration::ZERO;
+            return None;
+        }
+
+        if self.pending_time < SETTLE {
+            self.pending_time += elapsed;
+            if self.pending_time < SETTLE {
+                return None;
+            }
+        }
+
+        if self.current == Some(steno_host) {
+            return None;
+        }
+        let first = self.current.is_none();
+        self.current = Some(steno_host);
+
+        if !self.enabled {
+            return None;
+        }
+
+        match (steno_host, first) {
+            (true, _) => Some(LayoutMode::StenoDirect),
+            // At startup, we're already in the default mode.
+            (false, true) => None,
+            (false, false) if two_row => Some(LayoutMode::Taipo),
+            (false, false) => Some(LayoutMode::Qwerty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(auto: &mut AutoMode, steno_host: bool, ms: u64) -> Option<LayoutMode> {
+        let mut result = None;
+        for _ in 0..ms / 10 {
+            if let Some(mode) = auto.update(steno_host, Duration::from_millis(10), false) {
+                assert!(result.is_none());
+                result = Some(mode);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_auto_mode() {
+        let mut auto = AutoMode::new(true);
+
+        // Nothing happens at startup without a host.
+        assert_eq!(run(&mut auto, false, 5000), None);
+
+        // Brief opens are ignored.
+        assert_eq!(run(&mut auto, true, SETTLE.as_millis() / 2), None);
+        assert_eq!(run(&mut auto, false, 5000), None);
+
+        assert_eq!(run(&mut auto, true, 5000), Some(LayoutMode::StenoDirect));
+        assert_eq!(run(&mut auto, true, 5000), None);
+        assert_eq!(run(&mut auto, false, 5000), Some(LayoutMode::Qwerty));
+
+        // Disabled still tracks, but doesn't change the mode.
+        auto.set_enabled(false);
+        assert_eq!(run(&mut auto, true, 5000), None);
+        auto.set_enabled(true);
+        assert_eq!(run(&mut auto, true, 5000), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! Caps word.
+//!
+//! Caps word types the letters of a single word shifted, for names such as `MAX_KEYS`, without
+//! holding shift, or having to remember to turn caps lock back off.  It is turned on by a key in
+//! qwerty mode, or a chord in taipo, and turns itself back off at the end of the word:
+//!
+//! - Letters are shifted, and minus is shifted to an underscore.
+//! - Digits, backspace and delete are typed as they are, and stay in the word.
+//! - Anything else (space, enter, punctuation) ends the word, and is typed unshifted.
+//! - A key pressed with control, alt, or gui is a shortcut, and also ends the word.
+//! - After [`CAPS_WORD_TIMEOUT`] without a key, it turns off.
+//!
+//! Modifiers that are held, or latched, such as the taipo one-shot modifiers, are sent as they
+//! are.  While caps word is on, the layout reports [`crate::MinorMode::CapsWord`], so the firmware
+//! can show it.
+
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::time::Duration;
+use crate::Mods;
+
+/// Caps word turns off after this long without a key.
+pub const CAPS_WORD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The caps word state of a layout.
+#[derive(Debug, Default)]
+pub struct CapsWord {
+    on: bool,
+    /// Time since the last key, while on.
+    idle: Duration,
+    /// The state last reported through [`changed`](Self::changed).
+    shown: bool,
+}
+
+impl CapsWord {
+    pub fn new() -> CapsWord {
+        CapsWord::default()
+    }
+
+    /// The caps word key was pressed, turn it on, or back off.
+    pub fn toggle(&mut self) {
+        self.on = !self.on;
+        self.idle = Duration::ZERO;
+    }
+
+    /// Turn caps word off, such as when the modifiers are all released.
+    #[cfg(feature = "taipo")]
+    pub fn cancel(&mut self) {
+        self.on = false;
+    }
+
+    /// A key is being pressed, with these modifiers.  Returns the modifiers to send it with, and
+    /// turns caps word off if the key ends the word.
+    pub fn key(&mut self, key: Keyboard, mods: Mods) -> Mods {
+        if !self.on || key == Keyboard::NoEventIndicated {
+            return mods;
+        }
+        self.idle = Duration::ZERO;
+
+        if mods.intersects(Mods::CONTROL | Mods::ALT | Mods::GUI) {
+            self.on = false;
+            return mods;
+        }
+        match u8::from(key) {
+            // Letters, and minus, which becomes underscore.
+            0x04..=0x1d | 0x2d => mods | Mods::SHIFT,
+            // Digits, backspace, and delete, unless shifted into punctuation.
+            0x1e..=0x27 | 0x2a | 0x4c if !mods.contains(Mods::SHIFT) => mods,
+            _ => {
+                self.on = false;
+                mods
+            }
+        }
+    }
+
+    /// Track time, turning caps word off once it has been idle too long.
+    pub fn tick(&mut self, elapsed: Duration) {
+        if !self.on {
+            return;
+        }
+        self.idle += elapsed;
+        if self.idle >= CAPS_WORD_TIMEOUT {
+            self.on = false;
+        }
+    }
+
+    /// Has caps word turned on, or off, since this was last asked.  Gives the new state, for the
+    /// indicator.
+    pub fn changed(&mut self) -> Option<bool> {
+        if self.on == self.shown {
+            return None;
+        }
+        self.shown = self.on;
+        Some(self.on)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_caps_word() {
+        let mut caps = CapsWord::new();
+        assert_eq!(caps.key(Keyboard::A, Mods::empty()), Mods::empty());
+        assert_eq!(caps.changed(), None);
+
+        caps.toggle();
+        assert_eq!(caps.changed(), Some(true));
+        assert_eq!(caps.changed(), None);
+        assert_eq!(caps.key(Keyboard::A, Mods::empty()), Mods::SHIFT);
+        assert_eq!(caps.key(Keyboard::Minus, Mods::empty()), Mods::SHIFT);
+        assert_eq!(caps.key(Keyboard::Keyboard1, Mods::empty()), Mods::empty());
+        assert_eq!(caps.key(Keyboard::DeleteBackspace, Mods::empty()), Mods::empty());
+        // Modifier keys on their own don't affect it.
+        assert_eq!(caps.key(Keyboard::NoEventIndicated, Mods::CONTROL), Mods::CONTROL);
+        assert!(caps.on);
+
+        // Space ends the word.
+        assert_eq!(caps.key(Keyboard::Space, Mods::empty()), Mods::empty());
+        assert!(!caps.on);
+        assert_eq!(caps.changed(), Some(false));
+
+        // So does shifted punctuation, and a shortcut.
+        caps.toggle();
+        assert_eq!(caps.key(Keyboard::Keyboard1, Mods::SHIFT), Mods::SHIFT);
+        assert!(!caps.on);
+        caps.toggle();
+        assert_eq!(caps.key(Keyboard::C, Mods::CONTROL), Mods::CONTROL);
+        assert!(!caps.on);
+
+        // A key keeps it from timing out.
+        caps.toggle();
+        caps.tick(CAPS_WORD_TIMEOUT - Duration::from_millis(10));
+        caps.key(Keyboard::B, Mods::empty());
+        caps.tick(Duration::from_millis(20));
+        assert!(caps.on);
+        caps.tick(CAPS_WORD_TIMEOUT);
+        assert!(!caps.on);
+    }
+}
@@ -0,0 +1,285 @@
+//! Experimental layout modes.
+//!
+//! New chording schemes can be tried out without changing the layout manager.  An experiment
+//! implements [`Experiment`], and is registered with the manager under one of the mode ids
+//! reserved for experiments, `0..EXPERIMENTAL_IDS`.  It then becomes
+//! [`LayoutMode::Experimental`], with the id, and is selected like any other mode: by name from the
+//! console, or with a mode chord given in the board info.  Experiments are only entered directly,
+//! never by cycling with the mode key.
+//!
+//! The id's [`ExperimentInfo`] gives the name of the mode, and the color the firmware shows for
+//! it.  The name is also the namespace for the experiment's settings: a setting `name.key` is given
+//! to the experiment as `key`.
+//!
+//! ```ignore
+//! bbq_keyboard::register_experiments! { layout,
+//!     0 => ExperimentInfo { name: "chorder", indicator: RGB8::new(16, 0, 16) }, Chorder::new(),
+//! }
+//! ```
+//!
+//! Experiments don't use [`LayoutActions`] directly, as it isn't object safe.  Instead, they add
+//! [`Output`]s to a list, which the manager sends on.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use bbq_steno::Stroke;
+
+use crate::time::Duration;
+use crate::{KeyAction, KeyEvent, MinorMode, RGB8};
+
+use super::LayoutActions;
+
+/// The number of mode ids reserved for experiments.
+pub const EXPERIMENTAL_IDS: u8 = 8;
+
+/// Describes an experimental mode.
+#[derive(Debug)]
+pub struct ExperimentInfo {
+    /// The name of the mode, as given by [`super::LayoutMode::name`], and the namespace of its
+    /// settings.
+    pub name: &'static str,
+    /// The color of the mode's indicator.
+    pub indicator: RGB8,
+}
+
+/// Something an experiment does.  These are the same as the [`LayoutActions`] the built-in modes
+/// use.
+#[derive(Debug)]
+pub enum Output {
+    Key(KeyAction),
+    RawSteno(Stroke),
+    Text(String),
+    SubMode(MinorMode),
+}
+
+/// An experimental layout mode.
+pub trait Experiment: Send {
+    /// Handle a key event, while this mode is in use.
+    fn handle_event(&mut self, event: KeyEvent, out: &mut Vec<Output>);
+
+    /// Track time, for timeouts.  This is called whichever mode is in use.
+    fn tick(&mut self, _elapsed: Duration, _out: &mut Vec<Output>) {}
+
+    /// Another mode has been selected.  Anything held over should be forgotten.
+    fn reset(&mut self) {}
+
+    /// Change a setting, given without the namespace.  Returns false if the setting, or its
+    /// value, isn't known.
+    fn set(&mut self, _key: &str, _value: &str) -> bool {
+        false
+    }
+}
+
+/// The info for each registered id.  This is global, so that a mode can be named without the
+/// manager.
+static INFO: [AtomicPtr<ExperimentInfo>; EXPERIMENTAL_IDS as usize] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; EXPERIMENTAL_IDS as usize];
+
+/// The info of a registered experiment.
+pub fn info(id: u8) -> Option<&'static ExperimentInfo> {
+    let info = INFO.get(id as usize)?.load(Ordering::Acquire);
+    // Only ever set from a `&'static`.
+    unsafe { info.as_ref() }
+}
+
+/// Find a registered experiment by name, giving its id.
+pub fn find(name: &str) -> Option<u8> {
+    (0..EXPERIMENTAL_IDS).find(|&id| info(id).is_some_and(|info| info.name == name))
+}
+
+/// The experiments registered with a layout manager.
+#[derive(Default)]
+pub struct Experiments {
+    modes: Vec<(u8, Box<dyn Experiment>)>,
+    out: Vec<Output>,
+}
+
+impl Experiments {
+    /// Register an experiment under `id`.  Returns false if the id is outside of the reserved
+    /// range, or the name is already used by another id.
+    pub fn register<E: Experiment + 'static>(
+        &mut self,
+        id: u8,
+        info: &'static ExperimentInfo,
+        experiment: E,
+    ) -> bool {
+        if id >= EXPERIMENTAL_IDS || find(info.name).is_some_and(|other| other != id) {
+            return false;
+        }
+        INFO[id as usize].store(info as *const _ as *mut _, Ordering::Release);
+        self.modes.retain(|(i, _)| *i != id);
+        self.modes.push((id, Box::new(experiment)));
+        true
+    }
+
+    /// Is there an experiment registered with this id.
+    pub fn contains(&self, id: u8) -> bool {
+        self.modes.iter().any(|(i, _)| *i == id)
+    }
+
+    /// Give a setting, in the `name.key` form, to the experiment it belongs to.  Returns false if
+    /// there is no such experiment, or it doesn't know the setting.
+    pub fn set(&mut self, setting: &str, value: &str) -> bool {
+        let Some((name, key)) = setting.split_once('.') else {
+            return false;
+        };
+        let Some(id) = find(name) else {
+            return false;
+        };
+        match self.modes.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, experiment)) => experiment.set(key, value),
+            None => false,
+        }
+    }
+
+    pub async fn handle_event<ACT: LayoutActions>(
+        &mut self,
+        id: u8,
+        event: KeyEvent,
+        actions: &ACT,
+    ) {
+        if let Some((_, experiment)) = self.modes.iter_mut().find(|(i, _)| *i == id) {
+            experiment.handle_event(event, &mut self.out);
+        }
+        self.flush(actions).await;
+    }
+
+    pub async fn tick<ACT: LayoutActions>(&mut self, actions: &ACT, elapsed: Duration) {
+        for (_, experiment) in &mut self.modes {
+            experiment.tick(elapsed, &mut self.out);
+        }
+        self.flush(actions).await;
+    }
+
+    /// Reset every experiment but the one in use, if any.
+    pub fn reset_others(&mut self, current: Option<u8>) {
+        for (id, experiment) in &mut self.modes {
+            if Some(*id) != current {
+                experiment.reset();
+            }
+        }
+    }
+
+    async fn flush<ACT: LayoutActions>(&mut self, actions: &ACT) {
+        for output in self.out.drain(..) {
+            match output {
+                Output::Key(key) => actions.send_key(key).await,
+                Output::RawSteno(stroke) => actions.send_raw_steno(stroke).await,
+                Output::Text(text) => actions.type_text(&text).await,
+                Output::SubMode(submode) => actions.set_sub_mode(submode).await,
+            }
+        }
+    }
+}
+
+/// Register experiments with a [`LayoutManager`](super::LayoutManager), checking at compile time
+/// that each id is in the reserved range.  Each is given as `id => info, experiment`, where the
+/// info is a constant [`ExperimentInfo`].  Evaluates to false if any failed to register.
+#[macro_export]
+macro_rules! register_experiments {
+    ($layout:expr, $($id:literal => $info:expr, $experiment:expr),* $(,)?) => {{
+        let mut ok = true;
+        $(
+            ok &= {
+                const _: () = assert!(
+                    $id < $crate::layout::experimental::EXPERIMENTAL_IDS,
+                    "Experiment ids must be in the reserved range",
+                );
+                static INFO: $crate::layout::experimental::ExperimentInfo = $info;
+                $layout.register_experiment($id, &INFO, $experiment)
+            };
+        )*
+        ok
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use usbd_human_interface_device::page::Keyboard;
+
+    use super::*;
+    use crate::layout::{LayoutManager, LayoutMode};
+    use crate::Mods;
+    use crate::test_util::{run, Recorder};
+
+    /// Types 'a' for any key, or 'b' once set to.
+    #[derive(Default)]
+    struct Letters {
+        b: bool,
+    }
+
+    impl Experiment for Letters {
+        fn handle_event(&mut self, event: KeyEvent, out: &mut Vec<Output>) {
+            if let KeyEvent::Press(_) = event {
+                let key = if self.b { Keyboard::B } else { Keyboard::A };
+                out.push(Output::Key(KeyAction::KeyPress(key, Mods::empty())));
+            }
+        }
+
+        fn set(&mut self, key: &str, value: &str) -> bool {
+            match (key, value) {
+                ("letter", "a") => self.b = false,
+                ("letter", "b") => self.b = true,
+                _ => return false,
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn test_experiment() {
+        let mut layout = LayoutManager::new(false);
+        let mode = LayoutMode::Experimental(3);
+        assert!(!mode.is_enabled());
+        assert_eq!(LayoutMode::from_name("letters"), None);
+
+        assert!(register_experiments! { layout,
+            3 => ExperimentInfo { name: "letters", indicator: RGB8::new(16, 0, 16) },
+                Letters::default(),
+        });
+        assert!(mode.is_enabled());
+        assert_eq!(mode.name(), "letters");
+        assert_eq!(LayoutMode::from_name("letters"), Some(mode));
+
+        // The name can't be taken by another id, and ids past the range can't be used.
+        static OTHER: ExperimentInfo =
+            ExperimentInfo { name: "letters", indicator: RGB8::new(0, 0, 0) };
+        assert!(!layout.register_experiment(4, &OTHER, Letters::default()));
+        assert!(!layout.register_experiment(EXPERIMENTAL_IDS, &OTHER, Letters::default()));
+
+        // Selected with a chord, like the built-in modes.
+        let rec = Recorder::default();
+        assert!(layout.add_mode_chord(&[20, 21], mode));
+        for event in [
+            KeyEvent::Press(2),
+            KeyEvent::Press(20),
+            KeyEvent::Press(21),
+            KeyEvent::Release(20),
+            KeyEvent::Release(21),
+            KeyEvent::Release(2),
+        ] {
+            run(layout.handle_event(event, &rec));
+        }
+        assert_eq!(rec.mode.get(), Some(mode));
+
+        run(layout.handle_event(KeyEvent::Press(30), &rec));
+        run(layout.handle_event(KeyEvent::Release(30), &rec));
+        assert!(layout.experiment_setting("letters.letter", "b"));
+        assert!(!layout.experiment_setting("letters.letter", "c"));
+        assert!(!layout.experiment_setting("other.letter", "a"));
+        run(layout.handle_event(KeyEvent::Press(30), &rec));
+        assert_eq!(
+            *rec.keys.borrow(),
+            [
+                KeyAction::KeyPress(Keyboard::A, Mods::empty()),
+                KeyAction::KeyPress(Keyboard::B, Mods::empty()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Idle timeout.
+//!
+//! If the keyboard is left in a mode like NKRO or raw steno, coming back to it later can be
+//! confusing, as keys don't do what is expected.  After the keyboard has been idle for a while,
+//! this returns it to the default mode.
+//!
+//! When the timeout is reached, there is first a warning, which is shown as a mode select of the
+//! default mode.  If a key is pressed during the warning, the mode is kept.  Otherwise, the
+//! default mode is entered once the warning is over.
+
+use crate::time::Duration;
+
+/// How long the warning is shown before the mode changes.
+pub(crate) const WARN: Duration = Duration::from_millis(2000);
+
+/// What the idle timer wants done.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Idle {
+    /// The timeout has been reached, warn that the mode is about to change.
+    Warn,
+    /// The warning is over, change the mode.
+    Expire,
+}
+
+pub(crate) struct IdleTimer {
+    /// The timeout.  None disables.
+    timeout: Option<Duration>,
+
+    /// How long since the last activity.
+    elapsed: Duration,
+}
+
+impl IdleTimer {
+    pub(crate) fn new() -> Self {
+        IdleTimer {
+            timeout: None,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout.filter(|&t| t > Duration::ZERO);
+        self.elapsed = Duration::ZERO;
+    }
+
+    /// Note that a key was pressed or released.  Returns true if this cancelled a warning.
+    pub(crate) fn activity(&mut self) -> bool {
+        let warning = match self.timeout {
+            Some(timeout) => self.elapsed >= timeout && self.elapsed < timeout + WARN,
+            None => false,
+        };
+        self.elapsed = Duration::ZERO;
+        warning
+    }
+
+    /// Advance by `elapsed`, returning what, if anything, should be done.
+    pub(crate) fn tick(&mut self, elapsed: Duration) -> Option<Idle> {
+        let timeout = self.timeout?;
+        let before = self.elapsed;
+        self.elapsed += elapsed;
+
+        if before < timeout && self.elapsed >= timeout {
+            Some(Idle::Warn)
+        } else if before < timeout + WARN && self.elapsed >= timeout + WARN {
+            Some(Idle::Expire)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(idle: &mut IdleTimer, ms: u64) -> Vec<Idle> {
+        (0..ms / 10).filter_map(|_| idle.tick(Duration::from_millis(10))).collect()
+    }
+
+    #[test]
+    fn test_idle() {
+        let mut idle = IdleTimer::new();
+
+        // Disabled by default.
+        assert_eq!(run(&mut idle, 100_000), vec![]);
+
+        idle.set_timeout(Some(Duration::from_millis(5000)));
+        assert_eq!(run(&mut idle, 4000), vec![]);
+        assert!(!idle.activity());
+        assert_eq!(run(&mut idle, 4000), vec![]);
+        assert_eq!(run(&mut idle, 1000), vec![Idle::Warn]);
+
+        // A key during the warning cancels it.
+        assert!(idle.activity());
+        assert_eq!(run(&mut idle, 5000), vec![Idle::Warn]);
+        assert_eq!(run(&mut idle, WARN.as_millis()), vec![Idle::Expire]);
+
+        // Only once.
+        assert_eq!(run(&mut idle, 100_000), vec![]);
+        assert!(!idle.activity());
+    }
+}
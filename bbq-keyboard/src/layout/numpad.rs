@@ -0,0 +1,337 @@
+//! Number pad, and calculator, mode.
+//!
+//! The right hand gets a number grid, in the home position, and the left hand gets the operators:
+//!
+//! ```text
+//!   Esc  /   *   -  Bksp          7   8   9  Bksp
+//!   Tab  (   )   +   =            4   5   6   -
+//!                Enter            1   2   3   +
+//!                =  Bksp      0  Ent  .
+//! ```
+//!
+//! The keys are typed as they are, so this works as an ordinary number pad.  The operators use
+//! the keypad usages, so they don't depend on the host's layout, but the parentheses are the
+//! shifted 9 and 0 of a US layout.
+//!
+//! The keys typed are also kept as an expression.  The `=` key evaluates it, and types `=` and
+//! the result after it.  The result then starts the next expression, so a calculation can be
+//! carried on from it.  Enter, Tab, and Escape start a new expression.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::{KeyAction, KeyEvent, Mods};
+
+use super::LayoutActions;
+
+/// The longest expression kept.  Keys past this are still typed, but can't be evaluated.
+const MAX_EXPR: usize = 64;
+
+/// What a key does.
+#[derive(Clone, Copy)]
+enum Action {
+    /// Type a key that is part of the expression.
+    Char(Keyboard, Mods, char),
+    /// Backspace, which also removes the last character of the expression.
+    Backspace,
+    /// Type a key that ends the expression.
+    Key(Keyboard),
+    /// Evaluate the expression, and type the result.
+    Equals,
+}
+
+struct Entry {
+    code: u8,
+    action: Action,
+}
+
+const fn ch(code: u8, key: Keyboard, ch: char) -> Entry {
+    Entry { code, action: Action::Char(key, Mods::empty(), ch) }
+}
+
+const fn shifted(code: u8, key: Keyboard, ch: char) -> Entry {
+    Entry { code, action: Action::Char(key, Mods::SHIFT, ch) }
+}
+
+const fn key(code: u8, key: Keyboard) -> Entry {
+    Entry { code, action: Action::Key(key) }
+}
+
+static KEYS: [Entry; 28] = [
+    // Left hand, the operators.
+    key(4, Keyboard::Escape),
+    ch(8, Keyboard::KeypadDivide, '/'),
+    ch(12, Keyboard::KeypadMultiply, '*'),
+    ch(16, Keyboard::KeypadSubtract, '-'),
+    Entry { code: 20, action: Action::Backspace },
+    key(5, Keyboard::Tab),
+    shifted(9, Keyboard::Keyboard9, '('),
+    shifted(13, Keyboard::Keyboard0, ')'),
+    ch(17, Keyboard::KeypadAdd, '+'),
+    Entry { code: 21, action: Action::Equals },
+    key(18, Keyboard::ReturnEnter),
+    Entry { code: 19, action: Action::Equals },
+    Entry { code: 23, action: Action::Backspace },
+    // Right hand, the numbers.
+    ch(40, Keyboard::Keyboard7, '7'),
+    ch(36, Keyboard::Keyboard8, '8'),
+    ch(32, Keyboard::Keyboard9, '9'),
+    ch(41, Keyboard::Keyboard4, '4'),
+    ch(37, Keyboard::Keyboard5, '5'),
+    ch(33, Keyboard::Keyboard6, '6'),
+    ch(42, Keyboard::Keyboard1, '1'),
+    ch(38, Keyboard::Keyboard2, '2'),
+    ch(34, Keyboard::Keyboard3, '3'),
+    ch(47, Keyboard::Keyboard0, '0'),
+    ch(39, Keyboard::Dot, '.'),
+    key(43, Keyboard::ReturnEnter),
+    Entry { code: 28, action: Action::Backspace },
+    ch(29, Keyboard::KeypadSubtract, '-'),
+    ch(30, Keyboard::KeypadAdd, '+'),
+];
+
+#[derive(Default)]
+pub struct NumpadManager {
+    /// Keys that are currently down.
+    pressed: u64,
+
+    /// The expression typed so far.
+    expr: String,
+
+    /// The expression grew too long to be kept, and is incomplete.
+    overflow: bool,
+}
+
+impl NumpadManager {
+    pub async fn handle_event<ACT: LayoutActions>(&mut self, event: KeyEvent, actions: &ACT) {
+        match event {
+            KeyEvent::Press(code) => {
+                self.pressed |= 1 << code;
+                if let Some(entry) = KEYS.iter().find(|e| e.code == code) {
+                    self.action(entry.action, actions).await;
+                }
+            }
+            KeyEvent::Release(code) => {
+                let was = self.pressed;
+                self.pressed &= !(1 << code);
+                if was != 0 && self.pressed == 0 {
+                    actions.send_key(KeyAction::KeyRelease).await;
+                }
+            }
+        }
+    }
+
+    /// Forget the expression, such as when leaving the mode.
+    pub fn clear(&mut self) {
+        self.pressed = 0;
+        self.expr.clear();
+        self.overflow = false;
+    }
+
+    async fn action<ACT: LayoutActions>(&mut self, action: Action, actions: &ACT) {
+        match action {
+            Action::Char(key, mods, ch) => {
+                actions.send_key(KeyAction::KeyPress(key, mods)).await;
+                if self.expr.len() < MAX_EXPR {
+                    self.expr.push(ch);
+                } else {
+                    self.overflow = true;
+                }
+            }
+            Action::Backspace => {
+                actions.send_key(KeyAction::KeyPress(Keyboard::DeleteBackspace, Mods::empty())).await;
+                if !self.overflow {
+                    self.expr.pop();
+                }
+            }
+            Action::Key(key) => {
+                actions.send_key(KeyAction::KeyPress(key, Mods::empty())).await;
+                self.expr.clear();
+                self.overflow = false;
+            }
+            Action::Equals => {
+                if self.overflow {
+                    return;
+                }
+                let Some(result) = evaluate(&self.expr) else {
+                    return;
+                };
+                actions.type_text(&format!("={}", result)).await;
+                self.expr = result;
+            }
+        }
+    }
+}
+
+/// Evaluate a simple arithmetic expression: numbers, `+ - * /`, parentheses, and unary minus.
+/// Returns None if it doesn't parse, or divides by zero.
+pub fn evaluate(expr: &str) -> Option<String> {
+    let mut parser = Parser { text: expr.as_bytes(), pos: 0 };
+    let value = parser.expr()?;
+    if parser.pos != parser.text.len() || !value.is_finite() {
+        return None;
+    }
+
+    if value == (value as i64) as f64 {
+        return Some(format!("{}", value as i64));
+    }
+    let text = format!("{:.6}", value);
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    if text == "-0" {
+        Some(String::from("0"))
+    } else {
+        Some(String::from(text))
+    }
+}
+
+/// A recursive descent parser, evaluating as it goes.
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn expr(&mut self) -> Option<f64> {
+        let mut value = self.term()?;
+        loop {
+            match self.peek() {
+                Some(b'+') => {
+                    self.pos += 1;
+                    value += self.term()?;
+                }
+                Some(b'-') => {
+                    self.pos += 1;
+                    value -= self.term()?;
+                }
+                _ => return Some(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Option<f64> {
+        let mut value = self.factor()?;
+        loop {
+            match self.peek() {
+                Some(b'*') => {
+                    self.pos += 1;
+                    value *= self.factor()?;
+                }
+                Some(b'/') => {
+                    self.pos += 1;
+                    let divisor = self.factor()?;
+                    if divisor == 0.0 {
+                        return None;
+                    }
+                    value /= divisor;
+                }
+                _ => return Some(value),
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Option<f64> {
+        match self.peek()? {
+            b'-' => {
+                self.pos += 1;
+                Some(-self.factor()?)
+            }
+            b'(' => {
+                self.pos += 1;
+                let value = self.expr()?;
+                if self.peek()? != b')' {
+                    return None;
+                }
+                self.pos += 1;
+                Some(value)
+            }
+            _ => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(b'0'..=b'9' | b'.')) {
+                    self.pos += 1;
+                }
+                let text = core::str::from_utf8(&self.text[start..self.pos]).ok()?;
+                text.parse().ok()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{run, Recorder};
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("1+2*3").as_deref(), Some("7"));
+        assert_eq!(evaluate("(1+2)*3").as_deref(), Some("9"));
+        assert_eq!(evaluate("10-4-3").as_deref(), Some("3"));
+        assert_eq!(evaluate("-2*-3").as_deref(), Some("6"));
+        assert_eq!(evaluate("7/2").as_deref(), Some("3.5"));
+        assert_eq!(evaluate("1/3").as_deref(), Some("0.333333"));
+        assert_eq!(evaluate("0.1+0.2").as_deref(), Some("0.3"));
+        assert_eq!(evaluate("2-5").as_deref(), Some("-3"));
+        assert_eq!(evaluate("1/0"), None);
+        assert_eq!(evaluate("1+"), None);
+        assert_eq!(evaluate("(1+2"), None);
+        assert_eq!(evaluate("1.2.3"), None);
+        assert_eq!(evaluate(""), None);
+    }
+
+    fn tap(numpad: &mut NumpadManager, rec: &Recorder, code: u8) {
+        run(numpad.handle_event(KeyEvent::Press(code), rec));
+        run(numpad.handle_event(KeyEvent::Release(code), rec));
+    }
+
+    #[test]
+    fn test_numpad() {
+        let mut numpad = NumpadManager::default();
+        let rec = Recorder::default();
+
+        // 1 2 + 3 =
+        for code in [42, 38, 17, 34, 21] {
+            tap(&mut numpad, &rec, code);
+        }
+        assert_eq!(
+            *rec.keys.borrow(),
+            [
+                KeyAction::KeyPress(Keyboard::Keyboard1, Mods::empty()),
+                KeyAction::KeyRelease,
+                KeyAction::KeyPress(Keyboard::Keyboard2, Mods::empty()),
+                KeyAction::KeyRelease,
+                KeyAction::KeyPress(Keyboard::KeypadAdd, Mods::empty()),
+                KeyAction::KeyRelease,
+                KeyAction::KeyPress(Keyboard::Keyboard3, Mods::empty()),
+                KeyAction::KeyRelease,
+                KeyAction::KeyRelease,
+            ]
+        );
+        assert_eq!(*rec.text.borrow(), "=15");
+
+        // Carrying on from the result: * 2 =
+        for code in [12, 38, 21] {
+            tap(&mut numpad, &rec, code);
+        }
+        assert_eq!(*rec.text.borrow(), "=15=30");
+
+        // Backspace edits the expression: 4 5 bksp 6 =
+        tap(&mut numpad, &rec, 43);
+        for code in [41, 37, 23, 33, 21] {
+            tap(&mut numpad, &rec, code);
+        }
+        assert_eq!(*rec.text.borrow(), "=15=30=46");
+
+        // Nothing to evaluate types nothing.
+        tap(&mut numpad, &rec, 4);
+        tap(&mut numpad, &rec, 21);
+        assert_eq!(*rec.text.borrow(), "=15=30=46");
+    }
+}
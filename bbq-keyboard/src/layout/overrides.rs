@@ -0,0 +1,128 @@
+//! Key overrides.
+//!
+//! A key override sends a different key when a key is pressed with certain modifiers held, such as
+//! delete for shift and backspace (see [`crate::keymap::KeyOverride`]).  They are applied to each
+//! report qwerty mode sends, once the layers have decided what the keys are, so they work the same
+//! whichever layer the key comes from.
+//!
+//! The report is checked as a whole each time it changes, so an override lasts for as long as both
+//! its modifiers and its key are held.  Releasing the modifiers while the key is still held goes
+//! back to the key itself.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::keymap::KeyOverride;
+use crate::Mods;
+
+/// Replace the keys of a report that have an override for the modifiers held.  Where more than one
+/// override matches a key, the one needing the most modifiers is used.
+pub fn apply(overrides: &[KeyOverride], keys: &mut Vec<Keyboard>) {
+    if overrides.is_empty() {
+        return;
+    }
+
+    let held = keys.iter().filter_map(|&k| modifier(k)).fold(Mods::empty(), |a, b| a | b);
+    let mut release = Mods::empty();
+    let mut add = Mods::empty();
+    for key in keys.iter_mut().filter(|k| modifier(**k).is_none()) {
+        let code = u8::from(*key);
+        let found = overrides
+            .iter()
+            .filter(|o| o.code == code && held.contains(Mods::from_bits_truncate(o.mods)))
+            .max_by_key(|o| o.mods.count_ones());
+        if let Some(over) = found {
+            *key = Keyboard::from(over.send_code);
+            release |= Mods::from_bits_truncate(over.mods);
+            add |= Mods::from_bits_truncate(over.send_mods);
+        }
+    }
+    if release.is_empty() {
+        return;
+    }
+
+    // Modifiers come first in the report, as they do when it is built.
+    let release = release - add;
+    keys.retain(|&k| !modifier(k).is_some_and(|m| release.contains(m)));
+    let present = keys.iter().filter_map(|&k| modifier(k)).fold(Mods::empty(), |a, b| a | b);
+    let mut front = Vec::new();
+    for (m, k) in MODIFIERS {
+        if add.contains(m) && !present.contains(m) {
+            front.push(k);
+        }
+    }
+    keys.splice(0..0, front);
+}
+
+/// The modifier keys, as pushed for a key's modifiers.
+const MODIFIERS: [(Mods, Keyboard); 4] = [
+    (Mods::SHIFT, Keyboard::LeftShift),
+    (Mods::CONTROL, Keyboard::LeftControl),
+    (Mods::ALT, Keyboard::LeftAlt),
+    (Mods::GUI, Keyboard::LeftGUI),
+];
+
+/// The modifier a key is, if it is one.
+fn modifier(key: Keyboard) -> Option<Mods> {
+    match key {
+        Keyboard::LeftShift | Keyboard::RightShift => Some(Mods::SHIFT),
+        Keyboard::LeftControl | Keyboard::RightControl => Some(Mods::CONTROL),
+        Keyboard::LeftAlt | Keyboard::RightAlt => Some(Mods::ALT),
+        Keyboard::LeftGUI | Keyboard::RightGUI => Some(Mods::GUI),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let overrides = [
+            KeyOverride {
+                mods: Mods::SHIFT.bits(),
+                code: Keyboard::DeleteBackspace.into(),
+                send_code: Keyboard::DeleteForward.into(),
+                send_mods: 0,
+            },
+            KeyOverride {
+                mods: (Mods::SHIFT | Mods::CONTROL).bits(),
+                code: Keyboard::DeleteBackspace.into(),
+                send_code: Keyboard::DeleteForward.into(),
+                send_mods: Mods::CONTROL.bits(),
+            },
+            KeyOverride {
+                mods: Mods::GUI.bits(),
+                code: Keyboard::Escape.into(),
+                send_code: Keyboard::Grave.into(),
+                send_mods: 0,
+            },
+        ];
+        let run = |keys: &[Keyboard]| {
+            let mut keys = keys.to_vec();
+            apply(&overrides, &mut keys);
+            keys
+        };
+
+        // Without the modifiers, nothing changes.
+        assert_eq!(run(&[Keyboard::DeleteBackspace]), vec![Keyboard::DeleteBackspace]);
+        assert_eq!(run(&[Keyboard::LeftControl, Keyboard::DeleteBackspace]),
+                   vec![Keyboard::LeftControl, Keyboard::DeleteBackspace]);
+
+        // The triggering modifier is released, others stay.
+        assert_eq!(run(&[Keyboard::RightShift, Keyboard::DeleteBackspace]), vec![Keyboard::DeleteForward]);
+        assert_eq!(run(&[Keyboard::LeftShift, Keyboard::LeftAlt, Keyboard::DeleteBackspace]),
+                   vec![Keyboard::LeftAlt, Keyboard::DeleteForward]);
+        assert_eq!(run(&[Keyboard::LeftGUI, Keyboard::A, Keyboard::Escape]),
+                   vec![Keyboard::A, Keyboard::Grave]);
+
+        // The override with the most modifiers wins, and keeps the modifiers it sends.
+        assert_eq!(run(&[Keyboard::LeftShift, Keyboard::LeftControl, Keyboard::DeleteBackspace]),
+                   vec![Keyboard::LeftControl, Keyboard::DeleteForward]);
+    }
+}
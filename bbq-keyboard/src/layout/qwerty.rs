@@ -8,6 +8,11 @@
 //!   cause remaining keys to be interpreted differently.
 //! - Combo keys.  Some pairs of keys, when pressed closely enough together, can
 //!   be treated as a key themselves.
+//! - Tap-hold keys.  A key can type normally when tapped, but act as modifiers
+//!   or a layer shift when held.
+//! - Latched layers.  A one-shot layer key, tapped, applies its layer to just
+//!   the next key pressed, and a toggle key keeps its layer until the same key
+//!   is pressed again.
 //!
 //! Unlike how something like qmk handles the combinations, we handle them at
 //! the scancode layer, before there is any intepretation made. This does
@@ -15,25 +20,141 @@
 //! physical keys, not dependent on the layer). With some help with the layer
 //! code, this should avoid keys getting stuck with weird combinations of combo
 //! keys and layers.
+//!
+//! A keymap's overlays (see [`crate::keymap`]) are built along with its layers, so selecting one is
+//! only a matter of switching base layers.  The switch waits until no keys are held, and no layer
+//! is in use, so a key is never released in a different layer than it was pressed in.
+//!
+//! The nav layer also has mouse keys, which move the pointer while held, speeding up the longer
+//! they are held, and click.
+//!
+//! Key overrides from the keymap (see [`crate::keymap`]) are applied to each report as it is sent,
+//! after the layers have been resolved.
+//!
+//! The fn layer has keys to record and play keyboard macros (see [`crate::macros`]), one pair for
+//! each slot: the record keys on the lower row of the left hand, and the play keys above them.
+//!
+//! A caps word key shifts the letters of the word typed next (see [`super::capsword`]).  In the
+//! built-in layers, it is the caps lock combo, with the fn layer held.
+//!
+//! A lock key stays held once tapped, until the same key is pressed again, whatever it is in the
+//! layer in use by then.
 
 use alloc::collections::{BTreeMap, VecDeque};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
-use crate::Mods;
+use crate::{MinorMode, Mods, MouseButtons};
+use crate::backlight::KeyClass;
+use crate::keymap::{KeyDef, KeyOverride, Keymap, Layer};
 use crate::log::warn;
+use crate::macros::{Macros, StoredMacros};
 use usbd_human_interface_device::page::Keyboard;
 
+use crate::time::Duration;
 use crate::{KeyEvent, KeyAction};
 
+use super::capsword::CapsWord;
+use super::overrides;
 use super::LayoutActions;
 
+/// A key that could be part of a combo is held back this long, waiting for the rest of the combo.
+const COMBO_TIME: Duration = Duration::from_millis(50);
+
+/// A tap-hold key held this long acts as held, unless the keymap gives its own term.
+const TAPPING_TERM: Duration = Duration::from_millis(200);
+
+/// While a mouse movement key is held, the pointer moves a step this often.
+const MOUSE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The steps get a step larger each time a mouse movement key has been held this much longer.
+const MOUSE_ACCEL: Duration = Duration::from_millis(250);
+
+/// The most a step is multiplied by.
+const MOUSE_MAX_SPEED: u64 = 4;
+
 pub struct QwertyManager {
-    down: BTreeMap<u8, Mapping>,
+    // The keys that are down, and what they were pressed as, in the order
+    // they were pressed.
+    down: Vec<(u8, Mapping)>,
+
+    // The keys in the last report sent.  Each report is reached from this one
+    // key at a time (see `send_report`).
+    report: Vec<Keyboard>,
 
     // The combo mapper.
     combo: ComboHandler,
 
     // Current layer.
     layer: Layout,
+
+    // The base layer, returned to when a layer key is released.
+    root: Layout,
+
+    // The base layer of the keymap, without an overlay.
+    keymap_root: Layout,
+
+    // The base layer with each of the keymap's overlays.
+    overlays: Vec<(String, Layout)>,
+
+    // The overlay selected, if any.
+    overlay: Option<String>,
+
+    // A base layer to switch to, once no keys are held.
+    next_root: Option<Layout>,
+
+    // Time since the last mouse movement step.
+    mouse_age: Duration,
+
+    // How long mouse movement keys have been held, for the acceleration.
+    mouse_held: Duration,
+
+    // A tap-hold key that has been pressed, but not yet decided to be a tap or a hold.
+    tap_hold: Option<PendingTapHold>,
+
+    // Events that were held back while a tap-hold key was undecided, to be handled once it is.
+    replay: VecDeque<LayeredEvent>,
+
+    // How long a tap-hold key has to be held to be a hold.
+    tapping_term: Duration,
+
+    // A one-shot layer key that has been pressed, and not yet used up.
+    one_shot: Option<OneShot>,
+
+    // A layer that has been toggled on, and the key that toggled it, which turns it back off.
+    toggled: Option<(u8, Layout)>,
+
+    // The keyboard macros, and any recording.
+    macros: Macros,
+
+    // The keymap's key overrides.
+    overrides: Vec<KeyOverride>,
+
+    // Caps word, shifting the letters of the word being typed.
+    caps_word: CapsWord,
+
+    // Keys locked down by lock keys, by the scan code that locked them, in the order locked.
+    locked: Vec<(u8, KeyMapping)>,
+}
+
+// A one-shot layer, applying to the next key pressed.
+struct OneShot {
+    key: u8,
+    layer: Layout,
+    // Still held, in which case it acts like a layer shift until released.
+    held: bool,
+    // A key has been pressed in the layer.
+    used: bool,
+}
+
+// A tap-hold key, waiting to see if it is tapped or held.
+struct PendingTapHold {
+    key: u8,
+    mapping: TapHoldMapping,
+    age: Duration,
+    // The events that came in since it was pressed.
+    waiting: Vec<LayeredEvent>,
 }
 
 type Layout = &'static [Mapping];
@@ -54,7 +175,7 @@ struct ComboHandler {
 
     // When there is a pending key event, how long has it been since we've seen
     // it?
-    pending_age: usize,
+    pending_age: Duration,
 
     // For each combo that is pressed down, record the keys contained in it, and
     // some information about what layer it was in to be able to process the
@@ -86,7 +207,7 @@ impl Default for ComboHandler {
             combos,
             comboed: 0,
             pending: None,
-            pending_age: 0,
+            pending_age: Duration::ZERO,
             down: BTreeMap::new(),
             ready: VecDeque::new(),
         }
@@ -133,13 +254,13 @@ impl ComboHandler {
                             // And make the new key into a pending key, resetting the age timer for
                             // the new press.
                             self.pending = Some((key, layer));
-                            self.pending_age = 0;
+                            self.pending_age = Duration::ZERO;
                         }
                     } else {
                         // We have a possible key from a combo. Hold it for a
                         // little bit, and see if we get the other key.
                         self.pending = Some((key, layer));
-                        self.pending_age = 0;
+                        self.pending_age = Duration::ZERO;
                     }
                 } else {
                     // This key can't be part of a combo, so just queue it up.
@@ -191,14 +312,14 @@ impl ComboHandler {
     /// Called as part of the tick handler. Ages potentially pressed keys, so
     /// they will be sent in a timely manner if not accompanied by their
     /// companion.  May cause an event to be queue.
-    pub fn tick(&mut self, ticks: usize) {
+    pub fn tick(&mut self, elapsed: Duration) {
         if self.pending.is_none() {
             return;
         }
 
-        self.pending_age = self.pending_age.saturating_add(ticks);
+        self.pending_age += elapsed;
 
-        if self.pending_age >= 50 {
+        if self.pending_age >= COMBO_TIME {
             self.push_pending();
         }
     }
@@ -237,14 +358,99 @@ struct ComboInfo {
 impl Default for QwertyManager {
     fn default() -> Self {
         QwertyManager {
-            down: BTreeMap::new(),
+            down: Vec::new(),
+            report: Vec::new(),
             combo: ComboHandler::default(),
             layer: &ROOT_MAP,
+            root: &ROOT_MAP,
+            keymap_root: &ROOT_MAP,
+            overlays: Vec::new(),
+            overlay: None,
+            next_root: None,
+            mouse_age: Duration::ZERO,
+            mouse_held: Duration::ZERO,
+            tap_hold: None,
+            replay: VecDeque::new(),
+            tapping_term: TAPPING_TERM,
+            one_shot: None,
+            toggled: None,
+            macros: Macros::new(),
+            overrides: Vec::new(),
+            caps_word: CapsWord::new(),
+            locked: Vec::new(),
         }
     }
 }
 
 impl QwertyManager {
+    /// Switch to a different keymap, or back to the built-in one with None.  Returns false,
+    /// leaving the keymap alone, if the keymap isn't valid.
+    ///
+    /// The layers of a keymap live for the rest of the run, so this should only be done when the
+    /// user asks for it.
+    ///
+    /// The selected overlay carries over, if the new keymap has one with the same name.
+    pub fn set_keymap(&mut self, keymap: Option<&Keymap>) -> bool {
+        let (root, overlays) = match keymap {
+            None => (&ROOT_MAP[..], Vec::new()),
+            Some(keymap) => match build_layers(keymap) {
+                Some(built) => built,
+                None => return false,
+            },
+        };
+        self.keymap_root = root;
+        self.overlays = overlays;
+        self.next_root = None;
+        self.root = self.overlay_root();
+        self.layer = self.root;
+        self.one_shot = None;
+        self.toggled = None;
+        self.tapping_term = keymap
+            .and_then(|keymap| keymap.tapping_term)
+            .map(|ms| Duration::from_millis(ms as u64))
+            .unwrap_or(TAPPING_TERM);
+        self.overrides = keymap.and_then(|keymap| keymap.overrides.clone()).unwrap_or_default();
+        true
+    }
+
+    /// Select one of the keymap's overlays by name, or none.  A name the keymap has no overlay for
+    /// is the same as none.  The change happens once no keys are held.
+    pub fn set_overlay(&mut self, name: Option<&str>) {
+        self.overlay = name.map(String::from);
+        self.next_root = Some(self.overlay_root());
+        self.apply_overlay();
+    }
+
+    /// Use stored macros.  Returns false, keeping the current ones, if they can't be used.
+    pub fn set_macros(&mut self, macros: &StoredMacros) -> bool {
+        self.macros.load(macros)
+    }
+
+    /// The base layer for the selected overlay.
+    fn overlay_root(&self) -> Layout {
+        self.overlay
+            .as_deref()
+            .and_then(|name| self.overlays.iter().find(|(o, _)| o == name))
+            .map(|(_, root)| *root)
+            .unwrap_or(self.keymap_root)
+    }
+
+    /// Switch to the base layer of a newly selected overlay, if nothing is in use.
+    fn apply_overlay(&mut self) {
+        let Some(root) = self.next_root else {
+            return;
+        };
+        let idle = self.down.is_empty()
+            && self.tap_hold.is_none()
+            && self.replay.is_empty()
+            && core::ptr::eq(self.layer, self.root);
+        if idle {
+            self.root = root;
+            self.layer = root;
+            self.next_root = None;
+        }
+    }
+
     pub async fn handle_event<ACT: LayoutActions>(&mut self, event: KeyEvent, actions: &ACT, nkro: bool) {
         // Skip out of bound events.
         if event.key() as usize >= NKEYS {
@@ -260,22 +466,64 @@ impl QwertyManager {
         self.process_keys(actions).await;
     }
 
-    pub async fn tick<ACT: LayoutActions>(&mut self, actions: &ACT, ticks: usize) {
-        self.combo.tick(ticks);
+    pub async fn tick<ACT: LayoutActions>(&mut self, actions: &ACT, elapsed: Duration) {
+        // Move the mouse first, so a key pressed during this tick doesn't move a second time.
+        self.mouse_tick(actions, elapsed).await;
+        self.combo.tick(elapsed);
+        self.caps_word.tick(elapsed);
+        if let Some(pending) = &mut self.tap_hold {
+            pending.age += elapsed;
+            if pending.age >= self.tapping_term {
+                self.resolve_hold(actions).await;
+            }
+        }
         self.process_keys(actions).await;
     }
 
     async fn process_keys<ACT: LayoutActions>(&mut self, actions: &ACT) {
-        while let Some(LayeredEvent { key: event, layer }) = self.combo.next() {
+        while let Some(LayeredEvent { key: event, layer }) =
+            self.replay.pop_front().or_else(|| self.combo.next())
+        {
+            // While a tap-hold key is undecided, hold back everything else, until something
+            // decides it.
+            if let Some(pending) = &mut self.tap_hold {
+                if event == KeyEvent::Release(pending.key) {
+                    self.resolve_tap(actions).await;
+                    continue;
+                }
+                // Permissive hold: another key pressed and released while the tap-hold key is
+                // held makes it a hold.
+                let permissive = event.is_release()
+                    && pending.waiting.iter().any(|w| w.key == KeyEvent::Press(event.key()));
+                pending.waiting.push(LayeredEvent { key: event, layer });
+                if permissive {
+                    self.resolve_hold(actions).await;
+                }
+                continue;
+            }
+
             // Skip out of bound events.
             if event.key() as usize >= layer.len() {
                 // info!("Extra event: {}", event);
                 continue;
             }
 
+            // The key that latched a layer turns it back off, whatever it is in that layer, and
+            // likewise for a locked key.
+            if event.is_press() && self.unlatch(event.key()) {
+                continue;
+            }
+            if event.is_press() && self.unlock(event.key()) {
+                self.show(actions, None).await;
+                continue;
+            }
+
             // Get the mapping of a release event from the 'down' information, in case we have it.
             let code = if event.is_release() {
-                self.down.remove(&event.key())
+                self.down
+                    .iter()
+                    .position(|(key, _)| *key == event.key())
+                    .map(|pos| self.down.remove(pos).1)
             } else {
                 None
             };
@@ -287,13 +535,119 @@ impl QwertyManager {
                 continue;
             }
 
+            // Any other key pressed uses up a one-shot layer.
+            if event.is_press() && !code.is_layer() {
+                self.use_one_shot();
+            }
+
             // Handle layer changes.
             match code {
                 Mapping::LayerShift(nlayer) => {
                     if event.is_press() {
+                        self.one_shot = None;
                         self.layer = nlayer;
                     } else {
-                        self.layer = &ROOT_MAP;
+                        self.layer = self.base();
+                    }
+                    continue;
+                }
+                Mapping::LayerToggle(nlayer) => {
+                    // The release is found in the keys down, and does nothing.
+                    if event.is_press() {
+                        self.down.push((event.key(), code));
+                        self.one_shot = None;
+                        self.toggled = Some((event.key(), nlayer));
+                        self.layer = nlayer;
+                    }
+                    continue;
+                }
+                Mapping::OneShotLayer(nlayer) => {
+                    if event.is_press() {
+                        self.down.push((event.key(), code));
+                        self.one_shot = Some(OneShot {
+                            key: event.key(),
+                            layer: nlayer,
+                            held: true,
+                            used: false,
+                        });
+                        self.layer = nlayer;
+                    } else if let Some(one_shot) = &mut self.one_shot {
+                        if one_shot.key == event.key() {
+                            if one_shot.used {
+                                // Used while held, so it was just a layer shift.
+                                self.one_shot = None;
+                                self.layer = self.base();
+                            } else {
+                                one_shot.held = false;
+                            }
+                        }
+                    }
+                    continue;
+                }
+                Mapping::Mouse(mouse) => {
+                    if event.is_press() {
+                        self.down.retain(|(key, _)| *key != event.key());
+                        self.down.push((event.key(), code));
+                    }
+                    self.mouse(actions, mouse, event.is_press()).await;
+                    continue;
+                }
+                Mapping::Consumer(_) => {
+                    if event.is_press() {
+                        self.down.retain(|(key, _)| *key != event.key());
+                        self.down.push((event.key(), code));
+                    }
+                    self.consumer(actions).await;
+                    continue;
+                }
+                Mapping::MacroRecord(slot) => {
+                    // The release is found in the keys down, and does nothing.
+                    if event.is_press() {
+                        self.down.push((event.key(), code));
+                        if self.macros.toggle_record(slot) {
+                            actions.save_macros(&self.macros.stored()).await;
+                        }
+                    }
+                    continue;
+                }
+                Mapping::MacroPlay(slot) => {
+                    if event.is_press() {
+                        self.down.push((event.key(), code));
+                        self.play_macro(actions, slot).await;
+                    }
+                    continue;
+                }
+                Mapping::CapsWord => {
+                    // The release is found in the keys down, and does nothing.
+                    if event.is_press() {
+                        self.down.push((event.key(), code));
+                        self.caps_word.toggle();
+                    }
+                    continue;
+                }
+                Mapping::KeyLock(key) => {
+                    // The release is found in the keys down, and does nothing, leaving the key
+                    // locked down.
+                    if event.is_press() {
+                        self.down.push((event.key(), code));
+                        let Mapping::Key(key) = self.caps_word_key(Mapping::Key(key)) else {
+                            unreachable!()
+                        };
+                        self.locked.push((event.key(), key));
+                        self.show(actions, Some(Mapping::Key(key))).await;
+                    }
+                    continue;
+                }
+                Mapping::TapHold(mapping) => {
+                    // Only the press gets here, the release is taken by the pending key, or the
+                    // key is down as what it resolved to.
+                    if event.is_press() {
+                        self.tap_hold = Some(PendingTapHold {
+                            key: event.key(),
+                            mapping,
+                            age: Duration::ZERO,
+                            waiting: Vec::new(),
+                        });
                     }
                     continue;
                 }
@@ -302,15 +656,236 @@ impl QwertyManager {
 
             // info!("Event: {}", event);
             if event.is_press() {
-                self.down.insert(event.key(), code);
+                let code = self.caps_word_key(code);
+                // A repeated press moves the key to the end, as if it were
+                // released first.
+                self.down.retain(|(key, _)| *key != event.key());
+                self.down.push((event.key(), code));
                 self.show(actions, Some(code)).await;
             } else {
                 self.show(actions, None).await;
             }
         }
+        self.apply_overlay();
+        if let Some(on) = self.caps_word.changed() {
+            actions.set_sub_mode(MinorMode::CapsWord(on)).await;
+        }
+    }
+
+    /// Identifies the layer in use, to tell when it changes.
+    pub fn current_layer(&self) -> usize {
+        self.layer.as_ptr() as usize
     }
 
-    async fn show<ACT: LayoutActions>(&self, actions: &ACT, code: Option<Mapping>) {
+    /// What each key does in the layer in use, by scan code.
+    pub fn key_classes(&self) -> Vec<KeyClass> {
+        self.layer.iter().take(NKEYS).map(Mapping::class).collect()
+    }
+
+    /// The layer to go back to when a layer key is released, or a one-shot layer is used.
+    fn base(&self) -> Layout {
+        self.toggled.map(|(_, layer)| layer).unwrap_or(self.root)
+    }
+
+    /// A key has been pressed in a one-shot layer.  If the one-shot key has already been released,
+    /// that was the one key it applies to.
+    fn use_one_shot(&mut self) {
+        if let Some(one_shot) = &mut self.one_shot {
+            one_shot.used = true;
+            if !one_shot.held {
+                self.one_shot = None;
+                self.layer = self.base();
+            }
+        }
+    }
+
+    /// If this key latched a layer, a one-shot layer that is waiting or a toggled layer, turn the
+    /// layer back off.  The key goes down as the layer key, so that its release does nothing.
+    fn unlatch(&mut self, key: u8) -> bool {
+        let code = match (&self.one_shot, self.toggled) {
+            (Some(one_shot), _) if one_shot.key == key && !one_shot.held => {
+                let code = Mapping::OneShotLayer(one_shot.layer);
+                self.one_shot = None;
+                code
+            }
+            (_, Some((toggle, layer))) if toggle == key => {
+                self.toggled = None;
+                Mapping::LayerToggle(layer)
+            }
+            _ => return false,
+        };
+        self.down.retain(|(k, _)| *k != key);
+        self.down.push((key, code));
+        self.layer = self.base();
+        true
+    }
+
+    /// If this key locked a key down, release it.  The key goes down as nothing, so that its release
+    /// does nothing either.
+    fn unlock(&mut self, key: u8) -> bool {
+        let Some(pos) = self.locked.iter().position(|(k, _)| *k == key) else {
+            return false;
+        };
+        self.locked.remove(pos);
+        self.down.retain(|(k, _)| *k != key);
+        self.down.push((key, Mapping::Dead));
+        true
+    }
+
+    /// The keys held, by lock keys first, as they have been held longest, and then by the keys
+    /// down.
+    fn held(&self) -> impl Iterator<Item = &KeyMapping> {
+        let down = self.down.iter().filter_map(|(_, m)| match m {
+            Mapping::Key(m) => Some(m),
+            _ => None,
+        });
+        self.locked.iter().map(|(_, m)| m).chain(down)
+    }
+
+    /// The pending tap-hold key was released before it became a hold: type its key, and then handle
+    /// the events held back.
+    async fn resolve_tap<ACT: LayoutActions>(&mut self, actions: &ACT) {
+        let Some(pending) = self.tap_hold.take() else {
+            return;
+        };
+        let code = self.caps_word_key(Mapping::Key(pending.mapping.tap));
+        self.down.push((pending.key, code));
+        self.show(actions, Some(code)).await;
+        self.down.retain(|(key, _)| *key != pending.key);
+        self.show(actions, None).await;
+        self.requeue(pending.waiting);
+    }
+
+    /// The pending tap-hold key is a hold: apply the modifiers or layer while it stays down, and
+    /// then handle the events held back, as if it had been held all along.
+    async fn resolve_hold<ACT: LayoutActions>(&mut self, actions: &ACT) {
+        let Some(mut pending) = self.tap_hold.take() else {
+            return;
+        };
+        match pending.mapping.hold {
+            Hold::Mods(mods) => {
+                let code = Mapping::Key(KeyMapping { key: Keyboard::NoEventIndicated, mods });
+                self.down.push((pending.key, code));
+                self.show(actions, None).await;
+            }
+            Hold::Layer(layer) => {
+                // The release finds this, and goes back to the base layer.
+                self.down.push((pending.key, Mapping::LayerShift(layer)));
+                self.layer = layer;
+                for waiting in &mut pending.waiting {
+                    if waiting.key.is_press() {
+                        waiting.layer = layer;
+                    }
+                }
+            }
+        }
+        self.requeue(pending.waiting);
+    }
+
+    /// Send the keys of a macro, and then the keys held now, to leave things as they were.  While
+    /// recording, the macro is recorded too.
+    async fn play_macro<ACT: LayoutActions>(&mut self, actions: &ACT, slot: u8) {
+        for action in self.macros.play(slot) {
+            if let KeyAction::KeySet(keys) = &action {
+                self.macros.record(keys);
+                self.report.clone_from(keys);
+            }
+            actions.send_key(action).await;
+        }
+        self.show(actions, None).await;
+    }
+
+    /// A key is being pressed.  While caps word is on, this adds shift to the keys it shifts, or
+    /// turns it off at the end of the word.  The modifiers held by other keys count, so a letter
+    /// typed with control held is a shortcut, not part of the word.
+    fn caps_word_key(&mut self, code: Mapping) -> Mapping {
+        let Mapping::Key(mut key) = code else {
+            return code;
+        };
+        let held = self.held().filter(|m| m.is_mod()).fold(Mods::empty(), |mods, m| mods | m.mods);
+        let mods = self.caps_word.key(key.key, key.mods | held);
+        key.mods |= mods - held;
+        Mapping::Key(key)
+    }
+
+    /// Put events held back by a tap-hold key ahead of anything else still to be handled.
+    fn requeue(&mut self, events: Vec<LayeredEvent>) {
+        for event in events.into_iter().rev() {
+            self.replay.push_front(event);
+        }
+    }
+
+    /// Handle a mouse key being pressed or released.  It has already been added to, or removed
+    /// from, the keys down.
+    async fn mouse<ACT: LayoutActions>(&mut self, actions: &ACT, mouse: MouseMapping, press: bool) {
+        match mouse {
+            MouseMapping::Button(_) => {
+                let buttons = self
+                    .down
+                    .iter()
+                    .fold(MouseButtons::empty(), |b, (_, m)| match m {
+                        Mapping::Mouse(MouseMapping::Button(mb)) => b | *mb,
+                        _ => b,
+                    });
+                actions.send_key(KeyAction::MousePress(buttons)).await;
+            }
+            MouseMapping::Move(x, y) => {
+                if !press {
+                    return;
+                }
+                // Move right away, rather than waiting for the next step.
+                if self.mouse_motion() == (x as i32, y as i32) {
+                    self.mouse_held = Duration::ZERO;
+                }
+                self.mouse_age = Duration::ZERO;
+                actions.send_key(KeyAction::MouseMove(x, y)).await;
+            }
+        }
+    }
+
+    /// Send the media key most recently pressed of those still held, or none.  It has already been
+    /// added to, or removed from, the keys down.
+    async fn consumer<ACT: LayoutActions>(&mut self, actions: &ACT) {
+        let code = self
+            .down
+            .iter()
+            .rev()
+            .find_map(|(_, m)| match m {
+                Mapping::Consumer(code) => Some(*code),
+                _ => None,
+            })
+            .unwrap_or(0);
+        actions.send_key(KeyAction::Consumer(code)).await;
+    }
+
+    /// Move the mouse a step, if it is time to, while mouse movement keys are held.
+    async fn mouse_tick<ACT: LayoutActions>(&mut self, actions: &ACT, elapsed: Duration) {
+        let (x, y) = self.mouse_motion();
+        if (x, y) == (0, 0) {
+            return;
+        }
+
+        self.mouse_held += elapsed;
+        self.mouse_age += elapsed;
+        if self.mouse_age < MOUSE_INTERVAL {
+            return;
+        }
+        self.mouse_age = Duration::ZERO;
+
+        let speed = 1 + (self.mouse_held.as_micros() / MOUSE_ACCEL.as_micros()).min(MOUSE_MAX_SPEED - 1);
+        let scale = |v: i32| (v * speed as i32).clamp(-127, 127) as i8;
+        actions.send_key(KeyAction::MouseMove(scale(x), scale(y))).await;
+    }
+
+    /// The combined step of the mouse movement keys held.
+    fn mouse_motion(&self) -> (i32, i32) {
+        self.down.iter().fold((0, 0), |(x, y), (_, m)| match m {
+            Mapping::Mouse(MouseMapping::Move(dx, dy)) => (x + *dx as i32, y + *dy as i32),
+            _ => (x, y),
+        })
+    }
+
+    async fn show<ACT: LayoutActions>(&mut self, actions: &ACT, code: Option<Mapping>) {
         let mut keys: Vec<Keyboard> = Vec::new();
 
         // We first need to collect the modifiers from any keys that are
@@ -324,13 +899,9 @@ impl QwertyManager {
         let mut sent = Mods::empty();
 
         // Go through every key, and add modifiers that are just modifier presses.
-        for m in self.down.values() {
-            if let Mapping::Key(m) = m {
-                if m.is_mod() {
-                    if !sent.contains(m.mods) {
-                        push_mods(&mut sent, &mut keys, m.mods);
-                    }
-                }
+        for m in self.held() {
+            if m.is_mod() && !sent.contains(m.mods) {
+                push_mods(&mut sent, &mut keys, m.mods);
             }
         }
 
@@ -339,19 +910,53 @@ impl QwertyManager {
             push_mods(&mut sent, &mut keys, code.mods);
         }
 
-        // Now push the rest of the non-modifier keys.
-        for m in self.down.values() {
-            if let Mapping::Key(m) = m {
-                if m.has_nonmmod() {
-                    keys.push(m.key);
-                }
+        // Now push the rest of the non-modifier keys, oldest first.
+        for m in self.held() {
+            if m.has_nonmmod() {
+                keys.push(m.key);
             }
         }
 
+        overrides::apply(&self.overrides, &mut keys);
+        self.macros.record(&keys);
+        self.send_report(actions, keys).await;
+    }
+
+    /// Send the keys held, one change at a time.  A host given a report with several keys
+    /// changed at once has to pick an order for them, which is how a fast roll comes out
+    /// transposed, so each key that comes up, and then each that goes down, gets a report of its
+    /// own.  Modifiers are the last up, and the first down, so they apply to the keys sent with
+    /// them.
+    async fn send_report<ACT: LayoutActions>(&mut self, actions: &ACT, keys: Vec<Keyboard>) {
+        let mut report = core::mem::take(&mut self.report);
+        let mut steps = Vec::new();
+
+        let up: Vec<Keyboard> = report.iter().filter(|k| !keys.contains(k)).copied().collect();
+        for key in up.iter().filter(|k| !is_modifier(**k)).chain(up.iter().filter(|k| is_modifier(**k))) {
+            report.retain(|k| k != key);
+            steps.push(report.clone());
+        }
+        let down: Vec<Keyboard> = keys.iter().filter(|k| !report.contains(k)).copied().collect();
+        for key in down {
+            report.push(key);
+            steps.push(report.clone());
+        }
+
+        // The last step is the report itself, which is always sent, even if nothing changed.
+        steps.pop();
+        for step in steps {
+            actions.send_key(KeyAction::KeySet(step)).await;
+        }
+        self.report.clone_from(&keys);
         actions.send_key(KeyAction::KeySet(keys)).await;
     }
 }
 
+/// Is this one of the modifier keys?
+fn is_modifier(key: Keyboard) -> bool {
+    (Keyboard::LeftControl as u8..=Keyboard::RightGUI as u8).contains(&(key as u8))
+}
+
 // Push keys for any modifiers mentioned here. The 'sent' tracks those that have
 // already been pushed, so we don\t push redundant mods.
 fn push_mods(sent: &mut Mods, keys: &mut Vec<Keyboard>, mods: Mods) {
@@ -381,6 +986,25 @@ enum Mapping {
     // A layer change that works like a shift key, keys while this is held are
     // interpreted in the new layer.
     LayerShift(Layout),
+    // A mouse button, or pointer movement.
+    Mouse(MouseMapping),
+    // A media key, by its consumer usage code.
+    Consumer(u16),
+    // A key when tapped, something else when held.
+    TapHold(TapHoldMapping),
+    // A layer that stays on until this key is pressed again.
+    LayerToggle(Layout),
+    // A layer for just the next key pressed, or a layer shift if another key is pressed while this
+    // one is held.
+    OneShotLayer(Layout),
+    // Start or stop recording a macro into a slot.
+    MacroRecord(u8),
+    // Play the macro in a slot.
+    MacroPlay(u8),
+    // Turn caps word on or off.
+    CapsWord,
+    // A key that stays held once tapped, until this key is pressed again.
+    KeyLock(KeyMapping),
 }
 
 impl Mapping {
@@ -390,6 +1014,26 @@ impl Mapping {
             _ => false,
         }
     }
+
+    // Does this key change layers?
+    fn is_layer(&self) -> bool {
+        matches!(self, Mapping::LayerShift(_) | Mapping::LayerToggle(_) | Mapping::OneShotLayer(_))
+    }
+
+    // What this key does, for the backlight.  A tap-hold key is shown as its tap.
+    fn class(&self) -> KeyClass {
+        match self {
+            Mapping::Dead => KeyClass::None,
+            Mapping::Key(key) | Mapping::KeyLock(key) | Mapping::TapHold(TapHoldMapping { tap: key, .. }) => {
+                key.class()
+            }
+            Mapping::Mouse(_) => KeyClass::Mouse,
+            Mapping::Consumer(_) => KeyClass::Other,
+            Mapping::LayerShift(_) | Mapping::LayerToggle(_) | Mapping::OneShotLayer(_) => KeyClass::Layer,
+            Mapping::MacroRecord(_) | Mapping::MacroPlay(_) => KeyClass::Other,
+            Mapping::CapsWord => KeyClass::Modifier,
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -408,6 +1052,156 @@ impl KeyMapping {
     fn has_nonmmod(&self) -> bool {
         self.key != Keyboard::NoEventIndicated
     }
+
+    fn class(&self) -> KeyClass {
+        if self.is_mod() {
+            KeyClass::Modifier
+        } else {
+            KeyClass::of_usage(self.key.into())
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TapHoldMapping {
+    tap: KeyMapping,
+    hold: Hold,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Hold {
+    // Modifiers held while the key is.
+    Mods(Mods),
+    // A layer used while the key is held.
+    Layer(Layout),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MouseMapping {
+    // Buttons held while the key is.
+    Button(MouseButtons),
+    // A step right and down, repeated while the key is held.
+    Move(i8, i8),
+}
+
+// The layers of a keymap all have room for every scan code and combo.
+pub(crate) const LAYER_LEN: usize = NKEYS + COMBOS.len();
+
+/// Build the layers of a keymap, returning the base layer, and the base layer with each overlay.
+/// Each layer key has to refer to a later layer, so the layers can be built from the last, each
+/// referring to ones already built.
+fn build_layers(keymap: &Keymap) -> Option<(Layout, Vec<(String, Layout)>)> {
+    if let Err(e) = keymap.check() {
+        warn!("Bad keymap: {}", e.as_str());
+        return None;
+    }
+
+    let base = build(&keymap.layers, &[])?;
+    let mut overlays = Vec::new();
+    for overlay in keymap.overlays.as_deref().unwrap_or_default() {
+        // Share the layers the overlay doesn't change, and that don't lead to one it does.
+        let layers = keymap.overlaid(overlay);
+        let mut reuse: Vec<Option<Layout>> = vec![None; layers.len()];
+        for index in (0..layers.len()).rev() {
+            let same = layers[index] == keymap.layers[index]
+                && layers[index]
+                    .keys
+                    .iter()
+                    .filter_map(KeyDef::target)
+                    .all(|target| reuse[target as usize].is_some());
+            if same {
+                reuse[index] = Some(base[index]);
+            }
+        }
+        overlays.push((overlay.name.clone(), build(&layers, &reuse)?[0]));
+    }
+    Some((base[0], overlays))
+}
+
+/// Build layers, other than those given in `reuse`.
+fn build(layers: &[Layer], reuse: &[Option<Layout>]) -> Option<Vec<Layout>> {
+    let mut built: Vec<Option<Layout>> = vec![None; layers.len()];
+    for (index, layer) in layers.iter().enumerate().rev() {
+        if let Some(Some(layout)) = reuse.get(index) {
+            built[index] = Some(*layout);
+            continue;
+        }
+        let mut maps = Vec::with_capacity(LAYER_LEN);
+        for key in &layer.keys {
+            maps.push(match *key {
+                KeyDef::Dead => Mapping::Dead,
+                KeyDef::Key { code, mods } => Mapping::Key(KeyMapping {
+                    key: Keyboard::from(code),
+                    mods: Mods::from_bits_truncate(mods),
+                }),
+                KeyDef::Layer(target) => Mapping::LayerShift(built[target as usize]?),
+                KeyDef::LayerToggle(target) => Mapping::LayerToggle(built[target as usize]?),
+                KeyDef::OneShotLayer(target) => Mapping::OneShotLayer(built[target as usize]?),
+                KeyDef::MouseButton(buttons) => {
+                    Mapping::Mouse(MouseMapping::Button(MouseButtons::from_bits_truncate(buttons)))
+                }
+                KeyDef::MouseMove { x, y } => Mapping::Mouse(MouseMapping::Move(x, y)),
+                KeyDef::ModTap { code, mods, hold } => Mapping::TapHold(TapHoldMapping {
+                    tap: KeyMapping { key: Keyboard::from(code), mods: Mods::from_bits_truncate(mods) },
+                    hold: Hold::Mods(Mods::from_bits_truncate(hold)),
+                }),
+                KeyDef::LayerTap { code, mods, layer } => Mapping::TapHold(TapHoldMapping {
+                    tap: KeyMapping { key: Keyboard::from(code), mods: Mods::from_bits_truncate(mods) },
+                    hold: Hold::Layer(built[layer as usize]?),
+                }),
+                KeyDef::MacroRecord(slot) => Mapping::MacroRecord(slot),
+                KeyDef::MacroPlay(slot) => Mapping::MacroPlay(slot),
+                KeyDef::CapsWord => Mapping::CapsWord,
+                KeyDef::Consumer(code) => Mapping::Consumer(code),
+                KeyDef::KeyLock { code, mods } => Mapping::KeyLock(KeyMapping {
+                    key: Keyboard::from(code),
+                    mods: Mods::from_bits_truncate(mods),
+                }),
+            });
+        }
+        maps.resize(LAYER_LEN, Mapping::Dead);
+        built[index] = Some(Box::leak(maps.into_boxed_slice()));
+    }
+    built.into_iter().collect()
+}
+
+/// The built-in layers, as a keymap.
+pub(crate) fn builtin_keymap() -> Keymap {
+    let layers: [Layout; 4] = [&ROOT_MAP, &NUM_MAP, &FN_MAP, &NAV_MAP];
+    let layers = layers
+        .iter()
+        .map(|layer| Layer {
+            keys: layer
+                .iter()
+                .map(|m| match m {
+                    Mapping::Dead => KeyDef::Dead,
+                    Mapping::Key(KeyMapping { key, mods }) => {
+                        KeyDef::Key { code: u8::from(*key), mods: mods.bits() }
+                    }
+                    Mapping::LayerShift(target) => KeyDef::Layer(layer_index(&layers, target)),
+                    Mapping::LayerToggle(target) => KeyDef::LayerToggle(layer_index(&layers, target)),
+                    Mapping::OneShotLayer(target) => KeyDef::OneShotLayer(layer_index(&layers, target)),
+                    Mapping::Mouse(MouseMapping::Button(buttons)) => KeyDef::MouseButton(buttons.bits()),
+                    Mapping::Mouse(MouseMapping::Move(x, y)) => KeyDef::MouseMove { x: *x, y: *y },
+                    // The built-in layers have no tap-hold keys.
+                    Mapping::TapHold(_) => KeyDef::Dead,
+                    Mapping::MacroRecord(slot) => KeyDef::MacroRecord(*slot),
+                    Mapping::MacroPlay(slot) => KeyDef::MacroPlay(*slot),
+                    Mapping::CapsWord => KeyDef::CapsWord,
+                    Mapping::Consumer(code) => KeyDef::Consumer(*code),
+                    Mapping::KeyLock(KeyMapping { key, mods }) => {
+                        KeyDef::KeyLock { code: u8::from(*key), mods: mods.bits() }
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+    Keymap { name: "builtin".into(), layers, tapping_term: None, overlays: None, overrides: None }
+}
+
+// The index of a built-in layer.
+fn layer_index(layers: &[Layout], target: &Layout) -> u8 {
+    layers.iter().position(|l| core::ptr::eq(*l, *target)).unwrap() as u8
 }
 
 // Basic qwerty map for the proto3
@@ -520,7 +1314,7 @@ static ROOT_MAP: [Mapping; NKEYS + 24] = [
     // TODO: These are all layer shifts, wait for that to be implemented.
     Mapping::LayerShift(&FN_MAP),
     Mapping::LayerShift(&NUM_MAP),
-    Mapping::Dead,
+    Mapping::OneShotLayer(&NUM_MAP),
     Mapping::LayerShift(&NAV_MAP),
 ];
 
@@ -648,26 +1442,26 @@ static FN_MAP: [Mapping; NKEYS + 24] = [
 
     // 4
     Mapping::Key(KeyMapping { key: Keyboard::F1, mods: Mods::empty() }),
-    Mapping::Dead,
-    Mapping::Dead,
+    Mapping::MacroPlay(0),
+    Mapping::MacroRecord(0),
     Mapping::Dead,
 
     // 8
     Mapping::Key(KeyMapping { key: Keyboard::F2, mods: Mods::empty() }),
-    Mapping::Dead,
-    Mapping::Dead,
+    Mapping::MacroPlay(1),
+    Mapping::MacroRecord(1),
     Mapping::Dead,
 
     // 12
     Mapping::Key(KeyMapping { key: Keyboard::F3, mods: Mods::empty() }),
-    Mapping::Dead,
-    Mapping::Dead,
+    Mapping::MacroPlay(2),
+    Mapping::MacroRecord(2),
     Mapping::Dead,
 
     // 16
     Mapping::Key(KeyMapping { key: Keyboard::F4, mods: Mods::empty() }),
-    Mapping::Dead,
-    Mapping::Dead,
+    Mapping::MacroPlay(3),
+    Mapping::MacroRecord(3),
     Mapping::Dead,
 
     // 20
@@ -742,7 +1536,7 @@ static FN_MAP: [Mapping; NKEYS + 24] = [
     Mapping::Dead,
     Mapping::Dead,
     Mapping::Dead,
-    Mapping::Dead,
+    Mapping::CapsWord,
 
     // Thumb pairs "#A", "AO", "#U", "EU"
     // TODO: These are all layer shifts, wait for that to be implemented.
@@ -767,25 +1561,25 @@ static NAV_MAP: [Mapping; NKEYS + 24] = [
 
     // 8
     Mapping::Dead,
-    Mapping::Dead,
+    Mapping::Mouse(MouseMapping::Move(-4, 0)),
     Mapping::Dead,
     Mapping::Dead,
 
     // 12
-    Mapping::Dead,
-    Mapping::Dead,
+    Mapping::Mouse(MouseMapping::Move(0, -4)),
+    Mapping::Mouse(MouseMapping::Move(0, 4)),
     Mapping::Dead,
     Mapping::Dead,
 
     // 16
-    Mapping::Dead,
-    Mapping::Dead,
-    Mapping::Dead,
+    Mapping::Mouse(MouseMapping::Button(MouseButtons::RIGHT)),
+    Mapping::Mouse(MouseMapping::Move(4, 0)),
+    Mapping::Mouse(MouseMapping::Button(MouseButtons::MIDDLE)),
     Mapping::Dead,
 
     // 20
     Mapping::Dead,
-    Mapping::Dead,
+    Mapping::Mouse(MouseMapping::Button(MouseButtons::LEFT)),
     Mapping::Dead,
     Mapping::Dead,
 
@@ -973,3 +1767,577 @@ static COMBOS: [[u8; 2]; 24] = [
     [39, 43],
     [43, 47],
 ];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keymap::{KeyChange, Overlay};
+    use crate::test_util::{run, Recorder};
+
+    /// The scan code of a key on the root layer.
+    fn scan(key: Keyboard) -> u8 {
+        ROOT_MAP[..NKEYS]
+            .iter()
+            .position(|m| matches!(m, Mapping::Key(KeyMapping { key: k, mods }) if *k == key && mods.is_empty()))
+            .unwrap() as u8
+    }
+
+    /// The key each report presses (true) or releases (false), checking that each report
+    /// changes exactly one key.
+    fn changes(reports: Vec<KeyAction>) -> Vec<(Keyboard, bool)> {
+        let mut last: Vec<Keyboard> = Vec::new();
+        let mut result = Vec::new();
+        for report in reports {
+            let KeyAction::KeySet(keys) = report else {
+                panic!("Not a key report: {:?}", report);
+            };
+            let mut changed = keys.iter().filter(|k| !last.contains(k)).map(|k| (*k, true))
+                .chain(last.iter().filter(|k| !keys.contains(k)).map(|k| (*k, false)));
+            result.push(changed.next().expect("Report changed nothing"));
+            assert_eq!(changed.next(), None, "Report changed more than one key");
+            last = keys;
+        }
+        result
+    }
+
+    /// A fast roll of "the", with the keys overlapping.  Each key goes down, and comes up, in a
+    /// report of its own, in the order it was pressed or released.
+    #[test]
+    fn test_roll_order() {
+        let (t, h, e) = (scan(Keyboard::T), scan(Keyboard::H), scan(Keyboard::E));
+        // Otherwise, this doesn't tell the orders apart.
+        assert!(!(t < h && h < e));
+
+        let mut qwerty = QwertyManager::default();
+        let rec = Recorder::default();
+        for event in [KeyEvent::Press(t), KeyEvent::Press(h), KeyEvent::Release(t),
+                      KeyEvent::Press(e), KeyEvent::Release(h), KeyEvent::Release(e)] {
+            run(qwerty.handle_event(event, &rec, false));
+        }
+        run(qwerty.tick(&rec, Duration::from_millis(100)));
+
+        use Keyboard::{E, H, T};
+        assert_eq!(changes(rec.keys.into_inner()), vec![
+            (T, true),
+            (H, true),
+            (T, false),
+            (E, true),
+            (H, false),
+            (E, false),
+        ]);
+    }
+
+    /// The built-in layers survive a trip through a stored keymap.
+    #[test]
+    fn test_keymap() {
+        let keymap = Keymap::builtin();
+        let decoded = Keymap::decode(&keymap.encode()).unwrap();
+        assert_eq!(decoded, keymap);
+
+        let (root, _) = build_layers(&decoded).unwrap();
+        assert!(root == &ROOT_MAP[..]);
+
+        // Layer keys can only go to later layers.
+        let mut bad = keymap.clone();
+        bad.layers[1].keys[0] = KeyDef::Layer(0);
+        assert!(bad.check().is_err());
+        assert!(build_layers(&bad).is_none());
+        assert!(keymap.check().is_ok());
+
+        // Remap the 't' key, and type it.
+        let t = scan(Keyboard::T);
+        let mut remapped = keymap.clone();
+        remapped.layers[0].keys[t as usize] = KeyDef::Key { code: Keyboard::Z.into(), mods: 0 };
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&remapped)));
+        let rec = Recorder::default();
+        run(qwerty.handle_event(KeyEvent::Press(t), &rec, false));
+        run(qwerty.tick(&rec, Duration::from_millis(100)));
+        run(qwerty.handle_event(KeyEvent::Release(t), &rec, false));
+        assert_eq!(rec.keys.into_inner(), vec![
+            KeyAction::KeySet(vec![Keyboard::Z]),
+            KeyAction::KeySet(vec![]),
+        ]);
+    }
+
+    /// A key override replaces the key, and releases its modifier, only while both are held.
+    #[test]
+    fn test_overrides() {
+        let shift = scan(Keyboard::T);
+        let bksp = scan(Keyboard::Y);
+        let mut keymap = Keymap::builtin();
+        keymap.layers[0].keys[shift as usize] = KeyDef::Key { code: 0, mods: Mods::SHIFT.bits() };
+        keymap.layers[0].keys[bksp as usize] = KeyDef::Key { code: Keyboard::DeleteBackspace.into(), mods: 0 };
+        keymap.overrides = Some(vec![KeyOverride {
+            mods: Mods::SHIFT.bits(),
+            code: Keyboard::DeleteBackspace.into(),
+            send_code: Keyboard::DeleteForward.into(),
+            send_mods: 0,
+        }]);
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+
+        let rec = Recorder::default();
+        for event in [
+            KeyEvent::Press(shift),
+            KeyEvent::Press(bksp),
+            KeyEvent::Release(shift),
+            KeyEvent::Release(bksp),
+        ] {
+            run(qwerty.handle_event(event, &rec, false));
+            run(qwerty.tick(&rec, Duration::from_millis(100)));
+        }
+        // Shift comes up before the key it turns into goes down.
+        assert_eq!(rec.keys.into_inner(), vec![
+            KeyAction::KeySet(vec![Keyboard::LeftShift]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![Keyboard::DeleteForward]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![Keyboard::DeleteBackspace]),
+            KeyAction::KeySet(vec![]),
+        ]);
+
+        // An override needs modifiers.
+        keymap.overrides.as_mut().unwrap()[0].mods = 0;
+        assert!(keymap.check().is_err());
+    }
+
+    /// An overlay changes keys while it is selected, and only takes over once no keys are held.
+    #[test]
+    fn test_overlay() {
+        let t = scan(Keyboard::T);
+        let mut keymap = Keymap::builtin();
+        let copy = KeyDef::Key { code: Keyboard::C.into(), mods: Mods::CONTROL.bits() };
+        keymap.overlays = Some(vec![Overlay {
+            name: "files".into(),
+            changes: vec![
+                KeyChange { layer: 0, key: t, def: copy },
+                KeyChange { layer: 1, key: t, def: KeyDef::Dead },
+            ],
+        }]);
+        assert!(keymap.check().is_ok());
+
+        // Only the changed layers, and those that lead to them, are built again.
+        let (root, overlays) = build_layers(&keymap).unwrap();
+        let files = overlays[0].1;
+        let target = |layer: Layout, index: usize| match layer[index] {
+            Mapping::LayerShift(target) => target,
+            _ => panic!("Not a layer key"),
+        };
+        let (fn_key, num_key) = (NKEYS + 20, NKEYS + 21);
+        assert!(core::ptr::eq(target(root, fn_key), target(files, fn_key)));
+        assert!(!core::ptr::eq(target(root, num_key), target(files, num_key)));
+
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+        let rec = Recorder::default();
+        qwerty.set_overlay(Some("files"));
+        run(qwerty.handle_event(KeyEvent::Press(t), &rec, false));
+        // Deselected while the key is held, which still releases as pressed.
+        qwerty.set_overlay(None);
+        run(qwerty.handle_event(KeyEvent::Release(t), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Press(t), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(t), &rec, false));
+        // Unknown overlays are the same as none.
+        qwerty.set_overlay(Some("unknown"));
+        run(qwerty.handle_event(KeyEvent::Press(t), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(t), &rec, false));
+
+        use Keyboard::{LeftControl, C, T};
+        assert_eq!(rec.keys.into_inner(), vec![
+            KeyAction::KeySet(vec![LeftControl]),
+            KeyAction::KeySet(vec![LeftControl, C]),
+            KeyAction::KeySet(vec![LeftControl]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![T]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![T]),
+            KeyAction::KeySet(vec![]),
+        ]);
+
+        // Overlays can only change keys that are there.
+        keymap.overlays.as_mut().unwrap()[0].changes[0].layer = 4;
+        assert!(keymap.check().is_err());
+    }
+
+    /// The scan code of a mouse key on the nav layer.
+    fn mouse_scan(mouse: MouseMapping) -> u8 {
+        NAV_MAP.iter().position(|m| *m == Mapping::Mouse(mouse)).unwrap() as u8
+    }
+
+    /// Mouse keys move the pointer, faster the longer they are held, and click.
+    #[test]
+    fn test_mouse() {
+        let right = mouse_scan(MouseMapping::Move(4, 0));
+        let left = mouse_scan(MouseMapping::Button(MouseButtons::LEFT));
+
+        let mut qwerty = QwertyManager { layer: &NAV_MAP, ..Default::default() };
+        let rec = Recorder::default();
+        run(qwerty.handle_event(KeyEvent::Press(right), &rec, false));
+        run(qwerty.tick(&rec, Duration::from_millis(100)));
+        run(qwerty.tick(&rec, Duration::from_millis(10)));
+        run(qwerty.tick(&rec, Duration::from_millis(10)));
+        run(qwerty.tick(&rec, Duration::from_millis(500)));
+        run(qwerty.handle_event(KeyEvent::Release(right), &rec, false));
+        run(qwerty.tick(&rec, Duration::from_millis(100)));
+
+        run(qwerty.handle_event(KeyEvent::Press(left), &rec, false));
+        run(qwerty.tick(&rec, Duration::from_millis(100)));
+        run(qwerty.handle_event(KeyEvent::Release(left), &rec, false));
+        run(qwerty.tick(&rec, Duration::from_millis(100)));
+
+        assert_eq!(rec.keys.into_inner(), vec![
+            KeyAction::MouseMove(4, 0),
+            KeyAction::MouseMove(4, 0),
+            KeyAction::MouseMove(12, 0),
+            KeyAction::MousePress(MouseButtons::LEFT),
+            KeyAction::MousePress(MouseButtons::empty()),
+        ]);
+    }
+
+    /// Media keys send consumer reports, and the one held longest comes back when the other is
+    /// released.
+    #[test]
+    fn test_consumer() {
+        let (t, h) = (scan(Keyboard::T), scan(Keyboard::H));
+        let mut keymap = Keymap::builtin();
+        keymap.layers[0].keys[t as usize] = KeyDef::Consumer(0xe9);
+        keymap.layers[0].keys[h as usize] = KeyDef::Consumer(0xcd);
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+        let rec = Recorder::default();
+        for event in [
+            KeyEvent::Press(t),
+            KeyEvent::Press(h),
+            KeyEvent::Release(h),
+            KeyEvent::Release(t),
+        ] {
+            run(qwerty.handle_event(event, &rec, false));
+        }
+        assert_eq!(rec.keys.into_inner(), vec![
+            KeyAction::Consumer(0xe9),
+            KeyAction::Consumer(0xcd),
+            KeyAction::Consumer(0xe9),
+            KeyAction::Consumer(0),
+        ]);
+
+        // Codes past what the report holds aren't allowed in a keymap.
+        keymap.layers[0].keys[t as usize] = KeyDef::Consumer(0x400);
+        assert!(keymap.check().is_err());
+    }
+
+    /// Releases come out in the order they happen, not the order of the presses.
+    #[test]
+    fn test_release_order() {
+        let (t, h) = (scan(Keyboard::T), scan(Keyboard::H));
+
+        let mut qwerty = QwertyManager::default();
+        let rec = Recorder::default();
+        for event in [KeyEvent::Press(h), KeyEvent::Press(t), KeyEvent::Release(t)] {
+            run(qwerty.handle_event(event, &rec, false));
+        }
+        run(qwerty.tick(&rec, Duration::from_millis(100)));
+        run(qwerty.handle_event(KeyEvent::Release(h), &rec, false));
+
+        use Keyboard::{H, T};
+        assert_eq!(changes(rec.keys.into_inner()), vec![(H, true), (T, true), (T, false), (H, false)]);
+    }
+
+    /// A key that brings its own modifier, pressed while another is held, changes the report a
+    /// key at a time: the modifier goes down before the key, and comes up after it.
+    #[test]
+    fn test_modifier_order() {
+        let (t, c) = (scan(Keyboard::T), scan(Keyboard::C));
+        let mut keymap = Keymap::builtin();
+        keymap.layers[0].keys[c as usize] = KeyDef::Key {
+            code: Keyboard::C.into(),
+            mods: Mods::CONTROL.bits(),
+        };
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+
+        let rec = Recorder::default();
+        for event in [KeyEvent::Press(t), KeyEvent::Press(c), KeyEvent::Release(c), KeyEvent::Release(t)] {
+            run(qwerty.handle_event(event, &rec, false));
+            run(qwerty.tick(&rec, Duration::from_millis(100)));
+        }
+
+        use Keyboard::{LeftControl, C, T};
+        assert_eq!(changes(rec.keys.into_inner()), vec![
+            (T, true),
+            (LeftControl, true),
+            (C, true),
+            (C, false),
+            (LeftControl, false),
+            (T, false),
+        ]);
+    }
+
+    /// A tap-hold key types its key when tapped, and holds its modifiers or layer when held past
+    /// the tapping term, or while another key is pressed and released.
+    #[test]
+    fn test_tap_hold() {
+        let (t, y) = (scan(Keyboard::T), scan(Keyboard::Y));
+        let mut keymap = Keymap::builtin();
+        keymap.tapping_term = Some(150);
+        keymap.layers[0].keys[t as usize] =
+            KeyDef::ModTap { code: Keyboard::T.into(), mods: 0, hold: Mods::SHIFT.bits() };
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+        let rec = Recorder::default();
+
+        // Tapped, with another key rolled in before the release.
+        run(qwerty.handle_event(KeyEvent::Press(t), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Press(y), &rec, false));
+        run(qwerty.tick(&rec, Duration::from_millis(100)));
+        run(qwerty.handle_event(KeyEvent::Release(t), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(y), &rec, false));
+
+        // Held past the term.
+        run(qwerty.handle_event(KeyEvent::Press(t), &rec, false));
+        run(qwerty.tick(&rec, Duration::from_millis(150)));
+        run(qwerty.handle_event(KeyEvent::Press(y), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(y), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(t), &rec, false));
+
+        // Held while another key is tapped, well within the term.
+        run(qwerty.handle_event(KeyEvent::Press(t), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Press(y), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(y), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(t), &rec, false));
+
+        use Keyboard::{LeftShift, T, Y};
+        assert_eq!(rec.keys.into_inner(), vec![
+            KeyAction::KeySet(vec![T]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![Y]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![LeftShift]),
+            KeyAction::KeySet(vec![LeftShift, Y]),
+            KeyAction::KeySet(vec![LeftShift]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![LeftShift]),
+            KeyAction::KeySet(vec![LeftShift, Y]),
+            KeyAction::KeySet(vec![LeftShift]),
+            KeyAction::KeySet(vec![]),
+        ]);
+
+        // As a layer key, the held layer applies until the key is released.
+        keymap.layers[0].keys[t as usize] =
+            KeyDef::LayerTap { code: Keyboard::T.into(), mods: 0, layer: 1 };
+        assert!(qwerty.set_keymap(Some(&keymap)));
+        let rec = Recorder::default();
+        run(qwerty.handle_event(KeyEvent::Press(t), &rec, false));
+        run(qwerty.tick(&rec, Duration::from_millis(150)));
+        run(qwerty.handle_event(KeyEvent::Press(y), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(y), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(t), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Press(y), &rec, false));
+
+        assert_eq!(rec.keys.into_inner(), vec![
+            KeyAction::KeySet(vec![Keyboard::Keyboard6]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![Y]),
+        ]);
+    }
+
+    /// A one-shot layer applies to the next key only, unless held, and a toggled layer stays until
+    /// its key is pressed again.
+    #[test]
+    fn test_key_classes() {
+        let (t, y, esc) = (scan(Keyboard::T), scan(Keyboard::Y), scan(Keyboard::Escape));
+        let mut keymap = Keymap::builtin();
+        keymap.layers[0].keys[esc as usize] = KeyDef::LayerToggle(1);
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+        let rec = Recorder::default();
+
+        let root = qwerty.current_layer();
+        let classes = qwerty.key_classes();
+        assert_eq!(classes.len(), NKEYS);
+        assert_eq!(classes[t as usize], KeyClass::Letter);
+        assert_eq!(classes[esc as usize], KeyClass::Layer);
+
+        run(qwerty.handle_event(KeyEvent::Press(esc), &rec, false));
+        run(qwerty.handle_event(KeyEvent::Release(esc), &rec, false));
+        assert_ne!(qwerty.current_layer(), root);
+        assert_eq!(qwerty.key_classes()[y as usize], KeyClass::Digit);
+    }
+
+    #[test]
+    fn test_latched_layers() {
+        let (t, y, esc) = (scan(Keyboard::T), scan(Keyboard::Y), scan(Keyboard::Escape));
+        let mut keymap = Keymap::builtin();
+        keymap.layers[0].keys[t as usize] = KeyDef::OneShotLayer(1);
+        keymap.layers[0].keys[esc as usize] = KeyDef::LayerToggle(1);
+        assert!(keymap.check().is_ok());
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+        let rec = Recorder::default();
+        fn tap(qwerty: &mut QwertyManager, rec: &Recorder, key: u8) {
+            run(qwerty.handle_event(KeyEvent::Press(key), rec, false));
+            run(qwerty.handle_event(KeyEvent::Release(key), rec, false));
+        }
+
+        // One-shot, for just the next key.
+        tap(&mut qwerty, &rec, t);
+        tap(&mut qwerty, &rec, y);
+        tap(&mut qwerty, &rec, y);
+
+        // Held, as a layer shift.
+        run(qwerty.handle_event(KeyEvent::Press(t), &rec, false));
+        tap(&mut qwerty, &rec, y);
+        tap(&mut qwerty, &rec, y);
+        run(qwerty.handle_event(KeyEvent::Release(t), &rec, false));
+        tap(&mut qwerty, &rec, y);
+
+        // Tapped twice, cancelled.
+        tap(&mut qwerty, &rec, t);
+        tap(&mut qwerty, &rec, t);
+        tap(&mut qwerty, &rec, y);
+
+        // Toggled on, and back off.
+        tap(&mut qwerty, &rec, esc);
+        tap(&mut qwerty, &rec, y);
+        tap(&mut qwerty, &rec, y);
+        tap(&mut qwerty, &rec, esc);
+        tap(&mut qwerty, &rec, y);
+
+        use Keyboard::{Keyboard6, Y};
+        let six = [KeyAction::KeySet(vec![Keyboard6]), KeyAction::KeySet(vec![])];
+        let y = [KeyAction::KeySet(vec![Y]), KeyAction::KeySet(vec![])];
+        let expect: Vec<_> = [&six, &y, &six, &six, &y, &y, &six, &six, &y]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        assert_eq!(rec.keys.into_inner(), expect);
+    }
+
+    #[test]
+    fn test_macros() {
+        let (t, h, i, esc) = (scan(Keyboard::T), scan(Keyboard::H), scan(Keyboard::I), scan(Keyboard::Escape));
+        let mut keymap = Keymap::builtin();
+        keymap.layers[0].keys[esc as usize] = KeyDef::MacroRecord(0);
+        keymap.layers[0].keys[t as usize] = KeyDef::MacroPlay(0);
+        assert!(keymap.check().is_ok());
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+        let rec = Recorder::default();
+        fn tap(qwerty: &mut QwertyManager, rec: &Recorder, key: u8) {
+            run(qwerty.handle_event(KeyEvent::Press(key), rec, false));
+            run(qwerty.handle_event(KeyEvent::Release(key), rec, false));
+        }
+
+        // Record "hi", which is typed as it is recorded.
+        tap(&mut qwerty, &rec, esc);
+        tap(&mut qwerty, &rec, h);
+        tap(&mut qwerty, &rec, i);
+        assert!(rec.saved.borrow().is_none());
+        tap(&mut qwerty, &rec, esc);
+        let saved = rec.saved.take().unwrap();
+        assert_eq!(saved.macros.len(), 1);
+
+        // Play it back, ending with the keys held now.
+        rec.keys.take();
+        tap(&mut qwerty, &rec, t);
+        use Keyboard::{H, I};
+        assert_eq!(rec.keys.take(), vec![
+            KeyAction::KeySet(vec![H]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![I]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![]),
+        ]);
+
+        // The saved macros load again.
+        let mut loaded = QwertyManager::default();
+        assert!(loaded.set_keymap(Some(&keymap)));
+        assert!(loaded.set_macros(&saved));
+        tap(&mut loaded, &rec, t);
+        assert_eq!(rec.keys.take().len(), 5);
+
+        // A slot past the end isn't allowed in a keymap.
+        keymap.layers[0].keys[t as usize] = KeyDef::MacroPlay(crate::macros::MAX_MACROS as u8);
+        assert!(keymap.check().is_err());
+    }
+
+    /// Caps word shifts the letters of the next word, and turns off after it.
+    #[test]
+    fn test_caps_word() {
+        let (h, i, space, esc) =
+            (scan(Keyboard::H), scan(Keyboard::I), scan(Keyboard::Space), scan(Keyboard::Escape));
+        let mut keymap = Keymap::builtin();
+        keymap.layers[0].keys[esc as usize] = KeyDef::CapsWord;
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+        let rec = Recorder::default();
+        fn tap(qwerty: &mut QwertyManager, rec: &Recorder, key: u8) {
+            run(qwerty.handle_event(KeyEvent::Press(key), rec, false));
+            run(qwerty.handle_event(KeyEvent::Release(key), rec, false));
+        }
+
+        tap(&mut qwerty, &rec, esc);
+        tap(&mut qwerty, &rec, h);
+        tap(&mut qwerty, &rec, i);
+        tap(&mut qwerty, &rec, space);
+        tap(&mut qwerty, &rec, h);
+        use Keyboard::{LeftShift, Space, H, I};
+        assert_eq!(rec.keys.take(), vec![
+            KeyAction::KeySet(vec![LeftShift]),
+            KeyAction::KeySet(vec![LeftShift, H]),
+            KeyAction::KeySet(vec![LeftShift]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![LeftShift]),
+            KeyAction::KeySet(vec![LeftShift, I]),
+            KeyAction::KeySet(vec![LeftShift]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![Space]),
+            KeyAction::KeySet(vec![]),
+            KeyAction::KeySet(vec![H]),
+            KeyAction::KeySet(vec![]),
+        ]);
+        assert_eq!(rec.caps_word.take(), vec![true, false]);
+
+        // It also turns off when left alone.
+        tap(&mut qwerty, &rec, esc);
+        run(qwerty.tick(&rec, Duration::from_secs(10)));
+        assert_eq!(rec.caps_word.take(), vec![true, false]);
+
+        // The built-in fn layer has the key.
+        assert!(FN_MAP.contains(&Mapping::CapsWord));
+    }
+
+    /// A lock key stays held once tapped, until pressed again.
+    #[test]
+    fn test_key_lock() {
+        let (h, w, esc) = (scan(Keyboard::H), scan(Keyboard::W), scan(Keyboard::Escape));
+        let mut keymap = Keymap::builtin();
+        keymap.layers[0].keys[esc as usize] = KeyDef::KeyLock { code: 0, mods: Mods::SHIFT.bits() };
+        keymap.layers[0].keys[w as usize] = KeyDef::KeyLock { code: u8::from(Keyboard::W), mods: 0 };
+        let mut qwerty = QwertyManager::default();
+        assert!(qwerty.set_keymap(Some(&keymap)));
+        let rec = Recorder::default();
+        fn tap(qwerty: &mut QwertyManager, rec: &Recorder, key: u8) {
+            run(qwerty.handle_event(KeyEvent::Press(key), rec, false));
+            run(qwerty.handle_event(KeyEvent::Release(key), rec, false));
+        }
+
+        tap(&mut qwerty, &rec, esc);
+        tap(&mut qwerty, &rec, w);
+        tap(&mut qwerty, &rec, h);
+        tap(&mut qwerty, &rec, esc);
+        tap(&mut qwerty, &rec, w);
+        run(qwerty.tick(&rec, Duration::from_millis(100)));
+        use Keyboard::{LeftShift, H, W};
+        assert_eq!(rec.keys.take(), vec![
+            KeyAction::KeySet(vec![LeftShift]),
+            KeyAction::KeySet(vec![LeftShift, W]),
+            KeyAction::KeySet(vec![LeftShift, W, H]),
+            KeyAction::KeySet(vec![LeftShift, W]),
+            KeyAction::KeySet(vec![W]),
+            KeyAction::KeySet(vec![]),
+        ]);
+        assert!(qwerty.locked.is_empty() && qwerty.down.is_empty());
+    }
+}
@@ -1,5 +1,12 @@
 //! Steno key handling.
 
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::stenomap::StenoMap;
+use crate::time::Duration;
 use crate::KeyEvent;
 
 pub use bbq_steno::Stroke;
@@ -7,24 +14,34 @@ use bbq_steno_macros::stroke;
 
 use super::LayoutActions;
 
-// Normal steno mode operates in what is known as "last up", where when all keys
+// Steno mode can operate in what is known as "last up", where when all keys
 // have finally been released, we send a stroke containing all of the keys that
 // were pressed since the first press.
 //
-// "First up" works differently. As soon as a key is released, we send the
-// stroke of everything that was pressed. If an additional key is pressed, we
-// start recording new keys for possible additional strokes. This relies on good
-// debouncing to avoid seeing sprious interleaved events.
+// "First up", the default, works differently. As soon as a key is released, we
+// send the stroke of everything that was pressed. If an additional key is
+// pressed, we start recording new keys for possible additional strokes. This
+// relies on good debouncing to avoid seeing sprious interleaved events.
 
 pub struct RawStenoHandler {
     // Keys that are still pressed.
     down: Stroke,
 
     // Have we enabled first-up mode.
-    // first_up: bool,
+    first_up: bool,
 
     // Toggle between pressing, and releasing.
     pressing: bool,
+
+    // In last-up mode, the keys pressed since the stroke started.
+    seen: Stroke,
+
+    // In last-up mode, the scan codes held.  Keys with an empty stroke don't show up in 'down',
+    // but still hold the stroke open.
+    held: u64,
+
+    // The steno key for each scan code.
+    keys: Vec<Option<Stroke>>,
 }
 
 // The steno handler goes through these states. In Up indicates nothing is
@@ -38,26 +55,57 @@ impl RawStenoHandler {
     pub fn new() -> Self {
         RawStenoHandler {
             down: Stroke::empty(),
+            first_up: true,
             pressing: true,
+            seen: Stroke::empty(),
+            held: 0,
+            keys: STENO_KEYS.to_vec(),
         }
     }
 
+    /// Send each stroke as soon as the first key of it is released (first up), or only once all
+    /// are (last up).
+    pub fn set_first_up(&mut self, first_up: bool) {
+        self.first_up = first_up;
+        self.reset();
+    }
+
+    /// Use a steno map, or the built-in one with None.  Returns false if the map isn't valid, in
+    /// which case the current one is kept.
+    pub fn set_map(&mut self, map: Option<&StenoMap>) -> bool {
+        self.keys = match map {
+            None => STENO_KEYS.to_vec(),
+            Some(map) if map.check().is_ok() => map.strokes(),
+            Some(_) => return false,
+        };
+        self.reset();
+        true
+    }
+
+    fn reset(&mut self) {
+        self.down = Stroke::empty();
+        self.pressing = true;
+        self.seen = Stroke::empty();
+        self.held = 0;
+    }
+
     // For now, we don't do anything with the tick, but it will be needed when
     // trying to implement the hold modes.
-    pub fn tick(&mut self, _ticks: usize) {}
-    pub fn poll(&mut self) {}
+    pub fn tick(&mut self, _elapsed: Duration) {}
 
     // Handle a single event.
     pub async fn handle_event<ACT: LayoutActions>(&mut self, event: KeyEvent, actions: &ACT) {
         let key = event.key();
-        if key as usize >= STENO_KEYS.len() {
-            return;
-        }
-        if let Some(st) = STENO_KEYS[key as usize] {
+        if let Some(&Some(st)) = self.keys.get(key as usize) {
+            if !self.first_up {
+                self.handle_last_up(key, st, event.is_press(), actions).await;
+                return;
+            }
             match (event.is_press(), self.pressing) {
                 // We are expecting keys to be pressed.  Add to those seen.
                 (true, true) => {
                     self.down |= st;
+                    actions.prepare_steno(self.down).await;
                 }
                 // Expecting press, and got a release. This is our first
                 // release, so send what is seen.
@@ -70,6 +118,7 @@ impl RawStenoHandler {
                 (true, false) => {
                     self.down |= st;
                     self.pressing = true;
+                    actions.prepare_steno(self.down).await;
                 }
                 // Expecting release, and got one, just use the release.
                 (false, false) => {
@@ -78,6 +127,30 @@ impl RawStenoHandler {
             }
         }
     }
+
+    // Handle an event in last-up mode.  The stroke is everything pressed, sent once nothing is
+    // held.
+    async fn handle_last_up<ACT: LayoutActions>(&mut self, key: u8, st: Stroke, press: bool, actions: &ACT) {
+        if press {
+            self.held |= 1 << key;
+            self.seen |= st;
+            actions.prepare_steno(self.seen).await;
+        } else if self.held & (1 << key) != 0 {
+            self.held &= !(1 << key);
+            if self.held == 0 {
+                actions.send_raw_steno(self.seen).await;
+                self.seen = Stroke::empty();
+            }
+        }
+    }
+}
+
+/// The built-in steno keys, as a steno map.
+pub(crate) fn builtin_steno_map() -> StenoMap {
+    StenoMap {
+        name: String::from("builtin"),
+        keys: STENO_KEYS.iter().map(|key| key.map(Stroke::into_raw)).collect(),
+    }
 }
 
 #[cfg(feature = "proto2")]
@@ -194,3 +267,35 @@ static STENO_KEYS: &[Option<Stroke>] = &[
     Some(stroke!("E")),
 
 ];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{run, Recorder};
+
+    /// The scan code of a steno key.
+    fn scan(stroke: Stroke) -> u8 {
+        STENO_KEYS.iter().position(|k| *k == Some(stroke)).unwrap() as u8
+    }
+
+    /// Press 'S' and 'T', release 'S', press 'K', and release the rest.
+    fn roll(steno: &mut RawStenoHandler) -> Vec<Stroke> {
+        let (s, t, k) = (scan(stroke!("S")), scan(stroke!("T")), scan(stroke!("K")));
+        let rec = Recorder::default();
+        for event in [KeyEvent::Press(s), KeyEvent::Press(t), KeyEvent::Release(s),
+                      KeyEvent::Press(k), KeyEvent::Release(t), KeyEvent::Release(k)] {
+            run(steno.handle_event(event, &rec));
+        }
+        rec.strokes.into_inner()
+    }
+
+    /// First up sends as soon as a key comes up, last up only once they all have.
+    #[test]
+    fn test_first_up() {
+        let mut steno = RawStenoHandler::new();
+        assert_eq!(roll(&mut steno), vec![stroke!("ST"), stroke!("TK")]);
+
+        steno.set_first_up(false);
+        assert_eq!(roll(&mut steno), vec![stroke!("STK")]);
+    }
+}
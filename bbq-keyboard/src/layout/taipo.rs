@@ -24,6 +24,12 @@
 //! releasing some modifiers).  The modifiers will remain pressed until the two
 //! thumb keys are pressed together.  This is useful for some types of GUI
 //! manipulation, such as holding down alt while pressing tab or arrow keys.
+//!
+//! Caps word:
+//!
+//! Both thumb keys with the shift combo turn on caps word (see [`super::capsword`]), which shifts
+//! the letters of the next word.  The one shot modifiers still apply while it is on, but a key sent
+//! with control, alt or gui ends the word, as does the two thumb "null" key.
 
 // TODO: Fn key support. The function key causes the next stroke or two, if they
 // are numbers, to send function keys.
@@ -33,10 +39,15 @@ use usbd_human_interface_device::page::Keyboard;
 
 // use crate::log::info;
 
-use crate::{KeyEvent, Side, Mods, KeyAction};
+use crate::time::Duration;
+use crate::{KeyEvent, Side, Mods, KeyAction, MinorMode};
 
+use super::capsword::CapsWord;
 use super::LayoutActions;
 
+/// Keys on one side that go down within this time of each other are pressed together.
+const CHORD_TIME: Duration = Duration::from_millis(50);
+
 pub struct TaipoManager {
     /// Managing state for each side.
     sides: [SideManager; 2],
@@ -48,6 +59,9 @@ pub struct TaipoManager {
 
     /// Does the HID have a non-modifier key down?
     down: bool,
+
+    /// Caps word, shifting the letters of the word being typed.
+    caps_word: CapsWord,
 }
 
 impl Default for TaipoManager {
@@ -57,19 +71,17 @@ impl Default for TaipoManager {
             keys: TaipoEvents::new(),
             oneshot: Mods::empty(),
             down: false,
+            caps_word: CapsWord::new(),
         }
     }
 }
 
 impl TaipoManager {
-    /// Poll doesn't do anything.
-    pub fn poll(&mut self) {
-    }
-
     /// Tick is needed to track time.
-    pub async fn tick<ACT: LayoutActions>(&mut self, actions: &ACT, ticks: usize) {
-        self.sides[0].tick(&mut self.keys, ticks);
-        self.sides[1].tick(&mut self.keys, ticks);
+    pub async fn tick<ACT: LayoutActions>(&mut self, actions: &ACT, elapsed: Duration) {
+        self.sides[0].tick(&mut self.keys, elapsed);
+        self.sides[1].tick(&mut self.keys, elapsed);
+        self.caps_word.tick(elapsed);
 
         // After polling, handle any events.
         while let Some(tevent) = self.keys.pop_front() {
@@ -88,13 +100,15 @@ impl TaipoManager {
             match TAIPO_ACTIONS.iter().find(|e| e.code == tevent.code) {
                 Some(Entry { action: Action::Simple(k), .. }) => {
                     self.release_nonmod(actions).await;
-                    actions.send_key(KeyAction::KeyPress(*k, self.oneshot)).await;
+                    let mods = self.caps_word.key(*k, self.oneshot);
+                    actions.send_key(KeyAction::KeyPress(*k, mods)).await;
                     self.down = true;
                     self.oneshot = Mods::empty();
                 }
                 Some(Entry { action: Action::Shifted(k), .. }) => {
                     self.release_nonmod(actions).await;
-                    actions.send_key(KeyAction::KeyPress(*k, self.oneshot | Mods::SHIFT)).await;
+                    let mods = self.caps_word.key(*k, self.oneshot | Mods::SHIFT);
+                    actions.send_key(KeyAction::KeyPress(*k, mods)).await;
                     self.down = true;
                     self.oneshot = Mods::empty();
                 }
@@ -114,10 +128,16 @@ impl TaipoManager {
                         actions.send_key(KeyAction::KeyRelease).await;
                         self.oneshot = Mods::empty();
                     }
+                    self.caps_word.cancel();
                 }
+                Some(Entry { action: Action::CapsWord, .. }) => self.caps_word.toggle(),
                 None => (),
             }
         }
+
+        if let Some(on) = self.caps_word.changed() {
+            actions.set_sub_mode(MinorMode::CapsWord(on)).await;
+        }
     }
 
     /// Release any non-modifier keys.  Because of the alternation, which could
@@ -169,8 +189,8 @@ struct SideManager {
     pressed: u16,
     /// Keys that have been seen.
     seen: u16,
-    /// How many ticks since the last key pressed went down.
-    age: u32,
+    /// How long since the last key pressed went down.
+    age: Duration,
     /// Set when we determined a key was pressed, and sent a code. No more
     /// changes will happen.
     down: bool,
@@ -188,7 +208,7 @@ impl SideManager {
         // the key we want to send.
         if !self.down {
             self.seen |= tcode;
-            self.age = 0;
+            self.age = Duration::ZERO;
         }
         self.pressed |= tcode;
         // info!("Usmpress: down:{} seen:{}, age:{}", self.down, self.seen, self.age);
@@ -212,13 +232,13 @@ impl SideManager {
 
     }
 
-    fn tick(&mut self, keys: &mut TaipoEvents, ticks: usize) {
+    fn tick(&mut self, keys: &mut TaipoEvents, elapsed: Duration) {
         // If we already sent, or just if nothing has been pressed.
         if self.down || self.seen == 0 {
             return;
         }
-        self.age = self.age.saturating_add(ticks as u32);
-        if self.age >= 50 {
+        self.age += elapsed;
+        if self.age >= CHORD_TIME {
             let _ = keys.push_back(TaipoEvent { is_press: true, code: self.seen });
             // info!("taipo: tpress {:x}", self.seen);
             self.down = true;
@@ -228,7 +248,7 @@ impl SideManager {
 
 #[cfg(test)]
 mod test_side_manager {
-    use super::{SideManager, TaipoEvent, TaipoEvents};
+    use super::{Duration, SideManager, TaipoEvent, TaipoEvents};
 
     struct Tester {
         events: TaipoEvents,
@@ -251,8 +271,8 @@ mod test_side_manager {
             self.manager.release(keys, &mut self.events);
         }
 
-        fn spin(&mut self, ticks: usize) {
-            self.manager.tick(&mut self.events, ticks);
+        fn spin(&mut self, ms: u64) {
+            self.manager.tick(&mut self.events, Duration::from_millis(ms));
         }
 
         fn events(&mut self, events: &[TaipoEvent]) {
@@ -438,6 +458,7 @@ enum Action {
     Shifted(Keyboard),
     OneShot(Mods),
     Release,
+    CapsWord,
 }
 
 /// The mapping between each key and its Action.
@@ -446,7 +467,7 @@ struct Entry {
     action: Action,
 }
 
-static TAIPO_ACTIONS: [Entry; 126] = [
+static TAIPO_ACTIONS: [Entry; 127] = [
     // The thumb keys by themselves.
     Entry { code: 0x100, action: Action::Simple(Keyboard::Space), },
     Entry { code: 0x200, action: Action::Simple(Keyboard::DeleteBackspace), },
@@ -454,6 +475,9 @@ static TAIPO_ACTIONS: [Entry; 126] = [
     // The thumb keys together releases any modifiers.
     Entry { code: 0x300, action: Action::Release, },
 
+    // And with the shift combo, caps word.
+    Entry { code: 0x388, action: Action::CapsWord, },
+
     // Tab and variants.
     Entry { code: 0x0e0, action: Action::Simple(Keyboard::Tab), },
     Entry { code: 0x1e0, action: Action::Simple(Keyboard::DeleteForward), },
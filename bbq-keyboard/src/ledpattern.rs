@@ -0,0 +1,147 @@
+//! LED patterns stored in flash.
+//!
+//! Each LED indicator (the mode colors, and the various status displays) has a pattern built into
+//! the firmware.  The user can replace any of them with a pattern of their own, sent with
+//! [`minder::message::Leds::SetPattern`].  The replacements are kept together in the LED pattern
+//! partition (see [`minder::partition::LED_PATTERNS`]), and applied again at boot.
+//!
+//! Only the indicators that have been replaced are stored.  Which indicators there are is up to
+//! the firmware, so names are not checked here.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use minicbor::{Decode, Encode};
+use minder::{LedStep, MAX_LED_STEPS};
+
+use crate::log::warn;
+
+/// The most indicators that can have a pattern stored.
+pub const MAX_PATTERNS: usize = 32;
+
+/// The longest indicator name.
+pub const MAX_NAME: usize = 24;
+
+/// The patterns that replace the built-in ones.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+#[cbor(tag(0x6c65647061))]
+pub struct LedPatterns {
+    #[n(0)]
+    pub patterns: Vec<LedPattern>,
+}
+
+/// The pattern for a single indicator.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+pub struct LedPattern {
+    /// The indicator, as named by the firmware.
+    #[n(0)]
+    pub indicator: String,
+    #[n(1)]
+    pub steps: Vec<LedStep>,
+}
+
+impl LedPatterns {
+    /// Decode the stored patterns.  Anything that doesn't decode (such as erased flash) is None.
+    pub fn decode(data: &[u8]) -> Option<LedPatterns> {
+        match minicbor::decode(data) {
+            Ok(patterns) => Some(patterns),
+            Err(e) => {
+                warn!("No stored LED patterns: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Check a single pattern before it is stored.  Returns a description of the problem.
+    pub fn check_pattern(indicator: &str, steps: &[LedStep]) -> Result<(), String> {
+        if indicator.is_empty() || indicator.len() > MAX_NAME {
+            return Err(format!("Indicator name {:?} must be 1 to {} bytes", indicator, MAX_NAME));
+        }
+        if steps.len() > MAX_LED_STEPS {
+            return Err(format!("Pattern has {} steps, at most {} are allowed", steps.len(), MAX_LED_STEPS));
+        }
+        for (i, step) in steps.iter().enumerate() {
+            if step.color > 0xff_ffff {
+                return Err(format!("Step {} has an invalid color 0x{:x}", i, step.color));
+            }
+            if step.count == 0 {
+                return Err(format!("Step {} has a count of zero", i));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that all of the patterns can be used.  Returns a description of the first problem.
+    pub fn check(&self) -> Result<(), String> {
+        if self.patterns.len() > MAX_PATTERNS {
+            return Err(format!("{} patterns stored, at most {} are allowed", self.patterns.len(), MAX_PATTERNS));
+        }
+        for pattern in &self.patterns {
+            if pattern.steps.is_empty() {
+                return Err(format!("Pattern for {} has no steps", pattern.indicator));
+            }
+            Self::check_pattern(&pattern.indicator, &pattern.steps)?;
+        }
+        Ok(())
+    }
+
+    /// Encode the patterns, for storing.
+    pub fn encode(&self) -> Vec<u8> {
+        minicbor::to_vec(self).unwrap()
+    }
+
+    /// The stored pattern for an indicator, if it has one.
+    pub fn get(&self, indicator: &str) -> Option<&[LedStep]> {
+        self.patterns.iter().find(|p| p.indicator == indicator).map(|p| p.steps.as_slice())
+    }
+
+    /// Replace the pattern for an indicator.  No steps removes it, going back to the built-in one.
+    pub fn set(&mut self, indicator: &str, steps: &[LedStep]) {
+        self.patterns.retain(|p| p.indicator != indicator);
+        if !steps.is_empty() {
+            self.patterns.push(LedPattern { indicator: indicator.into(), steps: steps.to_vec() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ledpatterns() {
+        let steps = [LedStep { color: 0x10_00_10, count: 3 }, LedStep { color: 0, count: 3 }];
+        let mut patterns = LedPatterns::default();
+        patterns.set("qwerty", &steps);
+        patterns.set("steno", &steps[..1]);
+        assert!(patterns.check().is_ok());
+        assert_eq!(patterns.get("qwerty"), Some(&steps[..]));
+        assert_eq!(LedPatterns::decode(&patterns.encode()), Some(patterns.clone()));
+
+        // Erased flash has no patterns.
+        assert_eq!(LedPatterns::decode(&[0xff; 64]), None);
+
+        // Setting again replaces, and no steps goes back to the built-in one.
+        patterns.set("qwerty", &steps[1..]);
+        assert_eq!(patterns.get("qwerty"), Some(&steps[1..]));
+        patterns.set("qwerty", &[]);
+        assert_eq!(patterns.get("qwerty"), None);
+        assert_eq!(patterns.patterns.len(), 1);
+
+        assert!(LedPatterns::check_pattern("qwerty", &[LedStep { color: 0x100_0000, count: 1 }]).is_err());
+        assert!(LedPatterns::check_pattern("qwerty", &[LedStep { color: 0, count: 0 }]).is_err());
+        assert!(LedPatterns::check_pattern("", &steps).is_err());
+        assert!(LedPatterns::check_pattern("qwerty", &[steps[0]; MAX_LED_STEPS + 1]).is_err());
+
+        // A full set of the largest patterns still fits in the partition.
+        let mut full = LedPatterns::default();
+        for i in 0..MAX_PATTERNS {
+            let name = format!("{:0width$}", i, width = MAX_NAME);
+            full.set(&name, &[LedStep { color: 0xff_ffff, count: u16::MAX }; MAX_LED_STEPS]);
+        }
+        assert!(full.check().is_ok());
+        assert!(full.encode().len() <= minder::partition::LED_PATTERNS.size as usize);
+    }
+}
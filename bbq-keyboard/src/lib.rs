@@ -17,28 +17,54 @@ use bitflags::bitflags;
 
 pub use layout::LayoutMode;
 
+#[cfg(feature = "steno")]
 pub mod dict;
+pub mod backlight;
 pub mod boardinfo;
+pub mod debounce;
+pub mod encoder;
+pub mod engine;
+pub mod expand;
+pub mod hid;
 pub mod keys;
+#[cfg(feature = "qwerty")]
+pub mod keymap;
 pub mod ser2;
 pub mod serialize;
 pub mod modifiers;
 pub mod usb_typer;
 pub mod layout;
+pub mod ledpattern;
+pub mod macros;
+pub mod notify;
+pub mod output;
+pub mod power;
+pub mod scanrate;
+#[cfg(feature = "steno")]
+pub mod stenomap;
+pub mod time;
+pub mod trace;
+pub mod trainer;
+pub mod usage;
 
 #[cfg(feature = "std")]
 use clap::ValueEnum;
 
 #[cfg(test)]
 mod testlog;
+#[cfg(test)]
+pub(crate) mod test_util;
 
+// Not every feature combination uses all of these.
 #[cfg(not(feature = "defmt"))]
+#[allow(unused_imports)]
 mod log {
     pub use log::warn;
     pub use log::info;
 }
 
 #[cfg(feature = "defmt")]
+#[allow(unused_imports)]
 mod log {
     pub use defmt::info;
     pub use defmt::warn;
@@ -128,6 +154,23 @@ pub enum KeyAction {
     KeyRelease,
     KeySet(Vec<Keyboard>),
     Stall,
+    /// The mouse buttons now held.  Empty releases them all.
+    MousePress(MouseButtons),
+    /// Move the mouse pointer by this much, right and down.
+    MouseMove(i8, i8),
+    /// The media key now held, as a usage code on the consumer page (see [`hid::consumer_report`]).
+    /// Zero releases it.
+    Consumer(u16),
+}
+
+bitflags! {
+    /// The buttons of the mouse report.
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+    pub struct MouseButtons: u8 {
+        const LEFT = 0b0000_0001;
+        const RIGHT = 0b0000_0010;
+        const MIDDLE = 0b0000_0100;
+    }
 }
 
 bitflags! {
@@ -139,39 +182,33 @@ bitflags! {
         const SHIFT = 0b0000_0010;
         const ALT = 0b0000_0100;
         const GUI = 0b0000_1000;
+        /// The right alt key, which is AltGr on many non-US layouts, used to type the characters
+        /// printed on the third level of a key.
+        const ALTGR = 0b0100_0000;
     }
 }
 
-/// An event is something that happens in a handler to indicate some action
-/// likely needs to be performed on it.
-#[derive(Debug)]
-pub enum Event {
-    /// Events from the Matrix layer indicating changes in key actions.
-    Matrix(KeyEvent),
+/// A change in the connection to a host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HostEvent {
+    /// Change in USB status.
+    Usb(UsbDeviceState),
 
-    /// Events from the inner layer indicating changes in key actions.
-    InterKey(KeyEvent),
+    /// A BLE host connected (true) or disconnected (false).
+    Ble(bool),
+}
 
-    /// Change in USB status.
-    UsbState(UsbDeviceState),
+/// Something from the link to the other half.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InterEvent {
+    /// A key on the other half.
+    Key(KeyEvent),
 
-    /// Indicates that the inner channel has determined we are secondary.
+    /// The link has determined which side is driving.
     BecomeState(InterState),
 
-    /// Got heartbeat from secondary
+    /// Got heartbeat from secondary.
     Heartbeat,
-
-    /// Message back from the layout code that steno raw mode is enabled.
-    RawMode(bool),
-
-    /// Message received from the primary side to set out LEDs.
-    RecvLed(RGB8),
-
-    /// Led value to be sent to the other side.
-    SendLed(RGB8),
-
-    /// Tick.  Happens every 1 ms.
-    Tick,
 }
 
 /// Instead of the usb-device crate's UsbDeviceState, add our own, as the one in
@@ -186,15 +223,6 @@ pub enum UsbDeviceState {
     Resume,
 }
 
-/// A generalized event queue.  TODO: Handle the error better.  For now, we
-/// don't do anything with the error, so might as well.
-pub trait EventQueue {
-    // Attempt to push to the queue.  Events will be discarded if the queue is full.
-    fn push(&mut self, val: Event);
-    // This is not currently supported, but could be with async-trait.
-    // async fn send(&mut self, val: Event) -> Result<(), ()>;
-}
-
 /// State of inter communication.
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
 pub enum InterState {
@@ -219,9 +247,6 @@ pub enum MinorMode {
     // To start with, just distinguish artsy main from artsy nav mode.
     ArtseyMain,
     ArtseyNav,
-}
-
-/// Something we can use to get time.
-pub trait Timable {
-    fn get_ticks(&self) -> u64;
+    /// Caps word has turned on, or off, in qwerty or taipo.
+    CapsWord(bool),
 }
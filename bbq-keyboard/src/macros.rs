@@ -0,0 +1,419 @@
+//! Keyboard macros.
+//!
+//! In qwerty mode, a record key starts recording the keys sent into one of a few macro slots, and
+//! pressing a record key again stops.  A play key for the slot then sends the same keys again.
+//! What is recorded is the sequence of key reports, so modifiers and rolls come back as they were
+//! typed, but not the timing.  The mouse keys aren't recorded.
+//!
+//! The macros are kept in RAM while they are in use.  When a recording stops, they are given to
+//! [`crate::layout::LayoutActions::save_macros`], so the firmware can store them in the macro
+//! partition (see [`minder::partition::MACROS`]), and they can be loaded again at boot.
+//!
+//! A macro can also be given a name, and written on the host, then added to the stored macros
+//! over minder (see [`StoredMacros::add`]).  A recording into a named slot keeps the name.  With
+//! the `std` feature, macros can be serialized with serde, so host tools can keep them as JSON.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use minicbor::{Decode, Encode};
+#[cfg(feature = "std")]
+use serde::{Deserialize, Serialize};
+use usbd_human_interface_device::page::Keyboard;
+
+#[cfg(feature = "qwerty")]
+use crate::keymap::Keymap;
+use crate::log::{info, warn};
+use crate::KeyAction;
+
+/// The number of macro slots.
+pub const MAX_MACROS: usize = 4;
+
+/// The most key reports in a macro.  A recording that runs out of room stops there.
+pub const MAX_STEPS: usize = 64;
+
+/// The most keys recorded in a single report, the same as the boot keyboard report.
+pub const MAX_STEP_KEYS: usize = 6;
+
+/// The longest name a macro can have, in bytes.
+pub const MACRO_NAME_MAX: usize = 16;
+
+/// The macros, as stored in flash.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+#[cbor(tag(0x6d6163726f73))]
+pub struct StoredMacros {
+    #[n(0)]
+    pub macros: Vec<Macro>,
+}
+
+/// A single recorded macro.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+pub struct Macro {
+    #[n(0)]
+    pub slot: u8,
+    /// Each key report, as the HID usage codes of the keys held.
+    #[n(1)]
+    pub steps: Vec<Vec<u8>>,
+    /// A name, to manage the macro by.  Recorded macros don't have one until given one.
+    #[n(2)]
+    pub name: Option<String>,
+}
+
+impl Macro {
+    /// Decode a single macro, as sent over minder.
+    pub fn decode(data: &[u8]) -> Option<Macro> {
+        minicbor::decode(data).ok()
+    }
+
+    /// Encode a single macro, for sending over minder.
+    pub fn encode(&self) -> Vec<u8> {
+        minicbor::to_vec(self).unwrap()
+    }
+}
+
+/// Why a macro couldn't be added to the stored ones.
+#[cfg(feature = "qwerty")]
+#[derive(Debug, Eq, PartialEq)]
+pub enum AddError {
+    /// The macro can't be used, for this reason.
+    Invalid(String),
+    /// Another slot has a macro with the same name.
+    NameUsed(u8),
+    /// The slot already has a macro, and it wasn't to be replaced.
+    SlotUsed,
+    /// No key plays the macro's slot, so it could never be played.
+    Unbound,
+}
+
+impl StoredMacros {
+    /// Decode the stored macros.  Anything that doesn't decode (such as erased flash) is None.
+    pub fn decode(data: &[u8]) -> Option<StoredMacros> {
+        match minicbor::decode(data) {
+            Ok(macros) => Some(macros),
+            Err(e) => {
+                warn!("No stored macros: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Check that the macros can be used.  Returns a description of the first problem.
+    pub fn check(&self) -> Result<(), String> {
+        for (index, mac) in self.macros.iter().enumerate() {
+            if mac.slot as usize >= MAX_MACROS {
+                return Err(format!("Macro slot {} must be below {}", mac.slot, MAX_MACROS));
+            }
+            let earlier = &self.macros[..index];
+            if earlier.iter().any(|other| other.slot == mac.slot) {
+                return Err(format!("Macro slot {} is used twice", mac.slot));
+            }
+            if let Some(name) = &mac.name {
+                if name.is_empty() || name.len() > MACRO_NAME_MAX {
+                    return Err(format!("Macro {} has a name of {} bytes, it must be 1 to {}",
+                                       mac.slot, name.len(), MACRO_NAME_MAX));
+                }
+                if earlier.iter().any(|other| other.name.as_ref() == Some(name)) {
+                    return Err(format!("Macro name {:?} is used twice", name));
+                }
+            }
+            if mac.steps.len() > MAX_STEPS {
+                return Err(format!("Macro {} has {} steps, at most {} are allowed",
+                                   mac.slot, mac.steps.len(), MAX_STEPS));
+            }
+            if mac.steps.iter().any(|step| step.len() > MAX_STEP_KEYS) {
+                return Err(format!("Macro {} has a step with more than {} keys", mac.slot, MAX_STEP_KEYS));
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode the macros, for storing.
+    pub fn encode(&self) -> Vec<u8> {
+        minicbor::to_vec(self).unwrap()
+    }
+
+    /// Find a macro by name.
+    pub fn find(&self, name: &str) -> Option<&Macro> {
+        self.macros.iter().find(|mac| mac.name.as_deref() == Some(name))
+    }
+
+    /// Add a macro, made on the host, into its slot.  Its name can't be used by a macro in another
+    /// slot, and the slot has to be empty unless `replace`, so that uploading a macro doesn't lose
+    /// another by accident.  The `keymap` in use has to have a key that plays the slot.
+    #[cfg(feature = "qwerty")]
+    pub fn add(&mut self, mac: Macro, replace: bool, keymap: &Keymap) -> Result<(), AddError> {
+        if let Some(name) = &mac.name {
+            if let Some(other) = self.macros.iter().find(|other| other.slot != mac.slot && other.name.as_ref() == Some(name)) {
+                return Err(AddError::NameUsed(other.slot));
+            }
+        }
+        if !replace && self.macros.iter().any(|other| other.slot == mac.slot) {
+            return Err(AddError::SlotUsed);
+        }
+        if !keymap.plays_macro(mac.slot) {
+            return Err(AddError::Unbound);
+        }
+
+        let mut macros = self.clone();
+        macros.macros.retain(|other| other.slot != mac.slot);
+        macros.macros.push(mac);
+        macros.macros.sort_by_key(|mac| mac.slot);
+        macros.check().map_err(AddError::Invalid)?;
+        *self = macros;
+        Ok(())
+    }
+
+    /// Remove a macro by name.  Returns false if there is no such macro.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.macros.len();
+        self.macros.retain(|mac| mac.name.as_deref() != Some(name));
+        self.macros.len() != before
+    }
+}
+
+/// The macros, and any recording in progress.
+#[derive(Debug)]
+pub struct Macros {
+    /// The steps of each slot.  An empty slot plays nothing.
+    slots: Vec<Vec<Vec<u8>>>,
+
+    /// The name of each slot, if it has one.
+    names: Vec<Option<String>>,
+
+    /// The slot being recorded, and what has been recorded so far.
+    recording: Option<(u8, Vec<Vec<u8>>)>,
+}
+
+impl Default for Macros {
+    fn default() -> Self {
+        Macros {
+            slots: vec![Vec::new(); MAX_MACROS],
+            names: vec![None; MAX_MACROS],
+            recording: None,
+        }
+    }
+}
+
+impl Macros {
+    pub fn new() -> Macros {
+        Macros::default()
+    }
+
+    /// Replace the macros with stored ones.  Returns false, leaving them alone, if they can't be
+    /// used.  Any recording in progress is abandoned.
+    pub fn load(&mut self, stored: &StoredMacros) -> bool {
+        if let Err(e) = stored.check() {
+            warn!("Stored macros not used: {}", e);
+            return false;
+        }
+        *self = Macros::new();
+        for mac in &stored.macros {
+            self.slots[mac.slot as usize] = mac.steps.clone();
+            self.names[mac.slot as usize] = mac.name.clone();
+        }
+        true
+    }
+
+    /// The macros, for storing.  Empty slots are left out.
+    pub fn stored(&self) -> StoredMacros {
+        let macros = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(_, steps)| !steps.is_empty())
+            .map(|(slot, steps)| Macro { slot: slot as u8, steps: steps.clone(), name: self.names[slot].clone() })
+            .collect();
+        StoredMacros { macros }
+    }
+
+    /// The slot being recorded, if any.
+    pub fn recording(&self) -> Option<u8> {
+        self.recording.as_ref().map(|(slot, _)| *slot)
+    }
+
+    /// A record key was pressed.  Starts recording into the slot, or, if a recording is in
+    /// progress, stops it, whichever slot the key is for.  Returns true when a recording has
+    /// stopped, and the macros should be stored.
+    pub fn toggle_record(&mut self, slot: u8) -> bool {
+        if self.recording.is_some() {
+            self.stop();
+            return true;
+        }
+        if slot as usize >= MAX_MACROS {
+            return false;
+        }
+        info!("Recording macro {}", slot);
+        self.recording = Some((slot, Vec::new()));
+        false
+    }
+
+    /// Record a key report, if recording.
+    pub fn record(&mut self, keys: &[Keyboard]) {
+        let Some((_, steps)) = &mut self.recording else {
+            return;
+        };
+        if steps.len() >= MAX_STEPS {
+            warn!("Macro is full, recording stopped");
+            self.stop();
+            return;
+        }
+        steps.push(keys.iter().take(MAX_STEP_KEYS).map(|&k| u8::from(k)).collect());
+    }
+
+    /// The key actions to play a macro.  A slot that is being recorded plays nothing, so a macro
+    /// can't play itself.
+    pub fn play(&self, slot: u8) -> Vec<KeyAction> {
+        if self.recording() == Some(slot) {
+            return Vec::new();
+        }
+        self.slots
+            .get(slot as usize)
+            .map(|steps| {
+                steps
+                    .iter()
+                    .map(|step| KeyAction::KeySet(step.iter().map(|&k| Keyboard::from(k)).collect()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Stop recording, keeping what has been recorded.
+    fn stop(&mut self) {
+        if let Some((slot, steps)) = self.recording.take() {
+            info!("Recorded macro {}, {} steps", slot, steps.len());
+            self.slots[slot as usize] = steps;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "qwerty")]
+    use crate::keymap::KeyDef;
+
+    fn keysets(actions: Vec<KeyAction>) -> Vec<Vec<Keyboard>> {
+        actions
+            .into_iter()
+            .map(|act| match act {
+                KeyAction::KeySet(keys) => keys,
+                _ => panic!("Unexpected action"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut macros = Macros::new();
+
+        // Nothing is recorded until asked.
+        macros.record(&[Keyboard::A]);
+        assert!(macros.play(0).is_empty());
+
+        assert!(!macros.toggle_record(1));
+        assert_eq!(macros.recording(), Some(1));
+        macros.record(&[Keyboard::LeftShift, Keyboard::H]);
+        macros.record(&[]);
+        macros.record(&[Keyboard::I]);
+        macros.record(&[]);
+        // Can't play the macro being recorded.
+        assert!(macros.play(1).is_empty());
+        // Any record key stops.
+        assert!(macros.toggle_record(3));
+        assert_eq!(macros.recording(), None);
+
+        let expect = vec![vec![Keyboard::LeftShift, Keyboard::H], vec![], vec![Keyboard::I], vec![]];
+        assert_eq!(keysets(macros.play(1)), expect);
+
+        // Round trips through storage.
+        let stored = macros.stored();
+        assert_eq!(stored.macros.len(), 1);
+        let decoded = StoredMacros::decode(&stored.encode()).unwrap();
+        let mut loaded = Macros::new();
+        assert!(loaded.load(&decoded));
+        assert_eq!(keysets(loaded.play(1)), expect);
+
+        // Erased flash has no macros.
+        assert_eq!(StoredMacros::decode(&[0xff; 64]), None);
+        let bad = StoredMacros { macros: vec![Macro { slot: MAX_MACROS as u8, ..Macro::default() }] };
+        assert!(!loaded.load(&bad));
+
+        // A recording that fills up stops.
+        assert!(!macros.toggle_record(0));
+        for _ in 0..MAX_STEPS + 1 {
+            macros.record(&[Keyboard::A]);
+        }
+        assert_eq!(macros.recording(), None);
+        assert_eq!(macros.play(0).len(), MAX_STEPS);
+    }
+
+    #[test]
+    fn test_full_fits() {
+        // The largest set of macros still fits in the partition.
+        let step = vec![0xe7; MAX_STEP_KEYS];
+        let stored = StoredMacros {
+            macros: (0..MAX_MACROS)
+                .map(|slot| Macro {
+                    slot: slot as u8,
+                    steps: vec![step.clone(); MAX_STEPS],
+                    name: Some(format!("{:width$}", slot, width = MACRO_NAME_MAX)),
+                })
+                .collect(),
+        };
+        assert!(stored.check().is_ok());
+        assert!(stored.encode().len() <= minder::partition::MACROS.size as usize);
+    }
+
+    #[test]
+    #[cfg(feature = "qwerty")]
+    fn test_add() {
+        let keymap = Keymap::builtin();
+        let named = |slot: u8, name: &str| Macro {
+            slot,
+            steps: vec![vec![u8::from(Keyboard::A)], vec![]],
+            name: Some(name.to_string()),
+        };
+
+        let mut stored = StoredMacros::default();
+        assert_eq!(stored.add(named(2, "sig"), false, &keymap), Ok(()));
+        assert_eq!(stored.add(named(1, "addr"), false, &keymap), Ok(()));
+        assert_eq!(stored.macros.iter().map(|mac| mac.slot).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(stored.find("sig").map(|mac| mac.slot), Some(2));
+
+        // A name only goes with one slot, and a slot isn't replaced unless asked.
+        assert_eq!(stored.add(named(0, "sig"), true, &keymap), Err(AddError::NameUsed(2)));
+        assert_eq!(stored.add(named(2, "other"), false, &keymap), Err(AddError::SlotUsed));
+        assert_eq!(stored.add(named(2, "other"), true, &keymap), Ok(()));
+        assert_eq!(stored.find("sig"), None);
+
+        // A slot no key plays is no use.
+        let mut unbound = keymap.clone();
+        for layer in &mut unbound.layers {
+            layer.keys.retain(|def| *def != KeyDef::MacroPlay(3));
+        }
+        assert_eq!(stored.add(named(3, "x"), false, &unbound), Err(AddError::Unbound));
+
+        let long = "x".repeat(MACRO_NAME_MAX + 1);
+        assert!(matches!(stored.add(named(3, &long), false, &keymap), Err(AddError::Invalid(_))));
+        assert_eq!(stored.macros.len(), 2);
+
+        let mac = stored.find("addr").unwrap();
+        assert_eq!(Macro::decode(&mac.encode()).as_ref(), Some(mac));
+
+        assert!(stored.remove("addr"));
+        assert!(!stored.remove("addr"));
+        assert_eq!(stored.macros.len(), 1);
+
+        // Names survive loading, and recording again into the slot.
+        let mut macros = Macros::new();
+        assert!(macros.load(&stored));
+        assert!(!macros.toggle_record(2));
+        macros.record(&[Keyboard::B]);
+        assert!(macros.toggle_record(2));
+        assert_eq!(macros.stored().find("other").map(|mac| mac.steps.len()), Some(1));
+    }
+}
@@ -0,0 +1,105 @@
+//! Host notifications.
+//!
+//! When something goes wrong that the user needs to know about, and there is no companion program
+//! on the host to tell, the keyboard can type a short message.  As typing into whatever has focus is
+//! fairly intrusive, this is off unless configured (see [`BoardInfo`]), and each alert is heavily
+//! rate limited.
+//!
+//! [`BoardInfo`]: crate::boardinfo::BoardInfo
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+
+/// The conditions that can be reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Alert {
+    FlashWriteFailed,
+    NoDictionary,
+}
+
+const NUM_ALERTS: usize = 2;
+
+impl Alert {
+    fn index(self) -> usize {
+        match self {
+            Alert::FlashWriteFailed => 0,
+            Alert::NoDictionary => 1,
+        }
+    }
+
+    /// A short description, used in the typed message.
+    pub fn message(self) -> &'static str {
+        match self {
+            Alert::FlashWriteFailed => "flash write failed",
+            Alert::NoDictionary => "no steno dictionary",
+        }
+    }
+}
+
+/// Minimum time between repeats of the same alert, in ms.
+const REPEAT_MS: u64 = 10 * 60 * 1000;
+
+/// Minimum time between any two alerts, in ms.
+const ANY_MS: u64 = 60 * 1000;
+
+/// Decide when to notify, and what to type.
+pub struct Notifier {
+    /// The prefix to type before the message.  None means notifications are disabled.
+    prefix: Option<String>,
+
+    /// When each alert was last sent.
+    last: [Option<u64>; NUM_ALERTS],
+
+    /// When any alert was last sent.
+    last_any: Option<u64>,
+}
+
+impl Notifier {
+    pub fn new(prefix: Option<String>) -> Notifier {
+        Notifier {
+            prefix,
+            last: [None; NUM_ALERTS],
+            last_any: None,
+        }
+    }
+
+    /// Raise an alert at the given time (in ms).  Returns the text to type, if the alert should be
+    /// shown now.
+    pub fn alert(&mut self, alert: Alert, now: u64) -> Option<String> {
+        let prefix = self.prefix.as_ref()?;
+
+        let recent = |last: Option<u64>, limit| last.is_some_and(|t| now.saturating_sub(t) < limit);
+        if recent(self.last[alert.index()], REPEAT_MS) || recent(self.last_any, ANY_MS) {
+            return None;
+        }
+        self.last[alert.index()] = Some(now);
+        self.last_any = Some(now);
+
+        Some(format!(" [{}: {}] ", prefix, alert.message()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut off = Notifier::new(None);
+        assert_eq!(off.alert(Alert::FlashWriteFailed, 0), None);
+
+        let mut note = Notifier::new(Some("kbd".into()));
+        assert_eq!(
+            note.alert(Alert::FlashWriteFailed, 1000).as_deref(),
+            Some(" [kbd: flash write failed] ")
+        );
+        // Nothing else within the global limit.
+        assert_eq!(note.alert(Alert::NoDictionary, 1000 + ANY_MS - 1), None);
+        assert!(note.alert(Alert::NoDictionary, 1000 + ANY_MS).is_some());
+        // The same alert is limited for longer.
+        assert_eq!(note.alert(Alert::FlashWriteFailed, 1000 + 2 * ANY_MS), None);
+        assert!(note.alert(Alert::FlashWriteFailed, 1000 + REPEAT_MS).is_some());
+    }
+}
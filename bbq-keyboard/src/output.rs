@@ -0,0 +1,145 @@
+//! Output safeguards.
+//!
+//! A runaway macro, or a dictionary entry that expands to thousands of characters, can flood the
+//! host with keystrokes faster than anyone can stop it.  The limiter caps the rate of HID reports
+//! sent for typed output, drops single translations that are too long to be anything but a
+//! mistake, and supports an emergency kill (a chord in the mode selector) that aborts whatever
+//! typing is still in flight.
+//!
+//! Keys typed directly, such as in qwerty mode, are not limited, as they are already limited by
+//! how fast someone can press keys.
+
+/// Translations longer than this many characters are dropped instead of typed.
+pub const MAX_TRANSLATION: usize = 1024;
+
+/// How many reports can be sent back to back before the rate cap applies.
+pub const BURST: u32 = 32;
+
+/// Counts of output that was stopped.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OutputStats {
+    /// Times the output has been killed.
+    pub kills: u32,
+    /// Translations dropped for being too long.
+    pub dropped: u32,
+}
+
+/// Rate limiting and aborting of typed output.
+#[derive(Debug, Default)]
+pub struct OutputLimiter {
+    /// The maximum rate, in reports per second.  Zero is unlimited.
+    rate: u32,
+
+    /// Available reports, in thousandths of a report.  Negative when reports have been sent ahead
+    /// of the rate, and the sender must wait.
+    credit: i64,
+
+    /// The time `credit` was last updated, in ms.
+    last: u64,
+
+    /// Bumped on each kill.  Typing in progress notes this at the start, and stops when it changes.
+    generation: u32,
+
+    stats: OutputStats,
+}
+
+impl OutputLimiter {
+    pub fn new() -> OutputLimiter {
+        OutputLimiter::default()
+    }
+
+    /// Set the maximum rate, in reports per second.  None, or 0, removes the cap.
+    pub fn set_rate(&mut self, rate: Option<u32>) {
+        self.rate = rate.unwrap_or(0);
+        self.credit = BURST as i64 * 1000;
+    }
+
+    /// Account for sending one report at `now` (in ms).  Returns how long, in ms, to wait before
+    /// sending it.
+    pub fn delay(&mut self, now: u64) -> u64 {
+        if self.rate == 0 {
+            return 0;
+        }
+        let elapsed = now.saturating_sub(self.last);
+        self.last = self.last.max(now);
+        let limit = BURST as i64 * 1000;
+        self.credit = (self.credit + (elapsed as i64).saturating_mul(self.rate as i64)).min(limit);
+        self.credit -= 1000;
+        if self.credit >= 0 {
+            0
+        } else {
+            (-self.credit as u64).div_ceil(self.rate as u64)
+        }
+    }
+
+    /// Check a translation before it is typed.  Returns false, and counts it, if it should be
+    /// dropped.
+    pub fn check_text(&mut self, text: &str) -> bool {
+        if text.chars().count() > MAX_TRANSLATION {
+            self.stats.dropped += 1;
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Abort all typing in progress.
+    pub fn kill(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.stats.kills += 1;
+    }
+
+    /// The current generation.  Typing that started in a different generation has been killed.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn stats(&self) -> OutputStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate() {
+        let mut limiter = OutputLimiter::new();
+
+        // Unlimited by default.
+        for _ in 0..1000 {
+            assert_eq!(limiter.delay(0), 0);
+        }
+
+        // 100 reports per second, after the burst, is a report every 10ms.
+        limiter.set_rate(Some(100));
+        for _ in 0..BURST {
+            assert_eq!(limiter.delay(0), 0);
+        }
+        assert_eq!(limiter.delay(0), 10);
+        assert_eq!(limiter.delay(0), 20);
+        assert_eq!(limiter.delay(20), 10);
+
+        // Waiting refills the credit, up to the burst.
+        assert_eq!(limiter.delay(10_000), 0);
+        for _ in 1..BURST {
+            assert_eq!(limiter.delay(10_000), 0);
+        }
+        assert_eq!(limiter.delay(10_000), 10);
+
+        limiter.set_rate(None);
+        assert_eq!(limiter.delay(10_000), 0);
+    }
+
+    #[test]
+    fn test_kill() {
+        let mut limiter = OutputLimiter::new();
+        let generation = limiter.generation();
+        assert!(limiter.check_text("hello"));
+        assert!(!limiter.check_text(&"a".repeat(MAX_TRANSLATION + 1)));
+        limiter.kill();
+        assert_ne!(limiter.generation(), generation);
+        assert_eq!(limiter.stats(), OutputStats { kills: 1, dropped: 1 });
+    }
+}
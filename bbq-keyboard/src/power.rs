@@ -0,0 +1,260 @@
+//! Power policy.
+//!
+//! On battery, most of the power not spent on the radio goes to waking up: scanning the matrix,
+//! refreshing the LEDs, and sampling the usage stats.  When typing is slow, none of these need to
+//! be as responsive as they are while typing quickly, so the policy backs them all off together
+//! once the sustained typing rate (from [`crate::usage::Usage::rate`]) drops low.  A burst of keys
+//! brings everything back right away, without waiting for the sustained rate to catch up.
+//!
+//! Once nothing at all has happened for a while longer, the keyboard goes to sleep: the matrix
+//! isn't scanned until a key press interrupts, the LEDs go dark, and a split secondary powers down
+//! the link to the other half.  Any key wakes it again.
+//!
+//! On external power, the policy always stays at full responsiveness.
+
+use crate::time::{Duration, Instant};
+
+/// Below this many keys a minute, typing is slow enough to save power.
+pub const LOW_RATE: u32 = 60;
+
+/// This many keys pressed within [`BURST_WINDOW`] is a burst.
+pub const BURST_KEYS: usize = 3;
+
+/// How close together the keys of a burst are.
+pub const BURST_WINDOW: Duration = Duration::from_millis(1000);
+
+/// How long a burst keeps full responsiveness, even if the sustained rate is still low.
+pub const BURST_HOLD: Duration = Duration::from_secs(30);
+
+/// LED refresh interval at full responsiveness.
+pub const LED_PERIOD: Duration = Duration::from_millis(100);
+
+/// LED refresh interval when saving power.
+pub const LED_PERIOD_SAVING: Duration = Duration::from_millis(500);
+
+/// Usage stats sampling interval at full responsiveness.
+pub const STATS_PERIOD: Duration = Duration::from_millis(10);
+
+/// Usage stats sampling interval when saving power.
+pub const STATS_PERIOD_SAVING: Duration = Duration::from_millis(100);
+
+/// How long without any activity before sleeping, unless the board info gives another.
+pub const SLEEP_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// How responsive to be.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PowerLevel {
+    Full,
+    Saving,
+    /// Idle long enough to sleep, until a key is pressed.
+    Asleep,
+}
+
+pub struct PowerPolicy {
+    /// Running from the battery.
+    on_battery: bool,
+    /// The most recent sustained typing rate, in keys a minute.
+    rate: u32,
+    /// The times of the last few key presses, oldest first.
+    presses: [Option<Instant>; BURST_KEYS],
+    /// When the last burst was seen.
+    burst: Option<Instant>,
+    /// When there was last any activity, once known.
+    active: Option<Instant>,
+    /// How long without activity before sleeping.  None never sleeps.
+    sleep_after: Option<Duration>,
+    level: PowerLevel,
+}
+
+impl PowerPolicy {
+    pub fn new() -> PowerPolicy {
+        PowerPolicy {
+            on_battery: false,
+            rate: 0,
+            presses: [None; BURST_KEYS],
+            burst: None,
+            active: None,
+            sleep_after: Some(SLEEP_AFTER),
+            level: PowerLevel::Full,
+        }
+    }
+
+    /// Set how long without activity before sleeping, or None to never sleep.
+    pub fn set_sleep_after(&mut self, sleep_after: Option<Duration>) {
+        self.sleep_after = sleep_after;
+    }
+
+    /// Is the keyboard asleep.
+    pub fn is_asleep(&self) -> bool {
+        self.level == PowerLevel::Asleep
+    }
+
+    /// The current level.
+    pub fn level(&self) -> PowerLevel {
+        self.level
+    }
+
+    /// Note whether the keyboard is running from the battery.  Going to external power restores
+    /// full responsiveness right away.
+    pub fn set_battery(&mut self, on_battery: bool) {
+        self.on_battery = on_battery;
+        if !on_battery {
+            self.level = PowerLevel::Full;
+        }
+    }
+
+    /// Note activity other than typing, such as turning the encoder, which keeps the keyboard
+    /// awake, and wakes it if it is asleep.  Returns the new level, if it has changed.
+    pub fn add_activity(&mut self, now: Instant) -> Option<PowerLevel> {
+        self.active = Some(now);
+        if self.level != PowerLevel::Asleep {
+            return None;
+        }
+        // The rate decides between full and saving at the next update.
+        self.level = PowerLevel::Full;
+        Some(PowerLevel::Full)
+    }
+
+    /// Note a key press.  A burst restores full responsiveness right away.  Returns the new level,
+    /// if it has changed.
+    pub fn add_key(&mut self, now: Instant) -> Option<PowerLevel> {
+        let before = self.level;
+        self.add_activity(now);
+        self.presses.rotate_left(1);
+        self.presses[BURST_KEYS - 1] = Some(now);
+        if let Some(first) = self.presses[0] {
+            if now - first <= BURST_WINDOW {
+                self.burst = Some(now);
+                self.level = PowerLevel::Full;
+            }
+        }
+        (self.level != before).then_some(self.level)
+    }
+
+    /// Update with the sustained typing rate, in keys a minute.  Returns the new level, if it has
+    /// changed.
+    pub fn update(&mut self, now: Instant, rate: u32) -> Option<PowerLevel> {
+        self.rate = rate;
+        let active = *self.active.get_or_insert(now);
+        let idle = self.sleep_after.is_some_and(|after| now - active >= after);
+        let bursting = self.burst.map(|burst| now - burst < BURST_HOLD).unwrap_or(false);
+        let level = if self.on_battery && idle {
+            PowerLevel::Asleep
+        } else if self.on_battery && rate < LOW_RATE && !bursting {
+            PowerLevel::Saving
+        } else {
+            PowerLevel::Full
+        };
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+
+    /// How often to refresh the LEDs.
+    pub fn led_period(&self) -> Duration {
+        match self.level {
+            PowerLevel::Full => LED_PERIOD,
+            PowerLevel::Saving | PowerLevel::Asleep => LED_PERIOD_SAVING,
+        }
+    }
+
+    /// How often to sample the usage stats.
+    pub fn stats_period(&self) -> Duration {
+        match self.level {
+            PowerLevel::Full => STATS_PERIOD,
+            PowerLevel::Saving | PowerLevel::Asleep => STATS_PERIOD_SAVING,
+        }
+    }
+
+    /// Whether the matrix scan may speed up while keys are changing (see
+    /// [`crate::scanrate::ScanRate::set_saving`]).
+    pub fn scan_boost(&self) -> bool {
+        self.level == PowerLevel::Full
+    }
+}
+
+impl Default for PowerPolicy {
+    fn default() -> Self {
+        PowerPolicy::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_power_policy() {
+        let mut policy = PowerPolicy::new();
+        let mut now = Instant::from_micros(0);
+
+        // External power never saves.
+        assert_eq!(policy.update(now, 0), None);
+        assert_eq!(policy.level(), PowerLevel::Full);
+
+        policy.set_battery(true);
+        assert_eq!(policy.update(now, LOW_RATE), None);
+        assert_eq!(policy.update(now, LOW_RATE - 1), Some(PowerLevel::Saving));
+        assert_eq!(policy.led_period(), LED_PERIOD_SAVING);
+        assert_eq!(policy.stats_period(), STATS_PERIOD_SAVING);
+        assert!(!policy.scan_boost());
+
+        // Slow keys aren't a burst.
+        for _ in 0..BURST_KEYS {
+            now += BURST_WINDOW;
+            policy.add_key(now);
+            assert_eq!(policy.level(), PowerLevel::Saving);
+        }
+
+        // A burst restores right away, and holds even though the rate is low.
+        now += Duration::from_secs(5);
+        for _ in 0..BURST_KEYS {
+            now += Duration::from_millis(100);
+            policy.add_key(now);
+        }
+        assert_eq!(policy.level(), PowerLevel::Full);
+        assert!(policy.scan_boost());
+        assert_eq!(policy.update(now + (BURST_HOLD - Duration::from_millis(1)), 0), None);
+        assert_eq!(policy.update(now + BURST_HOLD, 0), Some(PowerLevel::Saving));
+
+        // Plugging in restores.
+        policy.set_battery(false);
+        assert_eq!(policy.level(), PowerLevel::Full);
+        assert_eq!(policy.update(now + BURST_HOLD, 0), None);
+    }
+
+    #[test]
+    fn test_sleep() {
+        let mut policy = PowerPolicy::new();
+        let mut now = Instant::from_micros(0);
+        policy.set_battery(true);
+        policy.set_sleep_after(Some(Duration::from_secs(60)));
+
+        // Saving first, and asleep once idle long enough.
+        assert_eq!(policy.update(now, 0), Some(PowerLevel::Saving));
+        now += Duration::from_secs(30);
+        assert_eq!(policy.update(now, 0), None);
+        assert_eq!(policy.add_activity(now), None);
+        now += Duration::from_secs(59);
+        assert_eq!(policy.update(now, 0), None);
+        now += Duration::from_secs(1);
+        assert_eq!(policy.update(now, 0), Some(PowerLevel::Asleep));
+        assert!(policy.is_asleep());
+        assert!(!policy.scan_boost());
+
+        // A key wakes it.
+        assert_eq!(policy.add_key(now), Some(PowerLevel::Full));
+        assert!(!policy.is_asleep());
+        assert_eq!(policy.update(now, 0), Some(PowerLevel::Saving));
+
+        // Never on external power, or when turned off.
+        now += Duration::from_secs(120);
+        policy.set_battery(false);
+        assert_eq!(policy.update(now, 0), None);
+        policy.set_battery(true);
+        policy.set_sleep_after(None);
+        assert_eq!(policy.update(now, 0), Some(PowerLevel::Saving));
+    }
+}
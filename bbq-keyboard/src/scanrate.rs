@@ -0,0 +1,177 @@
+//! Adaptive matrix scan rate.
+//!
+//! Scanning the matrix at a fixed 1kHz is more than is needed when nothing is happening, and can
+//! be a bit slow to resolve a fast chord.  This decides the interval until the next scan, based
+//! on what the last scan saw: faster while keys are changing, the normal rate while keys are
+//! held, and slower once the keyboard has been idle for a little while.  Idle for longer, it goes
+//! dormant: slower still, with the board expected to arm a key interrupt between scans, so that the
+//! first press is seen right away rather than at the next scan.
+//!
+//! As the interval varies, the debouncer needs to work with the actual time between scans, rather
+//! than counting them.
+
+use crate::time::Duration;
+
+/// Interval while keys are transitioning.
+pub const FAST: Duration = Duration::from_micros(500);
+
+/// The normal interval.
+pub const NORMAL: Duration = Duration::from_micros(1000);
+
+/// Interval when idle.
+pub const SLOW: Duration = Duration::from_micros(4000);
+
+/// Interval when dormant, with a key interrupt to catch the first press.
+pub const DORMANT: Duration = Duration::from_millis(10);
+
+/// How long without activity before dropping to the slow rate.
+const IDLE: Duration = Duration::from_millis(100);
+
+/// How long without activity before dropping to the slow rate, when saving power.
+const IDLE_SAVING: Duration = Duration::from_millis(20);
+
+/// How long without activity before going dormant.
+const IDLE_DORMANT: Duration = Duration::from_millis(1000);
+
+/// How long without activity before going dormant, when saving power.
+const IDLE_DORMANT_SAVING: Duration = Duration::from_millis(200);
+
+/// What a scan of the matrix found.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Activity {
+    /// No keys pressed.
+    Idle,
+    /// Keys are pressed, but none are changing.
+    Held,
+    /// At least one key is changing (being debounced).
+    Transition,
+}
+
+pub struct ScanRate {
+    /// How long we've been idle.
+    idle: Duration,
+    /// The current interval.
+    interval: Duration,
+    /// Saving power: never go fast, and slow down sooner.
+    saving: bool,
+}
+
+impl ScanRate {
+    pub fn new() -> ScanRate {
+        ScanRate {
+            idle: Duration::ZERO,
+            interval: NORMAL,
+            saving: false,
+        }
+    }
+
+    /// Save power, at the cost of responsiveness (see [`crate::power::PowerPolicy`]).
+    pub fn set_saving(&mut self, saving: bool) {
+        self.saving = saving;
+    }
+
+    /// The interval to wait before the next scan.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Has the keyboard been idle long enough that a key press should interrupt the wait for the
+    /// next scan.
+    pub fn is_dormant(&self) -> bool {
+        self.interval == DORMANT
+    }
+
+    /// Update after a scan.  `elapsed` is the time since the previous scan, and `cost` how long the
+    /// scan itself took.  The fast rate is only used if the scan is cheap enough to leave most of
+    /// the interval for everything else.
+    pub fn update(&mut self, activity: Activity, elapsed: Duration, cost: Duration) {
+        match activity {
+            Activity::Transition => {
+                self.idle = Duration::ZERO;
+                self.interval = if !self.saving && cost + cost <= FAST { FAST } else { NORMAL };
+            }
+            Activity::Held => {
+                self.idle = Duration::ZERO;
+                self.interval = NORMAL;
+            }
+            Activity::Idle => {
+                self.idle += elapsed;
+                let (idle, dormant) = if self.saving {
+                    (IDLE_SAVING, IDLE_DORMANT_SAVING)
+                } else {
+                    (IDLE, IDLE_DORMANT)
+                };
+                self.interval = if self.idle >= dormant {
+                    DORMANT
+                } else if self.idle >= idle {
+                    SLOW
+                } else {
+                    NORMAL
+                };
+            }
+        }
+    }
+}
+
+impl Default for ScanRate {
+    fn default() -> Self {
+        ScanRate::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scan_rate() {
+        let cheap = Duration::from_micros(50);
+        let mut rate = ScanRate::new();
+        assert_eq!(rate.interval(), NORMAL);
+
+        rate.update(Activity::Transition, NORMAL, cheap);
+        assert_eq!(rate.interval(), FAST);
+
+        // Too slow a scan can't go fast.
+        rate.update(Activity::Transition, FAST, Duration::from_micros(400));
+        assert_eq!(rate.interval(), NORMAL);
+
+        rate.update(Activity::Held, NORMAL, cheap);
+        assert_eq!(rate.interval(), NORMAL);
+
+        // Decay to slow after a short idle.
+        let mut time = Duration::ZERO;
+        while time < IDLE - NORMAL {
+            rate.update(Activity::Idle, NORMAL, cheap);
+            assert_eq!(rate.interval(), NORMAL);
+            time += NORMAL;
+        }
+        rate.update(Activity::Idle, NORMAL, cheap);
+        assert_eq!(rate.interval(), SLOW);
+        assert!(!rate.is_dormant());
+        time += NORMAL;
+
+        // And dormant after a longer one.
+        while time < IDLE_DORMANT - SLOW {
+            rate.update(Activity::Idle, SLOW, cheap);
+            time += SLOW;
+        }
+        assert_eq!(rate.interval(), SLOW);
+        rate.update(Activity::Idle, SLOW, cheap);
+        assert_eq!(rate.interval(), DORMANT);
+        assert!(rate.is_dormant());
+
+        rate.update(Activity::Transition, DORMANT, cheap);
+        assert_eq!(rate.interval(), FAST);
+        assert!(!rate.is_dormant());
+
+        // Saving power doesn't go fast, and slows down sooner.
+        rate.set_saving(true);
+        rate.update(Activity::Transition, FAST, cheap);
+        assert_eq!(rate.interval(), NORMAL);
+        rate.update(Activity::Idle, IDLE_SAVING, cheap);
+        assert_eq!(rate.interval(), SLOW);
+        rate.update(Activity::Idle, IDLE_DORMANT_SAVING - IDLE_SAVING, cheap);
+        assert_eq!(rate.interval(), DORMANT);
+    }
+}
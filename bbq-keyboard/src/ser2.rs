@@ -31,19 +31,62 @@
 //! 
 //! The inter-side manager will generally be wrapped in the implementation side with specific code
 //! to read/write the UART or other interface between the boards.
+//!
+//! Over a noisy cable, packets are lost (the CRC rejects any that are damaged), and a key that goes
+//! down and up between two packets that get through would be missed entirely.  So the Secondary
+//! also sends each key event, numbered, and keeps sending it until the Primary acknowledges it (see
+//! [`KeySender`] and [`KeyReceiver`]).  The key bitmap is still sent, so the two sides settle on
+//! the same keys held even if events are lost.
+//!
+//! The link is slow enough that a large packet, or one per tick from each of several things, can
+//! hold up the key events behind it.  [`LinkBudget`] keeps track of how much has been sent each
+//! tick, so that less urgent packets (LED state, heartbeats) wait for room, while key events are
+//! always sent right away, even cutting short a less urgent packet already being sent.
+//!
+//! A bad cable, or a loose connector, shows up as receive errors from the UART (overruns, and
+//! framing errors), which would otherwise be silently dropped along with the packets they damage.
+//! [`LinkQuality`] counts them, so they can be seen from the host, and says when they come fast
+//! enough that the receiver should start over at the next packet.
+//!
+//! Optionally, the link can be authenticated (see [`LinkAuth`]), so that a device plugged into the
+//! link can't inject keys by pretending to be the other half.  Both halves are given the same key
+//! in their board info.  Note that this doesn't hide the keys, only prevents forging them.
+
+extern crate alloc;
+
+use core::hash::Hasher;
 
+use alloc::vec::Vec;
+use arraydeque::{ArrayDeque, Wrapping};
+use bitflags::bitflags;
 use minicbor::{Decode, Encode};
+use siphasher::sip::SipHasher24;
 use smart_leds::RGB8;
 
-use crate::Side;
+use crate::{KeyEvent, Side};
 
 /// The bits representing the keys that have been pressed.  The bits are numbered with 0x01 in the
 /// first byte being 0, 0x80 being bit 7, and bit 8 being 0x01 in the `[1]` byte.  The size
 /// corresponds with number of keys possible.
 pub type KeyBits = [u8; 6];
 
+/// The key shared by the two halves, to authenticate the link.
+pub type LinkKey = [u8; 16];
+
+/// The number of keys in [`KeyBits`].
+const KEY_COUNT: u8 = 48;
+
+/// The most key events carried by a single packet.
+pub const PACKET_EVENTS: usize = 4;
+
+/// The most key events waiting to be acknowledged.  Beyond this, the oldest are dropped.
+const QUEUE_EVENTS: usize = 32;
+
+/// Set in an event byte for a press.
+const EVENT_PRESS: u8 = 0x80;
+
 /// The packet consists of multiple fields, many of which are optional.
-#[derive(Debug, Decode, Encode, PartialEq, Eq)]
+#[derive(Clone, Debug, Decode, Encode, PartialEq, Eq)]
 pub struct Packet {
     /// Our role, as much as is known.
     #[n(0)]
@@ -62,6 +105,28 @@ pub struct Packet {
     #[n(3)]
     #[cbor(with = "rgbcbor")]
     pub leds: Option<RGB8>,
+    /// For authenticated links, the sender's nonce for this session.  The other side includes this
+    /// in its MACs, so packets from earlier sessions can't be replayed.
+    #[n(4)]
+    pub nonce: Option<u32>,
+    /// For authenticated links, a count of the packets sent this session.
+    #[n(5)]
+    pub counter: Option<u32>,
+    /// For authenticated links, the MAC of the rest of the packet.
+    #[n(6)]
+    #[cbor(with = "minicbor::bytes")]
+    pub mac: Option<[u8; 4]>,
+    /// For the Secondary role, the sequence number of the first of `events`.
+    #[n(7)]
+    pub seq: Option<u16>,
+    /// For the Secondary role, key events not yet acknowledged, oldest first.  Each is the key
+    /// number, with [`EVENT_PRESS`] set for a press.
+    #[n(8)]
+    #[cbor(with = "minicbor::bytes")]
+    pub events: Option<Vec<u8>>,
+    /// For the Primary role, the sequence number of the next key event expected.
+    #[n(9)]
+    pub ack: Option<u16>,
 }
 
 impl Packet {
@@ -71,6 +136,12 @@ impl Packet {
             side,
             keys: None,
             leds: None,
+            nonce: None,
+            counter: None,
+            mac: None,
+            seq: None,
+            events: None,
+            ack: None,
         }
     }
 
@@ -85,6 +156,247 @@ impl Packet {
     }
 }
 
+/// The Secondary's side of the key events.
+///
+/// Events are numbered as they are added, and are sent until acknowledged.  The numbering starts
+/// at an arbitrary value each session, so the Primary can tell when the Secondary has restarted.
+pub struct KeySender {
+    /// Events that haven't been acknowledged, and their sequence numbers.
+    queue: ArrayDeque<(u16, u8), QUEUE_EVENTS, Wrapping>,
+    /// The sequence number for the next event.
+    next: u16,
+    /// The keys currently held.
+    keys: KeyBits,
+}
+
+impl KeySender {
+    /// A sender, with the first event numbered `seq`.
+    pub fn new(seq: u16) -> KeySender {
+        KeySender {
+            queue: ArrayDeque::new(),
+            next: seq,
+            keys: KeyBits::default(),
+        }
+    }
+
+    /// Add a key event to be sent.
+    pub fn add_key(&mut self, key: KeyEvent) {
+        let code = key.key();
+        if code >= KEY_COUNT {
+            return;
+        }
+        let bit = 1u8 << (code % 8);
+        if key.is_press() {
+            self.keys[code as usize / 8] |= bit;
+        } else {
+            self.keys[code as usize / 8] &= !bit;
+        }
+        let event = if key.is_press() { code | EVENT_PRESS } else { code };
+        self.queue.push_back((self.next, event));
+        self.next = self.next.wrapping_add(1);
+    }
+
+    /// Add the pending events to a packet.  The key bitmap is only added when all of the events
+    /// fit, as the Primary takes it as the state after them.
+    pub fn fill(&self, packet: &mut Packet) {
+        if let Some(&(seq, _)) = self.queue.front() {
+            packet.seq = Some(seq);
+            packet.events = Some(self.queue.iter().take(PACKET_EVENTS).map(|&(_, ev)| ev).collect());
+        }
+        if self.queue.len() <= PACKET_EVENTS {
+            packet.keys = Some(self.keys);
+        }
+    }
+
+    /// The Primary has seen every event before `ack`.
+    pub fn ack(&mut self, ack: u16) {
+        // Anything outside of the queue is from before a restart of either side, and is ignored.
+        let acked = ack.wrapping_sub(self.next.wrapping_sub(self.queue.len() as u16));
+        if acked as usize <= self.queue.len() {
+            for _ in 0..acked {
+                self.queue.pop_front();
+            }
+        }
+    }
+}
+
+/// The Primary's side of the key events.
+pub struct KeyReceiver {
+    /// The keys the Secondary holds, as far as we know.
+    keys: KeyBits,
+    /// The sequence number of the next event expected.  None until the first is seen.
+    expected: Option<u16>,
+}
+
+impl KeyReceiver {
+    pub fn new() -> KeyReceiver {
+        KeyReceiver {
+            keys: KeyBits::default(),
+            expected: None,
+        }
+    }
+
+    /// Handle the keys in a packet from the Secondary, calling `event` for each change, in order.
+    pub fn receive(&mut self, packet: &Packet, mut event: impl FnMut(KeyEvent)) {
+        if let (Some(seq), Some(events)) = (packet.seq, &packet.events) {
+            // The Secondary never has more than a queue of events outstanding, so the first it
+            // sends is never further back than that.  Anything else is a new session.
+            let skip = match self.expected {
+                Some(expected) if expected.wrapping_sub(seq) as usize <= QUEUE_EVENTS => {
+                    expected.wrapping_sub(seq) as usize
+                }
+                _ => 0,
+            };
+            for &ev in events.iter().skip(skip) {
+                self.apply(ev & !EVENT_PRESS, ev & EVENT_PRESS != 0, &mut event);
+            }
+            if skip <= events.len() {
+                self.expected = Some(seq.wrapping_add(events.len() as u16));
+            }
+        }
+
+        // Catch up with anything missed.  Usually, the events have already done this.
+        if let Some(keys) = packet.keys {
+            for code in 0..KEY_COUNT {
+                let held = keys[code as usize / 8] & (1 << (code % 8)) != 0;
+                self.apply(code, held, &mut event);
+            }
+        }
+    }
+
+    /// The acknowledgement to send back to the Secondary.
+    pub fn ack(&self) -> Option<u16> {
+        self.expected
+    }
+
+    /// The Secondary has gone quiet.  Release everything it held, so nothing is stuck down.
+    pub fn release_all(&mut self, mut event: impl FnMut(KeyEvent)) {
+        for code in 0..KEY_COUNT {
+            self.apply(code, false, &mut event);
+        }
+    }
+
+    /// Record a key as held or not, calling `event` if this changes it.
+    fn apply(&mut self, code: u8, press: bool, event: &mut impl FnMut(KeyEvent)) {
+        if code >= KEY_COUNT {
+            return;
+        }
+        let bit = 1u8 << (code % 8);
+        let byte = &mut self.keys[code as usize / 8];
+        if (*byte & bit != 0) == press {
+            return;
+        }
+        if press {
+            *byte |= bit;
+            event(KeyEvent::Press(code));
+        } else {
+            *byte &= !bit;
+            event(KeyEvent::Release(code));
+        }
+    }
+}
+
+impl Default for KeyReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Authentication of the packets on the link.
+///
+/// Each packet carries the sender's session nonce, a counter, and a MAC, which is a truncated
+/// SipHash, keyed with the shared key, over the rest of the packet and the receiver's nonce.  A
+/// side that has a key only accepts packets with a valid MAC and a counter that is larger than the
+/// last one.  The MAC isn't valid until each side has heard the other's nonce, so the first few
+/// packets of a session are dropped while this settles.
+///
+/// Our nonce is only used with one session of the other side.  If the other side starts a new
+/// session, we pick a new nonce too, so that packets recorded from either session no longer
+/// verify, and can't be replayed.  The other side then sees our nonce change, but as it hasn't
+/// used its new nonce with an earlier one of ours, it keeps it, and this settles.
+pub struct LinkAuth {
+    key: LinkKey,
+    /// Where new nonces come from.
+    random: fn() -> u32,
+    /// Our nonce for this session.
+    nonce: u32,
+    /// The nonce we've most recently seen from the other side, which we sign with.
+    peer_nonce: u32,
+    /// Our counter.
+    counter: u32,
+    /// The nonce, and last counter, of the other side's session that our nonce is used with.
+    peer_counter: Option<(u32, u32)>,
+}
+
+impl LinkAuth {
+    /// Create the authentication for a new session.  `random` gives the nonces, which must not
+    /// repeat, even across restarts.
+    pub fn new(key: LinkKey, random: fn() -> u32) -> LinkAuth {
+        LinkAuth {
+            key,
+            random,
+            nonce: random(),
+            peer_nonce: 0,
+            counter: 0,
+            peer_counter: None,
+        }
+    }
+
+    /// Add the authentication to a packet to be sent.
+    pub fn sign(&mut self, packet: &mut Packet) {
+        self.counter = self.counter.wrapping_add(1);
+        packet.nonce = Some(self.nonce);
+        packet.counter = Some(self.counter);
+        packet.mac = Some(self.compute(packet, self.peer_nonce));
+    }
+
+    /// Check a received packet, returning true if it can be trusted.
+    pub fn verify(&mut self, packet: &Packet) -> bool {
+        let (Some(nonce), Some(counter), Some(mac)) = (packet.nonce, packet.counter, packet.mac) else {
+            return false;
+        };
+
+        // Sign with the other side's nonce even before its packets can be trusted, as they won't
+        // be until it has heard ours.  A forged nonce only spoils what we send, until the next
+        // genuine packet.
+        self.peer_nonce = nonce;
+
+        if self.compute(packet, self.nonce) != mac {
+            return false;
+        }
+
+        match self.peer_counter {
+            // A new session on the other side, while our nonce was in use with an earlier one.
+            // Start over with a new nonce, so that the packets of neither can be replayed.  This is
+            // only believed once the MAC has been checked, so that a forged nonce can't be used to
+            // reset the counter.
+            Some((last_nonce, _)) if last_nonce != nonce => {
+                self.nonce = (self.random)();
+                self.peer_counter = None;
+                return false;
+            }
+            Some((_, last)) if counter <= last => return false,
+            _ => (),
+        }
+        self.peer_counter = Some((nonce, counter));
+        true
+    }
+
+    /// Compute the MAC of the packet, ignoring any MAC already there.
+    fn compute(&self, packet: &Packet, receiver_nonce: u32) -> [u8; 4] {
+        let mut unsigned = packet.clone();
+        unsigned.mac = None;
+        let body = minicbor::to_vec(&unsigned).unwrap();
+
+        // SipHash-2-4 is designed for short inputs like these.
+        let mut hasher = SipHasher24::new_with_key(&self.key);
+        hasher.write(&body);
+        hasher.write_u32(receiver_nonce);
+        let hash = hasher.finish().to_le_bytes();
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+}
+
 /// What the transmitter knows about it's role in the communication.
 #[derive(Debug, Decode, Encode, Copy, Clone, Eq, PartialEq)]
 #[cbor(index_only)]
@@ -158,14 +470,186 @@ mod rgbcbor {
     }
 }
 
+/// Roughly the bytes the link carries in a 5ms tick, at 115200 baud.
+pub const TICK_BYTES: usize = 56;
+
+/// How urgent a packet is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Priority {
+    /// Key events, or their acknowledgement.
+    Keys,
+    /// Anything else: LED state, and packets just to say we're here.
+    Chatter,
+}
+
+/// Counts of what the link has done, to see if it is keeping up.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LinkStats {
+    /// Packets started.
+    pub sent: u32,
+    /// Chatter packets put off, because the tick's budget was spent.
+    pub deferred: u32,
+    /// Chatter packets cut short, to send key events.
+    pub preempted: u32,
+}
+
+/// Budgeting of the bytes sent on the link.
+///
+/// Each tick, the link drains a tick's worth of bytes.  Key packets are always sent, but chatter
+/// is only sent when it fits in what is left of the tick, so it never builds up ahead of keys.
+pub struct LinkBudget {
+    /// The bytes drained each tick.
+    per_tick: usize,
+    /// The bytes sent that the link hasn't yet drained.
+    backlog: usize,
+    stats: LinkStats,
+}
+
+impl LinkBudget {
+    pub fn new(per_tick: usize) -> LinkBudget {
+        LinkBudget {
+            per_tick,
+            backlog: 0,
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// A tick has passed.
+    pub fn tick(&mut self) {
+        self.backlog = self.backlog.saturating_sub(self.per_tick);
+    }
+
+    /// May a packet of `size` bytes be sent now?  If so, it is counted as sent, otherwise as
+    /// deferred.
+    pub fn admit(&mut self, priority: Priority, size: usize) -> bool {
+        if priority == Priority::Chatter && self.backlog + size > self.per_tick {
+            self.stats.deferred = self.stats.deferred.wrapping_add(1);
+            return false;
+        }
+        self.backlog += size;
+        self.stats.sent = self.stats.sent.wrapping_add(1);
+        true
+    }
+
+    /// A chatter packet was cut short, with `unsent` bytes left unsent.
+    pub fn preempt(&mut self, unsent: usize) {
+        self.backlog = self.backlog.saturating_sub(unsent);
+        self.stats.preempted = self.stats.preempted.wrapping_add(1);
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+}
+
+bitflags! {
+    /// Receive errors reported by the link's UART.  The bits match Zephyr's `uart_err_check`.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+    pub struct UartErrors: u8 {
+        const OVERRUN = 0x01;
+        const PARITY = 0x02;
+        const FRAMING = 0x04;
+        const BREAK = 0x08;
+        const NOISE = 0x10;
+    }
+}
+
+/// This many errors within a second causes the link to be resynchronized.
+pub const RESYNC_ERRORS: u32 = 8;
+
+/// Counts of the receive errors on the link, to help debug a cable.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LinkErrors {
+    /// Received bytes lost because the FIFO was full.
+    pub overruns: u32,
+    /// Bytes received damaged: framing, parity, break, or noise errors.
+    pub framing: u32,
+    /// Errors in the last full second.
+    pub per_sec: u32,
+    /// Times the receiver was resynchronized because of errors.
+    pub resyncs: u32,
+}
+
+/// Tracking of the receive errors on the link.
+///
+/// Any error damages the packet being received, which the CRC will reject.  A burst of them
+/// (such as a cable being plugged in) can leave the receiver out of step, so once there have been
+/// [`RESYNC_ERRORS`] in a second, the receiver should be resynchronized: whatever is waiting in
+/// the FIFO discarded, and any partial packet dropped.
+pub struct LinkQuality {
+    /// How many ticks make up a second.
+    ticks_per_sec: u32,
+    /// Ticks into the current second.
+    ticks: u32,
+    /// Errors in the current second.
+    window: u32,
+    /// Errors in the current second since the last resync.
+    pending: u32,
+    stats: LinkErrors,
+}
+
+impl LinkQuality {
+    pub fn new(ticks_per_sec: u32) -> LinkQuality {
+        LinkQuality {
+            ticks_per_sec,
+            ticks: 0,
+            window: 0,
+            pending: 0,
+            stats: LinkErrors::default(),
+        }
+    }
+
+    /// A tick has passed.
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        if self.ticks >= self.ticks_per_sec {
+            self.ticks = 0;
+            self.stats.per_sec = self.window;
+            self.window = 0;
+            self.pending = 0;
+        }
+    }
+
+    /// The UART reported these errors.  Returns true if the receiver should be resynchronized.
+    pub fn errors(&mut self, errors: UartErrors) -> bool {
+        if errors.is_empty() {
+            return false;
+        }
+        if errors.contains(UartErrors::OVERRUN) {
+            self.stats.overruns = self.stats.overruns.wrapping_add(1);
+        }
+        if errors.intersects(!UartErrors::OVERRUN) {
+            self.stats.framing = self.stats.framing.wrapping_add(1);
+        }
+        self.window += 1;
+        self.pending += 1;
+        if self.pending < RESYNC_ERRORS {
+            return false;
+        }
+        self.pending = 0;
+        self.stats.resyncs = self.stats.resyncs.wrapping_add(1);
+        true
+    }
+
+    pub fn stats(&self) -> LinkErrors {
+        self.stats
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
     use minder::{serial_encode, SerialDecoder};
     use smart_leds::RGB8;
 
-    use crate::Side;
+    use crate::{KeyEvent, Side};
 
-    use super::{Packet, Role};
+    use super::{
+        KeyReceiver, KeySender, LinkAuth, LinkBudget, LinkErrors, LinkQuality, LinkStats, Packet,
+        Priority, Role, UartErrors, PACKET_EVENTS, RESYNC_ERRORS,
+        QUEUE_EVENTS,
+    };
 
     #[test]
     fn check_packets() {
@@ -190,13 +674,180 @@ mod test {
         todo!()
     }
 
+    #[test]
+    fn check_auth() {
+        let key = *b"0123456789abcdef";
+        let mut left = LinkAuth::new(key, || 0x1234_5678);
+        let mut right = LinkAuth::new(key, || 0x9abc_def0);
+
+        // Until the receiver's nonce has been heard, the MAC doesn't match.
+        assert!(!send(&mut left, &mut right).0);
+        assert!(send(&mut right, &mut left).0);
+        let (ok, packet) = send(&mut left, &mut right);
+        assert!(ok);
+
+        // Authenticated packets are larger, up to two FIFO frames.
+        let mut large = packet.clone();
+        large.counter = Some(u32::MAX);
+        large.mac = Some([0xfb, 0xfc, 0xfd, 0xfe]);
+        check_size(&large, 48);
+
+        // Replays are rejected.
+        assert!(!right.verify(&packet));
+
+        // Even after a packet with a forged nonce, which can't reset the counter.
+        let mut forged = packet.clone();
+        forged.nonce = Some(0x0bad_0bad);
+        assert!(!right.verify(&forged));
+        assert!(!right.verify(&packet));
+        assert!(send(&mut left, &mut right).0);
+
+        // As are changes.
+        let (_, mut packet) = send(&mut left, &mut LinkAuth::new(key, || 0));
+        packet.keys = Some([0; 6]);
+        assert!(!right.verify(&packet));
+
+        // And other keys.
+        let mut other = LinkAuth::new(*b"fedcba9876543210", || 0x1234_5678);
+        assert!(!send(&mut other, &mut right).0);
+        assert!(!send(&mut other, &mut right).0);
+
+        // An unauthenticated packet is never accepted.
+        assert!(!right.verify(&Packet::new(Role::Primary, Side::Left)));
+    }
+
+    /// Send a packet between the two sides, returning whether it was accepted.
+    fn send(from: &mut LinkAuth, to: &mut LinkAuth) -> (bool, Packet) {
+        let mut packet = Packet::new(Role::Secondary, Side::Left);
+        packet.set_keys([0xfb, 0xfb, 0xfb, 0xfb, 0xfb, 0xfb]);
+        from.sign(&mut packet);
+        (to.verify(&packet), packet)
+    }
+
+    #[test]
+    fn check_auth_restart() {
+        fn random() -> u32 {
+            static NEXT: AtomicU32 = AtomicU32::new(1);
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        }
+
+        let key = *b"0123456789abcdef";
+        let mut left = LinkAuth::new(key, random);
+        let mut right = LinkAuth::new(key, random);
+        send(&mut left, &mut right);
+        assert!(send(&mut right, &mut left).0);
+        let (ok, old) = send(&mut left, &mut right);
+        assert!(ok);
+
+        // The left side restarts.  Once it has heard the right's nonce, its packets check out, but
+        // the right picks a new nonce rather than accepting them.
+        let mut left = LinkAuth::new(key, random);
+        assert!(!send(&mut right, &mut left).0);
+        let (ok, first) = send(&mut left, &mut right);
+        assert!(!ok);
+
+        // Which settles, without the left side changing again.
+        assert!(!send(&mut left, &mut right).0);
+        assert!(send(&mut right, &mut left).0);
+        assert!(send(&mut left, &mut right).0);
+        assert!(send(&mut right, &mut left).0);
+        assert!(send(&mut left, &mut right).0);
+
+        // Packets from either of the left's sessions can't be replayed.
+        assert!(!right.verify(&old));
+        assert!(!right.verify(&first));
+        assert!(send(&mut left, &mut right).0);
+    }
+
+    #[test]
+    fn check_key_events() {
+        let mut sender = KeySender::new(0xfffe);
+        let mut receiver = KeyReceiver::new();
+        let mut seen = Vec::new();
+
+        let packet = |sender: &KeySender| {
+            let mut packet = Packet::new(Role::Secondary, Side::Left);
+            sender.fill(&mut packet);
+            packet
+        };
+
+        // A tap entirely between packets still arrives, across the sequence wrapping.
+        sender.add_key(KeyEvent::Press(5));
+        sender.add_key(KeyEvent::Release(5));
+        sender.add_key(KeyEvent::Press(40));
+        let first = packet(&sender);
+        check(&first);
+        receiver.receive(&first, |ev| seen.push(ev));
+        assert_eq!(seen, [KeyEvent::Press(5), KeyEvent::Release(5), KeyEvent::Press(40)]);
+
+        // Resent, because the ack was lost, nothing is repeated.
+        seen.clear();
+        receiver.receive(&first, |ev| seen.push(ev));
+        assert!(seen.is_empty());
+
+        // Once acknowledged, events aren't sent again.
+        let mut ack = Packet::new(Role::Primary, Side::Right);
+        ack.ack = receiver.ack();
+        check(&ack);
+        sender.ack(ack.ack.unwrap());
+        assert_eq!(packet(&sender).events, None);
+
+        // A backlog is sent a packet at a time, without the bitmap until the last.
+        for _ in 0..3 {
+            sender.add_key(KeyEvent::Press(7));
+            sender.add_key(KeyEvent::Release(7));
+        }
+        let partial = packet(&sender);
+        assert_eq!(partial.keys, None);
+        assert_eq!(partial.events.as_ref().map(|e| e.len()), Some(PACKET_EVENTS));
+        receiver.receive(&partial, |ev| seen.push(ev));
+        sender.ack(receiver.ack().unwrap());
+        receiver.receive(&packet(&sender), |ev| seen.push(ev));
+        assert_eq!(seen.len(), 6);
+        assert_eq!(seen[5], KeyEvent::Release(7));
+
+        // If too many are lost, the bitmap puts things right.
+        seen.clear();
+        for _ in 0..QUEUE_EVENTS {
+            sender.add_key(KeyEvent::Press(9));
+            sender.add_key(KeyEvent::Release(9));
+        }
+        sender.add_key(KeyEvent::Release(40));
+        loop {
+            let packet = packet(&sender);
+            if packet.events.is_none() {
+                break;
+            }
+            check(&packet);
+            receiver.receive(&packet, |ev| seen.push(ev));
+            sender.ack(receiver.ack().unwrap());
+        }
+        assert_eq!(seen.last(), Some(&KeyEvent::Release(40)));
+
+        // A restarted Secondary starts a new sequence.
+        let mut sender = KeySender::new(0x1234);
+        sender.add_key(KeyEvent::Press(1));
+        seen.clear();
+        receiver.receive(&packet(&sender), |ev| seen.push(ev));
+        assert_eq!(seen, [KeyEvent::Press(1)]);
+
+        // And if it goes quiet, its keys are released.
+        seen.clear();
+        receiver.release_all(|ev| seen.push(ev));
+        assert_eq!(seen, [KeyEvent::Release(1)]);
+    }
+
     fn check(item: &Packet) {
+        // Make sure the worst case packets still fit in a single FIFO frame.
+        check_size(item, 32);
+    }
+
+    fn check_size(item: &Packet, max: usize) {
         let mut buf = Vec::new();
         serial_encode(item, &mut buf, true).unwrap();
         println!("packet: {:02x?}", buf);
 
-        // Make sure the worst case packets still fit in a single FIFO frame.
-        assert!(buf.len() <= 32);
+        assert!(buf.len() <= max);
 
         let mut dec = SerialDecoder::new();
         let mut count = 0;
@@ -208,4 +859,53 @@ mod test {
         }
         assert_eq!(count, 1);
     }
+
+    #[test]
+    fn check_budget() {
+        let mut budget = LinkBudget::new(40);
+
+        // Chatter fits in a tick, but not two of them.
+        assert!(budget.admit(Priority::Chatter, 30));
+        assert!(!budget.admit(Priority::Chatter, 30));
+        // Keys always go.
+        assert!(budget.admit(Priority::Keys, 20));
+
+        // The backlog takes more than one tick to drain.
+        budget.tick();
+        assert!(!budget.admit(Priority::Chatter, 35));
+        budget.tick();
+        assert!(budget.admit(Priority::Chatter, 30));
+
+        // Cutting a packet short gives back what wasn't sent.
+        budget.preempt(25);
+        assert!(budget.admit(Priority::Chatter, 30));
+
+        assert_eq!(budget.stats(), LinkStats { sent: 4, deferred: 2, preempted: 1 });
+    }
+
+    #[test]
+    fn check_quality() {
+        let mut quality = LinkQuality::new(10);
+
+        assert!(!quality.errors(UartErrors::empty()));
+        assert!(!quality.errors(UartErrors::OVERRUN));
+        assert!(!quality.errors(UartErrors::FRAMING | UartErrors::PARITY));
+        assert_eq!(quality.stats(), LinkErrors { overruns: 1, framing: 1, per_sec: 0, resyncs: 0 });
+
+        // A second later, the rate is known, and the count toward a resync starts over.
+        for _ in 0..10 {
+            quality.tick();
+        }
+        assert_eq!(quality.stats().per_sec, 2);
+        for _ in 1..RESYNC_ERRORS {
+            assert!(!quality.errors(UartErrors::NOISE));
+        }
+        assert!(quality.errors(UartErrors::NOISE));
+        assert!(!quality.errors(UartErrors::NOISE));
+        for _ in 0..10 {
+            quality.tick();
+        }
+        assert_eq!(quality.stats().per_sec, RESYNC_ERRORS + 1);
+        assert_eq!(quality.stats().resyncs, 1);
+    }
 }
@@ -0,0 +1,109 @@
+//! Steno maps stored in flash.
+//!
+//! Which scan code is which steno key is compiled in, but a board wired differently can store its
+//! own assignment in the steno map partition (see [`minder::partition::STENO_MAP`]).  The firmware
+//! loads it at boot, in place of the built-in one, and keyminder can build one by asking for each
+//! steno key to be pressed in turn.
+//!
+//! Each scan code is either a steno key, given as the raw bits of the stroke it adds, or not used
+//! by steno at all.  A scan code with an empty stroke still takes part in the stroke, so releasing
+//! it sends what has been pressed, but adds nothing of its own.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bbq_steno::Stroke;
+use minicbor::{Decode, Encode};
+#[cfg(feature = "std")]
+use serde::{Deserialize, Serialize};
+
+use crate::log::warn;
+
+/// The most scan codes a steno map can cover.
+pub const MAX_KEYS: usize = 64;
+
+/// The bits of a raw stroke that are steno keys.
+const STROKE_BITS: u32 = 0x1ff_ffff;
+
+/// A steno key for each scan code.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+#[cbor(tag(0x7374656e6f6d))]
+pub struct StenoMap {
+    /// A name, only used for reporting.
+    #[n(0)]
+    pub name: String,
+    /// The raw stroke for each scan code, None for those that aren't steno keys.  Scan codes past
+    /// the end aren't steno keys.
+    #[n(1)]
+    pub keys: Vec<Option<u32>>,
+}
+
+impl StenoMap {
+    /// The steno map built into the firmware.
+    pub fn builtin() -> StenoMap {
+        crate::layout::builtin_steno_map()
+    }
+
+    /// Decode a stored steno map.  Anything that doesn't decode (such as erased flash) is None.
+    pub fn decode(data: &[u8]) -> Option<StenoMap> {
+        match minicbor::decode(data) {
+            Ok(map) => Some(map),
+            Err(e) => {
+                warn!("No stored steno map: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Check that the steno map can be used.  Returns a description of the first problem found.
+    pub fn check(&self) -> Result<(), String> {
+        if self.keys.len() > MAX_KEYS {
+            return Err(format!("Steno map has {} keys, at most {} are used", self.keys.len(), MAX_KEYS));
+        }
+        for (code, key) in self.keys.iter().enumerate() {
+            if let Some(raw) = *key {
+                if raw & !STROKE_BITS != 0 {
+                    return Err(format!("Scan code {} has an invalid stroke 0x{:x}", code, raw));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode the steno map, for storing.
+    pub fn encode(&self) -> Vec<u8> {
+        minicbor::to_vec(self).unwrap()
+    }
+
+    /// The stroke for each scan code, as the steno handler uses them.
+    pub fn strokes(&self) -> Vec<Option<Stroke>> {
+        self.keys.iter().map(|key| key.map(Stroke::from_raw)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stenomap() {
+        let builtin = StenoMap::builtin();
+        assert!(builtin.check().is_ok());
+        assert_eq!(StenoMap::decode(&builtin.encode()), Some(builtin.clone()));
+
+        // Erased flash isn't a steno map.
+        assert_eq!(StenoMap::decode(&[0xff; 64]), None);
+
+        let mut map = builtin;
+        map.keys.push(Some(0x200_0000));
+        assert!(map.check().is_err());
+        map.keys.pop();
+        map.keys.resize(MAX_KEYS, None);
+        assert!(map.check().is_ok());
+        map.keys.push(None);
+        assert!(map.check().is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! Helpers shared by the tests.
+//!
+//! The layouts are driven through async calls, but in the tests nothing ever waits.  [`Recorder`]
+//! keeps everything a layout, or the typer, sends, and [`run`] drives each call to completion.
+
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use bbq_steno::Stroke;
+
+use crate::layout::{LayoutActions, LayoutMode};
+use crate::macros::StoredMacros;
+use crate::usb_typer::ActionHandler;
+use crate::{KeyAction, MinorMode};
+
+/// Records what is sent to it.
+#[derive(Default)]
+pub struct Recorder {
+    /// The last mode set.
+    pub mode: Cell<Option<LayoutMode>>,
+    /// The keys sent, or the actions enqueued.
+    pub keys: RefCell<Vec<KeyAction>>,
+    /// The raw steno strokes sent.
+    pub strokes: RefCell<Vec<Stroke>>,
+    /// Each change to caps word.
+    pub caps_word: RefCell<Vec<bool>>,
+    /// The text typed.
+    pub text: RefCell<String>,
+    /// The macros last saved.
+    pub saved: RefCell<Option<StoredMacros>>,
+}
+
+impl LayoutActions for Recorder {
+    async fn set_mode(&self, mode: LayoutMode) {
+        self.mode.set(Some(mode));
+    }
+    async fn set_mode_select(&self, _mode: LayoutMode) {}
+    async fn send_key(&self, key: KeyAction) {
+        self.keys.borrow_mut().push(key);
+    }
+    async fn set_sub_mode(&self, submode: MinorMode) {
+        if let MinorMode::CapsWord(on) = submode {
+            self.caps_word.borrow_mut().push(on);
+        }
+    }
+    async fn send_raw_steno(&self, stroke: Stroke) {
+        self.strokes.borrow_mut().push(stroke);
+    }
+    async fn type_text(&self, text: &str) {
+        self.text.borrow_mut().push_str(text);
+    }
+    async fn save_macros(&self, macros: &StoredMacros) {
+        *self.saved.borrow_mut() = Some(macros.clone());
+    }
+}
+
+impl ActionHandler for Recorder {
+    async fn enqueue_actions<I: Iterator<Item = KeyAction>>(&mut self, events: I) {
+        self.keys.get_mut().extend(events);
+    }
+}
+
+/// The recorder never waits, so a single poll runs each call to completion.
+pub fn run<F: Future<Output = ()>>(future: F) {
+    let mut cx = Context::from_waker(Waker::noop());
+    assert_eq!(pin!(future).poll(&mut cx), Poll::Ready(()));
+}
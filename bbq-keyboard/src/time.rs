@@ -0,0 +1,164 @@
+//! Portable time.
+//!
+//! Each firmware has its own idea of time (Zephyr cycles and ticks, the rtic monotonic timer), but
+//! the shared code only needs to know how long things take.  A target provides a [`Clock`] for its
+//! time source, and everything here works in [`Instant`] and [`Duration`], which count
+//! microseconds, and don't depend on the rate of the underlying clock.
+
+use core::ops::{Add, AddAssign, Sub};
+
+/// A length of time, in microseconds.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Duration(u64);
+
+impl Duration {
+    pub const ZERO: Duration = Duration(0);
+
+    pub const fn from_micros(micros: u64) -> Duration {
+        Duration(micros)
+    }
+
+    pub const fn from_millis(millis: u64) -> Duration {
+        Duration(millis * 1000)
+    }
+
+    pub const fn from_secs(secs: u64) -> Duration {
+        Duration(secs * 1_000_000)
+    }
+
+    pub const fn as_micros(self) -> u64 {
+        self.0
+    }
+
+    pub const fn as_millis(self) -> u64 {
+        self.0 / 1000
+    }
+
+    /// The difference, or zero if `other` is longer.
+    pub const fn saturating_sub(self, other: Duration) -> Duration {
+        Duration(self.0.saturating_sub(other.0))
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, other: Duration) -> Duration {
+        Duration(self.0.saturating_add(other.0))
+    }
+}
+
+impl AddAssign for Duration {
+    fn add_assign(&mut self, other: Duration) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, other: Duration) -> Duration {
+        self.saturating_sub(other)
+    }
+}
+
+/// A point in time, in microseconds since the clock started (generally boot).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const fn from_micros(micros: u64) -> Instant {
+        Instant(micros)
+    }
+
+    /// Convert from a count of clock ticks at `hz`.
+    pub const fn from_ticks(ticks: u64, hz: u64) -> Instant {
+        Instant((ticks as u128 * 1_000_000 / hz as u128) as u64)
+    }
+
+    pub const fn as_micros(self) -> u64 {
+        self.0
+    }
+
+    pub const fn as_millis(self) -> u64 {
+        self.0 / 1000
+    }
+
+    /// Time since `earlier`, or zero if `earlier` is actually later.
+    pub const fn duration_since(self, earlier: Instant) -> Duration {
+        Duration(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, other: Duration) -> Instant {
+        Instant(self.0.saturating_add(other.0))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, other: Duration) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// A monotonic clock.  Each target implements this once, for its own time source.
+pub trait Clock {
+    /// The raw count of the underlying clock.
+    fn ticks(&self) -> u64;
+
+    /// The rate of [`Clock::ticks`], in Hz.
+    fn tick_hz(&self) -> u64;
+
+    /// The current time.
+    fn now(&self) -> Instant {
+        Instant::from_ticks(self.ticks(), self.tick_hz())
+    }
+
+    /// The current time, in ms.
+    fn millis(&self) -> u64 {
+        self.now().as_millis()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Fixed(u64, u64);
+
+    impl Clock for Fixed {
+        fn ticks(&self) -> u64 {
+            self.0
+        }
+
+        fn tick_hz(&self) -> u64 {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_clock() {
+        // A 125MHz cycle counter, well past where a u64 of micros times the rate would overflow.
+        let clock = Fixed(125_000_000 * 200_000, 125_000_000);
+        assert_eq!(clock.now(), Instant::from_micros(200_000_000_000));
+        assert_eq!(clock.millis(), 200_000_000);
+
+        let start = Fixed(10, 1000).now();
+        let stop = Fixed(25, 1000).now();
+        assert_eq!(stop - start, Duration::from_millis(15));
+        assert_eq!(start - stop, Duration::ZERO);
+        assert_eq!(start + Duration::from_millis(15), stop);
+        assert_eq!(Duration::from_secs(1).as_millis(), 1000);
+    }
+}
@@ -0,0 +1,268 @@
+//! Event trace.
+//!
+//! When the keyboard does something odd, it helps to know what led up to it.  With tracing built
+//! in, the firmware records the events it handles (keys from the matrix and from the other half,
+//! inter state, USB and BLE state, and mode changes) in a ring, along with when they happened.  The
+//! ring can be read over minder after the fact (see [`minder::message::Debug::GetTrace`]), and
+//! rendered on the host as a timeline with [`render`].
+//!
+//! Each record is a fixed [`RECORD_SIZE`] bytes, so the ring costs a known amount of RAM, and
+//! reading it out is just a copy.  A record is the time in ms since boot (as a little endian
+//! `u32`, which wraps after about 49 days), the kind of event, and a byte of argument.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::time::Instant;
+use crate::{HostEvent, InterEvent, InterState, KeyEvent, LayoutMode, UsbDeviceState};
+
+/// The size of a single record.
+pub const RECORD_SIZE: usize = 6;
+
+/// Inter states, by their index in a record.
+const INTER_STATES: [InterState; 3] = [InterState::Idle, InterState::Primary, InterState::Secondary];
+
+/// USB states, by their index in a record.
+const USB_STATES: [UsbDeviceState; 5] = [
+    UsbDeviceState::Default,
+    UsbDeviceState::Addressed,
+    UsbDeviceState::Configured,
+    UsbDeviceState::Suspend,
+    UsbDeviceState::Resume,
+];
+
+/// Experimental modes are recorded by their id, from this index.
+const EXPERIMENTAL_MODES: u8 = 0x80;
+
+/// Something worth tracing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceEvent {
+    /// A key on this side's matrix.
+    Matrix(KeyEvent),
+    /// A key from the other half.
+    InterKey(KeyEvent),
+    /// The inter link changed role.
+    Inter(InterState),
+    Usb(UsbDeviceState),
+    /// A BLE host connected or disconnected.
+    Ble(bool),
+    /// The layout mode changed.
+    Mode(LayoutMode),
+    /// Steno raw mode was turned on or off.
+    RawMode(bool),
+    Shutdown,
+}
+
+impl From<HostEvent> for TraceEvent {
+    fn from(event: HostEvent) -> TraceEvent {
+        match event {
+            HostEvent::Usb(state) => TraceEvent::Usb(state),
+            HostEvent::Ble(up) => TraceEvent::Ble(up),
+        }
+    }
+}
+
+impl TraceEvent {
+    /// The trace of something from the other half, for those worth tracing.  Heartbeats happen
+    /// constantly, and would push everything else out of the ring.
+    pub fn from_inter(event: &InterEvent) -> Option<TraceEvent> {
+        match event {
+            InterEvent::Key(key) => Some(TraceEvent::InterKey(*key)),
+            InterEvent::BecomeState(state) => Some(TraceEvent::Inter(*state)),
+            InterEvent::Heartbeat => None,
+        }
+    }
+
+    /// The kind and argument bytes of a record.
+    fn encode(self) -> (u8, u8) {
+        let index = |found: Option<usize>| found.unwrap_or(0xff) as u8;
+        match self {
+            TraceEvent::Matrix(KeyEvent::Press(key)) => (1, key),
+            TraceEvent::Matrix(KeyEvent::Release(key)) => (2, key),
+            TraceEvent::InterKey(KeyEvent::Press(key)) => (3, key),
+            TraceEvent::InterKey(KeyEvent::Release(key)) => (4, key),
+            TraceEvent::Inter(state) => (5, index(INTER_STATES.iter().position(|&s| s == state))),
+            TraceEvent::Usb(state) => (6, index(USB_STATES.iter().position(|&s| s == state))),
+            TraceEvent::Ble(up) => (7, up as u8),
+            // Experimental modes follow the built-in ones, by id.
+            TraceEvent::Mode(LayoutMode::Experimental(id)) => (8, EXPERIMENTAL_MODES + id),
+            TraceEvent::Mode(mode) => (8, index(LayoutMode::ALL.iter().position(|&m| m == mode))),
+            TraceEvent::RawMode(raw) => (9, raw as u8),
+            TraceEvent::Shutdown => (10, 0),
+        }
+    }
+
+    /// Decode the kind and argument of a record.  Returns None for anything unknown, such as from
+    /// newer firmware.
+    fn decode(kind: u8, arg: u8) -> Option<TraceEvent> {
+        Some(match kind {
+            1 => TraceEvent::Matrix(KeyEvent::Press(arg)),
+            2 => TraceEvent::Matrix(KeyEvent::Release(arg)),
+            3 => TraceEvent::InterKey(KeyEvent::Press(arg)),
+            4 => TraceEvent::InterKey(KeyEvent::Release(arg)),
+            5 => TraceEvent::Inter(*INTER_STATES.get(arg as usize)?),
+            6 => TraceEvent::Usb(*USB_STATES.get(arg as usize)?),
+            7 => TraceEvent::Ble(arg != 0),
+            8 if arg >= EXPERIMENTAL_MODES => {
+                TraceEvent::Mode(LayoutMode::Experimental(arg - EXPERIMENTAL_MODES))
+            }
+            8 => TraceEvent::Mode(*LayoutMode::ALL.get(arg as usize)?),
+            9 => TraceEvent::RawMode(arg != 0),
+            10 => TraceEvent::Shutdown,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let updown = |key: &KeyEvent| if key.is_press() { "down" } else { "up" };
+        let onoff = |on: bool| if on { "on" } else { "off" };
+        match self {
+            TraceEvent::Matrix(key) => write!(f, "key {} {}", key.key(), updown(key)),
+            TraceEvent::InterKey(key) => write!(f, "inter key {} {}", key.key(), updown(key)),
+            TraceEvent::Inter(state) => write!(f, "inter {:?}", state),
+            TraceEvent::Usb(state) => write!(f, "usb {:?}", state),
+            TraceEvent::Ble(up) => {
+                write!(f, "ble {}", if *up { "connected" } else { "disconnected" })
+            }
+            // The names of experimental modes are only known to the firmware.
+            TraceEvent::Mode(LayoutMode::Experimental(id)) => write!(f, "mode experimental {}", id),
+            TraceEvent::Mode(mode) => write!(f, "mode {}", mode.name()),
+            TraceEvent::RawMode(raw) => write!(f, "raw {}", onoff(*raw)),
+            TraceEvent::Shutdown => write!(f, "shutdown"),
+        }
+    }
+}
+
+/// A ring of the most recent events.
+#[derive(Debug)]
+pub struct Trace {
+    /// The records, as stored.
+    data: Vec<u8>,
+    /// Where the next record goes.
+    next: usize,
+    /// Has the ring wrapped.
+    full: bool,
+}
+
+impl Trace {
+    /// A ring holding up to `capacity` records.  The memory is allocated up front.
+    pub fn new(capacity: usize) -> Trace {
+        Trace {
+            data: alloc::vec![0; capacity * RECORD_SIZE],
+            next: 0,
+            full: false,
+        }
+    }
+
+    /// Record an event, replacing the oldest if the ring is full.
+    pub fn record(&mut self, now: Instant, event: TraceEvent) {
+        if self.data.is_empty() {
+            return;
+        }
+        let (kind, arg) = event.encode();
+        let record = &mut self.data[self.next..self.next + RECORD_SIZE];
+        record[..4].copy_from_slice(&(now.as_millis() as u32).to_le_bytes());
+        record[4] = kind;
+        record[5] = arg;
+        self.next += RECORD_SIZE;
+        if self.next == self.data.len() {
+            self.next = 0;
+            self.full = true;
+        }
+    }
+
+    /// The records in the ring, oldest first.
+    pub fn export(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.data.len());
+        if self.full {
+            result.extend_from_slice(&self.data[self.next..]);
+        }
+        result.extend_from_slice(&self.data[..self.next]);
+        result
+    }
+}
+
+/// Decode exported records into their time, in ms, and the event.  The event is None if the
+/// record isn't understood.  Any partial record at the end is ignored.
+pub fn decode(data: &[u8]) -> impl Iterator<Item = (u32, Option<TraceEvent>)> + '_ {
+    data.chunks_exact(RECORD_SIZE).map(|record| {
+        let time = u32::from_le_bytes(record[..4].try_into().unwrap());
+        (time, TraceEvent::decode(record[4], record[5]))
+    })
+}
+
+/// Render exported records as a timeline, one line for each event, with its time, and the time
+/// since the event before it.
+pub fn render(data: &[u8]) -> String {
+    let mut result = String::new();
+    let mut last = None;
+    for (time, event) in decode(data) {
+        let delta = time.wrapping_sub(last.unwrap_or(time));
+        last = Some(time);
+        let _ = write!(result, "{:>6}.{:03} {:>8} ", time / 1000, time % 1000, alloc::format!("+{}", delta));
+        let _ = match event {
+            Some(event) => writeln!(result, "{}", event),
+            None => writeln!(result, "unknown"),
+        };
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace() {
+        let at = |ms: u64| Instant::from_micros(ms * 1000);
+
+        assert_eq!(TraceEvent::from_inter(&InterEvent::Heartbeat), None);
+        assert_eq!(TraceEvent::from_inter(&InterEvent::Key(KeyEvent::Press(7))),
+                   Some(TraceEvent::InterKey(KeyEvent::Press(7))));
+        assert_eq!(TraceEvent::from(HostEvent::Ble(true)), TraceEvent::Ble(true));
+        let experimental = TraceEvent::Mode(LayoutMode::Experimental(3));
+        assert_eq!(experimental.encode(), (8, 0x83));
+        assert_eq!(TraceEvent::decode(8, 0x83), Some(experimental));
+        assert_eq!(experimental.to_string(), "mode experimental 3");
+
+        let events = [
+            TraceEvent::Usb(UsbDeviceState::Configured),
+            TraceEvent::Matrix(KeyEvent::Press(3)),
+            TraceEvent::InterKey(KeyEvent::Release(30)),
+            TraceEvent::Inter(InterState::Primary),
+            TraceEvent::Mode(LayoutMode::Taipo),
+            TraceEvent::Ble(false),
+        ];
+
+        // Until it fills, everything is kept.
+        let mut trace = Trace::new(4);
+        trace.record(at(1000), events[0]);
+        trace.record(at(1012), events[1]);
+        let data = trace.export();
+        assert_eq!(data.len(), 2 * RECORD_SIZE);
+        assert_eq!(decode(&data).collect::<Vec<_>>(),
+                   [(1000, Some(events[0])), (1012, Some(events[1]))]);
+
+        // After that, the oldest go.
+        for (i, &event) in events.iter().enumerate().skip(2) {
+            trace.record(at(1012 + i as u64), event);
+        }
+        let data = trace.export();
+        let decoded: Vec<_> = decode(&data).map(|(_, e)| e.unwrap()).collect();
+        assert_eq!(decoded, &events[2..]);
+
+        assert_eq!(render(&data[..2 * RECORD_SIZE]),
+                   "     1.014       +0 inter key 30 up\n     1.015       +1 inter Primary\n");
+
+        // Unknown records are still shown.
+        let mut data = data;
+        data[4] = 0xee;
+        assert!(render(&data).starts_with("     1.014       +0 unknown\n"));
+        assert_eq!(TraceEvent::decode(0xee, 0), None);
+    }
+}
@@ -0,0 +1,155 @@
+//! Practice aids.
+//!
+//! The metronome beats at a target rate of strokes per minute, which the firmware shows by
+//! blinking an LED.  Each stroke is compared against the nearest beat, and counted as on the beat,
+//! ahead of it, or behind it.  The totals for the session are reported over minder, so someone
+//! practicing can see whether they are rushing or dragging, and not just their overall speed.
+
+use minder::PaceSummary;
+
+use crate::time::Duration;
+
+/// Strokes within this fraction of a beat period (in percent) of the beat are on the beat.
+pub const ON_BEAT_PERCENT: u32 = 10;
+
+/// The metronome, and the summary of the strokes made against it.
+#[derive(Debug, Default)]
+pub struct Metronome {
+    /// The time between beats, in ms.  Zero when the metronome is off.
+    period: u32,
+
+    /// Time since the last beat, in ms.
+    phase: u32,
+
+    /// The strokes for this session.
+    summary: PaceSummary,
+
+    /// The total of the stroke offsets, in ms, for the average.
+    total_offset: i64,
+}
+
+impl Metronome {
+    pub fn new() -> Metronome {
+        Metronome::default()
+    }
+
+    /// Set the rate in strokes per minute, starting a new session with a beat now.  A rate of zero
+    /// stops the metronome, but keeps the summary of the session just finished.
+    pub fn set_rate(&mut self, spm: u32) {
+        self.period = if spm == 0 { 0 } else { 60_000 / spm.min(60_000) };
+        self.phase = 0;
+        if spm != 0 {
+            self.summary = PaceSummary { spm, ..PaceSummary::default() };
+            self.total_offset = 0;
+        }
+    }
+
+    /// Is the metronome running.
+    pub fn is_running(&self) -> bool {
+        self.period != 0
+    }
+
+    /// Advance by `elapsed`.  Returns true if a beat happened, and should be shown.
+    pub fn tick(&mut self, elapsed: Duration) -> bool {
+        if self.period == 0 {
+            return false;
+        }
+        let ms = elapsed.as_millis() as u32;
+        self.summary.ms += ms as u64;
+        self.phase += ms;
+        if self.phase >= self.period {
+            self.phase %= self.period;
+            self.summary.beats += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record a stroke, made now, against the nearest beat.
+    pub fn stroke(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        // Positive is behind the beat, negative ahead of the next one.
+        let offset = if self.phase * 2 < self.period {
+            self.phase as i32
+        } else {
+            self.phase as i32 - self.period as i32
+        };
+
+        let summary = &mut self.summary;
+        summary.strokes += 1;
+        if offset.unsigned_abs() * 100 <= self.period * ON_BEAT_PERCENT {
+            summary.on_beat += 1;
+        } else if offset < 0 {
+            summary.ahead += 1;
+        } else {
+            summary.behind += 1;
+        }
+        self.total_offset += offset as i64;
+        summary.mean_offset = (self.total_offset / summary.strokes as i64) as i32;
+    }
+
+    /// The summary of the current, or most recent, session.
+    pub fn summary(&self) -> PaceSummary {
+        self.summary.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run for `ms`, returning the number of beats.
+    fn run(metronome: &mut Metronome, ms: u32) -> u32 {
+        (0..ms / 10).filter(|_| metronome.tick(Duration::from_millis(10))).count() as u32
+    }
+
+    #[test]
+    fn test_metronome() {
+        let mut metronome = Metronome::new();
+
+        // Nothing happens when off.
+        assert_eq!(run(&mut metronome, 10_000), 0);
+        metronome.stroke();
+        assert_eq!(metronome.summary().strokes, 0);
+
+        // 120 spm is a beat every 500ms.
+        metronome.set_rate(120);
+        assert_eq!(run(&mut metronome, 5000), 10);
+
+        // On the beat.
+        metronome.stroke();
+        // 100ms behind.
+        run(&mut metronome, 100);
+        metronome.stroke();
+        // 100ms ahead of the next.
+        run(&mut metronome, 300);
+        metronome.stroke();
+        // Just inside the on beat window.
+        run(&mut metronome, 150);
+        metronome.stroke();
+
+        let summary = metronome.summary();
+        assert_eq!(summary.spm, 120);
+        assert_eq!(summary.beats, 11);
+        assert_eq!(summary.strokes, 4);
+        assert_eq!(summary.on_beat, 2);
+        assert_eq!(summary.ahead, 1);
+        assert_eq!(summary.behind, 1);
+        // On the beat, 100ms behind, 100ms ahead, then 50ms behind: 50ms over four strokes, which
+        // rounds down to 12.
+        assert_eq!(summary.mean_offset, 12);
+
+        // Stopping keeps the summary, but a new rate starts over.
+        metronome.set_rate(0);
+        assert!(!metronome.is_running());
+        assert_eq!(run(&mut metronome, 5000), 0);
+        assert_eq!(metronome.summary().strokes, 4);
+        metronome.set_rate(60);
+        assert_eq!(metronome.summary().strokes, 0);
+        assert_eq!(run(&mut metronome, 5000), 5);
+    }
+}
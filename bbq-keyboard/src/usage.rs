@@ -0,0 +1,211 @@
+//! Usage statistics.
+//!
+//! Keep track of how long is spent in each layout mode, and how much is typed in it, so it is
+//! possible to see how much steno is actually being used.  The firmware saves these to flash
+//! periodically (see [`minder::partition::STATS`]), and reports them in the minder status.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use minder::ModeUsage;
+use minicbor::{Decode, Encode};
+
+use crate::log::warn;
+use crate::time::{Duration, Instant};
+use crate::LayoutMode;
+
+/// Tag to recognize the saved stats.
+pub const USAGE_TAG: u64 = 0x7573616765737473;
+
+/// How often to save.  Flash wears out, so this is fairly infrequent.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The typing rate is counted over this many windows.
+const RATE_WINDOWS: usize = 6;
+
+/// The length of each rate window.  All of them together make a minute.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// The accumulated usage.
+#[derive(Debug, Default, Encode, Decode)]
+#[cbor(tag(0x7573616765737473))]
+#[cbor(map)]
+pub struct Usage {
+    /// One entry for each mode that has been used.
+    #[n(1)]
+    modes: Vec<ModeUsage>,
+
+    /// Time of the last save.
+    #[cbor(skip)]
+    saved: Instant,
+
+    /// Has anything changed since the last save.
+    #[cbor(skip)]
+    dirty: bool,
+
+    /// Keys pressed in each of the last few completed rate windows.
+    #[cbor(skip)]
+    recent: [u32; RATE_WINDOWS],
+
+    /// Keys pressed in the current rate window, and how much of it has passed.
+    #[cbor(skip)]
+    window_keys: u32,
+    #[cbor(skip)]
+    window: Duration,
+}
+
+impl Usage {
+    pub fn new() -> Usage {
+        Usage::default()
+    }
+
+    /// Decode previously saved usage.  Anything that doesn't decode (such as erased flash) starts
+    /// over.
+    pub fn decode(data: &[u8]) -> Usage {
+        match minicbor::decode(data) {
+            Ok(usage) => usage,
+            Err(e) => {
+                warn!("No saved usage: {:?}", e);
+                Usage::new()
+            }
+        }
+    }
+
+    /// Encode the usage, for saving.
+    pub fn encode(&self) -> Vec<u8> {
+        minicbor::to_vec(self).unwrap()
+    }
+
+    fn entry(&mut self, mode: LayoutMode) -> &mut ModeUsage {
+        self.dirty = true;
+        let name = mode.name();
+        match self.modes.iter().position(|m| m.mode == name) {
+            Some(pos) => &mut self.modes[pos],
+            None => {
+                self.modes.push(ModeUsage { mode: name.into(), ..ModeUsage::default() });
+                self.modes.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Account for time spent in a mode.
+    pub fn add_time(&mut self, mode: LayoutMode, elapsed: Duration) {
+        self.entry(mode).ms += elapsed.as_millis();
+        self.window += elapsed;
+        while self.window >= RATE_WINDOW {
+            self.window = self.window - RATE_WINDOW;
+            self.recent.rotate_left(1);
+            self.recent[RATE_WINDOWS - 1] = self.window_keys;
+            self.window_keys = 0;
+        }
+    }
+
+    /// Count a key press.
+    pub fn add_key(&mut self, mode: LayoutMode) {
+        self.entry(mode).keys += 1;
+        self.window_keys += 1;
+    }
+
+    /// The sustained typing rate, in keys a minute, over the last minute of time accounted for.
+    pub fn rate(&self) -> u32 {
+        self.recent.iter().sum()
+    }
+
+    /// Count a translated steno stroke.
+    pub fn add_stroke(&mut self, mode: LayoutMode) {
+        self.entry(mode).strokes += 1;
+    }
+
+    /// The usage of each mode, for reporting.
+    pub fn report(&self) -> Vec<ModeUsage> {
+        self.modes.clone()
+    }
+
+    /// Should the usage be saved now?  If this returns true, the caller is expected to save the
+    /// encoded usage.
+    pub fn should_save(&mut self, now: Instant) -> bool {
+        if !self.dirty || now - self.saved < SAVE_INTERVAL {
+            return false;
+        }
+        self.saved = now;
+        self.dirty = false;
+        true
+    }
+
+    /// Should the usage be saved before shutting down?  Like `should_save`, but doesn't wait for
+    /// the save interval.
+    pub fn flush(&mut self, now: Instant) -> bool {
+        if !self.dirty {
+            return false;
+        }
+        self.saved = now;
+        self.dirty = false;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_usage() {
+        let save = Instant::default() + SAVE_INTERVAL;
+        let mut usage = Usage::new();
+        assert!(!usage.should_save(save));
+
+        usage.add_time(LayoutMode::Steno, Duration::from_millis(100));
+        usage.add_stroke(LayoutMode::Steno);
+        usage.add_key(LayoutMode::Qwerty);
+        usage.add_time(LayoutMode::Steno, Duration::from_millis(50));
+        assert!(!usage.should_save(Instant::from_micros(save.as_micros() - 1)));
+        assert!(usage.should_save(save));
+        assert!(!usage.should_save(save + SAVE_INTERVAL));
+
+        let mut usage = Usage::decode(&usage.encode());
+        let report = usage.report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0], ModeUsage { mode: "steno".into(), ms: 150, keys: 0, strokes: 1 });
+        assert_eq!(report[1], ModeUsage { mode: "qwerty".into(), ms: 0, keys: 1, strokes: 0 });
+
+        // Freshly decoded stats don't need saving until they change.
+        assert!(!usage.should_save(save));
+        usage.add_key(LayoutMode::Qwerty);
+        assert!(usage.should_save(save));
+
+        // Shutting down saves changes without waiting.
+        assert!(!usage.flush(save));
+        usage.add_key(LayoutMode::Qwerty);
+        assert!(usage.flush(save));
+        assert!(!usage.should_save(save + SAVE_INTERVAL));
+
+        // Erased flash starts over.
+        assert!(Usage::decode(&[0xff; 64]).report().is_empty());
+    }
+
+    #[test]
+    fn test_rate() {
+        let mut usage = Usage::new();
+        let tick = Duration::from_millis(100);
+
+        // Keys only count once their window is complete.
+        for _ in 0..20 {
+            usage.add_key(LayoutMode::Steno);
+        }
+        assert_eq!(usage.rate(), 0);
+        for _ in 0..100 {
+            usage.add_time(LayoutMode::Steno, tick);
+        }
+        assert_eq!(usage.rate(), 20);
+
+        // And age out after a minute.
+        for _ in 0..500 {
+            usage.add_time(LayoutMode::Steno, tick);
+        }
+        assert_eq!(usage.rate(), 20);
+        for _ in 0..100 {
+            usage.add_time(LayoutMode::Steno, tick);
+        }
+        assert_eq!(usage.rate(), 0);
+    }
+}
@@ -1,17 +1,42 @@
 //! USB keyboard typer
 //!
 //! Accept strings and simulate typing them on a USB HID keyboard.
+//!
+//! The keys sent only become characters on the host, according to the keyboard layout it is set
+//! to, so the keys to type depend on the [`HostLayout`].  The US table covers ASCII, and each other
+//! layout has a short table of the characters it types differently, or adds, which is checked
+//! first.  A character from a dead key (such as `^` on a German layout) is typed as the dead key
+//! followed by a space.
+//!
+//! Characters the host layout has no key for are skipped, unless there is a [`Fallback`] to type
+//! them some other way.  The compose fallback types the usual X11 compose sequences, which the
+//! host needs to be set up for, with a compose key of its own, or a tool such as WinCompose.  The
+//! Unicode fallback types any character by its code point, in the way the host's OS accepts it
+//! (see [`UnicodeEntry`]).
 
 // The keytable represents the keys as u16's, with the low 8 bits corresponding
 // to the Keyboard enum value, and the upper bits indicating modifiers.
 
 use usbd_human_interface_device::page::Keyboard;
 
+pub use minder::{HostLayout, UnicodeEntry};
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
 use crate::{KeyAction, Mods};
 
 /// A shift modifier.
 const SHIFT: u16 = 0x100;
 
+/// The AltGr modifier (right alt).
+const ALTGR: u16 = 0x200;
+
+/// A dead key, which needs a space after it to type the character on its own.
+const DEAD: u16 = 0x400;
+
 /// An empty character, one we don't support sending.
 const NONE: u16 = 0xffff;
 
@@ -25,6 +50,16 @@ const fn s(ch: Keyboard) -> u16 {
     SHIFT | (ch as u16)
 }
 
+/// Encode a single keypress, with AltGr held.
+const fn g(ch: Keyboard) -> u16 {
+    ALTGR | (ch as u16)
+}
+
+/// Mark a keypress as a dead key.
+const fn dead(code: u16) -> u16 {
+    DEAD | code
+}
+
 static KEY_TABLE: [u16; 128] = [
     NONE,  // 0x00, Null character (often represented as NUL)
     NONE,  // 0x01, Start of Heading (often represented as SOH)
@@ -156,6 +191,300 @@ static KEY_TABLE: [u16; 128] = [
     NONE, // 0x7F, Delete (often represented as DEL)
 ];
 
+/// The characters a UK layout types differently from a US one.  The `#` key is the one next to
+/// enter on an ISO keyboard, which hosts treat the same as the US backslash key.
+static UK_KEYS: [(char, u16); 8] = [
+    ('"', s(Keyboard::Keyboard2)),
+    ('@', s(Keyboard::Apostrophe)),
+    ('#', n(Keyboard::NonUSHash)),
+    ('~', s(Keyboard::NonUSHash)),
+    ('\\', n(Keyboard::NonUSBackslash)),
+    ('|', s(Keyboard::NonUSBackslash)),
+    ('£', s(Keyboard::Keyboard3)),
+    ('€', g(Keyboard::Keyboard4)),
+];
+
+/// The characters a German layout types differently from a US one, or adds.
+static DE_KEYS: [(char, u16); 45] = [
+    ('"', s(Keyboard::Keyboard2)),
+    ('#', n(Keyboard::NonUSHash)),
+    ('&', s(Keyboard::Keyboard6)),
+    ('\'', s(Keyboard::NonUSHash)),
+    ('(', s(Keyboard::Keyboard8)),
+    (')', s(Keyboard::Keyboard9)),
+    ('*', s(Keyboard::RightBrace)),
+    ('+', n(Keyboard::RightBrace)),
+    ('-', n(Keyboard::ForwardSlash)),
+    ('/', s(Keyboard::Keyboard7)),
+    (':', s(Keyboard::Dot)),
+    (';', s(Keyboard::Comma)),
+    ('<', n(Keyboard::NonUSBackslash)),
+    ('=', s(Keyboard::Keyboard0)),
+    ('>', s(Keyboard::NonUSBackslash)),
+    ('?', s(Keyboard::Minus)),
+    ('@', g(Keyboard::Q)),
+    ('Y', s(Keyboard::Z)),
+    ('Z', s(Keyboard::Y)),
+    ('[', g(Keyboard::Keyboard8)),
+    ('\\', g(Keyboard::Minus)),
+    (']', g(Keyboard::Keyboard9)),
+    ('^', dead(n(Keyboard::Grave))),
+    ('_', s(Keyboard::ForwardSlash)),
+    ('`', dead(s(Keyboard::Equal))),
+    ('y', n(Keyboard::Z)),
+    ('z', n(Keyboard::Y)),
+    ('{', g(Keyboard::Keyboard7)),
+    ('|', g(Keyboard::NonUSBackslash)),
+    ('}', g(Keyboard::Keyboard0)),
+    ('~', g(Keyboard::RightBrace)),
+    ('§', s(Keyboard::Keyboard3)),
+    ('°', s(Keyboard::Grave)),
+    ('²', g(Keyboard::Keyboard2)),
+    ('³', g(Keyboard::Keyboard3)),
+    ('´', dead(n(Keyboard::Equal))),
+    ('µ', g(Keyboard::M)),
+    ('Ä', s(Keyboard::Apostrophe)),
+    ('Ö', s(Keyboard::Semicolon)),
+    ('Ü', s(Keyboard::LeftBrace)),
+    ('ß', n(Keyboard::Minus)),
+    ('ä', n(Keyboard::Apostrophe)),
+    ('ö', n(Keyboard::Semicolon)),
+    ('ü', n(Keyboard::LeftBrace)),
+    ('€', g(Keyboard::E)),
+];
+
+/// Compose sequences, typed after the compose key, for characters the host layout may not have.
+static COMPOSE: [(char, &str); 72] = [
+    ('á', "'a"),
+    ('é', "'e"),
+    ('í', "'i"),
+    ('ó', "'o"),
+    ('ú', "'u"),
+    ('Á', "'A"),
+    ('É', "'E"),
+    ('Í', "'I"),
+    ('Ó', "'O"),
+    ('Ú', "'U"),
+    ('à', "`a"),
+    ('è', "`e"),
+    ('ì', "`i"),
+    ('ò', "`o"),
+    ('ù', "`u"),
+    ('À', "`A"),
+    ('È', "`E"),
+    ('Ì', "`I"),
+    ('Ò', "`O"),
+    ('Ù', "`U"),
+    ('â', "^a"),
+    ('ê', "^e"),
+    ('î', "^i"),
+    ('ô', "^o"),
+    ('û', "^u"),
+    ('Â', "^A"),
+    ('Ê', "^E"),
+    ('Î', "^I"),
+    ('Ô', "^O"),
+    ('Û', "^U"),
+    ('ä', "\"a"),
+    ('ë', "\"e"),
+    ('ï', "\"i"),
+    ('ö', "\"o"),
+    ('ü', "\"u"),
+    ('Ä', "\"A"),
+    ('Ë', "\"E"),
+    ('Ï', "\"I"),
+    ('Ö', "\"O"),
+    ('Ü', "\"U"),
+    ('ñ', "~n"),
+    ('Ñ', "~N"),
+    ('ç', ",c"),
+    ('Ç', ",C"),
+    ('ß', "ss"),
+    ('æ', "ae"),
+    ('Æ', "AE"),
+    ('ø', "/o"),
+    ('Ø', "/O"),
+    ('å', "oa"),
+    ('Å', "oA"),
+    ('—', "---"),
+    ('–', "--."),
+    ('…', ".."),
+    ('‘', "<'"),
+    ('’', ">'"),
+    ('“', "<\""),
+    ('”', ">\""),
+    ('«', "<<"),
+    ('»', ">>"),
+    ('¡', "!!"),
+    ('¿', "??"),
+    ('€', "=e"),
+    ('£', "-L"),
+    ('°', "oo"),
+    ('×', "xx"),
+    ('÷', ":-"),
+    ('©', "oc"),
+    ('®', "or"),
+    ('™', "tm"),
+    ('·', ".-"),
+    ('±', "+-"),
+];
+
+/// The encoded key that types a character on a host layout, if there is one.
+fn lookup(layout: HostLayout, ch: char) -> Option<u16> {
+    let extra: &[(char, u16)] = match layout {
+        HostLayout::Us => &[],
+        HostLayout::Uk => &UK_KEYS,
+        HostLayout::De => &DE_KEYS,
+    };
+    if let Some(&(_, code)) = extra.iter().find(|(c, _)| *c == ch) {
+        return Some(code);
+    }
+    match *KEY_TABLE.get(ch as usize)? {
+        NONE => None,
+        code => Some(code),
+    }
+}
+
+/// How a character is typed on a host layout.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HostKey {
+    pub key: Keyboard,
+    /// The modifiers to hold with the key.
+    pub mods: Mods,
+    /// The key is a dead key, which has to be followed by a space.
+    pub dead: bool,
+}
+
+/// How to type a character on a host layout.  Returns None for characters the layout can't type.
+pub fn host_key(layout: HostLayout, ch: char) -> Option<HostKey> {
+    let code = lookup(layout, ch)?;
+    let mut mods = Mods::empty();
+    if code & SHIFT != 0 {
+        mods |= Mods::SHIFT;
+    }
+    if code & ALTGR != 0 {
+        mods |= Mods::ALTGR;
+    }
+    Some(HostKey { key: ((code & 0xFF) as u8).into(), mods, dead: code & DEAD != 0 })
+}
+
+/// How to type characters the host layout has no key for.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Fallback {
+    /// Skip them.
+    #[default]
+    None,
+    /// Type a compose sequence, after this key, which the host uses as its compose key.
+    Compose(Keyboard),
+    /// Type the code point, as the host's OS accepts it.
+    Unicode(UnicodeEntry),
+}
+
+/// The keys that type a character.
+enum Typed {
+    /// The character's own key.
+    Key(HostKey),
+    /// A compose key, and the characters of the sequence after it.
+    Compose(Keyboard, &'static str),
+    /// The code point, entered the host's way.
+    Unicode(UnicodeEntry),
+}
+
+/// How to type a character, using the fallback if the layout can't type it.  A compose sequence
+/// is only used if the layout can type each character in it, without dead keys.
+fn typed(layout: HostLayout, fallback: Fallback, ch: char) -> Option<Typed> {
+    if let Some(key) = host_key(layout, ch) {
+        return Some(Typed::Key(key));
+    }
+    match fallback {
+        Fallback::None => None,
+        Fallback::Compose(compose) => {
+            let &(_, seq) = COMPOSE.iter().find(|(c, _)| *c == ch)?;
+            seq.chars()
+                .all(|c| host_key(layout, c).is_some_and(|k| !k.dead))
+                .then_some(Typed::Compose(compose, seq))
+        }
+        Fallback::Unicode(UnicodeEntry::None) => None,
+        Fallback::Unicode(UnicodeEntry::Windows) if ch as u32 > 0xffff => None,
+        Fallback::Unicode(entry) => Some(Typed::Unicode(entry)),
+    }
+}
+
+/// The keypad digits, from 0 to 9.
+const KEYPAD: [Keyboard; 10] = [
+    Keyboard::Keypad0,
+    Keyboard::Keypad1,
+    Keyboard::Keypad2,
+    Keyboard::Keypad3,
+    Keyboard::Keypad4,
+    Keyboard::Keypad5,
+    Keyboard::Keypad6,
+    Keyboard::Keypad7,
+    Keyboard::Keypad8,
+    Keyboard::Keypad9,
+];
+
+/// The hex digits of a value, at least four of them.
+fn hex_digits(value: u32) -> impl Iterator<Item = char> {
+    let count = (32 - value.leading_zeros()).div_ceil(4).max(4);
+    (0..count).rev().filter_map(move |i| char::from_digit((value >> (i * 4)) & 0xf, 16))
+}
+
+/// The modifiers held throughout, and the keys, that enter a character by its code point.
+fn unicode_keys(layout: HostLayout, entry: UnicodeEntry, ch: char) -> (Mods, Vec<(Keyboard, Mods)>) {
+    let digit = |d: char| host_key(layout, d).map(|k| (k.key, k.mods));
+    match entry {
+        UnicodeEntry::None => (Mods::empty(), Vec::new()),
+        UnicodeEntry::Linux => {
+            let mut keys = vec![(Keyboard::U, Mods::CONTROL | Mods::SHIFT)];
+            keys.extend(hex_digits(ch as u32).filter_map(digit));
+            keys.push((Keyboard::Space, Mods::empty()));
+            (Mods::empty(), keys)
+        }
+        UnicodeEntry::Windows => {
+            // Digits have to come from the keypad, but the letters are the usual keys.
+            let mut keys = vec![(Keyboard::KeypadAdd, Mods::empty())];
+            keys.extend(hex_digits(ch as u32).filter_map(|d| match d.to_digit(10) {
+                Some(n) => Some((KEYPAD[n as usize], Mods::empty())),
+                None => digit(d),
+            }));
+            (Mods::ALT, keys)
+        }
+        UnicodeEntry::Mac => {
+            let mut units = [0; 2];
+            let keys = ch
+                .encode_utf16(&mut units)
+                .iter()
+                .flat_map(|&unit| hex_digits(unit as u32))
+                .filter_map(digit)
+                .collect();
+            (Mods::ALT, keys)
+        }
+    }
+}
+
+/// Can a character be typed on a host layout, either with its own key, or with the fallback.
+pub fn can_type(layout: HostLayout, fallback: Fallback, ch: char) -> bool {
+    typed(layout, fallback, ch).is_some()
+}
+
+/// The key, and whether it is shifted, that types a character on a US layout.  Returns None for
+/// characters that can't be typed.
+pub fn char_key(ch: char) -> Option<(Keyboard, bool)> {
+    let code = *KEY_TABLE.get(ch as usize)?;
+    if code == NONE {
+        return None;
+    }
+    Some((((code & 0xFF) as u8).into(), (code & SHIFT) != 0))
+}
+
+/// The character a key types, the reverse of the table used to type text.  Returns None for keys
+/// that don't type a character in the table.
+pub fn key_char(key: Keyboard, shift: bool) -> Option<char> {
+    let code = key as u16 | if shift { SHIFT } else { 0 };
+    KEY_TABLE.iter().position(|&c| c == code).map(|i| i as u8 as char)
+}
+
 /// An ActionHandler is something that is able to take actions.
 pub trait ActionHandler {
     // For now, suppress the warning.
@@ -163,27 +492,40 @@ pub trait ActionHandler {
     async fn enqueue_actions<I: Iterator<Item = KeyAction>>(&mut self, events: I);
 }
 
-/// Enqueue an action as keypresses.
-pub async fn enqueue_action<H: ActionHandler>(usb: &mut H, text: &str) {
+/// Enqueue an action as keypresses, for a host set to `layout`.  Characters the layout can't type
+/// are typed with the fallback, or skipped.
+pub async fn enqueue_action<H: ActionHandler>(
+    usb: &mut H,
+    layout: HostLayout,
+    fallback: Fallback,
+    text: &str,
+) {
     let mut last_action = None;
 
     for ch in text.chars() {
-        if ch < (128 as char) {
-            let code = KEY_TABLE[ch as usize];
-            if code == NONE {
-                continue;
+        match typed(layout, fallback, ch) {
+            Some(Typed::Key(key)) => tap(usb, &mut last_action, key).await,
+            Some(Typed::Compose(compose, seq)) => {
+                let compose = HostKey { key: compose, mods: Mods::empty(), dead: false };
+                tap(usb, &mut last_action, compose).await;
+                for key in seq.chars().filter_map(|c| host_key(layout, c)) {
+                    tap(usb, &mut last_action, key).await;
+                }
             }
-            let shifted = (code & SHIFT) != 0;
-            let code: Keyboard = ((code & 0xFF) as u8).into();
-            let action = KeyAction::KeyPress(code, if shifted {Mods::SHIFT} else {Mods::empty()});
-
-            // We only need to send an explicit KeyRelease when the last thing sent was the same as
-            // the current.  TODO: There is excess copying here.
-            if last_action == Some(action.clone()) {
-                usb.enqueue_actions([KeyAction::KeyRelease].iter().cloned()).await;
+            Some(Typed::Unicode(entry)) => {
+                // The modifiers stay held between the keys, so a repeated key is separated by
+                // releasing just the key.
+                let (held, keys) = unicode_keys(layout, entry, ch);
+                for (key, mods) in keys {
+                    let action = KeyAction::KeyPress(key, held | mods);
+                    if last_action == Some(action.clone()) {
+                        usb.enqueue_actions([KeyAction::ModOnly(held)].into_iter()).await;
+                    }
+                    usb.enqueue_actions([action.clone()].into_iter()).await;
+                    last_action = Some(action);
+                }
             }
-            usb.enqueue_actions([action.clone()].iter().cloned()).await;
-            last_action = Some(action);
+            None => (),
         }
 
         // Send a release at the end.
@@ -192,3 +534,171 @@ pub async fn enqueue_action<H: ActionHandler>(usb: &mut H, text: &str) {
         }
     }
 }
+
+/// Press a key, after `last_action`, the previous key pressed.
+async fn tap<H: ActionHandler>(usb: &mut H, last_action: &mut Option<KeyAction>, key: HostKey) {
+    let action = KeyAction::KeyPress(key.key, key.mods);
+
+    // We only need to send an explicit KeyRelease when the last thing sent was the same as
+    // the current.  TODO: There is excess copying here.
+    if *last_action == Some(action.clone()) {
+        usb.enqueue_actions([KeyAction::KeyRelease].iter().cloned()).await;
+    }
+    usb.enqueue_actions([action.clone()].iter().cloned()).await;
+    *last_action = Some(action);
+
+    // The space after a dead key types the character itself.
+    if key.dead {
+        let space = KeyAction::KeyPress(Keyboard::Space, Mods::empty());
+        usb.enqueue_actions([KeyAction::KeyRelease, space.clone()].into_iter()).await;
+        *last_action = Some(space);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{run, Recorder};
+
+    /// The actions that type some text.
+    fn actions(layout: HostLayout, fallback: Fallback, text: &str) -> Vec<KeyAction> {
+        let mut rec = Recorder::default();
+        run(enqueue_action(&mut rec, layout, fallback, text));
+        rec.keys.into_inner()
+    }
+
+    /// The keys pressed to type some text, leaving out the releases.
+    fn presses(layout: HostLayout, fallback: Fallback, text: &str) -> Vec<(Keyboard, Mods)> {
+        actions(layout, fallback, text)
+            .into_iter()
+            .filter_map(|action| match action {
+                KeyAction::KeyPress(key, mods) => Some((key, mods)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_host_key() {
+        let key = |layout, ch| host_key(layout, ch).map(|k| (k.key, k.mods, k.dead));
+
+        assert_eq!(key(HostLayout::Us, 'y'), Some((Keyboard::Y, Mods::empty(), false)));
+        assert_eq!(key(HostLayout::De, 'y'), Some((Keyboard::Z, Mods::empty(), false)));
+        assert_eq!(key(HostLayout::De, 'Z'), Some((Keyboard::Y, Mods::SHIFT, false)));
+        assert_eq!(key(HostLayout::De, '@'), Some((Keyboard::Q, Mods::ALTGR, false)));
+        assert_eq!(key(HostLayout::De, 'ß'), Some((Keyboard::Minus, Mods::empty(), false)));
+        assert_eq!(key(HostLayout::De, '^'), Some((Keyboard::Grave, Mods::empty(), true)));
+        assert_eq!(key(HostLayout::Uk, '@'), Some((Keyboard::Apostrophe, Mods::SHIFT, false)));
+        assert_eq!(key(HostLayout::Uk, '£'), Some((Keyboard::Keyboard3, Mods::SHIFT, false)));
+
+        // Layouts without a character don't fall back to US.
+        assert_eq!(key(HostLayout::Us, 'ü'), None);
+        assert_eq!(key(HostLayout::Uk, 'ü'), None);
+        assert_eq!(key(HostLayout::Us, '\t'), None);
+
+        // Every character has a key of its own.
+        for layout in [HostLayout::Us, HostLayout::Uk, HostLayout::De] {
+            let typed: Vec<_> = (0..=255u8)
+                .map(char::from)
+                .chain(['€'])
+                .filter_map(|ch| lookup(layout, ch))
+                .collect();
+            for (i, code) in typed.iter().enumerate() {
+                assert!(!typed[i + 1..].contains(code), "{:?} has two characters on {:#x}", layout, code);
+            }
+        }
+
+        // And the US table agrees with the older lookup.
+        for ch in (0..128u8).map(char::from) {
+            let us = host_key(HostLayout::Us, ch).map(|k| (k.key, k.mods.contains(Mods::SHIFT)));
+            assert_eq!(us, char_key(ch));
+        }
+    }
+
+    #[test]
+    fn test_ascii() {
+        // Every printable character types on every layout.
+        for layout in [HostLayout::Us, HostLayout::Uk, HostLayout::De] {
+            for ch in (' '..='~').chain(['\n']) {
+                assert!(can_type(layout, Fallback::None, ch), "{:?} can't type {:?}", layout, ch);
+                assert!(!presses(layout, Fallback::None, &ch.to_string()).is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_compose() {
+        let none = Mods::empty();
+        let compose = Fallback::Compose(Keyboard::RightGUI);
+
+        // Without a fallback, characters the layout lacks are skipped.
+        assert_eq!(presses(HostLayout::Us, Fallback::None, "é"), []);
+        assert!(!can_type(HostLayout::Us, Fallback::None, 'é'));
+
+        assert!(can_type(HostLayout::Us, compose, 'é'));
+        assert_eq!(
+            presses(HostLayout::Us, compose, "aé"),
+            [(Keyboard::A, none), (Keyboard::RightGUI, none), (Keyboard::Apostrophe, none), (Keyboard::E, none)]
+        );
+
+        // A character the layout has is typed with its own key.
+        assert_eq!(presses(HostLayout::De, compose, "ü"), [(Keyboard::LeftBrace, none)]);
+
+        // The sequence is typed with the host layout's keys.
+        assert_eq!(
+            presses(HostLayout::De, compose, "ñ"),
+            [(Keyboard::RightGUI, none), (Keyboard::RightBrace, Mods::ALTGR), (Keyboard::N, none)]
+        );
+
+        // But not with dead keys, which would combine with the next key.
+        assert!(!can_type(HostLayout::De, compose, 'ê'));
+
+        // Characters with no sequence are still skipped.
+        assert!(!can_type(HostLayout::Us, compose, '☃'));
+    }
+
+    #[test]
+    fn test_unicode() {
+        let none = Mods::empty();
+        let ctrl_shift = Mods::CONTROL | Mods::SHIFT;
+
+        // Linux, with the digits as the host layout types them.
+        assert_eq!(
+            presses(HostLayout::De, Fallback::Unicode(UnicodeEntry::Linux), "—"),
+            [
+                (Keyboard::U, ctrl_shift),
+                (Keyboard::Keyboard2, none),
+                (Keyboard::Keyboard0, none),
+                (Keyboard::Keyboard1, none),
+                (Keyboard::Keyboard4, none),
+                (Keyboard::Space, none),
+            ]
+        );
+
+        // Windows holds alt throughout, with the digits on the keypad.
+        let windows = Fallback::Unicode(UnicodeEntry::Windows);
+        assert_eq!(
+            actions(HostLayout::Us, windows, "é"),
+            [
+                KeyAction::KeyPress(Keyboard::KeypadAdd, Mods::ALT),
+                KeyAction::KeyPress(Keyboard::Keypad0, Mods::ALT),
+                KeyAction::ModOnly(Mods::ALT),
+                KeyAction::KeyPress(Keyboard::Keypad0, Mods::ALT),
+                KeyAction::KeyPress(Keyboard::E, Mods::ALT),
+                KeyAction::KeyPress(Keyboard::Keypad9, Mods::ALT),
+                KeyAction::KeyRelease,
+            ]
+        );
+        assert!(!can_type(HostLayout::Us, windows, '😀'));
+
+        // Mac enters characters outside the basic plane as a surrogate pair.
+        let mac = presses(HostLayout::Us, Fallback::Unicode(UnicodeEntry::Mac), "😀");
+        assert_eq!(mac.len(), 8);
+        assert!(mac.iter().all(|&(_, mods)| mods == Mods::ALT));
+        assert_eq!(mac[..4], [Keyboard::D, Keyboard::Keyboard8, Keyboard::Keyboard3, Keyboard::D].map(|k| (k, Mods::ALT)));
+
+        // Characters with a key are still typed with it.
+        assert_eq!(presses(HostLayout::Us, Fallback::Unicode(UnicodeEntry::Mac), "a"), [(Keyboard::A, none)]);
+        assert!(!can_type(HostLayout::Us, Fallback::Unicode(UnicodeEntry::None), 'é'));
+    }
+}
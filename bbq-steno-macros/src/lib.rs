@@ -1,9 +1,14 @@
-//! The 'stroke!()' macro.
+//! The 'stroke!()' and 'embed_dict!()' macros.
 //!
-//! This macro convererts a steno stroke in textual format into the internal
+//! `stroke!()` convererts a steno stroke in textual format into the internal
 //! integer representation at compile time.
+//!
+//! `embed_dict!()` reads a small dictionary file at compile time, and expands to
+//! a table of entries that can be built into a dictionary at runtime.
+
+use std::path::PathBuf;
 
-use bbq_steno::stroke::Stroke;
+use bbq_steno::{stroke::Stroke, Replacement};
 use proc_macro::TokenStream;
 use quote::quote;
 use syn::{parse_macro_input, LitStr};
@@ -28,3 +33,98 @@ pub fn stroke(input: TokenStream) -> TokenStream {
 
     TokenStream::from(expanded)
 }
+
+/// Embed a dictionary file.
+///
+/// The path is relative to the manifest of the crate using the macro.  Each line
+/// is of the form `'text': STROKES`, the same as the typey exercise files, and
+/// blank lines and those starting with `#` are ignored.  The text supports a few
+/// of the Plover operators: `{^}` to suppress a space, `{-|}` to capitalize the
+/// next word, and `{&x}` for fingerspelling.
+///
+/// This expands to a `&'static [(&'static [Stroke], &'static str)]`.
+#[proc_macro]
+pub fn embed_dict(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as LitStr);
+
+    let mut path = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default());
+    path.push(input.value());
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => {
+            return syn::Error::new(input.span(), format!("{}: {}", path.display(), e))
+                .into_compile_error()
+                .into();
+        }
+    };
+
+    let mut entries = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = line
+            .strip_prefix('\'')
+            .and_then(|line| line.rsplit_once("': "))
+            .ok_or_else(|| "expecting 'text': STROKES".to_string())
+            .and_then(|(text, steno)| {
+                let strokes = steno
+                    .split('/')
+                    .map(|s| Stroke::from_text(s).map(|s| s.into_raw()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("{}", e))?;
+                Ok((strokes, Replacement::encode(&decode_text(text))))
+            });
+        match entry {
+            Ok((strokes, text)) => entries.push(quote! {
+                (&[#(::bbq_steno::stroke::Stroke::from_raw(#strokes)),*], #text)
+            }),
+            Err(e) => {
+                return syn::Error::new(input.span(), format!("{}:{}: {}", path.display(), num + 1, e))
+                    .into_compile_error()
+                    .into();
+            }
+        }
+    }
+
+    // Include the file so that changes to it cause a rebuild.
+    let path = path.display().to_string();
+    let expanded = quote! {
+        {
+            const _: &[u8] = include_bytes!(#path);
+            &[#(#entries),*]
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+/// Decode the text of an embedded dictionary entry.
+fn decode_text(text: &str) -> Vec<Replacement> {
+    let mut result = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix("{^}") {
+            result.push(Replacement::DeleteSpace);
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("{-|}") {
+            result.push(Replacement::CapNext);
+            rest = tail;
+        } else if let Some((letter, tail)) = rest.strip_prefix("{&").and_then(|r| r.split_once('}')) {
+            result.push(Replacement::Stitch);
+            result.push(Replacement::Text(letter.to_string()));
+            rest = tail;
+        } else {
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|(_, ch)| *ch == '{')
+                .map(|(pos, _)| pos)
+                .unwrap_or(rest.len());
+            result.push(Replacement::Text(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+    }
+    result
+}
@@ -10,18 +10,19 @@
 extern crate alloc;
 
 use core::fmt::Debug;
+use core::ops::Range;
 
 use alloc::boxed::Box;
 use alloc::rc::Rc;
 use alloc::string::{String, ToString};
 
-use crate::Stroke;
+use crate::{Affix, Stroke};
 
 pub use self::mapdict::{RamDict, MapDictBuilder};
 pub use self::translate::Translator;
 pub use self::typer::TypeAction;
-pub use self::lookup::Lookup;
-pub use self::joiner::{Joiner, Joined};
+pub use self::lookup::{Lookup, Strategy, UNDO_DEPTH};
+pub use self::joiner::{Command, Joiner, Joined};
 pub use self::emily::EmilySymbols;
 
 mod emily;
@@ -30,6 +31,7 @@ mod lookup;
 mod mapdict;
 mod ortho;
 mod translate;
+pub mod json;
 pub mod typer;
 
 pub type Dict = Rc<dyn DictImpl>;
@@ -51,10 +53,20 @@ pub trait Selector: Debug {
 
     /// Print this selector, verbosely.  This will print all entries that match.
     fn dump(&self);
+
+    /// A copy of this selector, for results that are kept to be used again.
+    fn clone_box(&self) -> Box<dyn Selector>;
+
+    /// The affix class of the translation found by the step that gave this selector, if the
+    /// dictionary knows it.  None leaves it to be worked out from the text.
+    fn affix(&self) -> Option<Affix> {
+        None
+    }
 }
 
 /// A Selector over a dictionary tracks a range of the dictionary that specifies
 /// a range of entries in the dictionary that cover a given prefix.
+#[derive(Clone)]
 pub struct BinarySelector {
     /// The dictionary this entry applies to.
     dict: Dict,
@@ -96,10 +108,16 @@ impl Selector for BinarySelector {
     /// given token.  If there are zero entries in the dictionary that match,
     /// this will return None.
     fn lookup_step(&self, key: Stroke) -> Option<(Box<dyn Selector>, Option<String>)> {
-        let left = self.dict.scan(self.left, self.right, self.count, key);
-        // println!("left = {}", left);
-        let right = self.dict.scan(self.left, self.right, self.count, key.succ());
-        // println!("right = {}", right);
+        // The first stroke can come from an index.
+        let indexed = if self.count == 0 { self.dict.first_range(key) } else { None };
+        let (left, right) = match indexed {
+            Some(range) => (range.start, range.end),
+            None => (
+                self.dict.scan(self.left, self.right, self.count, key),
+                self.dict.scan(self.left, self.right, self.count, key.succ()),
+            ),
+        };
+        // println!("left = {}, right = {}", left, right);
         if right > left {
             let key = self.dict.key(left);
             let text = if key.len() == self.count + 1 {
@@ -129,6 +147,18 @@ impl Selector for BinarySelector {
         self.count
     }
 
+    fn clone_box(&self) -> Box<dyn Selector> {
+        Box::new(self.clone())
+    }
+
+    fn affix(&self) -> Option<Affix> {
+        if self.left < self.right && self.dict.key(self.left).len() == self.count {
+            Some(self.dict.affix(self.left))
+        } else {
+            None
+        }
+    }
+
     // The selector is only implemented with std.
     #[cfg(feature = "std")]
     fn dump(&self) {
@@ -155,6 +185,18 @@ pub trait DictImpl {
     fn value(&self, index: usize) -> &str;
     fn selector(self: Rc<Self>) -> Box<dyn Selector>;
 
+    /// How the given entry attaches to the words around it.  Dictionaries that store the class
+    /// should return it, to save scanning the text.
+    fn affix(&self, index: usize) -> Affix {
+        Affix::of(self.value(index))
+    }
+
+    /// The entries whose keys start with `stroke`, for dictionaries that can find them without a
+    /// search.  None leaves it to [`DictImpl::scan`].
+    fn first_range(&self, _stroke: Stroke) -> Option<Range<usize>> {
+        None
+    }
+
     /// For a given range of the dictionary, do a binary search for the given
     /// key as the nth character of a key.
     fn scan(&self, a: usize, b: usize, pos: usize, needle: Stroke) -> usize {
@@ -42,7 +42,7 @@ use super::{DictImpl, Selector};
 
 mod consts;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Decoded {
     // The translation.
     xlat: String,
@@ -270,6 +270,10 @@ impl Selector for Decoded {
 
     fn dump(&self) {
     }
+
+    fn clone_box(&self) -> Box<dyn Selector> {
+        Box::new(self.clone())
+    }
 }
 
 /// Emily's dictionary itself is just a marker.
@@ -324,4 +328,8 @@ impl Selector for RootSelector {
     fn dump(&self) {
         todo!()
     }
+
+    fn clone_box(&self) -> Box<dyn Selector> {
+        Box::new(RootSelector)
+    }
 }
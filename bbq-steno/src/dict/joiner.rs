@@ -13,7 +13,7 @@
 //!
 //! To make this more complicated, the action from the translation can also be an "undo", which
 //! needs to restore the input to the state it was in before that stroke was typed. Undo can be
-//! pressed repeatedly, up until a given history length.
+//! pressed repeatedly, up to [`UNDO_DEPTH`] strokes back, as [`Lookup`](super::Lookup) allows.
 
 extern crate alloc;
 
@@ -24,9 +24,10 @@ use alloc::vec::Vec;
 use log::info;
 
 use crate::replacements::Previous;
-use crate::Replacement;
+use crate::{Affix, Replacement};
 
-use super::lookup::Action;
+use super::lookup::{Action, UNDO_DEPTH};
+use super::ortho;
 
 /// The minimum amount of typed history to keep.
 const MIN_TYPED: usize = 256;
@@ -36,8 +37,12 @@ const MIN_TYPED: usize = 256;
 /// in the dictionary.
 const MAX_TYPED: usize = MIN_TYPED * 2 + 64;
 
+/// The most strokes a translation is expected to replace.  History beyond the undo depth is kept for
+/// these, so that a long translation can still find the text of the strokes it replaces.
+const LONGEST_OUTLINE: usize = 16;
+
 /// The largest we allow the history to grow to.  This is in strokes.
-const MAX_HISTORY: usize = 50;
+const MAX_HISTORY: usize = UNDO_DEPTH + LONGEST_OUTLINE;
 
 /// A Joiner to join steno translations together.
 pub struct Joiner {
@@ -54,6 +59,9 @@ pub struct Joiner {
     /// Each positive action is added here, to the back.
     history: VecDeque<Add>,
 
+    /// The state to start from when there is no history to take it from.
+    start: State,
+
     /// What has been typed.  These have an associated age" when they were created, and can be
     /// retrieved only as long as the age is valid.
     actions: VecDeque<(u64, Joined)>,
@@ -71,6 +79,11 @@ struct State {
     stitch: bool,
 }
 
+impl State {
+    /// The start of the text.
+    const START: State = State { cap: true, space: false, force_space: false, stitch: false };
+}
+
 /// Just the fields from the add action.
 #[derive(Debug)]
 struct Add {
@@ -86,12 +99,40 @@ struct Add {
 
 /// The result of the Joiner's calculations.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Joined {
     Type {
         /// How many times to press backspace.
         remove: usize,
         /// Characters to type.
         append: String,
+    },
+    /// Press a key combination, from a raw (`{#...}`) dictionary entry, such as "Control_L(z)".
+    Raw(String),
+    /// Carry out a command, rather than typing anything.
+    Command(Command),
+}
+
+/// A command for the keyboard, from a `{PLOVER:...}` or `{MODE:...}` dictionary entry.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Command {
+    /// Control of the steno engine itself, such as "TOGGLE", "SUSPEND", or "RESUME".
+    Plover(String),
+    /// An output mode, such as "CAPS" or "RESET".
+    Mode(String),
+    /// A command we don't recognize, as "NAME:ARG".
+    Unknown(String),
+}
+
+impl Command {
+    /// Decode a command from the "NAME:ARG" form stored in the dictionary.
+    pub fn parse(text: &str) -> Command {
+        match text.split_once(':') {
+            Some(("PLOVER", arg)) => Command::Plover(arg.to_uppercase()),
+            Some(("MODE", arg)) => Command::Mode(arg.to_uppercase()),
+            _ => Command::Unknown(text.into()),
+        }
     }
 }
 
@@ -109,6 +150,11 @@ struct Next {
     state: State,
     // What will be the end state after the actions.
     next_state: State,
+    // Raw keys and commands, sent after the typing.
+    extra: Vec<Joined>,
+    // The translation attaches to the previous word, and its text has not been typed yet, so it is
+    // a suffix, to be attached following the orthography rules.
+    suffix: bool,
 }
 
 impl Joiner {
@@ -117,6 +163,7 @@ impl Joiner {
             now: 0,
             typed: String::new(),
             history: VecDeque::new(),
+            start: State::START,
             actions: VecDeque::new(),
         }
     }
@@ -127,14 +174,14 @@ impl Joiner {
 
         match action {
             Action::Undo => self.undo(),
-            Action::Add { text, strokes } => {
-                self.do_add(text, strokes);
+            Action::Add { text, strokes, affix } => {
+                self.do_add(text, strokes, affix);
             }
         }
     }
 
     /// Perform an add of additional data.
-    fn do_add(&mut self, text: Vec<Replacement>, strokes: usize) {
+    fn do_add(&mut self, text: Vec<Replacement>, strokes: usize, affix: Affix) {
         // println!("do_add: {} {:?}", strokes, text);
 
         // Figure out how much to delete based on the previous state.
@@ -142,7 +189,10 @@ impl Joiner {
         let mut remove: isize = 0;
         let mut tmp = vec![];
         for _ in 1..strokes {
-            let elt = self.history.pop_back().unwrap();
+            // History that has expired can't be replaced.
+            let Some(elt) = self.history.pop_back() else {
+                break;
+            };
             // println!("remove: len:{}, remove:{}", elt.append.len(), elt.remove);
             remove += elt.append.len() as isize;
             remove -= elt.remove as isize;
@@ -160,7 +210,7 @@ impl Joiner {
         }
 
         // Compute the new state and action based on what is in the definition.
-        let mut next = Next::new(self, remove as usize, strokes);
+        let mut next = Next::new(self, remove as usize, strokes, affix);
 
         // Pop the removed characters.
         for _ in 0..remove {
@@ -203,6 +253,9 @@ impl Joiner {
             remove: next.remove,
             append: next.append,
         }));
+        for extra in next.extra {
+            self.actions.push_back((self.now, extra));
+        }
     }
 
     fn undo(&mut self) {
@@ -221,7 +274,7 @@ impl Joiner {
 
             // Synthesize an action for this.
             self.actions.push_back((self.now, Joined::Type {
-                remove: add.append.len(),
+                remove: add.append.chars().count(),
                 append: removed,
             }));
 
@@ -239,6 +292,32 @@ impl Joiner {
         }
     }
 
+    /// Forget what has been typed, for when the output didn't reach the host as it was given,
+    /// such as when it was stopped part way.  The next translation then starts afresh, rather
+    /// than removing, or attaching to, text that may not be there.
+    pub fn reset(&mut self) {
+        self.typed.clear();
+        self.history.clear();
+        self.start = State::START;
+        self.actions.clear();
+    }
+
+    /// Type text that doesn't come from steno, such as from the host, in order with the
+    /// translations.  Translations after it join onto it, but it can't be undone, and ones before
+    /// it can no longer be reached.
+    pub fn add_text(&mut self, text: &str) {
+        self.shrink();
+        self.typed.push_str(text);
+        self.history.clear();
+        self.start = State {
+            cap: false,
+            space: !text.ends_with(char::is_whitespace),
+            force_space: false,
+            stitch: false,
+        };
+        self.actions.push_back((self.now, Joined::Type { remove: 0, append: text.into() }));
+    }
+
     /// Shrink the history down enough so any additional can be added.
     fn shrink(&mut self) {
         // This is actually a little messy, because of Unicode.  We'll avoid the length calculation
@@ -271,14 +350,15 @@ impl Joiner {
 }
 
 impl Next {
-    fn new(joiner: &mut Joiner, remove: usize, strokes: usize) -> Next {
+    fn new(joiner: &mut Joiner, remove: usize, strokes: usize, affix: Affix) -> Next {
         // Go back in history, one less than the number of strokes in this definition to get our
         // starting state.
         let state = if let Some(node) = joiner.history.iter().rev().skip(strokes - 1).next() {
             node.state.clone()
         } else {
-            // Fake an initial state.  Shouldn't happen unless we back up over the history.
-            State { cap: true, space: false, force_space: false, stitch: false }
+            // Nothing to go on, other than how the text started.  Shouldn't happen unless we back
+            // up over the history.
+            joiner.start.clone()
         };
 
         // Carry the cap through, which we will remove, once we actually capitalize something.
@@ -290,6 +370,8 @@ impl Next {
             append: String::new(),
             state,
             next_state,
+            extra: Vec::new(),
+            suffix: affix.attaches_before(),
         }
     }
 
@@ -297,6 +379,10 @@ impl Next {
     fn add_replacement(&mut self, joiner: &mut Joiner, text: &Replacement) {
         match text {
             Replacement::Text(t) => {
+                let folded = if self.suffix { self.fold_suffix(joiner, t) } else { None };
+                let t = folded.as_ref().unwrap_or(t);
+                self.suffix = false;
+
                 if (self.state.space && (!self.state.stitch || !self.next_state.stitch)) ||
                     (self.state.force_space || self.next_state.force_space)
                 {
@@ -337,6 +423,10 @@ impl Next {
                 self.next_state.force_space = true;
             }
             Replacement::Stitch => self.next_state.stitch = true,
+            Replacement::Raw(raw) => self.extra.push(Joined::Raw(raw.clone())),
+            Replacement::Command(command) => {
+                self.extra.push(Joined::Command(Command::parse(command)));
+            }
 
             // Capitalize the previous 'n' words.
             Replacement::Previous(n, Previous::Capitalize) => {
@@ -353,6 +443,14 @@ impl Next {
                 self.fix_currency(joiner);
             }
 
+            // Join the previous words, or split them apart again.
+            Replacement::Previous(n, Previous::DeleteSpace) => {
+                self.replace_spaces(joiner, *n as usize, None);
+            }
+            Replacement::Previous(n, Previous::AddSpace) => {
+                self.add_spaces(joiner, *n as usize);
+            }
+
             Replacement::Previous(n, Previous::ReplaceSpace(ch)) => {
                 self.replace_spaces(joiner, *n as usize,
                                     if *ch == '\0' { None } else { Some(*ch) });
@@ -395,6 +493,33 @@ impl Next {
         mode.convert(&mut buf, &mut self.append);
     }
 
+    /// Attach a suffix to the word before it, following the orthography rules.  The word is taken
+    /// back off of what was typed, and the returned text types it again with the suffix.  Returns
+    /// None, leaving things alone, if either isn't made of letters.
+    fn fold_suffix(&mut self, joiner: &mut Joiner, suffix: &str) -> Option<String> {
+        if !suffix.starts_with(|ch: char| ch.is_alphabetic()) {
+            return None;
+        }
+
+        // Holds characters, in reverse order, as we pop them off of typed.
+        let mut buf = String::new();
+        while let Some(ch) = joiner.typed.pop() {
+            if !ch.is_alphabetic() {
+                joiner.typed.push(ch);
+                break;
+            }
+            buf.push(ch);
+            self.removed.push(ch);
+            self.remove += 1;
+        }
+        if buf.is_empty() {
+            return None;
+        }
+
+        let word: String = buf.chars().rev().collect();
+        Some(ortho::combine(&word, suffix))
+    }
+
     /// Replace the previous 'count' spaces with the given replacement.
     fn replace_spaces(&mut self, joiner: &mut Joiner, count: usize, replacement: Option<char>) {
         let mut buf = String::new();
@@ -425,6 +550,40 @@ impl Next {
         }
     }
 
+    /// Put a space in front of each of the previous 'count' translations that typed something,
+    /// where there isn't one already.  This works from the history, rather than the text, as
+    /// there is no space to find in what was typed.
+    fn add_spaces(&mut self, joiner: &mut Joiner, count: usize) {
+        let lens: Vec<usize> = joiner
+            .history
+            .iter()
+            .rev()
+            .map(|add| add.append.chars().count())
+            .filter(|&len| len > 0)
+            .take(count)
+            .collect();
+
+        // The text to retype, reversed, as the characters are popped.
+        let mut buf = String::new();
+        for len in lens {
+            for _ in 0..len {
+                let Some(ch) = joiner.typed.pop() else {
+                    break;
+                };
+                buf.push(ch);
+                self.removed.push(ch);
+                self.remove += 1;
+            }
+            if !buf.ends_with(' ') && !joiner.typed.is_empty() && !joiner.typed.ends_with(' ') {
+                buf.push(' ');
+            }
+        }
+
+        while let Some(ch) = buf.pop() {
+            self.append.push(ch);
+        }
+    }
+
     /// Add a dollar sign in front of the previous amount of currency.  There are we're looking for
     /// a sequence of digits to put this in front of.  The examples allow the word "million" and
     /// "billion" to occur, but, frankly, I see little reason to actually use this, and find it is
@@ -586,3 +745,124 @@ impl WordWalk {
         !self.in_word && self.word == count
     }
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    /// Add a translation, returning what gets typed.
+    fn add(joiner: &mut Joiner, text: &str) -> (usize, String) {
+        let affix = Affix::of(text);
+        let text = Replacement::decode(text).unwrap();
+        joiner.add(Action::Add { text, strokes: 1, affix });
+        match joiner.pop(0) {
+            Some(Joined::Type { remove, append }) => (remove, append),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_suffix() {
+        let mut joiner = Joiner::new();
+        assert_eq!(add(&mut joiner, "cherry"), (0, "Cherry".to_string()));
+        assert_eq!(add(&mut joiner, "\u{1}s"), (1, "ies".to_string()));
+        assert_eq!(add(&mut joiner, "defer"), (0, " defer".to_string()));
+        assert_eq!(add(&mut joiner, "\u{1}ed"), (0, "red".to_string()));
+
+        // Undo puts the word back the way it was.
+        joiner.add(Action::Undo);
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 3, .. })));
+
+        // Suffixes only attach to words.
+        assert_eq!(add(&mut joiner, "."), (0, " .".to_string()));
+        assert_eq!(add(&mut joiner, "\u{1}s"), (0, "s".to_string()));
+        // Attaching something other than letters leaves the word alone.
+        assert_eq!(add(&mut joiner, "try"), (0, " try".to_string()));
+        assert_eq!(add(&mut joiner, "\u{1}'s"), (0, "'s".to_string()));
+    }
+
+    #[test]
+    fn test_affix() {
+        // The stored class is used, rather than the markers.
+        let mut joiner = Joiner::new();
+        assert_eq!(add(&mut joiner, "cherry"), (0, "Cherry".to_string()));
+        let text = Replacement::decode("\u{1}s").unwrap();
+        joiner.add(Action::Add { text, strokes: 1, affix: Affix::None });
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 0, ref append }) if append == "s"));
+
+        // An infix attaches both ways.
+        assert_eq!(add(&mut joiner, "try"), (0, " try".to_string()));
+        assert_eq!(add(&mut joiner, "\u{1}-\u{1}"), (0, "-".to_string()));
+        assert_eq!(add(&mut joiner, "out"), (0, "out".to_string()));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut joiner = Joiner::new();
+        assert_eq!(add(&mut joiner, "cherry"), (0, "Cherry".to_string()));
+        joiner.reset();
+
+        // Nothing is left to attach to, or undo, and it starts again as at the start of the text.
+        assert_eq!(add(&mut joiner, "\u{1}s"), (0, "S".to_string()));
+        joiner.add(Action::Undo);
+        joiner.add(Action::Undo);
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 1, .. })));
+        assert!(joiner.pop(0).is_none());
+    }
+
+    #[test]
+    fn test_add_text() {
+        let mut joiner = Joiner::new();
+        assert_eq!(add(&mut joiner, "cherry"), (0, "Cherry".to_string()));
+        joiner.add_text(", see");
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 0, ref append }) if append == ", see"));
+
+        // Later translations join onto the text, but can't undo it, or reach past it.
+        assert_eq!(add(&mut joiner, "\u{1}s"), (0, "s".to_string()));
+        assert_eq!(add(&mut joiner, "world"), (0, " world".to_string()));
+        joiner.add(Action::Undo);
+        joiner.add(Action::Undo);
+        joiner.add(Action::Undo);
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 6, .. })));
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 1, .. })));
+        assert!(joiner.pop(0).is_none());
+
+        joiner.add_text("\n");
+        joiner.pop(0);
+        assert_eq!(add(&mut joiner, "world"), (0, "world".to_string()));
+    }
+
+    #[test]
+    fn test_retro() {
+        let mut joiner = Joiner::new();
+        assert_eq!(add(&mut joiner, "hello"), (0, "Hello".to_string()));
+        assert_eq!(add(&mut joiner, "world"), (0, " world".to_string()));
+
+        // Retro capitalize, `{*-|}`.
+        assert_eq!(add(&mut joiner, "\u{e001}\u{1}"), (5, "World".to_string()));
+
+        // Retro delete space, `{*!}`, and put it back again with `{*?}`.
+        assert_eq!(add(&mut joiner, "\u{e004}\u{1}"), (6, "World".to_string()));
+        assert_eq!(add(&mut joiner, "\u{e009}\u{1}"), (5, " World".to_string()));
+
+        // Undo takes the space back out, and then goes on back through the rest.
+        joiner.add(Action::Undo);
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 6, ref append }) if append == "World"));
+        joiner.add(Action::Undo);
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 5, ref append }) if append == " World"));
+        joiner.add(Action::Undo);
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 5, ref append }) if append == "world"));
+        joiner.add(Action::Undo);
+        assert!(matches!(joiner.pop(0), Some(Joined::Type { remove: 6, ref append }) if append.is_empty()));
+        assert_eq!(add(&mut joiner, "world"), (0, " world".to_string()));
+
+        // A space already there is left alone, as is the start of the text.
+        assert_eq!(add(&mut joiner, "again"), (0, " again".to_string()));
+        assert_eq!(add(&mut joiner, "\u{e009}\u{1}"), (0, "".to_string()));
+        let mut joiner = Joiner::new();
+        assert_eq!(add(&mut joiner, "hello"), (0, "Hello".to_string()));
+        assert_eq!(add(&mut joiner, "\u{e009}\u{1}"), (0, "".to_string()));
+    }
+}
@@ -0,0 +1,440 @@
+//! Plover JSON dictionaries.
+//!
+//! A small user dictionary can be sent to the keyboard as the Plover JSON file itself, and parsed
+//! here into a [`RamDict`], rather than being converted with bbq-tool on the host first.  Only the
+//! subset of JSON that Plover writes is accepted: a single object mapping stroke strings to
+//! definition strings.
+//!
+//! The definitions are translated into the [`Replacement`] encoding by [`translate`], which bbq-tool
+//! also uses when building dictionaries, so a dictionary loaded either way behaves the same.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::replacements::Previous;
+use crate::stroke;
+use crate::{Replacement, Stroke};
+
+use super::{MapDictBuilder, RamDict};
+
+/// Errors from parsing a dictionary.  The offsets are bytes into the source.
+#[derive(Debug)]
+pub enum Error {
+    /// The source is not valid UTF-8.
+    Utf8(usize),
+    /// Malformed JSON, or JSON that isn't an object of strings.
+    Syntax(usize),
+    /// The key at this offset is not a valid steno word.
+    Stroke(usize, stroke::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Utf8(pos) => write!(f, "invalid UTF-8 at {}", pos),
+            Error::Syntax(pos) => write!(f, "JSON syntax error at {}", pos),
+            Error::Stroke(pos, err) => write!(f, "invalid stroke at {}: {:?}", pos, err),
+        }
+    }
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+/// Parse a Plover JSON dictionary.  When a key is repeated, the last definition wins, the same as
+/// Plover.
+pub fn parse(data: &[u8]) -> Result<RamDict> {
+    let text = core::str::from_utf8(data).map_err(|e| Error::Utf8(e.valid_up_to()))?;
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let base = data.len() - text.len();
+    let mut parser = Parser { text, pos: 0 };
+
+    let mut dict = MapDictBuilder::new();
+
+    parser.expect('{')?;
+    if !parser.eat('}') {
+        loop {
+            parser.skip_ws();
+            let key_pos = parser.pos;
+            let key = parser.string()?;
+            parser.expect(':')?;
+            let value = parser.string()?;
+
+            let key = parse_word(&key).map_err(|e| Error::Stroke(base + key_pos, e))?;
+            dict.insert(key, translate(&value));
+
+            if parser.eat('}') {
+                break;
+            }
+            parser.expect(',')?;
+        }
+    }
+    parser.skip_ws();
+    if parser.pos != text.len() {
+        return Err(Error::Syntax(base + parser.pos));
+    }
+
+    Ok(dict.into_ram_dict())
+}
+
+/// Parse a slash separated group of strokes.
+fn parse_word(text: &str) -> core::result::Result<Vec<Stroke>, stroke::Error> {
+    text.split('/').map(Stroke::from_text).collect()
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.pos += ch.len_utf8();
+        Some(ch)
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `ch`, after any whitespace, if it is next.
+    fn eat(&mut self, ch: char) -> bool {
+        self.skip_ws();
+        if self.peek() == Some(ch) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, ch: char) -> Result<()> {
+        if self.eat(ch) {
+            Ok(())
+        } else {
+            Err(Error::Syntax(self.pos))
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        loop {
+            let pos = self.pos;
+            match self.next() {
+                None => return Err(Error::Syntax(pos)),
+                Some('"') => return Ok(result),
+                Some('\\') => {
+                    let ch = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{0008}',
+                        Some('f') => '\u{000c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.unicode(pos)?,
+                        _ => return Err(Error::Syntax(pos)),
+                    };
+                    result.push(ch);
+                }
+                Some(ch) if (ch as u32) < 0x20 => return Err(Error::Syntax(pos)),
+                Some(ch) => result.push(ch),
+            }
+        }
+    }
+
+    /// Decode the rest of a `\u` escape, including a following low surrogate.
+    fn unicode(&mut self, pos: usize) -> Result<char> {
+        let high = self.hex4().ok_or(Error::Syntax(pos))?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.text[self.pos..].starts_with("\\u") {
+                return Err(Error::Syntax(pos));
+            }
+            self.pos += 2;
+            let low = self.hex4().ok_or(Error::Syntax(pos))?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(Error::Syntax(pos));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or(Error::Syntax(pos))
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = self.text.get(self.pos..self.pos + 4)?;
+        let value = u32::from_str_radix(digits, 16).ok()?;
+        // from_str_radix allows a leading sign.
+        if digits.starts_with('+') {
+            return None;
+        }
+        self.pos += 4;
+        Some(value)
+    }
+}
+
+/// Translate a Plover definition into the replacement encoding.
+pub fn translate(text: &str) -> String {
+    let mut work = Vec::new();
+
+    let mut rest = text;
+    while !rest.is_empty() {
+        if rest.starts_with('{') {
+            match control_len(rest) {
+                Some(len) => {
+                    control(&mut work, &rest[..len]);
+                    rest = &rest[len..];
+                }
+                None => {
+                    // Unterminated, just type it.
+                    work.push(Replacement::Text(rest.to_string()));
+                    break;
+                }
+            }
+        } else {
+            // Literal text runs up to the next unescaped brace.
+            let mut piece = String::new();
+            let mut chars = rest.char_indices().peekable();
+            let mut len = rest.len();
+            while let Some((i, ch)) = chars.next() {
+                if ch == '{' {
+                    len = i;
+                    break;
+                }
+                if ch == '\\' && matches!(chars.peek(), Some((_, '{'))) {
+                    chars.next();
+                    piece.push('{');
+                } else {
+                    piece.push(ch);
+                }
+            }
+            work.push(Replacement::Text(piece));
+            rest = &rest[len..];
+        }
+    }
+
+    Replacement::encode(&work)
+}
+
+/// The length of the brace group at the start of `text`, or None if it isn't closed.
+fn control_len(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '{' => return None,
+            '}' => return Some(i + 1),
+            _ => (),
+        }
+    }
+    None
+}
+
+fn control(work: &mut Vec<Replacement>, text: &str) {
+    match text {
+        "{*}" => work.push(Replacement::RetroBreak),
+        "{^ ^}" | "{^}" => work.push(Replacement::DeleteSpace),
+        "{*-|}" => work.push(Replacement::Previous(1, Previous::Capitalize)),
+        "{*>}" => work.push(Replacement::Previous(1, Previous::Lowerize)),
+        "{*<}" => work.push(Replacement::Previous(1, Previous::Upcase)),
+        "{*!}" => work.push(Replacement::Previous(1, Previous::DeleteSpace)),
+        "{*?}" => work.push(Replacement::Previous(1, Previous::AddSpace)),
+        "{>}" => work.push(Replacement::NoCapNext),
+        "{-|}" => work.push(Replacement::CapNext),
+        "{?}" => {
+            work.push(Replacement::DeleteSpace);
+            work.push(Replacement::Text("?".to_string()));
+            work.push(Replacement::CapNext);
+        }
+        _ => {
+            let body = &text[1..text.len() - 1];
+            if let Some(stitch) = body.strip_prefix('&') {
+                work.push(Replacement::Stitch);
+                work.push(Replacement::Text(stitch.to_string()));
+            } else if let Some((command, arg)) = split_command(body) {
+                command_control(work, command, arg);
+            } else if let Some(raw) = body.strip_prefix('#') {
+                work.push(Replacement::Raw(raw.to_string()));
+            } else if !unspaced(work, body) {
+                work.push(Replacement::Text(text.to_string()));
+            }
+        }
+    }
+}
+
+/// Split `{name:arg}` or `{:name:arg}` style commands.
+fn split_command(body: &str) -> Option<(&str, &str)> {
+    let (command, arg) = body.split_once(':').and_then(|(a, b)| {
+        if a.is_empty() {
+            // The leading colon is part of the name.
+            let (name, arg) = b.split_once(':')?;
+            Some((&body[..name.len() + 1], arg))
+        } else {
+            Some((a, b))
+        }
+    })?;
+    let name = command.strip_prefix(':').unwrap_or(command);
+    if !name.is_empty() && name.chars().all(|ch| ch.is_ascii_alphabetic() || ch == '_') {
+        Some((command, arg))
+    } else {
+        None
+    }
+}
+
+fn command_control(work: &mut Vec<Replacement>, command: &str, arg: &str) {
+    let count = || arg.parse::<u32>().ok();
+    match command {
+        ":retro_title" if count().is_some() => {
+            work.push(Replacement::Previous(count().unwrap(), Previous::Capitalize));
+        }
+        ":retro_lower" if count().is_some() => {
+            work.push(Replacement::Previous(count().unwrap(), Previous::Lowerize));
+        }
+        ":retro_upper" if count().is_some() => {
+            work.push(Replacement::Previous(count().unwrap(), Previous::Upcase));
+        }
+        ":retro_replace_space" => {
+            if let Some((count, text)) = arg.split_once(':') {
+                if let Ok(count) = count.parse() {
+                    let ch = text.chars().next().unwrap_or('\u{0000}');
+                    work.push(Replacement::Previous(count, Previous::ReplaceSpace(ch)));
+                }
+            }
+        }
+        ":number_format_insert" => {
+            work.push(Replacement::Previous(1, Previous::Number(arg.to_string())));
+        }
+        ":number_format_roman" => {
+            work.push(Replacement::Text("<TODO:ROMAN>".to_string()));
+        }
+        ":retro_insert_currency" => {
+            work.push(Replacement::Previous(1, Previous::Currency(arg.to_string())));
+        }
+
+        // These are carried out by the keyboard, not typed.
+        "PLOVER" | "MODE" => {
+            work.push(Replacement::Command(format!("{}:{}", command, arg)));
+        }
+        command => {
+            work.push(Replacement::Text(format!("#<{}:{}>", command, arg)));
+        }
+    }
+}
+
+/// Text with optional attach markers: `{^text^}`, and the carry capitalization `{~|text}`.
+/// Returns false, adding nothing, if the body is something else.
+fn unspaced(work: &mut Vec<Replacement>, body: &str) -> bool {
+    let (before, body) = match body.strip_prefix('^') {
+        Some(body) => (true, body),
+        None => (false, body),
+    };
+    let body = body.strip_prefix("~|").unwrap_or(body);
+    let (body, after) = match body.strip_suffix('^') {
+        Some(body) => (body, true),
+        None => (body, false),
+    };
+    if body.contains('^') {
+        return false;
+    }
+
+    if before {
+        work.push(Replacement::DeleteSpace);
+    }
+    work.push(Replacement::Text(body.to_string()));
+    if after {
+        work.push(Replacement::DeleteSpace);
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dict::DictImpl;
+
+    #[test]
+    fn test_parse() {
+        let dict = parse(r#"
+            {
+                "KAT": "cat",
+                "KAT/-S": "cats",
+                "TKOG": "dog",
+                "-G": "{^ing}",
+                "KP-PL": "{-|}",
+                "KW-GS": "\"été\\",
+                "KAT": "kitty"
+            }
+        "#.as_bytes()).unwrap();
+
+        assert_eq!(dict.len(), 6);
+        let lookup = |key: &str| {
+            let key = parse_word(key).unwrap();
+            (0..dict.len())
+                .find(|&i| dict.key(i) == key.as_slice())
+                .map(|i| dict.value(i).to_string())
+        };
+        assert_eq!(lookup("KAT").as_deref(), Some("kitty"));
+        assert_eq!(lookup("KAT/-S").as_deref(), Some("cats"));
+        assert_eq!(lookup("-G").as_deref(), Some("\x01ing"));
+        assert_eq!(lookup("KP-PL").as_deref(), Some("\x02"));
+        assert_eq!(lookup("KW-GS").as_deref(), Some("\"été\\"));
+        assert_eq!(lookup("TKOGS"), None);
+
+        assert_eq!(parse(b" {} ").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(parse(br#"{"KAT": "cat",}"#), Err(Error::Syntax(14))));
+        assert!(matches!(parse(br#"{"KAT": 5}"#), Err(Error::Syntax(8))));
+        assert!(matches!(parse(br#"{"KAT": "cat"} x"#), Err(Error::Syntax(15))));
+        assert!(matches!(parse(br#"{"KAT": "cat"#), Err(Error::Syntax(_))));
+        assert!(matches!(parse(br#"{"KAT": "\x"}"#), Err(Error::Syntax(9))));
+        assert!(matches!(parse(br#"{"KAT": "cat", "K-X": "x"}"#), Err(Error::Stroke(15, _))));
+        assert!(matches!(parse(b"{\"KAT\": \"\xff\"}"), Err(Error::Utf8(9))));
+    }
+
+    #[test]
+    fn test_translate() {
+        let check = |text: &str, expect: &[Replacement]| {
+            assert_eq!(translate(text), Replacement::encode(expect), "{:?}", text);
+        };
+
+        check("plain text", &[Replacement::Text("plain text".to_string())]);
+        check("{^}", &[Replacement::DeleteSpace]);
+        check("{*}", &[Replacement::RetroBreak]);
+        check("{^ing}", &[Replacement::DeleteSpace, Replacement::Text("ing".to_string())]);
+        check("{pre^}", &[Replacement::Text("pre".to_string()), Replacement::DeleteSpace]);
+        check("{~|'^}", &[Replacement::Text("'".to_string()), Replacement::DeleteSpace]);
+        check("{.}{-|}", &[Replacement::Text(".".to_string()), Replacement::CapNext]);
+        check("{&a}", &[Replacement::Stitch, Replacement::Text("a".to_string())]);
+        check("{#Control_L(z)}", &[Replacement::Raw("Control_L(z)".to_string())]);
+        check("{:retro_title:2}", &[Replacement::Previous(2, Previous::Capitalize)]);
+        check("{*-|}", &[Replacement::Previous(1, Previous::Capitalize)]);
+        check("{*!}", &[Replacement::Previous(1, Previous::DeleteSpace)]);
+        check("{*?}", &[Replacement::Previous(1, Previous::AddSpace)]);
+        check("{PLOVER:TOGGLE}", &[Replacement::Command("PLOVER:TOGGLE".to_string())]);
+        check("a \\{b", &[Replacement::Text("a {b".to_string())]);
+        check("{^}dot{^}", &[
+            Replacement::DeleteSpace,
+            Replacement::Text("dot".to_string()),
+            Replacement::DeleteSpace,
+        ]);
+        check("{open", &[Replacement::Text("{open".to_string())]);
+    }
+}
@@ -10,33 +10,75 @@
 //!
 //! In addition to an action, there is also an Undo, which repeals the previous action.  Other
 //! layers will need to potentially replay what was replaced by the deleted action.  We maintain a
-//! limited amount of undo history due to memory constraints.
+//! limited amount of undo history due to memory constraints: the last [`UNDO_DEPTH`] strokes can
+//! be undone, one after another.  Undoing a stroke goes back to the lookup state from before it,
+//! so the next stroke can extend the translations it could have before, and the most recent
+//! translation is the one before it again.
 //!
 //! The only thing this layer knows about the translations is the concept of an undo barrier. This
 //! mostly comes from translations that indicate direct keypresses, and when these are sent, it is
 //! not meaningful to undo.  Lookup will simple discard the undo history when these are encountered.
+//!
+//! A stroke with no translation is given as its raw steno, except for number strokes, as Plover
+//! does them: the number bar with only digit keys writes those digits, glued as if the dictionary
+//! had `{&...}` for it, so numbers written a stroke at a time join together.
+//!
+//! To reduce the latency once a stroke is complete, the keyboard can call [`Lookup::prepare`] with
+//! the keys pressed so far, as they go down.  This does the dictionary searching for that stroke
+//! ahead of time, and if the completed stroke matches, `add` just uses the result.
+//!
+//! Most of the time of a lookup goes to searching the whole of each dictionary for the stroke as
+//! the start of a new translation, as the other searches only cover the entries that continue an
+//! earlier stroke.  The results of the last [`CACHE_LEN`] of these searches are kept, so common
+//! briefs, and strokes written again after an undo, don't need searching again.
+//!
+//! After a translation that took several strokes, [`Lookup::shorter`] can search the dictionaries
+//! for a shorter outline with the same definition, to help discover briefs.  The reverse lookup,
+//! [`Lookup::outlines`], finds every outline for a definition.
+//!
+//! How eagerly translations are given is set by the [`Strategy`].  The default,
+//! [`Strategy::Eager`], gives the best translation for every stroke right away, and replaces it
+//! when a later stroke makes a longer match.  With [`Strategy::Longest`], a translation that a
+//! later stroke could still extend is held back.  The stroke gives an empty placeholder, and the
+//! translation is given once a stroke doesn't extend it, or when [`Lookup::flush`] is called,
+//! which the keyboard does after a timeout.  This trades a little latency for not typing and then
+//! deleting the start of multi-stroke words.
 
 extern crate alloc;
 
 use heapless::Deque;
 
 use alloc::boxed::Box;
-use alloc::string::ToString;
+use alloc::string::{String, ToString};
 use alloc::vec;
 use alloc::vec::Vec;
-use crate::{Replacement, Stroke};
+use crate::{Affix, Replacement, Stroke};
 
 use super::{Dict, Selector};
 
 use alloc::format;
 
-/// The maximum history length.  This should be the sum of the desired history and the longest
-/// stroke we will process.  Realistically, undo is not typically done more than a dozen.
-const HISTORY_LEN: usize = 32;
+/// The most strokes that can be undone, one after another.  Realistically, undo is not typically
+/// done more than a dozen times.  This can be set when building, with the `BBQ_UNDO_DEPTH`
+/// environment variable, and is 32 otherwise.  Each level keeps the lookup state, and the text
+/// typed, of a stroke, so a deeper history costs memory.
+pub const UNDO_DEPTH: usize = match option_env!("BBQ_UNDO_DEPTH") {
+    Some(depth) => parse_depth(depth),
+    None => 32,
+};
+
+/// The maximum history length, the undo depth along with the starting point.
+const HISTORY_LEN: usize = UNDO_DEPTH + 1;
 
 /// A Deque that can hold `HISTORY_LEN` entries.
 type HistoryDeque<T> = Deque<T, HISTORY_LEN>;
 
+/// How many strokes' searches for a new translation are kept.
+const CACHE_LEN: usize = 16;
+
+/// What each dictionary found for a stroke, in the order of the dictionaries.
+type Found = Vec<Option<(Box<dyn Selector>, Option<String>)>>;
+
 /// Track dictionary lookups maintaining undo history.
 pub struct Lookup {
     /// The dictionaries to use for the lookups.
@@ -45,6 +87,49 @@ pub struct Lookup {
     /// The nodes at each state.  These correspond 1:1 with the input strokes.  New values go to
     /// "back", and are removed from the front as history expires.
     history: HistoryDeque<Entry>,
+
+    /// A lookup done ahead of time by `prepare`.
+    prepared: Option<Step>,
+
+    /// The definition, as it is in the dictionary, and the number of strokes, of the most recent
+    /// translation.
+    last: Option<(String, usize)>,
+
+    /// When translations are given.
+    strategy: Strategy,
+
+    /// Translations held back by [`Strategy::Longest`].  The last is the one waiting to be given,
+    /// the others are the ones it extended, kept so an undo can go back to them.
+    held: Vec<Action>,
+
+    /// Recent searches for a stroke starting a new translation, the most recently used first.
+    cache: Vec<(Stroke, Found)>,
+}
+
+/// When to give a translation that a later stroke could still extend.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Strategy {
+    /// Give the best translation of every stroke right away, replacing it if a later stroke
+    /// extends it.  Translations are strictly in the order of the strokes.
+    #[default]
+    Eager,
+    /// Hold back a translation that could be extended, until a stroke doesn't extend it, or the
+    /// lookup is flushed.
+    Longest,
+}
+
+/// The result of looking up a single stroke, before it is added to the history.
+struct Step {
+    /// The stroke looked up.
+    stroke: Stroke,
+    /// The new selectors.
+    nodes: Vec<Box<dyn Selector>>,
+    /// The best translation, and how many strokes it covers.
+    best: Option<(String, usize)>,
+    /// The affix class of the best translation, if its dictionary gave one.
+    affix: Option<Affix>,
+    /// Whether more strokes could still give a longer translation than the best one.
+    open: bool,
 }
 
 /// At a given state, these are the possible places we can go.
@@ -52,12 +137,15 @@ pub struct Lookup {
 struct Entry {
     /// NFA states at this point.
     nodes: Vec<Box<dyn Selector>>,
+    /// The most recent translation at this point, restored when undoing back to it.
+    last: Option<(String, usize)>,
 }
 
 impl Entry {
     fn new() -> Entry {
         Entry {
             nodes: Vec::new(),
+            last: None,
         }
     }
 }
@@ -74,11 +162,34 @@ pub enum Action {
         text: Vec<Replacement>,
         /// The number of strokes consumed by this insertion.
         strokes: usize,
+        /// How the translation attaches to the words around it.
+        affix: Affix,
     },
-    /// The undo key was pressed.
+    /// The undo key was pressed.  This is also given, without a stroke being undone, to remove
+    /// the placeholder of a held translation before the translation is given.
     Undo,
 }
 
+impl Action {
+    /// The empty translation given in place of a held one.  It covers the same strokes, so what
+    /// it replaces is counted the same way.
+    fn placeholder(&self) -> Action {
+        let strokes = match self {
+            Action::Add { strokes, .. } => *strokes,
+            Action::Undo => 1,
+        };
+        Action::Add { text: vec![], strokes, affix: Affix::None }
+    }
+
+    /// The number of strokes this translation covers.
+    fn strokes(&self) -> usize {
+        match self {
+            Action::Add { strokes, .. } => *strokes,
+            Action::Undo => 0,
+        }
+    }
+}
+
 impl Lookup {
     pub fn new(dicts: Vec<Dict>) -> Self {
         let mut history = HistoryDeque::new();
@@ -87,50 +198,156 @@ impl Lookup {
         Lookup {
             dicts,
             history,
+            prepared: None,
+            last: None,
+            strategy: Strategy::default(),
+            held: Vec::new(),
+            cache: Vec::new(),
         }
     }
 
-    /// Add a new stroke to the Translator.  Updates the internal state.
-    pub fn add(&mut self, stroke: Stroke) -> Action {
+    /// Change when translations are given.  Anything already held is still given by the next
+    /// stroke, or by [`Lookup::flush`].
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    /// When translations are given.
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Is a translation being held back, waiting for more strokes?
+    pub fn is_holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// Add a new stroke to the Translator.  Updates the internal state.  Returns the actions, in
+    /// order.  With [`Strategy::Eager`] this is always a single action, but giving a held
+    /// translation can add more.
+    pub fn add(&mut self, stroke: Stroke) -> Vec<Action> {
+        // Anything prepared is only valid for this stroke.
+        let prepared = self.prepared.take();
         if stroke.is_star() {
-            self.undo()
+            self.held.pop();
+            return vec![self.undo()];
+        }
+
+        let step = match prepared {
+            Some(step) if step.stroke == stroke => step,
+            _ => self.step(stroke),
+        };
+        let open = step.open;
+        let action = self.add_step(step);
+
+        let mut result = Vec::new();
+        // A translation covering more than this stroke extends what is held, and replaces it.
+        // Otherwise, the held translation is given first, in place of its placeholder.
+        if action.strokes() == 1 {
+            result.extend(self.flush());
+        }
+
+        // A translation giving key actions resets the history, so can't be extended.
+        if self.strategy == Strategy::Longest && open && self.history.len() > 1 {
+            result.push(action.placeholder());
+            self.held.push(action);
         } else {
-            self.add_stroke(stroke)
+            self.held.clear();
+            result.push(action);
         }
+        result
     }
 
-    fn add_stroke(&mut self, stroke: Stroke) -> Action {
+    /// Give the translation being held, if there is one.  The placeholder is undone, and the
+    /// translation added in its place.  Later strokes can still extend it, the same as with
+    /// [`Strategy::Eager`].
+    pub fn flush(&mut self) -> Vec<Action> {
+        match self.held.pop() {
+            Some(action) => {
+                self.held.clear();
+                vec![Action::Undo, action]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Speculatively look up a stroke that is still being written.  This can be called each time
+    /// a key goes down, and replaces any previously prepared stroke.
+    pub fn prepare(&mut self, stroke: Stroke) {
+        if stroke.is_empty() || stroke.is_star() {
+            self.prepared = None;
+        } else if self.prepared.as_ref().map(|p| p.stroke) != Some(stroke) {
+            self.prepared = Some(self.step(stroke));
+        }
+    }
+
+    /// Look up a stroke against the current history, without changing it.
+    fn step(&mut self, stroke: Stroke) -> Step {
+        let fresh = self.fresh(stroke);
+
         // The history should never be empty.
         let last = self.history.back().unwrap();
 
         let mut nodes = vec![];
         let mut best_len = 0;
         let mut best_text = None;
+        let mut best_affix = None;
+        let mut counts = vec![];
 
-        // Iterate over all current nodes, along with an additional episilon node for each
-        // dictionary.
-        let fresh: Vec<_> = self.dicts.iter().map(|d| d.clone().selector()).collect();
-        for entry in last.nodes.iter().chain(fresh.iter()) {
-            if let Some((sel, text)) = entry.lookup_step(stroke) {
-                // Dictionaries are in priority.  Any new entries override those of the same length.
-                if let Some(text) = text {
-                    if sel.count() >= best_len {
-                        best_len = sel.count();
-                        best_text = Some(text);
-                    }
-                }
+        // Iterate over all current nodes, along with the search from an additional episilon node
+        // for each dictionary.
+        let steps = last.nodes.iter().map(|entry| entry.lookup_step(stroke));
+        for (sel, text) in steps.chain(fresh).flatten() {
+            // A selector that found its only entry is finished.  Any other could go on to a
+            // longer translation.
+            let finished = sel.unique() && text.is_some();
 
-                nodes.push(sel);
+            // Dictionaries are in priority.  Any new entries override those of the same length.
+            if let Some(text) = text {
+                if sel.count() >= best_len {
+                    best_len = sel.count();
+                    best_text = Some(text);
+                    best_affix = sel.affix();
+                }
             }
+
+            counts.push((sel.count(), !finished));
+            nodes.push(sel);
         }
 
-        // If we got a translation, use it.  Otherwise fake a single stroke definition that is just
-        // the raw steno of this stroke.
-        let (best, best_len) = if let Some(best) = best_text {
-            (best, best_len)
-        } else {
-            (stroke.to_string(), 1) 
+        Step {
+            stroke,
+            nodes,
+            best: best_text.map(|text| (text, best_len)),
+            affix: best_affix,
+            open: counts.iter().any(|&(count, open)| open && count >= best_len.max(1)),
+        }
+    }
+
+    /// Search each dictionary for the stroke as the start of a new translation, using the cache
+    /// when the stroke was searched for recently.
+    fn fresh(&mut self, stroke: Stroke) -> Found {
+        let found = match self.cache.iter().position(|(s, _)| *s == stroke) {
+            Some(pos) => self.cache.remove(pos).1,
+            None => self.dicts.iter().map(|d| d.clone().selector().lookup_step(stroke)).collect(),
         };
+        let result = found
+            .iter()
+            .map(|f| f.as_ref().map(|(sel, text)| (sel.clone_box(), text.clone())))
+            .collect();
+        self.cache.insert(0, (stroke, found));
+        self.cache.truncate(CACHE_LEN);
+        result
+    }
+
+    fn add_step(&mut self, step: Step) -> Action {
+        let Step { stroke, nodes, best, affix, .. } = step;
+
+        // If we got a translation, use it.  Otherwise fake a single stroke definition that is just
+        // the raw steno of this stroke, or the digits of a number stroke.
+        self.last = best.clone();
+        let (best, best_len) = best.unwrap_or_else(|| (untranslated(stroke), 1));
+        let affix = affix.unwrap_or_else(|| Affix::of(&best));
 
         // When we have a match, we will never go back to previous matches that were shorter.  Think
         // of this:
@@ -147,7 +364,7 @@ impl Lookup {
         }
 
         // Add a new node to the history.
-        self.history.push_back(Entry { nodes }).unwrap();
+        self.history.push_back(Entry { nodes, last: self.last.clone() }).unwrap();
 
         let xlat = Replacement::decode(&best).unwrap_or_else(|| {
             // Insert a very obvious translation to let the user know there is a bad entry in their
@@ -172,20 +389,69 @@ impl Lookup {
         Action::Add {
             text: xlat,
             strokes: best_len,
+            affix,
         }
     }
 
+    /// Search for a shorter outline for the most recent translation.  Returns the shortest one
+    /// found, if it has fewer strokes than were used.
+    ///
+    /// This scans every entry in every dictionary, so should only be done when asked for.
+    pub fn shorter(&self) -> Option<Vec<Stroke>> {
+        let (text, strokes) = self.last.as_ref()?;
+        let mut best: Option<&[Stroke]> = None;
+
+        for dict in &self.dicts {
+            for i in 0..dict.len() {
+                let key = dict.key(i);
+                let limit = best.map(|b| b.len()).unwrap_or(*strokes);
+                if key.len() < limit && dict.value(i) == text {
+                    best = Some(key);
+                }
+            }
+        }
+        best.map(|key| key.to_vec())
+    }
+
+    /// Find every outline with the given definition, fewest strokes first.  Outlines with the
+    /// same number of strokes are in dictionary order, and one found in more than one dictionary
+    /// is only given once.
+    ///
+    /// As with [`Lookup::shorter`], this scans every entry in every dictionary.
+    pub fn outlines(&self, definition: &str) -> Vec<Vec<Stroke>> {
+        let mut result: Vec<Vec<Stroke>> = Vec::new();
+        for dict in &self.dicts {
+            for i in 0..dict.len() {
+                let key = dict.key(i);
+                if dict.value(i) == definition && !result.iter().any(|r| r == key) {
+                    result.push(key.to_vec());
+                }
+            }
+        }
+        // The sort is stable, keeping dictionary order within each length.
+        result.sort_by_key(|outline| outline.len());
+        result
+    }
+
+    /// The definition of the most recent translation, if there is one.
+    pub fn last_definition(&self) -> Option<&str> {
+        self.last.as_ref().map(|(text, _)| text.as_str())
+    }
+
     fn undo(&mut self) -> Action {
         // Be sure to not remove the first entry, as we need at least one starting point. This might
         // be potentially confusing, though.
         if self.history.len() > 1 {
             let _ = self.history.pop_back();
+            self.last = self.history.back().and_then(|entry| entry.last.clone());
             Action::Undo
         } else {
             // If there is no undo available, don't do anything.
+            self.last = None;
             Action::Add {
                 text: vec![],
                 strokes: 1,
+                affix: Affix::None,
             }
         }
     }
@@ -206,3 +472,223 @@ impl Lookup {
         }
     }
 }
+
+/// Parse the undo depth given when building.  An invalid depth stops the build.
+const fn parse_depth(text: &str) -> usize {
+    let bytes = text.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "BBQ_UNDO_DEPTH must be a number");
+        depth = depth * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    assert!(depth > 0, "BBQ_UNDO_DEPTH must be at least 1");
+    depth
+}
+
+/// The definition given to a stroke that isn't in any dictionary.  Number strokes are glued
+/// digits, anything else is the stroke itself.
+fn untranslated(stroke: Stroke) -> String {
+    match stroke.digits() {
+        Some(digits) => Replacement::encode(&[Replacement::Stitch, Replacement::Text(digits)]),
+        None => stroke.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::rc::Rc;
+
+    use super::*;
+    use crate::dict::MapDictBuilder;
+
+    fn word(text: &str) -> Vec<Stroke> {
+        text.split('/').map(|s| Stroke::from_text(s).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_shorter() {
+        let mut build = MapDictBuilder::new();
+        build.insert(word("KAT"), "cat".to_string());
+        build.insert(word("KAT/-S"), "cats".to_string());
+        build.insert(word("KAT/S-Z"), "cats".to_string());
+        build.insert(word("KATS"), "cats".to_string());
+        build.insert(word("TKOG/-S"), "dogs".to_string());
+        let dict: Dict = Rc::new(build.into_ram_dict());
+        let mut lookup = Lookup::new(vec![dict]);
+
+        // Nothing yet.
+        assert_eq!(lookup.shorter(), None);
+
+        lookup.add(word("KAT")[0]);
+        assert_eq!(lookup.shorter(), None);
+        lookup.add(word("-S")[0]);
+        assert_eq!(lookup.shorter(), Some(word("KATS")));
+
+        // No brief for this one.
+        lookup.add(word("TKOG")[0]);
+        lookup.add(word("-S")[0]);
+        assert_eq!(lookup.shorter(), None);
+
+        lookup.add(Stroke::from_text("*").unwrap());
+        assert_eq!(lookup.shorter(), None);
+    }
+
+    #[test]
+    fn test_outlines() {
+        let mut build = MapDictBuilder::new();
+        build.insert(word("KAT/-S"), "cats".to_string());
+        build.insert(word("KATS"), "cats".to_string());
+        build.insert(word("KAT/S-Z"), "cats".to_string());
+        build.insert(word("KAT"), "cat".to_string());
+        let dict: Dict = Rc::new(build.into_ram_dict());
+        let mut build = MapDictBuilder::new();
+        build.insert(word("KATS"), "cats".to_string());
+        build.insert(word("KA*TS"), "cats".to_string());
+        let user: Dict = Rc::new(build.into_ram_dict());
+        let mut lookup = Lookup::new(vec![user, dict]);
+
+        let outlines = lookup.outlines("cats");
+        assert_eq!(outlines.len(), 4);
+        assert!(outlines[..2].iter().all(|o| o.len() == 1));
+        assert!(outlines[2..].iter().all(|o| o.len() == 2));
+        assert!(outlines.contains(&word("KA*TS")));
+        assert!(lookup.outlines("dogs").is_empty());
+
+        assert_eq!(lookup.last_definition(), None);
+        lookup.add(word("KAT")[0]);
+        lookup.add(word("-S")[0]);
+        assert_eq!(lookup.last_definition(), Some("cats"));
+        // Undo goes back to the translation before.
+        lookup.add(Stroke::from_text("*").unwrap());
+        assert_eq!(lookup.last_definition(), Some("cat"));
+        lookup.add(Stroke::from_text("*").unwrap());
+        assert_eq!(lookup.last_definition(), None);
+    }
+
+    /// Type the strokes, with the strategy, returning what is typed, and how many characters
+    /// were deleted along the way.
+    fn type_with(strategy: Strategy, strokes: &[&str], flush: bool) -> (String, usize) {
+        use crate::dict::joiner::{Joined, Joiner};
+
+        let mut build = MapDictBuilder::new();
+        build.insert(word("KAT"), "cat".to_string());
+        build.insert(word("KAT/HROG"), "catalog".to_string());
+        build.insert(word("KAT/HROG/-S"), "catalogs".to_string());
+        build.insert(word("TPHU"), "new".to_string());
+        build.insert(word("TPHU/KHRAOER"), "nuclear".to_string());
+        build.insert(word("TKOG"), "dog".to_string());
+        // Fingerspelled "{&a}".
+        build.insert(word("A*"), "\u{3}a".to_string());
+        let dict: Dict = Rc::new(build.into_ram_dict());
+        let mut lookup = Lookup::new(vec![dict]);
+        lookup.set_strategy(strategy);
+
+        let mut joiner = Joiner::new();
+        let mut actions = Vec::new();
+        for stroke in strokes {
+            actions.extend(lookup.add(Stroke::from_text(stroke).unwrap()));
+        }
+        if flush {
+            actions.extend(lookup.flush());
+        }
+        for action in actions {
+            joiner.add(action);
+        }
+
+        let mut typed = String::new();
+        let mut deleted = 0;
+        while let Some(action) = joiner.pop(0) {
+            match action {
+                Joined::Type { remove, append } => {
+                    for _ in 0..remove {
+                        typed.pop();
+                    }
+                    deleted += remove;
+                    typed.push_str(&append);
+                }
+                Joined::Raw(keys) => panic!("Unexpected raw keys: {}", keys),
+                Joined::Command(command) => panic!("Unexpected command: {:?}", command),
+            }
+        }
+        (typed, deleted)
+    }
+
+    #[test]
+    fn test_strategy() {
+        let strokes = ["KAT", "TKOG", "KAT", "HROG", "-S", "TPHU", "KHRAOER", "KAT"];
+
+        // Both type the same thing, but eager has to go back to fix up the extended words.
+        let text = "Cat dog catalogs nuclear cat".to_string();
+        assert_eq!(type_with(Strategy::Eager, &strokes, false), (text.clone(), 2));
+        assert_eq!(type_with(Strategy::Longest, &strokes, true), (text, 0));
+
+        // The last word is held until flushed.
+        assert_eq!(type_with(Strategy::Longest, &strokes, false), ("Cat dog catalogs nuclear".to_string(), 0));
+        // A word that can't be extended isn't held.
+        assert_eq!(type_with(Strategy::Longest, &["KAT", "TKOG"], false), ("Cat dog".to_string(), 0));
+
+        // Undo goes back to what was held before.
+        assert_eq!(type_with(Strategy::Longest, &["KAT", "HROG", "*"], true), ("Cat".to_string(), 0));
+        assert_eq!(type_with(Strategy::Longest, &["KAT", "HROG", "*", "*"], true), ("".to_string(), 0));
+        assert_eq!(type_with(Strategy::Longest, &["KAT", "HROG", "*", "TKOG"], false),
+                   ("Cat dog".to_string(), 0));
+    }
+
+    #[test]
+    fn test_undo() {
+        // Each stroke is undone in turn, including those within a longer translation.
+        let strokes = ["KAT", "HROG", "-S", "TPHU", "KHRAOER", "*", "*", "*"];
+        assert_eq!(type_with(Strategy::Eager, &strokes, false).0, "Catalog");
+
+        // After undoing, a stroke can extend what it could before.
+        let strokes = ["KAT", "HROG", "TKOG", "*", "-S"];
+        assert_eq!(type_with(Strategy::Eager, &strokes, false).0, "Catalogs");
+
+        // Only the last UNDO_DEPTH strokes can be undone, further undo does nothing.
+        let mut strokes = vec!["TKOG"; UNDO_DEPTH + 2];
+        strokes.extend(vec!["*"; UNDO_DEPTH + 4]);
+        assert_eq!(type_with(Strategy::Eager, &strokes, false).0, "Dog dog");
+    }
+
+    #[test]
+    fn test_cache() {
+        // A stroke written again, after an undo, gives the same translations.
+        let strokes = ["KAT", "*", "KAT", "HROG", "*", "*", "KAT", "HROG", "-S"];
+        assert_eq!(type_with(Strategy::Eager, &strokes, false).0, "Catalogs");
+
+        let mut build = MapDictBuilder::new();
+        build.insert(word("KAT"), "cat".to_string());
+        let dict: Dict = Rc::new(build.into_ram_dict());
+        let mut lookup = Lookup::new(vec![dict]);
+
+        // Only the most recent strokes are kept, and using one makes it the most recent again.
+        let strokes: Vec<_> = (1..=CACHE_LEN as u32 + 1).map(Stroke::from_raw).collect();
+        for &stroke in &strokes {
+            lookup.add(stroke);
+        }
+        assert_eq!(lookup.cache.len(), CACHE_LEN);
+        assert!(!lookup.cache.iter().any(|(s, _)| *s == strokes[0]));
+        lookup.add(strokes[1]);
+        assert_eq!(lookup.cache[0].0, strokes[1]);
+        assert_eq!(lookup.cache.len(), CACHE_LEN);
+
+        // Cached searches still find the translation.
+        let kat = word("KAT")[0];
+        lookup.add(kat);
+        lookup.add(kat);
+        let actions = lookup.add(kat);
+        assert!(matches!(&actions[..], [Action::Add { text, .. }] if matches!(&text[..], [Replacement::Text(t)] if t == "cat")));
+    }
+
+    #[test]
+    fn test_numbers() {
+        // Numbers glue to each other, and to other glued translations, but not to words.
+        assert_eq!(type_with(Strategy::Eager, &["1", "2", "KAT"], false), ("12 cat".to_string(), 0));
+        assert_eq!(type_with(Strategy::Eager, &["KAT", "1-9", "A*", "TKOG"], false),
+                   ("Cat 19a dog".to_string(), 0));
+        // Other untranslated strokes are just the steno.
+        assert_eq!(type_with(Strategy::Eager, &["KAT", "1-D"], false), ("Cat 1-D".to_string(), 0));
+    }
+}
@@ -6,7 +6,7 @@ use alloc::string::String;
 use alloc::vec::Vec;
 
 use super::{DictImpl, Selector, BinarySelector};
-use crate::Stroke;
+use crate::{Affix, Stroke};
 
 extern crate alloc;
 
@@ -19,6 +19,8 @@ pub struct RamDict {
     text: String,
     // This is, likewise, a selection for each result.
     values: Vec<(u32, u32)>,
+    // The affix class of each result.
+    affixes: Vec<Affix>,
 }
 
 impl DictImpl for RamDict {
@@ -46,6 +48,10 @@ impl DictImpl for RamDict {
     fn selector(self: Rc<Self>) -> Box<dyn Selector> {
         Box::new(BinarySelector::new(self.clone()))
     }
+
+    fn affix(&self, index: usize) -> Affix {
+        self.affixes[index]
+    }
 }
 
 /// A dictionary builder.
@@ -103,6 +109,7 @@ impl MapDictBuilder {
         let mut text = String::new();
         let mut keys = Vec::new();
         let mut values = Vec::new();
+        let mut affixes = Vec::new();
 
         for (k, v) in self.map {
             let a = strokes.len();
@@ -114,8 +121,9 @@ impl MapDictBuilder {
             text.push_str(&v);
             let b = text.len();
             values.push((a as u32, b as u32));
+            affixes.push(Affix::of(&v));
         }
 
-        RamDict { strokes, keys, text, values }
+        RamDict { strokes, keys, text, values, affixes }
     }
 }
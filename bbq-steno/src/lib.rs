@@ -11,8 +11,9 @@ pub mod memdict;
 pub mod stroke;
 pub mod typer;
 pub mod replacements;
+pub mod tape;
 
-pub use replacements::Replacement;
+pub use replacements::{Affix, Replacement};
 
 pub use stroke::Stroke;
 
@@ -5,22 +5,51 @@
 //!
 //! Note that we will treat these as static lifetime. Testing might use
 //! temporary arrays, and it is important to make sure they aren't moved.
+//!
+//! Small changes, such as to a user dictionary, can be made with a [`DictPatch`], which lists
+//! entries to add, replace, or remove.  [`MemDict::patch_group`] applies one to a group of
+//! dictionaries, writing out a new image a dictionary at a time, so only the patch has to be sent
+//! to the keyboard.
+//!
+//! Each entry has its own place in the text block, but entries with the same text share it, and a
+//! text that starts another one is found within it.  Readers only follow the offsets, so this
+//! needs nothing from them.
+//!
+//! Each entry's [`Affix`] class is stored in a table of its own, one byte per entry, so the
+//! joiner knows how an entry attaches without looking through its text.  Dictionaries built
+//! before the table was added don't have one, and work the class out from the text instead.
+//!
+//! A dictionary can also be built with a first-stroke [`index`], which finds the entries starting
+//! with a stroke without searching the key table.  It makes the image larger, so it is only added
+//! when asked for, with [`DictBuilder::set_index`].  Dictionaries without one are searched as
+//! before, and firmware that doesn't know about the index ignores it.
 
 extern crate alloc;
 
+use core::ops::Range;
 use core::slice::from_raw_parts;
 
 use alloc::boxed::Box;
-use alloc::string::String;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
 use alloc::rc::Rc;
 use alloc::vec::Vec;
 use minicbor::{Decode, Encode};
 
-use crate::{dict::{BinarySelector, Dict, DictImpl, EmilySymbols, Selector}, stroke::Stroke};
+use crate::{dict::{BinarySelector, Dict, DictImpl, EmilySymbols, Selector}, stroke::Stroke, Affix};
 // use log::warn;
 
+pub use self::index::Index;
+
+pub mod index;
+
 pub const DICT_TAG: u64 = 0x7374656e6f646374;
 pub const GROUP_TAG: u64 = 0x7374656e6f6d6c74;
+pub const PATCH_TAG: u64 = 0x7374656e6f706174;
+
+/// The version of the layout described here.  Reported by the firmware, so a host can tell which
+/// dictionaries it can read.  Change this along with any incompatible change to the layout.
+pub const FORMAT_VERSION: u32 = 1;
 
 /// Size allowed for the header, needs to incorporate the largest number of
 /// groups used.
@@ -53,6 +82,48 @@ pub struct RawMemDict {
     /// Byte offset of the text table.
     #[n(6)]
     pub text_table_offset: u32,
+    /// A name for the dictionary, usually from the file it was built from.  Only used for
+    /// reporting.
+    #[n(7)]
+    pub name: Option<String>,
+    /// Byte offset of the affix table, one [`Affix`] per entry.  Older dictionaries don't have one.
+    #[n(8)]
+    pub affix_offset: Option<u32>,
+    /// The first-stroke index, if the dictionary was built with one.
+    #[n(9)]
+    pub index: Option<RawIndex>,
+}
+
+/// Where a dictionary's first-stroke [`index`] is.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct RawIndex {
+    /// Byte offset of the displacements, a u32 for each bucket.
+    #[n(0)]
+    pub displacement_offset: u32,
+    #[n(1)]
+    pub buckets: u32,
+    /// Byte offset of the slots, a pair of u32 for each first stroke.
+    #[n(2)]
+    pub slot_offset: u32,
+    #[n(3)]
+    pub slots: u32,
+}
+
+impl RawMemDict {
+    /// The range of bytes, relative to the start of the mapped region, holding this dictionary's
+    /// data.
+    pub fn extent(&self) -> core::ops::Range<u32> {
+        let end = self.text_table_offset.saturating_add(self.size.saturating_mul(4));
+        let end = match self.affix_offset {
+            Some(offset) => end.max(offset.saturating_add(self.size)),
+            None => end,
+        };
+        let end = match &self.index {
+            Some(index) => end.max(index.slot_offset.saturating_add(index.slots.saturating_mul(8))),
+            None => end,
+        };
+        self.keys_offset..end
+    }
 }
 
 /// This structure encodes multiple dictionaries.  We just define a fixed
@@ -89,6 +160,10 @@ pub struct MemDict {
     pub text: &'static [u8],
     /// The text offset table.
     pub text_offsets: &'static [u32],
+    /// The affix class of each entry, if the dictionary has them.
+    pub affixes: Option<&'static [u8]>,
+    /// The first-stroke index, if the dictionary has one.
+    pub index: Option<Index>,
 }
 
 // TODO: Come up with error handling.
@@ -144,7 +219,33 @@ impl MemDict {
         Vec::new()
     }
 
-    unsafe fn decode_single(ptr: *const u8, raw: RawMemDict) -> Option<MemDict> {
+    /// Decode just the headers of the dictionaries at the given base, in the order
+    /// `from_raw_ptr` would load them.  This is for reporting what is in flash; a set that
+    /// `from_raw_ptr` would reject may still have headers.
+    ///
+    /// # Safety
+    ///
+    /// There must be at least `HEADER_MAX_BYTES` of readable memory at `ptr`.  Only the headers
+    /// are read, so they don't have to stay there after this returns.
+    pub unsafe fn entries(ptr: *const u8) -> Vec<GroupEntry> {
+        let header: &[u8] = from_raw_parts(ptr, HEADER_MAX_BYTES);
+
+        if let Ok(single) = minicbor::decode::<RawMemDict>(header) {
+            return alloc::vec![GroupEntry::Memory(single)];
+        }
+        if let Ok(group) = minicbor::decode::<RawDictGroup>(header) {
+            return group.dicts;
+        }
+        Vec::new()
+    }
+
+    /// Map a single dictionary, given its header, from the base its offsets are relative to.
+    ///
+    /// # Safety
+    ///
+    /// The header's tables must all be within the memory at `ptr`, which must be word aligned,
+    /// and stay there for as long as the dictionary is used.
+    pub unsafe fn decode_single(ptr: *const u8, raw: RawMemDict) -> Option<MemDict> {
         // println!("single: {:#x?}", raw);
         let keys = core::slice::from_raw_parts(
             ptr.add(raw.keys_offset as usize) as *const Stroke,
@@ -162,6 +263,19 @@ impl MemDict {
             ptr.add(raw.text_table_offset as usize) as *const u32,
             raw.size as usize,
         );
+        let affixes = raw.affix_offset.map(|offset| {
+            core::slice::from_raw_parts(ptr.add(offset as usize), raw.size as usize)
+        });
+        let index = raw.index.as_ref().map(|index| Index {
+            displacements: core::slice::from_raw_parts(
+                ptr.add(index.displacement_offset as usize) as *const u32,
+                index.buckets as usize,
+            ),
+            slots: core::slice::from_raw_parts(
+                ptr.add(index.slot_offset as usize) as *const [u32; 2],
+                index.slots as usize,
+            ),
+        });
 
         Some(MemDict {
             raw,
@@ -169,10 +283,86 @@ impl MemDict {
             key_offsets,
             text,
             text_offsets,
+            affixes,
+            index,
         })
     }
 }
 
+impl MemDict {
+    /// The entries of this dictionary, with the changes applied, in order.
+    pub fn apply_patch(&self, changes: &[PatchChange]) -> BTreeMap<Vec<Stroke>, String> {
+        let mut entries: BTreeMap<_, _> = (0..self.len())
+            .map(|i| (self.key(i).to_vec(), self.value(i).to_string()))
+            .collect();
+        for change in changes {
+            match change {
+                PatchChange::Set { strokes, text } => {
+                    entries.insert(strokes_from_raw(strokes), text.clone());
+                }
+                PatchChange::Remove { strokes } => {
+                    entries.remove(&strokes_from_raw(strokes));
+                }
+            }
+        }
+        entries
+    }
+
+    /// Apply a patch to the group of dictionaries at `ptr`, giving the new image of the whole
+    /// group to `write`, as pieces and their offsets in the image.  The other dictionaries are
+    /// carried over unchanged.  Each dictionary is written as soon as it is built, so only one of
+    /// them is held in memory at a time, and the group header, at the start, comes last.  Returns
+    /// the size of the image, or None if the dictionary to change isn't there, the result can't
+    /// be encoded, or `write` fails.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a group of dictionaries, as for [`MemDict::from_raw_ptr`], which stays
+    /// there until this returns.  `write` must not write over it.
+    pub unsafe fn patch_group(
+        ptr: *const u8,
+        patch: &DictPatch,
+        write: &mut dyn FnMut(u32, &[u8]) -> bool,
+    ) -> Option<u32> {
+        let mut build = DictBuilder::new();
+        let mut found = false;
+        for entry in MemDict::entries(ptr) {
+            match entry {
+                GroupEntry::Memory(raw) => {
+                    let name = raw.name.clone().unwrap_or_default();
+                    // Keep the index of a dictionary that had one.
+                    build.set_index(raw.index.is_some());
+                    let dict = MemDict::decode_single(ptr, raw)?;
+                    let changes = if name == patch.name {
+                        found = true;
+                        &patch.changes[..]
+                    } else {
+                        &[]
+                    };
+                    let entries = dict.apply_patch(changes);
+                    if !build.add(&name, entries.iter().map(|(k, v)| (k.as_slice(), v.as_str()))) {
+                        return None;
+                    }
+                    let (offset, data) = build.take_data()?;
+                    if !write(offset, &data) {
+                        return None;
+                    }
+                }
+                GroupEntry::Builtin(name) => build.add_builtin(&name),
+            }
+        }
+        if !found {
+            return None;
+        }
+        let size = build.size();
+        let header = build.into_header()?;
+        if !write(0, &header) {
+            return None;
+        }
+        Some(size)
+    }
+}
+
 impl DictImpl for MemDict {
     fn len(&self) -> usize {
         self.key_offsets.len()
@@ -199,6 +389,267 @@ impl DictImpl for MemDict {
     fn selector(self: Rc<Self>) -> Box<dyn Selector> {
         Box::new(BinarySelector::new(self))
     }
+
+    fn affix(&self, n: usize) -> Affix {
+        self.affixes
+            .and_then(|affixes| Affix::from_raw(affixes[n]))
+            .unwrap_or_else(|| Affix::of(self.value(n)))
+    }
+
+    fn first_range(&self, stroke: Stroke) -> Option<Range<usize>> {
+        let (left, right) = self.index.as_ref()?.get(stroke)?;
+        // A stroke that doesn't start any entry lands in the slot of one that does.
+        if left < right && self.key(left).first() == Some(&stroke) {
+            Some(left..right)
+        } else {
+            Some(0..0)
+        }
+    }
+}
+
+/// Changes to one dictionary in a group.
+#[derive(Debug, Default, Encode, Decode)]
+#[cbor(tag(0x7374656e6f706174))]
+pub struct DictPatch {
+    /// The name of the dictionary to change, as given in its header.
+    #[n(0)]
+    pub name: String,
+    /// The changes, applied in order.
+    #[n(1)]
+    pub changes: Vec<PatchChange>,
+}
+
+/// A single change to a dictionary.  Strokes are given in their raw form.
+#[derive(Clone, Debug, Encode, Decode, Eq, PartialEq)]
+pub enum PatchChange {
+    /// Add an entry, or replace the text of an existing one.
+    #[n(0)]
+    Set {
+        #[n(0)]
+        strokes: Vec<u32>,
+        #[n(1)]
+        text: String,
+    },
+    /// Remove an entry.  Removing one that isn't there does nothing.
+    #[n(1)]
+    Remove {
+        #[n(0)]
+        strokes: Vec<u32>,
+    },
+}
+
+fn strokes_from_raw(raw: &[u32]) -> Vec<Stroke> {
+    raw.iter().map(|&st| Stroke::from_raw(st)).collect()
+}
+
+/// Builds the image of a group of memory dictionaries, as read by [`MemDict::from_raw_ptr`].
+pub struct DictBuilder {
+    dicts: Vec<(GroupEntry, Vec<u8>)>,
+    offset: usize,
+    /// Add a first-stroke index to each dictionary.
+    index: bool,
+}
+
+impl DictBuilder {
+    pub fn new() -> DictBuilder {
+        DictBuilder {
+            dicts: Vec::new(),
+            offset: HEADER_MAX_BYTES,
+            index: false,
+        }
+    }
+
+    /// Whether the dictionaries added after this have a first-stroke [`index`].
+    pub fn set_index(&mut self, index: bool) {
+        self.index = index;
+    }
+
+    /// Add a dictionary, with its entries in order, as from a `BTreeMap`.  Returns false, adding
+    /// nothing, if an entry is too long to encode.
+    pub fn add<'a, I>(&mut self, name: &str, entries: I) -> bool
+    where
+        I: IntoIterator<Item = (&'a [Stroke], &'a str)>,
+    {
+        let entries: Vec<_> = entries.into_iter().collect();
+        let mut entry = RawMemDict::default();
+        let mut data = Vec::new();
+
+        entry.size = entries.len() as u32;
+        entry.name = Some(name.to_string());
+
+        // Write out all of the keys, consecutively, collecting offset and length values for them.
+        entry.keys_offset = self.pos(&data);
+        let mut keys = Vec::new();
+        let mut offset = 0;
+        for (k, _) in &entries {
+            let Some(pos) = table_pos(offset, k.len()) else { return false };
+            keys.push(pos);
+            offset += k.len();
+            for st in k.iter() {
+                data.extend_from_slice(&st.into_raw().to_le_bytes());
+            }
+        }
+        pad_buffer(&mut data, 8);
+        entry.keys_length = self.pos(&data) - entry.keys_offset;
+
+        // Write out the key table.
+        entry.key_pos_offset = self.pos(&data);
+        for pos in &keys {
+            data.extend_from_slice(&pos.to_le_bytes());
+        }
+        pad_buffer(&mut data, 8);
+
+        // Add the text strings, tracking their offsets.  Each distinct text is only stored once,
+        // and one that starts another is stored as part of that one.
+        entry.text_offset = self.pos(&data);
+        let mut distinct: Vec<&str> = entries.iter().map(|(_, v)| *v).collect();
+        distinct.sort_unstable();
+        distinct.dedup();
+        // In order, a text that starts any other starts the one after it.  Going backwards, that
+        // one has already been placed.
+        let mut offsets: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut offset = 0;
+        for (i, text) in distinct.iter().enumerate().rev() {
+            match distinct.get(i + 1) {
+                Some(next) if next.starts_with(text) => {
+                    offsets.insert(text, offsets[next]);
+                }
+                _ => {
+                    offsets.insert(text, offset);
+                    offset += text.len();
+                    data.extend_from_slice(text.as_bytes());
+                }
+            }
+        }
+        let mut texts = Vec::new();
+        for (_, v) in &entries {
+            let Some(pos) = table_pos(offsets[v], v.len()) else { return false };
+            texts.push(pos);
+        }
+        pad_buffer(&mut data, 8);
+        entry.text_length = self.pos(&data) - entry.text_offset;
+
+        // Finally output a table of the offsets and lengths of the strings.
+        entry.text_table_offset = self.pos(&data);
+        for pos in &texts {
+            data.extend_from_slice(&pos.to_le_bytes());
+        }
+
+        // And the affix class of each entry.
+        entry.affix_offset = Some(self.pos(&data));
+        for (_, v) in &entries {
+            data.push(Affix::of(v) as u8);
+        }
+
+        if self.index {
+            entry.index = self.add_index(&mut data, &entries);
+        }
+
+        // Pad the whole thing to 16 bytes.
+        pad_buffer(&mut data, 16);
+
+        self.offset += data.len();
+        self.dicts.push((GroupEntry::Memory(entry), data));
+        true
+    }
+
+    /// Write the first-stroke index of the entries.  The entries are in order, so those with the
+    /// same first stroke are together.
+    fn add_index(&self, data: &mut Vec<u8>, entries: &[(&[Stroke], &str)]) -> Option<RawIndex> {
+        let mut firsts: Vec<(Stroke, u32, u32)> = Vec::new();
+        for (i, (k, _)) in entries.iter().enumerate() {
+            let Some(&first) = k.first() else { continue };
+            match firsts.last_mut() {
+                Some((stroke, _, right)) if *stroke == first => *right = i as u32 + 1,
+                _ => firsts.push((first, i as u32, i as u32 + 1)),
+            }
+        }
+        let (displacements, slots) = index::build(&firsts)?;
+
+        pad_buffer(data, 8);
+        let displacement_offset = self.pos(data);
+        for displacement in &displacements {
+            data.extend_from_slice(&displacement.to_le_bytes());
+        }
+        pad_buffer(data, 8);
+        let slot_offset = self.pos(data);
+        for [left, right] in &slots {
+            data.extend_from_slice(&left.to_le_bytes());
+            data.extend_from_slice(&right.to_le_bytes());
+        }
+        Some(RawIndex {
+            displacement_offset,
+            buckets: displacements.len() as u32,
+            slot_offset,
+            slots: slots.len() as u32,
+        })
+    }
+
+    /// Add a reference to a builtin dictionary.
+    pub fn add_builtin(&mut self, name: &str) {
+        self.dicts.push((GroupEntry::Builtin(name.to_string()), Vec::new()));
+    }
+
+    /// Take the data of the dictionary just added, with its offset in the image, so that it can be
+    /// written out without holding on to it.  An image built this way is finished with
+    /// [`DictBuilder::into_header`].
+    pub fn take_data(&mut self) -> Option<(u32, Vec<u8>)> {
+        let data = core::mem::take(&mut self.dicts.last_mut()?.1);
+        Some(((self.offset - data.len()) as u32, data))
+    }
+
+    /// The size of the image so far.
+    pub fn size(&self) -> u32 {
+        self.offset as u32
+    }
+
+    /// The encoded image.  Returns None if the group header doesn't fit in [`HEADER_MAX_BYTES`].
+    pub fn into_image(mut self) -> Option<Vec<u8>> {
+        let datas: Vec<_> = self.dicts.iter_mut().map(|(_, data)| core::mem::take(data)).collect();
+        let mut image = self.into_header()?;
+        for data in datas {
+            image.extend_from_slice(&data);
+        }
+        Some(image)
+    }
+
+    /// Just the group header, which goes at the start of the image, padded to
+    /// [`HEADER_MAX_BYTES`].  Returns None if it doesn't fit.
+    pub fn into_header(self) -> Option<Vec<u8>> {
+        let header = RawDictGroup { dicts: self.dicts.into_iter().map(|(raw, _)| raw).collect() };
+
+        let mut image = minicbor::to_vec(&header).ok()?;
+        if image.len() > HEADER_MAX_BYTES {
+            return None;
+        }
+        // Pad the header to the actual size.
+        image.resize(HEADER_MAX_BYTES, 0xff);
+        Some(image)
+    }
+
+    /// The position in the image of the end of `data`, which is being built for the next
+    /// dictionary.
+    fn pos(&self, data: &[u8]) -> u32 {
+        (self.offset + data.len()) as u32
+    }
+}
+
+impl Default for DictBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn pad_buffer(data: &mut Vec<u8>, padding: usize) {
+    data.resize(data.len().next_multiple_of(padding), 0xff);
+}
+
+/// Encode a table entry, with the length as the upper 8 bits, and the offset in the lower.
+fn table_pos(offset: usize, length: usize) -> Option<u32> {
+    if length >= (1 << 8) || offset >= (1 << 24) {
+        return None;
+    }
+    Some(((length << 24) | offset) as u32)
 }
 
 /*
@@ -0,0 +1,107 @@
+//! The first-stroke index of a memory dictionary.
+//!
+//! Finding the entries that start with a stroke is a binary search over the whole key table, the
+//! costliest part of a lookup.  The index finds them in one step instead.  A minimal perfect hash
+//! gives each first stroke in the dictionary a slot of its own, and the slot holds the range of
+//! entries starting with that stroke.  A stroke that doesn't start any entry still hashes to some
+//! slot, so the caller checks the first entry of the range.
+//!
+//! The hash is built by hash and displace: the strokes are hashed into buckets of a few each, and
+//! each bucket is given a displacement, the seed that sends every stroke in it to a slot not yet
+//! taken.  Building tries each seed in turn, largest buckets first.  Looking up is two hashes.
+
+extern crate alloc;
+
+use core::cmp::Reverse;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::Stroke;
+
+/// The average number of strokes in a bucket.  Fewer makes the index larger, more makes it slower
+/// to build.
+const BUCKET_SIZE: usize = 4;
+
+/// The most seeds tried for a bucket before giving up on the index.
+const MAX_DISPLACEMENT: u32 = 1 << 20;
+
+/// A first-stroke index, as mapped from the dictionary image.
+pub struct Index {
+    /// The seed for each bucket.
+    pub displacements: &'static [u32],
+    /// The range of entries for each first stroke, as the start and one past the end.
+    pub slots: &'static [[u32; 2]],
+}
+
+impl Index {
+    /// The range of entries in the slot the stroke hashes to.  The range is only for this stroke
+    /// if its first entry starts with it.
+    pub fn get(&self, stroke: Stroke) -> Option<(usize, usize)> {
+        if self.displacements.is_empty() || self.slots.is_empty() {
+            return None;
+        }
+        let bucket = hash(stroke, 0) as usize % self.displacements.len();
+        let seed = self.displacements[bucket];
+        let slot = hash(stroke, seed.wrapping_add(1)) as usize % self.slots.len();
+        let [left, right] = self.slots[slot];
+        Some((left as usize, right as usize))
+    }
+}
+
+/// Build the index for the first strokes of a dictionary, each with its range of entries.  Returns
+/// the displacements and the slots, or None if a bucket couldn't be placed, which shouldn't happen
+/// with distinct strokes.
+pub fn build(firsts: &[(Stroke, u32, u32)]) -> Option<(Vec<u32>, Vec<[u32; 2]>)> {
+    let count = firsts.len();
+    let buckets = count.div_ceil(BUCKET_SIZE).max(1);
+    let mut members = vec![Vec::new(); buckets];
+    for (i, &(stroke, _, _)) in firsts.iter().enumerate() {
+        members[hash(stroke, 0) as usize % buckets].push(i);
+    }
+    let mut order: Vec<usize> = (0..buckets).collect();
+    order.sort_by_key(|&b| Reverse(members[b].len()));
+
+    let mut displacements = vec![0; buckets];
+    let mut slots: Vec<Option<[u32; 2]>> = vec![None; count];
+    let mut taken = Vec::new();
+    for bucket in order {
+        if members[bucket].is_empty() {
+            break;
+        }
+        let mut seed = 0;
+        loop {
+            taken.clear();
+            let placed = members[bucket].iter().all(|&i| {
+                let slot = hash(firsts[i].0, seed + 1) as usize % count;
+                let free = slots[slot].is_none() && !taken.contains(&slot);
+                taken.push(slot);
+                free
+            });
+            if placed {
+                break;
+            }
+            seed += 1;
+            if seed >= MAX_DISPLACEMENT {
+                return None;
+            }
+        }
+        for (&i, &slot) in members[bucket].iter().zip(&taken) {
+            slots[slot] = Some([firsts[i].1, firsts[i].2]);
+        }
+        displacements[bucket] = seed;
+    }
+    Some((displacements, slots.into_iter().map(|slot| slot.unwrap()).collect()))
+}
+
+/// Hash a stroke with a seed.  This is the finalizer of MurmurHash3, which mixes the few bits that
+/// differ between strokes well enough.
+fn hash(stroke: Stroke, seed: u32) -> u32 {
+    let mut h = stroke.into_raw() ^ seed.wrapping_mul(0x9e37_79b9);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
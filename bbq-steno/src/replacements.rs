@@ -19,6 +19,12 @@
 //! - 0x0d - CR
 //! - 0x0exxxx0x0b - Number format, template in xxxx.
 //! - 0x0f - Upcase next
+//! - 0xe009C - Put a space back before each of the previous 'c' translations
+//! - 0xe00dxxx-x00 - Command for the keyboard, described by 'x' characters
+//!
+//! Whether an entry attaches to the words around it (its [`Affix`] class) follows from where the
+//! Delete Space markers are.  It is worked out once, when a dictionary is built, and stored
+//! alongside the entry, so the joiner doesn't have to look for the markers itself.
 
 extern crate alloc;
 
@@ -38,9 +44,11 @@ const REPL_SPACES: char = '\u{e005}';
 const RAW: char = '\u{e006}';
 const RETRO_BREAK: char = '\u{e007}';
 const BREAK: char = '\u{e008}';
+const ADD_SPACES: char = '\u{e009}';
 const RETRO_NUM: char = '\u{e00a}';
 const RETRO_CURRENCY: char = '\u{e00b}';
 const NOCAP_NEXT: char = '\u{e00c}';
+const COMMAND: char = '\u{e00d}';
 
 const TERM_TEXT: char = '\u{0000}';
 
@@ -66,6 +74,8 @@ pub enum Replacement {
     RetroBreak,
     /// Upcase next word.
     UpNext,
+    /// A command for the keyboard, as "NAME:ARG".
+    Command(String),
 }
 
 /// Previous actions
@@ -75,11 +85,55 @@ pub enum Previous {
     Lowerize,
     Upcase,
     DeleteSpace,
+    /// Put a space back in front of each of the previous translations.
+    AddSpace,
     ReplaceSpace(char),
     Number(String),
     Currency(String),
 }
 
+/// How a dictionary entry attaches to the words around it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Affix {
+    /// A word on its own.
+    None = 0,
+    /// Attaches to the word after it, such as `de{^}`.
+    Prefix = 1,
+    /// Attaches to the word before it, such as `{^}ing`.
+    Suffix = 2,
+    /// Attaches to both, such as `{^-^}`.
+    Infix = 3,
+}
+
+impl Affix {
+    /// The class of an encoded definition, from the Delete Space markers at either end.
+    pub fn of(text: &str) -> Affix {
+        match (text.starts_with(DELETE_SPACE), text.len() > 1 && text.ends_with(DELETE_SPACE)) {
+            (false, false) => Affix::None,
+            (false, true) => Affix::Prefix,
+            (true, false) => Affix::Suffix,
+            (true, true) => Affix::Infix,
+        }
+    }
+
+    /// Decode a class stored in a dictionary.  Unknown values are None.
+    pub fn from_raw(raw: u8) -> Option<Affix> {
+        match raw {
+            0 => Some(Affix::None),
+            1 => Some(Affix::Prefix),
+            2 => Some(Affix::Suffix),
+            3 => Some(Affix::Infix),
+            _ => None,
+        }
+    }
+
+    /// Does this attach to the word before it, so the orthography rules apply.
+    pub fn attaches_before(self) -> bool {
+        matches!(self, Affix::Suffix | Affix::Infix)
+    }
+}
+
 impl Replacement {
     /// Attempt to build a replacement.  Returns None if there are errors in the replacement,
     /// otherwise it is the decoded string as a vector of replacements.
@@ -112,12 +166,16 @@ impl Replacement {
                     let count = chars.next()?;
                     result.push(Replacement::Previous(count as u32, Previous::DeleteSpace));
                 }
+                ADD_SPACES => {
+                    let count = chars.next()?;
+                    result.push(Replacement::Previous(count as u32, Previous::AddSpace));
+                }
                 REPL_SPACES => {
                     let count = chars.next()?;
                     let next = chars.next()?;
                     result.push(Replacement::Previous(count as u32, Previous::ReplaceSpace(next)));
                 }
-                RAW | RETRO_NUM | RETRO_CURRENCY => {
+                RAW | RETRO_NUM | RETRO_CURRENCY | COMMAND => {
                     let mut raw = String::new();
                     loop {
                         let ch = chars.next()?;
@@ -128,6 +186,8 @@ impl Replacement {
                     }
                     if c == RAW {
                         result.push(Replacement::Raw(raw));
+                    } else if c == COMMAND {
+                        result.push(Replacement::Command(raw));
                     } else if c == RETRO_CURRENCY {
                         result.push(Replacement::Previous(1, Previous::Currency(raw)));
                     } else {
@@ -191,6 +251,10 @@ impl Replacement {
                             result.push(DEL_SPACES);
                             result.push(char::from_u32(*count).unwrap());
                         }
+                        Previous::AddSpace => {
+                            result.push(ADD_SPACES);
+                            result.push(char::from_u32(*count).unwrap());
+                        }
                         Previous::ReplaceSpace(with) => {
                             result.push(REPL_SPACES);
                             result.push(char::from_u32(*count).unwrap());
@@ -215,6 +279,11 @@ impl Replacement {
                     result.push_str(&raw);
                     result.push(TERM_TEXT);
                 }
+                Replacement::Command(command) => {
+                    result.push(COMMAND);
+                    result.push_str(command);
+                    result.push(TERM_TEXT);
+                }
                 Replacement::Text(text) => result.push_str(text),
             }
         }
@@ -225,6 +294,7 @@ impl Replacement {
 
 #[cfg(test)]
 mod testing {
+    use crate::replacements::Affix;
     use crate::Replacement;
 
     fn roundtrip(text: &str) {
@@ -239,5 +309,23 @@ mod testing {
         roundtrip("This is plain text.");
         roundtrip("aa \x01 bb \x02 cc \x03 dd \x04 ee \x05\x01 ff \x06\x02 gg \x07\x03 hh \x08\x04 ii");
         roundtrip("aa \x09\x01_ bb \x0aS-w\x0b cc");
+        roundtrip("aa \u{e006}Control_L(z)\0 bb \u{e00d}PLOVER:TOGGLE\0");
+        roundtrip("aa \u{e004}\x01 bb \u{e009}\x02 cc");
+    }
+
+    #[test]
+    fn test_affix() {
+        assert_eq!(Affix::of("word"), Affix::None);
+        assert_eq!(Affix::of("de\x01"), Affix::Prefix);
+        assert_eq!(Affix::of("\x01ing"), Affix::Suffix);
+        assert_eq!(Affix::of("\x01-\x01"), Affix::Infix);
+        // A lone attach is a suffix, not both.
+        assert_eq!(Affix::of("\x01"), Affix::Suffix);
+        assert_eq!(Affix::of(""), Affix::None);
+
+        for affix in [Affix::None, Affix::Prefix, Affix::Suffix, Affix::Infix] {
+            assert_eq!(Affix::from_raw(affix as u8), Some(affix));
+        }
+        assert_eq!(Affix::from_raw(0xff), None);
     }
 }
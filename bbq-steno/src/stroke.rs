@@ -253,6 +253,24 @@ impl Stroke {
         Stroke(self.0 & !other.0)
     }
 
+    /// The digits written by a number stroke, one with the number bar and only the keys that are
+    /// digits, such as `1-9`.  None for any other stroke, including the number bar by itself.
+    pub fn digits(self) -> Option<String> {
+        let keys = self.mask(NUM);
+        if !self.has_any(NUM) || keys.is_empty() || keys.has_any(!DIGITS) {
+            return None;
+        }
+        let mut digits = String::new();
+        let mut bit = NUM.0 >> 1;
+        for ch in NUMS.chars() {
+            if self.has_any(Stroke(bit)) {
+                digits.push(ch);
+            }
+            bit >>= 1;
+        }
+        Some(digits)
+    }
+
     /// Is this an empty stroke (with no keys pressed)
     pub fn is_empty(self) -> bool {
         self.0 == 0
@@ -370,6 +388,21 @@ impl Stroke {
         result
     }
 
+    /// Decode a Gemini packet, as produced by `to_gemini`.  Returns None if the packet isn't
+    /// framed correctly (only the first byte should have the high bit set).
+    pub fn from_gemini(packet: &[u8; 6]) -> Option<Stroke> {
+        if packet[0] & 0x80 == 0 || packet[1..].iter().any(|b| b & 0x80 != 0) {
+            return None;
+        }
+        let mut result = 0;
+        for (stenobit, (byte, bits)) in TOGEMINI.iter().enumerate() {
+            if (packet[*byte as usize] & bits) != 0 {
+                result |= 1 << stenobit;
+            }
+        }
+        Some(Stroke(result))
+    }
+
     /// Convert a stroke into a Plover HID report.
     pub fn to_plover_hid(&self) -> [u8; 9] {
         let mut result = [0u8; 9];
@@ -535,6 +568,27 @@ fn stroke_roundtrip() {
     }
 }
 
+#[test]
+fn number_digits() {
+    let digits = |text| Stroke::from_text(text).unwrap().digits();
+    assert_eq!(digits("1").as_deref(), Some("1"));
+    assert_eq!(digits("1-9").as_deref(), Some("19"));
+    assert_eq!(digits("1234506789").as_deref(), Some("1234506789"));
+    assert_eq!(digits("#"), None);
+    assert_eq!(digits("1-D"), None);
+    assert_eq!(digits("KAT"), None);
+}
+
+#[test]
+fn gemini_roundtrip() {
+    for text in ["STKPWHRAO*EUFRPBLGTSDZ", "#T-D", "^S-Z", "+WR", "-Z"] {
+        let stroke = Stroke::from_text(text).unwrap();
+        assert_eq!(Stroke::from_gemini(&stroke.to_gemini()), Some(stroke));
+    }
+    assert_eq!(Stroke::from_gemini(&[0x00, 0x20, 0, 0, 0, 0]), None);
+    assert_eq!(Stroke::from_gemini(&[0x80, 0x80, 0, 0, 0, 0]), None);
+}
+
 #[cfg(feature = "std")]
 mod std_features {
     use super::Error;
@@ -0,0 +1,358 @@
+//! Steno paper tape.
+//!
+//! The tape keeps a record of the most recent strokes, along with a short summary of what each
+//! stroke caused to be typed.  The storage is a fixed-size ring, so the memory used is bounded and
+//! known up front, which makes it reasonable to always have enabled, unlike the full logging.
+//! Once the ring is full, the oldest entries are discarded.
+//!
+//! The tape can be exported as a simple line-based text file, one stroke per line:
+//!
+//! ```text
+//! # bbq-tape
+//! KAT    0    cat
+//! -S     0    s
+//! *      4
+//! ```
+//!
+//! The fields are separated by tabs (shown as spaces above), and are the stroke, the number of
+//! characters removed, and the text that was appended.  Tabs, newlines, and backslashes within the
+//! text are escaped with a backslash.  This is the format that typey can replay.
+//!
+//! The keyboard saves the tape in a more compact binary form (see [`Tape::save`]), which is small
+//! enough to always fit in a single flash sector, and carries a checksum, so that a save cut short
+//! is noticed when it is read back.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use arrayvec::ArrayString;
+use heapless::Deque;
+
+use crate::dict::Joined;
+use crate::Stroke;
+
+/// The number of strokes remembered on the tape.
+pub const TAPE_LEN: usize = 64;
+
+/// The number of bytes of translation text kept for each stroke.  Longer translations are
+/// truncated, as this is only meant as a summary.
+pub const TEXT_LEN: usize = 24;
+
+/// The header line that starts an exported tape.
+pub const TAPE_HEADER: &str = "# bbq-tape";
+
+/// Marks a saved tape, "tape" in flash.
+const SAVE_MAGIC: u32 = u32::from_le_bytes(*b"tape");
+
+/// The header of a saved tape: the magic, the generation, the number of entries, and the checksum.
+const SAVE_HEADER: usize = 16;
+
+/// The most space a saved tape takes.  Each entry is the stroke, the number removed, and the
+/// length and bytes of the text.
+pub const SAVE_MAX: usize = SAVE_HEADER + TAPE_LEN * (4 + 2 + 1 + TEXT_LEN);
+
+/// A single stroke on the tape.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TapeEntry {
+    /// The stroke itself.
+    pub stroke: Stroke,
+    /// How many characters this stroke removed.
+    pub remove: u16,
+    /// The (possibly truncated) text appended by this stroke.
+    pub text: ArrayString<TEXT_LEN>,
+}
+
+impl TapeEntry {
+    /// Build an entry for a stroke, summarizing the given joined actions.
+    pub fn new(stroke: Stroke, joined: &[Joined]) -> TapeEntry {
+        let mut remove = 0usize;
+        let mut text = ArrayString::new();
+        for act in joined {
+            match act {
+                Joined::Type { remove: rem, append } => {
+                    // Removes after text has been appended eat into that text first.
+                    for _ in 0..*rem {
+                        if text.pop().is_none() {
+                            remove += 1;
+                        }
+                    }
+                    for ch in append.chars() {
+                        if text.try_push(ch).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Joined::Raw(_) | Joined::Command(_) => (),
+            }
+        }
+        TapeEntry {
+            stroke,
+            remove: remove.min(u16::MAX as usize) as u16,
+            text,
+        }
+    }
+
+    /// Format this entry as a single line of the tape file (without the newline).
+    pub fn to_line(&self) -> String {
+        let mut line = ArrayString::<64>::new();
+        self.stroke.to_arraystring(&mut line);
+        let mut result = String::from(line.as_str());
+        result.push('\t');
+        result.push_str(&alloc::format!("{}", self.remove));
+        if !self.text.is_empty() {
+            result.push('\t');
+            for ch in self.text.chars() {
+                match ch {
+                    '\t' => result.push_str("\\t"),
+                    '\n' => result.push_str("\\n"),
+                    '\\' => result.push_str("\\\\"),
+                    ch => result.push(ch),
+                }
+            }
+        }
+        result
+    }
+
+    /// Parse a single line of the tape file.  Returns None for lines that aren't entries (the
+    /// header, comments, and blank lines), or for lines that are malformed.
+    pub fn from_line(line: &str) -> Option<TapeEntry> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let mut fields = line.splitn(3, '\t');
+        let stroke = Stroke::from_text(fields.next()?).ok()?;
+        let remove = fields.next()?.parse().ok()?;
+
+        let mut text = ArrayString::new();
+        let mut chars = fields.next().unwrap_or("").chars();
+        while let Some(ch) = chars.next() {
+            let ch = match ch {
+                '\\' => match chars.next()? {
+                    't' => '\t',
+                    'n' => '\n',
+                    '\\' => '\\',
+                    _ => return None,
+                },
+                ch => ch,
+            };
+            text.try_push(ch).ok()?;
+        }
+
+        Some(TapeEntry {
+            stroke,
+            remove,
+            text,
+        })
+    }
+}
+
+/// The tape itself, holding the most recent `TAPE_LEN` strokes.
+pub struct Tape {
+    entries: Deque<TapeEntry, TAPE_LEN>,
+    /// Incremented on every change, so that something that wants to save the tape can tell if
+    /// there is anything new.
+    generation: u32,
+}
+
+impl Tape {
+    pub const fn new() -> Tape {
+        Tape {
+            entries: Deque::new(),
+            generation: 0,
+        }
+    }
+
+    /// Record a stroke, along with the actions it generated.
+    pub fn push(&mut self, stroke: Stroke, joined: &[Joined]) {
+        self.push_entry(TapeEntry::new(stroke, joined));
+    }
+
+    /// Record an already built entry, discarding the oldest if the tape is full.
+    pub fn push_entry(&mut self, entry: TapeEntry) {
+        if self.entries.is_full() {
+            let _ = self.entries.pop_front();
+        }
+        // Cannot fail, as we just made space.
+        let _ = self.entries.push_back(entry);
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    /// Discard everything on the tape.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The current generation of the tape.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Iterate over the entries, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &TapeEntry> {
+        self.entries.iter()
+    }
+
+    /// Export the entire tape in the tape file format.
+    pub fn export(&self) -> String {
+        let mut result = String::from(TAPE_HEADER);
+        result.push('\n');
+        for entry in self.iter() {
+            result.push_str(&entry.to_line());
+            result.push('\n');
+        }
+        result
+    }
+
+    /// Encode the tape for saving, along with its generation.
+    pub fn save(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(SAVE_MAX - SAVE_HEADER);
+        for entry in self.iter() {
+            body.extend_from_slice(&entry.stroke.into_raw().to_le_bytes());
+            body.extend_from_slice(&entry.remove.to_le_bytes());
+            body.push(entry.text.len() as u8);
+            body.extend_from_slice(entry.text.as_bytes());
+        }
+
+        let mut result = Vec::with_capacity(SAVE_HEADER + body.len());
+        result.extend_from_slice(&SAVE_MAGIC.to_le_bytes());
+        result.extend_from_slice(&self.generation.to_le_bytes());
+        result.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        result.extend_from_slice(&checksum(self.generation, &body).to_le_bytes());
+        result.extend_from_slice(&body);
+        result
+    }
+
+    /// Decode a tape written by [`Tape::save`].  Anything trailing the saved tape, such as the
+    /// rest of the flash sector, is ignored.  Returns None if there isn't a saved tape, or it is
+    /// damaged.
+    pub fn restore(data: &[u8]) -> Option<Tape> {
+        let word = |pos: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+        };
+        if word(0)? != SAVE_MAGIC {
+            return None;
+        }
+        let generation = word(4)?;
+        let count = word(8)? as usize;
+        let sum = word(12)?;
+        if count > TAPE_LEN {
+            return None;
+        }
+
+        let mut tape = Tape::new();
+        let mut pos = SAVE_HEADER;
+        for _ in 0..count {
+            let stroke = Stroke::from_raw(word(pos)?);
+            let remove = u16::from_le_bytes(data.get(pos + 4..pos + 6)?.try_into().ok()?);
+            let len = *data.get(pos + 6)? as usize;
+            let bytes = data.get(pos + 7..pos + 7 + len)?;
+            let text = ArrayString::from(core::str::from_utf8(bytes).ok()?).ok()?;
+            tape.push_entry(TapeEntry { stroke, remove, text });
+            pos += 7 + len;
+        }
+        if checksum(generation, &data[SAVE_HEADER..pos]) != sum {
+            return None;
+        }
+        tape.generation = generation;
+        Some(tape)
+    }
+}
+
+/// FNV-1a, over the generation and the entries of a saved tape.
+fn checksum(generation: u32, body: &[u8]) -> u32 {
+    generation
+        .to_le_bytes()
+        .iter()
+        .chain(body)
+        .fold(0x811c_9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Tape::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    use super::*;
+
+    fn typed(remove: usize, append: &str) -> Joined {
+        Joined::Type {
+            remove,
+            append: append.to_string(),
+        }
+    }
+
+    #[test]
+    fn tape_ring() {
+        let mut tape = Tape::new();
+        for i in 0..TAPE_LEN + 5 {
+            tape.push(Stroke::from_raw(i as u32 + 1), &[]);
+        }
+        assert_eq!(tape.len(), TAPE_LEN);
+        assert_eq!(tape.iter().next().unwrap().stroke, Stroke::from_raw(6));
+    }
+
+    #[test]
+    fn tape_roundtrip() {
+        let mut tape = Tape::new();
+        tape.push(Stroke::from_text("KAT").unwrap(), &[typed(0, " cat")]);
+        tape.push(Stroke::from_text("*").unwrap(), &[typed(4, "")]);
+        tape.push(Stroke::from_text("TAB").unwrap(), &[typed(0, "\t\\\n")]);
+        tape.push(
+            Stroke::from_text("HRAUPBG").unwrap(),
+            &[typed(0, " this is a much longer translation than fits")],
+        );
+
+        let text = tape.export();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some(TAPE_HEADER));
+        let back: vec::Vec<_> = lines.filter_map(TapeEntry::from_line).collect();
+        let orig: vec::Vec<_> = tape.iter().cloned().collect();
+        assert_eq!(back, orig);
+        assert_eq!(orig[1].remove, 4);
+        assert_eq!(orig[3].text.len(), TEXT_LEN);
+    }
+
+    #[test]
+    fn tape_save() {
+        let mut tape = Tape::new();
+        assert_eq!(Tape::restore(&tape.save()).map(|t| t.len()), Some(0));
+
+        for i in 0..TAPE_LEN + 3 {
+            tape.push(
+                Stroke::from_raw(i as u32 + 1),
+                &[typed(i % 3, " a translation well past the length kept")],
+            );
+        }
+        let mut saved = tape.save();
+        assert!(saved.len() <= SAVE_MAX);
+
+        // Flash past the end of the tape is ignored.
+        saved.resize(4096, 0xff);
+        let back = Tape::restore(&saved).unwrap();
+        assert_eq!(back.generation(), tape.generation());
+        assert!(back.iter().eq(tape.iter()));
+
+        // As is a save that was cut short, or never made.
+        saved[100] = 0xff;
+        assert!(Tape::restore(&saved).is_none());
+        assert!(Tape::restore(&saved[..40]).is_none());
+        assert!(Tape::restore(&[0xff; 64]).is_none());
+    }
+}
@@ -4,8 +4,10 @@ use std::{collections::BTreeMap, fs::File, rc::Rc};
 
 use anyhow::Result;
 use bbq_steno::{
-    dict::{RamDict, MapDictBuilder, DictImpl},
+    dict::{Dict, DictImpl, Joined, Joiner, Lookup, MapDictBuilder, RamDict, Strategy},
+    memdict::{DictBuilder, DictPatch, GroupEntry, MemDict, PatchChange},
     stroke::StenoWord,
+    Affix, Stroke,
 };
 use bbq_steno_macros::stroke;
 
@@ -31,6 +33,263 @@ fn ramdict() {
     // println!("ST/OP: {:?}", posc);
 }
 
+#[test]
+fn prepared_lookup() {
+    let mut b = MapDictBuilder::new();
+    b.insert(vec![stroke!("ST")], "ST".to_string());
+    b.insert(vec![stroke!("ST"), stroke!("OP")], "ST/OP".to_string());
+    b.insert(vec![stroke!("S")], "S".to_string());
+    let dicts: Vec<Dict> = vec![Rc::new(b.into_ram_dict())];
+
+    // Preparing the strokes as keys go down gives the same results as looking them up directly.
+    let mut plain = Lookup::new(dicts.clone());
+    let mut prepared = Lookup::new(dicts);
+    for stroke in [stroke!("ST"), stroke!("OP"), stroke!("*"), stroke!("S")] {
+        prepared.prepare(stroke!("S"));
+        prepared.prepare(stroke);
+        assert_eq!(format!("{:?}", prepared.add(stroke)), format!("{:?}", plain.add(stroke)));
+    }
+
+    // A prepared stroke that doesn't match is ignored.
+    prepared.prepare(stroke!("ST"));
+    assert_eq!(format!("{:?}", prepared.add(stroke!("S"))), format!("{:?}", plain.add(stroke!("S"))));
+}
+
+#[test]
+fn patched_memdict() {
+    let user = [
+        (vec![stroke!("KAT")], "cat"),
+        (vec![stroke!("TKOG")], "dog"),
+        (vec![stroke!("TKOG"), stroke!("-S")], "dogs"),
+    ];
+    let mut build = DictBuilder::new();
+    assert!(build.add("user", user.iter().map(|(k, v)| (k.as_slice(), *v))));
+    build.add_builtin("emily-symbols");
+    let image = aligned(&build.into_image().unwrap());
+
+    let patch = DictPatch {
+        name: "user".to_string(),
+        changes: vec![
+            PatchChange::Set { strokes: raw(&[stroke!("KAT")]), text: "kitty".to_string() },
+            PatchChange::Remove { strokes: raw(&[stroke!("TKOG"), stroke!("-S")]) },
+            PatchChange::Set { strokes: raw(&[stroke!("PWEUFRD")]), text: "bird".to_string() },
+        ],
+    };
+    let patched = aligned(&patch_image(&image, &patch).unwrap());
+    let dicts = unsafe { MemDict::from_raw_ptr(patched.as_ptr() as *const u8) };
+    assert_eq!(dicts.len(), 2);
+    let entries: Vec<_> = (0..dicts[0].len()).map(|i| (dicts[0].key(i), dicts[0].value(i))).collect();
+    assert_eq!(entries, [
+        (&[stroke!("PWEUFRD")][..], "bird"),
+        (&[stroke!("KAT")][..], "kitty"),
+        (&[stroke!("TKOG")][..], "dog"),
+    ]);
+
+    // A patch for a dictionary that isn't there is refused.
+    let patch = DictPatch { name: "other".to_string(), changes: vec![] };
+    assert!(patch_image(&image, &patch).is_none());
+}
+
+#[test]
+fn memdict_affixes() {
+    let entries: BTreeMap<_, _> = [
+        (vec![stroke!("TKE")], "de\u{1}"),
+        (vec![stroke!("-G")], "\u{1}ing"),
+        (vec![stroke!("KAT")], "cat"),
+    ].into_iter().collect();
+    let mut build = DictBuilder::new();
+    assert!(build.add("main", entries.iter().map(|(k, v)| (k.as_slice(), *v))));
+    let image = aligned(&build.into_image().unwrap());
+    let dicts = unsafe { MemDict::from_raw_ptr(image.as_ptr() as *const u8) };
+    let headers = unsafe { MemDict::entries(image.as_ptr() as *const u8) };
+    assert!(matches!(&headers[0], GroupEntry::Memory(raw) if raw.affix_offset.is_some()));
+
+    // The classes come from the stored table.
+    let affixes: Vec<_> = (0..dicts[0].len()).map(|i| (dicts[0].value(i), dicts[0].affix(i))).collect();
+    let expect: Vec<_> = entries.values().map(|&v| (v, Affix::of(v))).collect();
+    assert_eq!(affixes, expect);
+    assert!(expect.iter().any(|&(_, affix)| affix == Affix::Prefix));
+
+    // And are given to the joiner with the translation.
+    let mut lookup = Lookup::new(dicts);
+    assert!(format!("{:?}", lookup.add(stroke!("-G"))).contains("affix: Suffix"));
+    assert!(format!("{:?}", lookup.add(stroke!("KAT"))).contains("affix: None"));
+}
+
+#[test]
+fn memdict_shared_text() {
+    let entries: BTreeMap<_, _> = [
+        ("THE", "the"),
+        ("-T", "the"),
+        ("THEPL", "them"),
+        ("THAOEPL", "them"),
+        ("THES", "these"),
+        ("TP-PL", "{.}"),
+        ("P-P", "{.}"),
+        ("KAT", "cat"),
+        ("SKWRAOEUPB", ""),
+    ].into_iter().map(|(k, v)| (StenoWord::parse(k).unwrap().0, v)).collect();
+    let mut build = DictBuilder::new();
+    assert!(build.add("main", entries.iter().map(|(k, v)| (k.as_slice(), *v))));
+    let image = aligned(&build.into_image().unwrap());
+
+    // Only "these", "{.}" and "cat" need storing, the rest are within them.
+    let headers = unsafe { MemDict::entries(image.as_ptr() as *const u8) };
+    let GroupEntry::Memory(raw) = &headers[0] else { panic!("not a memory dictionary") };
+    assert_eq!(raw.text_length, "these{.}cat".len().next_multiple_of(8) as u32);
+
+    let dicts = unsafe { MemDict::from_raw_ptr(image.as_ptr() as *const u8) };
+    let values: Vec<_> = (0..dicts[0].len()).map(|i| (dicts[0].key(i).to_vec(), dicts[0].value(i))).collect();
+    let expect: Vec<_> = entries.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    assert_eq!(values, expect);
+}
+
+#[test]
+fn memdict_index() {
+    let entries: BTreeMap<_, _> = [
+        "KAT", "KAT/HROG", "KAT/HROG/-S", "TKOG", "TKOG/-S", "TPHU", "TPHU/KHRAOER", "HRAOEUBG",
+        "-G", "S", "T", "K", "P", "W", "H", "R", "A", "O", "E", "U", "-F", "-R", "-P", "-B",
+    ].into_iter().map(|k| (StenoWord::parse(k).unwrap().0, k.to_lowercase())).collect();
+    let image = |index| {
+        let mut build = DictBuilder::new();
+        build.set_index(index);
+        assert!(build.add("main", entries.iter().map(|(k, v)| (k.as_slice(), v.as_str()))));
+        aligned(&build.into_image().unwrap())
+    };
+    let plain = image(false);
+    let indexed = image(true);
+    let headers = unsafe { MemDict::entries(indexed.as_ptr() as *const u8) };
+    assert!(matches!(&headers[0], GroupEntry::Memory(raw) if raw.index.is_some()));
+    let headers = unsafe { MemDict::entries(plain.as_ptr() as *const u8) };
+    assert!(matches!(&headers[0], GroupEntry::Memory(raw) if raw.index.is_none()));
+
+    // Every first stroke finds its entries, and any other stroke finds none.
+    let dict = &unsafe { MemDict::from_raw_ptr(indexed.as_ptr() as *const u8) }[0];
+    for key in entries.keys() {
+        let range = dict.first_range(key[0]).unwrap();
+        assert!(!range.is_empty());
+        assert!(range.clone().all(|i| dict.key(i)[0] == key[0]));
+        assert!(range.start == 0 || dict.key(range.start - 1)[0] != key[0]);
+        assert!(range.end == dict.len() || dict.key(range.end)[0] != key[0]);
+    }
+    for other in [stroke!("STKPWHR"), stroke!("-Z"), stroke!("#"), stroke!("KAOT")] {
+        assert_eq!(dict.first_range(other), Some(0..0));
+    }
+
+    // Lookups give the same with and without the index.
+    let strokes = StenoWord::parse("KAT/HROG/-S/TPHU/KHRAOER/-Z/TKOG/TKOG/-S/HRAOEUBG").unwrap().0;
+    let run = |image: &[u32]| {
+        let mut lookup = Lookup::new(unsafe { MemDict::from_raw_ptr(image.as_ptr() as *const u8) });
+        strokes.iter().map(|&st| format!("{:?}", lookup.add(st))).collect::<Vec<_>>()
+    };
+    assert_eq!(run(&indexed), run(&plain));
+
+    // A patch keeps the index.
+    let patch = DictPatch {
+        name: "main".to_string(),
+        changes: vec![PatchChange::Set { strokes: raw(&[stroke!("PWEUFRD")]), text: "bird".to_string() }],
+    };
+    let patched = aligned(&patch_image(&indexed, &patch).unwrap());
+    let dict = &unsafe { MemDict::from_raw_ptr(patched.as_ptr() as *const u8) }[0];
+    let range = dict.first_range(stroke!("PWEUFRD")).unwrap();
+    assert_eq!(dict.value(range.start), "bird");
+}
+
+#[test]
+fn strategy_corpus() {
+    let entries: BTreeMap<_, _> = [
+        ("KAT", "cat"),
+        ("KAT/HROG", "catalog"),
+        ("TPHU", "new"),
+        ("TPHU/KHRAOER", "nuclear"),
+        ("TPHU/KHRAOER/-S", "nuclei"),
+        ("TKOG", "dog"),
+        ("HRAOEUBG", "like"),
+        ("HRAOEUBG/HRAOEUBG", "like, like"),
+    ].into_iter().map(|(k, v)| (StenoWord::parse(k).unwrap().0, v)).collect();
+    let mut build = DictBuilder::new();
+    assert!(build.add("main", entries.iter().map(|(k, v)| (k.as_slice(), *v))));
+    let image = aligned(&build.into_image().unwrap());
+    let dicts = unsafe { MemDict::from_raw_ptr(image.as_ptr() as *const u8) };
+
+    // Each case, the text typed, and how many characters eager lookup deletes to get there.
+    let corpus = [
+        ("KAT/TKOG", "Cat dog", 0),
+        ("KAT/HROG/TKOG", "Catalog dog", 0),
+        ("TPHU/KHRAOER/TKOG", "Nuclear dog", 2),
+        ("TPHU/KHRAOER/-S/TKOG", "Nuclei dog", 4),
+        ("HRAOEUBG/HRAOEUBG/HRAOEUBG", "Like, like like", 0),
+        ("TKOG/KAT/*/TPHU/KHRAOER", "Dog nuclear", 6),
+    ];
+
+    let run = |steno: &str, strategy| {
+        let mut lookup = Lookup::new(dicts.clone());
+        lookup.set_strategy(strategy);
+        let mut joiner = Joiner::new();
+        let steno = StenoWord::parse(steno).unwrap().0;
+        let mut actions: Vec<_> = steno.iter().flat_map(|&st| lookup.add(st)).collect();
+        actions.extend(lookup.flush());
+
+        let mut text = String::new();
+        let mut deleted = 0;
+        for action in actions {
+            joiner.add(action);
+            while let Some(action) = joiner.pop(0) {
+                match action {
+                    Joined::Type { remove, append } => {
+                        for _ in 0..remove {
+                            text.pop();
+                        }
+                        deleted += remove;
+                        text.push_str(&append);
+                    }
+                    Joined::Raw(keys) => panic!("Unexpected raw keys: {}", keys),
+                    Joined::Command(command) => panic!("Unexpected command: {:?}", command),
+                    other => panic!("Unexpected action: {:?}", other),
+                }
+            }
+        }
+        (text, deleted)
+    };
+
+    for (steno, text, deleted) in corpus {
+        // Both strategies type the same thing, but holding for the longest match never has to
+        // correct a translation.  Even the undo only takes back a word that was still held.
+        assert_eq!(run(steno, Strategy::Eager), (text.to_string(), deleted), "eager {}", steno);
+        assert_eq!(run(steno, Strategy::Longest), (text.to_string(), 0), "longest {}", steno);
+    }
+}
+
+fn raw(strokes: &[Stroke]) -> Vec<u32> {
+    strokes.iter().map(|st| st.into_raw()).collect()
+}
+
+/// Patch an image, gathering the pieces written into a new one.
+fn patch_image(image: &[u32], patch: &DictPatch) -> Option<Vec<u8>> {
+    let mut patched = Vec::new();
+    let size = unsafe {
+        MemDict::patch_group(image.as_ptr() as *const u8, patch, &mut |offset, data| {
+            let end = offset as usize + data.len();
+            if patched.len() < end {
+                patched.resize(end, 0xff);
+            }
+            patched[offset as usize..end].copy_from_slice(data);
+            true
+        })
+    }?;
+    assert_eq!(size as usize, patched.len());
+    Some(patched)
+}
+
+/// Copy an image into words, as the tables in it are read as words.
+fn aligned(image: &[u8]) -> Vec<u32> {
+    image.chunks(4).map(|w| {
+        let mut word = [0xff; 4];
+        word[..w.len()].copy_from_slice(w);
+        u32::from_ne_bytes(word)
+    }).collect()
+}
+
 /*
 #[test]
 fn simple_dict() {
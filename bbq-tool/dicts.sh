@@ -7,9 +7,6 @@ set -e
 
 uf2conv=$ZEPHYR_BASE/scripts/build/uf2conv.py
 
-main_addr=0x10300000
-user_addr=0x10200000
-
 cargo run -- build -o dicts.bin \
 	~/plover/phoenix.rtf \
 	~/plover/phoenix_fix.json \
@@ -20,6 +17,12 @@ cargo run -- build -o user-dict.bin \
 	~/plover/user.json \
 	~/plover/rust.yaml
 
+# The addresses come from the shared partition map, which also checks that the dictionaries fit.
+# The main dictionary can only be as big as the board's flash allows.
+flash_mib=${FLASH_MIB:-8}
+main_addr=$(cargo run -q -- partition main-dict --flash-mib $flash_mib --check dicts.bin)
+user_addr=$(cargo run -q -- partition user-dict --flash-mib $flash_mib --check user-dict.bin)
+
 # Full is used by host tools.
 cargo run -- build -o full.bin \
 	~/plover/phoenix.rtf \
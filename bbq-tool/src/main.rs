@@ -9,17 +9,25 @@
 use bbq_keyboard::Side;
 use clap::{Parser, Subcommand};
 
-use anyhow::Result;
-use encode::DictBuilder;
-use minicbor::encode::write::Writer;
+use anyhow::{anyhow, Result};
 
-use std::{collections::BTreeMap, fs::File};
-use bbq_steno::{memdict::MemDict, stroke::StenoWord};
-use bbq_keyboard::boardinfo::BoardInfo;
+use std::collections::BTreeMap;
+use bbq_steno::{memdict::{DictBuilder, DictPatch, MemDict, PatchChange}, stroke::StenoWord};
+use bbq_keyboard::backlight::Backlight;
+use bbq_keyboard::boardinfo::{BoardInfo, DebounceGroup, Features, ModeChord, Setting, Snippet};
+use bbq_keyboard::debounce::Algorithm;
+use bbq_keyboard::encoder::{EncoderAction, EncoderBinding};
+use bbq_keyboard::expand::MAX_TRIGGER;
+use bbq_keyboard::layout::LayoutMode;
+use bbq_keyboard::ser2::LinkKey;
+use minder::partition;
 
 mod rtfcre;
 mod jsondict;
-mod encode;
+mod merge;
+mod stats;
+
+use merge::{Merge, Prefer};
 
 #[derive(Parser)]
 #[command(name = "MyProgram")]
@@ -30,6 +38,8 @@ struct Cli {
 }
 
 #[derive(Subcommand)]
+// Only ever one of these, parsed from the command line.
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Build the specified files into the output
     Build {
@@ -37,17 +47,61 @@ enum Commands {
         #[arg(short, long, value_name = "FILE")]
         output: String,
 
+        /// Which dictionary keeps an entry that is in more than one.  The others drop it.
+        /// Internal dictionaries aren't checked.
+        #[arg(long, value_enum, default_value_t = Prefer::Last)]
+        prefer: Prefer,
+
+        /// List every conflicting entry, not just how many there are.
+        #[arg(long)]
+        show_conflicts: bool,
+
+        /// Add a first-stroke index to each dictionary, for faster lookups on the keyboard, at the
+        /// cost of a larger image.
+        #[arg(long)]
+        index: bool,
+
         /// Input files to build.  Use `+name` to represent an internal dictionary.
         #[arg(required = true)]
         files: Vec<String>,
     },
 
+    /// Write a patch with the changes from one version of a dictionary to another, to send to the
+    /// keyboard instead of the whole image.
+    Patch {
+        /// Output file
+        #[arg(short, long, value_name = "FILE")]
+        output: String,
+
+        /// The name of the dictionary in the image.  Defaults to the name build gives the new
+        /// file.
+        #[arg(long)]
+        name: Option<String>,
+
+        /// The dictionary as it was built into the image.
+        old: String,
+
+        /// The changed dictionary.
+        new: String,
+    },
+
     /// Show the contents of the specified file
     Show {
         /// The file to show
         filename: String,
     },
 
+    /// Report what takes the space in a dictionary image: the size of each section, the longest
+    /// outlines, and translations stored more than once
+    Stats {
+        /// The image, as written by build
+        filename: String,
+
+        /// How many of the most repeated translations to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+
     /// Generate a buildinfo record.
     BoardInfo {
         /// Output file
@@ -61,6 +115,140 @@ enum Commands {
         /// The side info.
         #[arg(long)]
         side: Option<Side>,
+
+        /// Disable automatic mode selection based on host activity.
+        #[arg(long)]
+        no_auto_mode: bool,
+
+        /// Type critical alerts to the host, prefixed with this text.  Off if not given.
+        #[arg(long)]
+        notify: Option<String>,
+
+        /// Return to the default mode after this many seconds without a key being touched.
+        #[arg(long, value_name = "SECONDS")]
+        idle_timeout: Option<u32>,
+
+        /// Authenticate the link between the halves with this key, given as 32 hex digits.  Use
+        /// the same key for both sides.
+        #[arg(long, value_name = "HEX", value_parser = parse_link_key)]
+        link_key: Option<LinkKey>,
+
+        /// Limit typed output to this many HID reports per second, to keep a runaway translation
+        /// from flooding the host.
+        #[arg(long, value_name = "REPORTS")]
+        output_rate: Option<u32>,
+
+        /// Pulse this LED when a shorter outline is available for what was just written in steno.
+        #[arg(long, value_name = "INDEX")]
+        brief_led: Option<u8>,
+
+        /// Use this scan code as the mode key, instead of the default.
+        #[arg(long, value_name = "SCAN")]
+        mode_key: Option<u8>,
+
+        /// Select a mode when these keys are pressed with the mode key, given as scan codes and a
+        /// mode name, such as "20,21=taipo".  The name can also be an experimental mode built into
+        /// the firmware.  Can be given more than once.
+        #[arg(long, value_name = "KEYS=MODE", value_parser = parse_mode_chord)]
+        mode_chord: Vec<ModeChord>,
+
+        /// In the keyboard modes, replace TRIGGER with TEXT when it is typed, such as
+        /// ";sig=Best regards".  A "\n" in the text is a newline.  Can be given more than once.
+        #[arg(long, value_name = "TRIGGER=TEXT", value_parser = parse_snippet)]
+        snippet: Vec<Snippet>,
+
+        /// Debounce these keys for this many milliseconds, instead of the default, given as scan
+        /// codes and a time, such as "15,19,39,43=30".  Can be given more than once.
+        #[arg(long, value_name = "KEYS=MS", value_parser = parse_debounce)]
+        debounce: Vec<DebounceGroup>,
+
+        /// How keys are debounced, instead of the default, deferred.
+        #[arg(long, value_enum)]
+        debounce_algorithm: Option<Algorithm>,
+
+        /// The debounce time, in milliseconds, for keys not given with --debounce, instead of the
+        /// default of 20.
+        #[arg(long, value_name = "MS")]
+        debounce_ms: Option<u8>,
+
+        /// Only offer a boot keyboard over USB, leaving out the steno, minder and mouse
+        /// interfaces, for a BIOS or KVM that can't cope with them.
+        #[arg(long)]
+        boot_keyboard: bool,
+
+        /// Send each steno stroke only once all of its keys are released (last up), instead of as
+        /// soon as the first one is (first up).
+        #[arg(long)]
+        last_up: bool,
+
+        /// Per-key backlight LEDs, given as the index of the first one and the scan code under
+        /// each, in order along the strip, such as "3:0,1,2,3".
+        #[arg(long, value_name = "FIRST:KEYS", value_parser = parse_backlight)]
+        backlight: Option<Backlight>,
+
+        /// A setting for an experimental layout mode, given as the mode's name and the setting,
+        /// such as "chorder.timeout=200".  Can be given more than once.
+        #[arg(long, value_name = "MODE.KEY=VALUE", value_parser = parse_setting)]
+        setting: Vec<Setting>,
+
+        /// What the rotary encoder does in a mode, or in any mode not given with "*": "volume",
+        /// "modes" to cycle through the modes, "none", or "keys:CW,CCW" with HID usage codes, such
+        /// as "qwerty=keys:79,80".  The default is volume.  Can be given more than once.
+        #[arg(long, value_name = "MODE=ACTION", value_parser = parse_encoder)]
+        encoder: Vec<EncoderBinding>,
+
+        /// The steps of the encoder's signals for each detent, instead of the default of 4.
+        #[arg(long, value_name = "STEPS")]
+        encoder_steps: Option<u8>,
+
+        /// The HID usage code of the host's compose key, such as 101 for the menu key, to type
+        /// characters the host's keyboard layout doesn't have as compose sequences.
+        #[arg(long, value_name = "CODE")]
+        compose_key: Option<u8>,
+
+        /// Sleep after this many seconds on battery without a key being touched, instead of the
+        /// default of ten minutes.  Zero never sleeps.
+        #[arg(long, value_name = "SECONDS")]
+        sleep_timeout: Option<u32>,
+
+        /// Only scan this many rows of the key matrix, for a board with fewer than its devicetree
+        /// gives.
+        #[arg(long)]
+        rows: Option<u8>,
+
+        /// Only scan this many columns of the key matrix.
+        #[arg(long)]
+        cols: Option<u8>,
+
+        /// The scan code of each key, by the code the matrix gives it, with "-" for a position
+        /// with no key, such as "2,1,4,-,5".  The right half follows the left.  The default is
+        /// the table built into the firmware for the board's name.
+        #[arg(long, value_name = "CODES", value_parser = parse_translate)]
+        translate: Option<Codes>,
+
+        /// How many LEDs the board has, leaving any others the firmware finds off.
+        #[arg(long, value_name = "COUNT")]
+        leds: Option<u8>,
+
+        /// A feature of the board: "two-row" for a board without the rows qwerty needs.  Can be
+        /// given more than once.  Giving any replaces those known for the board's name.
+        #[arg(long, value_name = "NAME", value_parser = parse_feature)]
+        feature: Vec<Features>,
+    },
+
+    /// Print the flash address of a partition, for use by scripts
+    Partition {
+        /// The partition name (boardinfo, user-dict, main-dict)
+        name: String,
+
+        /// Fail if this file will not fit in the partition
+        #[arg(long, value_name = "FILE")]
+        check: Option<String>,
+
+        /// The size of the board's flash, in MiB.  The main dictionary fills what there is; the
+        /// default is the smallest of the boards we support.
+        #[arg(long, default_value_t = 8)]
+        flash_mib: u32,
     },
 }
 
@@ -68,26 +256,42 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Build { output, files } => {
+        Commands::Build { output, prefer, show_conflicts, index, files } => {
             println!("Building files: {:?}", files);
+            let loaded: Vec<&String> = files.iter().filter(|f| !f.starts_with('+')).collect();
+            let mut dicts = loaded.iter().map(|f| load_dict(f)).collect::<Result<Vec<_>>>()?;
+            let names: Vec<String> = loaded.iter().map(|f| f.to_string()).collect();
+            report_merge(&merge::merge(&mut dicts, &names, *prefer)?, &names, *show_conflicts);
+
             let mut build = DictBuilder::new();
+            build.set_index(*index);
+            let mut dicts = dicts.into_iter();
             for f in files {
                 if f.starts_with('+') {
                     let mut iter = f.chars();
                     iter.next();
                     build.add_builtin(iter.as_str());
                 } else {
-                    let dict = load_dict(f)?;
-                    build.add(&dict);
+                    let dict = dicts.next().unwrap();
+                    let name = dict_name(f);
+                    if !build.add(&name, dict.iter().map(|(k, v)| (k.0.as_slice(), v.as_str()))) {
+                        return Err(anyhow!("{} has an entry too long to encode", f));
+                    }
                 }
             }
 
             println!("Output will be written to: {}", output);
 
-            // For now, just concatenate them, but really need a helper to
-            // put the group header.
-            let mut fd = File::create(output)?;
-            build.write_group(&mut fd)?;
+            let image = build.into_image()
+                .ok_or_else(|| anyhow!("Too many dictionaries for the group header"))?;
+            std::fs::write(output, image)?;
+        }
+        Commands::Patch { output, name, old, new } => {
+            let name = name.clone().unwrap_or_else(|| dict_name(new));
+            let patch = DictPatch { name, changes: diff(&load_dict(old)?, &load_dict(new)?) };
+            let data = minicbor::to_vec(&patch)?;
+            println!("{} changes to {:?}, {} bytes", patch.changes.len(), patch.name, data.len());
+            std::fs::write(output, data)?;
         }
         Commands::Show { filename } => {
             println!("Showing file: {}", filename);
@@ -102,20 +306,120 @@ fn main() -> Result<()> {
                 println!("text 0 {:?}", dict.value(0));
             }
         }
-        Commands::BoardInfo { output, name, side } => {
+        Commands::Stats { filename, top } => {
+            let image = std::fs::read(filename)?;
+            let (dicts, builtins) = stats::gather(&image)?;
+            stats::report(&dicts, &builtins, image.len(), *top);
+        }
+        Commands::BoardInfo { output, name, side, no_auto_mode, notify, idle_timeout, link_key, output_rate, brief_led,
+                              mode_key, mode_chord, snippet, debounce, debounce_algorithm, debounce_ms,
+                              boot_keyboard, last_up, backlight, setting, encoder, encoder_steps,
+                              compose_key, sleep_timeout, rows, cols, translate, leds, feature } => {
             let info = BoardInfo {
                 name: name.to_string(),
                 side: side.clone(),
+                auto_mode: if *no_auto_mode { Some(false) } else { None },
+                notify: notify.clone(),
+                idle_timeout: *idle_timeout,
+                link_key: *link_key,
+                output_rate: *output_rate,
+                brief_led: *brief_led,
+                mode_key: *mode_key,
+                mode_chords: if mode_chord.is_empty() { None } else { Some(mode_chord.clone()) },
+                snippets: if snippet.is_empty() { None } else { Some(snippet.clone()) },
+                debounce: if debounce.is_empty() { None } else { Some(debounce.clone()) },
+                boot_keyboard: if *boot_keyboard { Some(true) } else { None },
+                steno_first_up: if *last_up { Some(false) } else { None },
+                backlight: backlight.clone(),
+                settings: if setting.is_empty() { None } else { Some(setting.clone()) },
+                debounce_algorithm: *debounce_algorithm,
+                debounce_millis: *debounce_ms,
+                encoder: if encoder.is_empty() { None } else { Some(encoder.clone()) },
+                encoder_steps: *encoder_steps,
+                compose_key: *compose_key,
+                sleep_timeout: *sleep_timeout,
+                rows: *rows,
+                cols: *cols,
+                translate: translate.clone(),
+                leds: *leds,
+                features: feature.iter().copied().reduce(|a, b| a | b),
             };
 
-            let fd = File::create(output)?;
-            minicbor::encode(&info, Writer::new(fd))?;
+            // The firmware only reads the board info partition, so it all has to fit there.
+            let data = minicbor::to_vec(&info)?;
+            let part = partition::BOARD_INFO;
+            if data.len() > part.size as usize {
+                return Err(anyhow!("Board info is {} bytes, partition {} only holds {}",
+                                   data.len(), part.name, part.size));
+            }
+            std::fs::write(output, data)?;
+        }
+        Commands::Partition { name, check, flash_mib } => {
+            let part = partition::by_name(name)
+                .ok_or_else(|| anyhow!("Unknown partition: {:?}", name))?;
+            let flash = partition::Flash {
+                base: partition::FLASH_BASE as usize,
+                size: flash_mib * 1024 * 1024,
+            };
+            let part = flash.fit(part)
+                .ok_or_else(|| anyhow!("Partition {} is past the end of {} MiB of flash", name, flash_mib))?;
+            if let Some(check) = check {
+                let size = std::fs::metadata(check)?.len();
+                if size > part.size as u64 {
+                    return Err(anyhow!("{} is 0x{:x} bytes, partition {} only holds 0x{:x}",
+                                       check, size, part.name, part.size));
+                }
+            }
+            println!("0x{:08x}", part.address());
         }
     }
 
     Ok(())
 }
 
+/// The name a dictionary is given in the image, from its file.
+fn dict_name(file: &str) -> String {
+    std::path::Path::new(file)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file.to_string())
+}
+
+/// Show what merging the dictionaries found.
+fn report_merge(merge: &Merge, names: &[String], show_conflicts: bool) {
+    if merge.duplicates > 0 {
+        println!("Dropped {} duplicate entries", merge.duplicates);
+    }
+
+    // Conflicts are summarized by which dictionary overrode which.
+    let mut pairs: BTreeMap<(usize, usize), usize> = BTreeMap::new();
+    for conflict in &merge.conflicts {
+        *pairs.entry((conflict.kept.0, conflict.dropped.0)).or_default() += 1;
+        if show_conflicts {
+            println!("  {}: {:?} from {} over {:?} from {}",
+                     conflict.strokes, conflict.kept.1, names[conflict.kept.0],
+                     conflict.dropped.1, names[conflict.dropped.0]);
+        }
+    }
+    for ((kept, dropped), count) in pairs {
+        println!("{} entries in {} override {}", count, names[kept], names[dropped]);
+    }
+}
+
+/// The changes that turn `old` into `new`.
+fn diff(old: &BTreeMap<StenoWord, String>, new: &BTreeMap<StenoWord, String>) -> Vec<PatchChange> {
+    let raw = |k: &StenoWord| k.0.iter().map(|st| st.into_raw()).collect();
+    let removed = old
+        .keys()
+        .filter(|k| !new.contains_key(k))
+        .map(|k| PatchChange::Remove { strokes: raw(k) });
+    let set = new
+        .iter()
+        .filter(|(k, v)| old.get(k) != Some(v))
+        .map(|(k, v)| PatchChange::Set { strokes: raw(k), text: v.clone() });
+    removed.chain(set).collect()
+}
+
 /// Attempt to load the given dictionary.
 ///
 /// Loads the dictionary, based on the given type.  It is up to each loader to translate from that
@@ -143,3 +447,119 @@ fn load_yaml(name: &str) -> Result<BTreeMap<StenoWord, String>> {
 fn load_rtf(name: &str) -> Result<BTreeMap<StenoWord, String>> {
     rtfcre::import(name)
 }
+
+/// Parse a link key from hex.
+fn parse_link_key(text: &str) -> Result<LinkKey> {
+    if text.len() != 32 || !text.is_ascii() {
+        return Err(anyhow!("Link key must be 32 hex digits"));
+    }
+    let mut key = LinkKey::default();
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(key)
+}
+
+fn parse_mode_chord(text: &str) -> Result<ModeChord> {
+    let (keys, mode) = text
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Mode chord must be KEYS=MODE"))?;
+    // Experimental modes are only known to the firmware they are built into, which ignores chords
+    // for modes it doesn't have.
+    if LayoutMode::from_name(mode).is_none() {
+        eprintln!("Warning: {:?} isn't a built-in mode, it must be an experimental one", mode);
+    }
+    let keys = keys
+        .split(',')
+        .map(|k| k.trim().parse::<u8>())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(ModeChord { keys, mode: mode.to_string() })
+}
+
+fn parse_debounce(text: &str) -> Result<DebounceGroup> {
+    let (keys, millis) = text
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Debounce must be KEYS=MS"))?;
+    let keys = keys
+        .split(',')
+        .map(|k| k.trim().parse::<u8>())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(DebounceGroup { keys, millis: millis.trim().parse()? })
+}
+
+fn parse_backlight(text: &str) -> Result<Backlight> {
+    let (first, keys) = text
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Backlight must be FIRST:KEYS"))?;
+    let keys = keys
+        .split(',')
+        .map(|k| k.trim().parse::<u8>())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(Backlight { first_led: first.trim().parse()?, keys })
+}
+
+/// A list of scan codes.  Not spelled as a `Vec`, so clap takes it as one value.
+type Codes = Vec<u8>;
+
+fn parse_translate(text: &str) -> Result<Codes> {
+    text.split(',')
+        .map(|code| match code.trim() {
+            "-" => Ok(255),
+            code => Ok(code.parse::<u8>()?),
+        })
+        .collect()
+}
+
+fn parse_feature(text: &str) -> Result<Features> {
+    Features::from_name(text).ok_or_else(|| {
+        let names: Vec<_> = Features::NAMES.iter().map(|(name, _)| *name).collect();
+        anyhow!("Unknown feature {:?}, expecting one of {}", text, names.join(", "))
+    })
+}
+
+fn parse_setting(text: &str) -> Result<Setting> {
+    let (key, value) = text
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Setting must be MODE.KEY=VALUE"))?;
+    if !key.contains('.') {
+        return Err(anyhow!("Setting {:?} must start with the mode's name", key));
+    }
+    Ok(Setting { key: key.to_string(), value: value.to_string() })
+}
+
+fn parse_encoder(text: &str) -> Result<EncoderBinding> {
+    let (mode, action) = text
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Encoder must be MODE=ACTION"))?;
+    let mode = if mode == "*" {
+        None
+    } else {
+        if LayoutMode::from_name(mode).is_none() {
+            eprintln!("Warning: {:?} isn't a built-in mode, it must be an experimental one", mode);
+        }
+        Some(mode.to_string())
+    };
+    let action = match action {
+        "none" => EncoderAction::None,
+        "volume" => EncoderAction::Volume,
+        "modes" => EncoderAction::Modes,
+        _ => {
+            let (cw, ccw) = action
+                .strip_prefix("keys:")
+                .and_then(|keys| keys.split_once(','))
+                .ok_or_else(|| anyhow!("Unknown encoder action {:?}", action))?;
+            EncoderAction::Keys { cw: cw.trim().parse()?, ccw: ccw.trim().parse()? }
+        }
+    };
+    Ok(EncoderBinding { mode, action })
+}
+
+fn parse_snippet(text: &str) -> Result<Snippet> {
+    let (trigger, text) = text
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Snippet must be TRIGGER=TEXT"))?;
+    if trigger.is_empty() || trigger.chars().count() > MAX_TRIGGER {
+        return Err(anyhow!("Snippet trigger must be 1 to {} characters", MAX_TRIGGER));
+    }
+    Ok(Snippet { trigger: trigger.to_string(), text: text.replace("\\n", "\n") })
+}
@@ -0,0 +1,96 @@
+//! Merging dictionaries.
+//!
+//! The keyboard looks words up in every dictionary of a group, with later dictionaries overriding
+//! earlier ones.  An entry that is overridden is never used, but still takes up flash, and it is
+//! easy to override one without meaning to.  Before building, the entries that appear in more than
+//! one dictionary are found, reported, and only kept in the dictionary that wins.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use bbq_steno::stroke::StenoWord;
+use clap::ValueEnum;
+
+/// Which dictionary keeps an entry found in more than one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Prefer {
+    /// The earliest dictionary given.
+    First,
+    /// The latest dictionary given, which is what the keyboard would use anyway.
+    Last,
+    /// Refuse to build if any entries differ.
+    Error,
+}
+
+/// An entry given different translations by two dictionaries.
+#[derive(Debug)]
+pub struct Conflict {
+    pub strokes: StenoWord,
+    /// The dictionary whose translation is kept, and that translation.
+    pub kept: (usize, String),
+    /// The dictionary whose translation is dropped, and that translation.
+    pub dropped: (usize, String),
+}
+
+/// The result of a merge.
+#[derive(Debug, Default)]
+pub struct Merge {
+    /// Entries with the same translation in more than one dictionary.
+    pub duplicates: usize,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Remove the entries from `dicts` that appear in an earlier or later one, depending on `prefer`,
+/// so that each is only in one dictionary.  `names` is only used for the error.
+pub fn merge(
+    dicts: &mut [BTreeMap<StenoWord, String>],
+    names: &[String],
+    prefer: Prefer,
+) -> Result<Merge> {
+    let mut result = Merge::default();
+
+    // Which dictionary each entry is to be kept in.
+    let mut owners: BTreeMap<StenoWord, usize> = BTreeMap::new();
+    for (index, dict) in dicts.iter().enumerate() {
+        for strokes in dict.keys() {
+            let Some(owner) = owners.get_mut(strokes) else {
+                owners.insert(strokes.clone(), index);
+                continue;
+            };
+            let (kept, dropped) = match prefer {
+                Prefer::First => (*owner, index),
+                Prefer::Last | Prefer::Error => (index, *owner),
+            };
+            let kept_text = &dicts[kept][strokes];
+            let dropped_text = &dicts[dropped][strokes];
+            if kept_text == dropped_text {
+                result.duplicates += 1;
+            } else {
+                result.conflicts.push(Conflict {
+                    strokes: strokes.clone(),
+                    kept: (kept, kept_text.clone()),
+                    dropped: (dropped, dropped_text.clone()),
+                });
+            }
+            *owner = kept;
+        }
+    }
+
+    if prefer == Prefer::Error && !result.conflicts.is_empty() {
+        let first = &result.conflicts[0];
+        return Err(anyhow!(
+            "{} conflicting entries, the first is {}: {:?} in {}, {:?} in {}",
+            result.conflicts.len(),
+            first.strokes,
+            first.dropped.1,
+            names[first.dropped.0],
+            first.kept.1,
+            names[first.kept.0],
+        ));
+    }
+
+    for (index, dict) in dicts.iter_mut().enumerate() {
+        dict.retain(|strokes, _| owners[strokes] == index);
+    }
+    Ok(result)
+}
@@ -0,0 +1,189 @@
+//! Dictionary statistics.
+//!
+//! A built image has to fit in the dictionary partition of the keyboard.  This reports how big each
+//! dictionary in an image is, section by section, along with what takes the space: how many
+//! entries, the longest outlines, and the translations given to more than one outline.  Images
+//! built now store each translation once, and the report gives what that saves.  Older images
+//! store each entry's text on its own, and the report gives what sharing it would save.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use bbq_steno::{
+    dict::DictImpl,
+    memdict::{GroupEntry, MemDict, RawMemDict, HEADER_MAX_BYTES},
+    stroke::StenoWord,
+};
+
+/// The size of each section of one dictionary, in bytes.
+#[derive(Debug, Default)]
+pub struct Sections {
+    pub keys: u32,
+    pub key_table: u32,
+    pub text: u32,
+    pub text_table: u32,
+    pub affixes: u32,
+    pub index: u32,
+}
+
+impl Sections {
+    fn new(raw: &RawMemDict) -> Sections {
+        Sections {
+            keys: raw.keys_length,
+            key_table: raw.size * 4,
+            text: raw.text_length,
+            text_table: raw.size * 4,
+            affixes: if raw.affix_offset.is_some() { raw.size } else { 0 },
+            index: raw.index.as_ref().map(|index| index.buckets * 4 + index.slots * 8).unwrap_or(0),
+        }
+    }
+
+    pub fn total(&self) -> u32 {
+        self.keys + self.key_table + self.text + self.text_table + self.affixes + self.index
+    }
+}
+
+/// What is in one dictionary.
+#[derive(Debug)]
+pub struct Stats {
+    pub name: String,
+    pub entries: usize,
+    /// The strokes in every outline.
+    pub strokes: usize,
+    /// The longest outline, and its translation.
+    pub longest: Option<(StenoWord, String)>,
+    pub sections: Sections,
+    /// Translations given to more than one outline, most repeated first, with how many.
+    pub repeated: Vec<(String, usize)>,
+    /// The text of every entry, as it would be stored without any sharing.
+    pub text_bytes: usize,
+}
+
+impl Stats {
+    fn new(dict: &MemDict) -> Stats {
+        let raw = &dict.raw;
+        let mut strokes = 0;
+        let mut longest: Option<usize> = None;
+        let mut texts: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut text_bytes = 0;
+        for i in 0..dict.len() {
+            let key = dict.key(i);
+            strokes += key.len();
+            text_bytes += dict.value(i).len();
+            if longest.map(|l| key.len() > dict.key(l).len()).unwrap_or(true) {
+                longest = Some(i);
+            }
+            *texts.entry(dict.value(i)).or_default() += 1;
+        }
+
+        // Most repeated first, and in text order among the same count.
+        let mut repeated: Vec<_> = texts
+            .into_iter()
+            .filter(|&(_, count)| count > 1)
+            .map(|(text, count)| (text.to_string(), count))
+            .collect();
+        repeated.sort_by_key(|&(_, count)| Reverse(count));
+
+        Stats {
+            name: raw.name.clone().unwrap_or_default(),
+            entries: dict.len(),
+            strokes,
+            longest: longest.map(|i| (StenoWord(dict.key(i).to_vec()), dict.value(i).to_string())),
+            sections: Sections::new(raw),
+            repeated,
+            text_bytes,
+        }
+    }
+
+    /// The bytes of text that wouldn't be needed if each translation were only stored once.
+    pub fn dedup_savings(&self) -> usize {
+        self.repeated.iter().map(|(text, count)| text.len() * (count - 1)).sum()
+    }
+
+    /// The bytes of text saved by the image sharing it, zero for an image that doesn't.  This is
+    /// less the padding of the text section.
+    pub fn shared(&self) -> usize {
+        self.text_bytes.saturating_sub(self.sections.text as usize)
+    }
+}
+
+/// Gather the statistics of each memory dictionary in an image, and the names of the builtin
+/// dictionaries it uses.
+pub fn gather(image: &[u8]) -> Result<(Vec<Stats>, Vec<String>)> {
+    if image.len() < HEADER_MAX_BYTES {
+        return Err(anyhow!("Too short for a dictionary image"));
+    }
+    // The tables are read as words, so the image needs to be aligned.
+    let words: Vec<u32> = image
+        .chunks(4)
+        .map(|w| {
+            let mut word = [0xff; 4];
+            word[..w.len()].copy_from_slice(w);
+            u32::from_ne_bytes(word)
+        })
+        .collect();
+    let base = words.as_ptr() as *const u8;
+
+    let entries = unsafe { MemDict::entries(base) };
+    if entries.is_empty() {
+        return Err(anyhow!("Not a dictionary image"));
+    }
+
+    let mut stats = Vec::new();
+    let mut builtins = Vec::new();
+    for entry in entries {
+        match entry {
+            GroupEntry::Memory(raw) => {
+                if raw.extent().end as usize > image.len() {
+                    return Err(anyhow!("Dictionary {:?} runs past the end of the image", raw.name));
+                }
+                let dict = unsafe { MemDict::decode_single(base, raw) }
+                    .ok_or_else(|| anyhow!("Invalid dictionary"))?;
+                stats.push(Stats::new(&dict));
+            }
+            GroupEntry::Builtin(name) => builtins.push(name),
+        }
+    }
+    Ok((stats, builtins))
+}
+
+/// Print the statistics, with the `top` most repeated translations of each dictionary.
+pub fn report(stats: &[Stats], builtins: &[String], image_len: usize, top: usize) {
+    for dict in stats {
+        println!("Dictionary {:?}: {} entries, {} strokes", dict.name, dict.entries, dict.strokes);
+        if let Some((outline, text)) = &dict.longest {
+            println!("  Longest outline: {} strokes, {} {:?}", outline.0.len(), outline, text);
+        }
+
+        let sections = &dict.sections;
+        println!("  Keys:        {:>9} bytes", sections.keys);
+        println!("  Key table:   {:>9} bytes", sections.key_table);
+        println!("  Text:        {:>9} bytes", sections.text);
+        println!("  Text table:  {:>9} bytes", sections.text_table);
+        if sections.affixes > 0 {
+            println!("  Affixes:     {:>9} bytes", sections.affixes);
+        }
+        if sections.index > 0 {
+            println!("  Index:       {:>9} bytes", sections.index);
+        }
+        println!("  Total:       {:>9} bytes", sections.total());
+
+        if !dict.repeated.is_empty() {
+            println!("  Most repeated translations:");
+            for (text, count) in dict.repeated.iter().take(top) {
+                println!("    {:>6} {:?}", count, text);
+            }
+        }
+        if dict.shared() > 0 {
+            println!("  Sharing text saves {} bytes", dict.shared());
+        } else if !dict.repeated.is_empty() {
+            println!("  Storing each translation once would save {} bytes of text, from {} translations",
+                     dict.dedup_savings(), dict.repeated.len());
+        }
+    }
+    for name in builtins {
+        println!("Builtin dictionary {:?}", name);
+    }
+    println!("Image: {} bytes", image_len);
+}
@@ -0,0 +1,251 @@
+//! Regression corpus.
+//!
+//! A corpus is a set of stroke sequences, along with the text that Plover produces for them with
+//! the same dictionary.  Each one is run through our engine, and the differences are reported,
+//! sorted by the likely cause, so we can see which gaps in the engine matter most.
+//!
+//! The corpus file has one case per line, the strokes separated by '/', a tab, and then Plover's
+//! output.  Newlines and tabs in the output are written as `\n` and `\t`, and a backslash as
+//! `\\`.  Blank lines, and lines starting with '#' are ignored.
+//!
+//! The cases can also be run with each lookup [`Strategy`], to see what holding translations for
+//! a longer match changes: how many characters are typed and then deleted again, and which cases
+//! come out differently.
+//!
+//! Each case starts with a fresh engine, which capitalizes the first word and doesn't put a space
+//! before it.  Record the output with Plover's "start capitalized" and "start attached" options
+//! set to match.
+//!
+//! For example, with the tab shown as `<TAB>`:
+//!
+//! ```text
+//! # Suffixes
+//! TEFT/-G<TAB>testing
+//! ```
+
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use bbq_steno::{dict::{Dict, Joined, Joiner, Lookup, Strategy}, Stroke};
+
+/// A single case from the corpus.
+pub struct Case {
+    /// The line in the corpus file, for reporting.
+    line: usize,
+    steno: Vec<Stroke>,
+    expect: String,
+}
+
+/// The result of running a case that didn't match.
+pub struct Failure<'a> {
+    case: &'a Case,
+    got: String,
+    cause: Cause,
+}
+
+/// What the engine typed for a case.
+struct Output {
+    text: String,
+    /// Whether any raw keys or commands were given.
+    commands: bool,
+    /// The number of characters deleted along the way, to replace earlier translations.
+    deleted: usize,
+}
+
+/// Why we think the output differs.  This is a guess based on how the text differs, and what
+/// the engine did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Cause {
+    /// The engine produced raw keys or commands, or the text differs only in case, which is
+    /// controlled by commands such as `{-|}`.
+    Command,
+    /// The words are right, but the spacing between them differs, from attach or glue.
+    Glue,
+    /// A word has the right stem, but a different ending, from suffix rules.
+    Orthography,
+    /// Anything else, usually a different or missing translation.
+    Other,
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Cause::Command => "command handling",
+            Cause::Glue => "glue",
+            Cause::Orthography => "orthography",
+            Cause::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Load the cases from a corpus file.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Case>> {
+    let text = fs::read_to_string(path)?;
+    let mut cases = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        let line_num = num + 1;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (steno, expect) = line
+            .split_once('\t')
+            .ok_or_else(|| anyhow!("Line {}: expecting strokes, a tab, and text", line_num))?;
+        let steno = steno
+            .split('/')
+            .map(Stroke::from_text)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("Line {}: {}", line_num, e))?;
+        cases.push(Case { line: line_num, steno, expect: unescape(expect) });
+    }
+    Ok(cases)
+}
+
+/// Run all of the cases, and print a report of the ones that differ.  Returns the number that
+/// differ.
+pub fn run(cases: &[Case], dicts: Vec<Dict>, strategy: Strategy) -> usize {
+    let failures: Vec<_> = cases.iter().filter_map(|case| case.check(dicts.clone(), strategy)).collect();
+
+    let mut by_cause: BTreeMap<Cause, Vec<&Failure>> = BTreeMap::new();
+    for failure in &failures {
+        by_cause.entry(failure.cause).or_default().push(failure);
+    }
+
+    for (cause, failures) in &by_cause {
+        println!("{} ({}):", cause, failures.len());
+        for failure in failures {
+            let steno: Vec<_> = failure.case.steno.iter().map(|s| s.to_string()).collect();
+            println!("  line {}: {}", failure.case.line, steno.join("/"));
+            println!("    expect: {:?}", failure.case.expect);
+            println!("       got: {:?}", failure.got);
+        }
+    }
+
+    println!("{} cases, {} passed", cases.len(), cases.len() - failures.len());
+    for (cause, failures) in &by_cause {
+        println!("  {:>6} {}", failures.len(), cause);
+    }
+    failures.len()
+}
+
+/// Run all of the cases with each strategy, and print how the results differ.
+pub fn compare(cases: &[Case], dicts: Vec<Dict>) {
+    let mut passed = [0usize; 2];
+    let mut deleted = [0usize; 2];
+    let mut corrected = [0usize; 2];
+    let mut differ = 0;
+
+    for case in cases {
+        let eager = case.run(dicts.clone(), Strategy::Eager);
+        let longest = case.run(dicts.clone(), Strategy::Longest);
+        for (i, out) in [&eager, &longest].into_iter().enumerate() {
+            if out.text == case.expect {
+                passed[i] += 1;
+            }
+            deleted[i] += out.deleted;
+            if out.deleted > 0 {
+                corrected[i] += 1;
+            }
+        }
+        if eager.text != longest.text {
+            differ += 1;
+            let steno: Vec<_> = case.steno.iter().map(|s| s.to_string()).collect();
+            println!("line {}: {}", case.line, steno.join("/"));
+            println!("    eager: {:?}", eager.text);
+            println!("  longest: {:?}", longest.text);
+        }
+    }
+
+    println!("{} cases, {} with different output", cases.len(), differ);
+    println!("  {:<8} {:>8} {:>10} {:>8}", "", "passed", "corrected", "deleted");
+    for (i, name) in ["eager", "longest"].iter().enumerate() {
+        println!("  {:<8} {:>8} {:>10} {:>8}", name, passed[i], corrected[i], deleted[i]);
+    }
+}
+
+impl Case {
+    /// Run the strokes through a fresh engine, returning the failure if the output differs.
+    fn check(&self, dicts: Vec<Dict>, strategy: Strategy) -> Option<Failure<'_>> {
+        let Output { text, commands, .. } = self.run(dicts, strategy);
+        if text == self.expect {
+            return None;
+        }
+        let cause = classify(&self.expect, &text, commands);
+        Some(Failure { case: self, got: text, cause })
+    }
+
+    /// Run the strokes through a fresh engine.  Anything still held at the end is typed, as the
+    /// keyboard would once the timeout passes.
+    fn run(&self, dicts: Vec<Dict>, strategy: Strategy) -> Output {
+        let mut lookup = Lookup::new(dicts);
+        lookup.set_strategy(strategy);
+        let mut joiner = Joiner::new();
+        let mut out = Output { text: String::new(), commands: false, deleted: 0 };
+
+        let mut actions: Vec<_> = self.steno.iter().flat_map(|stroke| lookup.add(*stroke)).collect();
+        actions.extend(lookup.flush());
+        for action in actions {
+            joiner.add(action);
+            while let Some(act) = joiner.pop(0) {
+                match act {
+                    Joined::Type { remove, append } => {
+                        for _ in 0..remove {
+                            out.text.pop();
+                        }
+                        out.deleted += remove;
+                        out.text.push_str(&append);
+                    }
+                    _ => out.commands = true,
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Guess at why the two differ.
+fn classify(expect: &str, got: &str, commands: bool) -> Cause {
+    let squash = |text: &str| -> String { text.chars().filter(|c| !c.is_whitespace()).collect() };
+
+    if commands || expect.to_lowercase() == got.to_lowercase() {
+        return Cause::Command;
+    }
+    if squash(expect) == squash(got) {
+        return Cause::Glue;
+    }
+
+    // Orthography changes the end of a word, so the words line up, and the ones that differ
+    // share a stem.
+    let expect: Vec<_> = expect.split_whitespace().collect();
+    let got: Vec<_> = got.split_whitespace().collect();
+    if expect.len() == got.len()
+        && expect.iter().zip(&got).all(|(a, b)| a == b || common_prefix(a, b) >= 3)
+    {
+        return Cause::Orthography;
+    }
+    Cause::Other
+}
+
+/// The number of characters at the start of both.
+fn common_prefix(a: &str, b: &str) -> usize {
+    a.chars().zip(b.chars()).take_while(|(a, b)| a == b).count()
+}
+
+/// Decode the escapes in the expected output.
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
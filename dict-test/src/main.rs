@@ -1,16 +1,89 @@
 //! Steno dictionary lookup testing.
 
-use std::{path::Path, io::BufRead, io::BufReader, fs::File};
+use std::{path::{Path, PathBuf}, io::BufRead, io::BufReader, fs::File, process};
 
 use anyhow::{Result, anyhow};
-use bbq_steno::{dict::{Dict, Joined, Joiner, Lookup}, memdict::MemDict, Stroke};
+use bbq_steno::{dict::{Dict, Joined, Joiner, Lookup, Strategy}, memdict::MemDict, Stroke};
+use clap::{Parser, Subcommand, ValueEnum};
 use regex::Regex;
 
+mod corpus;
+
+#[derive(Parser)]
+#[command(about = "Check steno translation against known output")]
+struct Cli {
+    /// The dictionaries, as built by bbq-tool.
+    #[arg(long, default_value = "../bbq-tool/dicts.bin")]
+    dict: PathBuf,
+
+    /// With no command, check the Phoenix exercises.
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Compare against output recorded from Plover, reporting the differences by cause.
+    Corpus {
+        /// Corpus files, see the corpus module for the format.
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// When translations are typed.
+        #[arg(long, value_enum, default_value_t = StrategyArg::Eager)]
+        strategy: StrategyArg,
+
+        /// Run the cases with each strategy, and report how they differ, instead of comparing
+        /// against Plover.
+        #[arg(long)]
+        compare: bool,
+    },
+}
+
+/// The lookup strategies, see [`Strategy`].
+#[derive(Clone, Copy, ValueEnum)]
+enum StrategyArg {
+    Eager,
+    Longest,
+}
+
+impl From<StrategyArg> for Strategy {
+    fn from(arg: StrategyArg) -> Strategy {
+        match arg {
+            StrategyArg::Eager => Strategy::Eager,
+            StrategyArg::Longest => Strategy::Longest,
+        }
+    }
+}
+
 fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     // Pull in the user dictionary.
-    let bindict = std::fs::read("../bbq-tool/dicts.bin")?;
+    let bindict = std::fs::read(&cli.dict)?;
     let dicts = unsafe { MemDict::from_raw_ptr(bindict.as_ptr()) };
 
+    match cli.command {
+        None => phoenix(dicts),
+        Some(Commands::Corpus { files, strategy, compare }) => {
+            let mut cases = Vec::new();
+            for file in &files {
+                cases.append(&mut corpus::load(file)?);
+            }
+            if compare {
+                corpus::compare(&cases, dicts);
+                return Ok(());
+            }
+            if corpus::run(&cases, dicts, strategy.into()) > 0 {
+                process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Check each of the Phoenix drill exercises.
+fn phoenix(dicts: Vec<Dict>) -> Result<()> {
     let base = dirs::home_dir().unwrap().join("steno").join("steno-drill").join("phoenix");
     let mut names = Vec::new();
     for entry in base.read_dir()? {
@@ -127,16 +200,15 @@ impl Entry {
 
         for stroke in &self.steno {
             // println!("stroke: {}", stroke);
-            let action = lookup.add(*stroke);
-            joiner.add(action);
+            for action in lookup.add(*stroke) {
+                joiner.add(action);
+            }
             while let Some(act) = joiner.pop(0) {
-                match act {
-                    Joined::Type { remove, append } => {
-                        for _ in 0..remove {
-                            text.pop();
-                        }
-                        text.push_str(&append);
+                if let Joined::Type { remove, append } = act {
+                    for _ in 0..remove {
+                        text.pop();
                     }
+                    text.push_str(&append);
                 }
             }
         }
@@ -11,7 +11,45 @@
 // This builds a program that is run on the compilation host before the code is compiled.  It can
 // output configuration settings that affect the compilation.
 
+use std::{env, fs, path::Path};
+
+use minder::ImageInfo;
+
 fn main() {
     zephyr_build::export_bool_kconfig();
     zephyr_build::dt_cfgs();
+
+    write_image_info();
+    write_firmware_key();
+}
+
+/// Encode the image metadata, which is placed into the image by `image.rs`.
+fn write_image_info() {
+    let info = ImageInfo::for_build(&ImageInfo::zephyr_board().unwrap_or_else(|| "unknown".into()));
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("image_info.bin");
+    fs::write(out, info.to_block()).unwrap();
+}
+
+/// Write the public key that firmware updates must be signed by, given in hex by
+/// `BBQ_FIRMWARE_KEY` (see `keyminder fw key`).  Without one, the file is empty, and updates
+/// don't need to be signed.
+fn write_firmware_key() {
+    let key = match env::var("BBQ_FIRMWARE_KEY") {
+        Ok(hex) => {
+            let hex = hex.trim();
+            let key: Option<Vec<u8>> = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect();
+            match key {
+                Some(key) if key.len() == minder::sign::PUBLIC_KEY_SIZE => key,
+                _ => panic!("BBQ_FIRMWARE_KEY must be {} bytes in hex", minder::sign::PUBLIC_KEY_SIZE),
+            }
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("firmware_key.bin");
+    fs::write(out, key).unwrap();
+    println!("cargo:rerun-if-env-changed=BBQ_FIRMWARE_KEY");
 }
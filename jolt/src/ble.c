@@ -0,0 +1,528 @@
+// BLE HID over GATT.
+//
+// The GATT service tables are built from the Zephyr macros, which only exist in C.  This defines a
+// HID service with the same keyboard and plover reports as the USB interfaces, and exposes a small
+// API to send those reports, and to find out about connections.  Everything else is in
+// devices/ble.rs.
+//
+// There is also a service of our own, with a single characteristic that notifies the translated
+// steno text, for apps that would rather take the text directly than as key presses.
+//
+// New hosts can only pair while pairing is open (see ble_pairing), or while there are no bonds at
+// all.  Bonds can be listed, named, and deleted.  With BT_SETTINGS, the names are saved alongside
+// the bonds, and so last as long as they do.
+
+#include <errno.h>
+#include <string.h>
+#include <zephyr/kernel.h>
+#include <zephyr/bluetooth/bluetooth.h>
+#include <zephyr/bluetooth/conn.h>
+#include <zephyr/bluetooth/gatt.h>
+#include <zephyr/bluetooth/uuid.h>
+#include <zephyr/settings/settings.h>
+#include <zephyr/sys/util.h>
+
+// Callbacks into Rust.
+typedef void (*ble_status_cb)(bool connected);
+typedef void (*ble_notify_cb)(uint8_t report, bool enabled);
+
+static ble_status_cb status_cb;
+static ble_notify_cb notify_cb;
+
+// The longest name of a bond, which must match minder's BOND_NAME_MAX.
+#define BOND_NAME_MAX 32
+
+// A bond, as given to Rust.  The address is the type, then the address itself.
+struct ble_bond {
+	uint8_t addr[7];
+	bool connected;
+	char name[BOND_NAME_MAX + 1];
+};
+
+// The names given to bonds.
+static struct {
+	bt_addr_le_t addr;
+	char name[BOND_NAME_MAX + 1];
+} bond_names[CONFIG_BT_MAX_PAIRED];
+
+// Until when pairing is open, in uptime ms.
+static int64_t pairing_until;
+
+// The report ids.  The plover one must match the plover HID protocol.
+#define REPORT_KEYBOARD 1
+#define REPORT_PLOVER 0x50
+
+// The text stream isn't a report, but shares the notify callback, with an id no report has.
+#define STREAM_TEXT 0
+
+// Report reference types.
+#define REPORT_TYPE_INPUT 1
+
+// The USB boot keyboard report, with a report id, followed by the plover report.
+static const uint8_t report_map[] = {
+	0x05, 0x01,	  // Usage Page (Generic Desktop)
+	0x09, 0x06,	  // Usage (Keyboard)
+	0xa1, 0x01,	  // Collection (Application)
+	0x85, REPORT_KEYBOARD, //   Report ID
+	0x05, 0x07,	  //   Usage Page (Key Codes)
+	0x19, 0xe0,	  //   Usage Minimum (224)
+	0x29, 0xe7,	  //   Usage Maximum (231)
+	0x15, 0x00,	  //   Logical Minimum (0)
+	0x25, 0x01,	  //   Logical Maximum (1)
+	0x75, 0x01,	  //   Report Size (1)
+	0x95, 0x08,	  //   Report Count (8)
+	0x81, 0x02,	  //   Input (Data, Variable, Absolute), modifiers
+	0x95, 0x01,	  //   Report Count (1)
+	0x75, 0x08,	  //   Report Size (8)
+	0x81, 0x01,	  //   Input (Constant), reserved
+	0x95, 0x06,	  //   Report Count (6)
+	0x75, 0x08,	  //   Report Size (8)
+	0x15, 0x00,	  //   Logical Minimum (0)
+	0x25, 0x65,	  //   Logical Maximum (101)
+	0x05, 0x07,	  //   Usage Page (Key Codes)
+	0x19, 0x00,	  //   Usage Minimum (0)
+	0x29, 0x65,	  //   Usage Maximum (101)
+	0x81, 0x00,	  //   Input (Data, Array), keys
+	0xc0,		  // End Collection
+
+	0x06, 0x50, 0xff, // Usage Page (65360)
+	0x0a, 0x56, 0x4c, // Usage (19542)
+	0xa1, 0x02,	  // Collection (Logical)
+	0x85, REPORT_PLOVER, //   Report ID
+	0x25, 0x01,	  //   Logical Maximum (1)
+	0x75, 0x01,	  //   Report Size (1)
+	0x95, 0x40,	  //   Report Count (64)
+	0x05, 0x0a,	  //   Usage Page (Ordinal)
+	0x19, 0x00,	  //   Usage Minimum (0)
+	0x29, 0x3f,	  //   Usage Maximum (63)
+	0x81, 0x02,	  //   Input (Variable)
+	0xc0,		  // End Collection
+};
+
+struct hids_info {
+	uint16_t version;
+	uint8_t code;
+	uint8_t flags;
+} __packed;
+
+struct report_ref {
+	uint8_t id;
+	uint8_t type;
+} __packed;
+
+// HID 1.11, not localized, remote wake, normally connectable.
+static const struct hids_info info = {
+	.version = 0x0111,
+	.code = 0x00,
+	.flags = 0x03,
+};
+
+static const struct report_ref kbd_ref = {
+	.id = REPORT_KEYBOARD,
+	.type = REPORT_TYPE_INPUT,
+};
+
+static const struct report_ref plover_ref = {
+	.id = REPORT_PLOVER,
+	.type = REPORT_TYPE_INPUT,
+};
+
+static uint8_t ctrl_point;
+
+static ssize_t read_info(struct bt_conn *conn, const struct bt_gatt_attr *attr, void *buf,
+			 uint16_t len, uint16_t offset)
+{
+	return bt_gatt_attr_read(conn, attr, buf, len, offset, attr->user_data,
+				 sizeof(struct hids_info));
+}
+
+static ssize_t read_report_map(struct bt_conn *conn, const struct bt_gatt_attr *attr, void *buf,
+			       uint16_t len, uint16_t offset)
+{
+	return bt_gatt_attr_read(conn, attr, buf, len, offset, report_map, sizeof(report_map));
+}
+
+static ssize_t read_report_ref(struct bt_conn *conn, const struct bt_gatt_attr *attr, void *buf,
+			       uint16_t len, uint16_t offset)
+{
+	return bt_gatt_attr_read(conn, attr, buf, len, offset, attr->user_data,
+				 sizeof(struct report_ref));
+}
+
+// Input reports are only sent as notifications.  A read just gets an empty report.
+static ssize_t read_input_report(struct bt_conn *conn, const struct bt_gatt_attr *attr, void *buf,
+				 uint16_t len, uint16_t offset)
+{
+	return bt_gatt_attr_read(conn, attr, buf, len, offset, NULL, 0);
+}
+
+static ssize_t write_ctrl_point(struct bt_conn *conn, const struct bt_gatt_attr *attr,
+				const void *buf, uint16_t len, uint16_t offset, uint8_t flags)
+{
+	uint8_t *value = attr->user_data;
+
+	if (offset + len > sizeof(ctrl_point)) {
+		return BT_GATT_ERR(BT_ATT_ERR_INVALID_OFFSET);
+	}
+	memcpy(value + offset, buf, len);
+	return len;
+}
+
+static void kbd_ccc_changed(const struct bt_gatt_attr *attr, uint16_t value)
+{
+	if (notify_cb) {
+		notify_cb(REPORT_KEYBOARD, value == BT_GATT_CCC_NOTIFY);
+	}
+}
+
+static void plover_ccc_changed(const struct bt_gatt_attr *attr, uint16_t value)
+{
+	if (notify_cb) {
+		notify_cb(REPORT_PLOVER, value == BT_GATT_CCC_NOTIFY);
+	}
+}
+
+BT_GATT_SERVICE_DEFINE(hids_svc,
+	BT_GATT_PRIMARY_SERVICE(BT_UUID_HIDS),
+	BT_GATT_CHARACTERISTIC(BT_UUID_HIDS_INFO, BT_GATT_CHRC_READ, BT_GATT_PERM_READ,
+			       read_info, NULL, (void *)&info),
+	BT_GATT_CHARACTERISTIC(BT_UUID_HIDS_REPORT_MAP, BT_GATT_CHRC_READ, BT_GATT_PERM_READ,
+			       read_report_map, NULL, NULL),
+
+	// Keyboard report, the value is attribute 6.
+	BT_GATT_CHARACTERISTIC(BT_UUID_HIDS_REPORT, BT_GATT_CHRC_READ | BT_GATT_CHRC_NOTIFY,
+			       BT_GATT_PERM_READ_ENCRYPT, read_input_report, NULL, NULL),
+	BT_GATT_CCC(kbd_ccc_changed, BT_GATT_PERM_READ | BT_GATT_PERM_WRITE_ENCRYPT),
+	BT_GATT_DESCRIPTOR(BT_UUID_HIDS_REPORT_REF, BT_GATT_PERM_READ, read_report_ref, NULL,
+			   (void *)&kbd_ref),
+
+	// Plover report, the value is attribute 10.
+	BT_GATT_CHARACTERISTIC(BT_UUID_HIDS_REPORT, BT_GATT_CHRC_READ | BT_GATT_CHRC_NOTIFY,
+			       BT_GATT_PERM_READ_ENCRYPT, read_input_report, NULL, NULL),
+	BT_GATT_CCC(plover_ccc_changed, BT_GATT_PERM_READ | BT_GATT_PERM_WRITE_ENCRYPT),
+	BT_GATT_DESCRIPTOR(BT_UUID_HIDS_REPORT_REF, BT_GATT_PERM_READ, read_report_ref, NULL,
+			   (void *)&plover_ref),
+
+	BT_GATT_CHARACTERISTIC(BT_UUID_HIDS_CTRL_POINT, BT_GATT_CHRC_WRITE_WITHOUT_RESP,
+			       BT_GATT_PERM_WRITE, NULL, write_ctrl_point, &ctrl_point),
+);
+
+#define KBD_ATTR (&hids_svc.attrs[6])
+#define PLOVER_ATTR (&hids_svc.attrs[10])
+
+// The text stream service, and its characteristic.
+#define BT_UUID_TEXT_SVC_VAL BT_UUID_128_ENCODE(0x62627174, 0x6578, 0x7400, 0x9a3c, 0x5e0d1f2a7b61)
+#define BT_UUID_TEXT_VAL BT_UUID_128_ENCODE(0x62627174, 0x6578, 0x7401, 0x9a3c, 0x5e0d1f2a7b61)
+
+static const struct bt_uuid_128 text_svc_uuid = BT_UUID_INIT_128(BT_UUID_TEXT_SVC_VAL);
+static const struct bt_uuid_128 text_uuid = BT_UUID_INIT_128(BT_UUID_TEXT_VAL);
+
+static void text_ccc_changed(const struct bt_gatt_attr *attr, uint16_t value)
+{
+	if (notify_cb) {
+		notify_cb(STREAM_TEXT, value == BT_GATT_CCC_NOTIFY);
+	}
+}
+
+// The text is only sent as notifications, like the input reports.
+BT_GATT_SERVICE_DEFINE(text_svc,
+	BT_GATT_PRIMARY_SERVICE(&text_svc_uuid),
+
+	// The text, the value is attribute 2.
+	BT_GATT_CHARACTERISTIC(&text_uuid.uuid, BT_GATT_CHRC_READ | BT_GATT_CHRC_NOTIFY,
+			       BT_GATT_PERM_READ_ENCRYPT, read_input_report, NULL, NULL),
+	BT_GATT_CCC(text_ccc_changed, BT_GATT_PERM_READ | BT_GATT_PERM_WRITE_ENCRYPT),
+);
+
+#define TEXT_ATTR (&text_svc.attrs[2])
+
+static const struct bt_data ad[] = {
+	BT_DATA_BYTES(BT_DATA_GAP_APPEARANCE, 0xc1, 0x03), // Keyboard
+	BT_DATA_BYTES(BT_DATA_FLAGS, (BT_LE_AD_GENERAL | BT_LE_AD_NO_BREDR)),
+	BT_DATA_BYTES(BT_DATA_UUID16_ALL, BT_UUID_16_ENCODE(BT_UUID_HIDS_VAL)),
+};
+
+static const struct bt_data sd[] = {
+	BT_DATA(BT_DATA_NAME_COMPLETE, CONFIG_BT_DEVICE_NAME, sizeof(CONFIG_BT_DEVICE_NAME) - 1),
+};
+
+static void advertise(void)
+{
+	int err = bt_le_adv_start(BT_LE_ADV_CONN, ad, ARRAY_SIZE(ad), sd, ARRAY_SIZE(sd));
+
+	if (err && err != -EALREADY) {
+		printk("BLE advertising failed: %d\n", err);
+	}
+}
+
+static void connected(struct bt_conn *conn, uint8_t err)
+{
+	if (err) {
+		return;
+	}
+
+	// The reports need an encrypted link.
+	bt_conn_set_security(conn, BT_SECURITY_L2);
+	if (status_cb) {
+		status_cb(true);
+	}
+}
+
+static void disconnected(struct bt_conn *conn, uint8_t reason)
+{
+	if (status_cb) {
+		status_cb(false);
+	}
+}
+
+// Advertising stops on a connection, and can start again once that connection is gone.
+static void recycled(void)
+{
+	advertise();
+}
+
+BT_CONN_CB_DEFINE(conn_callbacks) = {
+	.connected = connected,
+	.disconnected = disconnected,
+	.recycled = recycled,
+};
+
+static void count_bond(const struct bt_bond_info *info, void *user_data)
+{
+	(*(size_t *)user_data)++;
+}
+
+static enum bt_security_err pairing_accept(struct bt_conn *conn,
+					   const struct bt_conn_pairing_feat *const feat)
+{
+	size_t count = 0;
+
+	bt_foreach_bond(BT_ID_DEFAULT, count_bond, &count);
+	if (count == 0 || k_uptime_get() < pairing_until) {
+		return BT_SECURITY_ERR_SUCCESS;
+	}
+	printk("BLE pairing refused, pairing isn't open\n");
+	return BT_SECURITY_ERR_PAIR_NOT_ALLOWED;
+}
+
+static struct bt_conn_auth_cb auth_cb = {
+	.pairing_accept = pairing_accept,
+};
+
+static void to_addr(bt_addr_le_t *addr, const uint8_t *raw)
+{
+	addr->type = raw[0];
+	memcpy(addr->a.val, raw + 1, sizeof(addr->a.val));
+}
+
+// The slot holding the name for an address, or with no address, a free one.
+static int find_name(const bt_addr_le_t *addr)
+{
+	for (int i = 0; i < ARRAY_SIZE(bond_names); i++) {
+		if (bt_addr_le_eq(&bond_names[i].addr, addr ? addr : BT_ADDR_LE_ANY)) {
+			return i;
+		}
+	}
+	return -1;
+}
+
+#if defined(CONFIG_BT_SETTINGS)
+// Names are kept under "bbq/bond/", followed by the address in hex, as given to Rust.
+static void name_key(char *key, size_t size, const bt_addr_le_t *addr)
+{
+	uint8_t raw[7] = { addr->type };
+
+	memcpy(raw + 1, addr->a.val, sizeof(addr->a.val));
+	int pos = snprintk(key, size, "bbq/bond/");
+	bin2hex(raw, sizeof(raw), key + pos, size - pos);
+}
+
+static int name_set(const char *key, size_t len, settings_read_cb read_cb, void *cb_arg)
+{
+	uint8_t raw[7];
+	bt_addr_le_t addr;
+
+	if (hex2bin(key, strlen(key), raw, sizeof(raw)) != sizeof(raw) || len > BOND_NAME_MAX) {
+		return -EINVAL;
+	}
+	to_addr(&addr, raw);
+	int slot = find_name(NULL);
+	if (slot < 0) {
+		return -ENOMEM;
+	}
+	int got = read_cb(cb_arg, bond_names[slot].name, len);
+	if (got < 0) {
+		return got;
+	}
+	bond_names[slot].name[got] = 0;
+	bond_names[slot].addr = addr;
+	return 0;
+}
+
+SETTINGS_STATIC_HANDLER_DEFINE(bbq_bond, "bbq/bond", NULL, name_set, NULL, NULL);
+#endif
+
+// Forget the name of an address, if it has one.
+static void clear_name(const bt_addr_le_t *addr)
+{
+	int slot = find_name(addr);
+
+	if (slot < 0) {
+		return;
+	}
+	bond_names[slot].addr = *BT_ADDR_LE_ANY;
+	bond_names[slot].name[0] = 0;
+#if defined(CONFIG_BT_SETTINGS)
+	char key[32];
+
+	name_key(key, sizeof(key), addr);
+	settings_delete(key);
+#endif
+}
+
+int ble_hid_init(ble_status_cb status, ble_notify_cb notify)
+{
+	status_cb = status;
+	notify_cb = notify;
+
+	int err = bt_conn_auth_cb_register(&auth_cb);
+	if (err) {
+		return err;
+	}
+
+	err = bt_enable(NULL);
+	if (err) {
+		return err;
+	}
+
+#if defined(CONFIG_BT_SETTINGS)
+	// The bonds, and their names.
+	settings_load();
+#endif
+
+	advertise();
+	return 0;
+}
+
+struct bond_list {
+	struct ble_bond *bonds;
+	size_t max;
+	size_t count;
+};
+
+static void list_bond(const struct bt_bond_info *info, void *user_data)
+{
+	struct bond_list *list = user_data;
+
+	if (list->count >= list->max) {
+		return;
+	}
+	struct ble_bond *bond = &list->bonds[list->count++];
+
+	bond->addr[0] = info->addr.type;
+	memcpy(bond->addr + 1, info->addr.a.val, sizeof(info->addr.a.val));
+
+	struct bt_conn *conn = bt_conn_lookup_addr_le(BT_ID_DEFAULT, &info->addr);
+	bond->connected = conn != NULL;
+	if (conn) {
+		bt_conn_unref(conn);
+	}
+
+	int slot = find_name(&info->addr);
+	strcpy(bond->name, slot < 0 ? "" : bond_names[slot].name);
+}
+
+// Fill in up to max bonds, returning how many there are.
+int ble_bonds(struct ble_bond *bonds, size_t max)
+{
+	struct bond_list list = { .bonds = bonds, .max = max };
+
+	bt_foreach_bond(BT_ID_DEFAULT, list_bond, &list);
+	return list.count;
+}
+
+struct bond_find {
+	const bt_addr_le_t *addr;
+	bool found;
+};
+
+static void find_bond(const struct bt_bond_info *info, void *user_data)
+{
+	struct bond_find *find = user_data;
+
+	if (bt_addr_le_eq(&info->addr, find->addr)) {
+		find->found = true;
+	}
+}
+
+// Name a bond, or with an empty name, forget its name.
+int ble_bond_name(const uint8_t *raw, const char *name, size_t len)
+{
+	bt_addr_le_t addr;
+	struct bond_find find = { .addr = &addr };
+
+	to_addr(&addr, raw);
+	bt_foreach_bond(BT_ID_DEFAULT, find_bond, &find);
+	if (!find.found) {
+		return -ENOENT;
+	}
+	if (len > BOND_NAME_MAX) {
+		return -EINVAL;
+	}
+
+	clear_name(&addr);
+	if (len == 0) {
+		return 0;
+	}
+	int slot = find_name(NULL);
+	if (slot < 0) {
+		return -ENOMEM;
+	}
+	memcpy(bond_names[slot].name, name, len);
+	bond_names[slot].name[len] = 0;
+	bond_names[slot].addr = addr;
+#if defined(CONFIG_BT_SETTINGS)
+	char key[32];
+
+	name_key(key, sizeof(key), &addr);
+	return settings_save_one(key, name, len);
+#else
+	return 0;
+#endif
+}
+
+// Delete a bond, disconnecting the host if it is connected.
+int ble_bond_delete(const uint8_t *raw)
+{
+	bt_addr_le_t addr;
+	struct bond_find find = { .addr = &addr };
+
+	to_addr(&addr, raw);
+	bt_foreach_bond(BT_ID_DEFAULT, find_bond, &find);
+	if (!find.found) {
+		return -ENOENT;
+	}
+	clear_name(&addr);
+	return bt_unpair(BT_ID_DEFAULT, &addr);
+}
+
+// Accept pairing from new hosts for the given time, or stop with zero.
+void ble_pairing(uint32_t seconds)
+{
+	pairing_until = seconds ? k_uptime_get() + (int64_t)seconds * 1000 : 0;
+}
+
+int ble_hid_send_keyboard(const uint8_t *report, size_t len)
+{
+	return bt_gatt_notify(NULL, KBD_ATTR, report, len);
+}
+
+int ble_hid_send_plover(const uint8_t *report, size_t len)
+{
+	return bt_gatt_notify(NULL, PLOVER_ATTR, report, len);
+}
+
+int ble_text_send(const uint8_t *data, size_t len)
+{
+	return bt_gatt_notify(NULL, TEXT_ATTR, data, len);
+}
@@ -0,0 +1,228 @@
+//! Debug console.
+//!
+//! A simple line based command interpreter on its own ACM port, so the keyboard can be poked at
+//! from any terminal program, without needing keyminder.  The same commands can be run through
+//! minder with the `Exec` request, which uses [`exec`].
+
+use core::fmt::Write;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use bbq_keyboard::{time::Clock, LayoutMode};
+use log::info;
+use zephyr::{
+    device::uart::UartIrq,
+    kobj_define,
+    sync::Arc,
+    time::{Duration, NoWait},
+};
+
+use crate::dispatch::Dispatch;
+use crate::image;
+use crate::leds::manager::TEST_INDICATOR;
+use crate::SysClock;
+
+/// The console.
+pub struct Console();
+
+/// Our uart, with fixed sized rings.
+type Uart = UartIrq<2, READ_RINGS>;
+
+const READ_RINGS: usize = 2;
+
+/// The size of the read buffers.  This is typed by hand, so it doesn't need to be large.
+const READ_BUFSIZE: usize = 64;
+
+/// The longest line we will accept.
+const MAX_LINE: usize = 80;
+
+const PROMPT: &str = "bbq> ";
+
+impl Console {
+    pub fn new(uart: Uart, dispatch: Arc<Dispatch>) -> Console {
+        let mut thread = CONSOLE_THREAD
+            .init_once(CONSOLE_STACK.init_once(()).unwrap())
+            .unwrap();
+        // Lower priority than minder, this is only for people.
+        thread.set_priority(6);
+        thread.set_name(c"console");
+        thread.spawn(move || {
+            console_thread(uart, dispatch);
+        });
+
+        Console()
+    }
+}
+
+fn console_thread(mut uart: Uart, dispatch: Arc<Dispatch>) {
+    for _ in 0..READ_RINGS {
+        uart.read_enqueue(vec![0u8; READ_BUFSIZE]).unwrap();
+    }
+
+    let mut line = String::new();
+    loop {
+        let mut out = String::new();
+        match uart.read_wait(Duration::millis_at_least(100)) {
+            Ok(buf) => {
+                for &byte in buf.as_slice() {
+                    match byte {
+                        b'\r' | b'\n' => {
+                            out.push_str("\r\n");
+                            if !line.trim().is_empty() {
+                                info!("Console: {:?}", line);
+                                for text in exec(&line, &dispatch).lines() {
+                                    out.push_str(text);
+                                    out.push_str("\r\n");
+                                }
+                            }
+                            out.push_str(PROMPT);
+                            line.clear();
+                        }
+                        // Backspace or delete.
+                        0x08 | 0x7f => {
+                            if line.pop().is_some() {
+                                out.push_str("\x08 \x08");
+                            }
+                        }
+                        b' '..=b'~' if line.len() < MAX_LINE => {
+                            line.push(byte as char);
+                            out.push(byte as char);
+                        }
+                        _ => (),
+                    }
+                }
+
+                // Put the buffer back.
+                uart.read_enqueue(buf.into_inner()).unwrap();
+            }
+            // Timeout, just go on.
+            Err(_) => (),
+        }
+
+        // Discard completed writes.
+        while let Ok(_) = uart.write_wait(NoWait) {}
+
+        // Echo and output are dropped if nobody is listening, or they aren't keeping up.
+        if out.is_empty() || uart.write_is_full() {
+            continue;
+        }
+        if unsafe { !uart.inner().is_dtr_set().unwrap_or(false) } {
+            continue;
+        }
+        let out = out.into_bytes();
+        let len = out.len();
+        let _ = uart.write_enqueue(out, 0..len);
+    }
+}
+
+/// Run a single console command, returning the text to show.
+pub fn exec(line: &str, dispatch: &Dispatch) -> String {
+    let words: Vec<_> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["help"] => HELP.into(),
+        ["status"] => status(dispatch),
+        ["mode", name] => match LayoutMode::from_name(name) {
+            Some(mode) => {
+                dispatch.request_mode(mode);
+                format!("Requested mode {}", mode.name())
+            }
+            None => format!("Unknown mode {:?}", name),
+        },
+        #[cfg(feature = "qwerty")]
+        ["keymap", which @ ("builtin" | "stored")] => {
+            dispatch.request_keymap(*which == "stored");
+            format!("Requested the {} keymap", which)
+        }
+        #[cfg(feature = "steno")]
+        ["stenomap", which @ ("builtin" | "stored")] => {
+            dispatch.request_steno_map(*which == "stored");
+            format!("Requested the {} steno map", which)
+        }
+        ["led", "test"] => {
+            let mut leds = dispatch.leds.lock().unwrap();
+            for i in 0..leds.count() {
+                leds.set_oneshot(i, &TEST_INDICATOR);
+            }
+            format!("Testing {} leds", leds.count())
+        }
+        #[cfg(feature = "trainer")]
+        ["pace"] => {
+            let pace = dispatch.pace();
+            format!("{} spm, {}s: {} strokes in {} beats, {} on, {} ahead, {} behind, mean {}ms",
+                    pace.spm, pace.ms / 1000, pace.strokes, pace.beats, pace.on_beat, pace.ahead,
+                    pace.behind, pace.mean_offset)
+        }
+        #[cfg(feature = "trainer")]
+        ["pace", spm] => match spm.parse() {
+            Ok(spm) => {
+                dispatch.set_pace(spm);
+                if spm == 0 { "Metronome stopped".into() } else { format!("Metronome at {} spm", spm) }
+            }
+            Err(_) => format!("Invalid rate {:?}", spm),
+        },
+        ["link"] => {
+            let stats = crate::inter::link_stats();
+            let errors = crate::inter::link_errors();
+            format!("Link: {} packets sent, {} deferred, {} preempted\n\
+                     Errors: {} overruns, {} framing, {}/s, {} resyncs",
+                    stats.sent, stats.deferred, stats.preempted,
+                    errors.overruns, errors.framing, errors.per_sec, errors.resyncs)
+        }
+        ["dump", "matrix"] => {
+            let keys = dispatch.keys_down();
+            if keys.is_empty() {
+                "No keys down".into()
+            } else {
+                format!("Keys down: {:?}", keys)
+            }
+        }
+        ["reboot"] => {
+            dispatch.request_shutdown();
+            "Rebooting".into()
+        }
+        _ => format!("Unknown command {:?}, try 'help'", line.trim()),
+    }
+}
+
+static HELP: &str = "\
+status        show firmware and mode
+mode NAME     switch to steno, steno-direct, artsey, taipo, qwerty, nkro or numpad
+keymap WHICH  use the builtin or stored qwerty keymap
+stenomap WHICH use the builtin or stored steno map
+led test      cycle the LEDs through some colors
+pace [SPM]    start the practice metronome (0 stops), or show the session
+link          show the inter-board link counters
+dump matrix   show the scan codes of the keys held down
+reboot        save state and reboot";
+
+fn status(dispatch: &Dispatch) -> String {
+    let image = image::info();
+    let uptime = SysClock.millis();
+    let mut text = String::new();
+    let _ = writeln!(text, "Firmware: {} {}{}, built for {}",
+                     image.version, image.git, if image.dirty { "-dirty" } else { "" }, image.board);
+    let _ = writeln!(text, "Build id: {:08x}", image.build_id());
+    let _ = writeln!(text, "Uptime: {}.{:03}s", uptime / 1000, uptime % 1000);
+    let _ = writeln!(text, "Mode: {}{}",
+                     dispatch.current_mode.lock().unwrap().name(),
+                     if *dispatch.raw_mode.lock().unwrap() { " (raw)" } else { "" });
+    let output = dispatch.output_stats();
+    let _ = writeln!(text, "Output: {} kills, {} dropped", output.kills, output.dropped);
+    let _ = write!(text, "Profile: {}", dispatch.profile_name().as_deref().unwrap_or("default"));
+    if let Some(overlay) = dispatch.profile_overlay() {
+        let _ = write!(text, ", overlay {}", overlay);
+    }
+    for mode in dispatch.usage() {
+        let _ = write!(text, "\n  {}: {}s, {} keys, {} strokes",
+                       mode.mode, mode.ms / 1000, mode.keys, mode.strokes);
+    }
+    text
+}
+
+kobj_define! {
+    static CONSOLE_THREAD: StaticThread;
+    static CONSOLE_STACK: ThreadStack<2048>;
+}
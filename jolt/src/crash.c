@@ -0,0 +1,265 @@
+// Crash reports, and the watchdog.
+//
+// The report is kept in RAM that isn't cleared on reset, so it survives the reboot that follows a
+// crash.  While running, the last of the console output is copied into it, which, for a Rust
+// panic, ends with the panic message.  A fatal error records where it happened, and reboots.  On
+// the next boot, the report is moved aside, for the host to fetch.
+//
+// The steno tape is also kept here, as it was last staged, until it has been saved to flash.  A
+// fatal error writes it out before rebooting, and so does a timer that sees the watchdog is about
+// to fire, so the strokes leading up to a crash or a hang are there to look at afterwards.
+
+#include <errno.h>
+#include <string.h>
+#include <zephyr/kernel.h>
+#include <zephyr/device.h>
+#include <zephyr/fatal.h>
+#include <zephyr/init.h>
+#include <zephyr/drivers/hwinfo.h>
+#include <zephyr/drivers/watchdog.h>
+#include <zephyr/sys/printk-hooks.h>
+#include <zephyr/sys/reboot.h>
+
+// The report is being written, and the tail is valid.
+#define CRASH_RUNNING 0x52554e21
+// A fatal error was recorded.
+#define CRASH_FATAL 0x44454144
+
+#define CRASH_TAIL 256
+
+// The tape is written as a single flash sector.
+#define TAPE_SECTOR 4096
+
+// How often to check on the watchdog, and how long before it would fire to write out the tape.
+// The margin covers the erase and program, with some to spare.
+#define RESCUE_CHECK_MS 100
+#define RESCUE_MARGIN_MS 500
+
+// The causes, matching minder's CrashCause.
+enum crash_cause {
+	CAUSE_FAULT,
+	CAUSE_STACK_OVERFLOW,
+	CAUSE_OOPS,
+	CAUSE_PANIC,
+	CAUSE_WATCHDOG,
+};
+
+// Shared with crash.rs.
+struct bbq_crash {
+	uint32_t magic;
+	uint32_t cause;
+	uint32_t reason;
+	uint32_t pc;
+	uint32_t lr;
+	uint32_t uptime;
+	// Characters written to the tail, which wraps.
+	uint32_t tail_pos;
+	char tail[CRASH_TAIL];
+};
+
+// The report for this boot.
+static __noinit struct bbq_crash current;
+
+// The report from the last boot, if it crashed.
+static struct bbq_crash previous;
+static bool have_previous;
+
+static int (*next_hook)(int c);
+
+// The tape, as last staged, already padded out to a full sector.  This has to be in RAM, as flash
+// can't be read while it is being written.
+static uint8_t tape_buf[TAPE_SECTOR] __aligned(4);
+static uint32_t tape_offset;
+// The staged tape hasn't been saved yet.
+static bool tape_dirty;
+
+// In flash.c.
+void bbq_flash_rescue(uint32_t offset, const uint8_t *data);
+
+// Write out the staged tape, if it hasn't been saved.
+static void tape_rescue(void) {
+	unsigned int key = irq_lock();
+	if (tape_dirty) {
+		tape_dirty = false;
+		bbq_flash_rescue(tape_offset, tape_buf);
+	}
+	irq_unlock(key);
+}
+
+static void note_char(char c) {
+	current.tail[current.tail_pos % CRASH_TAIL] = c;
+	current.tail_pos++;
+}
+
+static int crash_printk_hook(int c) {
+	unsigned int key = irq_lock();
+	note_char(c);
+	irq_unlock(key);
+	return next_hook != NULL ? next_hook(c) : c;
+}
+
+static int crash_init(void) {
+	uint32_t cause = 0;
+
+	(void)hwinfo_get_reset_cause(&cause);
+	(void)hwinfo_clear_reset_cause();
+
+	if (current.magic == CRASH_FATAL) {
+		previous = current;
+		have_previous = true;
+	} else if (cause & RESET_WATCHDOG) {
+		// The tail is only worth keeping if it was being written before the reset.
+		if (current.magic == CRASH_RUNNING) {
+			previous = current;
+		} else {
+			memset(&previous, 0, sizeof(previous));
+		}
+		previous.cause = CAUSE_WATCHDOG;
+		previous.reason = 0;
+		previous.pc = 0;
+		previous.lr = 0;
+		have_previous = true;
+	}
+
+	memset(&current, 0, sizeof(current));
+	current.magic = CRASH_RUNNING;
+
+	// Capture printk, passing it on to the console.
+	next_hook = __printk_get_hook();
+	__printk_hook_install(crash_printk_hook);
+	return 0;
+}
+
+// After the console is set up, so there is a hook to pass output on to.
+SYS_INIT(crash_init, APPLICATION, 0);
+
+void k_sys_fatal_error_handler(unsigned int reason, const struct arch_esf *esf) {
+	switch (reason) {
+	case K_ERR_STACK_CHK_FAIL:
+		current.cause = CAUSE_STACK_OVERFLOW;
+		break;
+	case K_ERR_KERNEL_OOPS:
+		current.cause = CAUSE_OOPS;
+		break;
+	case K_ERR_KERNEL_PANIC:
+		current.cause = CAUSE_PANIC;
+		break;
+	default:
+		current.cause = CAUSE_FAULT;
+		break;
+	}
+	current.reason = reason;
+	current.pc = esf != NULL ? esf->basic.pc : 0;
+	current.lr = esf != NULL ? esf->basic.lr : 0;
+	current.uptime = k_uptime_get_32();
+	current.magic = CRASH_FATAL;
+
+	tape_rescue();
+
+	sys_reboot(SYS_REBOOT_COLD);
+}
+
+// Add text, such as a log message, to the tail.
+void bbq_crash_note(const char *text, size_t len) {
+	unsigned int key = irq_lock();
+	for (size_t i = 0; i < len; i++) {
+		note_char(text[i]);
+	}
+	note_char('\n');
+	irq_unlock(key);
+}
+
+// Copy out the report from the last boot, returning false if there isn't one.
+bool bbq_crash_previous(struct bbq_crash *out) {
+	if (!have_previous) {
+		return false;
+	}
+	*out = previous;
+	return true;
+}
+
+// Forget the report from the last boot.
+void bbq_crash_clear(void) {
+	have_previous = false;
+}
+
+// Stage the encoded tape, to be written at offset if the keyboard goes down before it is saved.
+int bbq_tape_stage(uint32_t offset, const uint8_t *data, size_t len) {
+	if (len > TAPE_SECTOR) {
+		return -EINVAL;
+	}
+
+	unsigned int key = irq_lock();
+	memcpy(tape_buf, data, len);
+	memset(tape_buf + len, 0xff, TAPE_SECTOR - len);
+	tape_offset = offset;
+	tape_dirty = true;
+	irq_unlock(key);
+	return 0;
+}
+
+// The staged tape has been saved, so there is nothing to write out.
+void bbq_tape_saved(void) {
+	unsigned int key = irq_lock();
+	tape_dirty = false;
+	irq_unlock(key);
+}
+
+static const struct device *const wdt = DEVICE_DT_GET(DT_NODELABEL(wdt0));
+static int wdt_channel = -1;
+static uint32_t wdt_timeout;
+
+// The watchdog can't warn before it fires, so this watches for it getting close.  The timer runs
+// from the system clock interrupt, which keeps going when the main loop is stuck.
+static void rescue_check(struct k_timer *timer) {
+	ARG_UNUSED(timer);
+	if (wdt_channel >= 0 && k_uptime_get_32() - current.uptime + RESCUE_MARGIN_MS >= wdt_timeout) {
+		tape_rescue();
+	}
+}
+
+K_TIMER_DEFINE(rescue_timer, rescue_check, NULL);
+
+// Start the watchdog, which resets the keyboard if not fed within timeout_ms.
+int bbq_watchdog_start(uint32_t timeout_ms) {
+	const struct wdt_timeout_cfg cfg = {
+		.window.min = 0,
+		.window.max = timeout_ms,
+		.flags = WDT_FLAG_RESET_SOC,
+	};
+	int ret;
+
+	if (!device_is_ready(wdt)) {
+		return -ENODEV;
+	}
+	ret = wdt_install_timeout(wdt, &cfg);
+	if (ret < 0) {
+		return ret;
+	}
+	wdt_channel = ret;
+	wdt_timeout = timeout_ms;
+	current.uptime = k_uptime_get_32();
+	// Don't reset while stopped in the debugger.
+	ret = wdt_setup(wdt, WDT_OPT_PAUSE_HALTED_BY_DBG);
+	if (ret == 0) {
+		k_timer_start(&rescue_timer, K_MSEC(RESCUE_CHECK_MS), K_MSEC(RESCUE_CHECK_MS));
+	}
+	return ret;
+}
+
+void bbq_watchdog_feed(void) {
+	// If the watchdog does fire, this is the last time things were still running.
+	current.uptime = k_uptime_get_32();
+	if (wdt_channel >= 0) {
+		(void)wdt_feed(wdt, wdt_channel);
+	}
+}
+
+// Stop the watchdog, before something long that can't feed it, such as installing firmware.
+void bbq_watchdog_stop(void) {
+	k_timer_stop(&rescue_timer);
+	if (wdt_channel >= 0) {
+		(void)wdt_disable(wdt);
+		wdt_channel = -1;
+	}
+}
@@ -0,0 +1,137 @@
+//! Crash reports, and the watchdog.
+//!
+//! The recording is done in `crash.c`, which keeps the report in RAM that survives the reboot
+//! after a crash.  This reads the report from the last boot, for the host to fetch with
+//! `keyminder crash-log`.
+//!
+//! The watchdog resets the keyboard if the main loop stops running, which is reported the same
+//! way.  It is fed on every tick, so anything that holds up the main loop for long, such as a
+//! large flash erase, has to feed it as it goes.
+//!
+//! The steno tape is only saved to flash now and then, so each change is also staged here, and
+//! `crash.c` writes it out if the keyboard crashes, or the watchdog is about to fire, before the
+//! next save.
+
+use alloc::string::String;
+use core::ffi::c_int;
+
+use minder::{CrashCause, CrashLog};
+
+/// How long the main loop can go without a tick before the watchdog resets the keyboard.
+const WATCHDOG_TIMEOUT_MS: u32 = 2000;
+
+const CRASH_TAIL: usize = 256;
+
+/// The report, as kept by `crash.c`.
+#[repr(C)]
+struct RawCrash {
+    magic: u32,
+    cause: u32,
+    reason: u32,
+    pc: u32,
+    lr: u32,
+    uptime: u32,
+    tail_pos: u32,
+    tail: [u8; CRASH_TAIL],
+}
+
+extern "C" {
+    fn bbq_crash_note(text: *const u8, len: usize);
+    fn bbq_crash_previous(out: *mut RawCrash) -> bool;
+    fn bbq_crash_clear();
+    fn bbq_watchdog_start(timeout_ms: u32) -> c_int;
+    fn bbq_watchdog_feed();
+    fn bbq_watchdog_stop();
+    fn bbq_tape_stage(offset: u32, data: *const u8, len: usize) -> c_int;
+    fn bbq_tape_saved();
+}
+
+/// The report of the crash before this boot, if there was one.
+pub fn last() -> Option<CrashLog> {
+    let mut raw = RawCrash {
+        magic: 0,
+        cause: 0,
+        reason: 0,
+        pc: 0,
+        lr: 0,
+        uptime: 0,
+        tail_pos: 0,
+        tail: [0; CRASH_TAIL],
+    };
+    if !unsafe { bbq_crash_previous(&mut raw) } {
+        return None;
+    }
+
+    let cause = match raw.cause {
+        1 => CrashCause::StackOverflow,
+        2 => CrashCause::Oops,
+        3 => CrashCause::Panic,
+        4 => CrashCause::Watchdog,
+        _ => CrashCause::Fault,
+    };
+
+    // Once the tail has wrapped, the oldest character is at the write position.
+    let pos = raw.tail_pos as usize;
+    let mut bytes = alloc::vec::Vec::with_capacity(CRASH_TAIL);
+    if pos <= CRASH_TAIL {
+        bytes.extend_from_slice(&raw.tail[..pos]);
+    } else {
+        let split = pos % CRASH_TAIL;
+        bytes.extend_from_slice(&raw.tail[split..]);
+        bytes.extend_from_slice(&raw.tail[..split]);
+    }
+
+    Some(CrashLog {
+        cause,
+        reason: raw.reason,
+        pc: raw.pc,
+        lr: raw.lr,
+        uptime: raw.uptime,
+        text: String::from_utf8_lossy(&bytes).into_owned(),
+    })
+}
+
+/// Forget the report of the last crash.
+pub fn clear() {
+    unsafe { bbq_crash_clear() };
+}
+
+/// Add a line, such as a log message, to the text kept for the report.
+pub fn note(text: &str) {
+    unsafe { bbq_crash_note(text.as_ptr(), text.len()) };
+}
+
+/// Start the watchdog.  Failing to is only worth a warning, as the keyboard works without it.
+pub fn start_watchdog() -> Result<(), c_int> {
+    let ret = unsafe { bbq_watchdog_start(WATCHDOG_TIMEOUT_MS) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(ret)
+    }
+}
+
+pub fn feed_watchdog() {
+    unsafe { bbq_watchdog_feed() };
+}
+
+/// Stop the watchdog, before something that can't feed it, such as installing new firmware.
+pub fn stop_watchdog() {
+    unsafe { bbq_watchdog_stop() };
+}
+
+/// Stage the encoded tape, to be written to the flash sector at `offset` if the keyboard goes down
+/// before it is saved.
+pub fn stage_tape(offset: u32, data: &[u8]) -> Result<(), c_int> {
+    let ret = unsafe { bbq_tape_stage(offset, data.as_ptr(), data.len()) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(ret)
+    }
+}
+
+/// The staged tape has been saved, so there is nothing to write out.
+pub fn tape_saved() {
+    unsafe { bbq_tape_saved() };
+}
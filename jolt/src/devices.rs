@@ -3,6 +3,8 @@
 //! Management of the various devices used in the keyboards.  Some are just direct types from
 //! Zephyr, and others are wrapped.
 
+#[cfg(CONFIG_JOLT_BLE)]
+pub mod ble;
 pub mod usb;
 
 pub mod leds {
@@ -0,0 +1,235 @@
+//! Zephyr BLE HID interface.
+//!
+//! The HID over GATT service is defined in `ble.c`, as the GATT tables can only be built with the
+//! C macros.  This provides the same keyboard and plover report API as [`Usb`], so the dispatcher
+//! can send to whichever one is connected.  Minder is only available over USB.
+//!
+//! `ble.c` also has a text stream, for apps that want the translated steno text rather than key
+//! presses.  Each notification is a byte giving how many characters to delete, followed by UTF-8
+//! text to add.  A change too big for one notification is split over several, between characters.
+//!
+//! Hosts have to be bonded to connect.  A new host can only pair while pairing has been opened
+//! with [`Ble::set_pairing`], or while there are no bonds at all.  The bonds can be listed, named,
+//! and deleted, for minder.
+//!
+//! [`Usb`]: super::usb::Usb
+
+use core::ffi::{c_char, c_int};
+use core::sync::atomic::Ordering;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use bbq_keyboard::hid;
+use log::{info, warn};
+use minder::{BleAddress, BondInfo, BOND_NAME_MAX};
+use zephyr::{
+    error::to_result_void,
+    raw,
+    sync::atomic::AtomicBool,
+    time::Duration,
+    work::futures::sleep,
+    Result,
+};
+
+use crate::rust_ble_status;
+
+/// The report ids, as in `ble.c`, and the id the text stream's notify callback has.
+const REPORT_KEYBOARD: u8 = 1;
+const REPORT_PLOVER: u8 = 0x50;
+const STREAM_TEXT: u8 = 0;
+
+/// The most a text notification carries, as the smallest ATT MTU leaves room for.
+const TEXT_NOTIFY_MAX: usize = 20;
+
+/// How many times to retry a keyboard report when the stack is out of buffers.
+const RETRIES: usize = 20;
+
+/// The most bonds Zephyr keeps, from `CONFIG_BT_MAX_PAIRED`.
+const MAX_BONDS: usize = zephyr::kconfig::CONFIG_BT_MAX_PAIRED as usize;
+
+/// A bond, as `ble.c` gives it.
+#[repr(C)]
+struct BleBond {
+    addr: [u8; 7],
+    connected: bool,
+    name: [c_char; BOND_NAME_MAX + 1],
+}
+
+/// Is a host connected.
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Has the host enabled notifications for the plover report.
+static PLOVER_NOTIFY: AtomicBool = AtomicBool::new(false);
+
+/// Has the host subscribed to the text stream.
+static TEXT_NOTIFY: AtomicBool = AtomicBool::new(false);
+
+/// There is a single instance of the BLE HID service, like [`Usb`](super::usb::Usb).
+pub struct Ble {
+    _private: (),
+}
+
+impl Ble {
+    /// Start the Bluetooth stack, and start advertising.
+    pub fn new() -> Result<Ble> {
+        unsafe {
+            to_result_void(ble_hid_init(status_cb, notify_cb))?;
+        }
+        info!("BLE HID advertising");
+        Ok(Ble { _private: () })
+    }
+
+    /// Is a host connected.
+    pub fn is_connected(&self) -> bool {
+        CONNECTED.load(Ordering::Acquire)
+    }
+
+    /// Send a keyboard report.  The BLE keyboard only has the boot report, so more than six keys
+    /// held is reported as such.
+    pub async fn send_keyboard_report(&self, mods: u8, keys: &[u8]) {
+        // The report id is in the report reference, and not sent.
+        let report = hid::boot_report(mods, keys);
+
+        // Notifications are queued by the stack.  When it runs out of buffers, wait for some of
+        // them to be sent, rather than dropping a report, which could leave a key held down.
+        if !notify(|| unsafe { ble_hid_send_keyboard(report.as_ptr(), report.len()) }).await {
+            warn!("BLE keyboard report dropped");
+        }
+    }
+
+    pub fn send_plover_report(&self, report: &[u8]) {
+        // The USB report starts with the report id, which isn't sent over BLE.
+        let report = match report.split_first() {
+            Some((&REPORT_PLOVER, rest)) => rest,
+            _ => report,
+        };
+        let err = unsafe { ble_hid_send_plover(report.as_ptr(), report.len()) };
+        if err != 0 {
+            warn!("BLE plover report failed: {}", err);
+        }
+    }
+
+    /// Does the host have the plover report open.
+    pub fn plover_open(&self) -> bool {
+        self.is_connected() && PLOVER_NOTIFY.load(Ordering::Acquire)
+    }
+
+    /// Has the host subscribed to the text stream.
+    pub fn text_open(&self) -> bool {
+        self.is_connected() && TEXT_NOTIFY.load(Ordering::Acquire)
+    }
+
+    /// Send a change to the text over the text stream: delete `remove` characters, then add
+    /// `append`.
+    pub async fn send_text(&self, mut remove: usize, append: &str) {
+        let mut rest = append;
+        while remove > 0 || !rest.is_empty() {
+            let count = remove.min(u8::MAX as usize);
+            remove -= count;
+
+            let mut end = rest.len().min(TEXT_NOTIFY_MAX - 1);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (text, more) = rest.split_at(end);
+            rest = more;
+
+            let mut buf = [0u8; TEXT_NOTIFY_MAX];
+            buf[0] = count as u8;
+            buf[1..1 + text.len()].copy_from_slice(text.as_bytes());
+            let len = 1 + text.len();
+            // Losing part of the text would leave the app's copy wrong from then on.
+            if !notify(|| unsafe { ble_text_send(buf.as_ptr(), len) }).await {
+                warn!("BLE text dropped");
+                return;
+            }
+        }
+    }
+
+    /// The hosts we are bonded with.
+    pub fn bonds(&self) -> Vec<BondInfo> {
+        let mut bonds: [BleBond; MAX_BONDS] = unsafe { core::mem::zeroed() };
+        let count = unsafe { ble_bonds(bonds.as_mut_ptr(), MAX_BONDS) } as usize;
+        bonds[..count.min(MAX_BONDS)]
+            .iter()
+            .map(|bond| {
+                let len = bond.name.iter().position(|&c| c == 0).unwrap_or(BOND_NAME_MAX);
+                let name = bond.name[..len].iter().map(|&c| c as u8).collect();
+                BondInfo {
+                    address: BleAddress(bond.addr),
+                    name: String::from_utf8(name).ok().filter(|name| !name.is_empty()),
+                    connected: bond.connected,
+                }
+            })
+            .collect()
+    }
+
+    /// Name a bonded host, or forget its name with an empty one.  Returns the error code, for
+    /// minder.
+    pub fn name_bond(&self, address: &BleAddress, name: &str) -> c_int {
+        unsafe { ble_bond_name(address.0.as_ptr(), name.as_ptr() as *const c_char, name.len()) }
+    }
+
+    /// Delete the bond with a host.  Returns the error code, for minder.
+    pub fn delete_bond(&self, address: &BleAddress) -> c_int {
+        unsafe { ble_bond_delete(address.0.as_ptr()) }
+    }
+
+    /// Let new hosts pair for this many seconds, or stop with zero.
+    pub fn set_pairing(&self, seconds: u32) {
+        info!("BLE pairing open for {} seconds", seconds);
+        unsafe { ble_pairing(seconds) };
+    }
+}
+
+/// Send a notification, waiting for the stack to have a buffer for it.  Returns false if it was
+/// dropped.
+async fn notify(send: impl Fn() -> c_int) -> bool {
+    for _ in 0..RETRIES {
+        let err = send();
+        if err != -(raw::ENOMEM as c_int) {
+            if err != 0 {
+                warn!("BLE notify failed: {}", err);
+            }
+            return err == 0;
+        }
+        sleep(Duration::millis_at_least(5)).await;
+    }
+    false
+}
+
+extern "C" fn status_cb(connected: bool) {
+    CONNECTED.store(connected, Ordering::Release);
+    if !connected {
+        PLOVER_NOTIFY.store(false, Ordering::Release);
+        TEXT_NOTIFY.store(false, Ordering::Release);
+    }
+    rust_ble_status(connected);
+}
+
+extern "C" fn notify_cb(report: u8, enabled: bool) {
+    match report {
+        REPORT_KEYBOARD => info!("BLE keyboard notify: {}", enabled),
+        REPORT_PLOVER => PLOVER_NOTIFY.store(enabled, Ordering::Release),
+        STREAM_TEXT => {
+            info!("BLE text stream: {}", enabled);
+            TEXT_NOTIFY.store(enabled, Ordering::Release);
+        }
+        _ => (),
+    }
+}
+
+extern "C" {
+    fn ble_hid_init(
+        status: extern "C" fn(bool),
+        notify: extern "C" fn(u8, bool),
+    ) -> c_int;
+    fn ble_hid_send_keyboard(report: *const u8, len: usize) -> c_int;
+    fn ble_hid_send_plover(report: *const u8, len: usize) -> c_int;
+    fn ble_text_send(data: *const u8, len: usize) -> c_int;
+    fn ble_bonds(bonds: *mut BleBond, max: usize) -> c_int;
+    fn ble_bond_name(addr: *const u8, name: *const c_char, len: usize) -> c_int;
+    fn ble_bond_delete(addr: *const u8) -> c_int;
+    fn ble_pairing(seconds: u32);
+}
@@ -3,56 +3,102 @@
 //! This interfaces directly with the USB stack.  As this is not very general, we just use the
 //! unsafe entries directly.
 
-use core::{ffi::CStr, ptr, sync::atomic::Ordering};
+use core::{ffi::{c_int, CStr}, ptr, sync::atomic::Ordering};
 
 use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use bbq_keyboard::{hid, time::Clock};
+use bbq_steno::Stroke;
 use log::{error, info, warn};
 use zephyr::{
     error::to_result_void,
     kio::sync::Mutex,
     raw,
-    sync::{atomic::AtomicPtr, Arc},
+    sync::{atomic::{AtomicBool, AtomicPtr}, Arc},
     sys::sync::Semaphore,
     time::{NoWait, Timeout},
     Error, Result,
 };
 
-use crate::rust_usb_status;
+use crate::{rust_usb_status, SysClock};
+
+/// The host polls the plover endpoint every millisecond while it is open, so a report left unread
+/// for this long, in ms, means it has been closed.
+const PLOVER_UNREAD_MS: u64 = 50;
+
+/// With no strokes being sent, how often, in ms, to send an empty report to the plover endpoint,
+/// to find out whether it is still open.
+const PLOVER_PROBE_MS: u64 = 250;
 
 /// There is a single instance of the USB system.  As this is somewhat unsafe, we'll just
-/// require the caller to create only a single instance of this (for now).
+/// require the caller to create only a single instance of this (for now).  Clones share the same
+/// interfaces.
+#[derive(Clone)]
 pub struct Usb {
     hid0: Arc<HidWrap>,
     hid1: Arc<HidWrap>,
     hid2: Arc<HidWrap>,
+    hid3: Arc<HidWrap>,
+    hid4: Arc<HidWrap>,
+    /// Only the boot keyboard is registered.
+    boot_only: bool,
 }
 
 impl Usb {
-    pub fn new() -> Result<Usb> {
+    /// Bring up USB.  With `boot_only`, only the boot keyboard is registered, for hosts that can't
+    /// handle anything else.  This stack builds its descriptors at compile time, so the other
+    /// interfaces are still described to the host, but they are left without a report descriptor,
+    /// and nothing is sent on them.
+    ///
+    /// Otherwise, the keyboard is described as NKRO, and only falls back to the boot report if the
+    /// host selects the boot protocol.
+    pub fn new(boot_only: bool) -> Result<Usb> {
         let hid0 = Self::setup_hid(c"HID_0", &HID0, Semaphore::new(0, u32::MAX).unwrap());
         let hid1 = Self::setup_hid(c"HID_1", &HID1, Semaphore::new(0, u32::MAX).unwrap());
         let hid2 = Self::setup_hid(c"HID_2", &HID2, Semaphore::new(0, u32::MAX).unwrap());
+        let hid3 = Self::setup_hid(c"HID_3", &HID3, Semaphore::new(0, u32::MAX).unwrap());
+        let hid4 = Self::setup_hid(c"HID_4", &HID4, Semaphore::new(0, u32::MAX).unwrap());
 
-        let kbd_desc = unsafe { hid_get_kbd_desc() };
+        let kbd_desc = if boot_only {
+            unsafe { hid_get_kbd_desc() }
+        } else {
+            U8Vec { base: hid::NKRO_REPORT_DESC.as_ptr(), len: hid::NKRO_REPORT_DESC.len() }
+        };
         unsafe {
             raw::usb_hid_register_device(hid0.device, kbd_desc.base, kbd_desc.len, &USB_OPS);
+            if hid_set_boot_keyboard(hid0.device) != 0 {
+                warn!("Unable to mark the keyboard as a boot keyboard");
+            }
             raw::usb_hid_init(hid0.device);
 
-            raw::usb_hid_register_device(
-                hid1.device,
-                PLOVER_HID_DESC.as_ptr(),
-                PLOVER_HID_DESC.len(),
-                &USB_OPS,
-            );
-            raw::usb_hid_init(hid1.device);
+            if !boot_only {
+                raw::usb_hid_register_device(
+                    hid1.device,
+                    PLOVER_HID_DESC.as_ptr(),
+                    PLOVER_HID_DESC.len(),
+                    &USB_OPS,
+                );
+                raw::usb_hid_init(hid1.device);
 
-            raw::usb_hid_register_device(
-                hid2.device,
-                MINDER_HID_DESC.as_ptr(),
-                MINDER_HID_DESC.len(),
-                &USB_OPS,
-            );
-            raw::usb_hid_init(hid2.device);
+                raw::usb_hid_register_device(
+                    hid2.device,
+                    MINDER_HID_DESC.as_ptr(),
+                    MINDER_HID_DESC.len(),
+                    &USB_OPS,
+                );
+                raw::usb_hid_init(hid2.device);
+
+                let mouse_desc = hid_get_mouse_desc();
+                raw::usb_hid_register_device(hid3.device, mouse_desc.base, mouse_desc.len, &USB_OPS);
+                raw::usb_hid_init(hid3.device);
+
+                raw::usb_hid_register_device(
+                    hid4.device,
+                    hid::CONSUMER_REPORT_DESC.as_ptr(),
+                    hid::CONSUMER_REPORT_DESC.len(),
+                    &USB_OPS,
+                );
+                raw::usb_hid_init(hid4.device);
+            }
 
             if raw::usb_enable(Some(status_cb)) != 0 {
                 error!("Failed to enable USB");
@@ -60,7 +106,11 @@ impl Usb {
             }
         }
 
-        Ok(Usb { hid0, hid1, hid2 })
+        if boot_only {
+            info!("USB offers only a boot keyboard");
+        }
+
+        Ok(Usb { hid0, hid1, hid2, hid3, hid4, boot_only })
     }
 
     fn setup_hid(cname: &CStr, global: &AtomicPtr<HidWrap>, out_sem: Semaphore) -> Arc<HidWrap> {
@@ -72,9 +122,11 @@ impl Usb {
         let hid = Arc::new(HidWrap {
             device: dev,
             out_sem,
+            host_read: AtomicBool::new(false),
             state: Mutex::new(HidIn {
                 ready: true,
                 additional: VecDeque::new(),
+                written: 0,
             }),
         });
 
@@ -95,17 +147,22 @@ impl Usb {
         hid
     }
 
-    pub async fn send_keyboard_report(&self, mods: u8, keys: &[u8]) {
-        if keys.len() > 6 {
-            // Ignore ones that have too many keys down?
-            return;
-        }
+    /// Is the keyboard sending boot reports, either because that is all that was registered, or
+    /// because the host selected the boot protocol.
+    fn boot_protocol(&self) -> bool {
+        self.boot_only || BOOT_PROTOCOL.load(Ordering::Acquire)
+    }
 
-        let mut report = [0u8; 8];
-        report[0] = mods;
-        for (i, key) in keys.iter().enumerate() {
-            report[i + 2] = *key;
-        }
+    pub async fn send_keyboard_report(&self, mods: u8, keys: &[u8]) {
+        let boot;
+        let nkro;
+        let report: &[u8] = if self.boot_protocol() {
+            boot = hid::boot_report(mods, keys);
+            &boot
+        } else {
+            nkro = hid::nkro_report(mods, keys);
+            &nkro
+        };
 
         let mut state = self.hid0.state.lock_async().await.unwrap();
 
@@ -127,6 +184,52 @@ impl Usb {
         }
     }
 
+    /// Send a mouse report, with the buttons held, and the movement.
+    pub async fn send_mouse_report(&self, buttons: u8, x: i8, y: i8) {
+        if self.boot_only {
+            return;
+        }
+        let report = [buttons, x as u8, y as u8, 0];
+
+        let mut state = self.hid3.state.lock_async().await.unwrap();
+        if state.ready {
+            unsafe {
+                raw::hid_int_ep_write(
+                    self.hid3.device,
+                    report.as_ptr(),
+                    report.len() as u32,
+                    ptr::null_mut(),
+                );
+            }
+            state.ready = false;
+        } else {
+            state.additional.push_back(report.to_vec());
+        }
+    }
+
+    /// Send a media key report, with the consumer usage code held, or zero for none.
+    pub async fn send_consumer_report(&self, code: u16) {
+        if self.boot_only {
+            return;
+        }
+        let report = hid::consumer_report(code);
+
+        let mut state = self.hid4.state.lock_async().await.unwrap();
+        if state.ready {
+            unsafe {
+                raw::hid_int_ep_write(
+                    self.hid4.device,
+                    report.as_ptr(),
+                    report.len() as u32,
+                    ptr::null_mut(),
+                );
+            }
+            state.ready = false;
+        } else {
+            state.additional.push_back(report.to_vec());
+        }
+    }
+
     /// Read a HID out report from the keyboard, or None, if there is none available.
     /// TODO: We really want to be able to sleep on this, or have it send an event, but for now,
     /// polling should at least keep the keyboard from freezing.
@@ -149,6 +252,9 @@ impl Usb {
     }
 
     pub fn send_plover_report(&self, report: &[u8]) {
+        if self.boot_only {
+            return;
+        }
         let mut state = self.hid1.state.lock().unwrap();
 
         // Todo, this is repeated, perhaps in the HidWrap as a method.
@@ -162,21 +268,57 @@ impl Usb {
                 );
             }
             state.ready = false;
+            state.written = SysClock.millis();
         } else {
             state.additional.push_back(report.to_vec());
         }
     }
 
+    /// Does it look like a program on the host has the plover interface open?
+    ///
+    /// The host only polls the endpoint when the interface is open, so once it has read a report
+    /// from us, and isn't leaving reports unread, we consider it open.  So that a program closing
+    /// it is noticed without waiting for a stroke to go unread, this sends an empty report, which
+    /// changes nothing on the host, whenever the endpoint has been idle for a while.  This is
+    /// called at idle, by the mode selection.
+    pub fn plover_open(&self) -> bool {
+        if self.boot_only || !self.hid1.host_read.load(Ordering::Acquire) {
+            return false;
+        }
+        let mut state = self.hid1.state.lock().unwrap();
+        let now = SysClock.millis();
+        if state.ready && now - state.written >= PLOVER_PROBE_MS {
+            let report = Stroke::empty().to_plover_hid();
+            unsafe {
+                raw::hid_int_ep_write(
+                    self.hid1.device,
+                    report.as_ptr(),
+                    report.len() as u32,
+                    ptr::null_mut(),
+                );
+            }
+            state.ready = false;
+            state.written = now;
+        }
+        state.additional.is_empty() && (state.ready || now - state.written < PLOVER_UNREAD_MS)
+    }
+
+    /// Is the minder interface registered.
+    pub fn has_minder(&self) -> bool {
+        !self.boot_only
+    }
+
     // TODO: Ideally, some minder protocols should be able to be dropped if the queue gets too
     // large, so that should probably be an argument here.
-    #[allow(dead_code)]
     pub fn send_minder_report(&self, report: &[u8]) {
+        if self.boot_only {
+            return;
+        }
         let mut state = self.hid2.state.lock().unwrap();
 
         // Todo, this is repeated, perhaps in the HidWrap as a method.
         if state.ready {
             unsafe {
-                info!("Send report {:02x?}", report);
                 raw::hid_int_ep_write(
                     self.hid2.device,
                     report.as_ptr(),
@@ -191,7 +333,6 @@ impl Usb {
     }
 
     /// Try reading a minder packet.  Might return a timeout if the timeout isn't met.
-    #[allow(dead_code)]
     pub fn minder_read_out<T>(&self, timeout: T, buf: &mut [u8]) -> Result<usize>
     where
         T: Into<Timeout>,
@@ -223,6 +364,8 @@ struct HidIn {
     ready: bool,
     /// Additional events to send.
     additional: VecDeque<Vec<u8>>,
+    /// When the last report was given to the driver, in ms.
+    written: u64,
 }
 
 /// The outer wrapper holds the device (which will be constant) and the Mutex (and possibly a
@@ -230,6 +373,9 @@ struct HidIn {
 struct HidWrap {
     device: *const raw::device,
     out_sem: Semaphore,
+    /// Set once the host has read a report from this endpoint, since it was last reset, suspended,
+    /// or configured.
+    host_read: AtomicBool,
     state: Mutex<HidIn>,
 }
 
@@ -240,13 +386,22 @@ unsafe impl Sync for HidWrap {}
 static HID0: AtomicPtr<HidWrap> = AtomicPtr::new(ptr::null_mut());
 static HID1: AtomicPtr<HidWrap> = AtomicPtr::new(ptr::null_mut());
 static HID2: AtomicPtr<HidWrap> = AtomicPtr::new(ptr::null_mut());
+static HID3: AtomicPtr<HidWrap> = AtomicPtr::new(ptr::null_mut());
+static HID4: AtomicPtr<HidWrap> = AtomicPtr::new(ptr::null_mut());
+
+/// The host has selected the boot protocol for the keyboard.  A bus reset goes back to the report
+/// protocol.
+static BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+
+/// The protocol code for the boot protocol, from the HID spec.
+const HID_PROTOCOL_BOOT: u8 = 0;
 
 static USB_OPS: raw::hid_ops = raw::hid_ops {
     get_report: None,
     int_in_ready: Some(hid_in_ready),
     int_out_ready: Some(hid_out_ready),
     on_idle: None,
-    protocol_change: None,
+    protocol_change: Some(hid_protocol_change),
     set_report: None,
 };
 
@@ -262,6 +417,12 @@ extern "C" fn hid_in_ready(device: *const raw::device) {
     if check_hid_in_ready(device, &HID2) {
         return;
     }
+    if check_hid_in_ready(device, &HID3) {
+        return;
+    }
+    if check_hid_in_ready(device, &HID4) {
+        return;
+    }
     panic!("hid callback from unknown device");
 }
 
@@ -275,6 +436,7 @@ fn check_hid_in_ready(device: *const raw::device, global: &AtomicPtr<HidWrap>) -
     if device != wrap.device {
         return false;
     }
+    wrap.host_read.store(true, Ordering::Release);
     let mut state = wrap.state.lock().unwrap();
 
     if state.ready {
@@ -295,6 +457,7 @@ fn check_hid_in_ready(device: *const raw::device, global: &AtomicPtr<HidWrap>) -
                 ptr::null_mut(),
             );
         }
+        state.written = SysClock.millis();
     } else {
         // Otherwise, indicate ready, so the next send will go here.
         state.ready = true;
@@ -303,6 +466,17 @@ fn check_hid_in_ready(device: *const raw::device, global: &AtomicPtr<HidWrap>) -
     true
 }
 
+// Only the keyboard offers the boot protocol, so only it will see this.
+extern "C" fn hid_protocol_change(device: *const raw::device, protocol: u8) {
+    let wrap = HID0.load(Ordering::Acquire);
+    if wrap.is_null() || device != unsafe { &*wrap }.device {
+        return;
+    }
+    let boot = protocol == HID_PROTOCOL_BOOT;
+    info!("Host selected the {} protocol", if boot { "boot" } else { "report" });
+    BOOT_PROTOCOL.store(boot, Ordering::Release);
+}
+
 extern "C" fn hid_out_ready(device: *const raw::device) {
     if check_hid_out_ready(device, &HID0) {
         return;
@@ -313,6 +487,12 @@ extern "C" fn hid_out_ready(device: *const raw::device) {
     if check_hid_out_ready(device, &HID2) {
         return;
     }
+    if check_hid_out_ready(device, &HID3) {
+        return;
+    }
+    if check_hid_out_ready(device, &HID4) {
+        return;
+    }
     panic!("hid out callback from unknown device");
 }
 
@@ -334,13 +514,35 @@ fn check_hid_out_ready(device: *const raw::device, global: &AtomicPtr<HidWrap>)
 extern "C" fn status_cb(status: raw::usb_dc_status_code, _param: *const u8) {
     // There is some slightly redundant use of types here.
     match status {
-        raw::usb_dc_status_code_USB_DC_CONFIGURED => rust_usb_status(0),
-        raw::usb_dc_status_code_USB_DC_SUSPEND => rust_usb_status(1),
+        raw::usb_dc_status_code_USB_DC_RESET => {
+            BOOT_PROTOCOL.store(false, Ordering::Release);
+            forget_host_reads();
+        }
+        raw::usb_dc_status_code_USB_DC_DISCONNECTED => forget_host_reads(),
+        raw::usb_dc_status_code_USB_DC_CONFIGURED => {
+            forget_host_reads();
+            rust_usb_status(0);
+        }
+        raw::usb_dc_status_code_USB_DC_SUSPEND => {
+            forget_host_reads();
+            rust_usb_status(1);
+        }
         raw::usb_dc_status_code_USB_DC_RESUME => rust_usb_status(2),
         _ => (),
     }
 }
 
+/// Whatever had the interfaces open may not any more, so wait for the host to read from them
+/// again before counting them as open.
+fn forget_host_reads() {
+    for global in [&HID0, &HID1, &HID2, &HID3, &HID4] {
+        let wrap = global.load(Ordering::Acquire);
+        if !wrap.is_null() {
+            unsafe { &*wrap }.host_read.store(false, Ordering::Release);
+        }
+    }
+}
+
 #[repr(C)]
 struct U8Vec {
     base: *const u8,
@@ -349,6 +551,8 @@ struct U8Vec {
 
 extern "C" {
     fn hid_get_kbd_desc() -> U8Vec;
+    fn hid_get_mouse_desc() -> U8Vec;
+    fn hid_set_boot_keyboard(dev: *const raw::device) -> c_int;
 }
 
 /// Plover HID descriptor.
@@ -0,0 +1,41 @@
+// Keys wired directly to GPIOs.
+//
+// The pins come from the "bbq-kbd-direct" node in the devicetree, which the Rust devicetree
+// support doesn't know about, so this gives a small API to read them by index.  The debouncing is
+// done in matrix.rs.
+
+#define DT_DRV_COMPAT bbq_kbd_direct
+
+#include <errno.h>
+#include <zephyr/kernel.h>
+#include <zephyr/drivers/gpio.h>
+
+static const struct gpio_dt_spec keys[] = {
+	DT_INST_FOREACH_PROP_ELEM_SEP(0, key_gpios, GPIO_DT_SPEC_GET_BY_IDX, (,))
+};
+
+BUILD_ASSERT(ARRAY_SIZE(keys) <= 64, "Too many direct keys");
+
+// Configure the pins as inputs, returning the number of keys, or a negative error code.
+int bbq_direct_init(void) {
+	for (size_t i = 0; i < ARRAY_SIZE(keys); i++) {
+		int ret;
+
+		if (!gpio_is_ready_dt(&keys[i])) {
+			return -ENODEV;
+		}
+		ret = gpio_pin_configure_dt(&keys[i], GPIO_INPUT);
+		if (ret < 0) {
+			return ret;
+		}
+	}
+	return ARRAY_SIZE(keys);
+}
+
+// Is the key at index pressed.  The pin's flags give which level that is.
+bool bbq_direct_get(size_t index) {
+	if (index >= ARRAY_SIZE(keys)) {
+		return false;
+	}
+	return gpio_pin_get_dt(&keys[index]) > 0;
+}
@@ -23,10 +23,42 @@
 
 use core::{ffi::c_int, slice};
 
-use alloc::vec::Vec;
-use bbq_keyboard::{dict::Dict, layout::LayoutActions, usb_typer::{enqueue_action, ActionHandler}, Event, KeyAction, Keyboard, LayoutMode, MinorMode, Mods};
-use bbq_steno::{dict::Joined, Stroke};
+use alloc::{string::{String, ToString}, vec::Vec};
+#[cfg(feature = "steno")]
+use bbq_keyboard::dict::{Dict, StenoEvents};
+#[cfg(feature = "steno")]
+use bbq_steno::dict::Strategy;
+#[cfg(feature = "qwerty")]
+use bbq_keyboard::keymap::Keymap;
+#[cfg(feature = "steno")]
+use bbq_keyboard::stenomap::StenoMap;
+#[cfg(feature = "trace")]
+use bbq_keyboard::trace::{Trace, TraceEvent};
+#[cfg(feature = "trainer")]
+use bbq_keyboard::trainer::Metronome;
+use bbq_keyboard::expand::Expander;
+use bbq_keyboard::ledpattern::LedPatterns;
+use bbq_keyboard::macros::StoredMacros;
+#[cfg(feature = "qwerty")]
+use bbq_keyboard::macros::Macro;
+#[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+use bbq_keyboard::macros::AddError;
+use bbq_keyboard::{backlight::KeyClass, layout::LayoutActions, notify::{Alert, Notifier}, output::{OutputLimiter, OutputStats}, power::{PowerLevel, PowerPolicy}, time::{self as ktime, Clock}, usage::Usage, usb_typer::{can_type, enqueue_action, ActionHandler, Fallback, HostLayout, UnicodeEntry}, KeyAction, KeyEvent, Keyboard, LayoutMode, MinorMode, Mods, MouseButtons};
+use bbq_steno::{dict::Joined, tape::Tape, Stroke};
 use log::{info, warn};
+#[cfg(feature = "trainer")]
+use minder::PaceSummary;
+#[cfg(all(any(feature = "qwerty", feature = "steno"), feature = "minder-flash"))]
+use minder::KEYMAP_CHUNK;
+#[cfg(all(feature = "steno", feature = "minder-flash"))]
+use bbq_steno::memdict::{DictPatch, MemDict, HEADER_MAX_BYTES};
+#[cfg(all(feature = "steno", feature = "minder-flash"))]
+use minder::{partition::SECTOR_SIZE, DICT_PATCH_MAX};
+#[cfg(feature = "minder-flash")]
+use minder::LedStep;
+#[cfg(feature = "qwerty")]
+use minder::MacroInfo;
+use minder::{message::Debug, partition, session::Verdict, Arbiter, EventKind, LookupStrategy, Message, ModeUsage, SessionId, Stream};
 use zephyr::{
     kio::{self, sync::Mutex}, kobj_define, printkln,
     sync::{
@@ -34,11 +66,14 @@ use zephyr::{
         Arc, SpinMutex,
     },
     sys::sync::Semaphore,
-    time::{self, Duration},
-    work::{WorkQueue, WorkQueueBuilder},
+    time::{self, Duration, Instant, Tick},
+    work::{futures::sleep, WorkQueue, WorkQueueBuilder},
 };
 
-use crate::{devices::usb::Usb, get_steno_indicator, get_steno_select_indicator, leds::manager::{self, LedManager}, SendWrap, WrapTimer};
+#[cfg(CONFIG_JOLT_BLE)]
+use crate::devices::ble::Ble;
+use crate::{crash, devices::usb::Usb, flash, metrics, SysClock, get_mode_indicator, get_steno_indicator, get_steno_select_indicator, leds::manager::{self, LedManager}};
+use crate::events::Events;
 
 /// Priority of main work queue.
 const MAIN_PRIORITY: c_int = 2;
@@ -46,19 +81,43 @@ const MAIN_PRIORITY: c_int = 2;
 /// The Steno thread runs at the lowest priority as these lookups can often take dozens of ms.
 const STENO_PRIORITY: c_int = 5;
 
+/// How many events the trace holds.
+#[cfg(feature = "trace")]
+const TRACE_RECORDS: usize = 2048;
+
 /// For initialization, the main thread will build this struct, and invoke 'build'.  The use of
 /// build is mainly to avoid having a large number of unnamed arguments.
 pub struct DispatchBuilder {
-    /// For now, pass in a sender for the main event queue.
-    ///
-    /// This should eventually go away.
-    pub equeue_send: Sender<Event>,
+    /// The main loop's inputs.
+    pub events: Events,
 
     /// The USB manager.
     pub usb: Usb,
 
+    /// The BLE HID service, if it started.
+    #[cfg(CONFIG_JOLT_BLE)]
+    pub ble: Option<Ble>,
+
     /// The LED manager.
     pub leds: LedManager,
+
+    /// The prefix for alerts typed to the host, None to disable them.
+    pub notify: Option<String>,
+
+    /// The cap on typed output, in HID reports per second, None for no cap.
+    pub output_rate: Option<u32>,
+
+    /// The LED to pulse when a brief is available, None to not look for them.
+    pub brief_led: Option<u8>,
+
+    /// How to type characters the host layout has no key for.
+    pub fallback: Fallback,
+
+    /// Text expansion for the keyboard modes.
+    pub expander: Expander,
+
+    /// How long on battery without activity before sleeping, None to never sleep.
+    pub sleep_after: Option<ktime::Duration>,
 }
 
 impl DispatchBuilder {
@@ -89,14 +148,17 @@ pub struct Dispatch {
     pub steno_worker: WorkQueue,
 
     /// Work to be sent to the steno worker.
-    steno_send: Sender<Stroke>,
+    steno_send: Sender<StenoRequest>,
 
-    /// The main event queue.
-    ///
-    /// For transition, this queue still exists, but over time, various things that do need queues
-    /// should use more specific types, or, when running on the same worker, just call what needs
-    /// the work.
-    pub equeue_send: Sender<Event>,
+    /// The dictionaries have been unloaded, to be rewritten, and will be loaded again at restart.
+    dicts_unloaded: SpinMutex<bool>,
+
+    /// The latest partial stroke to prepare.  Only the most recent matters, so at most one
+    /// Prepare request is queued, and the worker picks up whatever is here when it gets to it.
+    prepare: SpinMutex<Option<Stroke>>,
+
+    /// The main loop's inputs.
+    pub events: Events,
 
     /// Mode and raw mode.
     ///
@@ -107,10 +169,164 @@ pub struct Dispatch {
     /// The USB handler.
     usb: Usb,
 
+    /// The BLE handler.
+    #[cfg(CONFIG_JOLT_BLE)]
+    ble: Option<Ble>,
+
+    /// Where keyboard and plover reports go.
+    transport: SpinMutex<Transport>,
+
     /// The LED manager.
     ///
     /// TODO: pub is for transition.
     pub leds: Mutex<LedManager>,
+
+    /// The steno paper tape.  Recorded by the steno worker, read by minder.
+    pub tape: SpinMutex<Tape>,
+
+    /// How far along saving the tape is.
+    tape_save: SpinMutex<TapeSave>,
+
+    /// The dictionary profile requested by the host, if any.
+    profile: SpinMutex<Option<Profile>>,
+
+    /// Rate limiting for alerts typed to the host.
+    notifier: SpinMutex<Notifier>,
+
+    /// Typed output, shared by the steno worker and alerts.
+    stenotype_send: Sender<Joined>,
+
+    /// The other end of the typed output queue, used to flush it when output is killed.
+    stenotype_flush: Receiver<Joined>,
+
+    /// Rate limiting, and killing, of typed output.
+    output: SpinMutex<OutputLimiter>,
+
+    /// The LED to pulse when a brief is available.
+    brief_led: Option<u8>,
+
+    /// The mouse buttons held, kept in the reports that move the mouse.
+    mouse_buttons: SpinMutex<MouseButtons>,
+
+    /// Text expansion, watching the keys sent in the keyboard modes.
+    expander: SpinMutex<Expander>,
+
+    /// The keyboard layout the host is set to, for typing text.
+    host_layout: SpinMutex<HostLayout>,
+
+    /// How to type characters the host layout has no key for, unless the host has set a way to
+    /// enter Unicode.
+    fallback: Fallback,
+
+    /// How the host accepts characters by their code point.
+    unicode_entry: SpinMutex<UnicodeEntry>,
+
+    /// A mode change requested from the debug console, picked up by the layout task.
+    requested_mode: SpinMutex<Option<LayoutMode>>,
+
+    /// Detents the encoder has turned, not yet picked up by the layout task.
+    encoder: SpinMutex<i8>,
+
+    /// The scan codes of the keys currently held, on either side.  Only used for debugging.
+    keys_down: SpinMutex<Vec<u8>>,
+
+    /// Key events and strokes streamed to a minder monitor.
+    stream: SpinMutex<Stream>,
+
+    /// Time spent, and typing done, in each mode.
+    usage: SpinMutex<Usage>,
+
+    /// How responsive to be, backed off when typing slowly on battery.
+    power: SpinMutex<PowerPolicy>,
+
+    /// The practice metronome.
+    #[cfg(feature = "trainer")]
+    metronome: SpinMutex<Metronome>,
+
+    /// Which minder session may make changes.
+    arbiter: SpinMutex<Arbiter>,
+
+    /// A keymap change requested from the debug console, picked up by the layout task.  True
+    /// selects the stored keymap, false the built-in one.
+    #[cfg(feature = "qwerty")]
+    requested_keymap: SpinMutex<Option<bool>>,
+
+    /// The qwerty keymap in use, None for the built-in one.
+    #[cfg(feature = "qwerty")]
+    keymap: SpinMutex<Option<Keymap>>,
+
+    /// A change of keymap overlay, from a new profile or one expiring, picked up by the layout
+    /// task.
+    #[cfg(feature = "qwerty")]
+    requested_overlay: SpinMutex<Option<Option<String>>>,
+
+    /// A keymap being received over minder.
+    #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+    keymap_upload: SpinMutex<Vec<u8>>,
+
+    /// Set when minder has changed the stored macros, for the layout task to load them again.
+    #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+    requested_macros: SpinMutex<bool>,
+
+    /// A request from minder to switch steno maps, true for the stored one.
+    #[cfg(feature = "steno")]
+    requested_steno_map: SpinMutex<Option<bool>>,
+
+    /// The stored steno map in use, None for the built-in one.
+    #[cfg(feature = "steno")]
+    steno_map: SpinMutex<Option<StenoMap>>,
+
+    /// A steno map being received over minder.
+    #[cfg(all(feature = "steno", feature = "minder-flash"))]
+    steno_map_upload: SpinMutex<Vec<u8>>,
+
+    /// A dictionary patch being received over minder.
+    #[cfg(all(feature = "steno", feature = "minder-flash"))]
+    patch_upload: SpinMutex<Vec<u8>>,
+
+    /// The most recent events, for working out what went wrong.
+    #[cfg(feature = "trace")]
+    trace: SpinMutex<Trace>,
+
+    /// The trace being read out over minder, so that new events don't shift it along mid read.
+    #[cfg(feature = "trace")]
+    trace_snapshot: SpinMutex<Vec<u8>>,
+}
+
+/// A way of sending HID reports to a host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    Usb,
+    #[cfg(CONFIG_JOLT_BLE)]
+    Ble,
+}
+
+/// Requests to the steno worker.
+enum StenoRequest {
+    /// Translate a completed stroke, which was completed at the given time.
+    Translate(Stroke, ktime::Instant),
+    /// Speculatively look up the stroke in `prepare`.
+    Prepare,
+    /// Forget what has been typed, as some of it didn't reach the host.
+    Reset,
+    /// Type text that doesn't come from steno, in order with the translations.
+    #[cfg(feature = "steno")]
+    Text(String),
+    /// Drop the dictionaries, which are about to be rewritten, and say when that is done.
+    #[cfg(feature = "steno")]
+    Unload(Sender<()>),
+}
+
+/// A dictionary profile requested by the host.
+struct Profile {
+    name: String,
+    dicts: Option<Vec<u8>>,
+    /// The qwerty keymap overlay, by name.
+    overlay: Option<String>,
+    /// When steno translations are typed.
+    strategy: Option<LookupStrategy>,
+    /// When to fall back to the default profile.
+    expires: Option<Instant>,
 }
 
 impl Dispatch {
@@ -131,26 +347,84 @@ impl Dispatch {
         let (steno_send, steno_recv) = channel::bounded(10);
         let (stenotype_send, stenotype_recv) = channel::unbounded();
 
+        apply_led_patterns();
+        let (tape, tape_save) = load_tape();
+
         let this = Arc::new(Dispatch {
             main_worker,
             steno_worker,
             steno_send,
-            equeue_send: builder.equeue_send,
+            dicts_unloaded: SpinMutex::new(false),
+            prepare: SpinMutex::new(None),
+            events: builder.events,
             usb: builder.usb,
+            #[cfg(CONFIG_JOLT_BLE)]
+            ble: builder.ble,
+            transport: SpinMutex::new(Transport::Usb),
             leds: Mutex::new(builder.leds),
             raw_mode: SpinMutex::new(false),
             current_mode: SpinMutex::new(LayoutMode::Steno),
+            tape: SpinMutex::new(tape),
+            tape_save: SpinMutex::new(tape_save),
+            profile: SpinMutex::new(None),
+            notifier: SpinMutex::new(Notifier::new(builder.notify)),
+            stenotype_send: stenotype_send.clone(),
+            stenotype_flush: stenotype_recv.clone(),
+            output: SpinMutex::new(output_limiter(builder.output_rate)),
+            brief_led: builder.brief_led,
+            mouse_buttons: SpinMutex::new(MouseButtons::empty()),
+            expander: SpinMutex::new(builder.expander),
+            host_layout: SpinMutex::new(HostLayout::default()),
+            fallback: builder.fallback,
+            unicode_entry: SpinMutex::new(UnicodeEntry::default()),
+            requested_mode: SpinMutex::new(None),
+            encoder: SpinMutex::new(0),
+            keys_down: SpinMutex::new(Vec::new()),
+            stream: SpinMutex::new(Stream::new()),
+            usage: SpinMutex::new(load_usage()),
+            power: SpinMutex::new(power_policy(builder.sleep_after)),
+            #[cfg(feature = "trainer")]
+            metronome: SpinMutex::new(Metronome::new()),
+            arbiter: SpinMutex::new(Arbiter::new()),
+            #[cfg(feature = "qwerty")]
+            requested_keymap: SpinMutex::new(None),
+            #[cfg(feature = "qwerty")]
+            requested_overlay: SpinMutex::new(None),
+            #[cfg(feature = "qwerty")]
+            keymap: SpinMutex::new(None),
+            #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+            keymap_upload: SpinMutex::new(Vec::new()),
+            #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+            requested_macros: SpinMutex::new(false),
+            #[cfg(feature = "steno")]
+            requested_steno_map: SpinMutex::new(None),
+            #[cfg(feature = "steno")]
+            steno_map: SpinMutex::new(None),
+            #[cfg(all(feature = "steno", feature = "minder-flash"))]
+            steno_map_upload: SpinMutex::new(Vec::new()),
+            #[cfg(all(feature = "steno", feature = "minder-flash"))]
+            patch_upload: SpinMutex::new(Vec::new()),
+            #[cfg(feature = "trace")]
+            trace: SpinMutex::new(Trace::new(TRACE_RECORDS)),
+            #[cfg(feature = "trace")]
+            trace_snapshot: SpinMutex::new(Vec::new()),
         });
 
         // Fire off the steno main thread.
-        let this2 = this.clone();
-        let _ = kio::spawn(
-            async {
-                kio::spawn_local(Self::steno_main(this2, steno_recv, stenotype_send), c"w:steno");
-            },
-            &this.main_worker,
-            c"w:steno-start",
-        );
+        #[cfg(feature = "steno")]
+        {
+            let this2 = this.clone();
+            let _ = kio::spawn(
+                async {
+                    kio::spawn_local(Self::steno_main(this2, steno_recv, stenotype_send), c"w:steno");
+                },
+                &this.main_worker,
+                c"w:steno-start",
+            );
+        }
+        // Without steno, the layout never sends strokes, so nothing will be received.
+        #[cfg(not(feature = "steno"))]
+        let _ = (steno_recv, stenotype_send);
 
         // And a small thread to receive the events back, and enqueue them.  This small queue is
         // needed to avoid priority inversion problems with the low priority steno worker holding
@@ -173,23 +447,63 @@ impl Dispatch {
 
     /// Pass a stroke along to the steno worker.
     pub fn translate_steno(&self, stroke: Stroke) {
-        self.steno_send.try_send(stroke).unwrap();
+        self.steno_send.try_send(StenoRequest::Translate(stroke, SysClock.now())).unwrap();
+    }
+
+    /// Pass a partial stroke to the steno worker, to start the lookup early.
+    #[cfg(feature = "speculative-lookup")]
+    fn prepare_lookup(&self, stroke: Stroke) {
+        if self.prepare.lock().unwrap().replace(stroke).is_none() {
+            let _ = self.steno_send.try_send(StenoRequest::Prepare);
+        }
+    }
+
+    /// Queue text that doesn't come from steno to be typed, after any steno output.  With steno,
+    /// this goes by way of the steno worker, so that the joiner knows the text is there (see
+    /// [`bbq_steno::dict::Joiner::add_text`]).  Returns false if the queue is full.
+    fn queue_text(&self, text: String) -> bool {
+        #[cfg(feature = "steno")]
+        let queued = self.steno_send.try_send(StenoRequest::Text(text)).is_ok();
+        #[cfg(not(feature = "steno"))]
+        let queued = self.stenotype_send.try_send(Joined::Type { remove: 0, append: text }).is_ok();
+        queued
+    }
+
+    /// Have the steno worker forget what has been typed, as not all of it reached the host.
+    fn reset_steno(&self) {
+        let _ = self.steno_send.try_send(StenoRequest::Reset);
     }
 
-    /// Receive the translations back from the steno worker.
+    /// Receive the translations back from the steno worker.  When an app has subscribed to the
+    /// BLE text stream, the text goes there, instead of being typed.
     async fn steno_typer(this: Arc<Self>, typed: Receiver<Joined>) {
         while let Ok(action) = typed.recv_async().await {
             match action {
                 Joined::Type { remove, append } => {
+                    #[cfg(CONFIG_JOLT_BLE)]
+                    if let Some(ble) = this.ble.as_ref().filter(|ble| ble.text_open()) {
+                        ble.send_text(remove, &append).await;
+                        continue;
+                    }
+                    let mut wrap = KeyActionWrap(&this, this.output.lock().unwrap().generation());
                     for _ in 0..remove {
-                        this.usb_hid_push(KeyAction::KeyPress(
-                                Keyboard::DeleteBackspace,
-                                Mods::empty()))
-                            .await;
-                        this.usb_hid_push(KeyAction::KeyRelease).await;
+                        wrap.enqueue_actions([
+                            KeyAction::KeyPress(Keyboard::DeleteBackspace, Mods::empty()),
+                            KeyAction::KeyRelease,
+                        ].into_iter()).await;
+                    }
+                    // The removal still goes out, as what it takes back was typed.  The joiner then
+                    // starts over, as it thinks the translation was typed too.
+                    if !this.output.lock().unwrap().check_text(&append) {
+                        warn!("Dropping translation of {} characters", append.len());
+                        this.reset_steno();
+                        continue;
                     }
-                    enqueue_action(&mut KeyActionWrap(&this), &append).await;
+                    let layout = *this.host_layout.lock().unwrap();
+                    enqueue_action(&mut wrap, layout, this.fallback(), &append).await;
                 }
+                // Raw keys and commands aren't supported yet.
+                other => warn!("Unhandled steno action: {:?}", other),
             }
         }
         panic!("Steno typer exited");
@@ -199,47 +513,832 @@ impl Dispatch {
     ///
     /// This loops forever, receiving strokes, processing them, and sending them back as 'StenoText'
     /// events.  Eventually, this should be dispatching USB events directly.
-    async fn steno_main(this: Arc<Self>, strokes: Receiver<Stroke>, typed: Sender<Joined>) {
+    #[cfg(feature = "steno")]
+    async fn steno_main(this: Arc<Self>, strokes: Receiver<StenoRequest>, typed: Sender<Joined>) {
         printkln!("Steno thread running");
-        let mut eq_send = SendWrap(this.equeue_send.clone());
-        let mut dict = Dict::new();
+        let mut steno_events = StenoLeds(this.clone());
+        let mut dict = Dict::new(&flash::FLASH);
+        dict.set_suggest(this.brief_led.is_some());
+        let mut stroke_times = metrics::StrokeTimes::default();
         loop {
-            let stroke = strokes.recv_async().await.unwrap();
-            for action in dict.handle_stroke(stroke, &mut eq_send, &WrapTimer) {
+            // While a translation is held for a longer match, only wait until it is due.
+            let request = match dict.deadline() {
+                Some(deadline) => {
+                    let wait = deadline - SysClock.now();
+                    let until = time::now() + Duration::millis_at_least(wait.as_millis() as Tick);
+                    match strokes.recv_timeout_async(until).await {
+                        Ok(request) => request,
+                        Err(_) => {
+                            let actions = dict.flush();
+                            this.tape.lock().unwrap().push(Stroke::empty(), &actions);
+                            for action in actions {
+                                typed.send(action).unwrap();
+                            }
+                            continue;
+                        }
+                    }
+                }
+                None => strokes.recv_async().await.unwrap(),
+            };
+            let (stroke, completed) = match request {
+                StenoRequest::Translate(stroke, completed) => (stroke, completed),
+                StenoRequest::Prepare => {
+                    if let Some(stroke) = this.prepare.lock().unwrap().take() {
+                        this.update_profile(&mut dict);
+                        dict.prepare(stroke);
+                    }
+                    continue;
+                }
+                StenoRequest::Reset => {
+                    dict.reset();
+                    continue;
+                }
+                StenoRequest::Text(text) => {
+                    for action in dict.type_text(&text) {
+                        typed.send(action).unwrap();
+                    }
+                    continue;
+                }
+                StenoRequest::Unload(done) => {
+                    dict.unload();
+                    let _ = done.send(());
+                    continue;
+                }
+            };
+            this.update_profile(&mut dict);
+            if dict.is_empty() {
+                this.alert(Alert::NoDictionary);
+            }
+            let actions = dict.handle_stroke(stroke, &mut steno_events, &SysClock);
+            this.tape.lock().unwrap().push(stroke, &actions);
+            for action in actions {
                 typed.send(action).unwrap();
             }
+            stroke_times.add(SysClock.now() - completed);
+        }
+    }
+
+    /// Raise an alert.  If alerts are enabled, and this one hasn't been shown recently, it is typed
+    /// to the host.  This goes through the same queue as steno output, so it won't land in the
+    /// middle of a translation.
+    pub fn alert(&self, alert: Alert) {
+        let now = SysClock.millis();
+        if let Some(text) = self.notifier.lock().unwrap().alert(alert, now) {
+            warn!("Alert: {}", alert.message());
+            let _ = self.queue_text(text);
+        }
+    }
+
+    /// Type text sent by the host.  This goes through the same queue as steno output, so it is
+    /// typed after any translation in progress, and is subject to the same limits.  Text that is
+    /// too long, or has a character without a key, is rejected as a whole.
+    pub fn type_text(&self, text: String) -> Result<(), c_int> {
+        let einval = -(zephyr::raw::EINVAL as c_int);
+        if text.chars().count() > minder::TYPE_TEXT_MAX {
+            warn!("Text to type is too long: {} characters", text.chars().count());
+            return Err(einval);
+        }
+        let layout = *self.host_layout.lock().unwrap();
+        let fallback = self.fallback();
+        if let Some(ch) = text.chars().find(|&ch| !can_type(layout, fallback, ch)) {
+            warn!("Text to type has a character without a key: {:?}", ch);
+            return Err(einval);
+        }
+        info!("Typing {} characters from the host", text.len());
+        if !self.queue_text(text) {
+            return Err(-(zephyr::raw::EAGAIN as c_int));
+        }
+        Ok(())
+    }
+
+    /// Set the keyboard layout the host is set to.  This applies to text typed from then on.
+    pub fn set_host_layout(&self, layout: HostLayout) {
+        info!("Host layout: {}", layout.name());
+        *self.host_layout.lock().unwrap() = layout;
+    }
+
+    /// Set how the host accepts characters by their code point.  This applies to text typed from
+    /// then on.
+    pub fn set_unicode_entry(&self, entry: UnicodeEntry) {
+        info!("Unicode entry: {}", entry.name());
+        *self.unicode_entry.lock().unwrap() = entry;
+    }
+
+    /// How to type characters the host layout has no key for.  Unicode entry, once the host has
+    /// set it, covers more than the compose key from the board info.
+    fn fallback(&self) -> Fallback {
+        match *self.unicode_entry.lock().unwrap() {
+            UnicodeEntry::None => self.fallback,
+            entry => Fallback::Unicode(entry),
+        }
+    }
+
+    /// Ask the layout manager to change modes.  As with the mode key, this takes effect once all
+    /// keys are released.
+    pub fn request_mode(&self, mode: LayoutMode) {
+        *self.requested_mode.lock().unwrap() = Some(mode);
+    }
+
+    /// Retrieve a pending mode request.
+    pub fn take_requested_mode(&self) -> Option<LayoutMode> {
+        self.requested_mode.lock().unwrap().take()
+    }
+
+    /// Add to the turns of the encoder, for the layout task to act on.
+    pub fn add_encoder(&self, delta: i8) {
+        let mut encoder = self.encoder.lock().unwrap();
+        *encoder = encoder.saturating_add(delta);
+        let woken = self.power.lock().unwrap().add_activity(SysClock.now()).is_some();
+        if woken {
+            self.wake();
+        }
+    }
+
+    /// Retrieve the turns of the encoder since the last call.
+    pub fn take_encoder(&self) -> i8 {
+        core::mem::take(&mut *self.encoder.lock().unwrap())
+    }
+
+    /// Ask the layout task to switch to the stored keymap, or the built-in one.
+    #[cfg(feature = "qwerty")]
+    pub fn request_keymap(&self, stored: bool) {
+        *self.requested_keymap.lock().unwrap() = Some(stored);
+    }
+
+    /// Retrieve a pending keymap request.
+    #[cfg(feature = "qwerty")]
+    pub fn take_requested_keymap(&self) -> Option<bool> {
+        self.requested_keymap.lock().unwrap().take()
+    }
+
+    /// Record the keymap the layout task has switched to, None for the built-in one.
+    #[cfg(feature = "qwerty")]
+    pub fn use_keymap(&self, keymap: Option<Keymap>) {
+        *self.keymap.lock().unwrap() = keymap;
+    }
+
+    /// The keymap in use.
+    #[cfg(feature = "qwerty")]
+    pub fn keymap(&self) -> Keymap {
+        self.keymap.lock().unwrap().clone().unwrap_or_else(Keymap::builtin)
+    }
+
+    /// Receive part of a keymap over minder.  Once all of it has arrived, it is checked, written to
+    /// the keymap partition, and the layout task is asked to switch to it.  Any error discards what
+    /// has been received so far.
+    #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+    pub fn receive_keymap(&self, offset: u32, size: u32, data: &[u8]) -> Result<(), c_int> {
+        let einval = -(zephyr::raw::EINVAL as c_int);
+        let Some(image) = receive_chunk(&self.keymap_upload, offset, size, data, KEYMAP_CHUNK,
+                                        partition::KEYMAP.size)? else {
+            return Ok(());
+        };
+
+        let keymap = Keymap::decode(&image).ok_or(einval)?;
+        if let Err(e) = keymap.check() {
+            warn!("Keymap not stored: {}", e);
+            return Err(einval);
+        }
+        flash::program(partition::KEYMAP.offset, &image)?;
+        info!("Stored keymap {:?}", keymap.name);
+        self.request_keymap(true);
+        Ok(())
+    }
+
+    /// The stored macros.
+    #[cfg(feature = "qwerty")]
+    pub fn macros(&self) -> Vec<MacroInfo> {
+        let stored = load_macros().unwrap_or_default();
+        stored
+            .macros
+            .iter()
+            .map(|mac| MacroInfo { slot: mac.slot, name: mac.name.clone(), steps: mac.steps.len() as u32 })
+            .collect()
+    }
+
+    /// A stored macro, encoded, or empty if there is no macro of that name.
+    #[cfg(feature = "qwerty")]
+    pub fn download_macro(&self, name: &str) -> Vec<u8> {
+        let stored = load_macros().unwrap_or_default();
+        stored.find(name).map(Macro::encode).unwrap_or_default()
+    }
+
+    /// Add a macro from minder to the stored ones, and have the layout task use them.  Collisions
+    /// with other macros, or a slot no key plays, are EEXIST and ENOENT.
+    #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+    pub fn upload_macro(&self, mac: Macro, replace: bool) -> Result<(), c_int> {
+        let slot = mac.slot;
+        let mut stored = load_macros().unwrap_or_default();
+        if let Err(e) = stored.add(mac, replace, &self.keymap()) {
+            warn!("Macro {} not stored: {:?}", slot, e);
+            return Err(-(match e {
+                AddError::Invalid(_) => zephyr::raw::EINVAL,
+                AddError::NameUsed(_) | AddError::SlotUsed => zephyr::raw::EEXIST,
+                AddError::Unbound => zephyr::raw::ENOENT,
+            } as c_int));
+        }
+        flash::write(&partition::MACROS, &stored.encode())?;
+        info!("Stored macro {}", slot);
+        *self.requested_macros.lock().unwrap() = true;
+        Ok(())
+    }
+
+    /// Delete a stored macro by name, and have the layout task stop using it.
+    #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+    pub fn delete_macro(&self, name: &str) -> Result<(), c_int> {
+        let mut stored = load_macros().unwrap_or_default();
+        if !stored.remove(name) {
+            return Err(-(zephyr::raw::ENOENT as c_int));
+        }
+        flash::write(&partition::MACROS, &stored.encode())?;
+        info!("Deleted macro {:?}", name);
+        *self.requested_macros.lock().unwrap() = true;
+        Ok(())
+    }
+
+    /// Has minder changed the stored macros since the last call.
+    #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+    pub fn take_requested_macros(&self) -> bool {
+        core::mem::take(&mut *self.requested_macros.lock().unwrap())
+    }
+
+    /// Ask the layout task to switch to the stored steno map, or the built-in one.
+    #[cfg(feature = "steno")]
+    pub fn request_steno_map(&self, stored: bool) {
+        *self.requested_steno_map.lock().unwrap() = Some(stored);
+    }
+
+    /// Retrieve a pending steno map request.
+    #[cfg(feature = "steno")]
+    pub fn take_requested_steno_map(&self) -> Option<bool> {
+        self.requested_steno_map.lock().unwrap().take()
+    }
+
+    /// Record the steno map the layout task has switched to, None for the built-in one.
+    #[cfg(feature = "steno")]
+    pub fn use_steno_map(&self, map: Option<StenoMap>) {
+        *self.steno_map.lock().unwrap() = map;
+    }
+
+    /// The steno map in use.
+    #[cfg(feature = "steno")]
+    pub fn steno_map(&self) -> StenoMap {
+        self.steno_map.lock().unwrap().clone().unwrap_or_else(StenoMap::builtin)
+    }
+
+    /// Receive part of a steno map over minder.  As with a keymap, once all of it has arrived, it
+    /// is checked, written to its partition, and the layout task is asked to switch to it.
+    #[cfg(all(feature = "steno", feature = "minder-flash"))]
+    pub fn receive_steno_map(&self, offset: u32, size: u32, data: &[u8]) -> Result<(), c_int> {
+        let einval = -(zephyr::raw::EINVAL as c_int);
+        let Some(image) = receive_chunk(&self.steno_map_upload, offset, size, data, KEYMAP_CHUNK,
+                                        partition::STENO_MAP.size)? else {
+            return Ok(());
+        };
+
+        let map = StenoMap::decode(&image).ok_or(einval)?;
+        if let Err(e) = map.check() {
+            warn!("Steno map not stored: {}", e);
+            return Err(einval);
+        }
+        flash::program(partition::STENO_MAP.offset, &image)?;
+        info!("Stored steno map {:?}", map.name);
+        self.request_steno_map(true);
+        Ok(())
+    }
+
+    /// Replace the pattern of an LED indicator, and store it with the others the user has set, so
+    /// it is used after a reboot.  No steps goes back to the built-in pattern.
+    #[cfg(feature = "minder-flash")]
+    pub fn set_led_pattern(&self, indicator: &str, steps: &[LedStep]) -> Result<(), c_int> {
+        let einval = -(zephyr::raw::EINVAL as c_int);
+        let Some(indication) = manager::find(indicator) else {
+            warn!("No LED indicator {:?}", indicator);
+            return Err(einval);
+        };
+        if let Err(e) = LedPatterns::check_pattern(indicator, steps) {
+            warn!("LED pattern not stored: {}", e);
+            return Err(einval);
+        }
+
+        let mut patterns = load_led_patterns().unwrap_or_default();
+        patterns.set(indicator, steps);
+        if let Err(e) = patterns.check() {
+            warn!("LED pattern not stored: {}", e);
+            return Err(einval);
+        }
+        flash::program(partition::LED_PATTERNS.offset, &patterns.encode())?;
+        indication.set_pattern(steps);
+        info!("Stored LED pattern for {}", indicator);
+        Ok(())
+    }
+
+    /// Receive part of a dictionary patch over minder.  Once all of it has arrived, the patched
+    /// user dictionary is written to the staging area, and the keyboard restarts, installing it
+    /// before the dictionaries are loaded (see [`flash`]).  Any error discards what has been
+    /// received so far, and leaves the user dictionary as it was.
+    #[cfg(all(feature = "steno", feature = "minder-flash"))]
+    pub fn receive_dict_patch(&self, offset: u32, size: u32, data: &[u8]) -> Result<(), c_int> {
+        let einval = -(zephyr::raw::EINVAL as c_int);
+        let Some(patch) = receive_chunk(&self.patch_upload, offset, size, data, SECTOR_SIZE,
+                                        DICT_PATCH_MAX)? else {
+            return Ok(());
+        };
+        let patch: DictPatch = minicbor::decode(&patch).map_err(|_| einval)?;
+
+        // The user dictionary is only read, and the new image goes elsewhere, so the dictionaries
+        // in use are left alone.
+        let base = flash::FLASH.address(partition::USER_DICT.offset) as *const u8;
+        let mut stage = flash::DictStage::new(HEADER_MAX_BYTES as u32)?;
+        let mut error = einval;
+        let image = unsafe {
+            MemDict::patch_group(base, &patch, &mut |offset, data| match stage.write(offset, data) {
+                Ok(()) => true,
+                Err(e) => {
+                    error = e;
+                    false
+                }
+            })
+        };
+        let Some(image) = image else {
+            return Err(error);
+        };
+        stage.finish(image)?;
+        info!("Patched dictionary {:?}, {} changes, {} bytes", patch.name, patch.changes.len(), image);
+
+        self.request_shutdown();
+        Ok(())
+    }
+
+    /// Track a key going up or down, so the debug console can show what is held.
+    pub fn track_key(&self, key: KeyEvent) {
+        if !key.is_valid() {
+            return;
+        }
+        self.stream.lock().unwrap().key(key.key(), key.is_press(), SysClock.millis());
+        let mut keys = self.keys_down.lock().unwrap();
+        keys.retain(|&k| k != key.key());
+        if key.is_press() {
+            keys.push(key.key());
+            keys.sort_unstable();
+            let mode = *self.current_mode.lock().unwrap();
+            self.usage.lock().unwrap().add_key(mode);
+            let woken = {
+                let mut power = self.power.lock().unwrap();
+                let asleep = power.is_asleep();
+                power.add_key(SysClock.now());
+                asleep
+            };
+            if woken {
+                self.wake();
+            }
+        }
+    }
+
+    /// Account for time spent in the current mode.
+    pub fn add_usage_time(&self, elapsed: ktime::Duration) {
+        let mode = *self.current_mode.lock().unwrap();
+        self.usage.lock().unwrap().add_time(mode, elapsed);
+    }
+
+    /// The usage of each mode, for reporting.
+    pub fn usage(&self) -> Vec<ModeUsage> {
+        self.usage.lock().unwrap().report()
+    }
+
+    /// Start the practice metronome, or stop it with a rate of zero.  Returns the summary of the
+    /// session that was running.
+    #[cfg(feature = "trainer")]
+    pub fn set_pace(&self, spm: u32) -> PaceSummary {
+        let mut metronome = self.metronome.lock().unwrap();
+        let summary = metronome.summary();
+        metronome.set_rate(spm);
+        summary
+    }
+
+    /// The summary of the current, or most recent, practice session.
+    #[cfg(feature = "trainer")]
+    pub fn pace(&self) -> PaceSummary {
+        self.metronome.lock().unwrap().summary()
+    }
+
+    /// Advance the metronome, flashing the mode LED on the beat.
+    #[cfg(feature = "trainer")]
+    pub fn pace_tick(&self, elapsed: ktime::Duration) {
+        if self.metronome.lock().unwrap().tick(elapsed) {
+            self.leds.lock().unwrap().set_oneshot(0, &manager::BEAT_INDICATOR);
+        }
+    }
+
+    /// Let the user know that a shorter outline is available for what they just wrote.
+    pub fn brief_available(&self) {
+        if let Some(led) = self.brief_led {
+            self.leds.lock().unwrap().set_oneshot(led as usize, &manager::BRIEF_INDICATOR);
+        }
+    }
+
+    /// Decide whether a minder message from a session can be handled now.
+    pub fn arbitrate(&self, session: SessionId, message: &Message) -> Verdict {
+        let now = SysClock.millis();
+        self.arbiter.lock().unwrap().check(session, message, now)
+    }
+
+    /// Start, renew, or end streaming events to a minder monitor.
+    pub fn subscribe(&self, events: Vec<EventKind>, timeout: u32) -> Debug {
+        self.stream.lock().unwrap().subscribe(events, timeout, SysClock.millis())
+    }
+
+    /// The next streamed event to send to the monitor.
+    pub fn stream_event(&self) -> Option<Debug> {
+        self.stream.lock().unwrap().pop(SysClock.millis())
+    }
+
+    /// Record an event in the trace.
+    #[cfg(feature = "trace")]
+    pub fn trace(&self, event: TraceEvent) {
+        self.trace.lock().unwrap().record(SysClock.now(), event);
+    }
+
+    /// Read part of the trace.  Reading from the start takes a fresh snapshot.
+    #[cfg(feature = "trace")]
+    pub fn read_trace(&self, offset: u32) -> Debug {
+        let mut snapshot = self.trace_snapshot.lock().unwrap();
+        if offset == 0 {
+            *snapshot = self.trace.lock().unwrap().export();
+        }
+        let start = (offset as usize).min(snapshot.len());
+        let end = (start + minder::TRACE_CHUNK as usize).min(snapshot.len());
+        Debug::Trace {
+            offset,
+            size: snapshot.len() as u32,
+            data: snapshot[start..end].to_vec(),
+        }
+    }
+
+    /// Note whether the keyboard is running from the battery.  There is no battery gauge, so this
+    /// is whenever neither USB nor the other half, as primary, is powering it.
+    pub fn set_battery(&self, on_battery: bool) {
+        self.power.lock().unwrap().set_battery(on_battery);
+    }
+
+    /// Update the power policy from the typing rate in the usage stats.
+    pub fn update_power(&self) {
+        let rate = self.usage.lock().unwrap().rate();
+        let level = self.power.lock().unwrap().update(SysClock.now(), rate);
+        if let Some(level) = level {
+            match level {
+                PowerLevel::Full => info!("Typing picked up, full power"),
+                PowerLevel::Saving => info!("Typing slowly on battery, saving power ({} keys/min)", rate),
+                PowerLevel::Asleep => {
+                    info!("Idle on battery, sleeping");
+                    self.leds.lock().unwrap().set_asleep(true);
+                }
+            }
+        }
+    }
+
+    /// The power policy has left sleep, on a key or the encoder.
+    fn wake(&self) {
+        info!("Woken from sleep");
+        self.leds.lock().unwrap().set_asleep(false);
+    }
+
+    /// Whether the keyboard is asleep, and the matrix can wait for a key interrupt.
+    pub fn is_asleep(&self) -> bool {
+        self.power.lock().unwrap().is_asleep()
+    }
+
+    /// How often to refresh the LEDs.
+    pub fn led_period(&self) -> ktime::Duration {
+        self.power.lock().unwrap().led_period()
+    }
+
+    /// How often to sample the usage stats.
+    pub fn stats_period(&self) -> ktime::Duration {
+        self.power.lock().unwrap().stats_period()
+    }
+
+    /// Whether the matrix scan may speed up while keys are changing.
+    pub fn scan_boost(&self) -> bool {
+        self.power.lock().unwrap().scan_boost()
+    }
+
+    /// Save the usage to flash, if it is time to.
+    pub fn save_usage(&self) {
+        let now = SysClock.now();
+        let data = {
+            let mut usage = self.usage.lock().unwrap();
+            if !usage.should_save(now) {
+                return;
+            }
+            usage.encode()
+        };
+        self.write_usage(&data);
+    }
+
+    fn write_usage(&self, data: &[u8]) {
+        if let Err(e) = flash::write(&partition::STATS, data) {
+            warn!("Unable to save usage: {}", e);
+            self.alert(Alert::FlashWriteFailed);
+        }
+    }
+
+    /// Keep the saved tape up to date.  Each change is staged, to be written out if the keyboard
+    /// goes down (see [`crash::stage_tape`]), and the tape is saved to flash every
+    /// [`TAPE_SAVE_INTERVAL`], or right away if `flush` is set.
+    pub fn save_tape(&self, flush: bool) {
+        let now = SysClock.now();
+        let (offset, data) = {
+            let mut save = self.tape_save.lock().unwrap();
+            {
+                let tape = self.tape.lock().unwrap();
+                if tape.generation() != save.staged {
+                    save.staged = tape.generation();
+                    save.data = tape.save();
+                    if let Err(e) = crash::stage_tape(save.offset(), &save.data) {
+                        warn!("Unable to stage tape: {}", e);
+                    }
+                }
+            }
+            if save.saved == save.staged || (!flush && now - save.at < TAPE_SAVE_INTERVAL) {
+                return;
+            }
+            save.saved = save.staged;
+            save.at = now;
+            let offset = save.offset();
+            // The next save goes to the other sector, so this one is kept if that is cut short.
+            save.slot ^= 1;
+            (offset, core::mem::take(&mut save.data))
+        };
+
+        let part = partition::Partition { offset, size: partition::SECTOR_SIZE, ..partition::TAPE };
+        match flash::write(&part, &data) {
+            Ok(()) => crash::tape_saved(),
+            Err(e) => warn!("Unable to save tape: {}", e),
+        }
+    }
+
+    /// Unload the steno dictionaries, before their partitions are written, as the steno worker reads
+    /// them straight from flash.  This waits until the worker has let go of them.  They stay
+    /// unloaded until the keyboard restarts, which it does once the session writing them is done
+    /// (see [`Dispatch::release`]).
+    #[cfg(all(feature = "steno", feature = "minder-flash"))]
+    pub fn unload_dicts(&self) {
+        // Only the session with the privilege to make changes writes flash, so there is no race
+        // between taking the flag and the worker letting go.
+        if core::mem::replace(&mut *self.dicts_unloaded.lock().unwrap(), true) {
+            return;
+        }
+        let (done, wait) = channel::bounded(1);
+        self.steno_send.send(StenoRequest::Unload(done)).unwrap();
+        let _ = wait.recv();
+        info!("Unloaded the steno dictionaries");
+    }
+
+    /// A session has given up the privilege to make changes.  If it wrote the dictionaries,
+    /// restart to load them again.
+    pub fn release(&self) {
+        if *self.dicts_unloaded.lock().unwrap() {
+            info!("Dictionaries written, restarting to load them");
+            self.request_shutdown();
+        }
+    }
+
+    /// Ask the main loop to shut down and reboot.  This returns right away, so the caller can
+    /// still reply to the host.
+    pub fn request_shutdown(&self) {
+        self.events.shutdown();
+    }
+
+    /// Get ready to reboot: stop typing, save the usage and the tape if they have changed, and park
+    /// the flash, so the reboot doesn't land in the middle of a write.
+    pub async fn shutdown(&self) {
+        info!("Shutting down");
+        self.kill_output().await;
+        let data = {
+            let mut usage = self.usage.lock().unwrap();
+            if usage.flush(SysClock.now()) { Some(usage.encode()) } else { None }
+        };
+        if let Some(data) = data {
+            self.write_usage(&data);
+        }
+        self.save_tape(true);
+        flash::park().await;
+    }
+
+    /// The scan codes of the keys currently held.
+    pub fn keys_down(&self) -> Vec<u8> {
+        self.keys_down.lock().unwrap().clone()
+    }
+
+    /// The name of the current dictionary profile, if one has been selected.
+    pub fn profile_name(&self) -> Option<String> {
+        self.profile.lock().unwrap().as_ref().map(|p| p.name.clone())
+    }
+
+    /// The keymap overlay selected by the current profile, if any.
+    pub fn profile_overlay(&self) -> Option<String> {
+        self.profile.lock().unwrap().as_ref().and_then(|p| p.overlay.clone())
+    }
+
+    /// Select a dictionary profile.  The dictionaries will be applied on the next stroke, and the
+    /// keymap overlay once no keys are held.  A timeout of zero never expires.
+    pub fn set_profile(
+        &self,
+        name: String,
+        dicts: Option<Vec<u8>>,
+        timeout: u32,
+        overlay: Option<String>,
+        strategy: Option<LookupStrategy>,
+    ) {
+        let expires = if timeout == 0 {
+            None
+        } else {
+            Some(time::now() + Duration::millis_at_least(timeout as Tick * 1000))
+        };
+        #[cfg(feature = "qwerty")]
+        {
+            *self.requested_overlay.lock().unwrap() = Some(overlay.clone());
+        }
+        *self.profile.lock().unwrap() = Some(Profile { name, dicts, overlay, strategy, expires });
+    }
+
+    /// Revert to the default profile, if the requested one has expired.
+    fn expire_profile(&self, profile: &mut Option<Profile>) {
+        let Some(Profile { expires: Some(expires), .. }) = profile.as_ref() else {
+            return;
+        };
+        if time::now() < *expires {
+            return;
+        }
+        if let Some(expired) = profile.take() {
+            info!("Profile {:?} expired", expired.name);
+            #[cfg(feature = "qwerty")]
+            if expired.overlay.is_some() {
+                *self.requested_overlay.lock().unwrap() = Some(None);
+            }
         }
     }
 
+    /// Apply the requested profile to the dictionary, reverting to the default if it has expired.
+    #[cfg(feature = "steno")]
+    fn update_profile(&self, dict: &mut Dict) {
+        let mut profile = self.profile.lock().unwrap();
+        self.expire_profile(&mut profile);
+        dict.select(profile.as_ref().and_then(|p| p.dicts.as_deref()));
+        let (strategy, timeout) = match profile.as_ref().and_then(|p| p.strategy).unwrap_or_default() {
+            LookupStrategy::Eager => (Strategy::Eager, 0),
+            LookupStrategy::Longest { timeout } => (Strategy::Longest, timeout),
+        };
+        dict.set_strategy(strategy, ktime::Duration::from_millis(timeout as u64));
+    }
+
+    /// Retrieve a change of keymap overlay, from a new profile, or the profile expiring.
+    #[cfg(feature = "qwerty")]
+    pub fn take_requested_overlay(&self) -> Option<Option<String>> {
+        self.expire_profile(&mut self.profile.lock().unwrap());
+        self.requested_overlay.lock().unwrap().take()
+    }
+
+    /// Push typed output to the USB stack, waiting as needed to stay within the rate cap.  Returns
+    /// false, without sending anything, if output has been killed since `generation`.
+    async fn typed_push(&self, key: KeyAction, generation: u32) -> bool {
+        let delay = {
+            let mut output = self.output.lock().unwrap();
+            if output.generation() != generation {
+                return false;
+            }
+            output.delay(SysClock.millis())
+        };
+        if delay > 0 {
+            sleep(Duration::millis_at_least(delay as Tick)).await;
+            // The output may have been killed while waiting.
+            if self.output.lock().unwrap().generation() != generation {
+                return false;
+            }
+        }
+        self.usb_hid_push(key).await;
+        true
+    }
+
+    /// The counts of typed output that was stopped.
+    pub fn output_stats(&self) -> OutputStats {
+        self.output.lock().unwrap().stats()
+    }
+
     /// Push USB-hid events to the USB stack.
     pub async fn usb_hid_push(&self, key: KeyAction) {
         match key {
             KeyAction::KeyPress(code, mods) => {
                 let code = code as u8;
-                self.usb
-                    .send_keyboard_report(mods.bits(), slice::from_ref(&code))
-                    .await;
+                self.send_keyboard_report(mods.bits(), slice::from_ref(&code)).await;
             }
             KeyAction::KeyRelease => {
-                self.usb.send_keyboard_report(0, &[]).await;
+                self.send_keyboard_report(0, &[]).await;
             }
             KeyAction::KeySet(keys) => {
-                // TODO We don't handle more than 6 keys, which qwerty mode can do.  For now, just
-                // report if we can.
+                // Any number of keys can be held in qwerty mode.  USB reports them all, unless the
+                // host wants the boot protocol.
                 let (mods, keys) = keyset_to_hid(keys);
-                self.usb.send_keyboard_report(mods.bits(), &keys).await;
+                self.send_keyboard_report(mods.bits(), &keys).await;
             }
             KeyAction::ModOnly(mods) => {
-                self.usb.send_keyboard_report(mods.bits(), &[]).await;
+                self.send_keyboard_report(mods.bits(), &[]).await;
             }
             KeyAction::Stall => (),
+            KeyAction::MousePress(buttons) => {
+                *self.mouse_buttons.lock().unwrap() = buttons;
+                self.send_mouse_report(buttons, 0, 0).await;
+            }
+            KeyAction::MouseMove(x, y) => {
+                let buttons = *self.mouse_buttons.lock().unwrap();
+                self.send_mouse_report(buttons, x, y).await;
+            }
+            KeyAction::Consumer(code) => self.send_consumer_report(code).await,
+        }
+    }
+
+    /// The BLE HID service, if it started, for minder to manage the bonds.
+    #[cfg(CONFIG_JOLT_BLE)]
+    pub fn ble(&self) -> Option<&Ble> {
+        self.ble.as_ref()
+    }
+
+    /// Choose where reports go.  USB is used whenever it is configured, as the keyboard is then
+    /// plugged into that host.  Otherwise, reports go to a connected BLE host.
+    pub fn select_transport(&self, usb: bool, ble: bool) {
+        #[cfg(CONFIG_JOLT_BLE)]
+        let next = if !usb && ble && self.ble.is_some() { Transport::Ble } else { Transport::Usb };
+        #[cfg(not(CONFIG_JOLT_BLE))]
+        let next = {
+            let _ = (usb, ble);
+            Transport::Usb
+        };
+
+        let mut transport = self.transport.lock().unwrap();
+        if *transport != next {
+            info!("Sending reports over {:?}", next);
+            *transport = next;
+        }
+    }
+
+    /// Send a keyboard report over the current transport.
+    async fn send_keyboard_report(&self, mods: u8, keys: &[u8]) {
+        let transport = *self.transport.lock().unwrap();
+        match transport {
+            Transport::Usb => self.usb.send_keyboard_report(mods, keys).await,
+            #[cfg(CONFIG_JOLT_BLE)]
+            Transport::Ble => {
+                if let Some(ble) = &self.ble {
+                    ble.send_keyboard_report(mods, keys).await;
+                }
+            }
+        }
+    }
+
+    /// Send a mouse report.  The mouse is only on USB, so these are dropped over BLE.
+    async fn send_mouse_report(&self, buttons: MouseButtons, x: i8, y: i8) {
+        let transport = *self.transport.lock().unwrap();
+        match transport {
+            Transport::Usb => self.usb.send_mouse_report(buttons.bits(), x, y).await,
+            #[cfg(CONFIG_JOLT_BLE)]
+            Transport::Ble => (),
+        }
+    }
+
+    /// Send a media key report.  As with the mouse, these are only on USB.
+    async fn send_consumer_report(&self, code: u16) {
+        let transport = *self.transport.lock().unwrap();
+        match transport {
+            Transport::Usb => self.usb.send_consumer_report(code).await,
+            #[cfg(CONFIG_JOLT_BLE)]
+            Transport::Ble => (),
+        }
+    }
+
+    /// Is a steno program on the host using the plover interface.
+    pub fn plover_open(&self) -> bool {
+        let transport = *self.transport.lock().unwrap();
+        match transport {
+            Transport::Usb => self.usb.plover_open(),
+            #[cfg(CONFIG_JOLT_BLE)]
+            Transport::Ble => self.ble.as_ref().is_some_and(|ble| ble.plover_open()),
         }
     }
 
     /// Send a report over the plover protocol.  Or at least attempt to.
     pub fn send_plover_report(&self, report: &[u8]) {
-        // TODO: This seems to block and should become async.
-        self.usb.send_plover_report(report);
+        let transport = *self.transport.lock().unwrap();
+        match transport {
+            // TODO: This seems to block and should become async.
+            Transport::Usb => self.usb.send_plover_report(report),
+            #[cfg(CONFIG_JOLT_BLE)]
+            Transport::Ble => {
+                if let Some(ble) = &self.ble {
+                    ble.send_plover_report(report);
+                }
+            }
+        }
     }
 
     /// This loop is needed to read the USB HID report.
@@ -276,15 +1375,12 @@ impl Dispatch {
 impl LayoutActions for Dispatch {
     async fn set_mode(&self, mode: LayoutMode) {
         info!("mode: {:?}", mode);
-        let next = match mode {
-            LayoutMode::Steno => get_steno_indicator(*self.raw_mode.lock().unwrap()),
-            LayoutMode::StenoDirect => &manager::STENO_DIRECT_INDICATOR,
-            LayoutMode::Taipo => &manager::TAIPO_INDICATOR,
-            LayoutMode::Qwerty => &manager::QWERTY_INDICATOR,
-            _ => &manager::QWERTY_INDICATOR,
-        };
+        let next = get_mode_indicator(mode, *self.raw_mode.lock().unwrap());
         self.leds.lock().unwrap().set_base(0, next);
         *self.current_mode.lock().unwrap() = mode;
+        self.expander.lock().unwrap().clear();
+        #[cfg(feature = "trace")]
+        self.trace(TraceEvent::Mode(mode));
     }
 
     async fn set_mode_select(&self, mode: LayoutMode) {
@@ -293,20 +1389,47 @@ impl LayoutActions for Dispatch {
             LayoutMode::StenoDirect => &manager::STENO_DIRECT_SELECT_INDICATOR,
             LayoutMode::Taipo => &manager::TAIPO_SELECT_INDICATOR,
             LayoutMode::Qwerty => &manager::QWERTY_SELECT_INDICATOR,
+            LayoutMode::Numpad => &manager::NUMPAD_SELECT_INDICATOR,
+            #[cfg(feature = "experimental")]
+            LayoutMode::Experimental(id) => manager::experiment_indicator(id, true),
             _ => &manager::QWERTY_SELECT_INDICATOR,
         };
         self.leds.lock().unwrap().set_base(0, next);
     }
 
     async fn send_key(&self, key: KeyAction) {
-        self.usb_hid_push(key).await
+        let expansion = self.expander.lock().unwrap().key(&key);
+        self.usb_hid_push(key).await;
+
+        // The expansion goes through the typed output queue, so it is typed after the trigger's
+        // key release.
+        if let Some(expansion) = expansion {
+            info!("Expanding {} characters", expansion.remove);
+            let _ = self.stenotype_send.try_send(Joined::Type {
+                remove: expansion.remove,
+                append: expansion.text,
+            });
+        }
     }
 
-    async fn set_sub_mode(&self, _submode: MinorMode) {
-        // At this point, this doesn't do anything.
+    async fn set_sub_mode(&self, submode: MinorMode) {
+        // Only caps word is shown, the artsey layers aren't.
+        if let MinorMode::CapsWord(on) = submode {
+            let next = if on {
+                &manager::CAPS_WORD_INDICATOR
+            } else {
+                get_mode_indicator(*self.current_mode.lock().unwrap(), *self.raw_mode.lock().unwrap())
+            };
+            self.leds.lock().unwrap().set_base(0, next);
+        }
     }
 
     async fn send_raw_steno(&self, stroke: Stroke) {
+        self.stream.lock().unwrap().stroke(|| stroke.to_string(), SysClock.millis());
+        let mode = *self.current_mode.lock().unwrap();
+        self.usage.lock().unwrap().add_stroke(mode);
+        #[cfg(feature = "trainer")]
+        self.metronome.lock().unwrap().stroke();
         if *self.current_mode.lock().unwrap() == LayoutMode::Steno {
             self.translate_steno(stroke);
         } else {
@@ -315,6 +1438,41 @@ impl LayoutActions for Dispatch {
             self.send_plover_report(&Stroke::empty().to_plover_hid());
         }
     }
+
+    #[cfg(feature = "speculative-lookup")]
+    async fn prepare_steno(&self, stroke: Stroke) {
+        if *self.current_mode.lock().unwrap() == LayoutMode::Steno {
+            self.prepare_lookup(stroke);
+        }
+    }
+
+    async fn kill_output(&self) {
+        self.output.lock().unwrap().kill();
+        let mut flushed = 0;
+        while self.stenotype_flush.try_recv().is_ok() {
+            flushed += 1;
+        }
+        // Typing may have stopped between a press and its release.
+        self.usb_hid_push(KeyAction::KeyRelease).await;
+        // What the joiner thinks was typed no longer is.
+        self.reset_steno();
+        warn!("Output killed, {} queued translations flushed", flushed);
+    }
+
+    async fn type_text(&self, text: &str) {
+        let _ = self.queue_text(text.to_string());
+    }
+
+    async fn set_key_classes(&self, keys: &[KeyClass]) {
+        self.leds.lock().unwrap().set_key_classes(keys);
+    }
+
+    async fn save_macros(&self, macros: &StoredMacros) {
+        if let Err(e) = flash::write(&partition::MACROS, &macros.encode()) {
+            warn!("Unable to save macros: {}", e);
+            self.alert(Alert::FlashWriteFailed);
+        }
+    }
 }
 
 // Qwerty mode just sends scan codes, but not the mod bits as expected by the HID layer.  To fix
@@ -334,12 +1492,184 @@ fn keyset_to_hid(keys: Vec<Keyboard>) -> (Mods, Vec<u8>) {
     (mods, result)
 }
 
-struct KeyActionWrap<'a>(&'a Dispatch);
+/// Add a chunk to an upload from minder.  Chunks must arrive in order, starting at offset zero, and
+/// be no larger than `chunk`, and the whole upload no larger than `limit`.  Returns the upload once
+/// all `size` bytes have arrived.  Any error discards what has been received so far.
+#[cfg(all(any(feature = "qwerty", feature = "steno"), feature = "minder-flash"))]
+fn receive_chunk(
+    upload: &SpinMutex<Vec<u8>>,
+    offset: u32,
+    size: u32,
+    data: &[u8],
+    chunk: u32,
+    limit: u32,
+) -> Result<Option<Vec<u8>>, c_int> {
+    let mut upload = upload.lock().unwrap();
+    if offset == 0 {
+        upload.clear();
+    }
+    if offset as usize != upload.len()
+        || data.len() > chunk as usize
+        || size > limit
+        || offset + data.len() as u32 > size
+    {
+        upload.clear();
+        return Err(-(zephyr::raw::EINVAL as c_int));
+    }
+    upload.extend_from_slice(data);
+    if upload.len() < size as usize {
+        return Ok(None);
+    }
+    Ok(Some(core::mem::take(&mut *upload)))
+}
+
+/// The power policy, with the configured sleep timeout.
+fn power_policy(sleep_after: Option<ktime::Duration>) -> PowerPolicy {
+    let mut policy = PowerPolicy::new();
+    policy.set_sleep_after(sleep_after);
+    policy
+}
+
+/// The output limiter, with the configured rate.
+fn output_limiter(rate: Option<u32>) -> OutputLimiter {
+    let mut limiter = OutputLimiter::new();
+    limiter.set_rate(rate);
+    limiter
+}
+
+/// What the steno thread reports as it translates, shown on the LEDs right away, rather than going
+/// through the main loop.
+#[cfg(feature = "steno")]
+struct StenoLeds(Arc<Dispatch>);
+
+#[cfg(feature = "steno")]
+impl StenoEvents for StenoLeds {
+    fn raw_mode(&mut self, raw: bool) {
+        info!("Switch raw: {:?}", raw);
+        #[cfg(feature = "trace")]
+        self.0.trace(TraceEvent::RawMode(raw));
+        *self.0.raw_mode.lock().unwrap() = raw;
+        if *self.0.current_mode.lock().unwrap() == LayoutMode::Steno {
+            self.0.leds.lock().unwrap().set_base(0, get_steno_indicator(raw));
+        }
+    }
+
+    fn brief_available(&mut self) {
+        self.0.brief_available();
+    }
+}
+
+/// Load the keymap stored in flash, if there is one.
+#[cfg(feature = "qwerty")]
+pub fn load_keymap() -> Option<Keymap> {
+    let data = flash::contents(&partition::KEYMAP);
+    Keymap::decode(data)
+}
+
+/// Load the keyboard macros stored in flash, if there are any.
+#[cfg(feature = "qwerty")]
+pub fn load_macros() -> Option<StoredMacros> {
+    let data = flash::contents(&partition::MACROS);
+    StoredMacros::decode(data)
+}
+
+/// Load the steno map stored in flash, if there is one.
+#[cfg(feature = "steno")]
+pub fn load_steno_map() -> Option<StenoMap> {
+    let data = flash::contents(&partition::STENO_MAP);
+    StenoMap::decode(data)
+}
+
+/// Load the LED patterns the user has set, if there are any.
+fn load_led_patterns() -> Option<LedPatterns> {
+    let data = flash::contents(&partition::LED_PATTERNS);
+    LedPatterns::decode(data)
+}
+
+/// Show the stored LED patterns in place of the built-in ones.
+fn apply_led_patterns() {
+    let Some(patterns) = load_led_patterns() else {
+        return;
+    };
+    if let Err(e) = patterns.check() {
+        warn!("Stored LED patterns not used: {}", e);
+        return;
+    }
+    for pattern in &patterns.patterns {
+        match manager::find(&pattern.indicator) {
+            Some(indication) => indication.set_pattern(&pattern.steps),
+            None => warn!("No LED indicator {:?}, stored pattern not used", pattern.indicator),
+        }
+    }
+}
+
+/// How often the tape is saved to flash, if it has changed.  Flash wears out, so this is a balance
+/// between that and how much of the tape is lost with the power.  A crash, or the watchdog firing,
+/// writes out the staged tape first, so loses none of it.
+const TAPE_SAVE_INTERVAL: ktime::Duration = ktime::Duration::from_secs(10 * 60);
+
+/// Saving the tape, which alternates between the two sectors of its partition.
+struct TapeSave {
+    /// The generation of the tape last encoded, and staged.
+    staged: u32,
+    /// The generation of the tape last saved to flash.
+    saved: u32,
+    /// The staged tape, encoded, until it is saved.
+    data: Vec<u8>,
+    /// Which sector the next save goes to.
+    slot: u32,
+    /// When the tape was last saved.
+    at: ktime::Instant,
+}
+
+impl TapeSave {
+    /// Where the next save goes.
+    fn offset(&self) -> u32 {
+        partition::TAPE.offset + self.slot * partition::SECTOR_SIZE
+    }
+}
+
+/// Load the newest tape saved in flash, and work out where the next save goes.
+fn load_tape() -> (Tape, TapeSave) {
+    let data = flash::contents(&partition::TAPE);
+    let mut slots = data.chunks(partition::SECTOR_SIZE as usize).map(Tape::restore);
+    let (first, second) = (slots.next().flatten(), slots.next().flatten());
+    // The generation wraps, so the newer tape is the one the other is behind.
+    let (tape, slot) = match (first, second) {
+        (Some(a), Some(b)) if b.generation().wrapping_sub(a.generation()) as i32 > 0 => (b, 0),
+        (Some(a), _) => (a, 1),
+        (None, Some(b)) => (b, 0),
+        (None, None) => (Tape::new(), 0),
+    };
+    if !tape.is_empty() {
+        info!("Loaded {} strokes of saved tape", tape.len());
+    }
+    let save = TapeSave {
+        staged: tape.generation(),
+        saved: tape.generation(),
+        data: Vec::new(),
+        slot,
+        at: SysClock.now(),
+    };
+    (tape, save)
+}
+
+/// Load the usage saved in flash.
+fn load_usage() -> Usage {
+    let data = flash::contents(&partition::STATS);
+    Usage::decode(data)
+}
+
+/// Typed output, from a single translation.  The u32 is the output generation when typing started,
+/// so the rest of the translation is dropped if output is killed.
+struct KeyActionWrap<'a>(&'a Dispatch, u32);
 
 impl<'a> ActionHandler for KeyActionWrap<'a> {
     async fn enqueue_actions<I: Iterator<Item = KeyAction>>(&mut self, events: I) {
         for act in events {
-            self.0.usb_hid_push(act).await;
+            if !self.0.typed_push(act, self.1).await {
+                break;
+            }
         }
     }
 }
@@ -0,0 +1,33 @@
+// A rotary encoder.
+//
+// The pins come from the "bbq-encoder" node in the devicetree, which the Rust devicetree support
+// doesn't know about, so this gives a small API to read them.  The decoding is done in
+// encoder.rs.
+
+#define DT_DRV_COMPAT bbq_encoder
+
+#include <errno.h>
+#include <zephyr/kernel.h>
+#include <zephyr/drivers/gpio.h>
+
+static const struct gpio_dt_spec pin_a = GPIO_DT_SPEC_INST_GET(0, a_gpios);
+static const struct gpio_dt_spec pin_b = GPIO_DT_SPEC_INST_GET(0, b_gpios);
+
+// Configure the pins as inputs, returning 0, or a negative error code.
+int bbq_encoder_init(void) {
+	int ret;
+
+	if (!gpio_is_ready_dt(&pin_a) || !gpio_is_ready_dt(&pin_b)) {
+		return -ENODEV;
+	}
+	ret = gpio_pin_configure_dt(&pin_a, GPIO_INPUT);
+	if (ret < 0) {
+		return ret;
+	}
+	return gpio_pin_configure_dt(&pin_b, GPIO_INPUT);
+}
+
+// Read the signals, as B in bit 1, and A in bit 0.
+unsigned int bbq_encoder_get(void) {
+	return (gpio_pin_get_dt(&pin_b) > 0) << 1 | (gpio_pin_get_dt(&pin_a) > 0);
+}
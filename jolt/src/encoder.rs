@@ -0,0 +1,49 @@
+//! The rotary encoder.
+//!
+//! The pins are read by `encoder.c`, on each scan of the keys, and decoded here.  Turns are sent to
+//! the main loop with [`Events::encoder`](crate::events::Events::encoder), and on, through the
+//! engine, to the layout manager, which does what the board info says for the mode in use.
+
+use core::ffi::{c_int, c_uint};
+
+use bbq_keyboard::boardinfo::BoardInfo;
+use bbq_keyboard::encoder::{self, Decoder};
+
+extern "C" {
+    fn bbq_encoder_init() -> c_int;
+    fn bbq_encoder_get() -> c_uint;
+}
+
+pub struct Encoder {
+    decoder: Decoder,
+}
+
+impl Encoder {
+    /// The steps per detent come from the board info.
+    pub fn new(info: &BoardInfo) -> Encoder {
+        let ret = unsafe { bbq_encoder_init() };
+        if ret < 0 {
+            panic!("Encoder not available: {}", ret);
+        }
+        let mut decoder = Decoder::new(info.encoder_steps.unwrap_or(encoder::DEFAULT_STEPS));
+        let (a, b) = read();
+        decoder.start(a, b);
+        Encoder { decoder }
+    }
+
+    /// Read the pins, returning the detents turned since the last scan.
+    pub fn scan(&mut self) -> i8 {
+        let (a, b) = read();
+        self.decoder.update(a, b)
+    }
+
+    /// Is the knob part way between detents, so should be scanned quickly.
+    pub fn is_moving(&self) -> bool {
+        self.decoder.is_moving()
+    }
+}
+
+fn read() -> (bool, bool) {
+    let bits = unsafe { bbq_encoder_get() };
+    (bits & 1 != 0, bits & 2 != 0)
+}
@@ -0,0 +1,109 @@
+//! The hardware the main loop drives.
+//!
+//! The main loop itself is [`bbq_keyboard::engine::Engine`].  This gives it the parts of the
+//! keyboard, almost all of which are reached through [`Dispatch`].
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+
+use bbq_keyboard::engine::{Engine, Inter, Keys, Leds, Timers, Usb};
+use bbq_keyboard::{time::Duration, InterState, KeyEvent};
+use zephyr::sync::channel::Sender;
+use zephyr::sync::Arc;
+
+use crate::dispatch::Dispatch;
+use crate::inter::InterUpdate;
+use crate::leds::manager::SLEEP_INDICATOR;
+use crate::metrics;
+
+/// Everything but the link to the other half, with where the layout task takes keys from.
+#[derive(Clone)]
+struct Hooks {
+    dispatch: Arc<Dispatch>,
+    layout: Sender<KeyEvent>,
+}
+
+/// The link to the other half, through the inter task.
+struct InterLink(Sender<InterUpdate>);
+
+/// Build the engine for the main loop.
+pub fn build(dispatch: Arc<Dispatch>, layout: Sender<KeyEvent>, inter: Option<Sender<InterUpdate>>) -> Engine {
+    let hooks = Hooks { dispatch, layout };
+    Engine::new(
+        Box::new(hooks.clone()),
+        Box::new(hooks.clone()),
+        inter.map(|inter| Box::new(InterLink(inter)) as Box<dyn Inter + Send>),
+        Box::new(hooks.clone()),
+        Box::new(hooks),
+    )
+}
+
+impl Usb for Hooks {
+    fn select_transport(&mut self, usb: bool, ble: bool) {
+        self.dispatch.select_transport(usb, ble);
+    }
+
+    fn set_battery(&mut self, battery: bool) {
+        self.dispatch.set_battery(battery);
+    }
+}
+
+impl Leds for Hooks {
+    fn set_suspended(&mut self, suspended: bool) {
+        let mut leds = self.dispatch.leds.lock().unwrap();
+        if suspended {
+            leds.set_global(0, &SLEEP_INDICATOR);
+        } else {
+            leds.clear_global(0);
+        }
+    }
+
+    fn tick(&mut self) {
+        self.dispatch.leds.lock().unwrap().tick();
+    }
+}
+
+impl Timers for Hooks {
+    fn led_period(&self) -> Duration {
+        self.dispatch.led_period()
+    }
+
+    fn update_power(&mut self) -> bool {
+        self.dispatch.update_power();
+        self.dispatch.is_asleep()
+    }
+}
+
+impl Keys for Hooks {
+    fn track(&mut self, key: KeyEvent) {
+        self.dispatch.track_key(key);
+    }
+
+    fn layout(&mut self, key: KeyEvent) -> bool {
+        if self.layout.try_send(key).is_err() {
+            metrics::key_dropped();
+            return false;
+        }
+        metrics::LAYOUT.sent();
+        true
+    }
+
+    fn encoder(&mut self, delta: i8) {
+        self.dispatch.add_encoder(delta);
+    }
+}
+
+impl Inter for InterLink {
+    fn add_key(&mut self, key: KeyEvent) {
+        self.0.send(InterUpdate::AddKey(key)).unwrap();
+    }
+
+    fn set_state(&mut self, state: InterState) {
+        self.0.send(InterUpdate::SetState(state)).unwrap();
+    }
+
+    fn sleep(&mut self, asleep: bool) {
+        self.0.send(InterUpdate::Sleep(asleep)).unwrap();
+    }
+}
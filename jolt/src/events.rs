@@ -0,0 +1,117 @@
+//! The main loop's inputs.
+//!
+//! Each kind of input has its own channel, typed as bbq-keyboard gives it, so that nothing has to be
+//! wrapped up to share one queue, and a burst of one kind, such as keys, can't crowd out the
+//! others.  The tick, and asking to shut down, are just flags.  Whatever sends an input also gives
+//! the main loop's semaphore, and each time the main loop wakes, it takes everything waiting.
+
+use core::sync::atomic::Ordering;
+
+use bbq_keyboard::{HostEvent, InterEvent, KeyEvent};
+use zephyr::sync::atomic::AtomicBool;
+use zephyr::sync::channel::{self, Receiver, Sender};
+use zephyr::sync::Arc;
+use zephyr::sys::sync::Semaphore;
+use zephyr::time::Forever;
+
+use crate::metrics;
+
+/// The flags, shared by both ends.
+struct Flags {
+    tick: AtomicBool,
+    shutdown: AtomicBool,
+}
+
+/// The sending end, cloned for each source.
+#[derive(Clone)]
+pub struct Events {
+    keys: Sender<KeyEvent>,
+    encoder: Sender<i8>,
+    inter: Sender<InterEvent>,
+    host: Sender<HostEvent>,
+    flags: Arc<Flags>,
+    wake: Arc<Semaphore>,
+}
+
+/// The receiving end, for the main loop.
+pub struct Inputs {
+    pub keys: Receiver<KeyEvent>,
+    pub encoder: Receiver<i8>,
+    pub inter: Receiver<InterEvent>,
+    pub host: Receiver<HostEvent>,
+    flags: Arc<Flags>,
+    wake: Arc<Semaphore>,
+}
+
+pub fn new() -> (Events, Inputs) {
+    let (keys_send, keys) = channel::bounded(metrics::KEYS.capacity);
+    let (encoder_send, encoder) = channel::bounded(metrics::ENCODER.capacity);
+    let (inter_send, inter) = channel::bounded(metrics::INTER.capacity);
+    let (host_send, host) = channel::bounded(metrics::HOST.capacity);
+    let flags = Arc::new(Flags { tick: AtomicBool::new(false), shutdown: AtomicBool::new(false) });
+    let wake = Arc::new(Semaphore::new(0, 1).unwrap());
+    let events = Events {
+        keys: keys_send,
+        encoder: encoder_send,
+        inter: inter_send,
+        host: host_send,
+        flags: flags.clone(),
+        wake: wake.clone(),
+    };
+    (events, Inputs { keys, encoder, inter, host, flags, wake })
+}
+
+impl Events {
+    /// A key on this side's matrix.
+    pub fn key(&self, key: KeyEvent) {
+        self.keys.send(key).unwrap();
+        self.wake.give();
+    }
+
+    /// The rotary encoder turned.
+    pub fn encoder(&self, delta: i8) {
+        self.encoder.send(delta).unwrap();
+        self.wake.give();
+    }
+
+    /// Something from the other half.
+    pub fn inter(&self, event: InterEvent) {
+        self.inter.send(event).unwrap();
+        self.wake.give();
+    }
+
+    /// A change in the connection to a host.
+    pub fn host(&self, event: HostEvent) {
+        self.host.send(event).unwrap();
+        self.wake.give();
+    }
+
+    /// A tick of the heartbeat.
+    pub fn tick(&self) {
+        self.flags.tick.store(true, Ordering::Release);
+        self.wake.give();
+    }
+
+    /// Ask the main loop to shut down and reboot.
+    pub fn shutdown(&self) {
+        self.flags.shutdown.store(true, Ordering::Release);
+        self.wake.give();
+    }
+}
+
+impl Inputs {
+    /// Wait until something has been sent.
+    pub async fn wait(&self) {
+        let _ = self.wake.take_async(Forever).await;
+    }
+
+    /// Has there been a tick since the last call.
+    pub fn take_tick(&self) -> bool {
+        self.flags.tick.swap(false, Ordering::Acquire)
+    }
+
+    /// Has shutting down been asked for.
+    pub fn shutdown_requested(&self) -> bool {
+        self.flags.shutdown.load(Ordering::Acquire)
+    }
+}
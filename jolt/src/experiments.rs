@@ -0,0 +1,21 @@
+//! Experimental layout modes.
+//!
+//! Out of tree layouts, such as new chording schemes, are plugged in here, so trying one out only
+//! needs this file changed, and the `experimental` feature.  See
+//! `bbq_keyboard::layout::experimental` for how to write one.  Each is given an id from the
+//! reserved range, a name, used to select it from the console or with a mode chord, and the color
+//! of its indicator.  Settings for it can be given in the board info, as `name.key=value`.
+
+use bbq_keyboard::layout::LayoutManager;
+
+/// Register the experimental modes, returning false if any failed.  Add them here, such as:
+///
+/// ```ignore
+/// bbq_keyboard::register_experiments! { layout,
+///     0 => ExperimentInfo { name: "chorder", indicator: RGB8::new(16, 0, 16) }, Chorder::new(),
+/// }
+/// ```
+#[allow(unused_variables)]
+pub fn register(layout: &mut LayoutManager) -> bool {
+    true
+}
@@ -0,0 +1,121 @@
+// Flash writes, for the small amount of state the keyboard saves itself, and for installing new
+// firmware.
+
+#include <errno.h>
+#include <zephyr/kernel.h>
+#include <zephyr/device.h>
+#include <zephyr/drivers/flash.h>
+#include <zephyr/irq.h>
+#include <zephyr/linker/section_tags.h>
+#include <cmsis_core.h>
+#include <hardware/regs/addressmap.h>
+#include <pico/bootrom.h>
+
+static const struct device *const flash_dev = DEVICE_DT_GET(DT_CHOSEN(zephyr_flash_controller));
+
+// Erase the region at offset, and write len bytes of data to the start of it.
+int bbq_flash_write(uint32_t offset, uint32_t size, const uint8_t *data, size_t len) {
+	int ret;
+
+	if (!device_is_ready(flash_dev)) {
+		return -ENODEV;
+	}
+	if (len > size) {
+		return -EINVAL;
+	}
+
+	ret = flash_erase(flash_dev, offset, size);
+	if (ret != 0) {
+		return ret;
+	}
+	return flash_write(flash_dev, offset, data, len);
+}
+
+// Erase whole sectors of flash, without writing anything.
+int bbq_flash_erase(uint32_t offset, uint32_t size) {
+	if (!device_is_ready(flash_dev)) {
+		return -ENODEV;
+	}
+	return flash_erase(flash_dev, offset, size);
+}
+
+// Write len bytes of data to flash that has already been erased.
+int bbq_flash_program(uint32_t offset, const uint8_t *data, size_t len) {
+	if (!device_is_ready(flash_dev)) {
+		return -ENODEV;
+	}
+	return flash_write(flash_dev, offset, data, len);
+}
+
+#define INSTALL_SECTOR 4096
+
+// The sector being copied.  Flash can't be read while it is being written, so each sector goes
+// through RAM.
+static uint8_t install_buf[INSTALL_SECTOR] __aligned(4);
+
+// Copy size bytes of a staged image, at offset from, over the firmware at the start of flash, and
+// reset into it.  Once the first sector is erased, there is no firmware to return to, so this all
+// runs from RAM, with interrupts locked, and only uses the boot ROM's flash functions.  Nothing
+// here may call into flash, which is why the copy is done by hand, rather than with memcpy.
+__ramfunc FUNC_NORETURN void bbq_install_image(uint32_t from, uint32_t size) {
+	rom_connect_internal_flash_fn connect_internal_flash =
+		(rom_connect_internal_flash_fn)rom_func_lookup_inline(ROM_FUNC_CONNECT_INTERNAL_FLASH);
+	rom_flash_exit_xip_fn flash_exit_xip =
+		(rom_flash_exit_xip_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_EXIT_XIP);
+	rom_flash_range_erase_fn flash_range_erase =
+		(rom_flash_range_erase_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_RANGE_ERASE);
+	rom_flash_range_program_fn flash_range_program =
+		(rom_flash_range_program_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_RANGE_PROGRAM);
+	rom_flash_flush_cache_fn flash_flush_cache =
+		(rom_flash_flush_cache_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_FLUSH_CACHE);
+	rom_flash_enter_cmd_xip_fn flash_enter_cmd_xip =
+		(rom_flash_enter_cmd_xip_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_ENTER_CMD_XIP);
+
+	(void)irq_lock();
+
+	for (uint32_t pos = 0; pos < size; pos += INSTALL_SECTOR) {
+		const volatile uint8_t *src = (const volatile uint8_t *)(XIP_BASE + from + pos);
+		for (uint32_t i = 0; i < INSTALL_SECTOR; i++) {
+			install_buf[i] = src[i];
+		}
+
+		connect_internal_flash();
+		flash_exit_xip();
+		flash_range_erase(pos, INSTALL_SECTOR, INSTALL_SECTOR, 0x20);
+		flash_range_program(pos, install_buf, INSTALL_SECTOR);
+		flash_flush_cache();
+		flash_enter_cmd_xip();
+	}
+
+	NVIC_SystemReset();
+}
+
+// Erase the sector at offset, and program it with data, which must be in RAM.  This is for writing
+// out the tape on the way down from a crash, when the flash driver can't be trusted, so like the
+// install, it runs from RAM with interrupts locked, and only uses the boot ROM.  Flash is left in
+// the boot ROM's slower XIP mode, which is fine, as a reboot is close behind.
+__ramfunc void bbq_flash_rescue(uint32_t offset, const uint8_t *data) {
+	rom_connect_internal_flash_fn connect_internal_flash =
+		(rom_connect_internal_flash_fn)rom_func_lookup_inline(ROM_FUNC_CONNECT_INTERNAL_FLASH);
+	rom_flash_exit_xip_fn flash_exit_xip =
+		(rom_flash_exit_xip_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_EXIT_XIP);
+	rom_flash_range_erase_fn flash_range_erase =
+		(rom_flash_range_erase_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_RANGE_ERASE);
+	rom_flash_range_program_fn flash_range_program =
+		(rom_flash_range_program_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_RANGE_PROGRAM);
+	rom_flash_flush_cache_fn flash_flush_cache =
+		(rom_flash_flush_cache_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_FLUSH_CACHE);
+	rom_flash_enter_cmd_xip_fn flash_enter_cmd_xip =
+		(rom_flash_enter_cmd_xip_fn)rom_func_lookup_inline(ROM_FUNC_FLASH_ENTER_CMD_XIP);
+
+	unsigned int key = irq_lock();
+
+	connect_internal_flash();
+	flash_exit_xip();
+	flash_range_erase(offset, INSTALL_SECTOR, INSTALL_SECTOR, 0x20);
+	flash_range_program(offset, data, INSTALL_SECTOR);
+	flash_flush_cache();
+	flash_enter_cmd_xip();
+
+	irq_unlock(key);
+}
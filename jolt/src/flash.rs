@@ -0,0 +1,295 @@
+//! Writing to flash.
+//!
+//! Almost everything in flash is written by the host.  This is for the little bit of state the
+//! keyboard keeps itself.
+//!
+//! Before rebooting, the flash is parked: any write in progress is allowed to finish, and later
+//! writes fail, so a reboot never lands in the middle of an erase.
+//!
+//! New firmware is written by the host into the staging area.  Once it has been checked, it is
+//! marked to be installed, and the reboot copies it over the running firmware instead.
+//!
+//! A patched user dictionary is staged there too, after the first sector.  Once all of it has been
+//! written, a mark in that first sector records its size and digest, and the keyboard restarts.
+//! At startup, before anything reads the dictionaries, a marked image is copied over the user
+//! dictionary, and the mark erased.  Until the mark is written, the user dictionary is untouched,
+//! and a copy cut short by losing power is finished at the next startup.
+
+use core::ffi::c_int;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::vec::Vec;
+#[cfg(feature = "minder-flash")]
+use log::{info, warn};
+use minder::partition::{Flash, Partition, PAGE_SIZE, SECTOR_SIZE};
+#[cfg(feature = "minder-flash")]
+use minder::{partition::{STAGING, USER_DICT}, HashAlgorithm};
+use zephyr::sync::atomic::AtomicU32;
+use zephyr::time::Duration;
+use zephyr::work::futures::sleep;
+
+extern "C" {
+    fn bbq_flash_write(offset: u32, size: u32, data: *const u8, len: usize) -> c_int;
+    #[cfg(feature = "minder-flash")]
+    fn bbq_flash_erase(offset: u32, size: u32) -> c_int;
+    #[cfg(feature = "minder-flash")]
+    fn bbq_flash_program(offset: u32, data: *const u8, len: usize) -> c_int;
+    #[cfg(feature = "minder-flash")]
+    fn bbq_install_image(from: u32, size: u32) -> !;
+}
+
+/// This board's flash, as configured.  The size is given in KiB.
+pub const FLASH: Flash = Flash {
+    base: zephyr::kconfig::CONFIG_FLASH_BASE_ADDRESS as usize,
+    size: zephyr::kconfig::CONFIG_FLASH_SIZE as u32 * 1024,
+};
+
+/// Writes currently in progress.
+static BUSY: AtomicU32 = AtomicU32::new(0);
+
+/// Set once the flash is parked for a reboot.
+static PARKED: AtomicBool = AtomicBool::new(false);
+
+/// The size of the staged image to install on reboot, or zero to just reboot.
+#[cfg(feature = "minder-flash")]
+static INSTALL: AtomicU32 = AtomicU32::new(0);
+
+/// Marks a staged dictionary image, "dict" in flash.
+#[cfg(feature = "minder-flash")]
+const DICT_MARK: u32 = u32::from_le_bytes(*b"dict");
+
+/// Where a staged dictionary image starts, after the sector holding its mark.
+#[cfg(feature = "minder-flash")]
+const DICT_STAGE: u32 = STAGING.offset + SECTOR_SIZE;
+
+/// The largest dictionary image that can be staged.
+#[cfg(feature = "minder-flash")]
+pub const DICT_STAGE_MAX: u32 = if STAGING.size - SECTOR_SIZE < USER_DICT.size {
+    STAGING.size - SECTOR_SIZE
+} else {
+    USER_DICT.size
+};
+
+/// The contents of the partition, as it is mapped in memory.  A partition past the end of this
+/// board's flash is empty.
+pub fn contents(part: &Partition) -> &'static [u8] {
+    let Some(part) = FLASH.fit(part) else {
+        return &[];
+    };
+    unsafe { slice::from_raw_parts(FLASH.address(part.offset) as *const u8, part.size as usize) }
+}
+
+/// Erase the partition, and write `data` at the start of it.  Everything running from flash stalls
+/// while this happens, so it should be done rarely.
+pub fn write(part: &Partition, data: &[u8]) -> Result<(), c_int> {
+    raw_write(part.offset, part.size, data)
+}
+
+/// Wait for any write in progress, and refuse any more.  There is no unparking; this is only done
+/// right before a reboot.
+pub async fn park() {
+    PARKED.store(true, Ordering::SeqCst);
+    while BUSY.load(Ordering::SeqCst) != 0 {
+        sleep(Duration::millis_at_least(1)).await;
+    }
+}
+
+/// Reboot, installing the staged image first if one has been marked.  The flash should already be
+/// parked.  This doesn't return.
+pub fn reboot() {
+    #[cfg(feature = "minder-flash")]
+    {
+        let size = INSTALL.load(Ordering::SeqCst);
+        if size != 0 {
+            // The copy runs with interrupts locked, and can't feed the watchdog.
+            crate::crash::stop_watchdog();
+            unsafe { bbq_install_image(STAGING.offset, size) };
+        }
+    }
+    unsafe { zephyr::raw::sys_reboot(zephyr::raw::SYS_REBOOT_COLD as i32) };
+}
+
+/// Run a flash operation, unless the flash is parked.
+fn guarded(op: impl FnOnce() -> c_int) -> Result<(), c_int> {
+    // Counting the write before checking for parking means `park` will always either see the
+    // write, or the write will see that it is parked.
+    BUSY.fetch_add(1, Ordering::SeqCst);
+    if PARKED.load(Ordering::SeqCst) {
+        BUSY.fetch_sub(1, Ordering::SeqCst);
+        return Err(-(zephyr::raw::EBUSY as c_int));
+    }
+    let ret = op();
+    BUSY.fetch_sub(1, Ordering::SeqCst);
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(ret)
+    }
+}
+
+fn raw_write(offset: u32, size: u32, data: &[u8]) -> Result<(), c_int> {
+    let mut buf = Vec::from(data);
+    buf.resize(data.len().next_multiple_of(PAGE_SIZE as usize), 0xff);
+    guarded(|| unsafe { bbq_flash_write(offset, size, buf.as_ptr(), buf.len()) })
+}
+
+/// Erase the sector at `offset`, and write `data` at the start of it.  This is for writes from the
+/// host, which have already been checked to fall within a data partition.
+#[cfg(feature = "minder-flash")]
+pub fn program(offset: u32, data: &[u8]) -> Result<(), c_int> {
+    raw_write(offset, SECTOR_SIZE, data)
+}
+
+/// Erase whole sectors of the staging area, ready for a new image.  The range has already been
+/// checked.  This is done a sector at a time, feeding the watchdog, as erasing the whole area takes
+/// several seconds.
+#[cfg(feature = "minder-flash")]
+pub fn erase_staging(offset: u32, size: u32) -> Result<(), c_int> {
+    // Anything staged is being replaced.
+    INSTALL.store(0, Ordering::SeqCst);
+    for sector in (offset..offset + size).step_by(SECTOR_SIZE as usize) {
+        guarded(|| unsafe { bbq_flash_erase(sector, SECTOR_SIZE) })?;
+        crate::crash::feed_watchdog();
+    }
+    Ok(())
+}
+
+/// Write part of a new image into the erased staging area, padding it to whole pages.  The range
+/// has already been checked.
+#[cfg(feature = "minder-flash")]
+pub fn program_staging(offset: u32, data: &[u8]) -> Result<(), c_int> {
+    INSTALL.store(0, Ordering::SeqCst);
+    let mut buf = Vec::from(data);
+    buf.resize(data.len().next_multiple_of(PAGE_SIZE as usize), 0xff);
+    guarded(|| unsafe { bbq_flash_program(offset, buf.as_ptr(), buf.len()) })
+}
+
+/// Mark the first `size` bytes of the staging area, which have been checked, to be installed over
+/// the firmware on the next reboot.
+#[cfg(feature = "minder-flash")]
+pub fn stage_install(size: u32) {
+    INSTALL.store(size.next_multiple_of(SECTOR_SIZE), Ordering::SeqCst);
+}
+
+/// A user dictionary image being written into the staging area.  The pieces of the image are
+/// written in order, apart from the start of it, below `start`, which is left erased to be written
+/// last.
+#[cfg(feature = "minder-flash")]
+pub struct DictStage {
+    /// Where the pages not yet written begin, in the image.
+    at: u32,
+    /// What has been given for the page at `at`, but not written.
+    page: Vec<u8>,
+    /// How much of the image has been erased.
+    erased: u32,
+    /// Where the pieces written in order start.
+    start: u32,
+}
+
+#[cfg(feature = "minder-flash")]
+impl DictStage {
+    /// Start staging a new image, whose pieces start at `start`, a whole page.  This replaces
+    /// anything staged before.
+    pub fn new(start: u32) -> Result<DictStage, c_int> {
+        // The mark goes first, so an image that is never finished is never copied.
+        erase_staging(STAGING.offset, SECTOR_SIZE)?;
+        Ok(DictStage { at: start, page: Vec::new(), erased: 0, start })
+    }
+
+    /// Write the piece of the image at `offset`.
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), c_int> {
+        let end = offset + data.len() as u32;
+        if end > DICT_STAGE_MAX {
+            return Err(-(zephyr::raw::ENOMEM as c_int));
+        }
+        self.erase_to(end)?;
+        if end <= self.start && offset % PAGE_SIZE == 0 {
+            return program_staging(DICT_STAGE + offset, data);
+        }
+        if offset != self.at + self.page.len() as u32 {
+            return Err(-(zephyr::raw::EINVAL as c_int));
+        }
+        self.page.extend_from_slice(data);
+        let whole = self.page.len() - self.page.len() % PAGE_SIZE as usize;
+        if whole > 0 {
+            program_staging(DICT_STAGE + self.at, &self.page[..whole])?;
+            self.page.drain(..whole);
+            self.at += whole as u32;
+        }
+        Ok(())
+    }
+
+    /// The whole image, `size` bytes, has been written.  Write what is left, and then the mark, so
+    /// it is copied over the user dictionary at the next startup.
+    pub fn finish(mut self, size: u32) -> Result<(), c_int> {
+        if !self.page.is_empty() {
+            program_staging(DICT_STAGE + self.at, &self.page)?;
+            self.page.clear();
+        }
+        let image = staged(DICT_STAGE, size).ok_or(-(zephyr::raw::EINVAL as c_int))?;
+        let digest = HashAlgorithm::Sha256.digest(image).ok_or(-(zephyr::raw::EINVAL as c_int))?;
+        let mut mark = Vec::new();
+        mark.extend_from_slice(&DICT_MARK.to_le_bytes());
+        mark.extend_from_slice(&size.to_le_bytes());
+        mark.extend_from_slice(&digest);
+        program_staging(STAGING.offset, &mark)
+    }
+
+    /// Erase the sectors up to `end`, as the image grows into them.
+    fn erase_to(&mut self, end: u32) -> Result<(), c_int> {
+        if end > self.erased {
+            let to = end.next_multiple_of(SECTOR_SIZE);
+            erase_staging(DICT_STAGE + self.erased, to - self.erased)?;
+            self.erased = to;
+        }
+        Ok(())
+    }
+}
+
+/// Copy a staged dictionary image over the user dictionary, if one has been marked, and then erase
+/// the mark.  This is done at startup, before the dictionaries are loaded.  Sectors that already
+/// match are skipped, so finishing a copy that was cut short is quick.
+#[cfg(feature = "minder-flash")]
+pub fn finish_dict_stage() {
+    let Some(mark) = staged(STAGING.offset, 40) else {
+        return;
+    };
+    if mark[..4] != DICT_MARK.to_le_bytes() {
+        return;
+    }
+    let size = u32::from_le_bytes(mark[4..8].try_into().unwrap());
+    let image = match staged(DICT_STAGE, size) {
+        Some(image) if size <= DICT_STAGE_MAX
+            && HashAlgorithm::Sha256.digest(image).as_deref() == Some(&mark[8..40]) => image,
+        _ => {
+            warn!("Staged dictionary is damaged, leaving the user dictionary alone");
+            let _ = guarded(|| unsafe { bbq_flash_erase(STAGING.offset, SECTOR_SIZE) });
+            return;
+        }
+    };
+
+    info!("Installing the staged user dictionary, {} bytes", size);
+    let current = contents(&USER_DICT);
+    for (pos, data) in (0..).step_by(SECTOR_SIZE as usize).zip(image.chunks(SECTOR_SIZE as usize)) {
+        if current.get(pos..pos + data.len()) == Some(data) {
+            continue;
+        }
+        if let Err(e) = program(USER_DICT.offset + pos as u32, data) {
+            // The mark is left, so this is tried again at the next startup.
+            warn!("Unable to install the staged dictionary: {}", e);
+            return;
+        }
+        crate::crash::feed_watchdog();
+    }
+    let _ = guarded(|| unsafe { bbq_flash_erase(STAGING.offset, SECTOR_SIZE) });
+}
+
+/// Part of the staging area, as it is mapped in memory, or None if it runs past the end.
+#[cfg(feature = "minder-flash")]
+fn staged(offset: u32, size: u32) -> Option<&'static [u8]> {
+    if !STAGING.contains(offset, size) || FLASH.fit(&STAGING)? != STAGING {
+        return None;
+    }
+    Some(unsafe { slice::from_raw_parts(FLASH.address(offset) as *const u8, size as usize) })
+}
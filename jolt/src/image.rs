@@ -0,0 +1,28 @@
+//! Image metadata.
+//!
+//! The metadata is encoded by build.rs, and included here as raw bytes.  `image_info.ld` places it
+//! at its fixed offset in the image (see [`minder::image`]), so that host tools can find it in the
+//! image file.
+//!
+//! The key firmware updates must be signed by is also given to build.rs, and included here.
+
+use minder::image::IMAGE_INFO_SIZE;
+use minder::ImageInfo;
+
+#[used]
+#[link_section = ".image_info"]
+static IMAGE_INFO: [u8; IMAGE_INFO_SIZE] = *include_bytes!(concat!(env!("OUT_DIR"), "/image_info.bin"));
+
+/// Decode the metadata for the running image.
+pub fn info() -> ImageInfo {
+    ImageInfo::from_bytes(&IMAGE_INFO).expect("Invalid image info")
+}
+
+#[cfg(feature = "minder-flash")]
+static FIRMWARE_KEY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/firmware_key.bin"));
+
+/// The public key that a new image must be signed by to be installed, if this was built with one.
+#[cfg(feature = "minder-flash")]
+pub fn firmware_key() -> Option<&'static [u8; minder::sign::PUBLIC_KEY_SIZE]> {
+    FIRMWARE_KEY.try_into().ok()
+}
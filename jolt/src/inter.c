@@ -0,0 +1,54 @@
+// Inter board uart support.
+
+#include <zephyr/device.h>
+#include <zephyr/drivers/uart.h>
+#include <zephyr/pm/device.h>
+#include <zephyr/random/random.h>
+#include <errno.h>
+
+// The Rust uart wrapper doesn't give the receive errors, so they are read here.
+
+#if DT_HAS_CHOSEN(inter_board_uart)
+
+static const struct device *const inter_uart = DEVICE_DT_GET(DT_CHOSEN(inter_board_uart));
+
+// The receive errors since this was last called, as the `uart_rx_stop_reason` bits, or a negative
+// errno if the driver can't tell.
+int bbq_inter_err_check(void) {
+	return uart_err_check(inter_uart);
+}
+
+// Power the uart down while the keyboard sleeps, or back up.  Without device power management, it
+// just stays up.
+int bbq_inter_suspend(bool suspend) {
+#ifdef CONFIG_PM_DEVICE
+	int err = pm_device_action_run(inter_uart,
+		suspend ? PM_DEVICE_ACTION_SUSPEND : PM_DEVICE_ACTION_RESUME);
+	// Already in that state.
+	if (err == -EALREADY) {
+		return 0;
+	}
+	return err;
+#else
+	ARG_UNUSED(suspend);
+	return 0;
+#endif
+}
+
+#else
+
+int bbq_inter_err_check(void) {
+	return 0;
+}
+
+int bbq_inter_suspend(bool suspend) {
+	ARG_UNUSED(suspend);
+	return 0;
+}
+
+#endif
+
+// A random nonce for the link's session, so that it differs on every boot.
+uint32_t bbq_inter_nonce(void) {
+	return sys_rand32_get();
+}
@@ -2,12 +2,18 @@
 
 use arraydeque::ArrayDeque;
 use bbq_keyboard::{
-    ser2::{KeyBits, Packet, Role},
-    Event, InterState, KeyEvent, Side,
+    ser2::{
+        KeyReceiver, KeySender, LinkAuth, LinkBudget, LinkErrors, LinkKey, LinkQuality, LinkStats, Packet, Priority,
+        Role, UartErrors, TICK_BYTES,
+    },
+    InterEvent, InterState, KeyEvent, Side, RGB8,
 };
 
+use core::ffi::c_int;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
 use log::{info, warn};
-use minder::{serial_encode, SerialDecoder, SerialWrite};
+use minder::{serial_encode, LinkStatus, SerialDecoder, SerialWrite};
 use zephyr::sync::channel::Sender;
 use zephyr::{
     device::uart::Uart,
@@ -16,9 +22,74 @@ use zephyr::{
 };
 
 use crate::devices::leds::LedRgb;
+use crate::events::Events;
+
+/// A buffer large enough to hold a single packet, including authentication.
+type PacketBuffer = ArrayDeque<u8, 64>;
+
+/// How many ticks the Secondary can be silent, as Primary, before its keys are released.
+const QUIET_TICKS: u32 = 40;
+
+/// LED state that hasn't changed is still sent this often, in ticks, in case it was lost.
+const LED_REFRESH_TICKS: u32 = 20;
+
+/// The ticks in a second, for the error rate.
+const TICKS_PER_SEC: u32 = 200;
+
+extern "C" {
+    fn bbq_inter_err_check() -> c_int;
+    fn bbq_inter_suspend(suspend: bool) -> c_int;
+    fn bbq_inter_nonce() -> u32;
+}
+
+/// The link counters, published for the console.
+static SENT: AtomicU32 = AtomicU32::new(0);
+static DEFERRED: AtomicU32 = AtomicU32::new(0);
+static PREEMPTED: AtomicU32 = AtomicU32::new(0);
+static OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static FRAMING: AtomicU32 = AtomicU32::new(0);
+static PER_SEC: AtomicU32 = AtomicU32::new(0);
+static RESYNCS: AtomicU32 = AtomicU32::new(0);
+
+/// Set once there is an inter link.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The counters of the inter link.
+pub fn link_stats() -> LinkStats {
+    LinkStats {
+        sent: SENT.load(Ordering::Relaxed),
+        deferred: DEFERRED.load(Ordering::Relaxed),
+        preempted: PREEMPTED.load(Ordering::Relaxed),
+    }
+}
+
+/// The receive errors of the inter link.
+pub fn link_errors() -> LinkErrors {
+    LinkErrors {
+        overruns: OVERRUNS.load(Ordering::Relaxed),
+        framing: FRAMING.load(Ordering::Relaxed),
+        per_sec: PER_SEC.load(Ordering::Relaxed),
+        resyncs: RESYNCS.load(Ordering::Relaxed),
+    }
+}
 
-/// A buffer large enough to hold a single packet.
-type PacketBuffer = ArrayDeque<u8, 32>;
+/// The state of the inter link, for the minder status.  None if this board doesn't have one.
+pub fn link_status() -> Option<LinkStatus> {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let stats = link_stats();
+    let errors = link_errors();
+    Some(LinkStatus {
+        sent: stats.sent,
+        deferred: stats.deferred,
+        preempted: stats.preempted,
+        overruns: errors.overruns,
+        framing: errors.framing,
+        errors_per_sec: errors.per_sec,
+        resyncs: errors.resyncs,
+    })
+}
 
 /// Updates to the inter state from the rest of the system are sent as these messages.
 pub enum InterUpdate {
@@ -26,40 +97,85 @@ pub enum InterUpdate {
     SetState(InterState),
     /// Add a key event to inform the other side.
     AddKey(KeyEvent),
+    /// The keyboard has gone to sleep, or woken.  Asleep, the link is powered down, and nothing is
+    /// sent until a key wakes it.
+    Sleep(bool),
 }
 
 pub struct InterHandler {
     xmit_buffer: PacketBuffer,
+    /// The priority of the packet in `xmit_buffer`.
+    xmit_priority: Priority,
+    /// What has been sent each tick, to keep chatter from delaying keys.
+    budget: LinkBudget,
+    /// Receive errors, and when to resync.
+    quality: LinkQuality,
+    /// The LED state last sent, and the ticks since.
+    leds_sent: Option<RGB8>,
+    leds_age: u32,
+    /// The acknowledgement last sent, as Primary.
+    ack_sent: Option<u16>,
     receiver: SerialDecoder,
     side: Side,
     state: InterState,
-    /// Keys being sent.
-    keys: KeyBits,
-    /// Values of keys pressed since last time we received a packet.
-    last_keys: KeyBits,
+    /// Key events being sent, as Secondary.
+    sender: KeySender,
+    /// Key events received, as Primary.
+    key_receiver: KeyReceiver,
+    /// Ticks since the last packet from the Secondary.
+    quiet: u32,
+    /// The link is powered down while the keyboard sleeps.
+    asleep: bool,
     leds: LedRgb,
-    events: Sender<Event>,
+    events: Events,
     uart: Uart,
     requests: Receiver<InterUpdate>,
+    /// Authentication of the link, if a key has been configured.
+    auth: Option<LinkAuth>,
 
     side_warn: bool,
+    auth_warn: bool,
 }
 
 impl InterHandler {
     #[allow(dead_code)]
-    pub fn new(side: Side, uart: Uart, events: Sender<Event>) -> (Self, Sender<InterUpdate>) {
+    pub fn new(
+        side: Side,
+        uart: Uart,
+        events: Events,
+        key: Option<LinkKey>,
+    ) -> (Self, Sender<InterUpdate>) {
         let (req_send, req_recv) = channel::bounded(32);
 
+        // The nonces, and the first key event number, have to differ between sessions, so that
+        // packets recorded from an earlier one can't be replayed.
+        let nonce = unsafe { bbq_inter_nonce() };
+        let auth = key.map(|key| {
+            info!("Inter link is authenticated");
+            LinkAuth::new(key, || unsafe { bbq_inter_nonce() })
+        });
+        ACTIVE.store(true, Ordering::Relaxed);
+
         (
             Self {
                 xmit_buffer: PacketBuffer::new(),
+                xmit_priority: Priority::Chatter,
+                budget: LinkBudget::new(TICK_BYTES),
+                quality: LinkQuality::new(TICKS_PER_SEC),
+                leds_sent: None,
+                leds_age: 0,
+                ack_sent: None,
                 receiver: SerialDecoder::new(),
                 leds: LedRgb::default(),
                 side,
                 state: InterState::Idle,
-                keys: KeyBits::default(),
-                last_keys: KeyBits::default(),
+                sender: KeySender::new(nonce as u16),
+                key_receiver: KeyReceiver::new(),
+                quiet: 0,
+                asleep: false,
                 side_warn: false,
+                auth_warn: false,
+                auth,
                 uart,
                 events,
                 requests: req_recv,
@@ -74,11 +190,16 @@ impl InterHandler {
         // harder work.
         let mut next = time::now() + Duration::millis_at_least(5);
         loop {
+            // Asleep, there is nothing to do until asked.
+            if self.asleep {
+                let ev = self.requests.recv_async().await.unwrap();
+                self.request(ev);
+                next = time::now() + Duration::millis_at_least(5);
+                continue;
+            }
+
             if let Ok(ev) = self.requests.recv_timeout_async(next).await {
-                match ev {
-                    InterUpdate::SetState(st) => self.set_state(st),
-                    InterUpdate::AddKey(key) => self.add_key(key),
-                }
+                self.request(ev);
                 continue;
             }
 
@@ -93,6 +214,35 @@ impl InterHandler {
         }
     }
 
+    /// Handle an update from the rest of the system.
+    fn request(&mut self, ev: InterUpdate) {
+        match ev {
+            InterUpdate::SetState(st) => self.set_state(st),
+            InterUpdate::AddKey(key) => {
+                self.sleep(false);
+                self.add_key(key);
+                self.send_keys();
+            }
+            InterUpdate::Sleep(asleep) => self.sleep(asleep),
+        }
+    }
+
+    /// Power the link down for sleep, or back up.  Whatever arrived while asleep is stale, so the
+    /// decoder starts over.
+    fn sleep(&mut self, asleep: bool) {
+        if self.asleep == asleep {
+            return;
+        }
+        self.asleep = asleep;
+        let err = unsafe { bbq_inter_suspend(asleep) };
+        if err < 0 {
+            warn!("Inter link {} failed: {}", if asleep { "suspend" } else { "resume" }, err);
+        }
+        if !asleep {
+            self.resync();
+        }
+    }
+
     pub async fn tick(&mut self) {
         // Make an assumption that the uart fifo is large enough to hold an
         // entire packet, and that this packet can be sent entirely in the 1ms
@@ -103,6 +253,15 @@ impl InterHandler {
                 Ok(Some(ch)) => {
                     if let Some(packet) = self.receiver.add_decode::<Packet>(ch) {
                         // info!("rcv: {:?}", packet);
+                        if let Some(auth) = &mut self.auth {
+                            if !auth.verify(&packet) {
+                                if packet.mac.is_none() && !self.auth_warn {
+                                    warn!("Other side isn't authenticating the link");
+                                    self.auth_warn = true;
+                                }
+                                continue;
+                            }
+                        }
                         match packet.role {
                             Role::Idle => {
                                 if packet.side == self.side && !self.side_warn {
@@ -113,12 +272,17 @@ impl InterHandler {
                             Role::Primary => {
                                 // Upon receiving a primary message, this tells us we are secondary.
                                 self.set_state(InterState::Secondary);
+                                if let Some(ack) = packet.ack {
+                                    self.sender.ack(ack);
+                                }
                             }
                             Role::Secondary => {
-                                self.events.send(Event::Heartbeat).unwrap();
-                                if let Some(keys) = packet.keys {
-                                    self.update_keys(keys);
-                                }
+                                self.events.inter(InterEvent::Heartbeat);
+                                self.quiet = 0;
+                                let events = &self.events;
+                                self.key_receiver.receive(&packet, |ev| {
+                                    events.inter(InterEvent::Key(ev));
+                                });
                             }
                         }
                     }
@@ -128,39 +292,141 @@ impl InterHandler {
             }
         }
 
+        // A receive error damages the packet being received, so drop it rather than wait for the
+        // CRC to.
+        let errors = unsafe { bbq_inter_err_check() };
+        if errors > 0 {
+            self.receiver.reset();
+            if self.quality.errors(UartErrors::from_bits_truncate(errors as u8)) {
+                self.resync();
+            }
+        }
+        self.quality.tick();
+
+        // If the Secondary has gone, nothing it held should stay down.
+        if self.state == InterState::Primary {
+            self.quiet += 1;
+            if self.quiet == QUIET_TICKS {
+                let events = &self.events;
+                self.key_receiver.release_all(|ev| events.inter(InterEvent::Key(ev)));
+            }
+        }
+
         // Add this yield to give a chance for the matrix scan to happen in between.
         zephyr::kio::yield_now().await;
 
-        // Transmit our state packet.
+        self.budget.tick();
+        self.leds_age += 1;
+
+        // Finish sending the previous packet before starting another.
+        if !self.xmit_buffer.is_empty() {
+            self.try_send();
+        } else {
+            self.send_packet();
+        }
+
+        let stats = self.budget.stats();
+        SENT.store(stats.sent, Ordering::Relaxed);
+        DEFERRED.store(stats.deferred, Ordering::Relaxed);
+        PREEMPTED.store(stats.preempted, Ordering::Relaxed);
+        let errors = self.quality.stats();
+        OVERRUNS.store(errors.overruns, Ordering::Relaxed);
+        FRAMING.store(errors.framing, Ordering::Relaxed);
+        PER_SEC.store(errors.per_sec, Ordering::Relaxed);
+        RESYNCS.store(errors.resyncs, Ordering::Relaxed);
+    }
+
+    /// After a burst of errors, start over at the next packet: discard whatever is waiting in the
+    /// FIFO, and any partial packet.
+    fn resync(&mut self) {
+        let mut discarded = 0;
+        while let Ok(Some(_)) = self.uart_read() {
+            discarded += 1;
+        }
+        self.receiver.reset();
+        warn!("Inter link resync after receive errors, {} bytes discarded", discarded);
+    }
+
+    /// Send key events right away, as Secondary, rather than waiting for the next tick.  A chatter
+    /// packet still being sent is cut short, the other side drops what it got of it.
+    fn send_keys(&mut self) {
+        if self.state != InterState::Secondary {
+            return;
+        }
+        if !self.xmit_buffer.is_empty() {
+            if self.xmit_priority == Priority::Keys {
+                // The events go out with the next packet.
+                return;
+            }
+            self.budget.preempt(self.xmit_buffer.len());
+            self.xmit_buffer.clear();
+        }
+        self.send_packet();
+    }
+
+    /// Build and start sending our state packet, if the budget allows.
+    fn send_packet(&mut self) {
         let mut packet;
+        let mut priority = Priority::Chatter;
         match self.state {
             InterState::Idle => {
                 packet = Packet::new(Role::Idle, self.side);
-                // Packet::Idle { side: self.side }.encode(&mut self.xmit_buffer, &mut self.seq);
             }
             InterState::Primary => {
                 packet = Packet::new(Role::Primary, self.side);
-                packet.set_leds(self.leds.to_rgb8());
+                // Only send the LEDs when they change, or now and then in case one was lost.
+                let leds = self.leds.to_rgb8();
+                if self.leds_sent != Some(leds) || self.leds_age >= LED_REFRESH_TICKS {
+                    packet.set_leds(leds);
+                }
+                packet.ack = self.key_receiver.ack();
+                if packet.ack != self.ack_sent {
+                    priority = Priority::Keys;
+                }
             }
             InterState::Secondary => {
                 packet = Packet::new(Role::Secondary, self.side);
-                packet.set_keys(self.keys);
+                self.sender.fill(&mut packet);
+                if packet.events.is_some() {
+                    priority = Priority::Keys;
+                }
             }
         }
-        self.xmit_buffer.clear();
+        if let Some(auth) = &mut self.auth {
+            auth.sign(&mut packet);
+        }
         serial_encode(&packet, PacketWrap(&mut self.xmit_buffer), true).unwrap();
 
+        if !self.budget.admit(priority, self.xmit_buffer.len()) {
+            self.xmit_buffer.clear();
+            return;
+        }
+        if let Some(leds) = packet.leds {
+            self.leds_sent = Some(leds);
+            self.leds_age = 0;
+        }
+        if packet.ack.is_some() {
+            self.ack_sent = packet.ack;
+        }
+        self.xmit_priority = priority;
         self.try_send();
     }
 
+    /// Send as much of the packet as will fit in the FIFO.  The rest is left in the buffer for
+    /// the next tick, as authenticated packets can be larger than the FIFO.
     fn try_send(&mut self) {
-        // TODO: Buffer this better.
-        while let Some(ch) = self.xmit_buffer.pop_front() {
+        while let Some(&ch) = self.xmit_buffer.front() {
             let buf = [ch];
             match unsafe { self.uart.fifo_fill(&buf) } {
-                Ok(1) => (),
-                Ok(_) => (), // TODO: warn?
-                Err(_) => (),
+                Ok(1) => {
+                    self.xmit_buffer.pop_front();
+                }
+                Ok(_) => break,
+                // Drop the packet, a new one will be sent next time.
+                Err(_) => {
+                    self.xmit_buffer.clear();
+                    break;
+                }
             }
         }
     }
@@ -172,46 +438,12 @@ impl InterHandler {
         if self.state != state {
             self.state = state;
             info!("Inter state change: {:?}", state);
-            self.events.send(Event::BecomeState(state)).unwrap();
+            self.events.inter(InterEvent::BecomeState(state));
         }
     }
 
     pub fn add_key(&mut self, key: KeyEvent) {
-        let index = key.key() / 8;
-        let bit = 1u8 << (key.key() % 8);
-        if key.is_press() {
-            self.keys[index as usize] |= bit;
-        } else {
-            self.keys[index as usize] &= !bit;
-        }
-    }
-
-    /// Send events for every key that has changed.
-    fn update_keys(&mut self, keys: KeyBits) {
-        // Quickly handle the common case of no changes.
-        if self.last_keys == keys {
-            return;
-        }
-
-        // info!("keys: {:02x?}, last: {:02x?}", keys, self.last_keys);
-
-        let mut key = 0;
-        for byte in 0..keys.len() {
-            for bit in 0..8 {
-                let bnum = 1 << bit;
-                if (keys[byte] & bnum) != (self.last_keys[byte] & bnum) {
-                    let ev = if (keys[byte] & bnum) != 0 {
-                        KeyEvent::Press(key)
-                    } else {
-                        KeyEvent::Release(key)
-                    };
-                    self.events.send(Event::InterKey(ev)).unwrap();
-                }
-
-                key += 1;
-            }
-        }
-        self.last_keys = keys;
+        self.sender.add_key(key);
     }
 
     /// Try to read a single byte from the UART.
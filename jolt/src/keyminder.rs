@@ -1,10 +1,24 @@
 //! Handle keyminder requests.
 
+use core::convert::Infallible;
+#[cfg(feature = "minder-flash")]
+use core::slice;
+use core::sync::atomic::{AtomicU8, Ordering};
+
 use alloc::vec;
+use alloc::format;
 use alloc::{string::ToString, vec::Vec};
 
-use log::info;
-use minder::{Reply, Request, SerialDecoder};
+use bbq_keyboard::time::Clock;
+use log::{info, warn};
+#[cfg(feature = "minder-flash")]
+use minder::partition;
+use minder::message::{Ble, Core, Debug, Dict, Flash, Keymap, Leds, Macros, Stats};
+use minder::{
+    pipeline::{Incoming, Sequenced},
+    session::Verdict, Arq, HashAlgorithm, HidDecoder, HidWrite, Message, Reply, SessionId, PACKET_SIZE,
+};
+use minicbor::{encode::{self, Write}, Encode, Encoder};
 use zephyr::{
     device::uart::UartIrq,
     kobj_define, printkln,
@@ -12,7 +26,15 @@ use zephyr::{
     time::{Duration, NoWait},
 };
 
+use crate::console;
+use crate::crash;
+use crate::devices::usb::Usb;
+use crate::dispatch::Dispatch;
+use crate::flash;
+use crate::image;
 use crate::logging::Logger;
+use crate::metrics;
+use crate::SysClock;
 
 /// The minder.
 pub struct Minder();
@@ -25,23 +47,50 @@ const READ_RINGS: usize = 8;
 /// The size of the read buffers.
 const READ_BUFSIZE: usize = 256;
 
+/// Our sessions, for arbitration between the transports.
+const SERIAL_SESSION: SessionId = 0;
+const HID_SESSION: SessionId = 1;
+
+/// The most sequenced requests a host can have outstanding.  The serial port's read rings hold far
+/// more than this, and HID requests wait in the host until read.
+const PIPELINE_DEPTH: u8 = 4;
+
+/// The largest flash read we will reply with.
+#[cfg(feature = "minder-flash")]
+const MAX_FLASH_READ: u32 = 1024;
+
 impl Minder {
-    pub fn new(uart: Uart, log: Arc<Mutex<Logger>>) -> Minder {
+    /// Start the minder on the serial port, and, unless USB only offers a boot keyboard, on the
+    /// HID interface.
+    pub fn new(uart: Uart, usb: Usb, log: Arc<Mutex<Logger>>, dispatch: Arc<Dispatch>) -> Minder {
+        if usb.has_minder() {
+            let mut thread = MINDER_HID_THREAD
+                .init_once(MINDER_HID_STACK.init_once(()).unwrap())
+                .unwrap();
+            thread.set_priority(4);
+            thread.set_name(c"minder-hid");
+            let dispatch = dispatch.clone();
+            thread.spawn(move || {
+                minder_hid_thread(usb, dispatch);
+            });
+        }
+
         let mut thread = MINDER_THREAD
             .init_once(MINDER_STACK.init_once(()).unwrap())
             .unwrap();
         thread.set_priority(4);
         thread.set_name(c"minder");
         thread.spawn(move || {
-            minder_thread(uart, log);
+            minder_thread(uart, log, dispatch);
         });
 
         Minder()
     }
 }
 
-fn minder_thread(mut uart: Uart, log: Arc<Mutex<Logger>>) {
-    let mut decoder = SerialDecoder::new();
+fn minder_thread(mut uart: Uart, log: Arc<Mutex<Logger>>, dispatch: Arc<Dispatch>) {
+    // Damaged requests are retried, for hosts that use ARQ.
+    let mut arq = Arq::responder();
 
     // Add two buffers for reading.
     for _ in 0..READ_RINGS {
@@ -51,14 +100,15 @@ fn minder_thread(mut uart: Uart, log: Arc<Mutex<Logger>>) {
     // TODO: This should be better than just counting, as it would print way more frequently with
     // more messages.
 
-    let mut reply_hello = false;
     loop {
         match uart.read_wait(Duration::millis_at_least(100)) {
             Ok(buf) => {
                 for &byte in buf.as_slice() {
-                    if let Some(packet) = decoder.add_decode::<Request>(byte) {
-                        info!("Minder: {:?}", packet);
-                        reply_hello = true;
+                    let Some(packet) = arq.add_packet(byte) else {
+                        continue;
+                    };
+                    if let Some(answer) = answer(&packet, SERIAL_SESSION, &dispatch) {
+                        arq.send(&answer);
                     }
                 }
 
@@ -69,22 +119,15 @@ fn minder_thread(mut uart: Uart, log: Arc<Mutex<Logger>>) {
             Err(_) => (),
         }
 
-        // If we got a hello, send a reply.
-        if reply_hello {
-            reply_hello = false;
-
-            let mut buffer = Vec::new();
-            let reply = Reply::Hello {
-                version: minder::VERSION.to_string(),
-                info: "todo: put build information here".to_string(),
-            };
-            minder::serial_encode(&reply, &mut buffer, true).unwrap();
-
-            // Attempt to write it, but just ignore the error if we can't.
-            let len = buffer.len();
-            let _ = uart.write_enqueue(buffer, 0..len);
+        // Streamed events go out with the replies.
+        while let Some(event) = stream_event(SERIAL_SESSION, &dispatch) {
+            arq.send(&event);
         }
 
+        // Send any replies to the requests we got, and the acknowledgements, and resend what
+        // hasn't been acknowledged in time.
+        flush(&mut uart, &mut arq);
+
         // Try printing out log messages.  We intentionally only lock for each message to avoid
         // locking anything too long.
         loop {
@@ -121,31 +164,533 @@ fn minder_thread(mut uart: Uart, log: Arc<Mutex<Logger>>) {
             drop(inner);
 
             if let Some(msg) = msg {
-                // Encode the message to a new Vec<u8> so we can write it as a single unit.
-                let mut buffer = Vec::new();
-                let reply = Reply::Log { message: msg };
-                minder::serial_encode(&reply, &mut buffer, true).unwrap();
-
-                // Write the entire thing.
-                let len = buffer.len();
-                uart.write_enqueue(buffer, 0..len)
-                    .expect("Queue full, despite check");
+                arq.send(&Reply::Log { message: msg });
+                flush(&mut uart, &mut arq);
             } else {
                 break;
             }
         }
-        /*
-        while let Some(msg) = log.lock().unwrap().pop(1) {
-            let reply = Reply::Log {
-                message: msg,
+    }
+}
+
+/// Write the frames ARQ has to send.  A frame that doesn't fit in the write queue is lost, the
+/// same as one damaged on the way, and is sent again if the host is using ARQ.
+fn flush(uart: &mut Uart, arq: &mut Arq) {
+    let now = SysClock.millis();
+    while let Some(frame) = arq.next_frame(now) {
+        let len = frame.len();
+        let _ = uart.write_enqueue(frame, 0..len);
+    }
+}
+
+/// The minder on the HID interface, for hosts that can't, or would rather not, use the serial
+/// port.  The requests and replies are the same, framed with [`minder::hid_encode`].  Logs only go
+/// to the serial port.
+fn minder_hid_thread(usb: Usb, dispatch: Arc<Dispatch>) {
+    let mut decoder = HidDecoder::new();
+    let mut buf = [0u8; PACKET_SIZE];
+
+    loop {
+        match usb.minder_read_out(Duration::millis_at_least(100), &mut buf) {
+            Ok(PACKET_SIZE) => {
+                decoder.add_packet(&buf);
+                if let Some(packet) = decoder.take_packet() {
+                    if let Some(answer) = answer(&packet, HID_SESSION, &dispatch) {
+                        answer.send_hid(&usb);
+                    }
+                }
+            }
+            Ok(count) => warn!("Short minder report: {} bytes", count),
+            // Timeout, just go on.
+            Err(_) => (),
+        }
+
+        while let Some(event) = stream_event(HID_SESSION, &dispatch) {
+            event.send_hid(&usb);
+        }
+    }
+}
+
+/// The session the monitor last subscribed from, which gets the streamed events.
+static STREAM_SESSION: AtomicU8 = AtomicU8::new(SERIAL_SESSION);
+
+/// A reply, in the form of the request it answers.  A bare request gets a bare reply, for older
+/// hosts, and a sequenced request gets its id back.
+struct Answer {
+    body: Body,
+    seq: Option<u32>,
+}
+
+enum Body {
+    Bare(Reply),
+    Message(Message),
+}
+
+impl Answer {
+    /// A message, as a bare reply if that is what is wanted.  None if it has no bare form.
+    fn new(message: Message, bare: bool, seq: Option<u32>) -> Option<Answer> {
+        let body = if bare {
+            Body::Bare(Reply::try_from(message).ok()?)
+        } else {
+            Body::Message(message)
+        };
+        Some(Answer { body, seq })
+    }
+
+    /// Send as minder HID reports.
+    fn send_hid(&self, usb: &Usb) {
+        minder::hid_encode(self, MinderReports(usb)).unwrap();
+    }
+}
+
+impl Encode<()> for Answer {
+    fn encode<W: Write>(&self, e: &mut Encoder<W>, ctx: &mut ()) -> Result<(), encode::Error<W::Error>> {
+        match (&self.body, self.seq) {
+            (Body::Bare(reply), None) => reply.encode(e, ctx),
+            (Body::Message(message), None) => message.encode(e, ctx),
+            (Body::Bare(reply), Some(seq)) => Sequenced { seq, item: reply }.encode(e, ctx),
+            (Body::Message(message), Some(seq)) => Sequenced { seq, item: message }.encode(e, ctx),
+        }
+    }
+}
+
+/// Writes packets as minder HID reports.  These are queued, so can't fail.
+struct MinderReports<'a>(&'a Usb);
+
+impl HidWrite for MinderReports<'_> {
+    type Error = Infallible;
+
+    fn write_packet(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.0.send_minder_report(buf);
+        Ok(())
+    }
+}
+
+/// Handle a request from a session, giving the reply, if there is one.  Requests are handled in
+/// the order they arrive, so a host can have several outstanding, up to [`PIPELINE_DEPTH`], as
+/// long as it gives them sequence ids to match the replies by.
+fn answer(packet: &[u8], session: SessionId, dispatch: &Dispatch) -> Option<Answer> {
+    let Incoming { message, bare, seq } = Incoming::decode(packet)?;
+    info!("Minder: {:?}", message);
+    if let Message::Debug(Debug::Subscribe { .. }) = message {
+        STREAM_SESSION.store(session, Ordering::Release);
+    }
+    let reply = match dispatch.arbitrate(session, &message) {
+        Verdict::Allow => handle_message(message, dispatch)?,
+        Verdict::Busy(owner) => Message::Core(Core::Busy { owner }),
+    };
+    Answer::new(reply, bare, seq)
+}
+
+/// The next streamed event, if the monitor subscribed from this session.
+fn stream_event(session: SessionId, dispatch: &Dispatch) -> Option<Answer> {
+    if STREAM_SESSION.load(Ordering::Acquire) != session {
+        return None;
+    }
+    let event = dispatch.stream_event()?;
+    Some(Answer { body: Body::Message(Message::Debug(event)), seq: None })
+}
+
+/// Build the reply to a single message, handing it to the handler for its topic.
+fn handle_message(message: Message, dispatch: &Dispatch) -> Option<Message> {
+    match message {
+        Message::Core(core) => handle_core(core, dispatch).map(Message::Core),
+        Message::Flash(flash) => handle_flash(flash, dispatch).map(Message::Flash),
+        Message::Dict(dict) => handle_dict(dict, dispatch).map(Message::Dict),
+        Message::Debug(debug) => handle_debug(debug, dispatch).map(Message::Debug),
+        Message::Stats(stats) => handle_stats(stats, dispatch).map(Message::Stats),
+        Message::Keymap(keymap) => handle_keymap(keymap, dispatch).map(Message::Keymap),
+        Message::Leds(leds) => handle_leds(leds, dispatch).map(Message::Leds),
+        Message::Ble(ble) => handle_ble(ble, dispatch).map(Message::Ble),
+        Message::Macros(macros) => handle_macros(macros, dispatch).map(Message::Macros),
+    }
+}
+
+fn handle_core(core: Core, dispatch: &Dispatch) -> Option<Core> {
+    match core {
+        Core::Hello { .. } => {
+            let image = image::info();
+            Some(Core::HelloReply {
+                version: minder::VERSION.to_string(),
+                info: format!("{} {}{} {:08x} {}",
+                              image.version,
+                              image.git,
+                              if image.dirty { "-dirty" } else { "" },
+                              image.build_id(),
+                              image.board),
+                hashes: Some(if cfg!(feature = "minder-flash") {
+                    HashAlgorithm::supported()
+                } else {
+                    Vec::new()
+                }),
+                pipeline: Some(PIPELINE_DEPTH),
+            })
+        }
+        Core::GetStatus => {
+            let image = image::info();
+            Some(Core::Status {
+                build_id: image.build_id(),
+                image,
+                uptime: SysClock.millis(),
+                usage: Some(dispatch.usage()),
+                #[cfg(feature = "steno")]
+                dicts: Some(bbq_keyboard::dict::status(&flash::FLASH)),
+                #[cfg(not(feature = "steno"))]
+                dicts: None,
+                link: crate::inter::link_status(),
+            })
+        }
+        Core::Reboot => {
+            dispatch.request_shutdown();
+            Some(Core::Rebooting)
+        }
+        Core::TypeText { text } => {
+            let status = match dispatch.type_text(text) {
+                Ok(()) => 0,
+                Err(e) => e,
             };
-            minder::serial_encode(&reply, WritePort(&mut uart)).unwrap();
+            Some(Core::TextQueued { status })
+        }
+        Core::SetHostLayout { layout } => {
+            dispatch.set_host_layout(layout);
+            Some(Core::HostLayoutSet { layout })
+        }
+        Core::SetUnicodeEntry { entry } => {
+            dispatch.set_unicode_entry(entry);
+            Some(Core::UnicodeEntrySet { entry })
+        }
+        Core::Release => {
+            dispatch.release();
+            None
         }
-        */
+        // Replies aren't for us.
+        _ => None,
+    }
+}
+
+#[cfg(feature = "minder-flash")]
+fn handle_flash(flash: Flash, dispatch: &Dispatch) -> Option<Flash> {
+    match flash {
+        Flash::Read { offset, size } => {
+            let data = flash_slice(offset, size.min(MAX_FLASH_READ)).unwrap_or_default();
+            Some(Flash::Data {
+                offset,
+                data: data.to_vec(),
+            })
+        }
+        Flash::Hash { offset, size, algorithm } => {
+            let algorithm = algorithm.unwrap_or(HashAlgorithm::Sha256);
+            let digest = flash_slice(offset, size)
+                .and_then(|data| algorithm.digest(data))
+                .unwrap_or_default();
+            Some(Flash::Digest { offset, size, algorithm, digest })
+        }
+        Flash::Program { offset, data } => {
+            let size = data.len() as u32;
+            let status = if offset % partition::SECTOR_SIZE != 0
+                || size > partition::SECTOR_SIZE
+                || flash::FLASH.find(offset, partition::SECTOR_SIZE).is_none()
+            {
+                -(zephyr::raw::EINVAL as i32)
+            } else {
+                #[cfg(feature = "steno")]
+                if partition::MAIN_DICT.contains(offset, size) || partition::USER_DICT.contains(offset, size) {
+                    dispatch.unload_dicts();
+                }
+                match flash::program(offset, &data) {
+                    Ok(()) => 0,
+                    Err(e) => e,
+                }
+            };
+            Some(Flash::Programmed { offset, size, status })
+        }
+        Flash::Erase { offset, size } => {
+            let status = if offset % partition::SECTOR_SIZE != 0
+                || size % partition::SECTOR_SIZE != 0
+                || !partition::STAGING.contains(offset, size)
+            {
+                -(zephyr::raw::EINVAL as i32)
+            } else {
+                match flash::erase_staging(offset, size) {
+                    Ok(()) => 0,
+                    Err(e) => e,
+                }
+            };
+            Some(Flash::Erased { offset, size, status })
+        }
+        Flash::ProgramImage { offset, data } => {
+            let size = data.len() as u32;
+            let status = if offset % partition::PAGE_SIZE != 0
+                || size > partition::SECTOR_SIZE
+                || !partition::STAGING.contains(offset, size.next_multiple_of(partition::PAGE_SIZE))
+            {
+                -(zephyr::raw::EINVAL as i32)
+            } else {
+                match flash::program_staging(offset, &data) {
+                    Ok(()) => 0,
+                    Err(e) => e,
+                }
+            };
+            Some(Flash::Programmed { offset, size, status })
+        }
+        Flash::Boot { size, digest, signature } => {
+            let status = match check_staged(size, &digest, signature.as_deref()) {
+                Ok(()) => {
+                    info!("Installing new firmware, {} bytes", size);
+                    flash::stage_install(size);
+                    dispatch.request_shutdown();
+                    0
+                }
+                Err(e) => e,
+            };
+            Some(Flash::Booting { status })
+        }
+        _ => None,
+    }
+}
+
+/// Check that the first `size` bytes of the staging area are an image for this board, matching the
+/// SHA-256 `digest`.  If this firmware was built with a signing key, the digest must also be signed
+/// by it.
+#[cfg(feature = "minder-flash")]
+fn check_staged(size: u32, digest: &[u8], signature: Option<&[u8]>) -> Result<(), i32> {
+    let image = flash_slice(partition::STAGING.offset, size).ok_or(-(zephyr::raw::EINVAL as i32))?;
+    if size == 0 || HashAlgorithm::Sha256.digest(image).as_deref() != Some(digest) {
+        return Err(-(zephyr::raw::EBADMSG as i32));
+    }
+    match image::firmware_key() {
+        Some(key) => {
+            if !signature.is_some_and(|signature| minder::sign::verify(key, digest, signature)) {
+                warn!("Firmware image is not signed by our key");
+                return Err(-(zephyr::raw::EACCES as i32));
+            }
+        }
+        None => info!("Firmware updates aren't signed in this build"),
+    }
+    match minder::ImageInfo::find(image) {
+        Some(new) if new.is_for_board(&image::info().board) => Ok(()),
+        _ => Err(-(zephyr::raw::ENOEXEC as i32)),
+    }
+}
+
+/// Left out of small builds.  Each request is still answered, as failing, so that the host isn't
+/// left waiting.
+#[cfg(not(feature = "minder-flash"))]
+fn handle_flash(flash: Flash, _dispatch: &Dispatch) -> Option<Flash> {
+    let status = -(zephyr::raw::ENOTSUP as i32);
+    match flash {
+        Flash::Read { offset, .. } => Some(Flash::Data { offset, data: Vec::new() }),
+        Flash::Hash { offset, size, algorithm } => Some(Flash::Digest {
+            offset,
+            size,
+            algorithm: algorithm.unwrap_or(HashAlgorithm::Sha256),
+            digest: Vec::new(),
+        }),
+        Flash::Program { offset, data } | Flash::ProgramImage { offset, data } => {
+            Some(Flash::Programmed { offset, size: data.len() as u32, status })
+        }
+        Flash::Erase { offset, size } => Some(Flash::Erased { offset, size, status }),
+        Flash::Boot { .. } => Some(Flash::Booting { status }),
+        _ => None,
+    }
+}
+
+fn handle_dict(dict: Dict, dispatch: &Dispatch) -> Option<Dict> {
+    match dict {
+        Dict::ReadTape => {
+            let tape = dispatch.tape.lock().unwrap();
+            Some(Dict::Tape {
+                generation: tape.generation(),
+                text: tape.export(),
+            })
+        }
+        Dict::SetProfile { name, dicts, timeout, overlay, strategy } => {
+            dispatch.set_profile(name.clone(), dicts, timeout, overlay, strategy);
+            Some(Dict::Profile { name })
+        }
+        #[cfg(feature = "steno")]
+        Dict::List { algorithm } => {
+            Some(Dict::Info { dicts: bbq_keyboard::dict::list(&flash::FLASH, algorithm) })
+        }
+        #[cfg(all(feature = "steno", feature = "minder-flash"))]
+        Dict::Patch { offset, size, data } => {
+            let status = match dispatch.receive_dict_patch(offset, size, &data) {
+                Ok(()) => 0,
+                Err(e) => e,
+            };
+            Some(Dict::Patched { offset, status })
+        }
+        _ => None,
+    }
+}
+
+fn handle_debug(debug: Debug, dispatch: &Dispatch) -> Option<Debug> {
+    match debug {
+        Debug::Exec { command } => {
+            Some(Debug::Output {
+                output: console::exec(&command, dispatch),
+            })
+        }
+        Debug::Subscribe { events, timeout } => Some(dispatch.subscribe(events, timeout)),
+        #[cfg(feature = "trace")]
+        Debug::GetTrace { offset } => Some(dispatch.read_trace(offset)),
+        // Without tracing, the trace is always empty.
+        #[cfg(not(feature = "trace"))]
+        Debug::GetTrace { offset } => Some(Debug::Trace { offset, size: 0, data: Vec::new() }),
+        Debug::GetCrashLog { clear } => {
+            let log = crash::last();
+            if clear {
+                crash::clear();
+            }
+            Some(Debug::CrashLog { log })
+        }
+        _ => None,
+    }
+}
+
+fn handle_stats(stats: Stats, dispatch: &Dispatch) -> Option<Stats> {
+    match stats {
+        Stats::GetUsage => Some(Stats::Usage { modes: dispatch.usage() }),
+        Stats::GetRuntime => Some(Stats::Runtime { stats: metrics::runtime() }),
+        #[cfg(feature = "trainer")]
+        Stats::SetPace { spm } => Some(Stats::Pace { summary: dispatch.set_pace(spm) }),
+        #[cfg(feature = "trainer")]
+        Stats::GetPace => Some(Stats::Pace { summary: dispatch.pace() }),
+        _ => None,
+    }
+}
+
+/// Keymaps, and steno maps, are only there with the modes that use them.
+#[allow(unused_variables)]
+fn handle_keymap(keymap: Keymap, dispatch: &Dispatch) -> Option<Keymap> {
+    match keymap {
+        #[cfg(feature = "qwerty")]
+        Keymap::Get { offset } => {
+            let (size, data) = map_chunk(&dispatch.keymap().encode(), offset);
+            Some(Keymap::Data { offset, size, data })
+        }
+        #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+        Keymap::Set { offset, size, data } => {
+            let status = match dispatch.receive_keymap(offset, size, &data) {
+                Ok(()) => 0,
+                Err(e) => e,
+            };
+            Some(Keymap::Stored { offset, status })
+        }
+        #[cfg(feature = "steno")]
+        Keymap::GetSteno { offset } => {
+            let (size, data) = map_chunk(&dispatch.steno_map().encode(), offset);
+            Some(Keymap::StenoData { offset, size, data })
+        }
+        #[cfg(all(feature = "steno", feature = "minder-flash"))]
+        Keymap::SetSteno { offset, size, data } => {
+            let status = match dispatch.receive_steno_map(offset, size, &data) {
+                Ok(()) => 0,
+                Err(e) => e,
+            };
+            Some(Keymap::StenoStored { offset, status })
+        }
+        _ => None,
+    }
+}
+
+/// LED patterns are stored in flash, so can only be set with flash writes.
+#[allow(unused_variables)]
+fn handle_leds(leds: Leds, dispatch: &Dispatch) -> Option<Leds> {
+    match leds {
+        #[cfg(feature = "minder-flash")]
+        Leds::SetPattern { indicator, steps } => {
+            let status = match dispatch.set_led_pattern(&indicator, &steps) {
+                Ok(()) => 0,
+                Err(e) => e,
+            };
+            Some(Leds::PatternSet { indicator, status })
+        }
+        _ => None,
+    }
+}
+
+/// Macros are only there with qwerty mode, and are stored in flash, so can only be changed with
+/// flash writes.
+#[allow(unused_variables)]
+fn handle_macros(macros: Macros, dispatch: &Dispatch) -> Option<Macros> {
+    match macros {
+        #[cfg(feature = "qwerty")]
+        Macros::List => Some(Macros::Listing { macros: dispatch.macros() }),
+        #[cfg(feature = "qwerty")]
+        Macros::Download { name } => {
+            let data = dispatch.download_macro(&name);
+            Some(Macros::Data { name, data })
+        }
+        #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+        Macros::Upload { data, replace } => {
+            let Some(mac) = bbq_keyboard::macros::Macro::decode(&data) else {
+                return Some(Macros::Uploaded { slot: 0, status: -(zephyr::raw::EINVAL as i32) });
+            };
+            let slot = mac.slot;
+            let status = match dispatch.upload_macro(mac, replace) {
+                Ok(()) => 0,
+                Err(e) => e,
+            };
+            Some(Macros::Uploaded { slot, status })
+        }
+        #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+        Macros::Delete { name } => {
+            let status = match dispatch.delete_macro(&name) {
+                Ok(()) => 0,
+                Err(e) => e,
+            };
+            Some(Macros::Deleted { name, status })
+        }
+        _ => None,
+    }
+}
+
+/// Only firmware with BLE has bonds to manage.
+#[allow(unused_variables)]
+fn handle_ble(ble: Ble, dispatch: &Dispatch) -> Option<Ble> {
+    #[cfg(CONFIG_JOLT_BLE)]
+    if let Some(dev) = dispatch.ble() {
+        return match ble {
+            Ble::ListBonds => Some(Ble::Bonds { bonds: dev.bonds() }),
+            Ble::NameBond { address, name } => {
+                Some(Ble::BondChanged { address, status: dev.name_bond(&address, &name) })
+            }
+            Ble::DeleteBond { address } => {
+                Some(Ble::BondChanged { address, status: dev.delete_bond(&address) })
+            }
+            Ble::Pair { seconds } => {
+                dev.set_pairing(seconds);
+                Some(Ble::Pairing { status: 0 })
+            }
+            _ => None,
+        };
+    }
+    None
+}
+
+/// The size of an encoded map, and the chunk of it at `offset`.
+#[cfg(any(feature = "qwerty", feature = "steno"))]
+fn map_chunk(data: &[u8], offset: u32) -> (u32, Vec<u8>) {
+    let start = (offset as usize).min(data.len());
+    let end = (start + minder::KEYMAP_CHUNK as usize).min(data.len());
+    (data.len() as u32, data[start..end].to_vec())
+}
+
+/// Get the flash at the given offset, as long as it is entirely within one of the data partitions,
+/// or the staging area.
+#[cfg(feature = "minder-flash")]
+fn flash_slice(offset: u32, size: u32) -> Option<&'static [u8]> {
+    if flash::FLASH.find(offset, size).is_none() && !partition::STAGING.contains(offset, size) {
+        return None;
     }
+    let base = flash::FLASH.address(offset) as *const u8;
+    Some(unsafe { slice::from_raw_parts(base, size as usize) })
 }
 
 kobj_define! {
     static MINDER_THREAD: StaticThread;
     static MINDER_STACK: ThreadStack<4096>;
+    static MINDER_HID_THREAD: StaticThread;
+    static MINDER_HID_STACK: ThreadStack<4096>;
 }
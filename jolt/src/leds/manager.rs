@@ -5,7 +5,16 @@
 
 extern crate alloc;
 
+use alloc::boxed::Box;
+use alloc::vec;
 use alloc::vec::Vec;
+use bbq_keyboard::backlight::{Backlight, KeyClass};
+#[cfg(feature = "experimental")]
+use bbq_keyboard::layout::experimental::{self, EXPERIMENTAL_IDS};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use log::warn;
+use minder::LedStep;
 use rgb::RGB8;
 use zephyr::kobj_define;
 use zephyr::sync::{Arc, Condvar, Mutex};
@@ -15,16 +24,103 @@ use super::LedSet;
 const OFF: RGB8 = RGB8::new(0, 0, 0);
 // const INIT: RGB8 = RGB8::new(8, 8, 0);
 
-pub struct Indication(&'static [Step]);
+/// A pattern shown on an LED.  Each has one built into the firmware, which the user can replace
+/// (see [`Indication::set_pattern`]), by giving its name.
+pub struct Indication {
+    name: &'static str,
+    builtin: &'static [Step],
+    /// The pattern set by the user, or null for the built-in one.
+    custom: AtomicPtr<Vec<Step>>,
+}
 
 struct Step {
     color: RGB8,
     count: usize,
 }
 
+impl Indication {
+    const fn new(name: &'static str, builtin: &'static [Step]) -> Indication {
+        Indication { name, builtin, custom: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    /// The name used to replace the pattern.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The steps to show, the user's if they have set any.
+    fn steps(&self) -> &'static [Step] {
+        let custom = self.custom.load(Ordering::Acquire);
+        if custom.is_null() {
+            self.builtin
+        } else {
+            // Set patterns are never freed, see `set_pattern`.
+            unsafe { (*custom).as_slice() }
+        }
+    }
+
+    /// Replace the pattern with the given steps, or go back to the built-in one if there are none.
+    /// Steps that are replaced are leaked, as an LED may still be showing them.  Patterns are
+    /// small, and only change when the user asks.
+    pub fn set_pattern(&self, steps: &[LedStep]) {
+        let custom = if steps.is_empty() {
+            ptr::null_mut()
+        } else {
+            let steps: Vec<_> = steps
+                .iter()
+                .map(|step| {
+                    let (r, g, b) = step.rgb();
+                    Step { color: RGB8::new(r, g, b), count: step.count as usize }
+                })
+                .collect();
+            Box::into_raw(Box::new(steps))
+        };
+        self.custom.store(custom, Ordering::Release);
+    }
+}
+
+/// Find an indicator by name.
+pub fn find(name: &str) -> Option<&'static Indication> {
+    #[cfg(feature = "trainer")]
+    if name == BEAT_INDICATOR.name {
+        return Some(&BEAT_INDICATOR);
+    }
+    INDICATORS.iter().copied().find(|ind| ind.name == name)
+}
+
+/// The indicators that can be found by name, other than those that depend on features.
+static INDICATORS: &[&Indication] = &[
+    &INIT_INDICATOR,
+    &UNDEF_INDICATOR,
+    &USB_PRIMARY,
+    &OFF_INDICATOR,
+    &GEMINI_INDICATOR,
+    &SLEEP_INDICATOR,
+    &STENO_INDICATOR,
+    &STENO_SELECT_INDICATOR,
+    &STENO_RAW_INDICATOR,
+    &STENO_RAW_SELECT_INDICATOR,
+    &STENO_DIRECT_INDICATOR,
+    &STENO_DIRECT_SELECT_INDICATOR,
+    &NKRO_INDICATOR,
+    &NKRO_SELECT_INDICATOR,
+    &ARTSEY_INDICATOR,
+    &ARTSEY_SELECT_INDICATOR,
+    &TAIPO_INDICATOR,
+    &TAIPO_SELECT_INDICATOR,
+    &QWERTY_INDICATOR,
+    &QWERTY_SELECT_INDICATOR,
+    &NUMPAD_INDICATOR,
+    &NUMPAD_SELECT_INDICATOR,
+    &ARTSEY_NAV_INDICATOR,
+    &CAPS_WORD_INDICATOR,
+    &BRIEF_INDICATOR,
+    &TEST_INDICATOR,
+];
+
 /// Indicates we are initializing, waiting for either USB configuration, or
 /// successful communication with the primary side, which does have USB.
-pub static INIT_INDICATOR: Indication = Indication(&[
+pub static INIT_INDICATOR: Indication = Indication::new("init", &[
     Step {
         color: RGB8::new(8, 0, 0),
         count: 1,
@@ -45,7 +141,7 @@ pub static INIT_INDICATOR: Indication = Indication(&[
 
 /// An unreferenced indicator.  Indicates the indicator has not been assigned.
 /// Intended to not be intrusive, but obvious.
-pub static UNDEF_INDICATOR: Indication = Indication(&[
+pub static UNDEF_INDICATOR: Indication = Indication::new("undef", &[
     Step {
         color: RGB8::new(1, 1, 1),
         count: 1,
@@ -58,7 +154,7 @@ pub static UNDEF_INDICATOR: Indication = Indication(&[
 
 /// Indicates we are connected to USB, but haven't established communication
 /// with the other half of the keyboard.
-pub static USB_PRIMARY: Indication = Indication(&[
+pub static USB_PRIMARY: Indication = Indication::new("usb-primary", &[
     Step {
         color: RGB8::new(8, 8, 0),
         count: 3,
@@ -70,26 +166,26 @@ pub static USB_PRIMARY: Indication = Indication(&[
 ]);
 
 /// An indicator that just stays off.
-pub static OFF_INDICATOR: Indication = Indication(&[Step {
+pub static OFF_INDICATOR: Indication = Indication::new("off", &[Step {
     color: OFF,
     count: 10,
 }]);
 
 /// Indicates that something is connected to the gemini protocol.
-pub static GEMINI_INDICATOR: Indication = Indication(&[Step {
+pub static GEMINI_INDICATOR: Indication = Indication::new("gemini", &[Step {
     color: RGB8::new(0, 0, 8),
     count: 10,
 }]);
 
 /// Just off.
 /*
-pub static OFF_INDICATOR: Indication = Indication(&[
+pub static OFF_INDICATOR: Indication = Indication::new("off", &[
     Step { color: OFF,                count: 10000 },
 ]);
 */
 
 /// Show we are sleeping.
-pub static SLEEP_INDICATOR: Indication = Indication(&[
+pub static SLEEP_INDICATOR: Indication = Indication::new("sleep", &[
     Step {
         color: RGB8::new(0, 0, 8),
         count: 30,
@@ -101,13 +197,13 @@ pub static SLEEP_INDICATOR: Indication = Indication(&[
 ]);
 
 /// Steno mode
-pub static STENO_INDICATOR: Indication = Indication(&[Step {
+pub static STENO_INDICATOR: Indication = Indication::new("steno", &[Step {
     color: RGB8::new(0, 0, 24),
     count: 100,
 }]);
 
 /// Steno mode select
-pub static STENO_SELECT_INDICATOR: Indication = Indication(&[
+pub static STENO_SELECT_INDICATOR: Indication = Indication::new("steno-select", &[
     Step {
         color: RGB8::new(0, 0, 24),
         count: 1,
@@ -119,13 +215,13 @@ pub static STENO_SELECT_INDICATOR: Indication = Indication(&[
 ]);
 
 /// Steno mode
-pub static STENO_RAW_INDICATOR: Indication = Indication(&[Step {
+pub static STENO_RAW_INDICATOR: Indication = Indication::new("steno-raw", &[Step {
     color: RGB8::new(0, 8, 24),
     count: 100,
 }]);
 
 /// Steno mode select
-pub static STENO_RAW_SELECT_INDICATOR: Indication = Indication(&[
+pub static STENO_RAW_SELECT_INDICATOR: Indication = Indication::new("steno-raw-select", &[
     Step {
         color: RGB8::new(0, 8, 24),
         count: 1,
@@ -137,13 +233,13 @@ pub static STENO_RAW_SELECT_INDICATOR: Indication = Indication(&[
 ]);
 
 /// Steno direct (for plover)
-pub static STENO_DIRECT_INDICATOR: Indication = Indication(&[Step {
+pub static STENO_DIRECT_INDICATOR: Indication = Indication::new("steno-direct", &[Step {
     color: RGB8::new(16, 8, 0),
     count: 100,
 }]);
 
 /// Steno direct mode select
-pub static STENO_DIRECT_SELECT_INDICATOR: Indication = Indication(&[
+pub static STENO_DIRECT_SELECT_INDICATOR: Indication = Indication::new("steno-direct-select", &[
     Step {
         color: RGB8::new(16, 8, 0),
         count: 1,
@@ -155,13 +251,13 @@ pub static STENO_DIRECT_SELECT_INDICATOR: Indication = Indication(&[
 ]);
 
 /// NKRO steno mode
-pub static NKRO_INDICATOR: Indication = Indication(&[Step {
+pub static NKRO_INDICATOR: Indication = Indication::new("nkro", &[Step {
     color: RGB8::new(32, 0, 32),
     count: 100,
 }]);
 
 /// NKRO steno select mode
-pub static NKRO_SELECT_INDICATOR: Indication = Indication(&[
+pub static NKRO_SELECT_INDICATOR: Indication = Indication::new("nkro-select", &[
     Step {
         color: RGB8::new(32, 0, 32),
         count: 1,
@@ -173,13 +269,13 @@ pub static NKRO_SELECT_INDICATOR: Indication = Indication(&[
 ]);
 
 /// Artsey mode
-pub static ARTSEY_INDICATOR: Indication = Indication(&[Step {
+pub static ARTSEY_INDICATOR: Indication = Indication::new("artsey", &[Step {
     color: RGB8::new(16, 0, 0),
     count: 100,
 }]);
 
 /// Artsey select mode
-pub static ARTSEY_SELECT_INDICATOR: Indication = Indication(&[
+pub static ARTSEY_SELECT_INDICATOR: Indication = Indication::new("artsey-select", &[
     Step {
         color: RGB8::new(16, 0, 0),
         count: 1,
@@ -191,13 +287,13 @@ pub static ARTSEY_SELECT_INDICATOR: Indication = Indication(&[
 ]);
 
 /// Taipo mode
-pub static TAIPO_INDICATOR: Indication = Indication(&[Step {
+pub static TAIPO_INDICATOR: Indication = Indication::new("taipo", &[Step {
     color: RGB8::new(16, 8, 24),
     count: 100,
 }]);
 
 /// Taipo select mode
-pub static TAIPO_SELECT_INDICATOR: Indication = Indication(&[
+pub static TAIPO_SELECT_INDICATOR: Indication = Indication::new("taipo-select", &[
     Step {
         color: RGB8::new(16, 8, 24),
         count: 1,
@@ -209,13 +305,13 @@ pub static TAIPO_SELECT_INDICATOR: Indication = Indication(&[
 ]);
 
 /// Qwerty mode
-pub static QWERTY_INDICATOR: Indication = Indication(&[Step {
+pub static QWERTY_INDICATOR: Indication = Indication::new("qwerty", &[Step {
     color: RGB8::new(0, 16, 0),
     count: 100,
 }]);
 
 /// Qwerty select mode
-pub static QWERTY_SELECT_INDICATOR: Indication = Indication(&[
+pub static QWERTY_SELECT_INDICATOR: Indication = Indication::new("qwerty-select", &[
     Step {
         color: RGB8::new(0, 16, 0),
         count: 1,
@@ -226,12 +322,101 @@ pub static QWERTY_SELECT_INDICATOR: Indication = Indication(&[
     },
 ]);
 
+/// Number pad mode
+pub static NUMPAD_INDICATOR: Indication = Indication::new("numpad", &[Step {
+    color: RGB8::new(8, 16, 0),
+    count: 100,
+}]);
+
+/// Number pad select mode
+pub static NUMPAD_SELECT_INDICATOR: Indication = Indication::new("numpad-select", &[
+    Step {
+        color: RGB8::new(8, 16, 0),
+        count: 1,
+    },
+    Step {
+        color: RGB8::new(0, 0, 0),
+        count: 1,
+    },
+]);
+
+/// The indicators of the experimental modes, by id, with the select indicator after each.  These
+/// are made from the mode's color the first time they are shown.
+#[cfg(feature = "experimental")]
+static EXPERIMENT_INDICATORS: [AtomicPtr<Indication>; 2 * EXPERIMENTAL_IDS as usize] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; 2 * EXPERIMENTAL_IDS as usize];
+
+/// An experimental mode, or selecting it.  Shown in the color the mode registered with, steady, or
+/// blinking when selecting, as with the built-in modes.
+#[cfg(feature = "experimental")]
+pub fn experiment_indicator(id: u8, select: bool) -> &'static Indication {
+    let Some(info) = experimental::info(id) else {
+        return if select { &QWERTY_SELECT_INDICATOR } else { &QWERTY_INDICATOR };
+    };
+    let slot = &EXPERIMENT_INDICATORS[2 * id as usize + select as usize];
+    let found = slot.load(Ordering::Acquire);
+    if !found.is_null() {
+        return unsafe { &*found };
+    }
+
+    // These are never freed, there are only a few of them.
+    let color = RGB8::new(info.indicator.r, info.indicator.g, info.indicator.b);
+    let steps = if select {
+        alloc::vec![Step { color, count: 1 }, Step { color: OFF, count: 1 }]
+    } else {
+        alloc::vec![Step { color, count: 100 }]
+    };
+    let steps: &'static [Step] = Box::leak(steps.into_boxed_slice());
+    let indication = Box::leak(Box::new(Indication::new(info.name, steps)));
+    slot.store(indication, Ordering::Release);
+    indication
+}
+
 /// Artsey Nav mode
-pub static ARTSEY_NAV_INDICATOR: Indication = Indication(&[Step {
+pub static ARTSEY_NAV_INDICATOR: Indication = Indication::new("artsey-nav", &[Step {
     color: RGB8::new(20, 20, 0),
     count: 100,
 }]);
 
+/// Caps word is on, in qwerty or taipo.
+pub static CAPS_WORD_INDICATOR: Indication = Indication::new("caps-word", &[Step {
+    color: RGB8::new(16, 0, 16),
+    count: 100,
+}]);
+
+/// A beat of the practice metronome, a brief white flash.
+#[cfg(feature = "trainer")]
+pub static BEAT_INDICATOR: Indication = Indication::new("beat", &[Step {
+    color: RGB8::new(32, 32, 32),
+    count: 1,
+}]);
+
+/// A shorter outline is available for what was just written, a short dim cyan pulse.
+pub static BRIEF_INDICATOR: Indication = Indication::new("brief", &[Step {
+    color: RGB8::new(0, 16, 16),
+    count: 2,
+}]);
+
+/// Cycle through the primary colors, and white, to check the LEDs.
+pub static TEST_INDICATOR: Indication = Indication::new("test", &[
+    Step {
+        color: RGB8::new(32, 0, 0),
+        count: 5,
+    },
+    Step {
+        color: RGB8::new(0, 32, 0),
+        count: 5,
+    },
+    Step {
+        color: RGB8::new(0, 0, 32),
+        count: 5,
+    },
+    Step {
+        color: RGB8::new(32, 32, 32),
+        count: 5,
+    },
+]);
+
 pub struct LedManager {
     /// The state shared with the thread that actually updates the LEDs.
     info: Arc<InfoPair>,
@@ -240,18 +425,28 @@ pub struct LedManager {
 
     /// Override the indicator by LEDs sent from the other side.
     other_side: bool,
+
+    /// The keyboard is asleep, and the LEDs are dark.
+    asleep: bool,
+
+    /// The per-key backlight, if the board has one.
+    backlight: Option<Backlight>,
+
+    /// The colors of the backlight LEDs, shown instead of their states.  Empty when the backlight
+    /// is off.
+    key_colors: Vec<RGB8>,
 }
 
 struct LedState {
     /// The base display. Shown when there is nothing else. Will repeat
     /// indefinitely.
-    base: &'static [Step],
+    base: &'static Indication,
 
     /// An override display.  Shown instead of base, used to indicate transient status.
-    global: Option<&'static [Step]>,
+    global: Option<&'static Indication>,
 
     /// A single shot.  Runs until out of steps, and then is removed.
-    oneshot: Option<&'static [Step]>,
+    oneshot: Option<&'static Indication>,
 
     /// Information on the current display.
     count: usize,
@@ -262,8 +457,15 @@ struct LedState {
 }
 
 impl LedManager {
-    pub fn new(leds: LedSet) -> Self {
-        let len = leds.len();
+    /// Manage the LEDs, or only the first `count` of them, if given.  The others are left off.
+    pub fn new(leds: LedSet, count: Option<u8>) -> Self {
+        let mut len = leds.len();
+        if let Some(count) = count.map(|count| count as usize) {
+            if count > len {
+                warn!("Board info gives {} LEDs, but only {} were found", count, len);
+            }
+            len = len.min(count);
+        }
 
         let condvar = Condvar::new();
         let info = LedInfo { leds: None };
@@ -283,9 +485,9 @@ impl LedManager {
         let states: Vec<_> = (0..len)
             .map(|i| {
                 if i == 0 {
-                    LedState::new(UNDEF_INDICATOR.0, Some(INIT_INDICATOR.0))
+                    LedState::new(&UNDEF_INDICATOR, Some(&INIT_INDICATOR))
                 } else {
-                    LedState::new(UNDEF_INDICATOR.0, None)
+                    LedState::new(&UNDEF_INDICATOR, None)
                 }
             })
             .collect();
@@ -293,28 +495,65 @@ impl LedManager {
         LedManager {
             states,
             other_side: false,
+            asleep: false,
+            backlight: None,
+            key_colors: Vec::new(),
             info,
         }
     }
 
     pub fn tick(&mut self) {
-        // If the other side is active, just leave the LED alone.
-        if self.other_side {
+        // If the other side is active, just leave the LED alone.  Asleep, they were already
+        // turned off.
+        if self.other_side || self.asleep {
             return;
         }
 
         // TODO: Is the double iteration costly? This could use MaybeUninit, but
         // that seems overkill here.
-        let colors: Vec<_> = self.states.iter_mut().map(|st| st.tick()).collect();
+        let mut colors: Vec<_> = self.states.iter_mut().map(|st| st.tick()).collect();
+
+        if let Some(backlight) = &self.backlight {
+            let first = backlight.first_led as usize;
+            for (color, key) in colors.iter_mut().skip(first).zip(&self.key_colors) {
+                *color = *key;
+            }
+        }
 
         self.set_state(colors);
     }
 
+    /// Set where the per-key backlight LEDs are.  Starts off, until key classes are given.
+    pub fn set_backlight(&mut self, backlight: Option<Backlight>) {
+        if let Some(bl) = &backlight {
+            if bl.first_led as usize + bl.keys.len() > self.states.len() {
+                warn!("Backlight of {} LEDs at {} doesn't fit in {} LEDs",
+                      bl.keys.len(), bl.first_led, self.states.len());
+                return;
+            }
+        }
+        self.backlight = backlight;
+        self.key_colors.clear();
+    }
+
+    /// Color each backlight LED by what its key does.  Empty turns the backlight off, giving the
+    /// LEDs back to the indicators.
+    pub fn set_key_classes(&mut self, classes: &[KeyClass]) {
+        let Some(backlight) = &self.backlight else {
+            return;
+        };
+        self.key_colors = if classes.is_empty() {
+            Vec::new()
+        } else {
+            backlight.colors(classes)
+        };
+    }
+
     /// Set a global indicator. This will override any other status being
     /// displayed, and usually indicates either an error, or an initial
     /// condition. It also usually indicates that the keyboard can't be used
     /// yet.
-    pub fn set_global(&mut self, index: usize, indicator: &Indication) {
+    pub fn set_global(&mut self, index: usize, indicator: &'static Indication) {
         if let Some(st) = self.states.get_mut(index) {
             st.set_global(indicator);
         }
@@ -326,7 +565,7 @@ impl LedManager {
         }
     }
 
-    pub fn set_base(&mut self, index: usize, indicator: &Indication) {
+    pub fn set_base(&mut self, index: usize, indicator: &'static Indication) {
         if let Some(st) = self.states.get_mut(index) {
             st.set_base(indicator);
         }
@@ -342,6 +581,17 @@ impl LedManager {
         self.set_state(state);
     }
 
+    /// Turn all of the LEDs off while the keyboard sleeps, or go back to the indicators.
+    pub fn set_asleep(&mut self, asleep: bool) {
+        if self.asleep == asleep {
+            return;
+        }
+        self.asleep = asleep;
+        if asleep {
+            self.set_state(vec![OFF; self.states.len()]);
+        }
+    }
+
     /// Set the led state for the child thread.
     fn set_state(&self, leds: Vec<RGB8>) {
         let (lock, cond) = &*self.info;
@@ -350,18 +600,21 @@ impl LedManager {
         cond.notify_one();
     }
 
-    /*
-    /// Set a oneshot indicator.
-    pub fn set_oneshot(&mut self, indicator: &Indication) {
-        self.oneshot = Some(indicator.0);
-        self.count = 0;
-        self.phase = 0;
+    /// The number of LEDs being managed.
+    pub fn count(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Set a oneshot indicator.  This runs once, and then returns to whatever was being shown.
+    pub fn set_oneshot(&mut self, index: usize, indicator: &'static Indication) {
+        if let Some(st) = self.states.get_mut(index) {
+            st.set_oneshot(indicator);
+        }
     }
-    */
 }
 
 impl LedState {
-    fn new(base: &'static [Step], global: Option<&'static [Step]>) -> LedState {
+    fn new(base: &'static Indication, global: Option<&'static Indication>) -> LedState {
         LedState {
             base,
             global,
@@ -375,12 +628,18 @@ impl LedState {
     /// Perform the tick for this single LED, returning the color this LED shold
     /// be.
     fn tick(&mut self) -> RGB8 {
-        let mut steps = self.base;
+        let mut indication = self.base;
         if let Some(gl) = self.global {
-            steps = gl;
+            indication = gl;
         }
         if let Some(one) = self.oneshot {
-            steps = one;
+            indication = one;
+        }
+        let steps = indication.steps();
+
+        // Without effects, indicators are just a steady color.
+        if !cfg!(feature = "led-effects") {
+            return steps[0].color;
         }
 
         if self.count == 0 {
@@ -417,8 +676,18 @@ impl LedState {
         }
     }
 
-    fn set_global(&mut self, indicator: &Indication) {
-        self.global = Some(indicator.0);
+    fn set_oneshot(&mut self, indicator: &'static Indication) {
+        // A oneshot would never finish without effects, so just don't show them.
+        if cfg!(not(feature = "led-effects")) {
+            return;
+        }
+        self.oneshot = Some(indicator);
+        self.count = 0;
+        self.phase = 0;
+    }
+
+    fn set_global(&mut self, indicator: &'static Indication) {
+        self.global = Some(indicator);
         self.count = 0;
         self.phase = 0;
     }
@@ -431,8 +700,8 @@ impl LedState {
         }
     }
 
-    fn set_base(&mut self, indicator: &Indication) {
-        self.base = indicator.0;
+    fn set_base(&mut self, indicator: &'static Indication) {
+        self.base = indicator;
         if self.oneshot.is_none() && self.global.is_none() {
             self.count = 0;
             self.phase = 0;
@@ -457,7 +726,9 @@ struct LedInfo {
 fn led_thread(mut all_leds: LedSet, info: Arc<InfoPair>) -> ! {
     let limit = all_leds.len();
     loop {
-        let info = get_info(&*info);
+        // Any LEDs past those managed are left off.
+        let mut info = get_info(&*info);
+        info.resize(limit, OFF);
         all_leds.update(&info);
     }
 }
@@ -18,48 +18,75 @@
 
 extern crate alloc;
 
-use core::mem;
-
 use alloc::boxed::Box;
+#[cfg(not(CONFIG_JOLT_DIRECT))]
 use alloc::vec::Vec;
 use bbq_keyboard::boardinfo::BoardInfo;
+use bbq_keyboard::expand::Expander;
 use dispatch::{Dispatch, DispatchBuilder};
+use events::Events;
+use console::Console;
 use keyminder::Minder;
 use leds::manager::Indication;
 use leds::LedSet;
 use logging::Logger;
+use zephyr::device::uart::{LineControl, Uart};
 use zephyr::kio::yield_now;
-use zephyr::raw::{sys_heap, sys_heap_runtime_stats_get, sys_memory_stats};
 use zephyr::sync::channel::{Receiver, Sender};
 use zephyr::sync::{channel, Arc};
 use zephyr::sys::sync::Semaphore;
-use zephyr::time::{Duration, NoWait, Tick};
+use zephyr::time::{Duration, Forever, NoWait, Tick};
 use zephyr::work::futures::sleep;
 use zephyr::work::WorkQueueBuilder;
 
 use log::{info, warn};
 
+#[cfg(not(CONFIG_JOLT_DIRECT))]
 use matrix::Matrix;
+// The scanner works the same with either.
+#[cfg(CONFIG_JOLT_DIRECT)]
+use matrix::DirectMatrix as Matrix;
 use zephyr::{kobj_define, printkln};
 
 use bbq_keyboard::{
-    layout::LayoutManager,
-    Event, EventQueue, InterState, KeyEvent, LayoutMode, Side, Timable,
-    UsbDeviceState,
+    layout::{AutoMode, LayoutManager},
+    ser2::LinkKey,
+    power,
+    scanrate::{Activity, ScanRate},
+    usb_typer::Fallback,
+    time::{self as ktime, Clock},
+    HostEvent, KeyEvent, LayoutMode, Side, UsbDeviceState,
 };
+#[cfg(feature = "trace")]
+use bbq_keyboard::trace::TraceEvent;
 
 #[allow(unused_imports)]
 use crate::inter::{InterHandler, InterUpdate};
 use crate::leds::manager::LedManager;
 
 mod devices;
+mod console;
+mod crash;
 mod dispatch;
+#[cfg(CONFIG_JOLT_ENCODER)]
+mod encoder;
+mod engine;
+mod events;
+#[cfg(feature = "experimental")]
+mod experiments;
+mod flash;
+mod image;
 mod inter;
 mod keyminder;
 mod leds;
 mod logging;
 mod matrix;
+mod metrics;
 mod translate;
+mod wake;
+
+/// How long to wait, in ms, after shutting down, before rebooting.
+const SHUTDOWN_GRACE_MS: Tick = 100;
 
 #[no_mangle]
 extern "C" fn rust_main() {
@@ -67,8 +94,17 @@ extern "C" fn rust_main() {
 
     let logger = Logger::new();
 
-    // Initialize the main event queue.
-    let (equeue_send, equeue_recv) = channel::bounded::<Event>(32);
+    // The full report is kept for `keyminder crash-log`, but a crash is worth noting in the log.
+    if let Some(log) = crash::last() {
+        warn!("Restarted after a {}, pc {:#010x}", log.cause.name(), log.pc);
+    }
+
+    // A patched user dictionary is installed before anything loads the dictionaries.
+    #[cfg(feature = "minder-flash")]
+    flash::finish_dict_stage();
+
+    // Initialize the main loop's inputs.
+    let (events, inputs) = events::new();
 
     // The heartbeat semaphore.
     let heart = Arc::new(Semaphore::new(1, 1).unwrap());
@@ -89,18 +125,17 @@ extern "C" fn rust_main() {
     );
 
     unsafe {
-        // Store a sender for the USB callback.
-        USB_CB_MAIN_SEND = Some(equeue_send.clone());
+        // Store a sender for the USB (and BLE) callback.
+        USB_CB_MAIN_SEND = Some(events.clone());
         // Store a sender for the Heartbeat callback.
-        HEARTBEAT_MAIN_SEND = Some(equeue_send.clone());
+        HEARTBEAT_MAIN_SEND = Some(events.clone());
     }
 
     // After the callbacks have the queue handles, we can start the heartbeat.
     setup_heartbeat();
 
     // Retrieve our information.
-    let side_data =
-        (zephyr::kconfig::CONFIG_FLASH_BASE_ADDRESS + 2 * 1024 * 1024 - 256) as *const u8;
+    let side_data = flash::FLASH.address(minder::partition::BOARD_INFO.offset) as *const u8;
     let info = unsafe { BoardInfo::decode_from_memory(side_data) }.expect("Board info not present");
 
     // Retrieve the side select.
@@ -109,48 +144,143 @@ extern "C" fn rust_main() {
     let side = info.side.unwrap_or(Side::Left);
     info!("Our side: {:?}, name: {:?}", side, info.name);
 
-    // Initialize USB HID.
-    let usb = devices::usb::Usb::new().unwrap();
-
-    // Is this the best way to do this?  These aren't that big.
-    let rows = zephyr::devicetree::aliases::matrix::get_rows();
-    let cols = zephyr::devicetree::aliases::matrix::get_cols();
+    // And BLE HID, which is only used when USB isn't.
+    #[cfg(CONFIG_JOLT_BLE)]
+    let ble = match devices::ble::Ble::new() {
+        Ok(ble) => Some(ble),
+        Err(err) => {
+            warn!("BLE HID not available: {:?}", err);
+            None
+        }
+    };
 
-    // Build a Vec for these.
-    let rows: Vec<_> = rows.into_iter().map(|p| p.unwrap()).collect();
-    let cols: Vec<_> = cols.into_iter().map(|p| p.unwrap()).collect();
+    #[cfg(not(CONFIG_JOLT_DIRECT))]
+    let mut matrix = {
+        // Is this the best way to do this?  These aren't that big.
+        let rows = zephyr::devicetree::aliases::matrix::get_rows();
+        let cols = zephyr::devicetree::aliases::matrix::get_cols();
 
-    let matrix = Matrix::new(rows, cols, side);
-    let scanner = Scanner::new(matrix, equeue_send.clone(), &info);
+        // Build a Vec for these.
+        let rows: Vec<_> = rows.into_iter().map(|p| p.unwrap()).collect();
+        let cols: Vec<_> = cols.into_iter().map(|p| p.unwrap()).collect();
 
-    // TODO: When we have definable DT properties, use the DT.  For now, just match names.
-    let two_row = match info.name.as_str() {
-        "proto4" => true,
-        _ => false,
+        Matrix::new(rows, cols, side, &info)
     };
-    let layout = LayoutManager::new(two_row);
+    #[cfg(CONFIG_JOLT_DIRECT)]
+    let mut matrix = Matrix::new(side, &info);
+
+    // Initialize USB HID.  Holding a key while plugging in asks for just a boot keyboard, for
+    // hosts that can't cope with more.
+    let boot_only = info.boot_keyboard.unwrap_or(false) || matrix.any_pressed();
+    let usb = devices::usb::Usb::new(boot_only).unwrap();
+    let minder_usb = usb.clone();
+    wake::init();
+    let scanner = Scanner::new(matrix, events.clone(), &info);
+
+    let mut layout = LayoutManager::new(info.is_two_row());
+    layout.set_idle_timeout(info.idle_timeout.map(|secs| ktime::Duration::from_secs(secs as u64)));
+    if let Some(key) = info.mode_key {
+        layout.set_mode_key(key);
+    }
+    // Experiments first, so the mode chords, and settings, can name them.
+    #[cfg(feature = "experimental")]
+    {
+        if !experiments::register(&mut layout) {
+            warn!("Some experimental modes failed to register");
+        }
+        for setting in info.settings.iter().flatten() {
+            if !layout.experiment_setting(&setting.key, &setting.value) {
+                warn!("Ignoring setting {:?}", setting);
+            }
+        }
+    }
+    #[cfg(feature = "steno")]
+    if let Some(first_up) = info.steno_first_up {
+        layout.set_steno_first_up(first_up);
+    }
+    if let Some(bindings) = &info.encoder {
+        layout.set_encoder(bindings);
+    }
+    for chord in info.mode_chords.iter().flatten() {
+        let added = LayoutMode::from_name(&chord.mode)
+            .map(|mode| layout.add_mode_chord(&chord.keys, mode))
+            .unwrap_or(false);
+        if !added {
+            warn!("Ignoring mode chord {:?}", chord);
+        }
+    }
+    let mut expander = Expander::new();
+    for snippet in info.snippets.iter().flatten() {
+        if !expander.add(&snippet.trigger, &snippet.text) {
+            warn!("Ignoring snippet {:?}", snippet.trigger);
+        }
+    }
+    let auto_mode = AutoMode::new(info.auto_mode.unwrap_or(true));
 
     let leds = LedSet::get_all();
-    let leds = LedManager::new(leds);
+    let mut leds = LedManager::new(leds, info.leds);
+    leds.set_backlight(info.backlight.clone());
 
     let dispatch = DispatchBuilder {
-        equeue_send: equeue_send.clone(),
+        events: events.clone(),
         usb,
+        #[cfg(CONFIG_JOLT_BLE)]
+        ble,
         leds,
+        notify: info.notify.clone(),
+        output_rate: info.output_rate,
+        brief_led: info.brief_led,
+        fallback: info.compose_key.map(|code| Fallback::Compose(code.into())).unwrap_or_default(),
+        expander,
+        sleep_after: match info.sleep_timeout {
+            None => Some(power::SLEEP_AFTER),
+            Some(0) => None,
+            Some(secs) => Some(ktime::Duration::from_secs(secs as u64)),
+        },
     }
     .build();
 
+    #[cfg(feature = "qwerty")]
+    if let Some(keymap) = dispatch::load_keymap() {
+        if layout.set_keymap(Some(&keymap)) {
+            info!("Using stored keymap {:?}", keymap.name);
+            dispatch.use_keymap(Some(keymap));
+        } else {
+            warn!("Stored keymap {:?} is invalid", keymap.name);
+        }
+    }
+
+    #[cfg(feature = "qwerty")]
+    if let Some(macros) = dispatch::load_macros() {
+        if layout.set_macros(&macros) {
+            info!("Using {} stored macros", macros.macros.len());
+        }
+    }
+
+    #[cfg(feature = "steno")]
+    if let Some(map) = dispatch::load_steno_map() {
+        if layout.set_steno_map(Some(&map)) {
+            info!("Using stored steno map {:?}", map.name);
+            dispatch.use_steno_map(Some(map));
+        } else {
+            warn!("Stored steno map {:?} is invalid", map.name);
+        }
+    }
+
     // Queue for layout events.  These should be processed readily, so this doesn't need to be
     // large.
-    let (lm_send, lm_recv) = channel::bounded(32);
+    let (lm_send, lm_recv) = channel::bounded(metrics::LAYOUT.capacity);
+
+    // The gemini port is only used to notice a steno program on the host.
+    let gemini = zephyr::devicetree::labels::acm_uart_0::get_instance().unwrap();
 
     let _ = zephyr::kio::spawn(
-        layout_task(layout, lm_recv, dispatch.clone()),
+        layout_task(layout, auto_mode, gemini, lm_recv, dispatch.clone()),
         &dispatch.main_worker,
         c"w:layout",
     );
 
-    let (inter_task, inter) = get_inter(side, equeue_send.clone()).unzip();
+    let (inter_task, inter) = get_inter(side, events.clone(), info.link_key).unzip();
 
     /*
     let mut acm = zephyr::devicetree::labels::acm_uart_0::get_instance().unwrap();
@@ -160,20 +290,19 @@ extern "C" fn rust_main() {
 
     let minder_uart = unsafe { minder_uart.into_irq().unwrap() };
 
-    let _minder = Minder::new(minder_uart, logger);
+    let _minder = Minder::new(minder_uart, minder_usb, logger, dispatch.clone());
+
+    let console_uart = zephyr::devicetree::labels::acm_uart_2::get_instance().unwrap();
+    let console_uart = unsafe { console_uart.into_irq().unwrap() };
+    let _console = Console::new(console_uart, dispatch.clone());
 
     // TODO: We should really ask for the current mode, instead of hoping to align them.
-    let mut state = InterState::Idle;
-    // let mut suspended = true;
-    // let mut woken = false;
-    let mut has_global = true;
+    let mut engine = engine::build(dispatch.clone(), lm_send, inter);
 
     let mut heap_counter = 0;
 
-    let mut led_counter = 0;
-
     // The scanner just runs periodically to scan the matrix.
-    let _ = zephyr::kio::spawn(scanner.run(), &dispatch.main_worker, c"w:scanner");
+    let _ = zephyr::kio::spawn(scanner.run(dispatch.clone()), &dispatch.main_worker, c"w:scanner");
 
     // Startup the inter-update, if it exists.
     let _ = inter_task
@@ -201,102 +330,64 @@ extern "C" fn rust_main() {
             }
             */
 
-            let ev = equeue_recv.recv_async().await.unwrap();
-
-            let mut is_tick = false;
-            match ev {
-                Event::Tick => is_tick = true,
-                Event::Matrix(key) => {
-                    // info!("Matrix: {:?}", key);
-                    match state {
-                        InterState::Primary | InterState::Idle => {
-                            if lm_send.try_send(key).is_err() {
-                                warn!("Key event dropped {:?}", key);
-                            }
-                        }
-                        InterState::Secondary => {
-                            if let Some(inter) = &inter {
-                                if key.is_valid() {
-                                    inter.send(InterUpdate::AddKey(key)).unwrap();
-                                }
-                            }
-                        }
-                    }
-                }
-
-                Event::InterKey(key) => {
-                    if state == InterState::Primary {
-                        if lm_send.try_send(key).is_err() {
-                            warn!("Key even dropped {:?}", key);
-                        }
-                    }
-                }
-
-                Event::RawMode(raw) => {
-                    info!("Switch raw: {:?}", raw);
-                    *dispatch.raw_mode.lock().unwrap() = raw;
-                    if *dispatch.current_mode.lock().unwrap() == LayoutMode::Steno {
-                        dispatch.leds.lock()
-                            .unwrap()
-                            .set_base(0, get_steno_indicator(raw))
-                    }
-                }
+            inputs.wait().await;
 
-                // Handle the USB becoming configured.
-                Event::UsbState(UsbDeviceState::Configured)
-                | Event::UsbState(UsbDeviceState::Resume) => {
-                    if has_global {
-                        dispatch.leds.lock().unwrap().clear_global(0);
-                        has_global = false;
-                    }
-                    // suspended = false;
-                    if let Some(inter) = &inter {
-                        inter
-                            .send(InterUpdate::SetState(bbq_keyboard::InterState::Primary))
-                            .unwrap();
-                    }
-                }
-
-                Event::UsbState(UsbDeviceState::Suspend) => {
-                    dispatch.leds.lock()
-                        .unwrap()
-                        .set_global(0, &leds::manager::SLEEP_INDICATOR);
-                    has_global = true;
-                    // suspended = true;
-                    // woken = false;
-                }
-
-                Event::BecomeState(new_state) => {
-                    if state != new_state {
-                        if new_state == InterState::Secondary {
-                            dispatch.leds.lock().unwrap().clear_global(0);
-                        } else if new_state == InterState::Idle {
-                            dispatch.leds.lock().unwrap().clear_global(0);
-                        }
-                    }
-                    state = new_state;
-                }
-
-                Event::Heartbeat => {}
-
-                ev => {
-                    printkln!("Event: {:?}", ev);
+            // The host state first, so keys go where it now says.  What was waiting in each queue
+            // is counted, for the metrics.
+            let mut count = 0;
+            while let Ok(event) = inputs.host.try_recv() {
+                #[cfg(feature = "trace")]
+                dispatch.trace(event.into());
+                engine.host(event);
+                count += 1;
+            }
+            metrics::HOST.waiting(count);
+            let mut count = 0;
+            while let Ok(event) = inputs.inter.try_recv() {
+                #[cfg(feature = "trace")]
+                if let Some(event) = TraceEvent::from_inter(&event) {
+                    dispatch.trace(event);
                 }
+                engine.inter(event);
+                count += 1;
+            }
+            metrics::INTER.waiting(count);
+            let mut count = 0;
+            while let Ok(key) = inputs.keys.try_recv() {
+                #[cfg(feature = "trace")]
+                dispatch.trace(TraceEvent::Matrix(key));
+                engine.key(key);
+                count += 1;
+            }
+            metrics::KEYS.waiting(count);
+            let mut count = 0;
+            while let Ok(delta) = inputs.encoder.try_recv() {
+                engine.encoder(delta);
+                count += 1;
+            }
+            metrics::ENCODER.waiting(count);
+
+            if inputs.shutdown_requested() {
+                #[cfg(feature = "trace")]
+                dispatch.trace(TraceEvent::Shutdown);
+                dispatch.shutdown().await;
+                engine.shutdown();
+                // Give the reply to the host, and the last inter packet, time to go out.
+                sleep(Duration::millis_at_least(SHUTDOWN_GRACE_MS)).await;
+                flash::reboot();
             }
 
             yield_now().await;
 
             // Only continue when the tick is received.
-            if !is_tick {
+            if !inputs.take_tick() {
                 continue;
             }
+            engine.tick();
 
-            // Update the LEDs every 100ms.
-            led_counter += 1;
-            if led_counter >= 100 {
-                led_counter = 0;
-                dispatch.leds.lock().unwrap().tick();
-            }
+            // Save the usage stats, which only happens occasionally, and keep the tape saved.
+            dispatch.save_usage();
+            dispatch.save_tape(false);
 
             // Print out heap stats every few minutes.
             heap_counter += 1;
@@ -305,6 +396,8 @@ extern "C" fn rust_main() {
                 show_heap_stats();
             }
 
+            crash::feed_watchdog();
+
             // After we process the heartbeat, give to the semaphore so we will get the next tick.  This
             // keeps ticks from building up and only enqueues a tick if the main loop made it through
             // everything.
@@ -315,6 +408,11 @@ extern "C" fn rust_main() {
         }
     };
 
+    // From here, the main loop has to keep ticking.
+    if let Err(err) = crash::start_watchdog() {
+        warn!("Watchdog not started: {}", err);
+    }
+
     let main_loop = zephyr::kio::spawn(main_loop, &dispatch2.main_worker, c"w:main");
 
     // Wait for the main loop.  This should never happen.
@@ -330,6 +428,20 @@ fn get_steno_indicator(raw: bool) -> &'static Indication {
     }
 }
 
+/// The indicator shown while in a mode.
+fn get_mode_indicator(mode: LayoutMode, raw: bool) -> &'static Indication {
+    match mode {
+        LayoutMode::Steno => get_steno_indicator(raw),
+        LayoutMode::StenoDirect => &leds::manager::STENO_DIRECT_INDICATOR,
+        LayoutMode::Taipo => &leds::manager::TAIPO_INDICATOR,
+        LayoutMode::Qwerty => &leds::manager::QWERTY_INDICATOR,
+        LayoutMode::Numpad => &leds::manager::NUMPAD_INDICATOR,
+        #[cfg(feature = "experimental")]
+        LayoutMode::Experimental(id) => leds::manager::experiment_indicator(id, false),
+        _ => &leds::manager::QWERTY_INDICATOR,
+    }
+}
+
 // TODO: Does this move to Dispatch?
 fn get_steno_select_indicator(raw: bool) -> &'static Indication {
     if raw {
@@ -354,18 +466,74 @@ fn get_steno_select_indicator(raw: bool) -> &'static Indication {
 async fn layout_task(
     // The layout manager to manage.
     mut layout: LayoutManager,
+    // Automatic mode selection.
+    mut auto_mode: AutoMode,
+    // The gemini uart, whose DTR indicates a steno program on the host.
+    mut gemini: Uart,
     // A receiver for the queue that processes layout events.
     keys: Receiver<KeyEvent>,
     // The dispatcher, for sending events to.
     dispatch: Arc<Dispatch>,
 ) {
-    const PERIOD_MS: usize = 10;
+    const PERIOD_MS: u64 = 10;
+    const PERIOD: ktime::Duration = ktime::Duration::from_millis(PERIOD_MS);
+    // Time not yet added to the usage stats, which are sampled less often when saving power.
+    let mut unsampled = ktime::Duration::ZERO;
     zephyr::event_loop!(keys, Duration::millis_at_least(PERIOD_MS as Tick),
                         Some(ev) => {
+                            metrics::LAYOUT.taken();
                             layout.handle_event(ev, dispatch.as_ref()).await;
                         },
                         None => {
-                            layout.tick(dispatch.as_ref(), PERIOD_MS).await;
+                            let dtr = matches!(unsafe { gemini.line_ctrl_get(LineControl::DTR) }, Ok(1));
+                            let steno_host = dtr || dispatch.plover_open();
+                            if let Some(mode) = auto_mode.update(steno_host, PERIOD, layout.is_two_row()) {
+                                info!("Host activity, switching to {:?}", mode);
+                                layout.request_mode(mode);
+                            }
+                            if let Some(mode) = dispatch.take_requested_mode() {
+                                layout.request_mode(mode);
+                            }
+                            layout.handle_encoder(dispatch.take_encoder(), dispatch.as_ref()).await;
+                            #[cfg(feature = "qwerty")]
+                            if let Some(stored) = dispatch.take_requested_keymap() {
+                                let keymap = if stored { dispatch::load_keymap() } else { None };
+                                if stored && keymap.is_none() {
+                                    warn!("No stored keymap");
+                                } else if layout.set_keymap(keymap.as_ref()) {
+                                    dispatch.use_keymap(keymap);
+                                } else {
+                                    warn!("Stored keymap is invalid");
+                                }
+                            }
+                            #[cfg(all(feature = "qwerty", feature = "minder-flash"))]
+                            if dispatch.take_requested_macros() {
+                                // Anything being recorded is lost, rather than saved over these.
+                                layout.set_macros(&dispatch::load_macros().unwrap_or_default());
+                            }
+                            #[cfg(feature = "qwerty")]
+                            if let Some(overlay) = dispatch.take_requested_overlay() {
+                                layout.set_overlay(overlay.as_deref());
+                            }
+                            #[cfg(feature = "steno")]
+                            if let Some(stored) = dispatch.take_requested_steno_map() {
+                                let map = if stored { dispatch::load_steno_map() } else { None };
+                                if stored && map.is_none() {
+                                    warn!("No stored steno map");
+                                } else if layout.set_steno_map(map.as_ref()) {
+                                    dispatch.use_steno_map(map);
+                                } else {
+                                    warn!("Stored steno map is invalid");
+                                }
+                            }
+                            layout.tick(dispatch.as_ref(), PERIOD).await;
+                            unsampled += PERIOD;
+                            if unsampled >= dispatch.stats_period() {
+                                dispatch.add_usage_time(unsampled);
+                                unsampled = ktime::Duration::ZERO;
+                            }
+                            #[cfg(feature = "trainer")]
+                            dispatch.pace_tick(PERIOD);
                         },
     );
 }
@@ -374,14 +542,15 @@ async fn layout_task(
 #[cfg(dt = "chosen::inter_board_uart")]
 fn get_inter(
     side: Side,
-    equeue_send: Sender<Event>,
+    events: Events,
+    key: Option<LinkKey>,
 ) -> Option<(InterHandler, Sender<InterUpdate>)> {
     let uart = zephyr::devicetree::chosen::inter_board_uart::get_instance().unwrap();
-    Some(InterHandler::new(side, uart, equeue_send))
+    Some(InterHandler::new(side, uart, events, key))
 }
 
 #[cfg(not(dt = "chosen::inter_board_uart"))]
-fn get_inter(_side: Side, _equeue_send: Sender<Event>) -> Option<InterHandler> {
+fn get_inter(_side: Side, _events: Events, _key: Option<LinkKey>) -> Option<InterHandler> {
     None
 }
 
@@ -391,62 +560,98 @@ fn get_inter(_side: Side, _equeue_send: Sender<Event>) -> Option<InterHandler> {
 /// other side.
 struct Scanner {
     matrix: Matrix,
-    events: Sender<Event>,
-    translate: fn(u8) -> u8,
+    #[cfg(CONFIG_JOLT_ENCODER)]
+    encoder: encoder::Encoder,
+    events: Events,
+    translate: translate::Translation,
 }
 
 impl Scanner {
-    fn new(matrix: Matrix, events: Sender<Event>, info: &BoardInfo) -> Scanner {
-        let translate = translate::get_translation(&info.name);
+    fn new(matrix: Matrix, events: Events, info: &BoardInfo) -> Scanner {
+        let translate = translate::get_translation(info);
         Scanner {
             matrix,
+            #[cfg(CONFIG_JOLT_ENCODER)]
+            encoder: encoder::Encoder::new(info),
             events,
             translate,
         }
     }
 
-    fn scan(&mut self) {
-        self.matrix.scan(|code, press| {
-            let code = (self.translate)(code);
+    fn scan(&mut self, elapsed: ktime::Duration) -> Activity {
+        #[allow(unused_mut)]
+        let mut activity = self.matrix.scan(elapsed, |code, press| {
+            let code = self.translate.get(code);
             let event = if press {
                 KeyEvent::Press(code)
             } else {
                 KeyEvent::Release(code)
             };
-            self.events.send(Event::Matrix(event)).unwrap();
+            self.events.key(event);
         });
+
+        // A turning knob needs the fast scan rate, to not miss steps.
+        #[cfg(CONFIG_JOLT_ENCODER)]
+        {
+            let delta = self.encoder.scan();
+            if delta != 0 {
+                self.events.encoder(delta);
+            }
+            if delta != 0 || self.encoder.is_moving() {
+                activity = Activity::Transition;
+            }
+        }
+        activity
     }
 
-    async fn run(mut self) {
+    async fn run(mut self, dispatch: Arc<Dispatch>) {
+        let mut rate = ScanRate::new();
+        let mut last = SysClock.now();
         loop {
+            rate.set_saving(!dispatch.scan_boost());
+            let period = rate.interval();
             // TODO: Use an absolute timer here.
-            sleep(Duration::millis_at_least(1)).await;
+            let interval = Duration::micros_at_least(period.as_micros() as Tick);
+            // Dormant, a key press interrupts the wait, to be scanned right away.
+            if !(rate.is_dormant() && wake::wait_for_key(interval).await) {
+                sleep(interval).await;
+            }
+
+            let start = SysClock.now();
+            let elapsed = start - last;
+            last = start;
 
-            self.scan();
+            let activity = self.scan(elapsed);
+            let took = SysClock.now() - start;
+            rate.update(activity, elapsed, took);
+            metrics::scan(took, elapsed.saturating_sub(period));
+
+            // Asleep, with nothing held, wait for a key instead of scanning.  The time asleep
+            // isn't debounce time, so the next scan starts counting afresh.
+            if activity == Activity::Idle && dispatch.is_asleep() {
+                wake::wait_for_key(Forever).await;
+                last = SysClock.now();
+            }
         }
     }
 }
 
-struct WrapTimer;
+/// The system clock, as seen by the shared code.
+pub struct SysClock;
 
-impl Timable for WrapTimer {
-    fn get_ticks(&self) -> u64 {
+impl Clock for SysClock {
+    fn ticks(&self) -> u64 {
         unsafe { zephyr::raw::k_cycle_get_64() }
     }
-}
 
-/// A wrapper around a Sender to implement the EventQueue trait.
-struct SendWrap(Sender<Event>);
-
-impl EventQueue for SendWrap {
-    fn push(&mut self, val: Event) {
-        self.0.send(val).unwrap();
+    fn tick_hz(&self) -> u64 {
+        zephyr::kconfig::CONFIG_SYS_CLOCK_HW_CYCLES_PER_SEC as u64
     }
 }
 
-/// Event queue sender for main queue.  Written once during init, and should be safe to just
-/// directly use.
-static mut USB_CB_MAIN_SEND: Option<Sender<Event>> = None;
+/// The main loop's inputs, for the USB callback.  Written once during init, and should be safe to
+/// just directly use.
+static mut USB_CB_MAIN_SEND: Option<Events> = None;
 
 /// Rust USB callback.
 pub fn rust_usb_status(state: u32) {
@@ -458,11 +663,18 @@ pub fn rust_usb_status(state: u32) {
         2 => UsbDeviceState::Resume,
         _ => unreachable!(),
     };
-    send.send(Event::UsbState(state)).unwrap();
+    send.host(HostEvent::Usb(state));
+}
+
+/// Rust BLE callback.
+#[cfg(CONFIG_JOLT_BLE)]
+pub fn rust_ble_status(connected: bool) {
+    let send = unsafe { USB_CB_MAIN_SEND.as_mut().unwrap() };
+    send.host(HostEvent::Ble(connected));
 }
 
 /// A reference into the main event loop for the heartbeat irq to use.
-static mut HEARTBEAT_MAIN_SEND: Option<Sender<Event>> = None;
+static mut HEARTBEAT_MAIN_SEND: Option<Events> = None;
 
 /// A semaphore so sync the heartbeat with the processing.
 static mut HEARTBEAT_SEM: Option<Arc<Semaphore>> = None;
@@ -478,7 +690,7 @@ extern "C" fn rust_heartbeat() {
         return;
     }
 
-    let _ = send.try_send(Event::Tick);
+    send.tick();
 }
 
 /// Initialize the heartbeat.
@@ -494,24 +706,10 @@ fn setup_heartbeat() {
 
 /// Show heap stats.
 fn show_heap_stats() {
-    unsafe {
-        extern "C" {
-            static mut z_malloc_heap: sys_heap;
-        }
-
-        let mut stats: sys_memory_stats = mem::zeroed();
-        match sys_heap_runtime_stats_get(&mut z_malloc_heap, &mut stats) {
-            0 => (),
-            n => {
-                warn!("Unable to collect heap stats: {}", n);
-                return;
-            }
-        }
-
-        info!("Heap free: {}", stats.free_bytes);
-        info!("    alloc: {}", stats.allocated_bytes);
-        info!("max alloc: {}", stats.max_allocated_bytes);
-
+    if let Some(heap) = metrics::heap() {
+        info!("Heap free: {}", heap.free);
+        info!("    alloc: {}", heap.used);
+        info!("max alloc: {}", heap.peak);
     }
 }
 
@@ -94,6 +94,9 @@ impl Log for LogWrapper {
     fn log(&self, record: &log::Record) {
         let message = format!("{}:{}: {}", record.level(), record.target(), record.args());
 
+        // Kept for the crash report, in case this is the last thing before a crash.
+        crate::crash::note(&message);
+
         // TODO: Record dropped messages.
 
         let mut inner = self.0.lock().unwrap();
@@ -1,17 +1,25 @@
 //! Matrix keyboard support
 //!
-//! Supports a keyboard connected via a pin matrix.
+//! Supports a keyboard connected via a pin matrix, or, with `CONFIG_JOLT_DIRECT`, one with each
+//! key wired to its own GPIO.  Both give the same scan codes and events, so the rest of the
+//! firmware doesn't need to know which a board has.
 
 extern crate alloc;
 
 use alloc::vec::Vec;
 
+#[cfg(not(CONFIG_JOLT_DIRECT))]
+use log::warn;
+#[cfg(not(CONFIG_JOLT_DIRECT))]
 use zephyr::device::gpio::{GpioPin, GpioToken};
+#[cfg(not(CONFIG_JOLT_DIRECT))]
 use zephyr::raw::{GPIO_INPUT, GPIO_OUTPUT_INACTIVE, GPIO_PULL_DOWN};
+#[cfg(not(CONFIG_JOLT_DIRECT))]
 use zephyr::sys::busy_wait;
 
-use bbq_keyboard::Side;
+use bbq_keyboard::{boardinfo::BoardInfo, debounce::Debouncer, scanrate::Activity, time::Duration, Side};
 
+#[cfg(not(CONFIG_JOLT_DIRECT))]
 pub struct Matrix {
     token: GpioToken,
     rows: Vec<GpioPin>,
@@ -20,11 +28,16 @@ pub struct Matrix {
     side: Side,
 }
 
+#[cfg(not(CONFIG_JOLT_DIRECT))]
 impl Matrix {
-    pub fn new(rows: Vec<GpioPin>, cols: Vec<GpioPin>, side: Side) -> Matrix {
-        let state = (0..rows.len() * cols.len())
-            .map(|_| Debouncer::new())
-            .collect();
+    /// The debouncing of each key comes from the board info, by its scan code.  The board info
+    /// can also limit the rows and columns scanned to fewer than the devicetree gives.
+    pub fn new(mut rows: Vec<GpioPin>, mut cols: Vec<GpioPin>, side: Side, info: &BoardInfo) -> Matrix {
+        limit(&mut rows, info.rows, "rows");
+        limit(&mut cols, info.cols, "columns");
+        let count = rows.len() * cols.len();
+        let bias = if side.is_left() { 0 } else { count };
+        let state = (0..count).map(|code| info.debouncer((code + bias) as u8)).collect();
         let token = unsafe { GpioToken::get_instance().unwrap() };
         let mut result = Matrix {
             token,
@@ -38,10 +51,13 @@ impl Matrix {
     }
 
     /// Perform a single scan of the matrix, calling `act` for every key that changes.
-    pub fn scan<F>(&mut self, mut act: F)
+    /// `elapsed` is the time since the previous scan, used for debouncing.  Returns what the scan
+    /// saw, so the scan rate can be adjusted.
+    pub fn scan<F>(&mut self, elapsed: Duration, mut act: F) -> Activity
     where
         F: FnMut(u8, bool),
     {
+        let mut activity = Activity::Idle;
         let bias = if self.side.is_left() {
             0
         } else {
@@ -57,21 +73,36 @@ impl Matrix {
             }
             for row in &mut self.rows {
                 let (code, state) = states.next().unwrap();
-                match state.react(unsafe { row.get(&mut self.token) }) {
-                    KeyAction::Press => {
-                        act((code + bias) as u8, true);
-                    }
-                    KeyAction::Release => {
-                        act((code + bias) as u8, false);
-                    }
-                    _ => (),
+                if let Some(pressed) = state.react(unsafe { row.get(&mut self.token) }, elapsed) {
+                    act((code + bias) as u8, pressed);
                 }
+                track_activity(&mut activity, state);
             }
             unsafe {
                 col.set(&mut self.token, false);
             }
             // busy_wait(5);
         }
+        activity
+    }
+
+    /// Is any key held down right now?  This reads the matrix directly, without debouncing, to
+    /// check for keys held while plugging in.
+    pub fn any_pressed(&mut self) -> bool {
+        let mut pressed = false;
+        for col in &mut self.cols {
+            unsafe {
+                col.set(&mut self.token, true);
+                busy_wait(5);
+            }
+            for row in &mut self.rows {
+                pressed |= unsafe { row.get(&mut self.token) };
+            }
+            unsafe {
+                col.set(&mut self.token, false);
+            }
+        }
+        pressed
     }
 
     /// Setup the gpios to drive from 'push' and read from 'pull'.
@@ -92,69 +123,76 @@ impl Matrix {
     }
 }
 
-/// The state of an individual key.
-#[derive(Clone, Copy, Eq, PartialEq)]
-enum KeyState {
-    /// Key is stable with the given pressed state.
-    Stable(bool),
-    /// We've detected the start of a transition to the dest, but need to see it stable before
-    /// considering it done.
-    Debounce(bool),
+/// Only use as many of the pins as the board info gives.
+#[cfg(not(CONFIG_JOLT_DIRECT))]
+fn limit(pins: &mut Vec<GpioPin>, count: Option<u8>, what: &str) {
+    let Some(count) = count.map(|count| count as usize) else {
+        return;
+    };
+    if count > pins.len() {
+        warn!("Board info gives {} {}, but the devicetree only has {}", count, what, pins.len());
+    }
+    pins.truncate(count);
 }
 
-/// The action keys undergo.
-#[derive(Clone, Copy)]
-enum KeyAction {
-    None,
-    Press,
-    Release,
+/// Keys each wired to their own GPIO, which are given in the devicetree, and read by `direct.c`.
+#[cfg(CONFIG_JOLT_DIRECT)]
+pub struct DirectMatrix {
+    state: Vec<Debouncer>,
+    side: Side,
 }
 
-struct Debouncer {
-    /// State for this key.
-    state: KeyState,
-    /// Count how many times we've seen a given debounce state.
-    counter: usize,
+#[cfg(CONFIG_JOLT_DIRECT)]
+extern "C" {
+    fn bbq_direct_init() -> core::ffi::c_int;
+    fn bbq_direct_get(index: usize) -> bool;
 }
 
-const DEBOUNCE_COUNT: usize = 20;
-
-impl Debouncer {
-    fn new() -> Debouncer {
-        Debouncer {
-            state: KeyState::Stable(false),
-            counter: 0,
+#[cfg(CONFIG_JOLT_DIRECT)]
+impl DirectMatrix {
+    /// The debouncing of each key comes from the board info, by its scan code.
+    pub fn new(side: Side, info: &BoardInfo) -> DirectMatrix {
+        let count = unsafe { bbq_direct_init() };
+        if count < 0 {
+            panic!("Direct keys not available: {}", count);
         }
+        let count = count as usize;
+        let bias = if side.is_left() { 0 } else { count };
+        let state = (0..count).map(|code| info.debouncer((code + bias) as u8)).collect();
+        DirectMatrix { state, side }
     }
 
-    fn react(&mut self, pressed: bool) -> KeyAction {
-        match self.state {
-            KeyState::Stable(cur) => {
-                if cur != pressed {
-                    self.state = KeyState::Debounce(pressed);
-                    self.counter = 0;
-                }
-                KeyAction::None
-            }
-            KeyState::Debounce(target) => {
-                if target != pressed {
-                    // Reset the counter any time the state isn't our goal.
-                    self.counter = 0;
-                    KeyAction::None
-                } else {
-                    self.counter += 1;
-                    if self.counter == DEBOUNCE_COUNT {
-                        self.state = KeyState::Stable(target);
-                        if target {
-                            KeyAction::Press
-                        } else {
-                            KeyAction::Release
-                        }
-                    } else {
-                        KeyAction::None
-                    }
-                }
+    /// Read every key, calling `act` for every key that changes, as with a matrix.
+    pub fn scan<F>(&mut self, elapsed: Duration, mut act: F) -> Activity
+    where
+        F: FnMut(u8, bool),
+    {
+        let mut activity = Activity::Idle;
+        let bias = if self.side.is_left() {
+            0
+        } else {
+            self.state.len()
+        };
+        for (code, state) in self.state.iter_mut().enumerate() {
+            if let Some(pressed) = state.react(unsafe { bbq_direct_get(code) }, elapsed) {
+                act((code + bias) as u8, pressed);
             }
+            track_activity(&mut activity, state);
         }
+        activity
+    }
+
+    /// Is any key held down right now, without debouncing.
+    pub fn any_pressed(&mut self) -> bool {
+        (0..self.state.len()).any(|code| unsafe { bbq_direct_get(code) })
+    }
+}
+
+/// Update what a scan has seen so far with a key.
+fn track_activity(activity: &mut Activity, state: &Debouncer) {
+    if state.is_changing() {
+        *activity = Activity::Transition;
+    } else if state.is_pressed() && *activity == Activity::Idle {
+        *activity = Activity::Held;
     }
 }
@@ -0,0 +1,177 @@
+//! Runtime metrics, for keyminder's stats.
+//!
+//! These are gathered as the firmware runs, so that a keyboard that is slow, or drops keys, can be
+//! looked into from the host, with `keyminder stats`.
+
+use core::mem;
+use core::sync::atomic::Ordering;
+
+use alloc::string::ToString;
+
+use bbq_keyboard::time::{Clock, Duration};
+use log::warn;
+use minder::{QueueStats, RuntimeStats};
+use zephyr::{
+    raw::{sys_heap, sys_heap_runtime_stats_get, sys_memory_stats},
+    sync::atomic::AtomicU32,
+};
+
+use crate::SysClock;
+
+/// One of the event queues, and the most events seen waiting in it.
+pub struct Queue {
+    name: &'static str,
+    /// The events the queue holds, which it is created with.
+    pub capacity: usize,
+    sent: AtomicU32,
+    taken: AtomicU32,
+    high: AtomicU32,
+}
+
+impl Queue {
+    const fn new(name: &'static str, capacity: usize) -> Queue {
+        Queue {
+            name,
+            capacity,
+            sent: AtomicU32::new(0),
+            taken: AtomicU32::new(0),
+            high: AtomicU32::new(0),
+        }
+    }
+
+    /// The receiver found this many events waiting.  For queues the receiver drains all at once.
+    pub fn waiting(&self, count: u32) {
+        self.high.fetch_max(count, Ordering::Relaxed);
+    }
+
+    /// An event was sent.  For queues whose receiver takes one event at a time, and calls
+    /// [`Queue::taken`] for each.
+    pub fn sent(&self) {
+        let sent = self.sent.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        self.waiting(sent.wrapping_sub(self.taken.load(Ordering::Relaxed)));
+    }
+
+    /// An event was taken from the queue.
+    pub fn taken(&self) {
+        self.taken.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            name: self.name.to_string(),
+            capacity: self.capacity as u32,
+            high_water: self.high.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The main loop's inputs (see [`crate::events`]).
+pub static KEYS: Queue = Queue::new("keys", 32);
+pub static ENCODER: Queue = Queue::new("encoder", 8);
+pub static INTER: Queue = Queue::new("inter", 32);
+pub static HOST: Queue = Queue::new("host", 8);
+
+/// Keys from the main loop to the layout task.
+pub static LAYOUT: Queue = Queue::new("layout", 32);
+
+static QUEUES: [&Queue; 5] = [&KEYS, &ENCODER, &INTER, &HOST, &LAYOUT];
+
+static DROPPED_KEYS: AtomicU32 = AtomicU32::new(0);
+static SCAN_US: AtomicU32 = AtomicU32::new(0);
+static SCAN_MAX_US: AtomicU32 = AtomicU32::new(0);
+static SCAN_LATE_MAX_US: AtomicU32 = AtomicU32::new(0);
+static STROKES: AtomicU32 = AtomicU32::new(0);
+static STROKE_US: AtomicU32 = AtomicU32::new(0);
+static STROKE_MAX_US: AtomicU32 = AtomicU32::new(0);
+static STROKE_MEAN_US: AtomicU32 = AtomicU32::new(0);
+
+/// A key was dropped, as the layout's queue was full.
+pub fn key_dropped() {
+    DROPPED_KEYS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A pass of the scan loop took `took`, having woken `late` past its interval.
+pub fn scan(took: Duration, late: Duration) {
+    let took = micros(took);
+    SCAN_US.store(took, Ordering::Relaxed);
+    SCAN_MAX_US.fetch_max(took, Ordering::Relaxed);
+    SCAN_LATE_MAX_US.fetch_max(micros(late), Ordering::Relaxed);
+}
+
+/// The time steno strokes take, kept by the steno thread, which publishes the figures for
+/// [`runtime`].
+#[derive(Default)]
+pub struct StrokeTimes {
+    count: u32,
+    total_us: u64,
+    max_us: u32,
+}
+
+impl StrokeTimes {
+    /// A stroke took `took` from being completed to its translation being passed on to be typed.
+    pub fn add(&mut self, took: Duration) {
+        let took = micros(took);
+        self.count = self.count.wrapping_add(1);
+        self.total_us += took as u64;
+        self.max_us = self.max_us.max(took);
+        STROKES.store(self.count, Ordering::Relaxed);
+        STROKE_US.store(took, Ordering::Relaxed);
+        STROKE_MAX_US.store(self.max_us, Ordering::Relaxed);
+        STROKE_MEAN_US.store((self.total_us / self.count.max(1) as u64) as u32, Ordering::Relaxed);
+    }
+}
+
+fn micros(time: Duration) -> u32 {
+    time.as_micros().min(u32::MAX as u64) as u32
+}
+
+/// The state of the heap.
+pub struct Heap {
+    pub used: u32,
+    pub free: u32,
+    pub peak: u32,
+}
+
+/// Query the heap.  None, with a warning, if Zephyr can't say.
+pub fn heap() -> Option<Heap> {
+    unsafe {
+        extern "C" {
+            static mut z_malloc_heap: sys_heap;
+        }
+
+        let mut stats: sys_memory_stats = mem::zeroed();
+        match sys_heap_runtime_stats_get(&mut z_malloc_heap, &mut stats) {
+            0 => (),
+            n => {
+                warn!("Unable to collect heap stats: {}", n);
+                return None;
+            }
+        }
+
+        Some(Heap {
+            used: stats.allocated_bytes as u32,
+            free: stats.free_bytes as u32,
+            peak: stats.max_allocated_bytes as u32,
+        })
+    }
+}
+
+/// Everything, for [`minder::message::Stats::Runtime`].
+pub fn runtime() -> RuntimeStats {
+    let heap = heap().unwrap_or(Heap { used: 0, free: 0, peak: 0 });
+    RuntimeStats {
+        uptime: SysClock.millis(),
+        heap_used: heap.used,
+        heap_free: heap.free,
+        heap_peak: heap.peak,
+        queues: QUEUES.iter().map(|queue| queue.stats()).collect(),
+        dropped_keys: DROPPED_KEYS.load(Ordering::Relaxed),
+        scan_us: SCAN_US.load(Ordering::Relaxed),
+        scan_max_us: SCAN_MAX_US.load(Ordering::Relaxed),
+        scan_late_max_us: SCAN_LATE_MAX_US.load(Ordering::Relaxed),
+        strokes: STROKES.load(Ordering::Relaxed),
+        stroke_us: STROKE_US.load(Ordering::Relaxed),
+        stroke_max_us: STROKE_MAX_US.load(Ordering::Relaxed),
+        stroke_mean_us: STROKE_MEAN_US.load(Ordering::Relaxed),
+    }
+}
@@ -2,17 +2,44 @@
 //!
 //! The bbq-keyboard scancodes are based on the "proto3" keyboard, which is
 //! the largest keyboard I've built.  Other boards may have fewer keys, or
-//! different scancodes.  This module provides a translation for scancodes
-//! that is based on a Kconfig value.
+//! different scancodes.  This module provides a translation for scancodes,
+//! from the table in the board info, or, for board info without one, built
+//! in for the board's name.
 
-pub fn get_translation(board: &str) -> fn(u8) -> u8 {
-    match board {
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use bbq_keyboard::boardinfo::BoardInfo;
+
+/// How scan codes from the matrix are translated.
+pub enum Translation {
+    /// Built in for a board.
+    Builtin(fn(u8) -> u8),
+    /// Given in the board info, see [`BoardInfo::translate`].
+    Table(Vec<u8>),
+}
+
+impl Translation {
+    pub fn get(&self, code: u8) -> u8 {
+        match self {
+            Translation::Builtin(xlate) => xlate(code),
+            Translation::Table(table) => *table.get(code as usize).unwrap_or(&255),
+        }
+    }
+}
+
+pub fn get_translation(info: &BoardInfo) -> Translation {
+    if let Some(table) = &info.translate {
+        return Translation::Table(table.clone());
+    }
+    Translation::Builtin(match info.name.as_str() {
         "proto3" => id,
         "proto4" => proto4,
         "jolt1" => id,
         "jolt2" => jolt2,
-        xlate => panic!("Unsupported translation table {:?}", xlate),
-    }
+        xlate => panic!("Unsupported translation table {:?}, and none in the board info", xlate),
+    })
 }
 
 fn id(code: u8) -> u8 {
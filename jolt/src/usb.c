@@ -6,8 +6,8 @@
 #include <zephyr/kernel.h>
 #include <zephyr/usb/class/usb_hid.h>
 
-// Use a basic keyboard HID report for boot mode.  As long as we aren't doing
-// NKRO, this should be adequate.
+// Use a basic keyboard HID report when only the boot keyboard is offered.  Otherwise, the NKRO
+// descriptor comes from bbq-keyboard.
 static const uint8_t hid_kbd_report_desc[] = HID_KEYBOARD_REPORT_DESC();
 
 // Return this to the Rust world.
@@ -22,3 +22,19 @@ struct u8_vec hid_get_kbd_desc(void) {
 		.len = sizeof(hid_kbd_report_desc),
 		});
 }
+
+// A three button mouse, the report is the buttons, then x, y, and wheel.
+static const uint8_t hid_mouse_report_desc[] = HID_MOUSE_REPORT_DESC(3);
+
+struct u8_vec hid_get_mouse_desc(void) {
+	return ((struct u8_vec){
+		.base = hid_mouse_report_desc,
+		.len = sizeof(hid_mouse_report_desc),
+		});
+}
+
+// Mark the interface as a boot keyboard, so a BIOS will use it.  This has to be done before
+// usb_hid_init.
+int hid_set_boot_keyboard(const struct device *dev) {
+	return usb_hid_set_proto_code(dev, HID_BOOT_IFACE_CODE_KEYBOARD);
+}
@@ -0,0 +1,96 @@
+// Waking on a key press.
+//
+// Asleep, or between dormant scans, the keys aren't scanned.  Instead, every column of the matrix
+// is driven, so that any key pulls its row active, and the rows interrupt.  With direct keys, each key's pin interrupts.  The
+// interrupt wakes the scanner in wake.rs, which takes over from there.
+
+#include <errno.h>
+#include <zephyr/kernel.h>
+#include <zephyr/drivers/gpio.h>
+
+extern void rust_key_wake(void);
+
+#ifdef CONFIG_JOLT_DIRECT
+
+#define DT_DRV_COMPAT bbq_kbd_direct
+
+static const struct gpio_dt_spec inputs[] = {
+	DT_INST_FOREACH_PROP_ELEM_SEP(0, key_gpios, GPIO_DT_SPEC_GET_BY_IDX, (,))
+};
+
+#else
+
+#define MATRIX DT_ALIAS(matrix)
+
+static const struct gpio_dt_spec inputs[] = {
+	DT_FOREACH_PROP_ELEM_SEP(MATRIX, row_gpios, GPIO_DT_SPEC_GET_BY_IDX, (,))
+};
+
+static const struct gpio_dt_spec outputs[] = {
+	DT_FOREACH_PROP_ELEM_SEP(MATRIX, col_gpios, GPIO_DT_SPEC_GET_BY_IDX, (,))
+};
+
+#endif
+
+static struct gpio_callback callbacks[ARRAY_SIZE(inputs)];
+static bool callbacks_added;
+
+static void disable_all(void) {
+	for (size_t i = 0; i < ARRAY_SIZE(inputs); i++) {
+		gpio_pin_interrupt_configure_dt(&inputs[i], GPIO_INT_DISABLE);
+	}
+}
+
+// The interrupts are level triggered, so they are turned off before anything else, to not fire
+// again for as long as the key is held.
+static void key_wake(const struct device *port, struct gpio_callback *cb, gpio_port_pins_t pins) {
+	ARG_UNUSED(port);
+	ARG_UNUSED(cb);
+	ARG_UNUSED(pins);
+
+	disable_all();
+	rust_key_wake();
+}
+
+// Go back to scanning: the interrupts off, and the columns idle.
+void bbq_wake_disarm(void) {
+	disable_all();
+#ifndef CONFIG_JOLT_DIRECT
+	for (size_t i = 0; i < ARRAY_SIZE(outputs); i++) {
+		gpio_pin_set_dt(&outputs[i], 0);
+	}
+#endif
+}
+
+// Arrange for a key press to call `rust_key_wake`, returning 0, or a negative error code, in which
+// case nothing is armed.  This should only be called with no keys down.
+int bbq_wake_arm(void) {
+	int ret;
+
+#ifndef CONFIG_JOLT_DIRECT
+	for (size_t i = 0; i < ARRAY_SIZE(outputs); i++) {
+		gpio_pin_set_dt(&outputs[i], 1);
+	}
+#endif
+
+	for (size_t i = 0; i < ARRAY_SIZE(inputs); i++) {
+		if (!callbacks_added) {
+			gpio_init_callback(&callbacks[i], key_wake, BIT(inputs[i].pin));
+			ret = gpio_add_callback_dt(&inputs[i], &callbacks[i]);
+			if (ret < 0) {
+				bbq_wake_disarm();
+				return ret;
+			}
+		}
+	}
+	callbacks_added = true;
+
+	for (size_t i = 0; i < ARRAY_SIZE(inputs); i++) {
+		ret = gpio_pin_interrupt_configure_dt(&inputs[i], GPIO_INT_LEVEL_ACTIVE);
+		if (ret < 0) {
+			bbq_wake_disarm();
+			return ret;
+		}
+	}
+	return 0;
+}
@@ -0,0 +1,66 @@
+//! Waking on a key.
+//!
+//! Asleep, the keys aren't scanned.  `wake.c` arms an interrupt on the key pins instead, and the
+//! scanner waits here until it fires.  Turning the encoder doesn't wake the keyboard, as its pins
+//! aren't watched.
+//!
+//! When the scan rate is dormant, the scanner waits here between scans too, so that the first
+//! press is scanned right away, rather than up to a dormant interval later.
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::ffi::c_int;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use log::warn;
+use zephyr::sys::sync::Semaphore;
+use zephyr::time::{NoWait, Timeout};
+
+extern "C" {
+    fn bbq_wake_arm() -> c_int;
+    fn bbq_wake_disarm();
+}
+
+/// Given by the key interrupt.
+static mut WAKE_SEM: Option<Arc<Semaphore>> = None;
+
+/// Cleared if the interrupt can't be armed, which won't get better by trying again.
+static ARMABLE: AtomicBool = AtomicBool::new(true);
+
+/// Set up the wake semaphore, before the scanner starts.
+pub fn init() {
+    unsafe {
+        WAKE_SEM = Some(Arc::new(Semaphore::new(0, 1).unwrap()));
+    }
+}
+
+/// Wait for a key to be pressed, or the timeout.  This should only be called with no keys down.
+/// Returns false, without waiting, if the interrupt can't be armed, for the caller to wait as it
+/// would have otherwise.
+pub async fn wait_for_key<T: Into<Timeout>>(timeout: T) -> bool {
+    if !ARMABLE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let sem = unsafe { WAKE_SEM.as_ref().unwrap() };
+
+    // A wake left from before isn't for this wait.
+    let _ = sem.take(NoWait);
+
+    let ret = unsafe { bbq_wake_arm() };
+    if ret < 0 {
+        warn!("Unable to arm key wake, scanning instead: {}", ret);
+        ARMABLE.store(false, Ordering::Relaxed);
+        return false;
+    }
+    let _ = sem.take_async(timeout).await;
+    unsafe { bbq_wake_disarm() };
+    true
+}
+
+#[no_mangle]
+extern "C" fn rust_key_wake() {
+    if let Some(sem) = unsafe { WAKE_SEM.as_ref() } {
+        sem.give();
+    }
+}
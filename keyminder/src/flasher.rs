@@ -0,0 +1,168 @@
+//! Programming flash over minder.
+//!
+//! Images are written a sector at a time.  Before writing anything, each sector of the image is
+//! hashed on the device and compared with the local image, and only the sectors that differ are
+//! programmed.  Each programmed sector is hashed again to verify it.
+//!
+//! Because the comparison is against what is actually in flash, an interrupted transfer can be
+//! resumed by just running it again: the sectors that made it are skipped.
+
+use anyhow::{anyhow, Result};
+use minder::{message::{Core, Flash, Message}, partition::{Partition, SECTOR_SIZE}, HashAlgorithm};
+
+use crate::{show, Port};
+
+/// How many times to try programming a sector that fails to verify.
+const RETRIES: usize = 3;
+
+/// What the flasher is doing, for progress reports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stage {
+    /// Comparing the image with flash.
+    Checking,
+    /// Programming the sectors that differ.
+    Programming,
+}
+
+/// Progress through a stage.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub stage: Stage,
+    /// Sectors handled so far in this stage.
+    pub done: usize,
+    /// Sectors to handle in this stage.
+    pub total: usize,
+}
+
+pub struct Flasher<'a> {
+    port: &'a mut Port,
+    part: &'static Partition,
+    image: &'a [u8],
+    algorithm: HashAlgorithm,
+}
+
+impl<'a> Flasher<'a> {
+    /// A flasher to write `image` to the start of a partition, comparing sectors with `algorithm`.
+    pub fn new(
+        port: &'a mut Port,
+        part: &'static Partition,
+        image: &'a [u8],
+        algorithm: HashAlgorithm,
+    ) -> Result<Flasher<'a>> {
+        if !part.contains(part.offset, image.len() as u32) {
+            return Err(anyhow!("Image of 0x{:x} bytes doesn't fit in {}", image.len(), part.name));
+        }
+        if !part.offset.is_multiple_of(SECTOR_SIZE) || !part.size.is_multiple_of(SECTOR_SIZE) {
+            return Err(anyhow!("Partition {} isn't made of whole sectors", part.name));
+        }
+        Ok(Flasher { port, part, image, algorithm })
+    }
+
+    /// The sectors of the image, as their offset in flash and their data.
+    fn sectors(&self) -> impl Iterator<Item = (u32, &'a [u8])> {
+        let base = self.part.offset;
+        self.image
+            .chunks(SECTOR_SIZE as usize)
+            .enumerate()
+            .map(move |(i, data)| (base + i as u32 * SECTOR_SIZE, data))
+    }
+
+    /// Find the sectors where flash differs from the image, returning their offsets.  The hashes
+    /// are pipelined, when the device allows it.
+    pub fn check(&mut self, progress: &mut dyn FnMut(Progress)) -> Result<Vec<u32>> {
+        let sectors: Vec<_> = self.sectors().collect();
+        let requests: Vec<_> = sectors
+            .iter()
+            .map(|(offset, data)| Message::Flash(Flash::Hash {
+                offset: *offset,
+                size: data.len() as u32,
+                algorithm: Some(self.algorithm),
+            }))
+            .collect();
+
+        let algorithm = self.algorithm;
+        let mut dirty = Vec::new();
+        let mut done = 0;
+        progress(Progress { stage: Stage::Checking, done, total: sectors.len() });
+        self.port.pipelined(&requests, |index, reply| {
+            let (offset, data) = sectors[index];
+            match reply {
+                Message::Flash(Flash::Digest { offset: got, digest, .. }) if *got == offset => {
+                    if digest.is_empty() {
+                        return Err(anyhow!("Device was unable to hash 0x{:x}", offset));
+                    }
+                    if Some(digest) != algorithm.digest(data).as_ref() {
+                        dirty.push(offset);
+                    }
+                    done += 1;
+                    progress(Progress { stage: Stage::Checking, done, total: sectors.len() });
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        })?;
+        // Replies can come back out of order.
+        dirty.sort_unstable();
+        Ok(dirty)
+    }
+
+    /// Program, and verify, every sector that differs.  Returns the number of sectors programmed.
+    pub fn program(&mut self, progress: &mut dyn FnMut(Progress)) -> Result<usize> {
+        let dirty = self.check(progress)?;
+        let sectors: Vec<_> = self.sectors().filter(|(offset, _)| dirty.contains(offset)).collect();
+
+        for (done, (offset, data)) in sectors.iter().enumerate() {
+            progress(Progress { stage: Stage::Programming, done, total: sectors.len() });
+            self.program_sector(*offset, data)?;
+        }
+        progress(Progress { stage: Stage::Programming, done: sectors.len(), total: sectors.len() });
+
+        // Let other sessions make changes again.
+        if !sectors.is_empty() {
+            self.port.send(&Message::Core(Core::Release))?;
+        }
+        Ok(sectors.len())
+    }
+
+    fn program_sector(&mut self, offset: u32, data: &[u8]) -> Result<()> {
+        for _ in 0..RETRIES {
+            self.port.send(&Message::Flash(Flash::Program { offset, data: data.to_vec() }))?;
+            let status = loop {
+                match self.port.read()? {
+                    None => return Err(anyhow!("Timeout programming 0x{:x}", offset)),
+                    Some(Message::Flash(Flash::Programmed { offset: got, status, .. })) if got == offset => {
+                        break status
+                    }
+                    Some(Message::Core(Core::Busy { owner })) => {
+                        return Err(anyhow!("Keyboard is busy with another session ({})", owner));
+                    }
+                    Some(packet) => show(&packet),
+                }
+            };
+            if status != 0 {
+                return Err(anyhow!("Device failed to program 0x{:x}: error {}", offset, status));
+            }
+            if self.matches(offset, data)? {
+                return Ok(());
+            }
+        }
+        Err(anyhow!("Sector at 0x{:x} doesn't verify after {} tries", offset, RETRIES))
+    }
+
+    /// Does flash at `offset` match `data`?
+    fn matches(&mut self, offset: u32, data: &[u8]) -> Result<bool> {
+        let size = data.len() as u32;
+        self.port.send(&Message::Flash(Flash::Hash { offset, size, algorithm: Some(self.algorithm) }))?;
+        let digest = loop {
+            match self.port.read()? {
+                None => return Err(anyhow!("Timeout waiting for hash of 0x{:x}", offset)),
+                Some(Message::Flash(Flash::Digest { offset: got, digest, .. })) if got == offset => break digest,
+                Some(packet) => show(&packet),
+            }
+        };
+        if digest.is_empty() {
+            return Err(anyhow!("Device was unable to hash 0x{:x}", offset));
+        }
+        Ok(Some(digest) == self.algorithm.digest(data))
+    }
+}
@@ -0,0 +1,124 @@
+//! The minder HID interface.
+//!
+//! Besides the serial port, the keyboard answers minder requests on a vendor HID interface, which
+//! needs no second CDC ACM port, and no port name.  The interface is found by its report
+//! descriptor, and talked to directly over USB.  On Linux, this detaches the kernel's HID driver
+//! from just that interface, which needs permission to write to the device.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use minder::{transport::PacketLink, HidWrite, PACKET_SIZE};
+use rusb::{DeviceHandle, Direction, GlobalContext, Recipient, RequestType, TransferType};
+
+/// The vendor id of the keyboards, which is Zephyr's.
+const JOLT_VID: u16 = 0x2fe3;
+
+/// The HID interface class.
+const HID_CLASS: u8 = 3;
+
+/// The start of the minder report descriptor: its vendor usage page, and usage.
+const MINDER_USAGE: [u8; 6] = [0x06, 0x4d, 0xff, 0x0a, 0x4e, 0x44];
+
+/// The standard GET_DESCRIPTOR request, for the HID report descriptor.
+const GET_DESCRIPTOR: u8 = 0x06;
+const REPORT_DESCRIPTOR: u16 = 0x2200;
+
+/// How long to wait for a report to be taken by the keyboard.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The minder interface of a keyboard, claimed for as long as this is held.
+pub struct HidLink {
+    handle: DeviceHandle<GlobalContext>,
+    iface: u8,
+    in_ep: u8,
+    out_ep: u8,
+    /// How long a read waits for a report.
+    timeout: Duration,
+}
+
+impl HidLink {
+    /// Find the first keyboard with a minder interface, and claim it.  Returns None if there isn't
+    /// one that can be opened.
+    pub fn find() -> Result<Option<HidLink>> {
+        for device in rusb::devices()?.iter() {
+            if device.device_descriptor()?.vendor_id() != JOLT_VID {
+                continue;
+            }
+            let Ok(config) = device.active_config_descriptor() else {
+                continue;
+            };
+            let Ok(handle) = device.open() else {
+                continue;
+            };
+
+            for alt in config.interfaces().flat_map(|iface| iface.descriptors()) {
+                let iface = alt.interface_number();
+                if alt.class_code() != HID_CLASS || !is_minder(&handle, iface) {
+                    continue;
+                }
+
+                let mut in_ep = None;
+                let mut out_ep = None;
+                for ep in alt.endpoint_descriptors() {
+                    if ep.transfer_type() != TransferType::Interrupt {
+                        continue;
+                    }
+                    match ep.direction() {
+                        Direction::In => in_ep = Some(ep.address()),
+                        Direction::Out => out_ep = Some(ep.address()),
+                    }
+                }
+                let (Some(in_ep), Some(out_ep)) = (in_ep, out_ep) else {
+                    continue;
+                };
+
+                // Not every platform has kernel drivers to detach.
+                let _ = handle.set_auto_detach_kernel_driver(true);
+                handle.claim_interface(iface)?;
+                return Ok(Some(HidLink { handle, iface, in_ep, out_ep, timeout: Duration::from_secs(1) }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Set how long a read waits for a report.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+impl Drop for HidLink {
+    fn drop(&mut self) {
+        let _ = self.handle.release_interface(self.iface);
+    }
+}
+
+/// Does the interface's report descriptor say it is the minder.
+fn is_minder(handle: &DeviceHandle<GlobalContext>, iface: u8) -> bool {
+    let mut buf = [0u8; 256];
+    let request_type = rusb::request_type(Direction::In, RequestType::Standard, Recipient::Interface);
+    match handle.read_control(request_type, GET_DESCRIPTOR, REPORT_DESCRIPTOR, iface as u16, &mut buf, WRITE_TIMEOUT) {
+        Ok(count) => buf[..count].starts_with(&MINDER_USAGE),
+        Err(_) => false,
+    }
+}
+
+impl HidWrite for HidLink {
+    type Error = rusb::Error;
+
+    fn write_packet(&mut self, buf: &[u8]) -> std::result::Result<(), Self::Error> {
+        self.handle.write_interrupt(self.out_ep, buf, WRITE_TIMEOUT)?;
+        Ok(())
+    }
+}
+
+impl PacketLink for HidLink {
+    fn read_packet(&mut self, buf: &mut [u8; PACKET_SIZE]) -> std::result::Result<bool, Self::Error> {
+        match self.handle.read_interrupt(self.in_ep, buf, self.timeout) {
+            Ok(_) => Ok(true),
+            Err(rusb::Error::Timeout) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -1,19 +1,50 @@
 //! Keyminder.
 
-use std::{io::{Error, Write}, time::Duration};
+use std::{io::{Error, Write}, time::{Duration, Instant, SystemTime}};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use bbq_keyboard::{keymap::Keymap, macros::{Macro, StoredMacros, MACRO_NAME_MAX}, stenomap::StenoMap, trace};
+use bbq_steno::Stroke;
 use clap::{Parser, Subcommand};
-use minder::{Reply, Request, SerialDecoder, SerialWrite};
+use minder::{
+    arq::{self, Arq},
+    message::{self, Ble, Core, Debug, Dict, Flash, Leds, Macros, Message, Stats},
+    partition::{self, SECTOR_SIZE},
+    pipeline::{self, Sequenced, Window},
+    sign,
+    transport::{ByteLink, HidTransport, SerialTransport}, BleAddress, BondInfo, DictInfo, DictStatus, EventKind, HashAlgorithm,
+    CrashCause, CrashLog, HostLayout, ImageInfo, LedStep, LinkStatus, LookupStrategy, ModeUsage, RuntimeStats,
+    SerialWrite, Transport, UnicodeEntry, BOND_NAME_MAX, DICT_PATCH_MAX, KEYMAP_CHUNK, TYPE_TEXT_MAX,
+};
 use serialport::SerialPort;
 
+use flasher::{Flasher, Progress, Stage};
+use hid::HidLink;
+
+mod flasher;
+mod hid;
+
+/// How much flash to ask for in a single request.
+const READ_CHUNK: u32 = 1024;
+
+/// How long the monitor's subscription lasts, in seconds.  It is renewed at half this.
+const MONITOR_TIMEOUT: u32 = 4;
+
+/// How long calibration waits for a key before moving on to the next steno key.
+const CALIBRATE_WAIT: Duration = Duration::from_secs(8);
+
+/// The error codes the keyboard's replies can give, as in Zephyr.
+const ENOENT: i32 = 2;
+const EEXIST: i32 = 17;
+
 #[derive(Parser)]
 #[command(name = "keyminder")]
 #[command(about = "Utility for speaking with bbq keyboards")]
 struct Cli {
-    /// The uart port to use.
+    /// The uart port to use.  Without one, the minder HID interface of the first keyboard found is
+    /// used, except for reading logs, which only go to the uart.
     #[arg(long)]
-    port: String,
+    port: Option<String>,
 
     #[command(subcommand)]
     command: Commands,
@@ -23,8 +54,312 @@ struct Cli {
 enum Commands {
     /// Read log packets, printing any messages.
     Log,
-    /// Read the dictionary out of flash.
-    Read,
+    /// Read a region of flash from the keyboard.
+    Read {
+        /// The partition to read from (boardinfo, user-dict, main-dict).
+        #[arg(long, default_value = "boardinfo")]
+        partition: String,
+
+        /// Offset within the partition to start reading.
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+
+        /// Number of bytes to read, defaults to the rest of the partition.
+        #[arg(long)]
+        size: Option<u32>,
+
+        /// File to write the data to.
+        #[arg(long)]
+        output: String,
+    },
+    /// Select a dictionary profile, for use by a host agent tracking the focused application.
+    Profile {
+        /// The profile name.
+        name: String,
+
+        /// Dictionaries to use, by index.  Selects the default (all of them) if not given.
+        #[arg(long, value_delimiter = ',')]
+        dicts: Option<Vec<u8>>,
+
+        /// Seconds until the keyboard falls back to the default profile.  Zero never does.
+        #[arg(long, default_value_t = 0)]
+        timeout: u32,
+
+        /// The qwerty keymap overlay to use, by name.
+        #[arg(long)]
+        overlay: Option<String>,
+
+        /// Hold steno translations that a later stroke could extend, for up to this many ms, so
+        /// only the longest match is typed.  Translations are typed right away if not given.
+        #[arg(long)]
+        longest: Option<u32>,
+    },
+    /// Check that flash matches a local image, by comparing hashes.
+    Check {
+        /// The partition the image was written to.
+        #[arg(long)]
+        partition: String,
+
+        /// Allow a fast, non-cryptographic hash (such as a CRC) if the device supports one.
+        #[arg(long)]
+        fast: bool,
+
+        /// The local image.
+        file: String,
+    },
+    /// Write an image to a partition, programming only the sectors that differ.  Run it again to
+    /// resume an interrupted write.  The keyboard restarts afterwards to load a new dictionary.
+    Flash {
+        /// The partition to write to (user-dict, main-dict).
+        #[arg(long)]
+        partition: String,
+
+        /// Compare sectors with a fast, non-cryptographic hash (such as a CRC) if the device
+        /// supports one.
+        #[arg(long)]
+        fast: bool,
+
+        /// The image to write.
+        file: String,
+    },
+    /// Show the status of the keyboard, including the running firmware.
+    Status,
+    /// List the steno dictionaries on the keyboard, and where they are in flash.
+    Dicts {
+        /// Also hash each dictionary.
+        #[arg(long)]
+        hash: bool,
+
+        /// Allow a fast, non-cryptographic hash (such as a CRC) if the device supports one.
+        #[arg(long, requires = "hash")]
+        fast: bool,
+    },
+    /// Check that a firmware image (.bin or .uf2) is built for the connected keyboard.
+    Image {
+        /// The firmware image.
+        file: String,
+    },
+    /// Update the keyboard's firmware, without a debug probe.  The image (.bin or .uf2) is written
+    /// to the staging area and verified, then the keyboard installs it and restarts.
+    FlashFirmware {
+        /// The image's signature, from fw sign, for keyboards that only install signed images.
+        /// Defaults to the image name with .sig added, if there is one.
+        #[arg(long)]
+        signature: Option<String>,
+
+        /// The firmware image.
+        file: String,
+    },
+    /// Sign firmware images, for keyboards built to only install images signed by their key.
+    Fw {
+        #[command(subcommand)]
+        command: FwCommands,
+    },
+    /// Run a debug console command on the keyboard, such as "status" or "mode qwerty".
+    Exec {
+        /// The command, and its arguments.
+        #[arg(required = true)]
+        command: Vec<String>,
+    },
+    /// Retrieve the steno paper tape.
+    Tape {
+        /// File to write the tape to.  Printed if not given.
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Retrieve the qwerty keymap in use, as JSON.
+    GetKeymap {
+        /// File to write the keymap to.  Printed if not given.
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Store a qwerty keymap, given as JSON (such as from get-keymap), and switch to it.
+    SetKeymap {
+        /// The keymap file.
+        file: String,
+    },
+    /// Show which scan codes are which steno keys.
+    StenoMap,
+    /// Work out which scan codes are which steno keys, by asking for each steno key to be pressed
+    /// in turn, and store the result as the steno map.
+    CalibrateSteno {
+        /// A name for the steno map.
+        #[arg(long, default_value = "calibrated")]
+        name: String,
+    },
+    /// Replace the LED pattern of an indicator (such as qwerty, or steno-select), and keep it
+    /// across reboots.  Leave out the steps to go back to the built-in pattern.
+    LedPattern {
+        /// The indicator.
+        indicator: String,
+
+        /// Each step, as a color and how many ticks to show it for, such as 001000:100.
+        #[arg(value_parser = parse_led_step)]
+        steps: Vec<LedStep>,
+    },
+    /// Have the keyboard type text into the focused application.
+    Type {
+        /// The text to type.  Read from stdin if not given, which keeps it off the command line.
+        text: Option<String>,
+    },
+    /// Tell the keyboard which keyboard layout the host is set to, so that typed text comes out as
+    /// the right characters.  The keyboard forgets it when it restarts.
+    HostLayout {
+        /// The layout (us, gb, de), or a name the host uses for it.  Detected from the host if not
+        /// given.
+        layout: Option<String>,
+    },
+    /// Tell the keyboard how this host accepts characters by their code point, so that typed
+    /// characters missing from the host layout can still be typed.  The keyboard forgets it when
+    /// it restarts.
+    UnicodeEntry {
+        /// The method (linux, windows, mac, or none).  Chosen by this host's OS if not given.
+        entry: Option<String>,
+    },
+    /// Apply a dictionary patch (from bbq-tool patch) to the user dictionary.  The keyboard
+    /// restarts afterwards to load it.
+    PatchDict {
+        /// The patch file.
+        file: String,
+    },
+    /// Watch key events and steno strokes as they happen, until interrupted.  Shows both unless
+    /// one is asked for.
+    Monitor {
+        /// Show keys going up and down, by scan code.
+        #[arg(long)]
+        keys: bool,
+
+        /// Show steno strokes.
+        #[arg(long)]
+        strokes: bool,
+    },
+    /// Save state and reboot the keyboard.
+    Reboot,
+    /// Show the report of the last crash, or watchdog reset, if the keyboard has restarted because
+    /// of one.
+    CrashLog {
+        /// Forget the report, once shown.
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Show the firmware's runtime metrics: the heap, its queues, and how promptly it scans.
+    Stats,
+    /// Work with the event trace, recorded by firmware built with the trace feature.
+    Trace {
+        #[command(subcommand)]
+        command: TraceCommands,
+    },
+    /// Manage the BLE hosts the keyboard is bonded with, on firmware with BLE.
+    Ble {
+        #[command(subcommand)]
+        command: BleCommands,
+    },
+    /// Manage the keyboard macros stored on the keyboard, by name.
+    Macro {
+        #[command(subcommand)]
+        command: MacroCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum FwCommands {
+    /// Make a signing key, unless the file already has one, and show its public key.  Build the
+    /// firmware with this in BBQ_FIRMWARE_KEY, and it will only install images signed by the key.
+    Key {
+        /// The file holding the secret key.  Keep it somewhere safe.
+        file: String,
+    },
+    /// Sign a firmware image (.bin or .uf2), for flash-firmware.
+    Sign {
+        /// The file holding the secret key, from fw key.
+        #[arg(long)]
+        key: String,
+
+        /// File to write the signature to, defaults to the image name with .sig added.
+        #[arg(long)]
+        output: Option<String>,
+
+        /// The firmware image.
+        file: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BleCommands {
+    /// List the bonded hosts.
+    List,
+    /// Name a bonded host, to tell it apart from the others.
+    Name {
+        /// The host, by its address, or the start of it, or by its name.
+        host: String,
+
+        /// The new name.  An empty name forgets the old one.
+        name: String,
+    },
+    /// Delete the bond with a host.  It will have to pair again to connect.
+    Delete {
+        /// The host, by its address, or the start of it, or by its name.
+        host: String,
+    },
+    /// Let new hosts pair.  Otherwise, they can only pair while there are no bonds.
+    Pair {
+        /// How long to allow pairing for, in seconds.  Zero stops pairing now.
+        #[arg(long, default_value_t = 60)]
+        seconds: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum MacroCommands {
+    /// List the stored macros.
+    List,
+    /// Add a macro, from a JSON file (as written by download), to the keyboard.  No other macro
+    /// can have its name, and a key in the keymap has to play its slot.
+    Upload {
+        /// The slot to put the macro in, instead of the one in the file.
+        #[arg(long)]
+        slot: Option<u8>,
+
+        /// The name to give the macro, instead of the one in the file.
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Replace the macro already in the slot.
+        #[arg(long)]
+        replace: bool,
+
+        /// The macro file.
+        file: String,
+    },
+    /// Read a macro from the keyboard, as JSON.
+    Download {
+        /// The name of the macro.
+        name: String,
+
+        /// File to write the macro to, instead of showing it.
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Delete a macro from the keyboard.
+    Delete {
+        /// The name of the macro.
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum TraceCommands {
+    /// Read the event trace from the keyboard.
+    Dump {
+        /// File to write the raw trace to.  Shown as a timeline if not given.
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Show a raw trace, saved by dump, as a timeline.
+    Decode {
+        /// The trace file.
+        file: String,
+    },
 }
 
 fn main() -> Result<()> {
@@ -34,8 +369,162 @@ fn main() -> Result<()> {
         Commands::Log => {
             cli.do_log()?;
         }
-        Commands::Read => {
-            cli.do_read()?;
+        Commands::Read { partition, offset, size, output } => {
+            cli.do_read(partition, *offset, *size, output)?;
+        }
+        Commands::Profile { name, dicts, timeout, overlay, longest } => {
+            let strategy = longest.map(|timeout| LookupStrategy::Longest { timeout });
+            cli.do_profile(name, dicts.clone(), *timeout, overlay.clone(), strategy)?;
+        }
+        Commands::Check { partition, fast, file } => {
+            cli.do_check(partition, *fast, file)?;
+        }
+        Commands::Status => {
+            let Status { image, build_id, uptime, usage, dicts, link } = cli.get_status()?;
+            println!("Firmware: {} {}{}, built for {}",
+                     image.version, image.git, if image.dirty { "-dirty" } else { "" }, image.board);
+            println!("Build id: {:08x}, source time {}", build_id, image.timestamp);
+            println!("Uptime: {}.{:03}s", uptime / 1000, uptime % 1000);
+            if !usage.is_empty() {
+                println!("{:<14} {:>10} {:>10} {:>10}", "Mode", "Hours", "Keys", "Strokes");
+                for mode in &usage {
+                    println!("{:<14} {:>10.1} {:>10} {:>10}",
+                             mode.mode, mode.ms as f64 / 3_600_000.0, mode.keys, mode.strokes);
+                }
+            }
+            if let Some(DictStatus { format, valid, dicts }) = dicts {
+                println!("Dictionaries: {}, format {}{}",
+                         dicts.len(), format, if valid { "" } else { ", some failed to load" });
+                for DictInfo { index, name, entries, digest, .. } in &dicts {
+                    let index = index.map(|i| i.to_string()).unwrap_or_else(|| "-".to_string());
+                    let crc = digest.as_ref()
+                        .map(|d| d.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+                        .unwrap_or_default();
+                    println!("{:>5} {:<20} {:>8} {}", index, name, entries, crc);
+                }
+            }
+            if let Some(link) = link {
+                println!("Link: {} packets sent, {} deferred, {} preempted",
+                         link.sent, link.deferred, link.preempted);
+                println!("Link errors: {} overruns, {} framing, {} in the last second, {} resyncs",
+                         link.overruns, link.framing, link.errors_per_sec, link.resyncs);
+            }
+        }
+        Commands::Flash { partition, fast, file } => {
+            cli.do_flash(partition, *fast, file)?;
+        }
+        Commands::Dicts { hash, fast } => {
+            cli.do_dicts(*hash, *fast)?;
+        }
+        Commands::Image { file } => {
+            cli.do_image(file)?;
+        }
+        Commands::FlashFirmware { signature, file } => {
+            cli.do_flash_firmware(file, signature.as_deref())?;
+        }
+        Commands::Fw { command: FwCommands::Key { file } } => {
+            firmware_key(file)?;
+        }
+        Commands::Fw { command: FwCommands::Sign { key, output, file } } => {
+            sign_firmware(key, file, output.as_deref())?;
+        }
+        Commands::Exec { command } => {
+            cli.do_exec(&command.join(" "))?;
+        }
+        Commands::Tape { output } => {
+            cli.do_tape(output.as_deref())?;
+        }
+        Commands::GetKeymap { output } => {
+            cli.do_get_keymap(output.as_deref())?;
+        }
+        Commands::SetKeymap { file } => {
+            cli.do_set_keymap(file)?;
+        }
+        Commands::StenoMap => {
+            cli.do_steno_map()?;
+        }
+        Commands::CalibrateSteno { name } => {
+            cli.do_calibrate_steno(name)?;
+        }
+        Commands::LedPattern { indicator, steps } => {
+            cli.do_led_pattern(indicator, steps.clone())?;
+        }
+        Commands::Type { text } => {
+            let text = match text {
+                Some(text) => text.clone(),
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            cli.do_type(text)?;
+        }
+        Commands::HostLayout { layout } => {
+            let layout = match layout {
+                Some(name) => HostLayout::from_hint(name)
+                    .ok_or_else(|| anyhow!("Unsupported layout {:?}, use us, gb, or de", name))?,
+                None => detect_host_layout()?,
+            };
+            cli.do_host_layout(layout)?;
+        }
+        Commands::UnicodeEntry { entry } => {
+            let entry = match entry {
+                Some(name) => UnicodeEntry::from_name(name)
+                    .ok_or_else(|| anyhow!("Unknown entry {:?}, use linux, windows, mac, or none", name))?,
+                None if cfg!(target_os = "windows") => UnicodeEntry::Windows,
+                None if cfg!(target_os = "macos") => UnicodeEntry::Mac,
+                None => UnicodeEntry::Linux,
+            };
+            cli.do_unicode_entry(entry)?;
+        }
+        Commands::PatchDict { file } => {
+            cli.do_patch_dict(file)?;
+        }
+        Commands::Monitor { keys, strokes } => {
+            let mut events = Vec::new();
+            if *keys || !*strokes {
+                events.push(EventKind::Keys);
+            }
+            if *strokes || !*keys {
+                events.push(EventKind::Strokes);
+            }
+            cli.do_monitor(events)?;
+        }
+        Commands::Reboot => {
+            cli.do_reboot()?;
+        }
+        Commands::CrashLog { clear } => {
+            cli.do_crash_log(*clear)?;
+        }
+        Commands::Stats => {
+            cli.do_stats()?;
+        }
+        Commands::Trace { command: TraceCommands::Dump { output } } => {
+            cli.do_trace_dump(output.as_deref())?;
+        }
+        Commands::Trace { command: TraceCommands::Decode { file } } => {
+            print!("{}", trace::render(&std::fs::read(file)?));
+        }
+        Commands::Ble { command: BleCommands::List } => {
+            cli.do_ble_list()?;
+        }
+        Commands::Ble { command: BleCommands::Name { host, name } } => {
+            cli.do_ble_name(host, name)?;
+        }
+        Commands::Ble { command: BleCommands::Delete { host } } => {
+            cli.do_ble_delete(host)?;
+        }
+        Commands::Ble { command: BleCommands::Pair { seconds } } => {
+            cli.do_ble_pair(*seconds)?;
+        }
+        Commands::Macro { command: MacroCommands::List } => {
+            cli.do_macro_list()?;
+        }
+        Commands::Macro { command: MacroCommands::Upload { slot, name, replace, file } } => {
+            cli.do_macro_upload(file, *slot, name.clone(), *replace)?;
+        }
+        Commands::Macro { command: MacroCommands::Download { name, output } } => {
+            cli.do_macro_download(name, output.as_deref())?;
+        }
+        Commands::Macro { command: MacroCommands::Delete { name } } => {
+            cli.do_macro_delete(name)?;
         }
     }
 
@@ -43,14 +532,23 @@ fn main() -> Result<()> {
 }
 
 impl Cli {
+    /// Open the port given on the command line, or the minder HID interface without one.
+    fn open(&self) -> Result<Port> {
+        match self.port.as_deref() {
+            Some(port) => Port::new(port),
+            None => Port::hid(),
+        }
+    }
+
     fn do_log(&self) -> Result<()> {
-        let mut port = Port::new(&self.port)?;
+        let port = self.port.as_deref().ok_or_else(|| anyhow!("Logs only go to the uart, which needs a --port"))?;
+        let mut port = Port::new(port)?;
 
         port.set_timeout(Duration::from_secs(120 * 60 * 60 * 24))?;
 
-        let req = Request::Hello {
+        let req = Message::Core(Core::Hello {
             version: minder::VERSION.to_string(),
-        };
+        });
 
         port.send(&req)?;
 
@@ -64,297 +562,1533 @@ impl Cli {
         Ok(())
     }
 
-    fn do_read(&self) -> Result<()> {
-        todo!()
+    fn do_read(&self, partition: &str, offset: u32, size: Option<u32>, output: &str) -> Result<()> {
+        let part = partition::by_name(partition)
+            .ok_or_else(|| anyhow!("Unknown partition: {:?}", partition))?;
+        if offset > part.size {
+            return Err(anyhow!("Offset 0x{:x} is past the end of {}", offset, part.name));
+        }
+        let size = size.unwrap_or(part.size - offset);
+        if !part.contains(part.offset + offset, size) {
+            return Err(anyhow!("Read of 0x{:x} bytes doesn't fit in {}", size, part.name));
+        }
+
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        let start = part.offset + offset;
+        let end = start + size;
+        let requests: Vec<_> = (start..end)
+            .step_by(READ_CHUNK as usize)
+            .map(|pos| Message::Flash(Flash::Read { offset: pos, size: (end - pos).min(READ_CHUNK) }))
+            .collect();
+
+        // The chunks can come back in any order.
+        port.hello()?;
+        let mut data = vec![0u8; size as usize];
+        port.pipelined(&requests, |index, reply| {
+            let Message::Flash(Flash::Read { offset: pos, size }) = requests[index] else {
+                unreachable!()
+            };
+            match reply {
+                Message::Flash(Flash::Data { offset, data: chunk }) if *offset == pos => {
+                    if chunk.len() != size as usize {
+                        return Err(anyhow!("Short flash read at 0x{:x}", pos));
+                    }
+                    let base = (pos - start) as usize;
+                    data[base..base + chunk.len()].copy_from_slice(chunk);
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        })?;
+
+        std::fs::write(output, &data)?;
+        Ok(())
     }
-}
 
-/// A port that can communicate with the device.
-struct Port {
-    port: Box<dyn SerialPort>,
-    buffer: Vec<u8>,
-    offset: usize,
-    len: usize,
-    dec: SerialDecoder,
-}
+    fn do_profile(
+        &self,
+        name: &str,
+        dicts: Option<Vec<u8>>,
+        timeout: u32,
+        overlay: Option<String>,
+        strategy: Option<LookupStrategy>,
+    ) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
 
-impl Port {
-    pub fn new(port: &str) -> Result<Port> {
-        Ok(Port {
-            port: serialport::new(port, 115200).open()?,
-            buffer: vec![0u8; 256],
-            offset: 0,
-            len: 0,
-            dec: SerialDecoder::new(),
-        })
+        port.send(&Message::Dict(Dict::SetProfile {
+            name: name.to_string(),
+            dicts,
+            timeout,
+            overlay,
+            strategy,
+        }))?;
+
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for profile change")),
+                Some(Message::Dict(Dict::Profile { .. })) => return Ok(()),
+                Some(packet) => show(&packet),
+            }
+        }
     }
 
-    pub fn send(&mut self, req: &Request) -> Result<()> {
-        minder::serial_encode(req, self, true)?;
+    fn do_led_pattern(&self, indicator: &str, steps: Vec<LedStep>) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        port.send(&Message::Leds(Leds::SetPattern { indicator: indicator.to_string(), steps }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for LED pattern")),
+                Some(Message::Leds(Leds::PatternSet { status, .. })) => {
+                    if status != 0 {
+                        return Err(anyhow!("LED pattern for {} rejected, status {}", indicator, status));
+                    }
+                    break;
+                }
+                Some(Message::Core(Core::Busy { owner })) => {
+                    return Err(anyhow!("Session {} is making changes, try again later", owner));
+                }
+                Some(packet) => show(&packet),
+            }
+        }
+        port.send(&Message::Core(Core::Release))?;
         Ok(())
     }
 
-    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
-        self.port.set_timeout(timeout)?;
+    fn do_type(&self, text: String) -> Result<()> {
+        let count = text.chars().count();
+        if count > TYPE_TEXT_MAX {
+            return Err(anyhow!("Text is {} characters, at most {} can be typed", count, TYPE_TEXT_MAX));
+        }
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        port.send(&Message::Core(Core::TypeText { text }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for text to be queued")),
+                Some(Message::Core(Core::TextQueued { status })) => {
+                    if status != 0 {
+                        return Err(anyhow!("Text rejected, status {}", status));
+                    }
+                    break;
+                }
+                Some(Message::Core(Core::Busy { owner })) => {
+                    return Err(anyhow!("Session {} is making changes, try again later", owner));
+                }
+                Some(packet) => show(&packet),
+            }
+        }
+        port.send(&Message::Core(Core::Release))?;
         Ok(())
     }
 
-    /// Try to read. Returns Ok(None) on timeout.
-    pub fn read(&mut self) -> Result<Option<Reply>> {
+    fn do_unicode_entry(&self, entry: UnicodeEntry) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        port.send(&Message::Core(Core::SetUnicodeEntry { entry }))?;
         loop {
-            if self.offset >= self.len {
-                let count = match self.port.read(&mut self.buffer) {
-                    Ok(count) => count,
-                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut => return Ok(None),
-                    Err(e) => Err(e)?,
-                };
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for the Unicode entry to be set")),
+                Some(Message::Core(Core::UnicodeEntrySet { entry })) => {
+                    println!("Unicode entry: {}", entry.name());
+                    break;
+                }
+                Some(Message::Core(Core::Busy { owner })) => {
+                    return Err(anyhow!("Session {} is making changes, try again later", owner));
+                }
+                Some(packet) => show(&packet),
+            }
+        }
+        port.send(&Message::Core(Core::Release))?;
+        Ok(())
+    }
+
+    fn do_host_layout(&self, layout: HostLayout) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
 
-                if count == 0 {
-                    panic!("Serial returned 0 bytes, but didn't timeout");
+        port.send(&Message::Core(Core::SetHostLayout { layout }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for the host layout to be set")),
+                Some(Message::Core(Core::HostLayoutSet { layout })) => {
+                    println!("Host layout: {}", layout.name());
+                    break;
+                }
+                Some(Message::Core(Core::Busy { owner })) => {
+                    return Err(anyhow!("Session {} is making changes, try again later", owner));
                 }
+                Some(packet) => show(&packet),
+            }
+        }
+        port.send(&Message::Core(Core::Release))?;
+        Ok(())
+    }
 
-                self.offset = 0;
-                self.len = count;
+    fn do_exec(&self, command: &str) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        port.send(&Message::Debug(Debug::Exec { command: command.to_string() }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for command output")),
+                Some(Message::Debug(Debug::Output { output })) => {
+                    println!("{}", output);
+                    return Ok(());
+                }
+                Some(packet) => show(&packet),
             }
+        }
+    }
+
+    fn do_reboot(&self) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
 
-            let byte = self.buffer[self.offset];
-            self.offset += 1;
-            if let Some(packet) = self.dec.add_decode::<Reply>(byte) {
-                return Ok(Some(packet));
+        port.send(&Message::Core(Core::Reboot))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for reboot")),
+                Some(Message::Core(Core::Rebooting)) => {
+                    println!("Rebooting");
+                    return Ok(());
+                }
+                Some(packet) => show(&packet),
             }
         }
     }
-}
 
-impl SerialWrite for Port {
-    type Error = Error;
+    fn do_crash_log(&self, clear: bool) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
 
-    fn write_all(&mut self, buf: &[u8]) -> std::result::Result<(), Self::Error> {
-        self.port.write_all(buf)
+        port.send(&Message::Debug(Debug::GetCrashLog { clear }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for crash log")),
+                Some(Message::Debug(Debug::CrashLog { log })) => {
+                    show_crash_log(log.as_ref());
+                    return Ok(());
+                }
+                Some(packet) => show(&packet),
+            }
+        }
     }
-}
 
-fn show(msg: &Reply) {
-    match msg {
-        Reply::Hello { version, info } => {
-            println!("Hello: {}, {}", version, info);
+    fn do_stats(&self) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        port.send(&Message::Stats(Stats::GetRuntime))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for stats")),
+                Some(Message::Stats(Stats::Runtime { stats })) => {
+                    show_stats(&stats);
+                    return Ok(());
+                }
+                Some(packet) => show(&packet),
+            }
         }
-        Reply::Log { message } => {
-            println!("{}", message);
+    }
+
+    fn do_ble_list(&self) -> Result<()> {
+        let mut port = self.open()?;
+        let bonds = read_bonds(&mut port)?;
+        if bonds.is_empty() {
+            println!("No bonded hosts");
         }
-        Reply::FlashData { offset, data } => {
-            println!("Read: 0x{:x}, 0x{:x} bytes", offset, data.len());
+        for bond in &bonds {
+            println!("{}  {:<20} {}",
+                     bond.address,
+                     bond.name.as_deref().unwrap_or("-"),
+                     if bond.connected { "connected" } else { "" });
         }
+        Ok(())
     }
-}
 
-// Zephyr vid: 2fe3
-// bbq keyboard: 4201
-// bbq keyoard test: 4202
+    fn do_ble_name(&self, host: &str, name: &str) -> Result<()> {
+        if name.len() > BOND_NAME_MAX {
+            return Err(anyhow!("Name is {} bytes, at most {} are allowed", name.len(), BOND_NAME_MAX));
+        }
+        let mut port = self.open()?;
+        let address = find_bond(&read_bonds(&mut port)?, host)?;
+        change_bond(&mut port, Ble::NameBond { address, name: name.to_string() })?;
+        if name.is_empty() {
+            println!("Forgot the name of {}", address);
+        } else {
+            println!("Named {} {}", address, name);
+        }
+        Ok(())
+    }
 
-/*
-const JOLT_VID: u16 = 0x2fe3;
-const JOLT_PID: u16 = 0x4202;
+    fn do_ble_delete(&self, host: &str) -> Result<()> {
+        let mut port = self.open()?;
+        let address = find_bond(&read_bonds(&mut port)?, host)?;
+        change_bond(&mut port, Ble::DeleteBond { address })?;
+        println!("Deleted the bond with {}", address);
+        Ok(())
+    }
 
-// Alternatively, look for the interface with the right data.
-static PREFIX: [u8; 6] = [0x06, 0x4d, 0xff, 0x0a, 0x4e, 0x44];
+    fn do_ble_pair(&self, seconds: u32) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
 
-fn main() -> Result<()> {
-    for device in rusb::devices()?.iter() {
-        let desc = device.device_descriptor()?;
-
-        if desc.vendor_id() != JOLT_VID || desc.product_id() != JOLT_PID {
-            continue;
-        }
-
-        println!("Bus {:03} Device {:03} ID {:04x}:{:04x} {:#?}",
-                 device.bus_number(),
-                 device.address(),
-                 desc.vendor_id(),
-                 desc.product_id(),
-                 desc,
-                 );
-
-        let config = device.active_config_descriptor()?;
-        println!("config: {:#?}", config);
-
-        let dev = device.open()?;
-
-        let mut in_ep = None;
-        let mut out_ep = None;
-
-        for iface in config.interfaces() {
-            // Try to read the HID Descriptior.
-            let mut buf = vec![0u8; 256];
-            let request_type = rusb::request_type(Direction::In, RequestType::Standard, Recipient::Interface);
-            let ret = dev.read_control(
-                request_type,
-                0x06,
-                0x2122,
-                iface.number() as u16,
-                &mut buf,
-                Duration::from_secs(1),
-            );
-            if ret.is_err() {
-                continue;
-            }
-            let count = ret.unwrap();
-
-            println!("iface {}", iface.number());
-
-            println!("HID: {:02x?}", &buf[..count]);
-
-            // Really didn't need to read that, I don't think.
-            let ret = dev.read_control(
-                request_type,
-                0x06,
-                0x2200,
-                iface.number() as u16,
-                &mut buf,
-                Duration::from_secs(1),
-            );
-            if ret.is_err() {
-                continue;
-            }
-            let count = ret.unwrap();
-
-            println!("Report Desc: {:02x?}", &buf[..count]);
-
-            // If this isn't the one we care about, continue.
-            if !buf[..count].starts_with(&PREFIX) {
-                continue;
-            }
-
-            for desc in iface.descriptors() {
-                if desc.class_code() != 3 {
-                    // continue;
-                }
-                // println!("  extra: {:#02x?}", desc.extra());
-                for endpoint in desc.endpoint_descriptors() {
-                    if endpoint.direction() == Direction::In {
-                        println!("  IN: {:02x}", endpoint.number());
-                        in_ep = Some(endpoint.address());
-                    } else {
-                        println!(" OUT: {:02x}", endpoint.number());
-                        out_ep = Some(endpoint.address());
+        port.send(&Message::Ble(Ble::Pair { seconds }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for pairing, does the firmware have BLE?")),
+                Some(Message::Ble(Ble::Pairing { status })) => {
+                    if status != 0 {
+                        return Err(anyhow!("Pairing not opened, status {}", status));
                     }
-                    println!("  endpoint {:#x?}", endpoint);
+                    break;
                 }
+                Some(Message::Core(Core::Busy { owner })) => {
+                    return Err(anyhow!("Session {} is making changes, try again later", owner));
+                }
+                Some(packet) => show(&packet),
             }
-            println!("HID control: {:?} {:?}", in_ep, out_ep);
         }
+        port.send(&Message::Core(Core::Release))?;
+        if seconds == 0 {
+            println!("Pairing stopped");
+        } else {
+            println!("New hosts can pair for {} seconds", seconds);
+        }
+        Ok(())
+    }
 
-        dev.claim_interface(out_ep.unwrap())?;
-        minder::hid_encode(&[Request::Hello { version: minder::VERSION.to_string() }],
-        HidDev(&dev, out_ep.unwrap()))?;
+    fn do_macro_list(&self) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
 
-        /*
-        let dev = device.open()?;
-        println!("Config: {}", dev.active_configuration()?);
-        */
+        port.send(&Message::Macros(Macros::List))?;
+        let macros = loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for macros, does the firmware have qwerty mode?")),
+                Some(Message::Macros(Macros::Listing { macros })) => break macros,
+                Some(packet) => show(&packet),
+            }
+        };
+        if macros.is_empty() {
+            println!("No stored macros");
+        }
+        for mac in &macros {
+            println!("{}  {:<width$} {} steps",
+                     mac.slot,
+                     mac.name.as_deref().unwrap_or("-"),
+                     mac.steps,
+                     width = MACRO_NAME_MAX);
+        }
+        Ok(())
     }
 
-    Ok(())
-}
+    fn do_macro_upload(&self, file: &str, slot: Option<u8>, name: Option<String>, replace: bool) -> Result<()> {
+        let mut mac: Macro = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+        if let Some(slot) = slot {
+            mac.slot = slot;
+        }
+        if name.is_some() {
+            mac.name = name;
+        }
+        if mac.name.is_none() {
+            return Err(anyhow!("The macro needs a name, give one with --name"));
+        }
+        StoredMacros { macros: vec![mac.clone()] }.check().map_err(|e| anyhow!("{}", e))?;
+
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        port.send(&Message::Macros(Macros::Upload { data: mac.encode(), replace }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for the macro to be stored")),
+                Some(Message::Macros(Macros::Uploaded { slot, status })) => {
+                    match -status {
+                        0 => break,
+                        EEXIST => {
+                            return Err(anyhow!("Macro slot {} is in use, or another slot has the name {:?}; \
+                                                use --replace to replace the slot",
+                                               slot, mac.name.unwrap_or_default()));
+                        }
+                        ENOENT => return Err(anyhow!("No key in the keymap plays macro slot {}", slot)),
+                        _ => return Err(anyhow!("Macro rejected, status {}", status)),
+                    }
+                }
+                Some(Message::Core(Core::Busy { owner })) => {
+                    return Err(anyhow!("Session {} is making changes, try again later", owner));
+                }
+                Some(packet) => show(&packet),
+            }
+        }
+        port.send(&Message::Core(Core::Release))?;
+        println!("Stored macro {:?} in slot {}", mac.name.unwrap_or_default(), mac.slot);
+        Ok(())
+    }
 
-struct HidDev<'a, T: UsbContext>(&'a DeviceHandle<T>, u8);
+    fn do_macro_download(&self, name: &str, output: Option<&str>) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        port.send(&Message::Macros(Macros::Download { name: name.to_string() }))?;
+        let data = loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for the macro")),
+                Some(Message::Macros(Macros::Data { data, .. })) => break data,
+                Some(packet) => show(&packet),
+            }
+        };
+        if data.is_empty() {
+            return Err(anyhow!("No macro named {:?}", name));
+        }
+        let mac = Macro::decode(&data).ok_or_else(|| anyhow!("Macro from the keyboard doesn't decode"))?;
+        let text = serde_json::to_string_pretty(&mac)? + "\n";
+        match output {
+            Some(name) => std::fs::write(name, text)?,
+            None => print!("{}", text),
+        }
+        Ok(())
+    }
 
-impl<'a, T: UsbContext> HidWrite for HidDev<'a, T> {
-    type Error = rusb::Error;
+    fn do_macro_delete(&self, name: &str) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
 
-    fn write_packet(&mut self, buf: &[u8]) -> std::result::Result<(), Self::Error> {
-        let count = self.0.write_interrupt(self.1, buf, Duration::from_secs(1))?;
-        if count != buf.len() {
-            println!("Warning: short write");
+        port.send(&Message::Macros(Macros::Delete { name: name.to_string() }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for the macro to be deleted")),
+                Some(Message::Macros(Macros::Deleted { status, .. })) => {
+                    match -status {
+                        0 => break,
+                        ENOENT => return Err(anyhow!("No macro named {:?}", name)),
+                        _ => return Err(anyhow!("Macro not deleted, status {}", status)),
+                    }
+                }
+                Some(Message::Core(Core::Busy { owner })) => {
+                    return Err(anyhow!("Session {} is making changes, try again later", owner));
+                }
+                Some(packet) => show(&packet),
+            }
         }
+        port.send(&Message::Core(Core::Release))?;
+        println!("Deleted macro {:?}", name);
         Ok(())
     }
-}
-*/
 
-/*
-fn main() -> Result<()> {
-    let api = HidApi::new()?;
-
-    let mut minder = None;
-    for device in api.device_list() {
-        if device.vendor_id() != 0x2fe3 {
-            continue;
-        }
-        if device.product_id() != 0x4202 {
-            continue;
-        }
-        /*
-        println!("{:?}: {:x?} sn:{:?}: {:x?}, i:{}, ",
-                 device.product_string(),
-                 device.path(),
-                 device.serial_number(),
-                 device,
-                 device.interface_number(),
-                 );
-        */
-
-        // Try opening it, and get the get the information.
-        let dev = match device.open_device(&api) {
-            Ok(dev) => dev,
-            // Allow for errors here, because the keyboard/mouse endpoints aren't usable.
-            Err(_) => continue,
+    fn do_check(&self, partition: &str, fast: bool, file: &str) -> Result<()> {
+        let part = partition::by_name(partition)
+            .ok_or_else(|| anyhow!("Unknown partition: {:?}", partition))?;
+        let image = std::fs::read(file)?;
+        let size = image.len() as u32;
+        if !part.contains(part.offset, size) {
+            return Err(anyhow!("{} doesn't fit in {}", file, part.name));
+        }
+
+        let mut port = self.open()?;
+        // Hashing the larger partitions can take a while on the device.
+        port.set_timeout(Duration::from_secs(30))?;
+
+        let algorithm = port.choose_hash(fast)?;
+        let expect = algorithm.digest(&image).unwrap();
+
+        port.send(&Message::Flash(Flash::Hash {
+            offset: part.offset,
+            size,
+            algorithm: Some(algorithm),
+        }))?;
+        let digest = loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for hash")),
+                Some(Message::Flash(Flash::Digest { digest, .. })) => break digest,
+                Some(packet) => show(&packet),
+            }
         };
-        let mut desc = [0u8; MAX_REPORT_DESCRIPTOR_SIZE];
-        let len = dev.get_report_descriptor(&mut desc)?;
-        let desc = &desc[..len];
-        if desc.starts_with(&PREFIX) {
-            println!("i:{}, desc:{:02x?}", device.interface_number(), &desc[..len]);
-            minder = Some(dev);
+
+        if digest.is_empty() {
+            return Err(anyhow!("Device was unable to compute the hash"));
         }
+        if digest != expect {
+            return Err(anyhow!("{} does not match {} ({:?})", part.name, file, algorithm));
+        }
+        println!("{} matches {} ({:?})", part.name, file, algorithm);
+        Ok(())
+    }
+
+    fn do_flash(&self, partition: &str, fast: bool, file: &str) -> Result<()> {
+        let part = partition::by_name(partition)
+            .ok_or_else(|| anyhow!("Unknown partition: {:?}", partition))?;
+        let image = std::fs::read(file)?;
 
-        // Store the last one here.  We might want to try to distinguish multiple keyboards since
-        // that will happen a lot while debugging.
-        // minder = Some(dev);
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+        let algorithm = port.choose_hash(fast)?;
+
+        let mut flasher = Flasher::new(&mut port, part, &image, algorithm)?;
+        let count = flasher.program(&mut show_progress)?;
+        eprintln!();
+        println!("{}: {} sectors programmed ({:?})", part.name, count, algorithm);
+        if count > 0 && (*part == partition::MAIN_DICT || *part == partition::USER_DICT) {
+            println!("Keyboard is restarting to load the dictionary");
+        }
+        Ok(())
     }
 
-    let minder = minder.unwrap();
+    fn do_dicts(&self, hash: bool, fast: bool) -> Result<()> {
+        let mut port = self.open()?;
+        // Hashing the larger dictionaries can take a while on the device.
+        port.set_timeout(Duration::from_secs(if hash { 60 } else { 5 }))?;
 
-    // Send a single Hello request.
-    minder::hid_encode(
-        &[Request::Hello {
-            version: minder::VERSION.to_string(),
-        },
-        ],
-        HidDev(&minder),
-    ).unwrap();
-
-    // Send a larger one to see if we drop things.
-    let mut req = Vec::new();
-    let tmp = &[Request::Hello { version: minder::VERSION.to_string() }];
-    for _ in 0..4 {
-        req.push(&tmp);
-    }
-    minder::hid_encode(&req[..], HidDev(&minder)).unwrap();
-
-    /*
-    // Read and print messages.
-    let mut buf = [0u8; 64];
-    loop {
-        let len = minder.read(&mut buf)?;
-        println!("rep: {:02x?}", &buf[..len]);
+        let algorithm = if hash { Some(port.choose_hash(fast)?) } else { None };
+        port.send(&Message::Dict(Dict::List { algorithm }))?;
+        let dicts = loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for dictionaries")),
+                Some(Message::Dict(Dict::Info { dicts })) => break dicts,
+                Some(packet) => show(&packet),
+            }
+        };
+
+        println!("{:>5} {:<20} {:<10} {:>10} {:>10} {:>8}",
+                 "Index", "Name", "Partition", "Address", "Size", "Entries");
+        for DictInfo { index, name, partition, offset, size, entries, digest } in &dicts {
+            let index = index.map(|i| i.to_string()).unwrap_or_else(|| "-".to_string());
+            print!("{:>5} {:<20} {:<10} 0x{:08x} {:>10} {:>8}",
+                   index, name, partition, partition::FLASH_BASE + offset, size, entries);
+            match digest {
+                Some(digest) => println!(" {}", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+                None => println!(),
+            }
+        }
+        if let Some(algorithm) = algorithm {
+            println!("Hashes are {:?}", algorithm);
+        }
+        Ok(())
     }
-    */
 
-    Ok(())
-}
+    fn get_status(&self) -> Result<Status> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
 
-struct HidDev<'a>(&'a HidDevice);
+        port.send(&Message::Core(Core::GetStatus))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for status")),
+                Some(Message::Core(Core::Status { image, build_id, uptime, usage, dicts, link })) => {
+                    let usage = usage.unwrap_or_default();
+                    return Ok(Status { image, build_id, uptime, usage, dicts, link });
+                }
+                Some(packet) => show(&packet),
+            }
+        }
+    }
 
-impl<'a> HidWrite for HidDev<'a> {
-    type Error = HidError;
+    /// Check an image against the running firmware.  An update must refuse an image that fails
+    /// this check.
+    fn do_image(&self, file: &str) -> Result<()> {
+        let data = read_firmware(file)?;
+        let new = ImageInfo::find(&data)
+            .ok_or_else(|| anyhow!("No image info found in {}", file))?;
+        println!("Image: {} {}{}, built for {}, build id {:08x}",
+                 new.version, new.git, if new.dirty { "-dirty" } else { "" }, new.board,
+                 new.build_id());
 
-    fn write_packet(&mut self, buf: &[u8]) -> std::result::Result<(), Self::Error> {
-        let count = self.0.write(buf)?;
-        if count != buf.len() {
-            println!("Warning: short hid write");
+        let Status { image: running, build_id, .. } = self.get_status()?;
+        if !new.is_for_board(&running.board) {
+            return Err(anyhow!("Image is for {}, but the keyboard is a {}", new.board, running.board));
+        }
+        if new.build_id() == build_id {
+            println!("This image is already running");
         }
         Ok(())
     }
+
+    /// Write a new firmware image to the staging area, check it, and have the keyboard install it.
+    /// The running firmware is untouched until the image has been verified, so an interrupted
+    /// transfer can just be run again.
+    fn do_flash_firmware(&self, file: &str, signature: Option<&str>) -> Result<()> {
+        let image = read_staged_firmware(file)?;
+        let new = ImageInfo::find(&image)
+            .ok_or_else(|| anyhow!("No image info found in {}", file))?;
+        let Status { image: running, .. } = self.get_status()?;
+        if !new.is_for_board(&running.board) {
+            return Err(anyhow!("Image is for {}, but the keyboard is a {}", new.board, running.board));
+        }
+        let signature = match signature {
+            Some(name) => Some(std::fs::read(name)?),
+            None => std::fs::read(format!("{}.sig", file)).ok(),
+        };
+
+        let staging = &partition::STAGING;
+        let size = image.len() as u32;
+        if !staging.contains(staging.offset, size) {
+            return Err(anyhow!("Image of 0x{:x} bytes doesn't fit in {}", size, staging.name));
+        }
+
+        let mut port = self.open()?;
+        // Erasing, and hashing, the whole image can take a while on the device.
+        port.set_timeout(Duration::from_secs(30))?;
+
+        let erase = size.next_multiple_of(SECTOR_SIZE);
+        port.send(&Message::Flash(Flash::Erase { offset: staging.offset, size: erase }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout erasing the staging area")),
+                Some(Message::Flash(Flash::Erased { status: 0, .. })) => break,
+                Some(Message::Flash(Flash::Erased { status, .. })) => {
+                    return Err(anyhow!("Device failed to erase the staging area: error {}", status));
+                }
+                Some(Message::Core(Core::Busy { owner })) => {
+                    return Err(anyhow!("Keyboard is busy with another session ({})", owner));
+                }
+                Some(packet) => show(&packet),
+            }
+        }
+
+        let chunks: Vec<_> = image.chunks(SECTOR_SIZE as usize).collect();
+        for (done, data) in chunks.iter().enumerate() {
+            show_progress(Progress { stage: Stage::Programming, done, total: chunks.len() });
+            let offset = staging.offset + done as u32 * SECTOR_SIZE;
+            port.send(&Message::Flash(Flash::ProgramImage { offset, data: data.to_vec() }))?;
+            loop {
+                match port.read()? {
+                    None => return Err(anyhow!("Timeout programming 0x{:x}", offset)),
+                    Some(Message::Flash(Flash::Programmed { offset: got, status: 0, .. })) if got == offset => {
+                        break
+                    }
+                    Some(Message::Flash(Flash::Programmed { offset: got, status, .. })) if got == offset => {
+                        return Err(anyhow!("Device failed to program 0x{:x}: error {}", offset, status));
+                    }
+                    Some(packet) => show(&packet),
+                }
+            }
+        }
+        show_progress(Progress { stage: Stage::Programming, done: chunks.len(), total: chunks.len() });
+
+        // The keyboard checks the image against this digest again before installing it.
+        let algorithm = HashAlgorithm::Sha256;
+        let expect = algorithm.digest(&image).unwrap();
+        port.send(&Message::Flash(Flash::Hash { offset: staging.offset, size, algorithm: Some(algorithm) }))?;
+        let digest = loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for hash")),
+                Some(Message::Flash(Flash::Digest { digest, .. })) => break digest,
+                Some(packet) => show(&packet),
+            }
+        };
+        if digest != expect {
+            return Err(anyhow!("Staged image does not match {}, run this again to rewrite it", file));
+        }
+
+        port.send(&Message::Flash(Flash::Boot { size, digest, signature }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for the keyboard to install the image")),
+                Some(Message::Flash(Flash::Booting { status: 0 })) => break,
+                Some(Message::Flash(Flash::Booting { status })) => {
+                    return Err(anyhow!("Keyboard refused the image: error {}", status));
+                }
+                Some(packet) => show(&packet),
+            }
+        }
+        println!("Installing {} {}{}, the keyboard will restart",
+                 new.version, new.git, if new.dirty { "-dirty" } else { "" });
+        Ok(())
+    }
+
+    fn do_tape(&self, output: Option<&str>) -> Result<()> {
+        let mut port = self.open()?;
+
+        port.set_timeout(Duration::from_secs(5))?;
+
+        port.send(&Message::Dict(Dict::ReadTape))?;
+
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout waiting for tape")),
+                Some(Message::Dict(Dict::Tape { text, .. })) => {
+                    match output {
+                        Some(name) => std::fs::write(name, text)?,
+                        None => print!("{}", text),
+                    }
+                    return Ok(());
+                }
+                // Log messages can come in before our reply.
+                Some(packet) => show(&packet),
+            }
+        }
+    }
+
+    /// The keyboard stops streaming when the subscription isn't renewed, so there is nothing to do
+    /// on the way out.
+    fn do_monitor(&self, events: Vec<EventKind>) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_millis(250))?;
+
+        let renew = Duration::from_secs(MONITOR_TIMEOUT as u64 / 2);
+        let mut last: Option<Instant> = None;
+        loop {
+            if last.is_none_or(|last| last.elapsed() >= renew) {
+                let events = events.clone();
+                port.send(&Message::Debug(Debug::Subscribe { events, timeout: MONITOR_TIMEOUT }))?;
+                last = Some(Instant::now());
+            }
+            match port.read()? {
+                None => (),
+                // Renewals are acknowledged each time, which isn't interesting.
+                Some(Message::Debug(Debug::Subscribed { .. })) => (),
+                Some(packet) => show(&packet),
+            }
+        }
+    }
+
+    fn do_get_keymap(&self, output: Option<&str>) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        let mut data = Vec::new();
+        loop {
+            let pos = data.len() as u32;
+            port.send(&Message::Keymap(message::Keymap::Get { offset: pos }))?;
+            let size = loop {
+                match port.read()? {
+                    None => return Err(anyhow!("Timeout reading keymap at 0x{:x}", pos)),
+                    Some(Message::Keymap(message::Keymap::Data { offset, size, data: chunk })) if offset == pos => {
+                        if chunk.is_empty() && pos < size {
+                            return Err(anyhow!("Empty keymap read at 0x{:x}", pos));
+                        }
+                        data.extend_from_slice(&chunk);
+                        break size;
+                    }
+                    Some(packet) => show(&packet),
+                }
+            };
+            if data.len() as u32 >= size {
+                break;
+            }
+        }
+
+        let keymap = Keymap::decode(&data)
+            .ok_or_else(|| anyhow!("Keymap from the keyboard doesn't decode"))?;
+        let text = serde_json::to_string_pretty(&keymap)? + "\n";
+        match output {
+            Some(name) => std::fs::write(name, text)?,
+            None => print!("{}", text),
+        }
+        Ok(())
+    }
+
+    fn do_set_keymap(&self, file: &str) -> Result<()> {
+        let keymap: Keymap = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+        keymap.check().map_err(|e| anyhow!("{}: {}", file, e))?;
+        let data = keymap.encode();
+        if data.len() as u32 > partition::KEYMAP.size {
+            return Err(anyhow!("Keymap is 0x{:x} bytes, which doesn't fit in {}",
+                               data.len(), partition::KEYMAP.name));
+        }
+
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        for (pos, chunk) in (0..).step_by(KEYMAP_CHUNK as usize).zip(data.chunks(KEYMAP_CHUNK as usize)) {
+            port.send(&Message::Keymap(message::Keymap::Set {
+                offset: pos,
+                size: data.len() as u32,
+                data: chunk.to_vec(),
+            }))?;
+            loop {
+                match port.read()? {
+                    None => return Err(anyhow!("Timeout sending keymap at 0x{:x}", pos)),
+                    Some(Message::Keymap(message::Keymap::Stored { offset, status })) if offset == pos => {
+                        if status != 0 {
+                            return Err(anyhow!("Keymap rejected at 0x{:x}, status {}", pos, status));
+                        }
+                        break;
+                    }
+                    Some(Message::Core(Core::Busy { owner })) => {
+                        return Err(anyhow!("Session {} is making changes, try again later", owner));
+                    }
+                    Some(packet) => show(&packet),
+                }
+            }
+        }
+        port.send(&Message::Core(Core::Release))?;
+
+        println!("Stored keymap {:?}, {} layers", keymap.name, keymap.layers.len());
+        Ok(())
+    }
+
+    fn do_steno_map(&self) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+        let map = read_steno_map(&mut port)?;
+
+        println!("Steno map {:?}", map.name);
+        for (code, key) in map.keys.iter().enumerate() {
+            match key {
+                None => (),
+                Some(0) => println!("{:>4}  (none)", code),
+                Some(raw) => println!("{:>4}  {}", code, Stroke::from_raw(*raw)),
+            }
+        }
+        Ok(())
+    }
+
+    fn do_calibrate_steno(&self, name: &str) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+        let old = read_steno_map(&mut port)?;
+        port.set_timeout(Duration::from_millis(250))?;
+
+        // Keys that were part of steno stay that way, but only add what they are assigned here.
+        let mut keys: Vec<Option<u32>> = old.keys.iter().map(|key| key.map(|_| 0)).collect();
+
+        println!("Press the key for each steno key as it is named.  Press any others that are the");
+        println!("same steno key as well, then press one of them again to move on, or wait {}s to skip",
+                 CALIBRATE_WAIT.as_secs());
+        println!("it.  What is pressed will also be typed, so choose somewhere that is harmless.");
+
+        let renew = Duration::from_secs(MONITOR_TIMEOUT as u64 / 2);
+        let mut last: Option<Instant> = None;
+        // From the number key, through the steno order.
+        for bit in (0..25).rev() {
+            let stroke = Stroke::from_raw(1 << bit);
+            print!("{:>3}:", stroke.to_string());
+            std::io::stdout().flush()?;
+
+            let mut assigned: Vec<u8> = Vec::new();
+            let mut waiting = Instant::now();
+            while waiting.elapsed() < CALIBRATE_WAIT {
+                if last.is_none_or(|last| last.elapsed() >= renew) {
+                    let events = vec![EventKind::Keys];
+                    port.send(&Message::Debug(Debug::Subscribe { events, timeout: MONITOR_TIMEOUT }))?;
+                    last = Some(Instant::now());
+                }
+                match port.read()? {
+                    Some(Message::Debug(Debug::Key { key, press: true, .. })) => {
+                        if assigned.contains(&key) {
+                            break;
+                        }
+                        if key as usize >= keys.len() {
+                            keys.resize(key as usize + 1, None);
+                        }
+                        keys[key as usize] = Some(stroke.into_raw());
+                        assigned.push(key);
+                        print!(" {}", key);
+                        std::io::stdout().flush()?;
+                        waiting = Instant::now();
+                    }
+                    None | Some(Message::Debug(Debug::Key { .. } | Debug::Subscribed { .. })) => (),
+                    Some(packet) => show(&packet),
+                }
+            }
+            println!("{}", if assigned.is_empty() { " skipped" } else { "" });
+        }
+        port.send(&Message::Debug(Debug::Subscribe { events: Vec::new(), timeout: 0 }))?;
+
+        let map = StenoMap { name: name.to_string(), keys };
+        write_steno_map(&mut port, &map)?;
+        println!("Stored steno map {:?}", map.name);
+        Ok(())
+    }
+
+    fn do_patch_dict(&self, file: &str) -> Result<()> {
+        let data = std::fs::read(file)?;
+        if data.len() as u32 > DICT_PATCH_MAX {
+            return Err(anyhow!("Patch is 0x{:x} bytes, limit is 0x{:x}", data.len(), DICT_PATCH_MAX));
+        }
+
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        for (pos, chunk) in (0..).step_by(SECTOR_SIZE as usize).zip(data.chunks(SECTOR_SIZE as usize)) {
+            port.send(&Message::Dict(Dict::Patch {
+                offset: pos,
+                size: data.len() as u32,
+                data: chunk.to_vec(),
+            }))?;
+            loop {
+                match port.read()? {
+                    None => return Err(anyhow!("Timeout sending patch at 0x{:x}", pos)),
+                    Some(Message::Dict(Dict::Patched { offset, status })) if offset == pos => {
+                        if status != 0 {
+                            return Err(anyhow!("Patch rejected at 0x{:x}, status {}", pos, status));
+                        }
+                        break;
+                    }
+                    Some(Message::Core(Core::Busy { owner })) => {
+                        return Err(anyhow!("Session {} is making changes, try again later", owner));
+                    }
+                    Some(packet) => show(&packet),
+                }
+            }
+        }
+
+        println!("Patched user dictionary, keyboard is restarting");
+        Ok(())
+    }
+
+    fn do_trace_dump(&self, output: Option<&str>) -> Result<()> {
+        let mut port = self.open()?;
+        port.set_timeout(Duration::from_secs(5))?;
+
+        let mut data = Vec::new();
+        loop {
+            let pos = data.len() as u32;
+            port.send(&Message::Debug(Debug::GetTrace { offset: pos }))?;
+            let size = loop {
+                match port.read()? {
+                    None => return Err(anyhow!("Timeout reading trace at 0x{:x}", pos)),
+                    Some(Message::Debug(Debug::Trace { offset, size, data: chunk })) if offset == pos => {
+                        if chunk.is_empty() && pos < size {
+                            return Err(anyhow!("Empty trace read at 0x{:x}", pos));
+                        }
+                        data.extend_from_slice(&chunk);
+                        break size;
+                    }
+                    Some(packet) => show(&packet),
+                }
+            };
+            if data.len() as u32 >= size {
+                break;
+            }
+        }
+
+        if data.is_empty() {
+            println!("The trace is empty, the firmware may have been built without it");
+            return Ok(());
+        }
+        match output {
+            Some(name) => {
+                std::fs::write(name, &data)?;
+                println!("Saved {} events", data.len() / trace::RECORD_SIZE);
+            }
+            None => print!("{}", trace::render(&data)),
+        }
+        Ok(())
+    }
+}
+
+/// Read the steno map in use from the keyboard.
+/// The hosts the keyboard is bonded with.
+fn read_bonds(port: &mut Port) -> Result<Vec<BondInfo>> {
+    port.set_timeout(Duration::from_secs(5))?;
+    port.send(&Message::Ble(Ble::ListBonds))?;
+    loop {
+        match port.read()? {
+            None => return Err(anyhow!("Timeout waiting for bonds, does the firmware have BLE?")),
+            Some(Message::Ble(Ble::Bonds { bonds })) => return Ok(bonds),
+            Some(packet) => show(&packet),
+        }
+    }
+}
+
+/// Find a bonded host by its name, or its address, or the start of its address, as long as only
+/// one host matches.
+fn find_bond(bonds: &[BondInfo], host: &str) -> Result<BleAddress> {
+    if let Some(bond) = bonds.iter().find(|bond| bond.name.as_deref() == Some(host)) {
+        return Ok(bond.address);
+    }
+    let prefix = host.to_uppercase();
+    let found: Vec<_> = bonds.iter().filter(|bond| bond.address.to_string().starts_with(&prefix)).collect();
+    match found.as_slice() {
+        [bond] => Ok(bond.address),
+        [] => Err(anyhow!("No bonded host {}", host)),
+        _ => Err(anyhow!("{} matches {} hosts", host, found.len())),
+    }
+}
+
+/// Make a change to a bond.
+fn change_bond(port: &mut Port, request: Ble) -> Result<()> {
+    port.send(&Message::Ble(request))?;
+    loop {
+        match port.read()? {
+            None => return Err(anyhow!("Timeout waiting for the bond to change")),
+            Some(Message::Ble(Ble::BondChanged { address, status })) => {
+                if status != 0 {
+                    return Err(anyhow!("Bond with {} not changed, status {}", address, status));
+                }
+                break;
+            }
+            Some(Message::Core(Core::Busy { owner })) => {
+                return Err(anyhow!("Session {} is making changes, try again later", owner));
+            }
+            Some(packet) => show(&packet),
+        }
+    }
+    port.send(&Message::Core(Core::Release))?;
+    Ok(())
+}
+
+fn read_steno_map(port: &mut Port) -> Result<StenoMap> {
+    let mut data = Vec::new();
+    loop {
+        let pos = data.len() as u32;
+        port.send(&Message::Keymap(message::Keymap::GetSteno { offset: pos }))?;
+        let size = loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout reading steno map at 0x{:x}", pos)),
+                Some(Message::Keymap(message::Keymap::StenoData { offset, size, data: chunk })) if offset == pos => {
+                    if chunk.is_empty() && pos < size {
+                        return Err(anyhow!("Empty steno map read at 0x{:x}", pos));
+                    }
+                    data.extend_from_slice(&chunk);
+                    break size;
+                }
+                Some(packet) => show(&packet),
+            }
+        };
+        if data.len() as u32 >= size {
+            break;
+        }
+    }
+    StenoMap::decode(&data).ok_or_else(|| anyhow!("Steno map from the keyboard doesn't decode"))
+}
+
+/// Store a steno map on the keyboard, which then uses it.
+fn write_steno_map(port: &mut Port, map: &StenoMap) -> Result<()> {
+    map.check().map_err(|e| anyhow!("{}", e))?;
+    let data = map.encode();
+    port.set_timeout(Duration::from_secs(5))?;
+    for (pos, chunk) in (0..).step_by(KEYMAP_CHUNK as usize).zip(data.chunks(KEYMAP_CHUNK as usize)) {
+        port.send(&Message::Keymap(message::Keymap::SetSteno {
+            offset: pos,
+            size: data.len() as u32,
+            data: chunk.to_vec(),
+        }))?;
+        loop {
+            match port.read()? {
+                None => return Err(anyhow!("Timeout sending steno map at 0x{:x}", pos)),
+                Some(Message::Keymap(message::Keymap::StenoStored { offset, status })) if offset == pos => {
+                    if status != 0 {
+                        return Err(anyhow!("Steno map rejected at 0x{:x}, status {}", pos, status));
+                    }
+                    break;
+                }
+                Some(Message::Core(Core::Busy { owner })) => {
+                    return Err(anyhow!("Session {} is making changes, try again later", owner));
+                }
+                // Key events still in flight from calibration.
+                Some(Message::Debug(Debug::Key { .. } | Debug::Subscribed { .. })) => (),
+                Some(packet) => show(&packet),
+            }
+        }
+    }
+    port.send(&Message::Core(Core::Release))?;
+    Ok(())
+}
+
+/// Read a firmware image, taking the payload out of a UF2 file.
+fn read_firmware(file: &str) -> Result<Vec<u8>> {
+    let data = std::fs::read(file)?;
+    if file.ends_with(".uf2") {
+        uf2_payload(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Extract the payload from a UF2 file, assuming the blocks are contiguous.
+fn uf2_payload(data: &[u8]) -> Result<Vec<u8>> {
+    const MAGIC0: u32 = 0x0a324655;
+    const MAGIC1: u32 = 0x9e5d5157;
+
+    let mut result = Vec::new();
+    for block in data.chunks(512) {
+        let word = |pos: usize| u32::from_le_bytes(block[pos..pos + 4].try_into().unwrap());
+        if block.len() != 512 || word(0) != MAGIC0 || word(4) != MAGIC1 {
+            return Err(anyhow!("Invalid UF2 block"));
+        }
+        let size = (word(16) as usize).min(476);
+        result.extend_from_slice(&block[32..32 + size]);
+    }
+    Ok(result)
+}
+
+/// Read a firmware image as it is staged, padded out to a whole page.  This is what the keyboard
+/// hashes, and so what is signed.
+fn read_staged_firmware(file: &str) -> Result<Vec<u8>> {
+    let mut image = read_firmware(file)?;
+    image.resize(image.len().next_multiple_of(partition::PAGE_SIZE as usize), 0xff);
+    Ok(image)
+}
+
+/// Show the public key of a firmware signing key, making the key first if the file doesn't exist.
+fn firmware_key(file: &str) -> Result<()> {
+    let secret = if std::path::Path::new(file).exists() {
+        read_secret_key(file)?
+    } else {
+        let mut secret = [0u8; sign::SECRET_KEY_SIZE];
+        std::io::Read::read_exact(&mut std::fs::File::open("/dev/urandom")?, &mut secret)?;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        writeln!(options.open(file)?, "{}", to_hex(&secret))?;
+        println!("Made a new signing key in {}", file);
+        secret
+    };
+    println!("BBQ_FIRMWARE_KEY={}", to_hex(&sign::public_key(&secret)));
+    Ok(())
+}
+
+/// Sign the SHA-256 digest of a firmware image, as the keyboard checks it.
+fn sign_firmware(key: &str, file: &str, output: Option<&str>) -> Result<()> {
+    let secret = read_secret_key(key)?;
+    let image = read_staged_firmware(file)?;
+    let info = ImageInfo::find(&image).ok_or_else(|| anyhow!("No image info found in {}", file))?;
+    let digest = HashAlgorithm::Sha256.digest(&image).unwrap();
+
+    let output = output.map(str::to_string).unwrap_or_else(|| format!("{}.sig", file));
+    std::fs::write(&output, sign::sign(&secret, &digest))?;
+    println!("Signed {} {}{} for {}, in {}",
+             info.version, info.git, if info.dirty { "-dirty" } else { "" }, info.board, output);
+    Ok(())
+}
+
+/// Read a secret key, written by [`firmware_key`] in hex.
+fn read_secret_key(file: &str) -> Result<[u8; sign::SECRET_KEY_SIZE]> {
+    let text = std::fs::read_to_string(file)?;
+    let text = text.trim();
+    let key: Option<Vec<u8>> = (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect();
+    key.and_then(|key| key.try_into().ok())
+        .ok_or_else(|| anyhow!("{} doesn't hold a signing key", file))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A port that can communicate with the device.
+struct Port {
+    transport: PortTransport,
+    /// How long a read waits.
+    timeout: Duration,
+    /// The device's pipeline depth, once it has said hello.  See [`Port::pipelined`].
+    pipeline: Option<u8>,
+}
+
+/// The ways of reaching the keyboard.
+enum PortTransport {
+    Serial(SerialTransport<SerialLink>),
+    Hid(HidTransport<HidLink>),
+}
+
+impl Port {
+    /// Open a uart.  Damaged messages are retried with ARQ, unless the keyboard turns out not to
+    /// have it.
+    pub fn new(port: &str) -> Result<Port> {
+        let link = SerialLink(serialport::new(port, 115200).open()?);
+        // Start the sequence somewhere different each time, so the keyboard can tell us apart from
+        // the last run.
+        let arq = Arq::initiator(millis() as u8);
+        let mut port = Port {
+            transport: PortTransport::Serial(SerialTransport::with_arq(link, arq, millis)),
+            timeout: Duration::ZERO,
+            pipeline: None,
+        };
+        port.set_timeout(Duration::from_secs(1))?;
+        Ok(port)
+    }
+
+    /// Open the minder HID interface of the first keyboard found.
+    pub fn hid() -> Result<Port> {
+        let link = HidLink::find()?
+            .ok_or_else(|| anyhow!("No keyboard found over USB HID, a --port is needed to reach it"))?;
+        Ok(Port {
+            transport: PortTransport::Hid(HidTransport::new(link)),
+            timeout: Duration::from_secs(1),
+            pipeline: None,
+        })
+    }
+
+    pub fn send(&mut self, req: &Message) -> Result<()> {
+        match &mut self.transport {
+            PortTransport::Serial(transport) => transport.send(req)?,
+            PortTransport::Hid(transport) => transport.send(req)?,
+        }
+        Ok(())
+    }
+
+    /// Send a request with a sequence id, for its reply to be given back with.
+    pub fn send_seq(&mut self, seq: u32, req: &Message) -> Result<()> {
+        let item = Sequenced { seq, item: req };
+        match &mut self.transport {
+            PortTransport::Serial(transport) => transport.send(&item)?,
+            PortTransport::Hid(transport) => transport.send(&item)?,
+        }
+        Ok(())
+    }
+
+    /// Say hello, learning the hashes the device supports, and how many requests it can have
+    /// outstanding.
+    pub fn hello(&mut self) -> Result<Option<Vec<HashAlgorithm>>> {
+        self.send(&Message::Core(Core::Hello {
+            version: minder::VERSION.to_string(),
+        }))?;
+        loop {
+            match self.read()? {
+                None => return Err(anyhow!("Timeout waiting for hello")),
+                Some(Message::Core(Core::HelloReply { hashes, pipeline, .. })) => {
+                    self.pipeline = pipeline;
+                    return Ok(hashes);
+                }
+                Some(packet) => show(&packet),
+            }
+        }
+    }
+
+    /// Ask the device which hashes it supports, and choose one we both do.  See
+    /// [`HashAlgorithm::choose`].
+    pub fn choose_hash(&mut self, fast: bool) -> Result<HashAlgorithm> {
+        let hashes = self.hello()?;
+        HashAlgorithm::choose(hashes.as_deref(), !fast)
+            .ok_or_else(|| anyhow!("No hash algorithm in common with the device"))
+    }
+
+    /// Send a run of requests, keeping as many outstanding as the device will take, and pass each
+    /// reply to `answer` with the index of the request it is for.  `answer` returns whether the
+    /// reply is the answer it was waiting for.  Replies that aren't are shown.  Without a hello
+    /// that gives the pipeline depth, the requests go one at a time.
+    pub fn pipelined<F>(&mut self, requests: &[Message], mut answer: F) -> Result<()>
+    where
+        F: FnMut(usize, &Message) -> Result<bool>,
+    {
+        let mut window = Window::new(self.pipeline.unwrap_or(0));
+        let mut next = 0;
+        while next < requests.len() || !window.is_empty() {
+            while next < requests.len() && !window.is_full() {
+                let seq = window.send(next);
+                match self.pipeline {
+                    Some(_) => self.send_seq(seq, &requests[next])?,
+                    None => self.send(&requests[next])?,
+                }
+                next += 1;
+            }
+
+            let (seq, reply) = self
+                .read_seq()?
+                .ok_or_else(|| anyhow!("Timeout waiting for a reply"))?;
+            match (self.pipeline, seq) {
+                (Some(_), Some(seq)) => {
+                    let Some(index) = window.complete(seq) else {
+                        show(&reply);
+                        continue;
+                    };
+                    if !answer(index, &reply)? {
+                        return Err(anyhow!("Unexpected reply to request {}", index));
+                    }
+                }
+                // Replies without ids aren't answers, such as log messages.
+                (Some(_), None) => show(&reply),
+                // Without ids, the answer is for the one request outstanding.
+                (None, _) => {
+                    let (seq, index) = window.oldest().map(|(seq, &index)| (seq, index)).unwrap();
+                    if answer(index, &reply)? {
+                        window.complete(seq);
+                    } else {
+                        show(&reply);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.timeout = timeout;
+        match &mut self.transport {
+            // Short reads, so that ARQ gets to resend while we wait.
+            PortTransport::Serial(transport) => {
+                let tick = Duration::from_millis(arq::RETRANSMIT_MS / 2);
+                transport.link().0.set_timeout(timeout.min(tick))?
+            }
+            PortTransport::Hid(transport) => transport.link().set_timeout(timeout),
+        }
+        Ok(())
+    }
+
+    /// Try to read. Returns Ok(None) on timeout.
+    pub fn read(&mut self) -> Result<Option<Message>> {
+        Ok(self.read_seq()?.map(|(_, reply)| reply))
+    }
+
+    /// Try to read, giving the sequence id of the reply, if it has one.  Returns Ok(None) on
+    /// timeout.
+    pub fn read_seq(&mut self) -> Result<Option<(Option<u32>, Message)>> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let packet = match &mut self.transport {
+                PortTransport::Serial(transport) => transport.poll()?,
+                PortTransport::Hid(transport) => transport.poll()?,
+            };
+            let Some(packet) = packet else {
+                if Instant::now() < deadline {
+                    continue;
+                }
+                return Ok(None);
+            };
+            match pipeline::decode_reply(&packet) {
+                Some(reply) => return Ok(Some(reply)),
+                None => eprintln!("Undecodable reply of {} bytes", packet.len()),
+            }
+        }
+    }
+}
+
+/// The serial port, as a minder link.  A read that times out has nothing to return.
+/// The time, in ms, for ARQ's timers.
+fn millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+struct SerialLink(Box<dyn SerialPort>);
+
+impl SerialWrite for SerialLink {
+    type Error = Error;
+
+    fn write_all(&mut self, buf: &[u8]) -> std::result::Result<(), Self::Error> {
+        self.0.write_all(buf)
+    }
+}
+
+impl ByteLink for SerialLink {
+    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, Self::Error> {
+        match self.0.read(buf) {
+            Ok(count) => Ok(count),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The status reported by the keyboard.
+struct Status {
+    image: ImageInfo,
+    build_id: u32,
+    /// Time since boot, in ms.
+    uptime: u64,
+    /// Usage of each mode.  Empty if the firmware doesn't track it.
+    usage: Vec<ModeUsage>,
+    /// The dictionaries in flash.  None if the firmware doesn't report them.
+    dicts: Option<DictStatus>,
+    /// The link between the halves.  None if there isn't one, or the firmware doesn't report it.
+    link: Option<LinkStatus>,
+}
+
+/// Show a progress bar on stderr, overwriting the previous one.
+fn show_progress(progress: Progress) {
+    const WIDTH: usize = 40;
+    let filled = (progress.done * WIDTH).checked_div(progress.total).unwrap_or(WIDTH);
+    let stage = match progress.stage {
+        Stage::Checking => "Checking",
+        Stage::Programming => "Programming",
+    };
+    eprint!("\r{:<12} [{}{}] {}/{}",
+            stage, "#".repeat(filled), " ".repeat(WIDTH - filled), progress.done, progress.total);
+    if progress.done == progress.total {
+        eprintln!();
+    }
+}
+
+/// Work out the keyboard layout the host is set to.  Each place a host keeps it is tried in turn
+/// (XKB, macOS, Windows, then the locale), and the first that names a supported layout is used.
+fn detect_host_layout() -> Result<HostLayout> {
+    let mut hints = Vec::new();
+    if let Some(out) = command_output("setxkbmap", &["-query"]) {
+        hints.extend(out.lines().filter_map(|line| line.strip_prefix("layout:")).map(|l| l.trim().to_string()));
+    }
+    if let Some(out) = command_output(
+        "defaults",
+        &["read", "com.apple.HIToolbox", "AppleCurrentKeyboardLayoutInputSourceID"],
+    ) {
+        hints.push(out.trim().to_string());
+    }
+    if let Some(out) = command_output("reg", &["query", r"HKCU\Keyboard Layout\Preload", "/v", "1"]) {
+        hints.extend(out.split_whitespace().last().map(str::to_string));
+    }
+    for var in ["LC_ALL", "LANG"] {
+        if let Ok(value) = std::env::var(var) {
+            hints.push(value);
+        }
+    }
+
+    for hint in &hints {
+        if let Some(layout) = HostLayout::from_hint(hint) {
+            println!("Detected host layout {} from {:?}", layout.name(), hint);
+            return Ok(layout);
+        }
+    }
+    Err(anyhow!("Couldn't detect the host layout from {:?}, give it instead", hints))
+}
+
+/// The output of a command, if it could be run, and succeeded.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Parse an LED step, given as rrggbb:count.
+fn parse_led_step(text: &str) -> Result<LedStep, String> {
+    let (color, count) = text.split_once(':').ok_or_else(|| format!("{:?} is not rrggbb:count", text))?;
+    if color.len() != 6 {
+        return Err(format!("Color {:?} is not rrggbb", color));
+    }
+    let color = u32::from_str_radix(color, 16).map_err(|e| format!("Color {:?}: {}", color, e))?;
+    let count = count.parse().map_err(|e| format!("Count {:?}: {}", count, e))?;
+    Ok(LedStep { color, count })
+}
+
+fn show(msg: &Message) {
+    match msg {
+        Message::Core(Core::HelloReply { version, info, hashes, pipeline }) => {
+            println!("Hello: {}, {}, hashes: {:?}, pipeline: {:?}", version, info, hashes, pipeline);
+        }
+        Message::Debug(Debug::Log { message }) => {
+            println!("{}", message);
+        }
+        Message::Flash(Flash::Data { offset, data }) => {
+            println!("Read: 0x{:x}, 0x{:x} bytes", offset, data.len());
+        }
+        Message::Dict(Dict::Profile { name }) => {
+            println!("Profile: {}", name);
+        }
+        Message::Flash(Flash::Digest { offset, size, algorithm, digest }) => {
+            println!("Hash: 0x{:x}, 0x{:x} bytes, {:?}: {:02x?}", offset, size, algorithm, digest);
+        }
+        Message::Core(Core::Status { image, build_id, .. }) => {
+            println!("Status: {} {} for {}, build id {:08x}", image.version, image.git, image.board, build_id);
+        }
+        Message::Dict(Dict::Tape { generation, text }) => {
+            println!("Tape: generation {}, {} strokes", generation, text.lines().count() - 1);
+        }
+        Message::Debug(Debug::Output { output }) => {
+            println!("Exec: {}", output);
+        }
+        Message::Dict(Dict::Info { dicts }) => {
+            println!("Dicts: {} dictionaries", dicts.len());
+        }
+        Message::Flash(Flash::Programmed { offset, size, status }) => {
+            println!("Programmed: 0x{:x}, 0x{:x} bytes, status {}", offset, size, status);
+        }
+        Message::Core(Core::Busy { owner }) => {
+            println!("Busy: session {} is making changes", owner);
+        }
+        Message::Keymap(message::Keymap::Data { offset, size, data }) => {
+            println!("Keymap: 0x{:x}, 0x{:x} of 0x{:x} bytes", offset, data.len(), size);
+        }
+        Message::Keymap(message::Keymap::Stored { offset, status }) => {
+            println!("Keymap stored: 0x{:x}, status {}", offset, status);
+        }
+        Message::Debug(Debug::Subscribed { events, timeout }) => {
+            println!("Subscribed: {:?} for {}s", events, timeout);
+        }
+        Message::Debug(Debug::Key { time, key, press }) => {
+            println!("{:>6}.{:03} key {:>3} {}", time / 1000, time % 1000, key,
+                     if *press { "down" } else { "up" });
+        }
+        Message::Debug(Debug::Stroke { time, stroke }) => {
+            println!("{:>6}.{:03} stroke {}", time / 1000, time % 1000, stroke);
+        }
+        Message::Core(Core::Rebooting) => {
+            println!("Rebooting");
+        }
+        Message::Dict(Dict::Patched { offset, status }) => {
+            println!("Dictionary patched: 0x{:x}, status {}", offset, status);
+        }
+        Message::Debug(Debug::Trace { offset, size, data }) => {
+            println!("Trace: 0x{:x}, 0x{:x} of 0x{:x} bytes", offset, data.len(), size);
+        }
+        Message::Keymap(message::Keymap::StenoData { offset, size, data }) => {
+            println!("Steno map: 0x{:x}, 0x{:x} of 0x{:x} bytes", offset, data.len(), size);
+        }
+        Message::Keymap(message::Keymap::StenoStored { offset, status }) => {
+            println!("Steno map stored: 0x{:x}, status {}", offset, status);
+        }
+        Message::Leds(Leds::PatternSet { indicator, status }) => {
+            println!("LED pattern set: {}, status {}", indicator, status);
+        }
+        Message::Core(Core::TextQueued { status }) => {
+            println!("Text queued, status {}", status);
+        }
+        Message::Core(Core::HostLayoutSet { layout }) => {
+            println!("Host layout: {}", layout.name());
+        }
+        Message::Core(Core::UnicodeEntrySet { entry }) => {
+            println!("Unicode entry: {}", entry.name());
+        }
+        Message::Flash(Flash::Erased { offset, size, status }) => {
+            println!("Erased: 0x{:x}, 0x{:x} bytes, status {}", offset, size, status);
+        }
+        Message::Flash(Flash::Booting { status }) => {
+            println!("Booting, status {}", status);
+        }
+        Message::Ble(Ble::BondChanged { address, status }) => {
+            println!("Bond changed: {}, status {}", address, status);
+        }
+        Message::Ble(Ble::Pairing { status }) => {
+            println!("Pairing, status {}", status);
+        }
+        Message::Macros(Macros::Uploaded { slot, status }) => {
+            println!("Macro stored: slot {}, status {}", slot, status);
+        }
+        Message::Macros(Macros::Deleted { name, status }) => {
+            println!("Macro deleted: {}, status {}", name, status);
+        }
+        Message::Debug(Debug::CrashLog { log }) => show_crash_log(log.as_ref()),
+        Message::Stats(Stats::Runtime { stats }) => show_stats(stats),
+        other => println!("Unexpected: {:?}", other),
+    }
+}
+
+fn show_stats(stats: &RuntimeStats) {
+    println!("Uptime: {}.{:03}s", stats.uptime / 1000, stats.uptime % 1000);
+    println!("Heap: {} used, {} free, {} at most", stats.heap_used, stats.heap_free, stats.heap_peak);
+    if !stats.queues.is_empty() {
+        println!("{:<10} {:>8} {:>10}", "Queue", "Size", "Most");
+        for queue in &stats.queues {
+            println!("{:<10} {:>8} {:>10}", queue.name, queue.capacity, queue.high_water);
+        }
+    }
+    println!("Dropped keys: {}", stats.dropped_keys);
+    println!("Scan: {}us, {}us at most, up to {}us late",
+             stats.scan_us, stats.scan_max_us, stats.scan_late_max_us);
+    if stats.strokes > 0 {
+        println!("Strokes: {}, last {}us, mean {}us, {}us at most",
+                 stats.strokes, stats.stroke_us, stats.stroke_mean_us, stats.stroke_max_us);
+    }
+}
+
+fn show_crash_log(log: Option<&CrashLog>) {
+    let Some(log) = log else {
+        println!("No crash reported");
+        return;
+    };
+    println!("Crash: {} (reason {}), after {}.{:03}s", log.cause.name(), log.reason,
+             log.uptime / 1000, log.uptime % 1000);
+    if log.cause != CrashCause::Watchdog {
+        println!("  pc 0x{:08x}, lr 0x{:08x}", log.pc, log.lr);
+    }
+    if !log.text.is_empty() {
+        println!("Last output:");
+        for line in log.text.lines() {
+            println!("  {}", line);
+        }
+    }
 }
-*/
@@ -0,0 +1,452 @@
+//! Retransmission for serial links.
+//!
+//! The serial framing detects damage with its CRC, but a damaged packet is just dropped, and on a
+//! flaky ACM link, a long run of requests, such as a dictionary upload, rarely gets through
+//! intact.  [`Arq`] adds automatic repeat requests on top of the framing: each frame carries a
+//! sequence number, the receiver acknowledges the frames that arrive in order, and asks for the
+//! rest again, and the sender resends anything not acknowledged in time.
+//!
+//! It is go-back-N: up to [`WINDOW`] frames are in flight, the receiver only takes them in order,
+//! and an ACK or NAK of `n` acknowledges everything before `n`.  A NAK also asks for everything
+//! from `n` to be sent again.  The receiver NAKs when it sees a damaged frame, or a gap.
+//!
+//! ARQ frames start with [`ARQ_MARK`], a CBOR break, which can't start a CBOR item, so they don't
+//! get confused with the plain frames of a peer without ARQ.  The initiator, the host, starts the
+//! link with a SYNC frame, which sets where the sequence numbers start, and the responder, the
+//! device, starts its own frames the same way once it has seen one.  Until then, the responder
+//! sends plain frames, so older hosts keep working, and an initiator that hears nothing from a
+//! peer that has never used ARQ falls back to plain frames, for older devices.
+//!
+//! The state machine has no clock of its own: the current time is given to
+//! [`Arq::next_frame`], which is also what sends the frames.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use log::warn;
+use minicbor::Encode;
+
+use crate::encode::serial::serial_frame;
+use crate::SerialDecoder;
+
+/// The first byte of an ARQ frame.
+pub const ARQ_MARK: u8 = 0xff;
+
+/// The kinds of ARQ frame, the byte after the mark.
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const NAK: u8 = 2;
+const SYNC: u8 = 3;
+
+/// The most frames in flight.
+pub const WINDOW: usize = 8;
+
+/// How long to wait for an acknowledgement before sending again, in ms.
+pub const RETRANSMIT_MS: u64 = 200;
+
+/// How many times to send a frame before giving up on it.
+pub const MAX_TRIES: u8 = 10;
+
+/// A frame waiting to be acknowledged.
+struct Outgoing {
+    seq: u8,
+    /// This is the first frame after a reset, and sets where the peer's sequence starts.
+    sync: bool,
+    payload: Vec<u8>,
+}
+
+pub struct Arq {
+    decoder: SerialDecoder,
+    initiator: bool,
+    /// Frames are being sent with ARQ.
+    active: bool,
+    /// The peer has sent an ARQ frame.
+    heard: bool,
+    /// The time as of the last [`Arq::next_frame`].
+    now: u64,
+
+    /// Frames not yet acknowledged, in order.  The first `sent` have been sent.
+    outgoing: VecDeque<Outgoing>,
+    sent: usize,
+    next_seq: u8,
+    /// The next frame doesn't need to be a SYNC.
+    synced: bool,
+    /// When the oldest frame was last sent.
+    sent_at: u64,
+    tries: u8,
+    /// Plain frames, for a peer without ARQ.
+    plain: VecDeque<Vec<u8>>,
+
+    /// The next sequence number expected from the peer, once it has sent a SYNC.
+    expect: Option<u8>,
+    /// The sequence number a NAK was last sent for, to only ask once.
+    naked: Option<u8>,
+    /// The ACK or NAK to send.  Only the latest matters.
+    control: Option<(u8, u8)>,
+}
+
+impl Arq {
+    /// The end that starts the link, the host.  It uses ARQ from its first frame.  Its sequence
+    /// starts at `start`, which should differ from one run to the next, such as by taking it from
+    /// the time, so that the device can tell the SYNC of a new host from one sent again.
+    pub fn initiator(start: u8) -> Arq {
+        Arq { next_seq: start, ..Arq::new(true) }
+    }
+
+    /// The end that answers, the device.  It uses ARQ once the initiator has.
+    pub fn responder() -> Arq {
+        Arq::new(false)
+    }
+
+    fn new(initiator: bool) -> Arq {
+        Arq {
+            decoder: SerialDecoder::new(),
+            initiator,
+            active: initiator,
+            heard: false,
+            now: 0,
+            outgoing: VecDeque::new(),
+            sent: 0,
+            next_seq: 0,
+            synced: false,
+            sent_at: 0,
+            tries: 0,
+            plain: VecDeque::new(),
+            expect: None,
+            naked: None,
+            control: None,
+        }
+    }
+
+    /// Is ARQ in use for the frames being sent.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Have all of the frames sent been acknowledged.
+    pub fn is_idle(&self) -> bool {
+        self.outgoing.is_empty() && self.plain.is_empty()
+    }
+
+    /// Forget any partly received frame, and anything waiting to be sent, such as after the other
+    /// end has gone away.
+    pub fn reset(&mut self) {
+        *self = Arq { next_seq: self.next_seq, ..Arq::new(self.initiator) };
+    }
+
+    /// Queue an item to be sent.  The frames go out through [`Arq::next_frame`].
+    pub fn send<T: Encode<()>>(&mut self, item: &T) {
+        let payload = minicbor::to_vec(item).unwrap();
+        if !self.active {
+            self.plain.push_back(serial_frame(&[], &payload));
+            return;
+        }
+        let sync = !self.synced;
+        self.synced = true;
+        self.outgoing.push_back(Outgoing { seq: self.next_seq, sync, payload });
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+
+    /// The next frame to write to the link, if any, given the time in ms.  Call this until it
+    /// returns None, after receiving, after sending, and every so often, so that frames not
+    /// acknowledged in time are sent again.
+    pub fn next_frame(&mut self, now: u64) -> Option<Vec<u8>> {
+        self.now = now;
+        if let Some((kind, seq)) = self.control.take() {
+            return Some(serial_frame(&[ARQ_MARK, kind, seq], &[]));
+        }
+        if let Some(frame) = self.plain.pop_front() {
+            return Some(frame);
+        }
+
+        if self.sent > 0 && now.saturating_sub(self.sent_at) >= RETRANSMIT_MS {
+            self.tries += 1;
+            if self.tries >= MAX_TRIES {
+                self.give_up();
+                return self.next_frame(now);
+            }
+            // Go back to the oldest.
+            self.sent = 0;
+        }
+
+        if self.sent >= WINDOW {
+            return None;
+        }
+        let frame = self.outgoing.get(self.sent)?;
+        let kind = if frame.sync { SYNC } else { DATA };
+        let frame = serial_frame(&[ARQ_MARK, kind, frame.seq], &frame.payload);
+        if self.sent == 0 {
+            self.sent_at = now;
+        }
+        self.sent += 1;
+        Some(frame)
+    }
+
+    /// Add a single byte received, returning the CBOR of the next packet to arrive in order.
+    pub fn add_packet(&mut self, byte: u8) -> Option<Vec<u8>> {
+        let Some(packet) = self.decoder.add_packet(byte) else {
+            if self.decoder.take_dropped() {
+                self.nak();
+            }
+            return None;
+        };
+        match *packet {
+            [ARQ_MARK, kind, seq, ref payload @ ..] => {
+                let payload = payload.to_vec();
+                self.frame(kind, seq, payload)
+            }
+            _ => {
+                let packet = packet.to_vec();
+                // The host has been replaced with one without ARQ.
+                if !self.initiator && self.active {
+                    self.reset();
+                }
+                Some(packet)
+            }
+        }
+    }
+
+    /// Handle an ARQ frame.
+    fn frame(&mut self, kind: u8, seq: u8, payload: Vec<u8>) -> Option<Vec<u8>> {
+        self.heard = true;
+        match kind {
+            ACK => {
+                self.acked(seq);
+                None
+            }
+            NAK => {
+                self.acked(seq);
+                self.sent = 0;
+                None
+            }
+            SYNC if self.expect == Some(seq.wrapping_add(1)) => {
+                // Our ACK was lost.
+                self.control = Some((ACK, seq.wrapping_add(1)));
+                None
+            }
+            SYNC => {
+                if !self.initiator {
+                    // A new host: anything for the old one is stale.
+                    self.active = true;
+                    self.outgoing.clear();
+                    self.sent = 0;
+                    self.tries = 0;
+                    self.synced = false;
+                }
+                self.receive(seq, payload)
+            }
+            DATA => match self.expect {
+                Some(expect) if seq == expect => self.receive(seq, payload),
+                Some(expect) => {
+                    if (expect.wrapping_sub(seq) as usize) <= WINDOW {
+                        // A frame sent again, after we had it.
+                        self.control = Some((ACK, expect));
+                    } else {
+                        self.nak();
+                    }
+                    None
+                }
+                // Waiting for a SYNC.
+                None => None,
+            },
+            _ => {
+                warn!("arq: unknown frame kind {}", kind);
+                None
+            }
+        }
+    }
+
+    /// Take a frame that arrived in order.
+    fn receive(&mut self, seq: u8, payload: Vec<u8>) -> Option<Vec<u8>> {
+        let expect = seq.wrapping_add(1);
+        self.expect = Some(expect);
+        self.naked = None;
+        self.control = Some((ACK, expect));
+        Some(payload)
+    }
+
+    /// Ask for the frames from the one expected, once.
+    fn nak(&mut self) {
+        if let Some(expect) = self.expect {
+            if self.naked != Some(expect) {
+                self.naked = Some(expect);
+                self.control = Some((NAK, expect));
+            }
+        }
+    }
+
+    /// The peer has everything before `seq`.
+    fn acked(&mut self, seq: u8) {
+        let Some(front) = self.outgoing.front() else {
+            return;
+        };
+        let count = seq.wrapping_sub(front.seq) as usize;
+        if count == 0 || count > self.sent {
+            return;
+        }
+        self.outgoing.drain(..count);
+        self.sent -= count;
+        self.sent_at = self.now;
+        self.tries = 0;
+    }
+
+    /// Nothing is getting through.
+    fn give_up(&mut self) {
+        if self.initiator && !self.heard {
+            // The peer may not have ARQ at all.
+            warn!("arq: no answer, falling back to plain frames");
+            self.active = false;
+            for frame in self.outgoing.drain(..) {
+                self.plain.push_back(serial_frame(&[], &frame.payload));
+            }
+        } else {
+            warn!("arq: giving up on {} frames", self.outgoing.len());
+            self.outgoing.clear();
+            // The peer may have lost track, so start again.
+            self.synced = false;
+        }
+        self.sent = 0;
+        self.tries = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    use super::*;
+    use crate::{Request, SerialDecoder};
+
+    /// Pass frames between two ends, for a while, with `damage` deciding what happens to each
+    /// frame, by the count of frames so far.  Returns what each end received.
+    fn run(
+        a: &mut Arq,
+        b: &mut Arq,
+        mut damage: impl FnMut(usize, &mut Vec<u8>) -> bool,
+    ) -> (Vec<Request>, Vec<Request>) {
+        let mut got = (Vec::new(), Vec::new());
+        let mut count = 0;
+        for now in (0..10_000).step_by(10) {
+            pass(a, b, now, &mut count, &mut damage, &mut got.1);
+            pass(b, a, now, &mut count, &mut damage, &mut got.0);
+        }
+        got
+    }
+
+    fn pass(
+        from: &mut Arq,
+        to: &mut Arq,
+        now: u64,
+        count: &mut usize,
+        damage: &mut impl FnMut(usize, &mut Vec<u8>) -> bool,
+        got: &mut Vec<Request>,
+    ) {
+        while let Some(mut frame) = from.next_frame(now) {
+            *count += 1;
+            if !damage(*count, &mut frame) {
+                continue;
+            }
+            for byte in frame {
+                if let Some(packet) = to.add_packet(byte) {
+                    got.push(minicbor::decode(&packet).unwrap());
+                }
+            }
+        }
+    }
+
+    fn requests(count: u32) -> Vec<Request> {
+        (0..count).map(|offset| Request::ReadFlash { offset, size: 1 }).collect()
+    }
+
+    #[test]
+    fn test_clean() {
+        let mut host = Arq::initiator(250);
+        let mut device = Arq::responder();
+        let sent = requests(40);
+        for req in &sent {
+            host.send(req);
+        }
+        device.send(&Request::ReadFlash { offset: 0, size: 64 });
+        let (to_host, to_device) = run(&mut host, &mut device, |_, _| true);
+        assert_eq!(to_device, sent);
+        // The device hadn't heard from an ARQ host yet.
+        assert_eq!(to_host, vec![Request::ReadFlash { offset: 0, size: 64 }]);
+        assert!(device.is_active());
+        assert!(host.is_idle() && device.is_idle());
+    }
+
+    #[test]
+    fn test_damage() {
+        let mut host = Arq::initiator(250);
+        let mut device = Arq::responder();
+        let sent = requests(100);
+        for req in &sent {
+            host.send(req);
+        }
+        // Corrupt some frames, drop others, in both directions.
+        let (_, to_device) = run(&mut host, &mut device, |count, frame| {
+            if count % 7 == 0 {
+                let mid = frame.len() / 2;
+                frame[mid] ^= 0x01;
+            }
+            count % 11 != 0
+        });
+        assert_eq!(to_device, sent);
+
+        // And replies, the other way.
+        for req in &sent {
+            device.send(req);
+        }
+        let (to_host, _) = run(&mut host, &mut device, |count, _| count % 5 != 0);
+        assert_eq!(to_host, sent);
+        assert!(host.is_idle() && device.is_idle());
+    }
+
+    #[test]
+    fn test_old_device() {
+        // A device without ARQ ignores the frames it can't decode, and answers in plain frames.
+        let mut host = Arq::initiator(250);
+        let req = Request::Hello { version: "test".to_string() };
+        host.send(&req);
+        let mut decoder = SerialDecoder::new();
+        let mut got = Vec::new();
+        for now in (0..10_000).step_by(10) {
+            while let Some(frame) = host.next_frame(now) {
+                for byte in frame {
+                    if let Some(item) = decoder.add_decode::<Request>(byte) {
+                        got.push(item);
+                    }
+                }
+            }
+        }
+        assert_eq!(got, vec![req]);
+        assert!(!host.is_active());
+    }
+
+    #[test]
+    fn test_new_host() {
+        let mut host = Arq::initiator(0);
+        let mut device = Arq::responder();
+        host.send(&Request::ReadFlash { offset: 0, size: 64 });
+        run(&mut host, &mut device, |_, _| true);
+
+        // The host goes away, with a reply unread, and another starts over.
+        device.send(&Request::ReadFlash { offset: 0, size: 64 });
+        let mut host = Arq::initiator(100);
+        let sent = requests(3);
+        for req in &sent {
+            host.send(req);
+        }
+        let (to_host, to_device) = run(&mut host, &mut device, |_, _| true);
+        assert_eq!(to_device, sent);
+        assert!(to_host.is_empty());
+
+        // And one without ARQ.
+        let mut buf = Vec::new();
+        crate::serial_encode(&Request::ReadFlash { offset: 0, size: 64 }, &mut buf, true).unwrap();
+        let got: Vec<_> = buf.into_iter().filter_map(|byte| device.add_packet(byte)).collect();
+        assert_eq!(got.len(), 1);
+        assert!(!device.is_active());
+    }
+}
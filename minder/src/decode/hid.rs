@@ -69,6 +69,21 @@ impl HidDecoder {
         }
     }
 
+    /// Forget any partly received packet.
+    pub fn reset(&mut self) {
+        self.state = State::Empty;
+        self.buffer.clear();
+    }
+
+    /// Take the assembled packet, if one is ready, leaving the decoder ready for the next.
+    pub fn take_packet(&mut self) -> Option<Vec<u8>> {
+        if !self.is_ready() {
+            return None;
+        }
+        self.state = State::Empty;
+        Some(core::mem::take(&mut self.buffer))
+    }
+
     pub fn is_ready(&self) -> bool {
         match self.state {
             State::Ready => true,
@@ -20,6 +20,8 @@ pub struct SerialDecoder {
     quoting: bool,
     /// The current packet being assembled.
     buffer: Vec<u8>,
+    /// A packet was discarded as damaged since the last [`SerialDecoder::take_dropped`].
+    dropped: bool,
 }
 
 impl SerialDecoder {
@@ -28,9 +30,23 @@ impl SerialDecoder {
             inside: false,
             quoting: false,
             buffer: Vec::new(),
+            dropped: false,
         }
     }
 
+    /// Forget any partly received packet.
+    pub fn reset(&mut self) {
+        self.inside = false;
+        self.quoting = false;
+        self.buffer.clear();
+    }
+
+    /// Has a packet been discarded as damaged, by a bad CRC, bad quoting, or being too long, since
+    /// this was last asked.  Packets that never started aren't counted.
+    pub fn take_dropped(&mut self) -> bool {
+        core::mem::take(&mut self.dropped)
+    }
+
     /// Add a single byte, and decode if that makes sense.  This keeps things fairly simple, and
     /// makes it easier to deal with packate boundaries not lining up with the boundaries of the
     /// received data.
@@ -38,8 +54,20 @@ impl SerialDecoder {
     where
         T: Decode<'a, ()>,
     {
+        let packet = self.add_packet(byte)?;
+        let res = minicbor::decode(packet);
+        if let Err(ref e) = res {
+            warn!("cbor decode: {:?}", e);
+        }
+        res.ok()
+    }
+
+    /// Add a single byte, returning the cbor of the packet once a complete, valid one has been
+    /// received.  This is useful when the packet might be one of several types.
+    pub fn add_packet(&mut self, byte: u8) -> Option<&[u8]> {
         // If the buffer is overflow, discard the rest of this packet.
         if self.buffer.len() >= MAX_PACKET {
+            self.dropped = true;
             self.inside = false;
             self.quoting = false;
             self.buffer.clear();
@@ -47,7 +75,9 @@ impl SerialDecoder {
 
         match byte {
             START => {
-                // No matter what, forget what we've seen and start a new packet.
+                // No matter what, forget what we've seen and start a new packet.  A packet still
+                // going has lost its end.
+                self.dropped |= self.inside;
                 self.buffer.clear();
                 self.inside = true;
                 self.quoting = false;
@@ -55,6 +85,7 @@ impl SerialDecoder {
             QUOTE => {
                 if !self.inside || self.quoting {
                     // Invalid state, discard.
+                    self.dropped |= self.inside;
                     self.inside = false;
                     self.quoting = false;
                     return None;
@@ -64,6 +95,7 @@ impl SerialDecoder {
             END | END_CRC => {
                 // If quoting, this is an error.
                 if !self.inside || self.quoting {
+                    self.dropped |= self.inside;
                     self.inside = false;
                     self.quoting = false;
                     return None;
@@ -82,22 +114,18 @@ impl SerialDecoder {
                     } else {
                         // CRC mismatch, discard the packet.
                         warn!("crc mismatch");
+                        self.dropped = true;
                         self.inside = false;
                         self.buffer.clear();
                         return None;
                     }
                 }
 
-                let res = minicbor::decode(&self.buffer);
-                if let Err(ref e) = res {
-                    warn!("cbor decode: {:?}", e);
-                }
-                let res = res.ok();
                 self.inside = false;
                 // We can't clear the buffer yet, because the returned data can have references to
                 // it.  Instead clear it on the next packet received.
                 // self.buffer.clear();
-                return res;
+                return Some(&self.buffer);
             }
             byte => {
                 if !self.inside {
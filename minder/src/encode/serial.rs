@@ -3,8 +3,8 @@
 //! Encode a CBOR packet for sending over a serial port (or something like a serial port).  The
 //! data stream uses simple framing to be able to recover from various data errors.
 //!
-//! Note that, as of this point, this protocol is too simplistic to use over an actual serial port,
-//! and only adds framing support.  CRCs and such would need to be added for real serial port use.
+//! The framing, with its CRC, only detects damage; a damaged packet is simply dropped.  Retrying
+//! is left to [`crate::arq`], which sends its own frames through here.
 
 use core::convert::Infallible;
 
@@ -80,15 +80,31 @@ pub fn serial_encode<T: Encode<()>, W: SerialWrite>(
     let mut buf = VecWrite::new(use_crc);
     buf.buffer.push(START);
     minicbor::encode(item, &mut buf).unwrap();
-    if use_crc {
-        // Compute the CRC, not on the CRC value itself.
-        let res = buf.crc.take().unwrap().finalize();
-        let res = [(res & 0xff) as u8, (res >> 8) as u8];
-        buf.write_all(&res).unwrap();
-        buf.buffer.push(END_CRC);
-    } else {
-        buf.buffer.push(END);
-    }
+    write.write_all(&buf.finish())
+}
+
+/// Frame a packet that is already encoded, after a `header`, always with a CRC.  This is how
+/// [`crate::arq`] sends its frames.
+pub(crate) fn serial_frame(header: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut buf = VecWrite::new(true);
+    buf.buffer.push(START);
+    buf.write_all(header).unwrap();
+    buf.write_all(payload).unwrap();
+    buf.finish()
+}
 
-    write.write_all(&buf.buffer)
+impl VecWrite {
+    /// Add the CRC, if there is one, and the end of the packet.
+    fn finish(mut self) -> Vec<u8> {
+        if let Some(crc) = self.crc.take() {
+            // Compute the CRC, not on the CRC value itself.
+            let res = crc.finalize();
+            let res = [(res & 0xff) as u8, (res >> 8) as u8];
+            self.write_all(&res).unwrap();
+            self.buffer.push(END_CRC);
+        } else {
+            self.buffer.push(END);
+        }
+        self.buffer
+    }
 }
@@ -0,0 +1,115 @@
+//! Hashing of flash regions.
+//!
+//! The host can ask the device to hash a region of flash, to check that what is there matches what
+//! it expects without having to read it all back.  Different devices are able to compute different
+//! hashes at very different speeds (some have hardware CRC, but a slow SHA-256), so the device
+//! reports which algorithms it supports in its Hello reply, and the host picks one.
+//!
+//! The algorithms beyond CRC32 are behind features, so that firmware that doesn't want the code
+//! size can leave them out.
+
+use alloc::vec::Vec;
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use minicbor::{Decode, Encode};
+
+/// The hash algorithms.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Encode, Decode)]
+#[cbor(index_only)]
+pub enum HashAlgorithm {
+    #[n(0)]
+    Crc32,
+    #[n(1)]
+    Sha256,
+    #[n(2)]
+    Blake2s,
+}
+
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+impl HashAlgorithm {
+    /// The algorithms this build supports.
+    pub fn supported() -> Vec<HashAlgorithm> {
+        [
+            Some(HashAlgorithm::Crc32),
+            cfg!(feature = "sha256").then_some(HashAlgorithm::Sha256),
+            cfg!(feature = "blake2s").then_some(HashAlgorithm::Blake2s),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Is this algorithm suitable for checking that an image hasn't been corrupted or tampered
+    /// with, rather than just catching transfer errors.
+    pub fn is_secure(self) -> bool {
+        !matches!(self, HashAlgorithm::Crc32)
+    }
+
+    /// Choose an algorithm both we and the device support.  The device's list comes from its
+    /// Hello reply, and None (an older device) means only SHA-256.  If `secure` is set, only a
+    /// cryptographic hash will be chosen, preferring SHA-256.  Otherwise, the fastest is chosen.
+    pub fn choose(device: Option<&[HashAlgorithm]>, secure: bool) -> Option<HashAlgorithm> {
+        let device = device.unwrap_or(&[HashAlgorithm::Sha256]);
+        let ours = Self::supported();
+        let order: &[HashAlgorithm] = if secure {
+            &[HashAlgorithm::Sha256, HashAlgorithm::Blake2s]
+        } else {
+            &[HashAlgorithm::Crc32, HashAlgorithm::Blake2s, HashAlgorithm::Sha256]
+        };
+        order
+            .iter()
+            .copied()
+            .find(|alg| device.contains(alg) && ours.contains(alg))
+    }
+
+    /// Compute the hash of the data.  Returns None if this build doesn't support the algorithm.
+    pub fn digest(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            HashAlgorithm::Crc32 => Some(CRC32.checksum(data).to_le_bytes().to_vec()),
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => {
+                use sha2::Digest;
+                Some(sha2::Sha256::digest(data).to_vec())
+            }
+            #[cfg(feature = "blake2s")]
+            HashAlgorithm::Blake2s => {
+                use blake2::Digest;
+                Some(blake2::Blake2s256::digest(data).to_vec())
+            }
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HashAlgorithm;
+
+    #[test]
+    fn test_choose() {
+        let all = HashAlgorithm::supported();
+        assert_eq!(HashAlgorithm::choose(Some(&all), false), Some(HashAlgorithm::Crc32));
+        assert_eq!(HashAlgorithm::choose(Some(&all), true), Some(HashAlgorithm::Sha256));
+        assert_eq!(HashAlgorithm::choose(None, false), Some(HashAlgorithm::Sha256));
+        assert_eq!(
+            HashAlgorithm::choose(Some(&[HashAlgorithm::Crc32, HashAlgorithm::Blake2s]), true),
+            Some(HashAlgorithm::Blake2s)
+        );
+        assert_eq!(HashAlgorithm::choose(Some(&[HashAlgorithm::Crc32]), true), None);
+    }
+
+    #[test]
+    fn test_digest() {
+        for alg in HashAlgorithm::supported() {
+            let a = alg.digest(b"hello").unwrap();
+            let b = alg.digest(b"hellp").unwrap();
+            assert_ne!(a, b);
+        }
+        assert_eq!(
+            HashAlgorithm::Crc32.digest(b"123456789").unwrap(),
+            0xcbf43926u32.to_le_bytes()
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Firmware image metadata.
+//!
+//! Each firmware image carries a small record describing how it was built.  The firmware reports
+//! this in reply to GetStatus, and host tools can find it within an image file, which lets an
+//! update refuse an image built for a different board.
+//!
+//! The record is stored as tagged CBOR, in a block of [`IMAGE_INFO_SIZE`] bytes at
+//! [`IMAGE_INFO_OFFSET`] from the start of the image, so the tools can read it without knowing
+//! anything else about the image.  Each firmware's linker script puts its `.image_info` section
+//! there.  All of the targets are RP2040s, where the image starts with the second stage boot
+//! loader, then the vector table, and the block comes right after them.
+//!
+//! The timestamp is the source time (the commit time, or `SOURCE_DATE_EPOCH`) and not the time of
+//! the build, so building the same commit produces the same metadata, and the same build id.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use minicbor::{Decode, Encode};
+
+/// Where the image info is, from the start of the image.
+pub const IMAGE_INFO_OFFSET: usize = 0x200;
+
+/// The size of the block holding the image info.  The rest of the block is padded with 0xff.
+pub const IMAGE_INFO_SIZE: usize = 0x100;
+
+/// Information about a firmware image.
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+#[cbor(tag(0x696d616765696e66))]
+#[cbor(map)]
+pub struct ImageInfo {
+    /// The version of the firmware, from its Cargo.toml.
+    #[n(1)]
+    pub version: String,
+    /// The git commit the image was built from.
+    #[n(2)]
+    pub git: String,
+    /// Were there uncommitted changes when built.
+    #[n(3)]
+    pub dirty: bool,
+    /// Source timestamp, in seconds since the Unix epoch.
+    #[n(4)]
+    pub timestamp: u64,
+    /// The board this image is built for.
+    #[n(5)]
+    pub board: String,
+}
+
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+impl ImageInfo {
+    /// A short identifier for this build, derived from the metadata.
+    pub fn build_id(&self) -> u32 {
+        let mut buf = Vec::new();
+        minicbor::encode(self, &mut buf).unwrap();
+        CRC32.checksum(&buf)
+    }
+
+    /// Can this image be installed on the given board.
+    pub fn is_for_board(&self, board: &str) -> bool {
+        self.board == board
+    }
+
+    /// Decode image info from the start of the buffer.
+    pub fn from_bytes(buf: &[u8]) -> Option<ImageInfo> {
+        minicbor::decode(buf).ok()
+    }
+
+    /// Read the image info from its block within the given image.
+    pub fn find(image: &[u8]) -> Option<ImageInfo> {
+        Self::from_bytes(image.get(IMAGE_INFO_OFFSET..IMAGE_INFO_OFFSET + IMAGE_INFO_SIZE)?)
+    }
+
+    /// Encode the image info into its block, for a build script to place in the image.  Panics if
+    /// it doesn't fit.
+    pub fn to_block(&self) -> [u8; IMAGE_INFO_SIZE] {
+        let mut buf = Vec::new();
+        minicbor::encode(self, &mut buf).unwrap();
+        assert!(buf.len() <= IMAGE_INFO_SIZE, "Image info is {} bytes", buf.len());
+        let mut block = [0xff; IMAGE_INFO_SIZE];
+        block[..buf.len()].copy_from_slice(&buf);
+        block
+    }
+
+    /// The image info for the firmware being built, from a build script.  The board is given by
+    /// the build.
+    ///
+    /// Everything here comes from the source, and not the time of the build, so that the same
+    /// commit builds the same metadata.
+    #[cfg(feature = "build")]
+    pub fn for_build(board: &str) -> ImageInfo {
+        let _ = build_data::rerun_if_git_commit_or_branch_changed();
+        println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+        ImageInfo {
+            version: std::env::var("CARGO_PKG_VERSION").unwrap(),
+            git: build_data::get_git_commit_short().unwrap_or_else(|_| "unknown".into()),
+            dirty: build_data::get_git_dirty().unwrap_or(false),
+            timestamp: build_data::get_source_time().unwrap_or(0),
+            board: board.into(),
+        }
+    }
+
+    /// Retrieve CONFIG_BOARD from the Zephyr generated .config, for a build script of a Zephyr
+    /// application.
+    #[cfg(feature = "build")]
+    pub fn zephyr_board() -> Option<String> {
+        let config = std::fs::read_to_string(std::env::var("DOTCONFIG").ok()?).ok()?;
+        config.lines().find_map(|line| {
+            let value = line.strip_prefix("CONFIG_BOARD=")?;
+            Some(value.trim_matches('"').to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ImageInfo, IMAGE_INFO_OFFSET};
+
+    #[test]
+    fn test_find() {
+        let info = ImageInfo {
+            version: "0.1.0".to_string(),
+            git: "0123abcd".to_string(),
+            dirty: false,
+            timestamp: 1_700_000_000,
+            board: "jolt2".to_string(),
+        };
+        let mut image = vec![0x55u8; IMAGE_INFO_OFFSET];
+        image.extend_from_slice(&info.to_block());
+        image.extend_from_slice(&[0xaa; 100]);
+
+        assert_eq!(ImageInfo::find(&image), Some(info.clone()));
+        assert_eq!(ImageInfo::find(&image[..IMAGE_INFO_OFFSET + 10]), None);
+        // Only the block is looked at.
+        let mut moved = vec![0x55u8; 0x10];
+        moved.extend_from_slice(&image);
+        assert_eq!(ImageInfo::find(&moved), None);
+        assert!(info.is_for_board("jolt2"));
+        assert!(!info.is_for_board("proto4"));
+
+        let mut other = info.clone();
+        other.dirty = true;
+        assert_ne!(info.build_id(), other.build_id());
+    }
+}
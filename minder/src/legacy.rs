@@ -0,0 +1,127 @@
+//! Bare requests and replies, from before messages had topics.
+//!
+//! Older hosts send these untagged, and expect to be answered in kind.  The set is frozen at what
+//! those hosts know: each one is decoded into the [`Message`] it stands for, and a message is only
+//! sent back in this form if it has one here.  New messages go in their topic (see
+//! [`crate::message`]).
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use minicbor::{Decode, Encode};
+
+use crate::message::{Core, Debug, Flash, Message};
+
+/// A bare request.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Request {
+    /// See [`Core::Hello`].
+    #[n(1)]
+    Hello {
+        #[n(1)]
+        version: String,
+    },
+    /// See [`Flash::Read`].
+    #[n(2)]
+    ReadFlash {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        size: u32,
+    },
+}
+
+/// A bare reply.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Reply {
+    /// See [`Core::HelloReply`].
+    #[n(1)]
+    Hello {
+        #[n(1)]
+        version: String,
+        #[n(2)]
+        info: String,
+    },
+    /// See [`Debug::Log`].
+    #[n(2)]
+    Log {
+        #[n(1)]
+        message: String,
+    },
+    /// See [`Flash::Data`].
+    #[n(3)]
+    FlashData {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        data: Vec<u8>,
+    },
+}
+
+impl From<Request> for Message {
+    fn from(request: Request) -> Message {
+        match request {
+            Request::Hello { version } => Message::Core(Core::Hello { version }),
+            Request::ReadFlash { offset, size } => Message::Flash(Flash::Read { offset, size }),
+        }
+    }
+}
+
+impl From<Reply> for Message {
+    fn from(reply: Reply) -> Message {
+        match reply {
+            Reply::Hello { version, info } => {
+                Message::Core(Core::HelloReply { version, info, hashes: None, pipeline: None })
+            }
+            Reply::Log { message } => Message::Debug(Debug::Log { message }),
+            Reply::FlashData { offset, data } => Message::Flash(Flash::Data { offset, data }),
+        }
+    }
+}
+
+/// The bare form of a message, for answering an older host.  Messages without one are returned.
+/// An older host doesn't know about the newer hello fields, so they are left off.
+impl TryFrom<Message> for Reply {
+    type Error = Message;
+
+    fn try_from(message: Message) -> Result<Reply, Message> {
+        Ok(match message {
+            Message::Core(Core::HelloReply { version, info, .. }) => Reply::Hello { version, info },
+            Message::Debug(Debug::Log { message }) => Reply::Log { message },
+            Message::Flash(Flash::Data { offset, data }) => Reply::FlashData { offset, data },
+            other => return Err(other),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::message::{Dict, Stats};
+
+    #[test]
+    fn test_legacy() {
+        // Bare requests decode as the message they stand for.
+        let buf = minicbor::to_vec(Request::ReadFlash { offset: 0x1000, size: 256 }).unwrap();
+        assert_eq!(Message::decode_request(&buf),
+                   Some((Message::Flash(Flash::Read { offset: 0x1000, size: 256 }), true)));
+
+        // And the replies go back, if they have a bare form.
+        let reply = Message::Core(Core::HelloReply {
+            version: "1.0".to_string(),
+            info: "jolt".to_string(),
+            hashes: None,
+            pipeline: Some(4),
+        });
+        assert_eq!(Reply::try_from(reply),
+                   Ok(Reply::Hello { version: "1.0".to_string(), info: "jolt".to_string() }));
+        assert!(Reply::try_from(Message::Dict(Dict::Profile { name: "editor".to_string() })).is_err());
+        assert!(Reply::try_from(Message::Stats(Stats::Usage { modes: Vec::new() })).is_err());
+
+        // A bare reply is decoded by the host as its message.
+        let buf = minicbor::to_vec(Reply::Log { message: "ok".to_string() }).unwrap();
+        assert_eq!(Message::decode_reply(&buf), Some(Message::Debug(Debug::Log { message: "ok".to_string() })));
+    }
+}
@@ -12,8 +12,15 @@
 //! We could enable certain reports automatically, but as HID doesn't have a concept of a
 //! connection, the keyboard would receive no notification if the monitoring tool were disconnected.
 //! As such, reports will only be generated on-demand, and the protocol will implement a fairly
-//! strict request/reply, in the manner of a REST API.  The messages a encoded in a Request, and
-//! Reply enum.
+//! strict request/reply, in the manner of a REST API.  The messages are encoded in a [`Message`],
+//! which is routed by its [`Topic`], and older hosts can still send the bare requests they know
+//! (see [`legacy`]).  A host can have several requests outstanding by giving them sequence ids (see
+//! [`pipeline`]).
+//!
+//! The one exception is [`message::Debug::Subscribe`], which asks for key events and steno strokes to be
+//! streamed as they happen.  The subscription only lasts a few seconds, and the host has to renew
+//! it to keep the events coming, so streaming stops soon after the monitor goes away (see
+//! [`stream`]).
 //!
 //! The encoding used by minicbor is intended to be robust against upgrades.  There is a hello
 //! request and reply that can be used to learn various information about the devices, but this
@@ -28,61 +35,465 @@ use alloc::vec::Vec;
 
 use minicbor::{Decode, Encode};
 
+pub mod arq;
 mod decode;
 mod encode;
+pub mod hash;
+pub mod image;
+pub mod legacy;
+pub mod message;
+pub mod partition;
+pub mod pipeline;
+pub mod session;
+#[cfg(feature = "ed25519")]
+pub mod sign;
+pub mod stream;
+pub mod transport;
 
+pub use arq::Arq;
 pub use decode::{HidDecoder, SerialDecoder};
 pub use encode::{HidWrite, hid_encode, SerialWrite, serial_encode};
+pub use hash::HashAlgorithm;
+pub use image::ImageInfo;
+pub use legacy::{Reply, Request};
+pub use message::{Message, Topic};
+pub use session::{Arbiter, SessionId};
+pub use stream::{EventKind, Stream};
+pub use transport::Transport;
 
 pub const PACKET_SIZE: usize = 64;
 
+/// The most keymap data carried by one [`message::Keymap::Set`] or [`message::Keymap::Data`], and
+/// steno map data by their steno map counterparts.  This keeps each of these messages within a
+/// single packet.
+pub const KEYMAP_CHUNK: u32 = 32;
+
+/// The most trace data carried by one [`message::Debug::Trace`], keeping it within a single packet.
+pub const TRACE_CHUNK: u32 = 32;
+
+/// The largest dictionary patch that can be sent with [`message::Dict::Patch`].
+pub const DICT_PATCH_MAX: u32 = 0x4000;
+
+/// The most steps in an LED pattern sent with [`message::Leds::SetPattern`].
+pub const MAX_LED_STEPS: usize = 8;
+
+/// The longest text, in characters, that can be sent with [`message::Core::TypeText`].
+pub const TYPE_TEXT_MAX: usize = 1024;
+
+/// The longest name, in bytes, that can be given to a BLE bond with [`message::Ble::NameBond`].
+pub const BOND_NAME_MAX: usize = 32;
+
 // The version of the protocol described here.
 pub static VERSION: &'static str = "2024-11-01a";
 
-#[derive(Debug, Encode, Decode, Eq, PartialEq)]
-pub enum Request {
+/// A single step of an LED pattern: a color, shown for a number of LED ticks.
+#[derive(Debug, Clone, Copy, Default, Encode, Decode, Eq, PartialEq)]
+pub struct LedStep {
+    /// The color, as 0xrrggbb.
+    #[n(0)]
+    pub color: u32,
+    /// How many ticks to show the color for.  A pattern repeats once all of its steps are shown.
     #[n(1)]
-    Hello {
-        #[n(1)]
-        version: String,
-    },
+    pub count: u16,
+}
+
+impl LedStep {
+    /// The red, green, and blue parts of the color.
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        ((self.color >> 16) as u8, (self.color >> 8) as u8, self.color as u8)
+    }
+}
+
+/// The time spent, and typing done, in a single layout mode.
+#[derive(Debug, Clone, Default, Encode, Decode, Eq, PartialEq)]
+pub struct ModeUsage {
+    /// The mode, as named by the firmware.
+    #[n(0)]
+    pub mode: String,
+    /// Time spent in the mode, in ms.
+    #[n(1)]
+    pub ms: u64,
+    /// Keys pressed.
     #[n(2)]
-    ReadFlash {
+    pub keys: u32,
+    /// Steno strokes translated.
+    #[n(3)]
+    pub strokes: u32,
+}
+
+/// A practice session against the metronome (see [`message::Stats::SetPace`]).
+#[derive(Debug, Clone, Default, Encode, Decode, Eq, PartialEq)]
+pub struct PaceSummary {
+    /// The target rate, in strokes per minute.
+    #[n(0)]
+    pub spm: u32,
+    /// Length of the session, in ms.
+    #[n(1)]
+    pub ms: u64,
+    /// Beats of the metronome.
+    #[n(2)]
+    pub beats: u32,
+    /// Strokes made.
+    #[n(3)]
+    pub strokes: u32,
+    /// Strokes close enough to a beat to count as on it.
+    #[n(4)]
+    pub on_beat: u32,
+    /// Strokes made early, before the beat.
+    #[n(5)]
+    pub ahead: u32,
+    /// Strokes made late, after the beat.
+    #[n(6)]
+    pub behind: u32,
+    /// The average distance of the strokes from their beat, in ms.  Negative is ahead.
+    #[n(7)]
+    pub mean_offset: i32,
+}
+
+/// When steno translations are typed, when a later stroke could still make a longer one.
+#[derive(Debug, Clone, Copy, Default, Encode, Decode, Eq, PartialEq)]
+pub enum LookupStrategy {
+    /// Type the best translation of each stroke right away, and correct it if a later stroke
+    /// extends it.
+    #[default]
+    #[n(0)]
+    Eager,
+    /// Hold back a translation that could still be extended, until a stroke doesn't extend it,
+    /// or for at most `timeout` ms.
+    #[n(1)]
+    Longest {
         #[n(0)]
-        offset: u32,
-        #[n(1)]
-        size: u32,
+        timeout: u32,
     },
 }
 
-#[derive(Debug, Encode, Decode)]
-pub enum Reply {
+/// The keyboard layout the host is set to.  Typing a character means sending the key that makes it
+/// on this layout, so the keys for the same text differ between layouts.
+#[derive(Debug, Clone, Copy, Default, Encode, Decode, Eq, PartialEq)]
+#[cbor(index_only)]
+pub enum HostLayout {
+    /// US English, which is what the keyboard assumes until told otherwise.
+    #[default]
+    #[n(0)]
+    Us,
+    /// UK English.
     #[n(1)]
-    Hello {
-        /// The protocol version.
-        #[n(1)]
-        version: String,
-        /// Version information about this device.
-        #[n(2)]
-        info: String,
-    },
+    Uk,
+    /// German, QWERTZ with dead keys.
     #[n(2)]
-    Log {
-        /// The message to log.
-        #[n(1)]
-        message: String,
-    },
+    De,
+}
+
+impl HostLayout {
+    /// A short name for the layout, as accepted by [`HostLayout::from_hint`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            HostLayout::Us => "us",
+            HostLayout::Uk => "gb",
+            HostLayout::De => "de",
+        }
+    }
+
+    /// Work out the layout from the way a host names it: an XKB layout (`de(nodeadkeys)`), a
+    /// locale (`en_GB.UTF-8`), a macOS input source (`com.apple.keylayout.German`), or a Windows
+    /// layout id (`00000407`).  Only the first of a list of layouts is used.  Returns None for
+    /// layouts that aren't supported, such as Swiss German.
+    pub fn from_hint(hint: &str) -> Option<HostLayout> {
+        let hint = hint.trim().to_ascii_lowercase();
+        let hint = hint.strip_prefix("com.apple.keylayout.").unwrap_or(&hint);
+        let mut parts = hint.split(|ch: char| !ch.is_ascii_alphanumeric());
+        let first = parts.next().unwrap_or("");
+        let second = parts.next().unwrap_or("");
+        match (first, second) {
+            ("en", "gb") | ("gb" | "uk" | "british" | "00000809", _) => Some(HostLayout::Uk),
+            ("en", _) | ("us" | "abc" | "00000409", _) => Some(HostLayout::Us),
+            ("de", "ch") => None,
+            ("de" | "german" | "00000407", _) => Some(HostLayout::De),
+            _ => None,
+        }
+    }
+}
+
+/// How the host accepts a character typed as its code point, in hex.  Each needs something set up
+/// on the host, and only works in applications that go along with it.
+#[derive(Debug, Clone, Copy, Default, Encode, Decode, Eq, PartialEq)]
+#[cbor(index_only)]
+pub enum UnicodeEntry {
+    /// Characters without a key are skipped.
+    #[default]
+    #[n(0)]
+    None,
+    /// Ctrl+Shift+U, the code point, then space, as handled by IBus and GTK.
+    #[n(1)]
+    Linux,
+    /// Alt held, with the keypad plus, and the code point.  This needs the `EnableHexNumpad`
+    /// registry setting, and only covers the basic multilingual plane.
+    #[n(2)]
+    Windows,
+    /// Option held, with the code point as UTF-16, for the Unicode Hex Input source.
     #[n(3)]
-    FlashData {
-        /// Offset the data came from.
-        #[n(0)]
-        offset: u32,
-        /// The data itself.
-        #[n(1)]
-        data: Vec<u8>,
+    Mac,
+}
+
+impl UnicodeEntry {
+    /// A short name for the method, as accepted by [`UnicodeEntry::from_name`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnicodeEntry::None => "none",
+            UnicodeEntry::Linux => "linux",
+            UnicodeEntry::Windows => "windows",
+            UnicodeEntry::Mac => "mac",
+        }
+    }
+
+    /// The method with a given name, ignoring case.  Also accepts "macos".
+    pub fn from_name(name: &str) -> Option<UnicodeEntry> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "none" => Some(UnicodeEntry::None),
+            "linux" => Some(UnicodeEntry::Linux),
+            "windows" => Some(UnicodeEntry::Windows),
+            "mac" | "macos" => Some(UnicodeEntry::Mac),
+            _ => None,
+        }
+    }
+}
+
+/// A single steno dictionary, as found in flash.
+#[derive(Debug, Clone, Default, Encode, Decode, Eq, PartialEq)]
+pub struct DictInfo {
+    /// The index of the dictionary, as used in [`message::Dict::SetProfile`].  None if the firmware
+    /// didn't load it, because something else in the same partition was invalid.
+    #[n(0)]
+    pub index: Option<u8>,
+    /// The name given when the dictionary was built.  Dictionaries built without a name are named
+    /// by their position in the partition.
+    #[n(1)]
+    pub name: String,
+    /// The partition holding the dictionary (see [`partition`]).
+    #[n(2)]
+    pub partition: String,
+    /// Offset of the dictionary data from the start of flash.  Built-in dictionaries, which are
+    /// part of the firmware, have no data, and have an offset and size of zero.
+    #[n(3)]
+    pub offset: u32,
+    /// Size of the data, in bytes.
+    #[n(4)]
+    pub size: u32,
+    /// The number of entries.
+    #[n(5)]
+    pub entries: u32,
+    /// The hash of the data, if one was requested.
+    #[n(6)]
+    pub digest: Option<Vec<u8>>,
+}
+
+/// A summary of the steno dictionaries in flash, given in the status so a host can tell at a
+/// glance whether they are the ones it expects.
+#[derive(Debug, Clone, Default, Encode, Decode, Eq, PartialEq)]
+pub struct DictStatus {
+    /// The version of the dictionary format the firmware reads.
+    #[n(0)]
+    pub format: u32,
+    /// Whether every dictionary found was loaded.  When false, at least one partition was
+    /// rejected, and its dictionaries have no index.
+    #[n(1)]
+    pub valid: bool,
+    /// Each dictionary, with a CRC-32 of its data.
+    #[n(2)]
+    pub dicts: Vec<DictInfo>,
+}
+
+/// The link between the halves of a split keyboard, to help debug a cable.
+#[derive(Debug, Clone, Default, Encode, Decode, Eq, PartialEq)]
+pub struct LinkStatus {
+    /// Packets sent.
+    #[n(0)]
+    pub sent: u32,
+    /// Less urgent packets put off, because the link was busy.
+    #[n(1)]
+    pub deferred: u32,
+    /// Less urgent packets cut short, to send key events.
+    #[n(2)]
+    pub preempted: u32,
+    /// Received bytes lost because the UART's FIFO was full.
+    #[n(3)]
+    pub overruns: u32,
+    /// Bytes received damaged: framing, parity, break, or noise errors.
+    #[n(4)]
+    pub framing: u32,
+    /// Receive errors in the last second.
+    #[n(5)]
+    pub errors_per_sec: u32,
+    /// Times the receiver started over because of errors.
+    #[n(6)]
+    pub resyncs: u32,
+}
+
+/// The firmware's runtime metrics.  The maximums are since the keyboard started.
+#[derive(Debug, Clone, Default, Encode, Decode, Eq, PartialEq)]
+pub struct RuntimeStats {
+    /// Time since boot, in ms.
+    #[n(0)]
+    pub uptime: u64,
+    /// Bytes of the heap in use.
+    #[n(1)]
+    pub heap_used: u32,
+    /// Bytes of the heap free.
+    #[n(2)]
+    pub heap_free: u32,
+    /// The most of the heap that has been in use at once.
+    #[n(3)]
+    pub heap_peak: u32,
+    /// Each of the firmware's event queues.
+    #[n(4)]
+    pub queues: Vec<QueueStats>,
+    /// Key events dropped, because the layout's queue was full.
+    #[n(5)]
+    pub dropped_keys: u32,
+    /// How long the last pass of the scan loop took, in microseconds.
+    #[n(6)]
+    pub scan_us: u32,
+    /// The longest pass of the scan loop, in microseconds.
+    #[n(7)]
+    pub scan_max_us: u32,
+    /// The furthest the scan loop has woken past its interval, in microseconds.
+    #[n(8)]
+    pub scan_late_max_us: u32,
+    /// Steno strokes translated, which the latencies below are over.
+    #[n(9)]
+    pub strokes: u32,
+    /// How long the last stroke took from being completed to its translation being passed on to
+    /// be typed, in microseconds.  This includes waiting for the steno worker.
+    #[n(10)]
+    pub stroke_us: u32,
+    /// The longest a stroke has taken to translate, in microseconds.
+    #[n(11)]
+    pub stroke_max_us: u32,
+    /// The mean time strokes have taken to translate, in microseconds.
+    #[n(12)]
+    pub stroke_mean_us: u32,
+}
+
+/// How full one of the firmware's event queues has got.
+#[derive(Debug, Clone, Default, Encode, Decode, Eq, PartialEq)]
+pub struct QueueStats {
+    #[n(0)]
+    pub name: String,
+    /// The events the queue holds.
+    #[n(1)]
+    pub capacity: u32,
+    /// The most events that have been waiting in it at once.
+    #[n(2)]
+    pub high_water: u32,
+}
+
+/// Why the keyboard crashed.
+#[derive(Debug, Clone, Copy, Encode, Decode, Eq, PartialEq)]
+#[cbor(index_only)]
+pub enum CrashCause {
+    /// A CPU exception, such as a bad memory access.
+    #[n(0)]
+    Fault,
+    /// A thread overran its stack.
+    #[n(1)]
+    StackOverflow,
+    /// A thread was stopped by the kernel, such as for a failed assertion.
+    #[n(2)]
+    Oops,
+    /// A panic, either in the kernel or the Rust code.
+    #[n(3)]
+    Panic,
+    /// The watchdog wasn't fed, and reset the keyboard.
+    #[n(4)]
+    Watchdog,
+}
+
+/// A report of the last crash, kept across the restart that followed it.
+#[derive(Debug, Clone, Encode, Decode, Eq, PartialEq)]
+pub struct CrashLog {
+    #[n(0)]
+    pub cause: CrashCause,
+    /// The fatal error reason code from the kernel, which narrows down the cause.
+    #[n(1)]
+    pub reason: u32,
+    /// The program counter, and link register, where the fault happened.  These are zero when not
+    /// known, such as after a watchdog reset.
+    #[n(2)]
+    pub pc: u32,
+    #[n(3)]
+    pub lr: u32,
+    /// How long the keyboard had been running, in milliseconds.  For a watchdog reset, this is when
+    /// the watchdog was last fed.
+    #[n(4)]
+    pub uptime: u32,
+    /// The last of the console output, and log messages, before the crash.  For a panic, this
+    /// ends with the panic message.
+    #[n(5)]
+    pub text: String,
+}
+
+impl CrashCause {
+    /// A short description of the cause.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CrashCause::Fault => "fault",
+            CrashCause::StackOverflow => "stack overflow",
+            CrashCause::Oops => "oops",
+            CrashCause::Panic => "panic",
+            CrashCause::Watchdog => "watchdog reset",
+        }
+    }
+}
+
+/// A keyboard macro stored on the keyboard (see `bbq_keyboard::macros`).
+#[derive(Debug, Clone, Encode, Decode, Eq, PartialEq)]
+pub struct MacroInfo {
+    /// The slot, which the keymap's macro keys play.
+    #[n(0)]
+    pub slot: u8,
+    /// Its name, if it has been given one.
+    #[n(1)]
+    pub name: Option<String>,
+    /// How many key reports it sends.
+    #[n(2)]
+    pub steps: u32,
+}
+
+/// The address of a BLE host, as Zephyr keeps it: the address type (0 for public, 1 for random),
+/// then the six bytes of the address, least significant first.
+#[derive(Debug, Clone, Copy, Encode, Decode, Eq, PartialEq)]
+pub struct BleAddress(#[n(0)] #[cbor(with = "minicbor::bytes")] pub [u8; 7]);
+
+/// Shown the way Zephyr does, such as "C0:12:34:56:78:9A (random)".
+impl core::fmt::Display for BleAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [kind, addr @ ..] = self.0;
+        for (i, byte) in addr.iter().rev().enumerate() {
+            write!(f, "{}{:02X}", if i == 0 { "" } else { ":" }, byte)?;
+        }
+        match kind {
+            0 => write!(f, " (public)"),
+            1 => write!(f, " (random)"),
+            2 => write!(f, " (public-id)"),
+            3 => write!(f, " (random-id)"),
+            _ => write!(f, " (type {})", kind),
+        }
     }
 }
 
+/// A BLE host the keyboard is bonded with.
+#[derive(Debug, Clone, Encode, Decode, Eq, PartialEq)]
+pub struct BondInfo {
+    #[n(0)]
+    pub address: BleAddress,
+    /// The name given to it with [`message::Ble::NameBond`], if any.
+    #[n(1)]
+    pub name: Option<String>,
+    /// Is it connected now.
+    #[n(2)]
+    pub connected: bool,
+}
+
 #[cfg(test)]
 mod tests_hid {
     use core::convert::Infallible;
@@ -170,3 +581,48 @@ mod tests_serial {
         assert_eq!(count, 1);
     }
 }
+
+#[cfg(test)]
+mod tests_layout {
+    use crate::{HostLayout, UnicodeEntry};
+
+    #[test]
+    fn test_from_hint() {
+        assert_eq!(HostLayout::from_hint("us"), Some(HostLayout::Us));
+        assert_eq!(HostLayout::from_hint("de(nodeadkeys)"), Some(HostLayout::De));
+        assert_eq!(HostLayout::from_hint("gb,us"), Some(HostLayout::Uk));
+        assert_eq!(HostLayout::from_hint("de_AT.UTF-8"), Some(HostLayout::De));
+        assert_eq!(HostLayout::from_hint("en_GB.UTF-8"), Some(HostLayout::Uk));
+        assert_eq!(HostLayout::from_hint("en_US.UTF-8"), Some(HostLayout::Us));
+        assert_eq!(HostLayout::from_hint(" com.apple.keylayout.German\n"), Some(HostLayout::De));
+        assert_eq!(HostLayout::from_hint("00000809"), Some(HostLayout::Uk));
+        assert_eq!(HostLayout::from_hint("de_CH"), None);
+        assert_eq!(HostLayout::from_hint("fr"), None);
+        assert_eq!(HostLayout::from_hint(""), None);
+
+        for layout in [HostLayout::Us, HostLayout::Uk, HostLayout::De] {
+            assert_eq!(HostLayout::from_hint(layout.name()), Some(layout));
+        }
+    }
+
+    #[test]
+    fn test_unicode_entry() {
+        assert_eq!(UnicodeEntry::from_name("macOS"), Some(UnicodeEntry::Mac));
+        assert_eq!(UnicodeEntry::from_name("ibus"), None);
+        for entry in [UnicodeEntry::None, UnicodeEntry::Linux, UnicodeEntry::Windows, UnicodeEntry::Mac] {
+            assert_eq!(UnicodeEntry::from_name(entry.name()), Some(entry));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_ble {
+    use crate::BleAddress;
+
+    #[test]
+    fn test_address() {
+        let addr = BleAddress([1, 0x9a, 0x78, 0x56, 0x34, 0x12, 0xc0]);
+        assert_eq!(addr.to_string(), "C0:12:34:56:78:9A (random)");
+        assert_eq!(BleAddress([0, 1, 2, 3, 4, 5, 6]).to_string(), "06:05:04:03:02:01 (public)");
+    }
+}
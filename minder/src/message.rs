@@ -0,0 +1,1083 @@
+//! Message envelope.
+//!
+//! A [`Message`] carries a [`Topic`], and a payload enum that is owned by that topic.  Each
+//! subsystem's messages are defined once, in its topic, and the firmware can route each message
+//! to the handler for its topic without looking inside.  New messages go into the topic they
+//! belong to.
+//!
+//! On the wire, a message is tagged, so it can be told apart from the bare requests and replies of
+//! older hosts (see [`crate::legacy`]), which are still accepted, and answered in kind.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use minicbor::{Decode, Encode};
+
+use crate::session::SessionId;
+use crate::stream::EventKind;
+use crate::{
+    BleAddress, BondInfo, CrashLog, DictInfo, DictStatus, HashAlgorithm, HostLayout, ImageInfo, LedStep, LinkStatus,
+    LookupStrategy, MacroInfo, ModeUsage, PaceSummary, RuntimeStats, UnicodeEntry,
+};
+use crate::legacy::{Reply, Request};
+#[cfg(doc)]
+use crate::{
+    partition, session, stream, BOND_NAME_MAX, DICT_PATCH_MAX, KEYMAP_CHUNK, MAX_LED_STEPS, TRACE_CHUNK, TYPE_TEXT_MAX,
+};
+
+/// The CBOR tag on a message, "minder".
+pub const MESSAGE_TAG: u64 = 0x6d696e646572;
+
+/// The subsystem a message belongs to.
+#[derive(Clone, Copy, Debug, Encode, Decode, Eq, PartialEq)]
+#[cbor(index_only)]
+pub enum Topic {
+    /// Identifying the device, and its general status.
+    #[n(0)]
+    Core,
+    /// Reading and checking the flash.
+    #[n(1)]
+    Flash,
+    /// The steno dictionaries and translation.
+    #[n(2)]
+    Dict,
+    /// Debugging, the console and the log.
+    #[n(3)]
+    Debug,
+    /// Usage statistics.
+    #[n(4)]
+    Stats,
+    /// The qwerty keymap.
+    #[n(5)]
+    Keymap,
+    /// The LED indicators.
+    #[n(6)]
+    Leds,
+    /// BLE hosts, and pairing with them.
+    #[n(7)]
+    Ble,
+    /// Keyboard macros.
+    #[n(8)]
+    Macros,
+}
+
+/// A message, in either direction, routed by its topic.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+#[cbor(tag(0x6d696e646572))]
+pub enum Message {
+    #[n(0)]
+    Core(#[n(0)] Core),
+    #[n(1)]
+    Flash(#[n(0)] Flash),
+    #[n(2)]
+    Dict(#[n(0)] Dict),
+    #[n(3)]
+    Debug(#[n(0)] Debug),
+    #[n(4)]
+    Stats(#[n(0)] Stats),
+    #[n(5)]
+    Keymap(#[n(0)] Keymap),
+    #[n(6)]
+    Leds(#[n(0)] Leds),
+    #[n(7)]
+    Ble(#[n(0)] Ble),
+    #[n(8)]
+    Macros(#[n(0)] Macros),
+}
+
+impl Message {
+    /// Decode a packet from the host, which can be either a message, or an older bare
+    /// [`Request`].  The flag is true for a bare request, which should be answered with a bare
+    /// [`Reply`].
+    pub fn decode_request(packet: &[u8]) -> Option<(Message, bool)> {
+        if let Ok(message) = minicbor::decode::<Message>(packet) {
+            return Some((message, false));
+        }
+        minicbor::decode::<Request>(packet).ok().map(|request| (request.into(), true))
+    }
+
+    /// Decode a packet from the device, which can be either a message, or a bare [`Reply`] from
+    /// older firmware.
+    pub fn decode_reply(packet: &[u8]) -> Option<Message> {
+        if let Ok(message) = minicbor::decode::<Message>(packet) {
+            return Some(message);
+        }
+        minicbor::decode::<Reply>(packet).ok().map(Message::from)
+    }
+
+    /// The topic of this message.
+    pub fn topic(&self) -> Topic {
+        match self {
+            Message::Core(_) => Topic::Core,
+            Message::Flash(_) => Topic::Flash,
+            Message::Dict(_) => Topic::Dict,
+            Message::Debug(_) => Topic::Debug,
+            Message::Stats(_) => Topic::Stats,
+            Message::Keymap(_) => Topic::Keymap,
+            Message::Leds(_) => Topic::Leds,
+            Message::Ble(_) => Topic::Ble,
+            Message::Macros(_) => Topic::Macros,
+        }
+    }
+}
+
+/// Messages about the device itself.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Core {
+    /// Introduce the host, and ask about the device.
+    #[n(0)]
+    Hello {
+        /// The protocol version.
+        #[n(0)]
+        version: String,
+    },
+    /// The answer to [`Core::Hello`].
+    #[n(1)]
+    HelloReply {
+        /// The protocol version.
+        #[n(0)]
+        version: String,
+        /// Version information about this device.
+        #[n(1)]
+        info: String,
+        /// The hash algorithms supported by the device.  Devices that don't send this only
+        /// support SHA-256.
+        #[n(2)]
+        hashes: Option<Vec<HashAlgorithm>>,
+        /// The most sequenced requests the device will take outstanding.  Devices that don't send
+        /// this don't understand sequence ids.  See [`crate::pipeline`].
+        #[n(3)]
+        pipeline: Option<u8>,
+    },
+    /// Request the device status.
+    #[n(2)]
+    GetStatus,
+    /// The device status.
+    #[n(3)]
+    Status {
+        /// The running firmware.
+        #[n(0)]
+        image: ImageInfo,
+        /// The build id of the running firmware (see `ImageInfo::build_id`).
+        #[n(1)]
+        build_id: u32,
+        /// Time since boot, in ms.
+        #[n(2)]
+        uptime: u64,
+        /// How much each layout mode has been used.  Also available from [`Stats::Usage`], this
+        /// is here to answer the older status request.
+        #[n(3)]
+        usage: Option<Vec<ModeUsage>>,
+        /// The steno dictionaries found in flash.  None if the firmware doesn't do steno.
+        #[n(4)]
+        dicts: Option<DictStatus>,
+        /// The link between the halves of a split keyboard.  None if there isn't one.
+        #[n(5)]
+        link: Option<LinkStatus>,
+    },
+    /// Give up the privilege to make changes, so another session can have it (see
+    /// [`session`]).  There is no reply.  If the session wrote the dictionaries, the keyboard
+    /// restarts.
+    #[n(4)]
+    Release,
+    /// The request needs the privilege to make changes, which another session holds (see
+    /// [`session`]).
+    #[n(5)]
+    Busy {
+        /// The session holding the privilege.
+        #[n(0)]
+        owner: SessionId,
+    },
+    /// Save anything that needs saving, and reboot the keyboard.  The reply,
+    /// [`Core::Rebooting`], is sent before the reboot, after which the device will go away.
+    #[n(6)]
+    Reboot,
+    /// The keyboard is shutting down to reboot.
+    #[n(7)]
+    Rebooting,
+    /// Type text to the host, as if it had been written on the keyboard, so host automation can
+    /// type into the focused application.  It is queued behind any steno output.  The text must
+    /// be no longer than [`TYPE_TEXT_MAX`], and only use characters with a key on the host layout
+    /// (see [`Core::SetHostLayout`]), or that the host accepts by code point (see
+    /// [`Core::SetUnicodeEntry`]).
+    #[n(8)]
+    TypeText {
+        #[n(0)]
+        text: String,
+    },
+    /// The text from [`Core::TypeText`] was queued.  The status is zero on success, or a
+    /// negative error code, such as for text that is too long, or can't be typed.
+    #[n(9)]
+    TextQueued {
+        #[n(0)]
+        status: i32,
+    },
+    /// Tell the keyboard which keyboard layout the host is set to, so that typed text, from steno
+    /// or [`Core::TypeText`], comes out as the right characters.  This is intended to be sent by
+    /// an agent on the host that knows the layout (see [`HostLayout::from_hint`]).  The keyboard
+    /// doesn't store it, and goes back to [`HostLayout::Us`] when it restarts.
+    #[n(10)]
+    SetHostLayout {
+        #[n(0)]
+        layout: HostLayout,
+    },
+    /// Acknowledge a change of host layout, with the layout now in use.
+    #[n(11)]
+    HostLayoutSet {
+        #[n(0)]
+        layout: HostLayout,
+    },
+    /// Tell the keyboard how the host accepts characters by their Unicode code point, so that
+    /// typed text with characters the host layout has no key for, such as an em dash, can still
+    /// be typed.  As with [`Core::SetHostLayout`], the keyboard goes back to
+    /// [`UnicodeEntry::None`] when it restarts.
+    #[n(12)]
+    SetUnicodeEntry {
+        #[n(0)]
+        entry: UnicodeEntry,
+    },
+    /// Acknowledge a change of Unicode entry, with the method now in use.
+    #[n(13)]
+    UnicodeEntrySet {
+        #[n(0)]
+        entry: UnicodeEntry,
+    },
+}
+
+/// Messages about the flash.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Flash {
+    /// Read a region of flash.
+    #[n(0)]
+    Read {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        size: u32,
+    },
+    /// Data read from flash.  Empty if the region can't be read.
+    #[n(1)]
+    Data {
+        /// Offset the data came from.
+        #[n(0)]
+        offset: u32,
+        /// The data itself.
+        #[n(1)]
+        data: Vec<u8>,
+    },
+    /// Compute a hash of a region of flash.
+    #[n(2)]
+    Hash {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        size: u32,
+        /// The algorithm to use, from those listed in the hello reply.  None means SHA-256.
+        #[n(2)]
+        algorithm: Option<HashAlgorithm>,
+    },
+    /// The hash of a region of flash.  If the request couldn't be satisfied (bad range, or an
+    /// unsupported algorithm), the digest is empty.
+    #[n(3)]
+    Digest {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        size: u32,
+        #[n(2)]
+        algorithm: HashAlgorithm,
+        #[n(3)]
+        digest: Vec<u8>,
+    },
+    /// Erase a sector of flash, and program it with `data`.  The offset must be at the start of a
+    /// sector (see [`partition::SECTOR_SIZE`]), entirely within one of the data partitions, and the
+    /// data no larger than a sector.  The rest of the sector is left erased.
+    ///
+    /// Before the first write to a dictionary partition, the dictionaries are unloaded, and steno
+    /// translates nothing.  The keyboard restarts to load them again once the session releases its
+    /// privilege (see [`Core::Release`]).
+    #[n(4)]
+    Program {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+    },
+    /// The result of programming a sector.  The status is zero on success, or a negative error
+    /// code.
+    #[n(5)]
+    Programmed {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        size: u32,
+        #[n(2)]
+        status: i32,
+    },
+    /// Erase part of the firmware staging area (see [`partition::STAGING`]), ready for a new
+    /// image to be programmed with [`Flash::ProgramImage`].  The offset and size must be whole
+    /// sectors.  Erasing a large area can take a few seconds.
+    #[n(6)]
+    Erase {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        size: u32,
+    },
+    /// The result of erasing part of the staging area.  The status is zero on success, or a
+    /// negative error code.
+    #[n(7)]
+    Erased {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        size: u32,
+        #[n(2)]
+        status: i32,
+    },
+    /// Program part of a new firmware image into the staging area, which must already be erased.
+    /// The offset must be at the start of a page (see [`partition::PAGE_SIZE`]), and the data no
+    /// larger than a sector.  The staging area can be read, and hashed, like the data partitions.
+    /// The reply is [`Flash::Programmed`].
+    #[n(8)]
+    ProgramImage {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+    },
+    /// Install the first `size` bytes of the staging area as the firmware, and restart into it.
+    /// The image must match the SHA-256 `digest`, and be built for this board, or it is refused.
+    /// Firmware built with a signing key also refuses an image unless `signature` is the Ed25519
+    /// signature of the digest by that key (see [`crate::sign`]).  Only firmware built with flash
+    /// support can be updated this way.  The reply, [`Flash::Booting`], is sent before the image
+    /// is installed, after which the device will go away.
+    #[n(9)]
+    Boot {
+        #[n(0)]
+        size: u32,
+        #[n(1)]
+        #[cbor(with = "minicbor::bytes")]
+        digest: Vec<u8>,
+        #[n(2)]
+        #[cbor(with = "minicbor::bytes")]
+        signature: Option<Vec<u8>>,
+    },
+    /// The answer to [`Flash::Boot`].  The status is zero if the image is being installed, or a
+    /// negative error code if it was refused.
+    #[n(10)]
+    Booting {
+        #[n(0)]
+        status: i32,
+    },
+}
+
+/// Messages about steno translation.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Dict {
+    /// Request the steno paper tape.  The tape is kept in flash (see [`partition::TAPE`]), so this
+    /// includes the strokes from before the keyboard last restarted, or crashed.
+    #[n(0)]
+    ReadTape,
+    /// The steno paper tape.
+    #[n(1)]
+    Tape {
+        /// The tape generation, which changes whenever a stroke is added.
+        #[n(0)]
+        generation: u32,
+        /// The tape, in the tape file format (see `bbq_steno::tape`).
+        #[n(1)]
+        text: String,
+    },
+    /// Select a dictionary profile.
+    ///
+    /// This is intended to be sent by an agent on the host that watches which application has
+    /// focus.  Along with the dictionaries, it can select an overlay of the qwerty keymap.  The
+    /// profile reverts to the default if it isn't sent again within `timeout` seconds, so that
+    /// the keyboard isn't left in an odd state if the agent goes away.
+    #[n(2)]
+    SetProfile {
+        /// A name for the profile, only used for reporting.
+        #[n(0)]
+        name: String,
+        /// The dictionaries to use, as indices in flash order.  None selects the default, which
+        /// uses all of them.
+        #[n(1)]
+        dicts: Option<Vec<u8>>,
+        /// Seconds until reverting to the default.  Zero means never.
+        #[n(2)]
+        timeout: u32,
+        /// The qwerty keymap overlay to use, by name.  None, or a name the keymap doesn't have,
+        /// uses the keymap as it is.
+        #[n(3)]
+        overlay: Option<String>,
+        /// When steno translations are typed.  None types them eagerly, as the default profile
+        /// does.
+        #[n(4)]
+        strategy: Option<LookupStrategy>,
+    },
+    /// Acknowledge a profile change.
+    #[n(3)]
+    Profile {
+        #[n(0)]
+        name: String,
+    },
+    /// List the steno dictionaries in flash.
+    #[n(4)]
+    List {
+        /// Also hash each dictionary with this algorithm, from those listed in the hello reply.
+        /// Hashing the larger dictionaries can take a while.
+        #[n(0)]
+        algorithm: Option<HashAlgorithm>,
+    },
+    /// The steno dictionaries in flash.
+    #[n(5)]
+    Info {
+        #[n(0)]
+        dicts: Vec<DictInfo>,
+    },
+    /// Send part of a patch to a dictionary in the user dictionary partition, encoded as a
+    /// `bbq_steno::memdict::DictPatch`.  The chunks must be sent in order, starting at offset
+    /// zero, and be no larger than [`partition::SECTOR_SIZE`].  The whole patch can be no larger
+    /// than [`DICT_PATCH_MAX`].  Once all `size` bytes have arrived, the patched partition is
+    /// written to the staging area (see [`partition::STAGING`]), and the keyboard reboots,
+    /// installing it.  Anything staged before, such as firmware, is lost.
+    #[n(6)]
+    Patch {
+        #[n(0)]
+        offset: u32,
+        /// The size of the whole patch.
+        #[n(1)]
+        size: u32,
+        #[n(2)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+    },
+    /// Acknowledge part of a dictionary patch.  The status is zero on success, or a negative
+    /// error code, after which the patch has to be sent again from the start.
+    #[n(7)]
+    Patched {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        status: i32,
+    },
+}
+
+/// Debugging messages.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Debug {
+    /// Run a command from the device's debug console.
+    #[n(0)]
+    Exec {
+        #[n(0)]
+        command: String,
+    },
+    /// The output of a console command.
+    #[n(1)]
+    Output {
+        #[n(0)]
+        output: String,
+    },
+    /// A message from the device's log.
+    #[n(2)]
+    Log {
+        #[n(0)]
+        message: String,
+    },
+    /// Stream these kinds of events, as [`Debug::Key`] and [`Debug::Stroke`], for `timeout`
+    /// seconds.  Sending this again renews the subscription, and an empty list of events ends it.
+    /// A timeout of zero uses [`stream::DEFAULT_TIMEOUT`].
+    #[n(3)]
+    Subscribe {
+        #[n(0)]
+        events: Vec<EventKind>,
+        #[n(1)]
+        timeout: u32,
+    },
+    /// Acknowledge a subscription, with the events that will be streamed, and the seconds until
+    /// it has to be renewed.
+    #[n(4)]
+    Subscribed {
+        #[n(0)]
+        events: Vec<EventKind>,
+        #[n(1)]
+        timeout: u32,
+    },
+    /// A key went up or down.
+    #[n(5)]
+    Key {
+        /// Time since boot, in ms.
+        #[n(0)]
+        time: u64,
+        /// The scan code.
+        #[n(1)]
+        key: u8,
+        #[n(2)]
+        press: bool,
+    },
+    /// A steno stroke was made.
+    #[n(6)]
+    Stroke {
+        /// Time since boot, in ms.
+        #[n(0)]
+        time: u64,
+        /// The stroke, in steno notation.
+        #[n(1)]
+        stroke: String,
+    },
+    /// Read part of the event trace (see `bbq_keyboard::trace`).  A read at offset zero takes a
+    /// snapshot of the trace, and later offsets read from that snapshot, until the size given in
+    /// the reply has been read.
+    #[n(7)]
+    GetTrace {
+        #[n(0)]
+        offset: u32,
+    },
+    /// Part of the event trace, no more than [`TRACE_CHUNK`] bytes.  The size is zero if the
+    /// firmware was built without tracing.
+    #[n(8)]
+    Trace {
+        #[n(0)]
+        offset: u32,
+        /// The size of the whole trace.
+        #[n(1)]
+        size: u32,
+        #[n(2)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+    },
+    /// Get the report of the last crash, or watchdog reset, if the keyboard has restarted because
+    /// of one, so the cause of a mysterious reboot can be found.  The report is kept until it is
+    /// cleared, or the keyboard restarts again.  The reply is [`Debug::CrashLog`].
+    #[n(9)]
+    GetCrashLog {
+        /// Forget the report once it has been sent.
+        #[n(0)]
+        clear: bool,
+    },
+    /// The answer to [`Debug::GetCrashLog`].  None if there is no crash to report.
+    #[n(10)]
+    CrashLog {
+        #[n(0)]
+        log: Option<CrashLog>,
+    },
+}
+
+/// Usage statistics.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Stats {
+    /// Request the usage of each layout mode.
+    #[n(0)]
+    GetUsage,
+    /// The usage of each layout mode.
+    #[n(1)]
+    Usage {
+        #[n(0)]
+        modes: Vec<ModeUsage>,
+    },
+    /// Start the practice metronome at a rate in strokes per minute, or stop it with zero.
+    /// Answered with the summary of the session that was running.
+    #[n(2)]
+    SetPace {
+        #[n(0)]
+        spm: u32,
+    },
+    /// Request the summary of the current, or most recent, practice session.
+    #[n(3)]
+    GetPace,
+    /// The summary of a practice session.
+    #[n(4)]
+    Pace {
+        #[n(0)]
+        summary: PaceSummary,
+    },
+    /// Get the firmware's runtime metrics: the heap, how full its queues have got, and how
+    /// promptly it scans, so a slow or stuttering keyboard can be looked into without a debug
+    /// probe.  The reply is [`Stats::Runtime`].
+    #[n(5)]
+    GetRuntime,
+    /// The firmware's runtime metrics.
+    #[n(6)]
+    Runtime {
+        #[n(0)]
+        stats: RuntimeStats,
+    },
+}
+
+/// Keymap transfers.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Keymap {
+    /// Read part of the keymap in use, encoded as it is stored in flash.  The keymap is read a
+    /// chunk at a time, with increasing offsets, until the size given in the reply has been read.
+    #[n(0)]
+    Get {
+        #[n(0)]
+        offset: u32,
+    },
+    /// Part of the keymap in use.
+    #[n(1)]
+    Data {
+        #[n(0)]
+        offset: u32,
+        /// The size of the whole keymap.
+        #[n(1)]
+        size: u32,
+        #[n(2)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+    },
+    /// Send part of a new keymap.  The chunks must be sent in order, starting at offset zero, and
+    /// be no larger than [`KEYMAP_CHUNK`].  Once all `size` bytes have arrived, the keymap is
+    /// checked, written to the keymap partition, and used.
+    #[n(2)]
+    Set {
+        #[n(0)]
+        offset: u32,
+        /// The size of the whole keymap.
+        #[n(1)]
+        size: u32,
+        #[n(2)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+    },
+    /// Acknowledge part of a new keymap.  The status is zero on success, or a negative error code,
+    /// after which the keymap has to be sent again from the start.
+    #[n(3)]
+    Stored {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        status: i32,
+    },
+    /// Read part of the steno map in use (see `bbq_keyboard::stenomap`), a chunk at a time, as
+    /// with [`Keymap::Get`].
+    #[n(4)]
+    GetSteno {
+        #[n(0)]
+        offset: u32,
+    },
+    /// Part of the steno map in use.
+    #[n(5)]
+    StenoData {
+        #[n(0)]
+        offset: u32,
+        /// The size of the whole steno map.
+        #[n(1)]
+        size: u32,
+        #[n(2)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+    },
+    /// Send part of a new steno map, as with [`Keymap::Set`].  Once all of it has arrived, it is
+    /// checked, written to the steno map partition, and used.
+    #[n(6)]
+    SetSteno {
+        #[n(0)]
+        offset: u32,
+        /// The size of the whole steno map.
+        #[n(1)]
+        size: u32,
+        #[n(2)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+    },
+    /// Acknowledge part of a new steno map, as with [`Keymap::Stored`].
+    #[n(7)]
+    StenoStored {
+        #[n(0)]
+        offset: u32,
+        #[n(1)]
+        status: i32,
+    },
+}
+
+/// The LED indicators.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Leds {
+    /// Replace the LED pattern shown for an indicator (such as `qwerty`, or `steno-select`), and
+    /// store it so it is still used after a reboot.  An empty list of steps goes back to the
+    /// pattern built into the firmware.  There can be no more than [`MAX_LED_STEPS`] steps.
+    #[n(0)]
+    SetPattern {
+        #[n(0)]
+        indicator: String,
+        #[n(1)]
+        steps: Vec<LedStep>,
+    },
+    /// The result of setting an LED pattern.  The status is zero on success, or a negative error
+    /// code, such as for an indicator the firmware doesn't have.
+    #[n(1)]
+    PatternSet {
+        #[n(0)]
+        indicator: String,
+        #[n(1)]
+        status: i32,
+    },
+}
+
+/// BLE hosts, and pairing with them.  Firmware without BLE doesn't answer these.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Ble {
+    /// List the hosts the keyboard is bonded with.  The reply is [`Ble::Bonds`].
+    #[n(0)]
+    ListBonds,
+    /// The bonded hosts.
+    #[n(1)]
+    Bonds {
+        #[n(0)]
+        bonds: Vec<BondInfo>,
+    },
+    /// Name a bonded host, to tell it apart from the others, or forget its name with an empty one.
+    /// The name can be no longer than [`BOND_NAME_MAX`].  The reply is [`Ble::BondChanged`].
+    #[n(2)]
+    NameBond {
+        #[n(0)]
+        address: BleAddress,
+        #[n(1)]
+        name: String,
+    },
+    /// Delete the bond with a host, disconnecting it if it is connected.  It will have to pair
+    /// again to connect.  The reply is [`Ble::BondChanged`].
+    #[n(3)]
+    DeleteBond {
+        #[n(0)]
+        address: BleAddress,
+    },
+    /// The result of changing a bond.  The status is zero on success, or a negative error code,
+    /// such as for a host that isn't bonded.
+    #[n(4)]
+    BondChanged {
+        #[n(0)]
+        address: BleAddress,
+        #[n(1)]
+        status: i32,
+    },
+    /// Let new hosts pair for this many seconds, or stop with zero.  Otherwise, new hosts can only
+    /// pair while there are no bonds at all.  The reply is [`Ble::Pairing`].
+    #[n(5)]
+    Pair {
+        #[n(0)]
+        seconds: u32,
+    },
+    /// The answer to [`Ble::Pair`].  The status is zero if pairing is open, or closed with zero
+    /// seconds, or a negative error code.
+    #[n(6)]
+    Pairing {
+        #[n(0)]
+        status: i32,
+    },
+}
+
+/// Keyboard macros, which are stored in the macro partition (see [`partition::MACROS`]).  They
+/// are managed by name; a macro recorded on the keyboard has to be named before it can be
+/// downloaded or deleted.  Firmware without qwerty mode doesn't answer these.
+#[derive(Debug, Encode, Decode, Eq, PartialEq)]
+pub enum Macros {
+    /// List the stored macros.  The reply is [`Macros::Listing`].
+    #[n(0)]
+    List,
+    /// The stored macros.
+    #[n(1)]
+    Listing {
+        #[n(0)]
+        macros: Vec<MacroInfo>,
+    },
+    /// Add a macro, encoded as `bbq_keyboard::macros::Macro`, into its slot.  Its name can't be
+    /// used by a macro in another slot, and a slot that already has a macro is only replaced with
+    /// `replace`.  The keymap in use must have a key that plays the slot.  The reply is
+    /// [`Macros::Uploaded`].
+    #[n(2)]
+    Upload {
+        #[n(0)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+        #[n(1)]
+        replace: bool,
+    },
+    /// The result of an upload.  The status is zero on success, or a negative error code: EEXIST
+    /// if the name or slot is in use, ENOENT if no key plays the slot, or EINVAL if the macro
+    /// can't be used.
+    #[n(3)]
+    Uploaded {
+        #[n(0)]
+        slot: u8,
+        #[n(1)]
+        status: i32,
+    },
+    /// Read a macro by name.  The reply is [`Macros::Data`].
+    #[n(4)]
+    Download {
+        #[n(0)]
+        name: String,
+    },
+    /// A macro, encoded as for [`Macros::Upload`], or empty if there is no macro of that name.
+    #[n(5)]
+    Data {
+        #[n(0)]
+        name: String,
+        #[n(1)]
+        #[cbor(with = "minicbor::bytes")]
+        data: Vec<u8>,
+    },
+    /// Delete a macro by name.  The reply is [`Macros::Deleted`].
+    #[n(6)]
+    Delete {
+        #[n(0)]
+        name: String,
+    },
+    /// The result of a delete.  The status is zero on success, or a negative error code, ENOENT if
+    /// there is no macro of that name.
+    #[n(7)]
+    Deleted {
+        #[n(0)]
+        name: String,
+        #[n(1)]
+        status: i32,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::{serial_encode, CrashCause, QueueStats, SerialDecoder, KEYMAP_CHUNK, PACKET_SIZE, TRACE_CHUNK};
+
+    /// Encode and decode a message.
+    fn roundtrip(message: &Message) -> Message {
+        minicbor::decode(&minicbor::to_vec(message).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_message() {
+        let message = Message::Debug(Debug::Exec { command: "status".to_string() });
+        assert_eq!(message.topic(), Topic::Debug);
+
+        let mut buf = Vec::new();
+        serial_encode(&message, &mut buf, true).unwrap();
+        let mut dec = SerialDecoder::new();
+        let mut decoded = None;
+        for &byte in &buf {
+            if let Some(packet) = dec.add_packet(byte) {
+                // The tag keeps it from being mistaken for a bare request.
+                assert!(minicbor::decode::<Request>(packet).is_err());
+                decoded = Some(minicbor::decode::<Message>(packet).unwrap());
+            }
+        }
+        assert_eq!(decoded, Some(message));
+
+        // Both forms can be received.
+        let mut buf = Vec::new();
+        minicbor::encode(Request::Hello { version: "1.0".to_string() }, &mut buf).unwrap();
+        assert_eq!(Message::decode_request(&buf),
+                   Some((Message::Core(Core::Hello { version: "1.0".to_string() }), true)));
+        buf.clear();
+        minicbor::encode(Message::Stats(Stats::GetUsage), &mut buf).unwrap();
+        assert_eq!(Message::decode_request(&buf), Some((Message::Stats(Stats::GetUsage), false)));
+        buf.clear();
+        minicbor::encode(Message::Stats(Stats::Usage { modes: Vec::new() }), &mut buf).unwrap();
+        assert_eq!(Message::decode_reply(&buf), Some(Message::Stats(Stats::Usage { modes: Vec::new() })));
+
+        let list = Message::Dict(Dict::List { algorithm: Some(HashAlgorithm::Crc32) });
+        assert_eq!(list.topic(), Topic::Dict);
+        assert_eq!(roundtrip(&list), list);
+
+        let profile = Message::Dict(Dict::SetProfile {
+            name: "editor".to_string(),
+            dicts: None,
+            timeout: 60,
+            overlay: None,
+            strategy: Some(LookupStrategy::Longest { timeout: 250 }),
+        });
+        assert_eq!(roundtrip(&profile), profile);
+
+        let typed = Message::Core(Core::TypeText { text: "hello\n".to_string() });
+        assert_eq!(typed.topic(), Topic::Core);
+        assert_eq!(roundtrip(&typed), typed);
+
+        let layout = Message::Core(Core::SetHostLayout { layout: HostLayout::De });
+        assert!(layout.is_privileged());
+        assert_eq!(roundtrip(&layout), layout);
+        let reply = Message::Core(Core::HostLayoutSet { layout: HostLayout::De });
+        assert!(!reply.is_privileged());
+        assert_eq!(roundtrip(&reply), reply);
+
+        let entry = Message::Core(Core::SetUnicodeEntry { entry: UnicodeEntry::Mac });
+        assert!(entry.is_privileged());
+        assert_eq!(roundtrip(&entry), entry);
+        let reply = Message::Core(Core::UnicodeEntrySet { entry: UnicodeEntry::Mac });
+        assert_eq!(roundtrip(&reply), reply);
+
+        // Messages without an older form can't be sent bare.
+        assert!(Reply::try_from(Message::Stats(Stats::GetUsage)).is_err());
+    }
+
+    /// A full chunk of keymap fits in a single packet.
+    #[test]
+    fn test_keymap_chunk() {
+        let data = alloc::vec![0xa5; KEYMAP_CHUNK as usize];
+        let message = Message::Keymap(Keymap::Set { offset: 4000, size: 4096, data: data.clone() });
+        assert_eq!(message.topic(), Topic::Keymap);
+        assert!(minicbor::to_vec(&message).unwrap().len() <= PACKET_SIZE);
+
+        let reply = Message::Keymap(Keymap::Data { offset: 4000, size: 4096, data });
+        assert!(minicbor::to_vec(&reply).unwrap().len() <= PACKET_SIZE);
+
+        // The same goes for the trace.
+        let data = alloc::vec![0xa5; TRACE_CHUNK as usize];
+        let reply = Message::Debug(Debug::Trace { offset: 24000, size: 24576, data });
+        assert!(minicbor::to_vec(&reply).unwrap().len() <= PACKET_SIZE);
+        assert_eq!(roundtrip(&reply), reply);
+    }
+
+    #[test]
+    fn test_firmware_update() {
+        let erase = Message::Flash(Flash::Erase { offset: 0xfd000, size: 0x1000 });
+        assert_eq!(erase.topic(), Topic::Flash);
+        assert!(erase.is_privileged());
+        assert_eq!(roundtrip(&erase), erase);
+
+        let program = Message::Flash(Flash::ProgramImage { offset: 0xfd100, data: alloc::vec![0xa5; 4096] });
+        assert!(program.is_privileged());
+        assert_eq!(roundtrip(&program), program);
+
+        let boot = Message::Flash(Flash::Boot { size: 0x4_0000, digest: alloc::vec![1; 32], signature: None });
+        assert!(boot.is_privileged());
+        assert_eq!(roundtrip(&boot), boot);
+        let signed = Message::Flash(Flash::Boot {
+            size: 0x4_0000,
+            digest: alloc::vec![1; 32],
+            signature: Some(alloc::vec![2; 64]),
+        });
+        assert_eq!(roundtrip(&signed), signed);
+
+        let reply = Message::Flash(Flash::Erased { offset: 0xfd000, size: 0x1000, status: 0 });
+        assert!(!reply.is_privileged());
+        assert_eq!(roundtrip(&reply), reply);
+        let reply = Message::Flash(Flash::Booting { status: -22 });
+        assert_eq!(roundtrip(&reply), reply);
+    }
+
+    #[test]
+    fn test_crash_log() {
+        // Only clearing the log changes anything.
+        let request = Message::Debug(Debug::GetCrashLog { clear: false });
+        assert_eq!(request.topic(), Topic::Debug);
+        assert!(!request.is_privileged());
+        assert!(Message::Debug(Debug::GetCrashLog { clear: true }).is_privileged());
+        assert_eq!(roundtrip(&request), request);
+
+        let log = CrashLog {
+            cause: CrashCause::Panic,
+            reason: 4,
+            pc: 0x1000_2345,
+            lr: 0x1000_1001,
+            uptime: 123_456,
+            text: "panicked at src/lib.rs:10:5:\nindex out of bounds\n".to_string(),
+        };
+        let reply = Message::Debug(Debug::CrashLog { log: Some(log) });
+        assert_eq!(roundtrip(&reply), reply);
+        let reply = Message::Debug(Debug::CrashLog { log: None });
+        assert_eq!(roundtrip(&reply), reply);
+    }
+
+    #[test]
+    fn test_stats() {
+        let request = Message::Stats(Stats::GetRuntime);
+        assert_eq!(request.topic(), Topic::Stats);
+        assert!(!request.is_privileged());
+        assert_eq!(roundtrip(&request), request);
+
+        let stats = RuntimeStats {
+            uptime: 3_600_000,
+            heap_used: 12_000,
+            heap_free: 52_000,
+            heap_peak: 20_000,
+            queues: alloc::vec![
+                QueueStats { name: "keys".to_string(), capacity: 32, high_water: 3 },
+                QueueStats { name: "layout".to_string(), capacity: 32, high_water: 1 },
+            ],
+            dropped_keys: 0,
+            scan_us: 40,
+            scan_max_us: 310,
+            scan_late_max_us: 1_200,
+            strokes: 500,
+            stroke_us: 900,
+            stroke_max_us: 4_500,
+            stroke_mean_us: 1_100,
+        };
+        let reply = Message::Stats(Stats::Runtime { stats });
+        assert_eq!(roundtrip(&reply), reply);
+    }
+
+    #[test]
+    fn test_led_pattern() {
+        let steps = alloc::vec![
+            LedStep { color: 0x00_10_00, count: 1 },
+            LedStep { color: 0, count: 300 },
+        ];
+        let message = Message::Leds(Leds::SetPattern { indicator: "qwerty".to_string(), steps });
+        assert_eq!(message.topic(), Topic::Leds);
+        assert!(message.is_privileged());
+        assert_eq!(roundtrip(&message), message);
+        assert_eq!(LedStep { color: 0x20_08_01, count: 1 }.rgb(), (0x20, 0x08, 0x01));
+
+        let reply = Message::Leds(Leds::PatternSet { indicator: "qwerty".to_string(), status: -22 });
+        assert!(!reply.is_privileged());
+        assert_eq!(roundtrip(&reply), reply);
+    }
+
+    #[test]
+    fn test_ble() {
+        let address = BleAddress([1, 0x9a, 0x78, 0x56, 0x34, 0x12, 0xc0]);
+        let message = Message::Ble(Ble::ListBonds);
+        assert_eq!(message.topic(), Topic::Ble);
+        assert!(!message.is_privileged());
+        assert_eq!(roundtrip(&message), message);
+
+        let bonds = alloc::vec![
+            BondInfo { address, name: Some("laptop".to_string()), connected: true },
+            BondInfo { address: BleAddress([0, 1, 2, 3, 4, 5, 6]), name: None, connected: false },
+        ];
+        let reply = Message::Ble(Ble::Bonds { bonds });
+        assert_eq!(roundtrip(&reply), reply);
+
+        for message in [
+            Message::Ble(Ble::NameBond { address, name: "laptop".to_string() }),
+            Message::Ble(Ble::DeleteBond { address }),
+            Message::Ble(Ble::Pair { seconds: 60 }),
+        ] {
+            assert!(message.is_privileged());
+            assert_eq!(roundtrip(&message), message);
+        }
+        let reply = Message::Ble(Ble::BondChanged { address, status: -2 });
+        assert_eq!(roundtrip(&reply), reply);
+    }
+
+    #[test]
+    fn test_macros() {
+        let message = Message::Macros(Macros::List);
+        assert_eq!(message.topic(), Topic::Macros);
+        assert!(!message.is_privileged());
+        assert_eq!(roundtrip(&message), message);
+
+        let macros = alloc::vec![MacroInfo { slot: 1, name: Some("sig".to_string()), steps: 12 }];
+        let reply = Message::Macros(Macros::Listing { macros });
+        assert_eq!(roundtrip(&reply), reply);
+
+        for message in [
+            Message::Macros(Macros::Upload { data: alloc::vec![0xa2, 0, 1, 1, 0x80], replace: true }),
+            Message::Macros(Macros::Delete { name: "sig".to_string() }),
+        ] {
+            assert!(message.is_privileged());
+            assert_eq!(roundtrip(&message), message);
+        }
+        let message = Message::Macros(Macros::Download { name: "sig".to_string() });
+        assert!(!message.is_privileged());
+        let reply = Message::Macros(Macros::Data { name: "sig".to_string(), data: alloc::vec![] });
+        assert_eq!(roundtrip(&reply), reply);
+    }
+}
@@ -0,0 +1,249 @@
+//! Flash partition map.
+//!
+//! The keyboards store more than just the firmware in flash.  The board information and the steno
+//! dictionaries are written separately, at fixed locations.  These locations are shared between the
+//! firmware, which reads them, and the host tools, which write and read them.  Keeping them here
+//! means there is a single place that defines them.
+//!
+//! All offsets are relative to the start of flash.  Where flash is, and how much of it there is,
+//! depends on the board (see [`Flash`]).
+
+/// The address flash is mapped at on the RP2040.  This is what the host tools build images for;
+/// the firmware takes the address from its own configuration.
+pub const FLASH_BASE: u32 = 0x1000_0000;
+
+/// The most flash any board we support has.  Boards with less have a smaller main dictionary.
+pub const MAX_FLASH_SIZE: u32 = 16 * 1024 * 1024;
+
+/// The erase unit of the flash.  Writes from the host are done a sector at a time.
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// The program unit of the flash.  Firmware images are written a page at a time.
+pub const PAGE_SIZE: u32 = 256;
+
+/// A single region of flash.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Partition {
+    /// The name used to refer to this partition from the host tools.
+    pub name: &'static str,
+    /// Offset from the start of flash.
+    pub offset: u32,
+    /// Size, in bytes.
+    pub size: u32,
+}
+
+impl Partition {
+    /// The address of the start of this partition, in an image built for the RP2040.
+    pub const fn address(&self) -> u32 {
+        FLASH_BASE + self.offset
+    }
+
+    /// The offset just past the end of this partition.
+    pub const fn end(&self) -> u32 {
+        self.offset + self.size
+    }
+
+    /// Does the given range fall entirely within this partition?
+    pub const fn contains(&self, offset: u32, size: u32) -> bool {
+        offset >= self.offset && size <= self.size && offset - self.offset <= self.size - size
+    }
+}
+
+/// The running firmware, at the start of flash.  This isn't a data partition, and can't be read
+/// or written over minder.
+pub const FIRMWARE: Partition = Partition {
+    name: "firmware",
+    offset: 0,
+    size: 0xfc000,
+};
+
+/// Where a new firmware image is written, before it is installed over [`FIRMWARE`].  This is the
+/// same size as the firmware, between it and the data partitions.
+pub const STAGING: Partition = Partition {
+    name: "staging",
+    offset: 0xfc000,
+    size: 0xfc000,
+};
+
+/// The steno paper tape, saved periodically by the firmware, and on the way down from a crash.
+/// This is two erase sectors, written in turn, so a save cut short still leaves the one before.
+pub const TAPE: Partition = Partition {
+    name: "tape",
+    offset: 0x1f_8000,
+    size: 0x2000,
+};
+
+/// The keyboard macros recorded by the user.  Written by the firmware, a full erase sector below
+/// the LED patterns.
+pub const MACROS: Partition = Partition {
+    name: "macros",
+    offset: 0x1f_a000,
+    size: 0x1000,
+};
+
+/// The LED patterns set by the user, in place of the built-in ones.  Written by the firmware, a
+/// full erase sector below the steno map.
+pub const LED_PATTERNS: Partition = Partition {
+    name: "led-patterns",
+    offset: 0x1f_b000,
+    size: 0x1000,
+};
+
+/// The steno map, assigning steno keys to scan codes in place of the built-in one.  Written by the
+/// host, a full erase sector below the keymap.
+pub const STENO_MAP: Partition = Partition {
+    name: "steno-map",
+    offset: 0x1f_c000,
+    size: 0x1000,
+};
+
+/// A keymap, replacing the built-in qwerty layers.  Written by the host, a full erase sector below
+/// the usage statistics.
+pub const KEYMAP: Partition = Partition {
+    name: "keymap",
+    offset: 0x1f_d000,
+    size: 0x1000,
+};
+
+/// Usage statistics, saved periodically by the firmware.  This is a full erase sector, just below
+/// the board info.
+pub const STATS: Partition = Partition {
+    name: "stats",
+    offset: 0x1f_e000,
+    size: 0x1000,
+};
+
+/// The board information, in the last 256 bytes before the user dictionary.
+pub const BOARD_INFO: Partition = Partition {
+    name: "boardinfo",
+    offset: 0x1f_ff00,
+    size: 256,
+};
+
+/// The user dictionary.
+pub const USER_DICT: Partition = Partition {
+    name: "user-dict",
+    offset: 0x20_0000,
+    size: 0x10_0000,
+};
+
+/// The main dictionary, filling the rest of flash.  This is its size with the most flash; use
+/// [`Flash::fit`] for the size on a particular board.
+pub const MAIN_DICT: Partition = Partition {
+    name: "main-dict",
+    offset: 0x30_0000,
+    size: MAX_FLASH_SIZE - 0x30_0000,
+};
+
+/// All of the data partitions.  The firmware itself lives below these.
+pub static PARTITIONS: &[Partition] = &[
+    TAPE,
+    MACROS,
+    LED_PATTERNS,
+    STENO_MAP,
+    KEYMAP,
+    STATS,
+    BOARD_INFO,
+    USER_DICT,
+    MAIN_DICT,
+];
+
+/// The start of the data partitions.  The firmware, and its staging area, must fit below this.
+pub const DATA_START: u32 = TAPE.offset;
+
+/// Look up a partition by name.
+pub fn by_name(name: &str) -> Option<&'static Partition> {
+    PARTITIONS.iter().find(|p| p.name == name)
+}
+
+/// The flash on a particular board.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Flash {
+    /// The address flash is mapped at.
+    pub base: usize,
+    /// The total size, in bytes.
+    pub size: u32,
+}
+
+impl Flash {
+    /// The part of the partition that is present in this flash, or None if it is entirely past the
+    /// end.
+    pub const fn fit(&self, part: &Partition) -> Option<Partition> {
+        if part.offset >= self.size {
+            return None;
+        }
+        let room = self.size - part.offset;
+        Some(Partition {
+            size: if part.size < room { part.size } else { room },
+            ..*part
+        })
+    }
+
+    /// The memory-mapped address of the given offset.
+    pub const fn address(&self, offset: u32) -> usize {
+        self.base + offset as usize
+    }
+
+    /// Find the partition holding the given range.  Requests to access flash should be checked
+    /// against this, so that a bad offset can't reach outside of the data areas, or past the end of
+    /// flash.
+    pub fn find(&self, offset: u32, size: u32) -> Option<Partition> {
+        PARTITIONS.iter().filter_map(|p| self.fit(p)).find(|p| p.contains(offset, size))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BIG: Flash = Flash { base: FLASH_BASE as usize, size: MAX_FLASH_SIZE };
+    const SMALL: Flash = Flash { base: FLASH_BASE as usize, size: 8 * 1024 * 1024 };
+
+    #[test]
+    fn test_layout() {
+        // The partitions must not overlap, and must all fit within flash.
+        for (i, a) in PARTITIONS.iter().enumerate() {
+            assert!(a.end() <= MAX_FLASH_SIZE);
+            for b in &PARTITIONS[i + 1..] {
+                assert!(a.end() <= b.offset || b.end() <= a.offset);
+            }
+        }
+        for part in PARTITIONS {
+            assert!(part.offset >= DATA_START);
+        }
+        // The firmware and its staging area fill the space below the data.
+        assert_eq!(FIRMWARE.end(), STAGING.offset);
+        assert_eq!(STAGING.end(), DATA_START);
+        assert_eq!(STAGING.offset % SECTOR_SIZE, 0);
+        assert_eq!(BIG.find(STAGING.offset, 64), None);
+        assert_eq!(TAPE.address(), 0x101f_8000);
+        assert_eq!(TAPE.size, 2 * SECTOR_SIZE);
+        assert_eq!(TAPE.end(), MACROS.offset);
+        assert_eq!(MACROS.address(), 0x101f_a000);
+        assert_eq!(LED_PATTERNS.address(), 0x101f_b000);
+        assert_eq!(STENO_MAP.address(), 0x101f_c000);
+        assert_eq!(KEYMAP.address(), 0x101f_d000);
+        assert_eq!(STATS.address(), 0x101f_e000);
+        assert_eq!(BOARD_INFO.address(), 0x101f_ff00);
+        assert_eq!(USER_DICT.address(), 0x1020_0000);
+        assert_eq!(MAIN_DICT.address(), 0x1030_0000);
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(BIG.find(0x20_0000, 64), Some(USER_DICT));
+        assert_eq!(BIG.find(0x2f_ffc0, 64), Some(USER_DICT));
+        assert_eq!(BIG.find(0x2f_ffc1, 64), None);
+        assert_eq!(BIG.find(0x1000, 64), None);
+        assert_eq!(BIG.find(MAX_FLASH_SIZE - 1, u32::MAX), None);
+        assert_eq!(by_name("main-dict"), Some(&MAIN_DICT));
+
+        // The main dictionary stops at the end of a smaller flash.
+        assert_eq!(BIG.find(0x7f_f000, SECTOR_SIZE), Some(MAIN_DICT));
+        assert_eq!(SMALL.find(0x7f_f000, SECTOR_SIZE).map(|p| p.end()), Some(SMALL.size));
+        assert_eq!(SMALL.find(0x80_0000, SECTOR_SIZE), None);
+        assert_eq!(SMALL.fit(&USER_DICT), Some(USER_DICT));
+        assert_eq!(Flash { size: 0x20_0000, ..SMALL }.fit(&MAIN_DICT), None);
+        assert_eq!(SMALL.address(USER_DICT.offset), 0x1020_0000);
+    }
+}
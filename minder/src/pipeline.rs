@@ -0,0 +1,185 @@
+//! Sequence ids, and pipelining.
+//!
+//! A host that waits for each reply before sending the next request spends most of its time
+//! waiting: hashing a sector is quick on the device, but the request and reply each take HID
+//! frames, or a trip through the serial driver.  Wrapping requests in [`Sequenced`] lets a host
+//! have several outstanding at once, and match the replies up by their id, as the device wraps each
+//! reply with the id of the request it answers.
+//!
+//! A device that understands sequence ids gives the most requests it will take outstanding in its
+//! hello reply, its pipeline depth.  Requests past that may be dropped.  Requests without an id are
+//! answered without one, as before, so older hosts and devices keep working.  [`Window`] keeps
+//! track of the requests a host has outstanding.
+
+use alloc::collections::VecDeque;
+
+use minicbor::{Decode, Encode};
+
+use crate::{Message, Reply, Request};
+
+/// The CBOR tag on a sequenced request or reply, "mseq".
+pub const SEQUENCED_TAG: u64 = 0x6d736571;
+
+/// A request or reply, with the id that pairs them up.
+#[derive(Debug, Encode, Decode)]
+#[cbor(tag(0x6d736571))]
+pub struct Sequenced<T> {
+    #[n(0)]
+    pub seq: u32,
+    #[n(1)]
+    pub item: T,
+}
+
+/// Decode a packet that may or may not be sequenced, giving its id if it was.
+pub fn decode<T>(packet: &[u8]) -> Option<(Option<u32>, T)>
+where
+    T: for<'b> Decode<'b, ()>,
+{
+    if let Ok(sequenced) = minicbor::decode::<Sequenced<T>>(packet) {
+        return Some((Some(sequenced.seq), sequenced.item));
+    }
+    minicbor::decode::<T>(packet).ok().map(|item| (None, item))
+}
+
+/// Decode a reply from the device: a message or a bare reply from older firmware, either of which
+/// can be sequenced.  See [`Message::decode_reply`].
+pub fn decode_reply(packet: &[u8]) -> Option<(Option<u32>, Message)> {
+    if let Ok(sequenced) = minicbor::decode::<Sequenced<Message>>(packet) {
+        return Some((Some(sequenced.seq), sequenced.item));
+    }
+    if let Ok(sequenced) = minicbor::decode::<Sequenced<Reply>>(packet) {
+        return Some((Some(sequenced.seq), sequenced.item.into()));
+    }
+    Message::decode_reply(packet).map(|message| (None, message))
+}
+
+/// A request, as the device received it.
+#[derive(Debug)]
+pub struct Incoming {
+    pub message: Message,
+    /// It was a bare [`Request`], and should be answered with a bare [`Reply`].
+    pub bare: bool,
+    /// The sequence id, to give back with the reply.
+    pub seq: Option<u32>,
+}
+
+impl Incoming {
+    /// Decode a packet from the host: a message or a bare request, either of which can be
+    /// sequenced.  See [`Message::decode_request`].
+    pub fn decode(packet: &[u8]) -> Option<Incoming> {
+        if let Ok(sequenced) = minicbor::decode::<Sequenced<Message>>(packet) {
+            return Some(Incoming { message: sequenced.item, bare: false, seq: Some(sequenced.seq) });
+        }
+        if let Ok(sequenced) = minicbor::decode::<Sequenced<Request>>(packet) {
+            return Some(Incoming { message: sequenced.item.into(), bare: true, seq: Some(sequenced.seq) });
+        }
+        let (message, bare) = Message::decode_request(packet)?;
+        Some(Incoming { message, bare, seq: None })
+    }
+}
+
+/// The requests a host has outstanding, each with something to remember it by.
+#[derive(Debug)]
+pub struct Window<T> {
+    depth: usize,
+    next: u32,
+    outstanding: VecDeque<(u32, T)>,
+}
+
+impl<T> Window<T> {
+    /// A window for a device with this pipeline depth.  A depth of zero still allows one request
+    /// at a time.
+    pub fn new(depth: u8) -> Window<T> {
+        Window { depth: (depth as usize).max(1), next: 0, outstanding: VecDeque::new() }
+    }
+
+    /// Are there as many requests outstanding as the device will take.
+    pub fn is_full(&self) -> bool {
+        self.outstanding.len() >= self.depth
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    /// Note a request being sent, returning the id to send it with.
+    pub fn send(&mut self, tag: T) -> u32 {
+        let seq = self.next;
+        self.next = self.next.wrapping_add(1);
+        self.outstanding.push_back((seq, tag));
+        seq
+    }
+
+    /// A reply arrived with this id.  Returns what the request was remembered by, or None if it
+    /// isn't one of ours.
+    pub fn complete(&mut self, seq: u32) -> Option<T> {
+        let pos = self.outstanding.iter().position(|(s, _)| *s == seq)?;
+        self.outstanding.remove(pos).map(|(_, tag)| tag)
+    }
+
+    /// The oldest outstanding request, for replies without ids, which come in order.
+    pub fn oldest(&self) -> Option<(u32, &T)> {
+        self.outstanding.front().map(|(seq, tag)| (*seq, tag))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::message::{Debug, Flash};
+
+    #[test]
+    fn test_decode() {
+        // Each form of request.
+        let read = || Request::ReadFlash { offset: 0, size: 64 };
+        let read_message = || Message::Flash(Flash::Read { offset: 0, size: 64 });
+        let bare = minicbor::to_vec(read()).unwrap();
+        let message = minicbor::to_vec(read_message()).unwrap();
+        let seq_bare = minicbor::to_vec(Sequenced { seq: 7, item: read() }).unwrap();
+        let seq_message = minicbor::to_vec(Sequenced { seq: 8, item: read_message() }).unwrap();
+        for (packet, bare, seq) in [(bare, true, None), (message, false, None), (seq_bare, true, Some(7)), (seq_message, false, Some(8))] {
+            let incoming = Incoming::decode(&packet).unwrap();
+            assert_eq!(incoming.message, read_message());
+            assert_eq!((incoming.bare, incoming.seq), (bare, seq));
+        }
+        assert!(Incoming::decode(&[0xff]).is_none());
+
+        // And replies, in each form.
+        let log = || Message::Debug(Debug::Log { message: "main".to_string() });
+        let reply = Reply::Log { message: "main".to_string() };
+        let packet = minicbor::to_vec(Sequenced { seq: 3, item: &reply }).unwrap();
+        assert_eq!(decode_reply(&packet), Some((Some(3), log())));
+        let packet = minicbor::to_vec(&reply).unwrap();
+        assert_eq!(decode_reply(&packet), Some((None, log())));
+        let packet = minicbor::to_vec(Sequenced { seq: 4, item: log() }).unwrap();
+        assert_eq!(decode_reply(&packet), Some((Some(4), log())));
+        let packet = minicbor::to_vec(log()).unwrap();
+        assert!(matches!(decode::<Message>(&packet), Some((None, Message::Debug(Debug::Log { .. })))));
+    }
+
+    #[test]
+    fn test_window() {
+        let mut window = Window::new(2);
+        let a = window.send('a');
+        let b = window.send('b');
+        assert!(window.is_full());
+        assert_eq!(window.oldest(), Some((a, &'a')));
+
+        // Replies can come in any order, and strays are ignored.
+        assert_eq!(window.complete(b), Some('b'));
+        assert_eq!(window.complete(b), None);
+        assert!(!window.is_full());
+        let c = window.send('c');
+        assert_ne!(c, a);
+        assert_eq!(window.complete(a), Some('a'));
+        assert_eq!(window.complete(c), Some('c'));
+        assert!(window.is_empty());
+
+        // Devices without pipelining take one at a time.
+        let mut window = Window::new(0);
+        window.send(());
+        assert!(window.is_full());
+    }
+}
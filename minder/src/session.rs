@@ -0,0 +1,155 @@
+//! Session arbitration.
+//!
+//! A keyboard can have minder open on more than one transport at the same time.  Requests that
+//! only read are fine to interleave, but two hosts changing things at once (two programming runs,
+//! say) would leave a mess.  The arbiter lets one session at a time hold the privilege to make
+//! changes.  Requests from other sessions that need it are answered with [`Core::Busy`].
+//!
+//! A session gains the privilege with its first privileged request, and keeps it until it sends
+//! [`Core::Release`], or goes quiet for [`LEASE_MS`], after which any other session can take it
+//! over.  This keeps a host that went away mid-session from locking everyone else out.
+//!
+//! [`Core::Busy`]: crate::message::Core::Busy
+//! [`Core::Release`]: crate::message::Core::Release
+
+use crate::message::{Ble, Core, Debug, Dict, Flash, Keymap, Leds, Macros, Message, Stats};
+
+/// Identifies a session, generally one per transport.
+pub type SessionId = u8;
+
+/// How long, in ms, a privileged session can be idle before another can take over.
+pub const LEASE_MS: u64 = 10_000;
+
+/// What to do with a message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /// Handle the message.
+    Allow,
+    /// Another session holds the privilege, answer with Busy.
+    Busy(SessionId),
+}
+
+/// Tracks which session, if any, holds the privilege.
+#[derive(Debug, Default)]
+pub struct Arbiter {
+    /// The privileged session, and when, in ms, it was last heard from.
+    owner: Option<(SessionId, u64)>,
+}
+
+impl Arbiter {
+    pub fn new() -> Arbiter {
+        Arbiter::default()
+    }
+
+    /// The session currently holding the privilege, if its lease is still good.
+    pub fn owner(&self, now: u64) -> Option<SessionId> {
+        match self.owner {
+            Some((owner, seen)) if now.saturating_sub(seen) < LEASE_MS => Some(owner),
+            _ => None,
+        }
+    }
+
+    /// Decide what to do with a message received from `session` at `now` ms.
+    pub fn check(&mut self, session: SessionId, message: &Message, now: u64) -> Verdict {
+        let owner = self.owner(now);
+
+        // Any traffic from the owner keeps its lease.
+        if owner == Some(session) {
+            self.owner = Some((session, now));
+        }
+
+        if let Message::Core(Core::Release) = message {
+            if owner == Some(session) {
+                self.owner = None;
+            }
+            return Verdict::Allow;
+        }
+
+        if !message.is_privileged() {
+            return Verdict::Allow;
+        }
+        match owner {
+            Some(owner) if owner != session => Verdict::Busy(owner),
+            _ => {
+                self.owner = Some((session, now));
+                Verdict::Allow
+            }
+        }
+    }
+}
+
+impl Message {
+    /// Does this message change the state of the keyboard?  These need the privilege of the
+    /// [`Arbiter`].  Replies are never privileged, as the keyboard doesn't act on them.
+    pub fn is_privileged(&self) -> bool {
+        matches!(
+            self,
+            Message::Flash(Flash::Program { .. })
+                | Message::Flash(Flash::Erase { .. })
+                | Message::Flash(Flash::ProgramImage { .. })
+                | Message::Flash(Flash::Boot { .. })
+                | Message::Dict(Dict::SetProfile { .. })
+                | Message::Dict(Dict::Patch { .. })
+                | Message::Debug(Debug::Exec { .. })
+                | Message::Debug(Debug::GetCrashLog { clear: true })
+                | Message::Stats(Stats::SetPace { .. })
+                | Message::Keymap(Keymap::Set { .. })
+                | Message::Keymap(Keymap::SetSteno { .. })
+                | Message::Leds(Leds::SetPattern { .. })
+                | Message::Core(Core::Reboot)
+                | Message::Core(Core::TypeText { .. })
+                | Message::Core(Core::SetHostLayout { .. })
+                | Message::Core(Core::SetUnicodeEntry { .. })
+                | Message::Ble(Ble::NameBond { .. })
+                | Message::Ble(Ble::DeleteBond { .. })
+                | Message::Ble(Ble::Pair { .. })
+                | Message::Macros(Macros::Upload { .. })
+                | Message::Macros(Macros::Delete { .. })
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn program() -> Message {
+        Message::Flash(Flash::Program { offset: 0x20_0000, data: Vec::new() })
+    }
+
+    #[test]
+    fn test_arbiter() {
+        let mut arb = Arbiter::new();
+        let read = Message::Flash(Flash::Hash { offset: 0x20_0000, size: 16, algorithm: None });
+
+        // Reads are always fine.
+        assert_eq!(arb.check(0, &read, 0), Verdict::Allow);
+        assert_eq!(arb.owner(0), None);
+
+        // The first to write gets the privilege.
+        assert_eq!(arb.check(0, &program(), 100), Verdict::Allow);
+        assert_eq!(arb.check(1, &program(), 200), Verdict::Busy(0));
+        assert_eq!(arb.check(1, &read, 300), Verdict::Allow);
+
+        // Reads from the owner keep the lease.
+        assert_eq!(arb.check(0, &read, LEASE_MS), Verdict::Allow);
+        assert_eq!(arb.check(1, &program(), LEASE_MS + 100), Verdict::Busy(0));
+
+        // A quiet owner can be taken over.
+        assert_eq!(arb.check(1, &program(), 2 * LEASE_MS + 1), Verdict::Allow);
+        assert_eq!(arb.owner(2 * LEASE_MS + 1), Some(1));
+        assert_eq!(arb.check(0, &program(), 2 * LEASE_MS + 2), Verdict::Busy(1));
+        assert_eq!(arb.check(0, &Message::Core(Core::Reboot), 2 * LEASE_MS + 2), Verdict::Busy(1));
+        let type_text = Message::Core(Core::TypeText { text: "hello".to_string() });
+        assert_eq!(arb.check(0, &type_text, 2 * LEASE_MS + 2), Verdict::Busy(1));
+
+        // Only the owner can release.
+        assert_eq!(arb.check(0, &Message::Core(Core::Release), 2 * LEASE_MS + 3), Verdict::Allow);
+        assert_eq!(arb.owner(2 * LEASE_MS + 3), Some(1));
+        assert_eq!(arb.check(1, &Message::Core(Core::Release), 2 * LEASE_MS + 4), Verdict::Allow);
+        assert_eq!(arb.owner(2 * LEASE_MS + 4), None);
+        assert_eq!(arb.check(0, &program(), 2 * LEASE_MS + 5), Verdict::Allow);
+    }
+}
@@ -0,0 +1,107 @@
+//! Ed25519 signatures, for firmware images.
+//!
+//! A firmware update is only installed if its image is signed by the key the running firmware was
+//! built with.  The signature is over the SHA-256 digest of the staged image, which the device
+//! computes anyway to check the transfer, so it doesn't need to hash the whole image a second time.
+//!
+//! The signatures themselves come from `ed25519-compact`.  Signing is also here, for the host
+//! tools.  Secret keys are kept as their 32-byte seed.
+
+use ed25519_compact::{KeyPair, PublicKey, Seed, Signature};
+
+/// The size of a public key.
+pub const PUBLIC_KEY_SIZE: usize = PublicKey::BYTES;
+
+/// The size of a secret key, which is the seed the key pair is derived from.
+pub const SECRET_KEY_SIZE: usize = Seed::BYTES;
+
+/// The size of a signature.
+pub const SIGNATURE_SIZE: usize = Signature::BYTES;
+
+/// The public key for a secret key.
+pub fn public_key(secret: &[u8; SECRET_KEY_SIZE]) -> [u8; PUBLIC_KEY_SIZE] {
+    *KeyPair::from_seed(Seed::new(*secret)).pk
+}
+
+/// Sign a message.
+pub fn sign(secret: &[u8; SECRET_KEY_SIZE], message: &[u8]) -> [u8; SIGNATURE_SIZE] {
+    *KeyPair::from_seed(Seed::new(*secret)).sk.sign(message, None)
+}
+
+/// Check the signature of a message.  A signature of the wrong size is just a bad signature.
+pub fn verify(public: &[u8; PUBLIC_KEY_SIZE], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    PublicKey::new(*public).verify(message, &signature).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex<const N: usize>(text: &str) -> [u8; N] {
+        core::array::from_fn(|i| u8::from_str_radix(&text[2 * i..2 * i + 2], 16).unwrap())
+    }
+
+    #[test]
+    fn test_rfc8032() {
+        // The first two test vectors from RFC 8032.
+        let vectors: [(&str, &str, &[u8], &str); 2] = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                b"",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bac\
+                 c61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                &[0x72],
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e\
+                 458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+        for (secret, public, message, signature) in vectors {
+            let secret = hex::<32>(secret);
+            let public = hex::<32>(public);
+            let signature = hex::<64>(signature);
+            assert_eq!(public_key(&secret), public);
+            assert_eq!(sign(&secret, message), signature);
+            assert!(verify(&public, message, &signature));
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let secret = [7u8; 32];
+        let public = public_key(&secret);
+        let signature = sign(&secret, b"firmware");
+        assert!(verify(&public, b"firmware", &signature));
+
+        // Anything changed is refused.
+        assert!(!verify(&public, b"firmwarf", &signature));
+        assert!(!verify(&public_key(&[8; 32]), b"firmware", &signature));
+        for i in [0, 31, 32, 63] {
+            let mut bad = signature;
+            bad[i] ^= 1;
+            assert!(!verify(&public, b"firmware", &bad));
+        }
+        assert!(!verify(&public, b"firmware", &signature[..63]));
+
+        // So is s + L, which would otherwise check out.
+        const L: [i64; 32] = [
+            0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde,
+            0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+        ];
+        let mut malleable = signature;
+        let mut carry = 0;
+        for i in 0..32 {
+            let sum = malleable[32 + i] as i64 + L[i] + carry;
+            malleable[32 + i] = sum as u8;
+            carry = sum >> 8;
+        }
+        assert!(!verify(&public, b"firmware", &malleable));
+    }
+}
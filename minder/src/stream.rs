@@ -0,0 +1,163 @@
+//! Event streaming.
+//!
+//! A monitor on the host can ask to watch key events and steno strokes as they happen, to debug a
+//! layout.  As HID has no idea of a connection, the keyboard can't tell when the monitor goes away,
+//! so a subscription only lasts for a timeout, and the monitor has to renew it before then.  Once
+//! it lapses, the stream stops, and anything still queued is dropped.
+//!
+//! The events are queued here until the firmware gets around to sending them.  If they aren't
+//! being sent fast enough, the oldest are dropped, rather than using up memory.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use minicbor::{Decode, Encode};
+
+use crate::message::Debug;
+
+/// Seconds a subscription lasts when the request doesn't say.
+pub const DEFAULT_TIMEOUT: u32 = 5;
+
+/// The longest a subscription can last without being renewed, in seconds.
+pub const MAX_TIMEOUT: u32 = 60;
+
+/// The most events queued to be sent.
+pub const MAX_QUEUED: usize = 64;
+
+/// The kinds of events that can be streamed.
+#[derive(Clone, Copy, Debug, Encode, Decode, Eq, PartialEq)]
+#[cbor(index_only)]
+pub enum EventKind {
+    /// Keys going up and down, by scan code.
+    #[n(0)]
+    Keys,
+    /// Steno strokes.
+    #[n(1)]
+    Strokes,
+}
+
+/// The current subscription, and the events waiting to be sent.
+#[derive(Debug, Default)]
+pub struct Stream {
+    /// The kinds of events subscribed to.
+    events: Vec<EventKind>,
+    /// When, in ms, the subscription lapses.
+    until: u64,
+    /// Events waiting to be sent.
+    queue: VecDeque<Debug>,
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream::default()
+    }
+
+    /// Start, renew, or with no events, end a subscription, at `now` ms.  Returns the reply to
+    /// send, with the timeout actually used.
+    pub fn subscribe(&mut self, events: Vec<EventKind>, timeout: u32, now: u64) -> Debug {
+        let timeout = match timeout {
+            0 => DEFAULT_TIMEOUT,
+            t => t.min(MAX_TIMEOUT),
+        };
+        if events.is_empty() {
+            self.end();
+        } else {
+            self.until = now + timeout as u64 * 1000;
+        }
+        self.events = events.clone();
+        Debug::Subscribed { events, timeout }
+    }
+
+    /// Is the subscription good at `now`, and does it include this kind of event.
+    pub fn wants(&mut self, kind: EventKind, now: u64) -> bool {
+        self.check(now);
+        self.events.contains(&kind)
+    }
+
+    /// Queue a key event, if subscribed to.
+    pub fn key(&mut self, key: u8, press: bool, now: u64) {
+        if self.wants(EventKind::Keys, now) {
+            self.push(Debug::Key { time: now, key, press });
+        }
+    }
+
+    /// Queue a stroke, if subscribed to.  The stroke is only built when it will be sent.
+    pub fn stroke<F: FnOnce() -> String>(&mut self, stroke: F, now: u64) {
+        if self.wants(EventKind::Strokes, now) {
+            self.push(Debug::Stroke { time: now, stroke: stroke() });
+        }
+    }
+
+    /// The next event to send.
+    pub fn pop(&mut self, now: u64) -> Option<Debug> {
+        self.check(now);
+        self.queue.pop_front()
+    }
+
+    fn push(&mut self, event: Debug) {
+        if self.queue.len() == MAX_QUEUED {
+            self.queue.pop_front();
+        }
+        self.queue.push_back(event);
+    }
+
+    /// End the subscription if it has lapsed.
+    fn check(&mut self, now: u64) {
+        if !self.events.is_empty() && now >= self.until {
+            self.end();
+        }
+    }
+
+    fn end(&mut self) {
+        self.events.clear();
+        self.queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_stream() {
+        let mut stream = Stream::new();
+
+        // Nothing is queued without a subscription.
+        stream.key(3, true, 0);
+        assert!(stream.pop(0).is_none());
+
+        assert!(matches!(stream.subscribe(vec![EventKind::Keys], 0, 1000),
+                         Debug::Subscribed { timeout: DEFAULT_TIMEOUT, .. }));
+        stream.key(3, true, 1100);
+        stream.stroke(|| panic!("not subscribed to strokes"), 1200);
+        stream.key(3, false, 1300);
+        assert!(matches!(stream.pop(1400), Some(Debug::Key { time: 1100, key: 3, press: true })));
+
+        // Renewing keeps it going past the first timeout.
+        stream.subscribe(vec![EventKind::Keys, EventKind::Strokes], 10, 5000);
+        assert!(matches!(stream.pop(5500), Some(Debug::Key { time: 1300, key: 3, press: false })));
+        stream.stroke(|| "STKPW".to_string(), 14000);
+        assert!(matches!(stream.pop(14000), Some(Debug::Stroke { time: 14000, .. })));
+
+        // Once it lapses, events are dropped.
+        stream.key(4, true, 14500);
+        assert!(stream.pop(15000).is_none());
+        stream.key(4, false, 15100);
+        assert!(stream.pop(15100).is_none());
+
+        // Too many events drops the oldest.
+        stream.subscribe(vec![EventKind::Keys], 1000, 20000);
+        for i in 0..MAX_QUEUED + 2 {
+            stream.key(i as u8, true, 20000);
+        }
+        assert!(matches!(stream.pop(20000), Some(Debug::Key { key: 2, .. })));
+
+        // An empty subscription ends it.
+        stream.subscribe(vec![], 0, 21000);
+        assert!(stream.pop(21000).is_none());
+    }
+}
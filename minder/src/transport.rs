@@ -0,0 +1,433 @@
+//! Transports.
+//!
+//! Minder messages travel over more than one kind of link.  A serial link is a stream of bytes,
+//! framed by [`serial_encode`] and [`SerialDecoder`], and a HID link is a series of fixed-size
+//! reports, framed by [`hid_encode`] and [`HidDecoder`].  A [`Transport`] hides the framing, so
+//! the code on either end only deals in whole messages, and can be written once for any link.
+//!
+//! A serial transport can also retry damaged messages, with [`SerialTransport::with_arq`].  See
+//! [`crate::arq`].
+//!
+//! The link itself is supplied by the user of the transport, as a [`ByteLink`] or a
+//! [`PacketLink`].  [`MemTransport`] skips the link entirely, and passes messages between a pair of
+//! transports in memory, which is useful for testing.
+
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use log::warn;
+use minicbor::{Decode, Encode};
+
+use crate::{hid_encode, serial_encode, Arq, HidDecoder, HidWrite, SerialDecoder, SerialWrite, PACKET_SIZE};
+
+/// Carries whole messages over a link.
+pub trait Transport {
+    type Error;
+
+    /// Send a single item.
+    fn send<T: Encode<()>>(&mut self, item: &T) -> Result<(), Self::Error>;
+
+    /// Receive what the link has, returning the CBOR of the next complete message.  Returns None
+    /// once the link has nothing more for now.
+    fn poll(&mut self) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Forget any partly received message, such as after the other end has gone away.
+    fn reset(&mut self);
+
+    /// Receive the next message that decodes as a `T`.  Messages that don't are skipped.
+    fn receive<T>(&mut self) -> Result<Option<T>, Self::Error>
+    where
+        T: for<'b> Decode<'b, ()>,
+    {
+        while let Some(packet) = self.poll()? {
+            match minicbor::decode(&packet) {
+                Ok(item) => return Ok(Some(item)),
+                Err(e) => warn!("cbor decode: {:?}", e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A link carrying a stream of bytes, such as a serial port.
+pub trait ByteLink: SerialWrite {
+    /// Read whatever bytes have arrived, returning how many.  Zero means there are none for now.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+/// A link carrying packets of [`PACKET_SIZE`] bytes, such as HID reports.
+pub trait PacketLink: HidWrite {
+    /// Read a single packet, if one has arrived.  Returns whether one was read.
+    fn read_packet(&mut self, buf: &mut [u8; PACKET_SIZE]) -> Result<bool, Self::Error>;
+}
+
+/// The size of the reads from a serial link.
+const READ_SIZE: usize = 256;
+
+/// A transport over a serial link.
+pub struct SerialTransport<L> {
+    link: L,
+    decoder: SerialDecoder,
+    use_crc: bool,
+    /// Retransmission, if used, and the clock it runs on, in ms.
+    arq: Option<(Arq, fn() -> u64)>,
+    /// Bytes read from the link, but not yet decoded.
+    buffer: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl<L: ByteLink> SerialTransport<L> {
+    pub fn new(link: L, use_crc: bool) -> SerialTransport<L> {
+        SerialTransport {
+            link,
+            decoder: SerialDecoder::new(),
+            use_crc,
+            arq: None,
+            buffer: vec![0u8; READ_SIZE],
+            offset: 0,
+            len: 0,
+        }
+    }
+
+    /// A transport that retries damaged messages with `arq`, whose timers run on `clock`, the time
+    /// in ms.  Frames not acknowledged in time are sent again when polled, so a poll should come
+    /// every [`crate::arq::RETRANSMIT_MS`] or so, while waiting.
+    pub fn with_arq(link: L, arq: Arq, clock: fn() -> u64) -> SerialTransport<L> {
+        SerialTransport { arq: Some((arq, clock)), ..SerialTransport::new(link, true) }
+    }
+
+    /// Write whatever ARQ has to send.
+    fn flush(&mut self) -> Result<(), L::Error> {
+        if let Some((arq, clock)) = &mut self.arq {
+            let now = clock();
+            while let Some(frame) = arq.next_frame(now) {
+                self.link.write_all(&frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The link, for settings that belong to it.
+    pub fn link(&mut self) -> &mut L {
+        &mut self.link
+    }
+}
+
+impl<L: ByteLink> Transport for SerialTransport<L> {
+    type Error = L::Error;
+
+    fn send<T: Encode<()>>(&mut self, item: &T) -> Result<(), Self::Error> {
+        match &mut self.arq {
+            Some((arq, _)) => {
+                arq.send(item);
+                self.flush()
+            }
+            None => serial_encode(item, &mut self.link, self.use_crc),
+        }
+    }
+
+    fn poll(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        loop {
+            if self.offset >= self.len {
+                // Acknowledge what has arrived, and resend what is overdue, before waiting.
+                self.flush()?;
+                let count = self.link.read(&mut self.buffer)?;
+                if count == 0 {
+                    return Ok(None);
+                }
+                self.offset = 0;
+                self.len = count;
+            }
+
+            let byte = self.buffer[self.offset];
+            self.offset += 1;
+            match &mut self.arq {
+                Some((arq, _)) => {
+                    if let Some(packet) = arq.add_packet(byte) {
+                        return Ok(Some(packet));
+                    }
+                }
+                None => {
+                    if let Some(packet) = self.decoder.add_packet(byte) {
+                        return Ok(Some(packet.to_vec()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.decoder.reset();
+        if let Some((arq, _)) = &mut self.arq {
+            arq.reset();
+        }
+        self.offset = 0;
+        self.len = 0;
+    }
+}
+
+/// A transport over a HID link.
+pub struct HidTransport<L> {
+    link: L,
+    decoder: HidDecoder,
+}
+
+/// Collects the packets of an item, so that encoding can't fail part way through writing to the
+/// link.
+struct Packets(Vec<[u8; PACKET_SIZE]>);
+
+impl HidWrite for Packets {
+    type Error = Infallible;
+
+    fn write_packet(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        let mut packet = [0u8; PACKET_SIZE];
+        packet.copy_from_slice(buf);
+        self.0.push(packet);
+        Ok(())
+    }
+}
+
+impl<L: PacketLink> HidTransport<L> {
+    pub fn new(link: L) -> HidTransport<L> {
+        HidTransport { link, decoder: HidDecoder::new() }
+    }
+
+    /// The link, for settings that belong to it.
+    pub fn link(&mut self) -> &mut L {
+        &mut self.link
+    }
+}
+
+impl<L: PacketLink> Transport for HidTransport<L> {
+    type Error = L::Error;
+
+    fn send<T: Encode<()>>(&mut self, item: &T) -> Result<(), Self::Error> {
+        let mut packets = Packets(Vec::new());
+        // Writing to memory can't fail.
+        hid_encode(item, &mut packets).unwrap();
+        for packet in &packets.0 {
+            self.link.write_packet(packet)?;
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let mut packet = [0u8; PACKET_SIZE];
+        while self.link.read_packet(&mut packet)? {
+            self.decoder.add_packet(&packet);
+            if let Some(message) = self.decoder.take_packet() {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    fn reset(&mut self) {
+        self.decoder.reset();
+    }
+}
+
+/// A transport that passes messages to its peer in memory, without any link.
+pub struct MemTransport {
+    incoming: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    outgoing: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl MemTransport {
+    /// Two transports, each receiving what the other sends.
+    pub fn pair() -> (MemTransport, MemTransport) {
+        let a = Rc::new(RefCell::new(VecDeque::new()));
+        let b = Rc::new(RefCell::new(VecDeque::new()));
+        (
+            MemTransport { incoming: a.clone(), outgoing: b.clone() },
+            MemTransport { incoming: b, outgoing: a },
+        )
+    }
+}
+
+impl Transport for MemTransport {
+    type Error = Infallible;
+
+    fn send<T: Encode<()>>(&mut self, item: &T) -> Result<(), Self::Error> {
+        self.outgoing.borrow_mut().push_back(minicbor::to_vec(item).unwrap());
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.incoming.borrow_mut().pop_front())
+    }
+
+    fn reset(&mut self) {
+        self.incoming.borrow_mut().clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::message::Core;
+    use crate::{Message, Reply, Request};
+
+    /// One direction of a link, shared between the two ends.
+    type Queue<T> = Rc<RefCell<VecDeque<T>>>;
+
+    /// An end of an in-memory link, which can be either kind.
+    struct Pipe<T> {
+        rx: Queue<T>,
+        tx: Queue<T>,
+    }
+
+    fn pipes<T>() -> (Pipe<T>, Pipe<T>) {
+        let a = Rc::new(RefCell::new(VecDeque::new()));
+        let b = Rc::new(RefCell::new(VecDeque::new()));
+        (Pipe { rx: a.clone(), tx: b.clone() }, Pipe { rx: b, tx: a })
+    }
+
+    impl SerialWrite for Pipe<u8> {
+        type Error = Infallible;
+
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            self.tx.borrow_mut().extend(buf);
+            Ok(())
+        }
+    }
+
+    impl ByteLink for Pipe<u8> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            // Short reads, so packets are split across them.
+            let mut rx = self.rx.borrow_mut();
+            let count = rx.len().min(buf.len()).min(7);
+            for (dest, byte) in buf.iter_mut().zip(rx.drain(..count)) {
+                *dest = byte;
+            }
+            Ok(count)
+        }
+    }
+
+    impl HidWrite for Pipe<[u8; PACKET_SIZE]> {
+        type Error = Infallible;
+
+        fn write_packet(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            let mut packet = [0u8; PACKET_SIZE];
+            packet.copy_from_slice(buf);
+            self.tx.borrow_mut().push_back(packet);
+            Ok(())
+        }
+    }
+
+    impl PacketLink for Pipe<[u8; PACKET_SIZE]> {
+        fn read_packet(&mut self, buf: &mut [u8; PACKET_SIZE]) -> Result<bool, Self::Error> {
+            match self.rx.borrow_mut().pop_front() {
+                Some(packet) => {
+                    *buf = packet;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+    }
+
+    /// A request and its reply, in both the bare and message forms, each way across the pair.
+    fn check_roundtrip<T: Transport>(host: &mut T, device: &mut T)
+    where
+        T::Error: core::fmt::Debug,
+    {
+        assert!(device.poll().unwrap().is_none());
+
+        // Long enough to take several HID packets.
+        let version = "A version string that is long enough to need more than one packet".repeat(2);
+        host.send(&Request::Hello { version: version.clone() }).unwrap();
+        host.send(&Message::Core(Core::GetStatus)).unwrap();
+
+        let packet = device.poll().unwrap().unwrap();
+        let (message, bare) = Message::decode_request(&packet).unwrap();
+        assert!(bare);
+        assert_eq!(message, Message::Core(Core::Hello { version: version.clone() }));
+        let packet = device.poll().unwrap().unwrap();
+        assert!(matches!(Message::decode_request(&packet), Some((Message::Core(_), false))));
+        assert!(device.poll().unwrap().is_none());
+
+        device.send(&Message::Core(Core::Rebooting)).unwrap();
+        device.send(&Reply::Log { message: version.clone() }).unwrap();
+        assert_eq!(host.receive::<Message>().unwrap(), Some(Message::Core(Core::Rebooting)));
+        assert!(matches!(host.receive::<Reply>().unwrap(), Some(Reply::Log { message }) if message == version));
+        assert!(host.receive::<Reply>().unwrap().is_none());
+
+        // Things that don't decode are skipped.
+        device.send(&Message::Core(Core::Rebooting)).unwrap();
+        device.send(&Reply::Log { message: version.clone() }).unwrap();
+        assert!(matches!(host.receive::<Reply>().unwrap(), Some(Reply::Log { .. })));
+    }
+
+    #[test]
+    fn test_serial() {
+        let (a, b) = pipes();
+        check_roundtrip(&mut SerialTransport::new(a, true), &mut SerialTransport::new(b, true));
+
+        // A partial message is dropped by a reset.
+        let (a, b) = pipes();
+        let (mut host, mut device) = (SerialTransport::new(a, false), SerialTransport::new(b, false));
+        host.send(&Request::ReadFlash { offset: 0, size: 64 }).unwrap();
+        let half = host.link().tx.borrow().len() / 2;
+        host.link().tx.borrow_mut().truncate(half);
+        assert!(device.poll().unwrap().is_none());
+        device.reset();
+        host.send(&Message::Core(Core::Reboot)).unwrap();
+        assert_eq!(device.receive::<Message>().unwrap(), Some(Message::Core(Core::Reboot)));
+    }
+
+    #[test]
+    fn test_serial_arq() {
+        let (a, b) = pipes();
+        check_roundtrip(
+            &mut SerialTransport::with_arq(a, Arq::initiator(0), || 0),
+            &mut SerialTransport::with_arq(b, Arq::responder(), || 0),
+        );
+
+        // A damaged message is sent again, once it's overdue.
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        let clock = || NOW.load(Ordering::Relaxed);
+        let (a, b) = pipes();
+        let (mut host, mut device) =
+            (SerialTransport::with_arq(a, Arq::initiator(0), clock), SerialTransport::with_arq(b, Arq::responder(), clock));
+        host.send(&Request::ReadFlash { offset: 0, size: 64 }).unwrap();
+        host.link().tx.borrow_mut()[4] ^= 0x01;
+        assert!(device.poll().unwrap().is_none());
+        NOW.store(crate::arq::RETRANSMIT_MS, Ordering::Relaxed);
+        assert!(host.poll().unwrap().is_none());
+        assert_eq!(device.receive::<Request>().unwrap(), Some(Request::ReadFlash { offset: 0, size: 64 }));
+    }
+
+    #[test]
+    fn test_hid() {
+        let (a, b) = pipes();
+        check_roundtrip(&mut HidTransport::new(a), &mut HidTransport::new(b));
+
+        let (a, b) = pipes();
+        let (mut host, mut device) = (HidTransport::new(a), HidTransport::new(b));
+        host.send(&Request::Hello { version: "x".repeat(100) }).unwrap();
+        host.link().tx.borrow_mut().pop_back();
+        assert!(device.poll().unwrap().is_none());
+        device.reset();
+        host.send(&Message::Core(Core::Reboot)).unwrap();
+        assert_eq!(device.receive::<Message>().unwrap(), Some(Message::Core(Core::Reboot)));
+    }
+
+    #[test]
+    fn test_mem() {
+        let (mut host, mut device) = MemTransport::pair();
+        check_roundtrip(&mut host, &mut device);
+
+        host.send(&Request::Hello { version: "test".to_string() }).unwrap();
+        device.reset();
+        assert!(device.poll().unwrap().is_none());
+    }
+}
@@ -13,6 +13,8 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+use minder::ImageInfo;
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -28,4 +30,12 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // The image metadata, which `image.rs` places into the image.  The board is the keyboard
+    // selected by the features.
+    let board = if env::var_os("CARGO_FEATURE_PROTO2").is_some() { "proto2" } else { "proto3" };
+    File::create(out.join("image_info.bin"))
+        .unwrap()
+        .write_all(&ImageInfo::for_build(board).to_block())
+        .unwrap();
 }
@@ -12,4 +12,15 @@ SECTIONS {
     {
         KEEP(*(.boot2));
     } > BOOT2
-} INSERT BEFORE .text;
\ No newline at end of file
+} INSERT BEFORE .text;
+
+SECTIONS {
+    /* ### Image metadata, at a fixed offset from the start of the image (see minder::image) */
+    .image_info ORIGIN(BOOT2) + 0x200 :
+    {
+        KEEP(*(.image_info));
+    } > FLASH
+} INSERT AFTER .vector_table;
+
+/* The code goes after the metadata, rather than right after the vector table. */
+_stext = ORIGIN(BOOT2) + 0x300;
@@ -10,6 +10,14 @@ pub use proto2::*;
 #[cfg(feature = "proto3")]
 pub use proto3::*;
 
+use minder::partition::{Flash, FLASH_BASE};
+
+/// The flash on the Sparkfun Pro Micro RP2040, which both prototypes are built on.
+pub const FLASH: Flash = Flash {
+    base: FLASH_BASE as usize,
+    size: 16 * 1024 * 1024,
+};
+
 macro_rules! col_pins {
     ($pins:expr, $($pin:ident),*) => {
         [
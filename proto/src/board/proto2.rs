@@ -4,6 +4,9 @@ pub const NCOLS: usize = 5;
 pub const NROWS: usize = 3;
 pub const NKEYS: usize = NCOLS * NROWS;
 
+/// Whether the layouts should treat this as a two-row keyboard.
+pub const TWO_ROW: bool = false;
+
 macro_rules! cols {
     ($pins:expr) => {
         crate::board::col_pins!($pins, gpio2, gpio3, gpio4, gpio5, gpio6)
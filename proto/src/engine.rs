@@ -0,0 +1,109 @@
+//! The hardware the event task drives.
+//!
+//! The main loop itself is [`bbq_keyboard::engine::Engine`].  The hardware is held in RTIC
+//! resources, which only a task can lock, so the engine's calls are queued as [`Request`]s, and
+//! the event task carries them out after giving the engine its inputs.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+
+use bbq_keyboard::engine::{Engine, Inter, Keys, Leds, Timers, Usb, TICK};
+use bbq_keyboard::{time::Duration, InterState, KeyEvent};
+use defmt::warn;
+use rtic_sync::channel::Sender;
+
+/// Enough for every key, on both sides, to change at once, along with what else comes with them.
+pub const REQUEST_CAPACITY: usize = 128;
+
+/// What the engine asks of the hardware.
+pub enum Request {
+    /// Show, or stop showing, that USB is suspended.
+    Suspended(bool),
+    /// A key changed, which wakes a suspended host.
+    Activity,
+    /// Give a key to the layout.
+    Layout(KeyEvent),
+    /// Send a key to the primary side.
+    AddKey(KeyEvent),
+    /// Tell the other half which side is driving.
+    SetState(InterState),
+}
+
+#[derive(Clone)]
+struct Hooks(Sender<'static, Request, REQUEST_CAPACITY>);
+
+impl Hooks {
+    fn push(&mut self, request: Request) -> bool {
+        if self.0.try_send(request).is_err() {
+            warn!("Unable to queue engine request");
+            return false;
+        }
+        true
+    }
+}
+
+/// Build the engine for the event task.
+pub fn build(requests: Sender<'static, Request, REQUEST_CAPACITY>) -> Engine {
+    let hooks = Hooks(requests);
+    Engine::new(
+        Box::new(hooks.clone()),
+        Box::new(hooks.clone()),
+        Some(Box::new(hooks.clone())),
+        Box::new(hooks.clone()),
+        Box::new(hooks),
+    )
+}
+
+/// The proto boards only have USB, and no battery.
+impl Usb for Hooks {
+    fn select_transport(&mut self, _usb: bool, _ble: bool) {}
+
+    fn set_battery(&mut self, _battery: bool) {}
+}
+
+impl Leds for Hooks {
+    fn set_suspended(&mut self, suspended: bool) {
+        self.push(Request::Suspended(suspended));
+    }
+
+    /// The periodic task steps the LEDs, as it also passes them between the halves.
+    fn tick(&mut self) {}
+}
+
+/// There is no power policy, so nothing to slow down.
+impl Timers for Hooks {
+    fn led_period(&self) -> Duration {
+        TICK
+    }
+
+    fn update_power(&mut self) -> bool {
+        false
+    }
+}
+
+impl Keys for Hooks {
+    fn track(&mut self, _key: KeyEvent) {
+        self.push(Request::Activity);
+    }
+
+    fn layout(&mut self, key: KeyEvent) -> bool {
+        self.push(Request::Layout(key))
+    }
+
+    /// The proto boards don't have an encoder.
+    fn encoder(&mut self, _delta: i8) {}
+}
+
+impl Inter for Hooks {
+    fn add_key(&mut self, key: KeyEvent) {
+        self.push(Request::AddKey(key));
+    }
+
+    fn set_state(&mut self, state: InterState) {
+        self.push(Request::SetState(state));
+    }
+
+    /// The link doesn't sleep.
+    fn sleep(&mut self, _asleep: bool) {}
+}
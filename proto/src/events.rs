@@ -0,0 +1,101 @@
+//! The event task's inputs.
+//!
+//! Each kind of input has its own channel, typed as bbq-keyboard gives it, the same as jolt does,
+//! so that a burst of keys can't crowd out the others.  The tick is a channel of one, so ticks the
+//! event task hasn't gotten to yet are merged.  Whatever sends an input also wakes the event task,
+//! and each time it wakes, it takes everything waiting.
+
+use bbq_keyboard::{HostEvent, InterEvent, KeyEvent};
+use defmt::warn;
+use rtic_sync::channel::{Receiver, Sender};
+use rtic_sync::make_channel;
+
+/// Enough for every key, on both sides, to change at once.
+pub const KEY_CAPACITY: usize = 64;
+pub const INTER_CAPACITY: usize = 64;
+pub const HOST_CAPACITY: usize = 8;
+
+/// The sending end, cloned for each source.
+#[derive(Clone)]
+pub struct Events {
+    keys: Sender<'static, KeyEvent, KEY_CAPACITY>,
+    inter: Sender<'static, InterEvent, INTER_CAPACITY>,
+    host: Sender<'static, HostEvent, HOST_CAPACITY>,
+    tick: Sender<'static, (), 1>,
+    wake: Sender<'static, (), 1>,
+}
+
+/// The receiving end, for the event task.
+pub struct Inputs {
+    pub keys: Receiver<'static, KeyEvent, KEY_CAPACITY>,
+    pub inter: Receiver<'static, InterEvent, INTER_CAPACITY>,
+    pub host: Receiver<'static, HostEvent, HOST_CAPACITY>,
+    tick: Receiver<'static, (), 1>,
+    wake: Receiver<'static, (), 1>,
+}
+
+/// Make the channels.  This can only be called once.
+pub fn new() -> (Events, Inputs) {
+    let (keys_send, keys) = make_channel!(KeyEvent, KEY_CAPACITY);
+    let (inter_send, inter) = make_channel!(InterEvent, INTER_CAPACITY);
+    let (host_send, host) = make_channel!(HostEvent, HOST_CAPACITY);
+    let (tick_send, tick) = make_channel!((), 1);
+    let (wake_send, wake) = make_channel!((), 1);
+    let events = Events {
+        keys: keys_send,
+        inter: inter_send,
+        host: host_send,
+        tick: tick_send,
+        wake: wake_send,
+    };
+    (events, Inputs { keys, inter, host, tick, wake })
+}
+
+impl Events {
+    /// A key on this side's matrix.  This waits for room, rather than lose the key.
+    pub async fn key(&mut self, key: KeyEvent) {
+        if self.keys.send(key).await.is_err() {
+            warn!("Unable to send key event");
+        }
+        self.wake();
+    }
+
+    /// Something from the other half.
+    pub fn inter(&mut self, event: InterEvent) {
+        if self.inter.try_send(event).is_err() {
+            warn!("UART: event queue full");
+        }
+        self.wake();
+    }
+
+    /// A change in the connection to a host.
+    pub fn host(&mut self, event: HostEvent) {
+        if self.host.try_send(event).is_err() {
+            warn!("USB IRQ: event queue full");
+        }
+        self.wake();
+    }
+
+    /// A tick of the periodic task.
+    pub fn tick(&mut self) {
+        let _ = self.tick.try_send(());
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        // Already full just means the event task hasn't woken yet.
+        let _ = self.wake.try_send(());
+    }
+}
+
+impl Inputs {
+    /// Wait until something has been sent.
+    pub async fn wait(&mut self) {
+        let _ = self.wake.recv().await;
+    }
+
+    /// Has there been a tick since the last call.
+    pub fn take_tick(&mut self) -> bool {
+        self.tick.try_recv().is_ok()
+    }
+}
@@ -2,19 +2,19 @@
 
 // At this point, we're just using the rp2040_hal UART type directly.
 
-use core::mem::replace;
-
 use arraydeque::ArrayDeque;
 use defmt::{info, warn};
 use embedded_hal::serial::Read;
-use rtic_sync::channel::Sender;
 use smart_leds::RGB8;
 use sparkfun_pro_micro_rp2040::hal;
-use sparkfun_pro_micro_rp2040::hal::uart::UartPeripheral;
+use sparkfun_pro_micro_rp2040::hal::uart::{ReadErrorType, UartPeripheral};
+
+use bbq_keyboard::ser2::{LinkQuality, UartErrors};
+use bbq_keyboard::{InterEvent, InterState, KeyEvent, Side};
 
-use bbq_keyboard::{Event, InterState, KeyEvent, Side};
+use bbq_keyboard::serialize::{Decoder, KeyBits, Packet, PacketBuffer};
 
-use bbq_keyboard::serialize::{Decoder, EventVec, Packet, PacketBuffer};
+use crate::events::Events;
 
 pub struct InterHandler<D, P>
 where
@@ -28,10 +28,19 @@ where
     side: Side,
     seq: u8,
     state: InterState,
-    keys: EventVec,
+    /// The keys currently pressed on this side, sent when secondary.
+    keys: KeyBits,
+    /// The keys last received from the other side.
+    last_keys: KeyBits,
 
     /// RGB values to send to other side.
     leds: RGB8,
+
+    /// RGB values received from the other side, not yet shown.
+    other_leds: Option<RGB8>,
+
+    /// Receive errors, and when to resync.
+    quality: LinkQuality,
 }
 
 impl<D: hal::uart::UartDevice, P: hal::uart::ValidUartPinout<D>> InterHandler<D, P> {
@@ -50,20 +59,23 @@ impl<D: hal::uart::UartDevice, P: hal::uart::ValidUartPinout<D>> InterHandler<D,
             seq: 1,
             side,
             state: InterState::Idle,
-            keys: EventVec::new(),
+            keys: KeyBits::default(),
+            last_keys: KeyBits::default(),
             leds: RGB8::new(4, 4, 4),
+            other_leds: None,
+            // Ticked every ms.
+            quality: LinkQuality::new(1000),
         }
     }
 
-    pub(crate) fn poll(
-        &mut self,
-        events: &mut Sender<'static, Event, { crate::app::EVENT_CAPACITY }>,
-    ) {
+    pub(crate) fn poll(&mut self, events: &mut Events) {
         self.try_recv(events);
         self.try_send();
     }
 
     pub fn tick(&mut self) {
+        self.quality.tick();
+
         // If we are transmitting still, just warn about the overflow.
         if !self.xmit_buffer.is_empty() {
             warn!("transmit overflow");
@@ -85,13 +97,9 @@ impl<D: hal::uart::UartDevice, P: hal::uart::ValidUartPinout<D>> InterHandler<D,
                 .encode(&mut self.xmit_buffer, &mut self.seq);
             }
             InterState::Secondary => {
-                let keys = replace(&mut self.keys, EventVec::new());
-                // if !keys.is_empty() {
-                //     info!("Send secondary {} keys", keys.len());
-                // }
                 Packet::Secondary {
                     side: self.side,
-                    keys,
+                    keys: self.keys,
                 }
                 .encode(&mut self.xmit_buffer, &mut self.seq);
             }
@@ -125,12 +133,23 @@ impl<D: hal::uart::UartDevice, P: hal::uart::ValidUartPinout<D>> InterHandler<D,
         }
     }
 
-    fn try_recv(&mut self, events: &mut Sender<'static, Event, { crate::app::EVENT_CAPACITY }>) {
+    fn try_recv(&mut self, events: &mut Events) {
         while self.uart.uart_is_readable() {
             let byte = match self.uart.read() {
                 Ok(b) => b,
-                Err(_) => {
-                    warn!("Uart recv error");
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(err)) => {
+                    let errors = match err {
+                        ReadErrorType::Overrun => UartErrors::OVERRUN,
+                        ReadErrorType::Break => UartErrors::BREAK,
+                        ReadErrorType::Parity => UartErrors::PARITY,
+                        ReadErrorType::Framing => UartErrors::FRAMING,
+                    };
+                    if self.quality.errors(errors) {
+                        self.resync();
+                        continue;
+                    }
+                    // An invalid token, which drops any partial packet.
                     0x80
                 }
             };
@@ -150,49 +169,83 @@ impl<D: hal::uart::UartDevice, P: hal::uart::ValidUartPinout<D>> InterHandler<D,
                         // are secondary.
                         // info!("Got primary");
                         self.set_state(InterState::Secondary, events);
-                        let _ = events.try_send(Event::RecvLed(led));
+                        self.other_leds = Some(led);
                     }
                     Packet::Secondary { side: _, keys } => {
                         // info!("Secondary");
-                        if events.try_send(Event::Heartbeat).is_err() {
-                            warn!("UART: event queue full");
-                        }
-                        for key in &keys {
-                            if events.try_send(Event::InterKey(*key)).is_err() {
-                                warn!("UART: key event queue full");
-                            }
-                        }
-                        // if !keys.is_empty() {
-                        //     info!("{} keys", keys.len());
-                        // }
+                        events.inter(InterEvent::Heartbeat);
+                        self.update_keys(keys, events);
                     }
                 }
             }
         }
     }
 
+    /// After a burst of errors, start over at the next packet: discard whatever is waiting in the
+    /// FIFO, and any partial packet.
+    fn resync(&mut self) {
+        while self.uart.uart_is_readable() {
+            let _ = self.uart.read();
+        }
+        self.receiver = Decoder::new();
+        let errors = self.quality.stats();
+        warn!("Uart resync, {} overruns, {} framing errors", errors.overruns, errors.framing);
+    }
+
     /// Set our current state.  This is generally either Primary or Idle, where
     /// Primary indicates we have become the primary in the communication, and
     /// Idle which indicates we have disconnected from USB.
-    pub(crate) fn set_state(
-        &mut self,
-        state: InterState,
-        events: &mut Sender<'static, Event, { crate::app::EVENT_CAPACITY }>,
-    ) {
+    pub(crate) fn set_state(&mut self, state: InterState, events: &mut Events) {
         if self.state != state {
             self.state = state;
             info!("Inter state change: {}", state);
-            if events.try_send(Event::BecomeState(state)).is_err() {
-                warn!("set_state: UART: event queue full");
-            }
+            events.inter(InterEvent::BecomeState(state));
         }
     }
 
     pub fn add_key(&mut self, key: KeyEvent) {
-        self.keys.push(key);
+        let index = key.key() / 8;
+        let bit = 1u8 << (key.key() % 8);
+        if key.is_press() {
+            self.keys[index as usize] |= bit;
+        } else {
+            self.keys[index as usize] &= !bit;
+        }
+    }
+
+    /// Send events for every key that has changed.
+    fn update_keys(&mut self, keys: KeyBits, events: &mut Events) {
+        // Quickly handle the common case of no changes.
+        if self.last_keys == keys {
+            return;
+        }
+
+        let mut key = 0;
+        for byte in 0..keys.len() {
+            for bit in 0..8 {
+                let bnum = 1 << bit;
+                if (keys[byte] & bnum) != (self.last_keys[byte] & bnum) {
+                    let ev = if (keys[byte] & bnum) != 0 {
+                        KeyEvent::Press(key)
+                    } else {
+                        KeyEvent::Release(key)
+                    };
+                    events.inter(InterEvent::Key(ev));
+                }
+
+                key += 1;
+            }
+        }
+        self.last_keys = keys;
     }
 
     pub fn set_other_led(&mut self, leds: RGB8) {
         self.leds = leds;
     }
+
+    /// The LEDs the primary side last asked for, if they haven't been taken yet.  These are left
+    /// for the periodic task to show, rather than holding up the UART interrupt.
+    pub fn take_other_led(&mut self) -> Option<RGB8> {
+        self.other_leds.take()
+    }
 }
@@ -5,8 +5,6 @@
 
 use core::iter::once;
 
-use bbq_keyboard::Event;
-use rtic_sync::channel::Sender;
 use smart_leds::{SmartLedsWrite, RGB8};
 
 const OFF: RGB8 = RGB8::new(0, 0, 0);
@@ -186,6 +184,12 @@ pub static ARTSEY_NAV_INDICATOR: Indication = Indication(&[Step {
     count: 10000,
 }]);
 
+/// Caps word is on
+pub static CAPS_WORD_INDICATOR: Indication = Indication(&[Step {
+    color: RGB8::new(16, 0, 16),
+    count: 10000,
+}]);
+
 pub struct LedManager<L: SmartLedsWrite<Color = RGB8>> {
     leds: L,
 
@@ -221,13 +225,11 @@ impl<L: SmartLedsWrite<Color = RGB8>> LedManager<L> {
         }
     }
 
-    pub fn tick(
-        &mut self,
-        events: &mut Sender<'static, Event, { crate::app::EVENT_CAPACITY }>,
-    ) {
+    /// Step the display.  Returns the color shown, when it changes, to be sent to the other side.
+    pub fn tick(&mut self) -> Option<RGB8> {
         // If the other side is active, just leave the LED alone.
         if self.other_side {
-            return;
+            return None;
         }
 
         if self.count == 0 {
@@ -252,19 +254,19 @@ impl<L: SmartLedsWrite<Color = RGB8>> LedManager<L> {
                     self.phase = 1000;
 
                     // Just wait until the next tick.
-                    return;
+                    return None;
                 }
             }
 
             let rgb = steps[self.phase].color;
-            let _ = events.try_send(Event::SendLed(rgb));
-
             let _ = self.leds.write(once(rgb));
             // let _ = self.leds.write(once(if self.phase { INIT } else { OFF }));
             self.count = steps[self.phase].count;
             self.phase += 1;
+            Some(rgb)
         } else {
             self.count -= 1;
+            None
         }
     }
 
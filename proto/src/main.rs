@@ -24,6 +24,9 @@ use panic_probe as _;
 use sparkfun_pro_micro_rp2040 as bsp;
 
 mod board;
+mod engine;
+mod events;
+mod image;
 mod inter;
 mod leds;
 mod matrix;
@@ -50,6 +53,8 @@ type MatrixType = Matrix<
 )]
 mod app {
     use crate::bsp;
+    use crate::engine::{self, Request, REQUEST_CAPACITY};
+    use crate::events::{self, Events, Inputs};
     use crate::inter;
     use crate::leds;
     use crate::matrix::Matrix;
@@ -58,19 +63,21 @@ mod app {
     use crate::HEAP;
     use crate::HEAP_MEM;
     use crate::HEAP_SIZE;
-    use arrayvec::ArrayString;
+    use alloc::vec::Vec;
     use bbq_keyboard::Mods;
-    use bbq_keyboard::layout::LayoutManager;
-    use bbq_keyboard::usb_typer::enqueue_action;
-    use bbq_keyboard::dict::Dict;
-    use bbq_keyboard::Event;
-    use bbq_keyboard::EventQueue;
+    use bbq_keyboard::layout::{LayoutActions, LayoutManager};
+    use bbq_keyboard::usb_typer::{enqueue_action, ActionHandler, Fallback, HostLayout};
+    use bbq_keyboard::dict::{Dict, StenoEvents};
+    use bbq_keyboard::HostEvent;
+    use bbq_keyboard::InterEvent;
     use bbq_keyboard::InterState;
     use bbq_keyboard::KeyAction;
     use bbq_keyboard::LayoutMode;
     use bbq_keyboard::MinorMode;
     use bbq_keyboard::Side;
-    use bbq_keyboard::Timable;
+    use bbq_keyboard::time::{Clock, Duration};
+    use bbq_keyboard::UsbDeviceState;
+    use bbq_steno::dict::Joined;
     use bbq_steno::Stroke;
     use bsp::hal::clocks::init_clocks_and_plls;
     use bsp::hal::gpio::bank0::Gpio8;
@@ -92,6 +99,7 @@ mod app {
     use bsp::hal::Sio;
     use bsp::{hal, XOSC_CRYSTAL_FREQ};
     use usbd_human_interface_device::page::Keyboard;
+    use core::cell::RefCell;
     use core::iter::once;
     use core::mem::MaybeUninit;
     use defmt::info;
@@ -106,11 +114,10 @@ mod app {
     use rtic_sync::channel::Sender;
     use rtic_sync::make_channel;
     use usb_device::class_prelude::UsbBusAllocator;
-    use usb_device::prelude::UsbDeviceState;
     use ws2812_pio::Ws2812Direct;
 
-    pub const EVENT_CAPACITY: usize = 200;
     pub const STENO_CAPACITY: usize = 8;
+    pub const LAYOUT_CAPACITY: usize = 64;
 
     type UartPinout = (
         Pin<Gpio8, FunctionUart, PullDown>,
@@ -120,7 +127,6 @@ mod app {
     #[shared]
     struct Shared {
         inter_handler: inter::InterHandler<UART1, UartPinout>,
-        layout_manager: LayoutManager,
         led_manager:
             leds::LedManager<Ws2812Direct<PIO0, SM0, Pin<DynPinId, FunctionPio0, PullDown>>>,
         usb_handler: usb::UsbHandler<'static, UsbBus>,
@@ -129,10 +135,13 @@ mod app {
     #[local]
     struct Local {
         matrix: MatrixType,
-        usb_event: Sender<'static, Event, EVENT_CAPACITY>,
-        inter_event: Sender<'static, Event, EVENT_CAPACITY>,
-        event_event: Sender<'static, Event, EVENT_CAPACITY>,
-        periodic_event: Sender<'static, Event, EVENT_CAPACITY>,
+        usb_events: Events,
+        inter_events: Events,
+        event_events: Events,
+        periodic_events: Events,
+        steno_events: StenoLeds,
+        layout_manager: LayoutManager,
+        actions: Actions,
         dict: Dict,
     }
 
@@ -158,6 +167,9 @@ mod app {
         }
 
         info!("Init running");
+        let image = crate::image::info();
+        info!("Image: {} {} for {}, build id {:08x}",
+              image.version.as_str(), image.git.as_str(), image.board.as_str(), image.build_id());
 
         let rp2040_timer_token = rtic_monotonics::create_rp2040_monotonic_token!();
         Timer::start(ctx.device.TIMER, &mut ctx.device.RESETS, rp2040_timer_token);
@@ -228,9 +240,9 @@ mod app {
 
         let inter_handler = inter::InterHandler::new(uart, side);
 
-        let layout_manager = LayoutManager::new();
+        let layout_manager = LayoutManager::new(crate::board::TWO_ROW);
 
-        let dict = Dict::new();
+        let dict = Dict::new(&crate::board::FLASH);
 
         let usb_bus: &'static _ =
             ctx.local
@@ -244,16 +256,21 @@ mod app {
                 )));
         let usb_handler = usb::UsbHandler::new(&usb_bus);
 
-        let (event_send, event_receive) = make_channel!(Event, EVENT_CAPACITY);
+        let (events, inputs) = events::new();
+        let (request_send, request_receive) = make_channel!(Request, REQUEST_CAPACITY);
         let (steno_send, steno_receive) = make_channel!(Stroke, STENO_CAPACITY);
+        let (layout_send, layout_receive) = make_channel!(LayoutEvent, LAYOUT_CAPACITY);
 
-        let usb_event = event_send.clone();
-        let inter_event = event_send.clone();
-        let event_event = event_send.clone();
-        let periodic_event = event_send.clone();
+        let usb_events = events.clone();
+        let inter_events = events.clone();
+        let event_events = events.clone();
+        let periodic_events = events;
+        let steno_events = StenoLeds(layout_send.clone());
+        let actions = Actions(RefCell::new(layout_send));
 
         periodic_task::spawn().unwrap();
-        event_task::spawn(event_receive, steno_send).unwrap();
+        event_task::spawn(inputs, request_send, request_receive).unwrap();
+        layout_task::spawn(layout_receive, steno_send).unwrap();
         steno_task::spawn(steno_receive).unwrap();
 
         // let _timer = Timer::new(ctx.device.TIMER, &mut ctx.device.RESETS, &clocks);
@@ -261,16 +278,18 @@ mod app {
         (
             Shared {
                 inter_handler,
-                layout_manager,
                 led_manager,
                 usb_handler,
             },
             Local {
                 matrix,
-                usb_event,
-                inter_event,
-                event_event,
-                periodic_event,
+                usb_events,
+                inter_events,
+                event_events,
+                periodic_events,
+                steno_events,
+                layout_manager,
+                actions,
                 dict,
             },
         )
@@ -284,19 +303,19 @@ mod app {
     // able to avoid the issue by increasing the maximum endpoint-0 packet size.
     // Regardless, give the USB IRQ the highest priority to be able soon for the
     // initial packet.
-    #[task(binds = USBCTRL_IRQ, shared = [usb_handler], local = [usb_event], priority = 4)]
+    #[task(binds = USBCTRL_IRQ, shared = [usb_handler], local = [usb_events], priority = 4)]
     fn usbctrl_irq(mut cx: usbctrl_irq::Context) {
         cx.shared.usb_handler.lock(|usb_handler| {
-            usb_handler.poll(cx.local.usb_event);
+            usb_handler.poll(cx.local.usb_events);
         });
     }
 
     // The UART task needs to be able to drain the FIFO before it fills at
     // 32-bytes. At 400-kbps, that gives us around 800us.
-    #[task(binds = UART1_IRQ, shared = [inter_handler], local = [inter_event], priority = 3)]
+    #[task(binds = UART1_IRQ, shared = [inter_handler], local = [inter_events], priority = 3)]
     fn uart1_irq(mut cx: uart1_irq::Context) {
         cx.shared.inter_handler.lock(|inter_handler| {
-            inter_handler.poll(cx.local.inter_event);
+            inter_handler.poll(cx.local.inter_events);
         });
     }
 
@@ -314,9 +333,10 @@ mod app {
     }
 
     /// The periodic task. This calls 'tick' on various manager subsystems, once
-    /// every ms.
-    #[task(shared = [usb_handler, inter_handler, layout_manager, led_manager],
-           local = [periodic_event, matrix],
+    /// every ms.  The layout manager is ticked from the event task, as it needs
+    /// to be able to wait.  The LEDs are passed between the halves here too.
+    #[task(shared = [usb_handler, inter_handler, led_manager],
+           local = [periodic_events, matrix],
            priority = 2
     )]
     async fn periodic_task(mut ctx: periodic_task::Context) {
@@ -326,197 +346,210 @@ mod app {
             Timer::delay_until(next).await;
 
             lock!(ctx, usb_handler, usb_handler.tick());
-            lock!(ctx, inter_handler, inter_handler.tick());
-            lock!(ctx, layout_manager, {
-                layout_manager.tick(&mut EventWrapper(ctx.local.periodic_event));
+            let other = lock!(ctx, inter_handler, {
+                inter_handler.tick();
+                inter_handler.take_other_led()
             });
-            lock!(ctx, led_manager, {
-                led_manager.tick(ctx.local.periodic_event);
+            ctx.local.periodic_events.tick();
+            let shown = lock!(ctx, led_manager, {
+                if let Some(rgb) = other {
+                    led_manager.set_other_side(rgb);
+                }
+                led_manager.tick()
             });
-            ctx.local.matrix.tick(ctx.local.periodic_event).await;
+            if let Some(rgb) = shown {
+                lock!(ctx, inter_handler, inter_handler.set_other_led(rgb));
+            }
+            ctx.local.matrix.tick(ctx.local.periodic_events).await;
         }
     }
 
-    /// The main event processor. This is responsible for receiving events, and
-    /// dispatching them to appropriate other parts of the system.
-    #[task(shared = [layout_manager, led_manager, inter_handler, usb_handler],
-           local = [event_event],
+    /// The main event processor.  This gives the inputs to the engine, and
+    /// carries out what it asks for.
+    #[task(shared = [led_manager, inter_handler, usb_handler],
+           local = [event_events, layout_manager, actions],
            priority = 2)]
     async fn event_task(
         mut ctx: event_task::Context,
-        mut recv: Receiver<'static, Event, EVENT_CAPACITY>,
-        mut steno: Sender<'static, Stroke, STENO_CAPACITY>,
+        mut inputs: Inputs,
+        requests: Sender<'static, Request, REQUEST_CAPACITY>,
+        mut recv: Receiver<'static, Request, REQUEST_CAPACITY>,
     ) {
         let mut last_size = 0;
-        let mut state = InterState::Idle;
+        let mut engine = engine::build(requests);
+        // From USB coming up until the other half is heard from, the LEDs flash to show this side
+        // is primary.
         let mut flashing = true;
         let mut usb_suspended = true;
-        let mut current_mode = LayoutMode::Steno;
-        while let Ok(event) = recv.recv().await {
-            match event {
-                Event::Matrix(key) => {
-                    match state {
-                        InterState::Primary | InterState::Idle => {
-                            lock!(ctx, layout_manager, {
-                                layout_manager.handle_event(
-                                    key,
-                                    &mut EventWrapper(ctx.local.event_event),
-                                );
-                            });
-                        }
-                        InterState::Secondary => {
-                            lock!(ctx, inter_handler, {
-                                inter_handler.add_key(key);
-                            });
-                        }
+        let layout_manager = ctx.local.layout_manager;
+        let actions = &*ctx.local.actions;
+        loop {
+            inputs.wait().await;
+
+            // The host state first, so keys go where it now says.
+            let mut configured = false;
+            while let Ok(event) = inputs.host.try_recv() {
+                configured |= event == HostEvent::Usb(UsbDeviceState::Configured);
+                engine.host(event);
+            }
+            // Either half hearing from the other stops the flashing.  The secondary side is told
+            // it is secondary again when the primary side is reset, which is common when
+            // programming firmware, or waking from sleep.
+            let mut heard = false;
+            while let Ok(event) = inputs.inter.try_recv() {
+                heard |= matches!(
+                    event,
+                    InterEvent::Heartbeat | InterEvent::BecomeState(InterState::Secondary)
+                );
+                engine.inter(event);
+            }
+            while let Ok(key) = inputs.keys.try_recv() {
+                engine.key(key);
+            }
+            let ticked = inputs.take_tick();
+            if ticked {
+                engine.tick();
+            }
+
+            while let Ok(request) = recv.try_recv() {
+                match request {
+                    Request::Suspended(true) => {
+                        // This indicates the host has gone to sleep.
+                        lock!(ctx, led_manager, led_manager.set_global(&leds::SLEEP_INDICATOR));
+                        usb_suspended = true;
                     }
-                    if usb_suspended {
-                        // This is specific to our implementation.
-                        // TODO: Only do this if remote wakeup enabled.
-                        lock!(ctx, usb_handler, {
-                            usb_handler.wakeup();
-                        });
+                    Request::Suspended(false) => {
+                        lock!(ctx, led_manager, led_manager.clear_global());
+                        usb_suspended = false;
                     }
-                }
-                Event::InterKey(key) => {
-                    if state == InterState::Primary {
-                        lock!(ctx, layout_manager, {
-                            layout_manager.handle_event(
-                                key,
-                                &mut EventWrapper(ctx.local.event_event),
-                            );
+                    Request::Activity => {
+                        if usb_suspended {
+                            // This is specific to our implementation.
+                            // TODO: Only do this if remote wakeup enabled.
+                            lock!(ctx, usb_handler, usb_handler.wakeup());
+                        }
+                    }
+                    Request::Layout(key) => layout_manager.handle_event(key, actions).await,
+                    Request::AddKey(key) => lock!(ctx, inter_handler, inter_handler.add_key(key)),
+                    Request::SetState(state) => {
+                        lock!(ctx, inter_handler, {
+                            inter_handler.set_state(state, ctx.local.event_events);
                         });
                     }
                 }
-                Event::Key(action) => {
+            }
+
+            if configured {
+                lock!(ctx, led_manager, led_manager.set_global(&leds::USB_PRIMARY));
+                flashing = true;
+            } else if flashing && heard {
+                lock!(ctx, led_manager, led_manager.clear_global());
+                flashing = false;
+            }
+
+            if ticked {
+                layout_manager.tick(actions, Duration::from_millis(1)).await;
+            }
+
+            // Heap debugging is useful.
+            let new_used = HEAP.used();
+            if new_used > last_size {
+                let free = HEAP.free();
+                info!("Heap: {} used, {} free", new_used, free);
+                last_size = new_used;
+            }
+        }
+    }
+
+    /// The layout task.  This carries out the actions requested by the layout
+    /// manager.
+    #[task(shared = [led_manager, usb_handler],
+           priority = 2)]
+    async fn layout_task(
+        mut ctx: layout_task::Context,
+        mut recv: Receiver<'static, LayoutEvent, LAYOUT_CAPACITY>,
+        mut steno: Sender<'static, Stroke, STENO_CAPACITY>,
+    ) {
+        let mut current_mode = LayoutMode::Steno;
+        let mut raw = false;
+        while let Ok(event) = recv.recv().await {
+            match event {
+                LayoutEvent::Key(action) => {
                     lock!(ctx, usb_handler, usb_handler.enqueue(once(action)));
                 }
-                Event::RawSteno(stroke) => {
-                    let mut buffer = ArrayString::<24>::new();
-                    stroke.to_arraystring(&mut buffer);
-
-                    // TODO: Use a mode selection for this.
+                LayoutEvent::RawSteno(stroke) => {
                     if current_mode == LayoutMode::Steno {
                         let _ = steno.try_send(stroke);
                     } else {
-                        // Enqueue with USB to send raw.
-                        // TODO: Add a mode to have raw mode that types the
-                        // steno, vs sending via Gemini.
-                        // lock!(ctx, usb_handler, {
-                        //     enqueue_action(usb_handler, buffer.as_str());
-                        //     enqueue_action(usb_handler, " ");
-                        // });
+                        // Direct steno is sent to the host via Gemini.
                         lock!(ctx, usb_handler, {
                             let packet = stroke.to_gemini();
                             usb_handler.enqueue_serial(&packet);
                         });
                     }
                 }
-                Event::UsbState(UsbDeviceState::Configured) => {
-                    // TODO: Unclear how to handle suspend, but once we are
-                    // configured, we need to start figuring out which side
-                    // we are so we can communicate between the halves.
-                    lock!(ctx, led_manager, {
-                        led_manager.set_global(&leds::USB_PRIMARY);
-                    });
-                    flashing = true;
-                    lock!(ctx, inter_handler, {
-                        inter_handler.set_state(InterState::Primary, ctx.local.event_event);
-                    });
-                    usb_suspended = false;
-                }
-                Event::UsbState(UsbDeviceState::Suspend) => {
-                    // This indicates the host has gone to sleep.
-                    lock!(
-                        ctx,
-                        led_manager,
-                        led_manager.set_global(&leds::SLEEP_INDICATOR)
-                    );
-                    // flashing = true;
-                    usb_suspended = true;
-                }
-                Event::UsbState(_) => (),
-                Event::BecomeState(new_state) => {
-                    if state != new_state {
-                        if new_state == InterState::Secondary {
-                            info!("Secondary");
-                            // We've gone into secondary state, stop blinking the LEDs.
-                            lock!(ctx, led_manager, led_manager.clear_global());
-                        } else if new_state == InterState::Idle {
-                            info!("Idle");
-                            lock!(ctx, led_manager, led_manager.clear_global());
-                        } else {
-                            info!("Primary");
-                        }
-                        state = new_state;
-                    } else if new_state == InterState::Secondary && flashing {
-                        // This happens if the secondary side is running,
-                        // and the primary side is reset (common when
-                        // programming firmware, or waking from sleep).
-                        // Detect that we are flashing the lights, and turn
-                        // them off.
-                        lock!(ctx, led_manager, led_manager.clear_global());
-                        flashing = false;
+                LayoutEvent::RawMode(new_raw) => {
+                    info!("Switch raw: {}", new_raw);
+                    raw = new_raw;
+                    if current_mode == LayoutMode::Steno {
+                        lock!(ctx, led_manager, led_manager.set_base(steno_indicator(raw)));
                     }
                 }
-                Event::Mode(mode) => {
-                    let visible = match mode {
-                        LayoutMode::Steno => &leds::STENO_INDICATOR,
-                        LayoutMode::StenoRaw => &leds::STENO_RAW_INDICATOR,
-                        LayoutMode::Artsey => &leds::ARTSEY_INDICATOR,
-                        LayoutMode::Taipo => &leds::TAIPO_INDICATOR,
-                        LayoutMode::Qwerty => &leds::QWERTY_INDICATOR,
-                        LayoutMode::NKRO => &leds::NKRO_INDICATOR,
-                    };
+                LayoutEvent::Mode(mode) => {
+                    let visible = mode_indicator(mode, raw);
                     lock!(ctx, led_manager, led_manager.set_base(visible));
                     current_mode = mode;
                 }
-                Event::ModeSelect(mode) => {
+                LayoutEvent::ModeSelect(mode) => {
                     let visible = match mode {
                         LayoutMode::Steno => &leds::STENO_SELECT_INDICATOR,
-                        LayoutMode::StenoRaw => &leds::STENO_RAW_SELECT_INDICATOR,
+                        LayoutMode::StenoDirect => &leds::STENO_RAW_SELECT_INDICATOR,
                         LayoutMode::Artsey => &leds::ARTSEY_SELECT_INDICATOR,
                         LayoutMode::Taipo => &leds::TAIPO_SELECT_INDICATOR,
                         LayoutMode::Qwerty => &leds::QWERTY_SELECT_INDICATOR,
                         LayoutMode::NKRO => &leds::NKRO_SELECT_INDICATOR,
+                        // The number pad, and experiments, aren't built into this firmware.
+                        LayoutMode::Numpad | LayoutMode::Experimental(_) => {
+                            &leds::QWERTY_SELECT_INDICATOR
+                        }
                     };
                     lock!(ctx, led_manager, led_manager.set_base(visible));
                 }
-                Event::Indicator(mode) => {
+                LayoutEvent::SubMode(mode) => {
                     let visible = match mode {
                         MinorMode::ArtseyMain => &leds::ARTSEY_INDICATOR,
                         MinorMode::ArtseyNav => &leds::ARTSEY_NAV_INDICATOR,
+                        MinorMode::CapsWord(true) => &leds::CAPS_WORD_INDICATOR,
+                        MinorMode::CapsWord(false) => mode_indicator(current_mode, raw),
                     };
                     lock!(ctx, led_manager, led_manager.set_base(visible));
                 }
-                Event::Heartbeat => {
-                    if flashing {
-                        lock!(ctx, led_manager, {
-                            led_manager.clear_global();
-                        });
-                        flashing = false;
-                    }
-                }
-                Event::RecvLed(rgb) => {
-                    lock!(ctx, led_manager, led_manager.set_other_side(rgb));
-                }
-                Event::SendLed(rgb) => {
-                    lock!(ctx, inter_handler, inter_handler.set_other_led(rgb));
-                }
             }
+        }
+    }
 
-            // Heap debugging is useful.
-            let new_used = HEAP.used();
-            if new_used > last_size {
-                let free = HEAP.free();
-                info!("Heap: {} used, {} free", new_used, free);
-                last_size = new_used;
-            }
+    fn mode_indicator(mode: LayoutMode, raw: bool) -> &'static leds::Indication {
+        match mode {
+            LayoutMode::Steno => steno_indicator(raw),
+            LayoutMode::StenoDirect => &leds::STENO_RAW_INDICATOR,
+            LayoutMode::Artsey => &leds::ARTSEY_INDICATOR,
+            LayoutMode::Taipo => &leds::TAIPO_INDICATOR,
+            LayoutMode::Qwerty => &leds::QWERTY_INDICATOR,
+            LayoutMode::NKRO => &leds::NKRO_INDICATOR,
+            LayoutMode::Numpad | LayoutMode::Experimental(_) => &leds::QWERTY_INDICATOR,
+        }
+    }
+
+    fn steno_indicator(raw: bool) -> &'static leds::Indication {
+        if raw {
+            &leds::STENO_RAW_INDICATOR
+        } else {
+            &leds::STENO_INDICATOR
         }
     }
 
     #[task(
-        local = [dict],
+        local = [dict, steno_events],
         shared = [usb_handler],
         priority = 1,
     )]
@@ -525,42 +558,111 @@ mod app {
         mut steno: Receiver<'static, Stroke, STENO_CAPACITY>
     ) {
         while let Ok(stroke) = steno.recv().await {
-            for action in ctx.local.dict.handle_stroke(stroke, &WrapTimer) {
-                info!("type action: {} del, {} add", action.remove, action.text.len());
-
-                lock!(ctx, usb_handler, {
-                    // Press backspace for each remove.
-                    for _ in 0..action.remove {
-                        usb_handler.enqueue([
-                            KeyAction::KeyPress(Keyboard::DeleteBackspace, Mods::empty()),
-                            KeyAction::KeyRelease,
-                        ].iter().cloned());
-                    }
-                    enqueue_action(usb_handler, &action.text);
-                });
+            let actions = ctx.local.dict.handle_stroke(
+                stroke,
+                ctx.local.steno_events,
+                &SysClock,
+            );
+            for action in actions {
+                let Joined::Type { remove, append } = action else {
+                    // Raw keys and commands aren't supported yet.
+                    warn!("Unhandled steno action");
+                    continue;
+                };
+                info!("type action: {} del, {} add", remove, append.len());
+
+                // Build up the keys, and then queue them all at once.
+                let mut keys = KeyCollector(Vec::new());
+                for _ in 0..remove {
+                    keys.0.push(KeyAction::KeyPress(Keyboard::DeleteBackspace, Mods::empty()));
+                    keys.0.push(KeyAction::KeyRelease);
+                }
+                enqueue_action(&mut keys, HostLayout::Us, Fallback::None, &append).await;
+                lock!(ctx, usb_handler, usb_handler.enqueue(keys.0.into_iter()));
             }
         }
     }
 
-    /// Wrap the event queue in a way so that the bbq-keyboard package doesn't need
-    /// to know how it is implemented.
-    struct EventWrapper<'a>(&'a mut Sender<'static, Event, EVENT_CAPACITY>);
+    /// The requests from the layout manager, carried out by the layout task.
+    pub enum LayoutEvent {
+        Mode(LayoutMode),
+        ModeSelect(LayoutMode),
+        Key(KeyAction),
+        SubMode(MinorMode),
+        RawSteno(Stroke),
+        /// The dictionary has switched in or out of raw mode.
+        RawMode(bool),
+    }
+
+    /// The layout actions, which are queued for the layout task.  The layout
+    /// manager only needs a shared reference, so the sender is wrapped.
+    pub struct Actions(RefCell<Sender<'static, LayoutEvent, LAYOUT_CAPACITY>>);
 
-    impl<'a> EventQueue for EventWrapper<'a> {
-        fn push(&mut self, event: Event) {
-            if self.0.try_send(event).is_err() {
-                warn!("Unable to queue event");
+    impl Actions {
+        fn push(&self, event: LayoutEvent) {
+            if self.0.borrow_mut().try_send(event).is_err() {
+                warn!("Unable to queue layout event");
             }
         }
     }
 
-    /// Placeholder for the timer, until we implement a real one.
-    struct WrapTimer;
+    impl LayoutActions for Actions {
+        async fn set_mode(&self, mode: LayoutMode) {
+            self.push(LayoutEvent::Mode(mode));
+        }
+
+        async fn set_mode_select(&self, mode: LayoutMode) {
+            self.push(LayoutEvent::ModeSelect(mode));
+        }
+
+        async fn send_key(&self, key: KeyAction) {
+            self.push(LayoutEvent::Key(key));
+        }
+
+        async fn set_sub_mode(&self, submode: MinorMode) {
+            self.push(LayoutEvent::SubMode(submode));
+        }
+
+        async fn send_raw_steno(&self, stroke: Stroke) {
+            self.push(LayoutEvent::RawSteno(stroke));
+        }
+    }
+
+    /// Gather up key actions, so they can be queued to USB under a single lock.
+    struct KeyCollector(Vec<KeyAction>);
 
-    impl Timable for WrapTimer {
-        fn get_ticks(&self) -> u64 {
+    impl ActionHandler for KeyCollector {
+        async fn enqueue_actions<I: Iterator<Item = KeyAction>>(&mut self, events: I) {
+            self.0.extend(events);
+        }
+    }
+
+    /// What the dictionary reports as it translates.  Raw mode changes the steno
+    /// indicator, which the layout task looks after.  Briefs aren't shown.
+    pub struct StenoLeds(Sender<'static, LayoutEvent, LAYOUT_CAPACITY>);
+
+    impl StenoEvents for StenoLeds {
+        fn raw_mode(&mut self, raw: bool) {
+            if self.0.try_send(LayoutEvent::RawMode(raw)).is_err() {
+                warn!("Unable to queue layout event");
+            }
+        }
+
+        fn brief_available(&mut self) {}
+    }
+
+    /// The rp2040 timer, as seen by the shared code.
+    struct SysClock;
+
+    impl Clock for SysClock {
+        fn ticks(&self) -> u64 {
             Timer::now().ticks()
         }
+
+        fn tick_hz(&self) -> u64 {
+            // The rp2040 timer always counts microseconds.
+            1_000_000
+        }
     }
 }
 
@@ -3,10 +3,9 @@
 use arraydeque::ArrayDeque;
 use arrayvec::ArrayVec;
 use bbq_keyboard::usb_typer::ActionHandler;
-use bbq_keyboard::{Event, KeyAction, Mods};
+use bbq_keyboard::{HostEvent, KeyAction, Mods, UsbDeviceState as KbdState};
 use defmt::{info, warn};
 use frunk::{HCons, HNil};
-use rtic_sync::channel::Sender;
 use usb_device::{
     class_prelude::{UsbBus, UsbBusAllocator, UsbClass},
     prelude::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid},
@@ -22,6 +21,8 @@ use usbd_human_interface_device::{
 };
 use usbd_serial::SerialPort;
 
+use crate::events::Events;
+
 // Type of the device list, which is internal to usbd_human_interface_device.
 type InterfaceList<'a, Bus> = HCons<NKROBootKeyboard<'a, Bus>, HNil>;
 
@@ -108,7 +109,7 @@ impl<'a, Bus: UsbBus> UsbHandler<'a, Bus> {
 
         // If we have keys to queue up, try to do that here.
         if let Some(key) = self.keys.front() {
-            let mut keys = ArrayVec::<_, 5>::new();
+            let mut keys = ArrayVec::<_, 6>::new();
 
             // Capture all of the keys that should be down for this press.
             let iter = match key {
@@ -125,6 +126,9 @@ impl<'a, Bus: UsbBus> UsbHandler<'a, Bus> {
                     if m.contains(Mods::GUI) {
                         keys.push(Keyboard::LeftGUI);
                     }
+                    if m.contains(Mods::ALTGR) {
+                        keys.push(Keyboard::RightAlt);
+                    }
                     keys.push(*k);
                     None
                 }
@@ -158,6 +162,11 @@ impl<'a, Bus: UsbBus> UsbHandler<'a, Bus> {
                     self.stall = 50;
                     return;
                 }
+                // There is no mouse, or media key, interface, drop these.
+                KeyAction::MousePress(_) | KeyAction::MouseMove(..) | KeyAction::Consumer(_) => {
+                    let _ = self.keys.pop_front();
+                    return;
+                }
             };
 
             let status = match iter {
@@ -201,10 +210,7 @@ impl<'a, Bus: UsbBus> UsbHandler<'a, Bus> {
     /// calling sufficiently fast should also work.
     /// The docs suggest this can be called on say a 1ms tick, but this seems to
     /// break device identification.
-    pub(crate) fn poll(
-        &mut self,
-        events: &mut Sender<'static, Event, { crate::app::EVENT_CAPACITY }>,
-    ) {
+    pub(crate) fn poll(&mut self, events: &mut Events) {
         if self.dev.poll(&mut [&mut self.hid, &mut self.serial]) {
             self.hid.poll();
             self.serial.poll();
@@ -229,9 +235,13 @@ impl<'a, Bus: UsbBus> UsbHandler<'a, Bus> {
                 UsbDeviceState::Suspend => info!("State: Suspend"),
             }
             self.state = Some(new_state);
-            if events.try_send(Event::UsbState(new_state)).is_err() {
-                warn!("USB IRQ: Event queue full");
-            }
+            let kbd_state = match new_state {
+                UsbDeviceState::Addressed => KbdState::Addressed,
+                UsbDeviceState::Configured => KbdState::Configured,
+                UsbDeviceState::Default => KbdState::Default,
+                UsbDeviceState::Suspend => KbdState::Suspend,
+            };
+            events.host(HostEvent::Usb(kbd_state));
         }
     }
 }
@@ -248,7 +258,7 @@ impl<'a> UsbHandler<'a, sparkfun_pro_micro_rp2040::hal::usb::UsbBus> {
 }
 
 impl<'a, Bus: UsbBus> ActionHandler for UsbHandler<'a, Bus> {
-    fn enqueue_actions<I: Iterator<Item = KeyAction>>(&mut self, events: I) {
+    async fn enqueue_actions<I: Iterator<Item = KeyAction>>(&mut self, events: I) {
         self.enqueue(events)
     }
 }
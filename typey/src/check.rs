@@ -0,0 +1,262 @@
+//! Translation regression checks.
+//!
+//! A check file is a list of cases, one per line, giving the strokes and the text they are
+//! expected to produce:
+//!
+//! ```text
+//! # Suffixes
+//! TEFT/-G -> Testing
+//! TKOG/-S -> Dogs
+//! ```
+//!
+//! Each case is run through a fresh Lookup and Joiner, the same as the keyboard uses, so it starts
+//! capitalized and without a space.  Newlines and tabs in the expected text are written as `\n` and
+//! `\t`, and a backslash as `\\`.  Raw keys and commands are shown in the output as they are
+//! written in the dictionary, such as `{#Control_L(z)}` or `{PLOVER:TOGGLE}`.  Blank lines, and
+//! lines starting with '#' are ignored.
+//!
+//! The results are printed as TAP, or as a JUnit XML report for CI systems that read those.  Any
+//! failure gives a nonzero exit status, so changes to the translation engine can be gated on it.
+
+use std::{fmt::Write as _, fs, process};
+
+use anyhow::{anyhow, Result};
+use bbq_steno::{
+    dict::{Command, Dict, Joined, Joiner, Lookup},
+    stroke::StenoWord,
+    Stroke,
+};
+use clap::ValueEnum;
+use regex::Regex;
+
+/// How the results are reported.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// Test Anything Protocol.
+    Tap,
+    /// JUnit XML.
+    Junit,
+}
+
+/// A single case from a check file.
+struct Case {
+    /// The file, and line in it, for reporting.
+    file: String,
+    line: usize,
+    steno: StenoWord,
+    expect: String,
+}
+
+/// The outcome of running a case.
+struct Outcome<'a> {
+    case: &'a Case,
+    got: String,
+}
+
+impl Outcome<'_> {
+    fn passed(&self) -> bool {
+        self.got == self.case.expect
+    }
+}
+
+/// Run the cases in the given files, keeping those matching `filter`, and print the report.  Exits
+/// with a failure status if any case fails.
+pub fn check(dicts: Vec<Dict>, files: &[String], filter: Option<&str>, format: Format) -> Result<()> {
+    let filter = filter.map(Regex::new).transpose()?;
+
+    let mut cases = Vec::new();
+    for file in files {
+        cases.append(&mut load(file)?);
+    }
+    if let Some(filter) = &filter {
+        cases.retain(|case| filter.is_match(&case.steno.to_string()) || filter.is_match(&case.expect));
+    }
+
+    let outcomes: Vec<_> = cases
+        .iter()
+        .map(|case| Outcome { case, got: translate(dicts.clone(), &case.steno) })
+        .collect();
+    let report = match format {
+        Format::Tap => tap(&outcomes),
+        Format::Junit => junit(&outcomes),
+    };
+    print!("{}", report);
+
+    if outcomes.iter().any(|o| !o.passed()) {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Load the cases from a check file.
+fn load(file: &str) -> Result<Vec<Case>> {
+    let text = fs::read_to_string(file)?;
+    let mut cases = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        let line_num = num + 1;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (steno, expect) = line
+            .split_once(" -> ")
+            .ok_or_else(|| anyhow!("{}:{}: expecting strokes, ' -> ', and text", file, line_num))?;
+        let steno = steno
+            .trim()
+            .split('/')
+            .map(Stroke::from_text)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("{}:{}: {}", file, line_num, e))?;
+        cases.push(Case {
+            file: file.to_string(),
+            line: line_num,
+            steno: StenoWord(steno),
+            expect: unescape(expect),
+        });
+    }
+    Ok(cases)
+}
+
+/// Translate the strokes, giving the text that would be typed.
+fn translate(dicts: Vec<Dict>, steno: &StenoWord) -> String {
+    let mut lookup = Lookup::new(dicts);
+    let mut joiner = Joiner::new();
+    let mut text = String::new();
+
+    let mut actions = Vec::new();
+    for stroke in &steno.0 {
+        actions.append(&mut lookup.add(*stroke));
+    }
+    // Anything still held for a longer match is typed at the end.
+    actions.append(&mut lookup.flush());
+
+    for action in actions {
+        joiner.add(action);
+        while let Some(act) = joiner.pop(0) {
+            match act {
+                Joined::Type { remove, append } => {
+                    for _ in 0..remove {
+                        text.pop();
+                    }
+                    text.push_str(&append);
+                }
+                Joined::Raw(keys) => {
+                    let _ = write!(text, "{{#{}}}", keys);
+                }
+                Joined::Command(Command::Plover(arg)) => {
+                    let _ = write!(text, "{{PLOVER:{}}}", arg);
+                }
+                Joined::Command(Command::Mode(arg)) => {
+                    let _ = write!(text, "{{MODE:{}}}", arg);
+                }
+                Joined::Command(Command::Unknown(arg)) => {
+                    let _ = write!(text, "{{{}}}", arg);
+                }
+                other => {
+                    let _ = write!(text, "{{{:?}}}", other);
+                }
+            }
+        }
+    }
+    text
+}
+
+/// The report as TAP.
+fn tap(outcomes: &[Outcome]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "TAP version 13");
+    let _ = writeln!(out, "1..{}", outcomes.len());
+    for (num, outcome) in outcomes.iter().enumerate() {
+        let case = outcome.case;
+        let status = if outcome.passed() { "ok" } else { "not ok" };
+        let _ = writeln!(out, "{} {} - {} ({}:{})", status, num + 1, case.steno, case.file, case.line);
+        if !outcome.passed() {
+            let _ = writeln!(out, "  ---");
+            let _ = writeln!(out, "  expect: {:?}", case.expect);
+            let _ = writeln!(out, "  got: {:?}", outcome.got);
+            let _ = writeln!(out, "  ...");
+        }
+    }
+    let failed = outcomes.iter().filter(|o| !o.passed()).count();
+    let _ = writeln!(out, "# {} cases, {} passed, {} failed", outcomes.len(), outcomes.len() - failed, failed);
+    out
+}
+
+/// The report as JUnit XML, with a test suite for each file.
+fn junit(outcomes: &[Outcome]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(out, "<testsuites>");
+
+    let mut rest = outcomes;
+    while let Some(first) = rest.first() {
+        let count = rest.iter().take_while(|o| o.case.file == first.case.file).count();
+        let (suite, tail) = rest.split_at(count);
+        rest = tail;
+
+        let failures = suite.iter().filter(|o| !o.passed()).count();
+        let _ = writeln!(
+            out,
+            r#"  <testsuite name="{}" tests="{}" failures="{}">"#,
+            xml_escape(&first.case.file),
+            suite.len(),
+            failures
+        );
+        for outcome in suite {
+            let case = outcome.case;
+            let _ = write!(
+                out,
+                r#"    <testcase name="{}" classname="{}:{}""#,
+                xml_escape(&case.steno.to_string()),
+                xml_escape(&case.file),
+                case.line
+            );
+            if outcome.passed() {
+                let _ = writeln!(out, "/>");
+                continue;
+            }
+            let message = format!("expect {:?}, got {:?}", case.expect, outcome.got);
+            let _ = writeln!(out, ">");
+            let _ = writeln!(out, r#"      <failure message="{}"/>"#, xml_escape(&message));
+            let _ = writeln!(out, "    </testcase>");
+        }
+        let _ = writeln!(out, "  </testsuite>");
+    }
+
+    let _ = writeln!(out, "</testsuites>");
+    out
+}
+
+/// Escape text for an XML attribute.
+fn xml_escape(text: &str) -> String {
+    let mut result = String::new();
+    for ch in text.chars() {
+        match ch {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            ch => result.push(ch),
+        }
+    }
+    result
+}
+
+/// Decode the escapes in the expected text.
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
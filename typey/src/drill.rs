@@ -0,0 +1,186 @@
+//! Interactive drills.
+//!
+//! Run through an exercise file, as written by `exbuild`, prompting for each entry in turn.  The
+//! strokes are translated with the same Lookup and Joiner used by the keyboard, so what counts as
+//! correct here matches what the keyboard would have typed.  Each entry is timed, and the number of
+//! strokes used is compared against the strokes in the exercise.  The results are written out as
+//! CSV.
+
+use std::{
+    fs::File,
+    io::{stdin, stdout, BufRead, BufReader, Write},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use bbq_steno::{
+    dict::{Dict, Joined, Joiner, Lookup},
+    stroke::StenoWord,
+    Stroke,
+};
+use regex::Regex;
+use termion::{event::Key, input::TermRead, raw::IntoRawMode};
+
+/// A single exercise entry.
+struct Entry {
+    text: String,
+    steno: StenoWord,
+}
+
+/// The result from drilling a single entry.
+struct Stat {
+    text: String,
+    steno: StenoWord,
+    /// Total strokes written for this entry, including mistakes.
+    strokes: usize,
+    /// Strokes that were `*` corrections.
+    undos: usize,
+    /// Time from the first stroke until the text was correct.
+    time: Duration,
+    /// Was the entry completed, or skipped.
+    completed: bool,
+}
+
+impl Stat {
+    /// Strokes beyond what the exercise needed.
+    fn misstrokes(&self) -> usize {
+        self.strokes.saturating_sub(self.steno.0.len())
+    }
+}
+
+/// Load the entries from an exercise file.
+fn load_exercise(name: &str) -> Result<Vec<Entry>> {
+    let re = Regex::new(r"^'(.*)': ([A-Z0-9/^+*-]+)$")?;
+    let mut result = Vec::new();
+    for line in BufReader::new(File::open(name)?).lines() {
+        let line = line?;
+        // Skip the title, and blank lines.
+        let caps = match re.captures(&line) {
+            Some(caps) => caps,
+            None => continue,
+        };
+        // exbuild writes explicit spaces as a visible space.
+        result.push(Entry {
+            text: caps[1].replace('␣', " "),
+            steno: StenoWord::parse(&caps[2])?,
+        });
+    }
+    if result.is_empty() {
+        return Err(anyhow!("No entries found in {:?}", name));
+    }
+    Ok(result)
+}
+
+/// Run the drill.  Entries are written with space separated strokes.  Tab skips an entry, and Esc
+/// ends the drill early.  Results for the entries seen are written to `output`.
+pub fn drill(dict: Vec<Dict>, exercise: &str, output: &str) -> Result<()> {
+    let entries = load_exercise(exercise)?;
+    let mut stats = Vec::new();
+
+    let stdin = stdin();
+    let mut stdout = stdout().into_raw_mode()?;
+    let mut keys = stdin.keys();
+
+    writeln!(stdout, "--- {} entries, Tab to skip, Esc to stop ---\r", entries.len())?;
+    'entries: for entry in &entries {
+        // Start each entry fresh, so that strokes from a previous entry can't combine with this
+        // one.
+        let mut xlat = Lookup::new(dict.clone());
+        let mut joiner = Joiner::new();
+        let mut typed = String::new();
+        let mut word = String::new();
+        let mut start = None;
+        let mut stat = Stat {
+            text: entry.text.clone(),
+            steno: entry.steno.clone(),
+            strokes: 0,
+            undos: 0,
+            time: Duration::ZERO,
+            completed: false,
+        };
+
+        write!(stdout, "\r\n{}\r\n> ", entry.text)?;
+        stdout.flush()?;
+
+        for key in keys.by_ref() {
+            match key? {
+                Key::Esc => break 'entries,
+                Key::Char('\t') => break,
+                Key::Char(' ') => {
+                    let stroke = match Stroke::from_text(&word) {
+                        Ok(stroke) => stroke,
+                        Err(_) => {
+                            write!(stdout, "#<{}>", word)?;
+                            word.clear();
+                            stdout.flush()?;
+                            continue;
+                        }
+                    };
+                    word.clear();
+                    let now = Instant::now();
+                    let first = *start.get_or_insert(now);
+                    stat.strokes += 1;
+                    if stroke.is_star() {
+                        stat.undos += 1;
+                    }
+
+                    for action in xlat.add(stroke) {
+                        joiner.add(action);
+                    }
+                    while let Some(act) = joiner.pop(0) {
+                        // Raw keys and commands don't type anything.
+                        let Joined::Type { remove, append } = act else {
+                            continue;
+                        };
+                        for _ in 0..remove {
+                            write!(stdout, "\u{0008} \u{0008}")?;
+                            typed.pop();
+                        }
+                        write!(stdout, "{}", append)?;
+                        typed.push_str(&append);
+                    }
+                    stdout.flush()?;
+
+                    if typed.trim_start() == entry.text {
+                        stat.time = now - first;
+                        stat.completed = true;
+                        break;
+                    }
+                }
+                Key::Char(ch) => word.push(ch),
+                _ => (),
+            }
+        }
+        stats.push(stat);
+    }
+    writeln!(stdout, "\r")?;
+    drop(stdout);
+
+    write_csv(output, &stats)?;
+    let done = stats.iter().filter(|s| s.completed).count();
+    let miss: usize = stats.iter().map(|s| s.misstrokes()).sum();
+    println!("{} of {} completed, {} misstrokes, written to {}", done, stats.len(), miss, output);
+    Ok(())
+}
+
+/// Write the stats as CSV.
+fn write_csv(name: &str, stats: &[Stat]) -> Result<()> {
+    let mut out = File::create(name)?;
+    writeln!(out, "text,steno,completed,strokes,misstrokes,undos,millis")?;
+    for stat in stats {
+        writeln!(out, "{},{},{},{},{},{},{}",
+                 csv_quote(&stat.text),
+                 csv_quote(&stat.steno.to_string()),
+                 stat.completed,
+                 stat.strokes,
+                 stat.misstrokes(),
+                 stat.undos,
+                 stat.time.as_millis())?;
+    }
+    Ok(())
+}
+
+/// Quote a field for CSV.
+fn csv_quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "\"\""))
+}
@@ -0,0 +1,56 @@
+//! Gemini PR input.
+//!
+//! Read strokes from a serial port speaking Gemini PR, such as the keyboard itself in raw steno
+//! mode.  This allows the engine to be tested with real chords from the hardware, rather than
+//! typed pseudo-steno.
+
+use std::{io::{ErrorKind, Read}, time::Duration};
+
+use anyhow::Result;
+use bbq_steno::Stroke;
+use serialport::SerialPort;
+
+pub struct Gemini {
+    port: Box<dyn SerialPort>,
+    packet: Vec<u8>,
+}
+
+impl Gemini {
+    pub fn open(name: &str) -> Result<Gemini> {
+        let port = serialport::new(name, 115_200)
+            .timeout(Duration::from_millis(100))
+            .open()?;
+        Ok(Gemini { port, packet: Vec::new() })
+    }
+
+    /// Wait for the next stroke.  Returns None if no stroke arrives before the port's timeout, so
+    /// the caller can check for other input.
+    pub fn next_stroke(&mut self) -> Result<Option<Stroke>> {
+        let mut buf = [0u8; 1];
+        loop {
+            match self.port.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+
+            // The high bit marks the start of a packet; anything before one is dropped.
+            if buf[0] & 0x80 != 0 {
+                self.packet.clear();
+            } else if self.packet.is_empty() {
+                continue;
+            }
+            self.packet.push(buf[0]);
+
+            if self.packet.len() == 6 {
+                let packet: [u8; 6] = self.packet[..].try_into().unwrap();
+                self.packet.clear();
+                match Stroke::from_gemini(&packet) {
+                    Some(stroke) => return Ok(Some(stroke)),
+                    None => log::warn!("Invalid gemini packet: {:02x?}", packet),
+                }
+            }
+        }
+    }
+}
@@ -1,14 +1,15 @@
 use std::{
-    fs::{File, OpenOptions}, io::{stdin, stdout, BufRead, BufReader, Write}, rc::Rc, str::FromStr
+    fs::{File, OpenOptions}, io::{stdin, stdout, BufRead, BufReader, Stdout, Write}, rc::Rc, str::FromStr
 };
 
 use anyhow::{anyhow, Result};
 use bbq_steno::{
-    dict::{Dict, Joined, Joiner, Lookup, MapDictBuilder}, memdict::MemDict, stroke::StenoWord, Stroke
+    dict::{Dict, Joined, Joiner, Lookup, MapDictBuilder}, memdict::MemDict, stroke::StenoWord,
+    tape::TapeEntry, Stroke
 };
 use clap::{Parser, Subcommand, ValueEnum};
 use regex::Regex;
-use termion::{event::Key, input::TermRead, raw::IntoRawMode};
+use termion::{async_stdin, event::Key, input::TermRead, raw::{IntoRawMode, RawTerminal}};
 
 /// The main commands available.
 #[derive(Debug, Subcommand)]
@@ -19,6 +20,18 @@ enum Command {
     #[clap(name = "exbuild")]
     /// Build a steno dictionary.
     Exbuild(ExbuildCommand),
+    #[clap(name = "drill")]
+    /// Drill an exercise, recording statistics.
+    Drill(DrillCommand),
+    #[clap(name = "replay")]
+    /// Replay a paper tape retrieved from the keyboard.
+    Replay(ReplayCommand),
+    #[clap(name = "suggest")]
+    /// Show every outline for a word or phrase, fewest strokes first.
+    Suggest(SuggestCommand),
+    #[clap(name = "check")]
+    /// Check translations against a file of expected text, for regression testing.
+    Check(CheckCommand),
 }
 
 #[derive(Debug, Parser)]
@@ -34,6 +47,10 @@ struct WriteCommand {
     #[arg(long, default_value = "joiner")]
     /// Where to stop printing.
     stop: StopPoint,
+
+    #[arg(long)]
+    /// Read strokes from a Gemini PR serial port, instead of typed steno.
+    gemini: Option<String>,
 }
 
 #[derive(Debug, Parser)]
@@ -47,6 +64,60 @@ struct ExbuildCommand {
     output: String,
 }
 
+#[derive(Debug, Parser)]
+struct DrillCommand {
+    #[arg(long = "dict")]
+    /// The path to the dictionary to use.
+    file: Option<String>,
+
+    #[arg(long, default_value = "drill.csv")]
+    /// Where to write the statistics, as CSV.
+    output: String,
+
+    /// The exercise file, as written by exbuild.
+    exercise: String,
+}
+
+#[derive(Debug, Parser)]
+struct ReplayCommand {
+    #[arg(long = "dict")]
+    /// The path to the dictionary to use.
+    file: Option<String>,
+
+    /// The tape file to replay.
+    tape: String,
+}
+
+#[derive(Debug, Parser)]
+struct SuggestCommand {
+    #[arg(long = "dict")]
+    /// The path to the dictionary to use.
+    file: Option<String>,
+
+    #[arg(required = true)]
+    /// The word or phrase to look up.
+    words: Vec<String>,
+}
+
+#[derive(Debug, Parser)]
+struct CheckCommand {
+    #[arg(long = "dict")]
+    /// The path to the dictionary to use.
+    file: Option<String>,
+
+    #[arg(long)]
+    /// Only run the cases whose strokes or expected text match this regex.
+    filter: Option<String>,
+
+    #[arg(long, value_enum, default_value = "tap")]
+    /// How to report the results.
+    format: check::Format,
+
+    #[arg(required = true)]
+    /// The check files, see the check module for the format.
+    cases: Vec<String>,
+}
+
 #[derive(Debug, Parser)]
 #[command(name = "typey")]
 #[command(about = "Typing testing utilities")]
@@ -86,6 +157,9 @@ impl FromStr for ShowStyle {
 }
 
 // mod rtfcre;
+mod check;
+mod drill;
+mod gemini;
 
 fn main() -> Result<()> {
     // Regular env logger, but add a carriage return so the output is still sane even when in raw
@@ -96,13 +170,24 @@ fn main() -> Result<()> {
         .init();
     log::warn!("program started");
     let opt = Opt::parse();
-    println!("command: {:?}", opt);
+    // Logged rather than printed, so it doesn't end up in the check reports.
+    log::info!("command: {:?}", opt);
 
     match opt.command {
         Command::Write(cmd) => {
             writer(&cmd)?;
         }
         Command::Exbuild(cmd) => exbuild(&cmd)?,
+        Command::Drill(cmd) => {
+            let file = cmd.file.clone().unwrap_or_else(|| "../phoenix/phoenix.bin".to_string());
+            drill::drill(load_dict(&file)?, &cmd.exercise, &cmd.output)?;
+        }
+        Command::Replay(cmd) => replay(&cmd)?,
+        Command::Suggest(cmd) => suggest(&cmd)?,
+        Command::Check(cmd) => {
+            let file = cmd.file.clone().unwrap_or_else(|| "../phoenix/phoenix.bin".to_string());
+            check::check(load_dict(&file)?, &cmd.cases, cmd.filter.as_deref(), cmd.format)?;
+        }
     }
 
     Ok(())
@@ -112,47 +197,47 @@ fn writer(cmd: &WriteCommand) -> Result<()> {
     let file = cmd.file.clone().unwrap_or_else(|| "../phoenix/phoenix.bin".to_string());
     let dict = load_dict(&file)?;
     let mut xlat = Lookup::new(dict);
-    let stdin = stdin();
     let mut stdout = stdout().into_raw_mode()?;
 
     let mut joiner = Joiner::new();
 
+    if let Some(port) = &cmd.gemini {
+        let mut gemini = gemini::Gemini::open(port)?;
+        let mut keys = async_stdin().keys();
+        writeln!(stdout, "Begin, reading from {}.\r", port)?;
+        loop {
+            match keys.next() {
+                Some(Ok(Key::Esc)) => {
+                    writeln!(stdout, "Done\r")?;
+                    break;
+                }
+                Some(Ok(Key::Char('\t'))) => show_last_outlines(&xlat, &mut stdout)?,
+                _ => (),
+            }
+            if let Some(stroke) = gemini.next_stroke()? {
+                write_stroke(cmd, &mut xlat, &mut joiner, &mut stdout, stroke)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let stdin = stdin();
     let mut word = String::new();
-    writeln!(stdout, "Begin.\r")?;
+    writeln!(stdout, "Begin.  Tab shows the outlines for the last translation.\r")?;
     for key in stdin.keys() {
         let key = key?;
         if key == Key::Esc {
             writeln!(stdout, "Done\r")?;
             break;
         }
+        if key == Key::Char('\t') {
+            show_last_outlines(&xlat, &mut stdout)?;
+            continue;
+        }
         if key == Key::Char(' ') {
             if let Ok(stroke) = Stroke::from_text(&word) {
                 word.clear();
-                writeln!(stdout, "Write: {}\r", stroke)?;
-                if cmd.stop == StopPoint::Steno {
-                    continue;
-                }
-                stdout.suspend_raw_mode()?;
-                let action = xlat.add(stroke);
-                match cmd.show {
-                    Some(ShowStyle::Short) => xlat.show(),
-                    Some(ShowStyle::Long) => xlat.show_verbose(),
-                    None => (),
-                }
-                writeln!(stdout, "Action: {:?}", action)?;
-
-                if cmd.stop == StopPoint::Lookup {
-                    stdout.activate_raw_mode()?;
-                    continue;
-                }
-                joiner.add(action);
-                if let Some(ShowStyle::Short) = cmd.show {
-                    joiner.show();
-                }
-                while let Some(act) = joiner.pop(0) {
-                    writeln!(stdout, "Act: {:?}", act)?;
-                }
-                stdout.activate_raw_mode()?;
+                write_stroke(cmd, &mut xlat, &mut joiner, &mut stdout, stroke)?;
                 continue;
             } else {
                 writeln!(stdout, "Invalid: {:?}\r", word)?;
@@ -169,6 +254,130 @@ fn writer(cmd: &WriteCommand) -> Result<()> {
     Ok(())
 }
 
+/// Process a single stroke for the writer, showing as much as requested.
+fn write_stroke(
+    cmd: &WriteCommand,
+    xlat: &mut Lookup,
+    joiner: &mut Joiner,
+    stdout: &mut RawTerminal<Stdout>,
+    stroke: Stroke,
+) -> Result<()> {
+    writeln!(stdout, "Write: {}\r", stroke)?;
+    if cmd.stop == StopPoint::Steno {
+        return Ok(());
+    }
+    stdout.suspend_raw_mode()?;
+    let actions = xlat.add(stroke);
+    match cmd.show {
+        Some(ShowStyle::Short) => xlat.show(),
+        Some(ShowStyle::Long) => xlat.show_verbose(),
+        None => (),
+    }
+    for action in &actions {
+        writeln!(stdout, "Action: {:?}", action)?;
+    }
+
+    if cmd.stop == StopPoint::Lookup {
+        stdout.activate_raw_mode()?;
+        return Ok(());
+    }
+    for action in actions {
+        joiner.add(action);
+    }
+    if let Some(ShowStyle::Short) = cmd.show {
+        joiner.show();
+    }
+    while let Some(act) = joiner.pop(0) {
+        writeln!(stdout, "Act: {:?}", act)?;
+    }
+    stdout.activate_raw_mode()?;
+    Ok(())
+}
+
+/// Show every outline for the most recent translation, to help find briefs while writing.
+fn show_last_outlines(xlat: &Lookup, stdout: &mut RawTerminal<Stdout>) -> Result<()> {
+    let Some(definition) = xlat.last_definition() else {
+        writeln!(stdout, "Nothing translated yet\r")?;
+        return Ok(());
+    };
+    for line in outline_lines(definition, &xlat.outlines(definition)) {
+        writeln!(stdout, "{}\r", line)?;
+    }
+    Ok(())
+}
+
+/// Look up every outline for a word or phrase.  When the whole phrase isn't in the dictionary, each
+/// word of it is looked up instead.
+fn suggest(cmd: &SuggestCommand) -> Result<()> {
+    let file = cmd.file.clone().unwrap_or_else(|| "../phoenix/phoenix.bin".to_string());
+    let xlat = Lookup::new(load_dict(&file)?);
+
+    let phrase = cmd.words.join(" ");
+    let outlines = xlat.outlines(&phrase);
+    let words: Vec<_> = phrase.split_whitespace().collect();
+    if !outlines.is_empty() || words.len() < 2 {
+        for line in outline_lines(&phrase, &outlines) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    println!("{:?} is not in the dictionary, looking up each word.", phrase);
+    for word in words {
+        for line in outline_lines(word, &xlat.outlines(word)) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Lines describing the outlines for a definition, fewest strokes first.
+fn outline_lines(definition: &str, outlines: &[Vec<Stroke>]) -> Vec<String> {
+    if outlines.is_empty() {
+        return vec![format!("{:?}: no outlines", definition)];
+    }
+    let mut lines = vec![format!("{:?}: {} outlines", definition, outlines.len())];
+    for outline in outlines {
+        lines.push(format!("  {:>2} {}", outline.len(), StenoWord(outline.clone())));
+    }
+    lines
+}
+
+/// Replay a paper tape through the translator, showing what the keyboard recorded for each stroke
+/// alongside what we translate it as now.  Differences are flagged.
+fn replay(cmd: &ReplayCommand) -> Result<()> {
+    let file = cmd.file.clone().unwrap_or_else(|| "../phoenix/phoenix.bin".to_string());
+    let dict = load_dict(&file)?;
+    let mut xlat = Lookup::new(dict);
+    let mut joiner = Joiner::new();
+
+    for line in BufReader::new(File::open(&cmd.tape)?).lines() {
+        let line = line?;
+        let entry = match TapeEntry::from_line(&line) {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        // An entry without a stroke is where a held translation was typed.
+        let lookup = if entry.stroke.is_empty() { xlat.flush() } else { xlat.add(entry.stroke) };
+        for action in lookup {
+            joiner.add(action);
+        }
+        let mut actions = Vec::new();
+        while let Some(act) = joiner.pop(0) {
+            actions.push(act);
+        }
+        let now = TapeEntry::new(entry.stroke, &actions);
+
+        let mark = if now == entry { " " } else { "!" };
+        println!("{} {:<12} {:>2} {:<24?} {:>2} {:?}",
+                 mark, entry.stroke.to_string(),
+                 entry.remove, entry.text.as_str(),
+                 now.remove, now.text.as_str());
+    }
+    Ok(())
+}
+
 /// Load the given dictionary, using the extension to determine what type it is.
 /// Note that memory dictionaries are leaked, so that they are static.  This is a consequence of the
 /// API of the embedded dictionary which is intended to operate on memory mapped data.
@@ -238,10 +447,14 @@ fn exbuild(cmd: &ExbuildCommand) -> Result<()> {
                 } else {
                     strokes.push(stroke);
                 }
-                let action = xlat.add(stroke);
-                joiner.add(action);
+                for action in xlat.add(stroke) {
+                    joiner.add(action);
+                }
                 // TODO: Handle raw and other types.
-                while let Some(Joined::Type { remove, append }) = joiner.pop(0) {
+                while let Some(act) = joiner.pop(0) {
+                    let Joined::Type { remove, append } = act else {
+                        continue;
+                    };
                     for _ in 0..remove {
                         write!(stdout, "\u{0008} \u{0008}")?;
                         text.pop();
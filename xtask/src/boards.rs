@@ -0,0 +1,92 @@
+//! The boards we know how to build for.
+//!
+//! This mirrors the table in `jolt/justfile`.
+
+use minder::partition::{Flash, FLASH_BASE};
+
+const MIB: u32 = 1024 * 1024;
+
+pub struct Board {
+    /// Our name for the board, which is also the shield, and the name in the board info.
+    pub name: &'static str,
+    /// The Zephyr board the shield sits on.
+    pub target: &'static str,
+    /// Extra cmake flags.
+    pub flags: &'static [&'static str],
+    /// Is this a split board, with an MCU on each side.
+    pub split: bool,
+    /// The size of the flash on the Zephyr board.
+    pub flash_size: u32,
+}
+
+pub static BOARDS: &[Board] = &[
+    Board {
+        name: "proto2",
+        target: "sparkfun_pro_micro_rp2040",
+        flags: &[],
+        split: true,
+        flash_size: 16 * MIB,
+    },
+    Board {
+        name: "proto3",
+        target: "sparkfun_pro_micro_rp2040",
+        flags: &[],
+        split: true,
+        flash_size: 16 * MIB,
+    },
+    Board {
+        name: "proto4",
+        target: "pimoroni_tiny_2040",
+        flags: &["-DCONFIG_JOLT_INTER=n"],
+        split: false,
+        flash_size: 8 * MIB,
+    },
+    Board {
+        name: "highboard",
+        target: "adafruit_feather_rp2040",
+        flags: &["-DCONFIG_JOLT_INTER=n"],
+        split: false,
+        flash_size: 8 * MIB,
+    },
+    Board {
+        name: "jolt1",
+        target: "pimoroni_tiny_2040",
+        flags: &["-DCONFIG_JOLT_INTER=y"],
+        split: true,
+        flash_size: 8 * MIB,
+    },
+    Board {
+        name: "jolt2",
+        target: "pimoroni_tiny_2040",
+        flags: &["-DCONFIG_JOLT_INTER=y"],
+        split: true,
+        flash_size: 8 * MIB,
+    },
+    Board {
+        name: "jolt2dir",
+        target: "pimoroni_tiny_2040",
+        flags: &["-DCONFIG_JOLT_INTER=y"],
+        split: true,
+        flash_size: 8 * MIB,
+    },
+];
+
+pub fn by_name(name: &str) -> Option<&'static Board> {
+    BOARDS.iter().find(|b| b.name == name)
+}
+
+impl Board {
+    /// The board's flash.  The main dictionary has to fit in what it has.
+    pub fn flash(&self) -> Flash {
+        Flash { base: FLASH_BASE as usize, size: self.flash_size }
+    }
+
+    /// The sides to generate board info for.  None is a board with a single MCU.
+    pub fn sides(&self) -> &'static [Option<&'static str>] {
+        if self.split {
+            &[Some("left"), Some("right")]
+        } else {
+            &[None]
+        }
+    }
+}
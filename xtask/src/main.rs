@@ -0,0 +1,362 @@
+//! Build tasks.
+//!
+//! Building a complete keyboard image takes several steps across several crates: the firmware is
+//! built with west, the dictionaries and board info with bbq-tool, and each has to be placed at
+//! the right address from the partition map.  This gathers those steps up, and can put everything
+//! into a single UF2 bundle.
+//!
+//! Run with `cargo xtask <command>` from anywhere in the tree.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Parser, Subcommand};
+use minder::{
+    partition::{self, Partition, FLASH_BASE},
+    ImageInfo,
+};
+
+use boards::Board;
+use uf2::Uf2;
+
+mod boards;
+mod uf2;
+
+#[derive(Parser)]
+#[command(name = "xtask")]
+#[command(about = "Build and package keyboard firmware")]
+struct Cli {
+    /// Where to put the results.  Defaults to target/xtask at the top of the tree.
+    #[arg(long)]
+    out: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Build the firmware for a board.
+    Build {
+        /// The board to build for.
+        #[arg(long)]
+        board: String,
+    },
+    /// Build the dictionaries.
+    Dicts {
+        #[command(flatten)]
+        dicts: DictArgs,
+    },
+    /// Generate the board info for each side of a board.
+    BoardInfo {
+        /// The board to generate for.
+        #[arg(long)]
+        board: String,
+    },
+    /// Build everything for a board into a single UF2, and optionally flash it.
+    Bundle {
+        /// The board to build for.
+        #[arg(long)]
+        board: String,
+
+        /// Which side, for split boards.
+        #[arg(long)]
+        side: Option<String>,
+
+        /// Use an already built firmware image (zephyr.bin) instead of building.
+        #[arg(long)]
+        firmware: Option<PathBuf>,
+
+        #[command(flatten)]
+        dicts: DictArgs,
+
+        /// Copy the bundle to the bootloader drive at this path.
+        #[arg(long)]
+        flash: Option<PathBuf>,
+
+        /// After flashing, use keyminder to check the firmware and dictionaries on the keyboard.
+        #[arg(long, requires = "flash")]
+        verify: bool,
+    },
+    /// Report the size of the firmware, failing if it is over budget.  Each result is also added
+    /// to sizes.csv in the output directory, to track the size over time.
+    Size {
+        /// The board to build for.
+        #[arg(long)]
+        board: String,
+
+        /// Use an already built firmware image (zephyr.bin) instead of building.
+        #[arg(long)]
+        firmware: Option<PathBuf>,
+
+        /// The largest the image can be, in bytes.  Defaults to the firmware partition, which
+        /// leaves room to stage an update.  Use 131072 to check that a small build fits a 128KB part.
+        #[arg(long)]
+        budget: Option<u32>,
+
+        /// Fail if the image has grown by more than this many bytes since the last size recorded
+        /// for the board in sizes.csv.
+        #[arg(long)]
+        growth: Option<u32>,
+    },
+}
+
+#[derive(clap::Args)]
+struct DictArgs {
+    /// Sources for the main dictionary, in the order given to bbq-tool.
+    #[arg(long = "main", num_args = 1..)]
+    main: Vec<String>,
+
+    /// Sources for the user dictionary.
+    #[arg(long = "user", num_args = 1..)]
+    user: Vec<String>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let out = cli.out.unwrap_or_else(|| top().join("target/xtask"));
+    fs::create_dir_all(&out)?;
+
+    match &cli.command {
+        Commands::Build { board } => {
+            let image = build(get_board(board)?, &out)?;
+            println!("Firmware: {}", image.display());
+        }
+        Commands::Dicts { dicts } => {
+            for (part, file) in build_dicts(dicts, &out)? {
+                println!("{}: {}", part.name, file.display());
+            }
+        }
+        Commands::BoardInfo { board } => {
+            let board = get_board(board)?;
+            for side in board.sides() {
+                println!("Board info: {}", board_info(board, *side, &out)?.display());
+            }
+        }
+        Commands::Bundle { board, side, firmware, dicts, flash, verify } => {
+            let board = get_board(board)?;
+            let side = get_side(board, side.as_deref())?;
+            let firmware = match firmware {
+                Some(firmware) => firmware.clone(),
+                None => build(board, &out)?,
+            };
+            let (bundle, parts) = bundle(board, side, &firmware, dicts, &out)?;
+            println!("Bundle: {}", bundle.display());
+
+            if let Some(drive) = flash {
+                flash_bundle(&bundle, drive)?;
+                if *verify {
+                    verify_bundle(&firmware, &parts)?;
+                }
+            }
+        }
+        Commands::Size { board, firmware, budget, growth } => {
+            let board = get_board(board)?;
+            let firmware = match firmware {
+                Some(firmware) => firmware.clone(),
+                None => build(board, &out)?,
+            };
+            size(board, &firmware, budget.unwrap_or(partition::FIRMWARE.size), *growth, &out)?;
+        }
+    }
+    Ok(())
+}
+
+/// The top of the source tree.
+fn top() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap().to_path_buf()
+}
+
+fn get_board(name: &str) -> Result<&'static Board> {
+    boards::by_name(name).ok_or_else(|| {
+        let names: Vec<_> = boards::BOARDS.iter().map(|b| b.name).collect();
+        anyhow!("Unknown board {:?}, expecting one of {}", name, names.join(", "))
+    })
+}
+
+/// Check the side is appropriate for the board.
+fn get_side(board: &Board, side: Option<&str>) -> Result<Option<&'static str>> {
+    board
+        .sides()
+        .iter()
+        .copied()
+        .find(|s| *s == side)
+        .ok_or_else(|| match side {
+            Some(side) => anyhow!("Board {} has no side {:?}", board.name, side),
+            None => anyhow!("Board {} is split, --side is needed", board.name),
+        })
+}
+
+/// Run a command, failing if it does.
+fn run(cmd: &mut Command) -> Result<()> {
+    let status = cmd.status()?;
+    if !status.success() {
+        bail!("Command failed ({}): {:?}", status, cmd);
+    }
+    Ok(())
+}
+
+/// A command to run one of the host tools in the tree.
+fn tool(name: &str) -> Command {
+    let mut cmd = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()));
+    cmd.args(["run", "-q", "--release", "--manifest-path"])
+        .arg(top().join(name).join("Cargo.toml"))
+        .arg("--");
+    cmd
+}
+
+/// Build the firmware, returning the path to the binary image.
+fn build(board: &Board, out: &Path) -> Result<PathBuf> {
+    let dir = out.join(board.name).join("build");
+    let jolt = top().join("jolt");
+    run(Command::new("west")
+        .arg("build")
+        .arg("-d")
+        .arg(&dir)
+        .arg("-s")
+        .arg(&jolt)
+        .args(["--board", board.target, "--"])
+        .args(board.flags)
+        .arg(format!("-DSHIELD={}", board.name))
+        .arg(format!("-DEXTRA_ZEPHYR_MODULES={}", jolt.join("bbqboards").display())))?;
+    Ok(dir.join("zephyr/zephyr.bin"))
+}
+
+/// Build whichever dictionaries have sources given, returning where each is to go.
+fn build_dicts(dicts: &DictArgs, out: &Path) -> Result<Vec<(Partition, PathBuf)>> {
+    let mut result = Vec::new();
+    for (part, sources) in [(partition::MAIN_DICT, &dicts.main), (partition::USER_DICT, &dicts.user)] {
+        if sources.is_empty() {
+            continue;
+        }
+        let file = out.join(format!("{}.bin", part.name));
+        run(tool("bbq-tool").arg("build").arg("-o").arg(&file).args(sources))?;
+        result.push((part, file));
+    }
+    Ok(result)
+}
+
+/// Generate the board info for one side of a board.
+fn board_info(board: &Board, side: Option<&str>, out: &Path) -> Result<PathBuf> {
+    let file = match side {
+        Some(side) => out.join(format!("{}-{}.bin", board.name, side)),
+        None => out.join(format!("{}.bin", board.name)),
+    };
+    let mut cmd = tool("bbq-tool");
+    cmd.arg("board-info").arg("-o").arg(&file).args(["--name", board.name]);
+    if let Some(side) = side {
+        cmd.args(["--side", side]);
+    }
+    run(&mut cmd)?;
+    Ok(file)
+}
+
+/// Put the firmware, board info, and any dictionaries into a single UF2.  Returns the bundle, and
+/// the partitions that were included in it.
+fn bundle(
+    board: &Board,
+    side: Option<&str>,
+    firmware: &Path,
+    dicts: &DictArgs,
+    out: &Path,
+) -> Result<(PathBuf, Vec<(Partition, PathBuf)>)> {
+    let image = fs::read(firmware)?;
+    let info = ImageInfo::find(&image)
+        .ok_or_else(|| anyhow!("No image info found in {}", firmware.display()))?;
+    if !info.is_for_board(board.target) {
+        bail!("Firmware is built for {}, not {}", info.board, board.target);
+    }
+    if image.len() as u32 > partition::FIRMWARE.size {
+        bail!("Firmware is too large ({} bytes) and would not leave room to stage an update", image.len());
+    }
+    println!("Firmware {} ({}{}), build id {:08x}",
+             info.version, info.git, if info.dirty { "-dirty" } else { "" }, info.build_id());
+
+    let mut parts = vec![(partition::BOARD_INFO, board_info(board, side, out)?)];
+    parts.extend(build_dicts(dicts, out)?);
+
+    let mut uf2 = Uf2::new(uf2::RP2040_FAMILY);
+    uf2.add(FLASH_BASE, &image)?;
+    let flash = board.flash();
+    for (part, file) in &parts {
+        let data = fs::read(file)?;
+        if !flash.fit(part).is_some_and(|p| p.contains(p.offset, data.len() as u32)) {
+            bail!("{} ({} bytes) doesn't fit in {} on {}", file.display(), data.len(), part.name, board.name);
+        }
+        uf2.add(part.address(), &data)?;
+    }
+
+    let name = match side {
+        Some(side) => format!("{}-{}.uf2", board.name, side),
+        None => format!("{}.uf2", board.name),
+    };
+    let bundle = out.join(name);
+    fs::write(&bundle, uf2.encode())?;
+    Ok((bundle, parts))
+}
+
+/// Check the firmware image against the budget, and record its size.  With a growth limit, also
+/// check it against the last size recorded for this board.
+fn size(board: &Board, firmware: &Path, budget: u32, growth: Option<u32>, out: &Path) -> Result<()> {
+    let image = fs::read(firmware)?;
+    let info = ImageInfo::find(&image)
+        .ok_or_else(|| anyhow!("No image info found in {}", firmware.display()))?;
+    let len = image.len() as u32;
+    println!("{}: {} bytes, {}% of {} bytes", board.name, len, len as u64 * 100 / budget as u64, budget);
+
+    let log = out.join("sizes.csv");
+    let mut text = fs::read_to_string(&log).unwrap_or_default();
+    let last = text
+        .lines()
+        .rev()
+        .find_map(|line| {
+            let fields: Vec<_> = line.split(',').collect();
+            match fields[..] {
+                [_, name, len, _] if name == board.name => len.parse::<u32>().ok(),
+                _ => None,
+            }
+        });
+    text.push_str(&format!("{}{},{},{},{}\n",
+                           info.git, if info.dirty { "-dirty" } else { "" }, board.name, len, budget));
+    fs::write(&log, text)?;
+
+    if len > budget {
+        bail!("Firmware is {} bytes over budget", len - budget);
+    }
+    if let Some(last) = last {
+        println!("{}: {:+} bytes since the last recorded size", board.name, len as i64 - last as i64);
+        if let Some(growth) = growth {
+            if len > last + growth {
+                bail!("Firmware grew by {} bytes, more than the {} allowed", len - last, growth);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copy the bundle to the bootloader's drive.
+fn flash_bundle(bundle: &Path, drive: &Path) -> Result<()> {
+    if !drive.join("INFO_UF2.TXT").exists() {
+        bail!("{} doesn't look like a UF2 bootloader drive", drive.display());
+    }
+    fs::copy(bundle, drive.join(bundle.file_name().unwrap()))?;
+    println!("Copied to {}", drive.display());
+    Ok(())
+}
+
+/// Check the keyboard is running the firmware, and that the rest of the bundle made it to flash.
+fn verify_bundle(firmware: &Path, parts: &[(Partition, PathBuf)]) -> Result<()> {
+    // Give the keyboard time to restart and enumerate.
+    thread::sleep(Duration::from_secs(5));
+    run(tool("keyminder").arg("image").arg(firmware))?;
+    for (part, file) in parts {
+        run(tool("keyminder").args(["check", "--partition", part.name]).arg(file))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! UF2 output.
+//!
+//! The RP2040 bootloader accepts UF2 files, which can hold several regions of flash, so a single
+//! file can carry the firmware, board info, and dictionaries.
+
+use anyhow::{anyhow, Result};
+
+/// The RP2040 family id.
+pub const RP2040_FAMILY: u32 = 0xe48bf556;
+
+const MAGIC_START0: u32 = 0x0a324655;
+const MAGIC_START1: u32 = 0x9e5d5157;
+const MAGIC_END: u32 = 0x0ab16f30;
+const FLAG_FAMILY_ID: u32 = 0x0000_2000;
+
+/// Bytes of payload in each block.  The RP2040 bootloader only accepts 256.
+const PAYLOAD: usize = 256;
+
+/// A UF2 image under construction.
+pub struct Uf2 {
+    family: u32,
+    /// Each chunk, as (address, data).
+    chunks: Vec<(u32, Vec<u8>)>,
+}
+
+impl Uf2 {
+    pub fn new(family: u32) -> Uf2 {
+        Uf2 { family, chunks: Vec::new() }
+    }
+
+    /// Add a region to be written at the given address.  Regions must not overlap.
+    pub fn add(&mut self, address: u32, data: &[u8]) -> Result<()> {
+        let end = address + data.len() as u32;
+        for (other, odata) in &self.chunks {
+            if address < other + odata.len() as u32 && *other < end {
+                return Err(anyhow!("Region at 0x{:x} overlaps region at 0x{:x}", address, other));
+            }
+        }
+        for (pos, chunk) in data.chunks(PAYLOAD).enumerate() {
+            self.chunks.push((address + (pos * PAYLOAD) as u32, chunk.to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Encode the whole image.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.chunks.len() * 512);
+        let total = self.chunks.len() as u32;
+        for (num, (address, data)) in self.chunks.iter().enumerate() {
+            let mut block = [0u8; 512];
+            let words = [
+                MAGIC_START0,
+                MAGIC_START1,
+                FLAG_FAMILY_ID,
+                *address,
+                PAYLOAD as u32,
+                num as u32,
+                total,
+                self.family,
+            ];
+            for (i, word) in words.iter().enumerate() {
+                block[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+            }
+            block[32..32 + data.len()].copy_from_slice(data);
+            block[508..].copy_from_slice(&MAGIC_END.to_le_bytes());
+            result.extend_from_slice(&block);
+        }
+        result
+    }
+}
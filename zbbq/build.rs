@@ -5,6 +5,7 @@ use std::env;
 use std::fs::File;
 use std::path::Path;
 
+use minder::ImageInfo;
 use regex::Regex;
 
 fn main() {
@@ -52,4 +53,8 @@ fn main() {
     writeln!(&mut f, "}}").unwrap();
 
     zephyr_dt_build::support();
+
+    // The image metadata, which `image.rs` places into the image.
+    let info = ImageInfo::for_build(&ImageInfo::zephyr_board().unwrap_or_else(|| "unknown".into()));
+    std::fs::write(Path::new(&outdir).join("image_info.bin"), info.to_block()).unwrap();
 }
@@ -7,9 +7,9 @@ use core::ffi::{c_int, CStr};
 use alloc::vec::Vec;
 use bitflags::bitflags;
 
-use crate::{Error, Result, event_queue};
+use crate::{Error, Result, host_queue};
 
-use bbq_keyboard::{UsbDeviceState, Event};
+use bbq_keyboard::{HostEvent, UsbDeviceState};
 
 #[allow(non_camel_case_types)]
 type gpio_pin_t = u8;
@@ -214,7 +214,7 @@ pub extern "C" fn rust_usb_status(state: u32) {
         _ => return,
     };
 
-    let _ = event_queue().try_send(Event::UsbState(devstate));
+    let _ = host_queue().try_send(HostEvent::Usb(devstate));
 }
 
 pub mod leds {
@@ -0,0 +1,123 @@
+//! The hardware the main loop drives.
+//!
+//! The main loop itself is [`bbq_keyboard::engine::Engine`].  The LEDs, the inter link and the
+//! layout all belong to the main loop, so the engine's calls are queued as [`Request`]s, along
+//! with the layout's and the steno thread's, and the main loop carries them out after giving the
+//! engine its inputs.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+
+use bbq_keyboard::engine::{Engine, Inter, Keys, Leds, Timers, Usb, TICK};
+use bbq_keyboard::time::Duration;
+use bbq_keyboard::{InterState, KeyAction, KeyEvent, LayoutMode, MinorMode};
+use bbq_steno::dict::Joined;
+use bbq_steno::Stroke;
+
+use crate::{requests, warn};
+
+/// What the main loop is asked to do.
+pub enum Request {
+    /// Show, or stop showing, that USB is suspended.
+    Suspended(bool),
+    /// A key changed, which wakes a suspended host.
+    Activity,
+    /// Give a key to the layout.
+    Layout(KeyEvent),
+    /// Send a key to the primary side.
+    AddKey(KeyEvent),
+    /// Tell the other half which side is driving.
+    SetState(InterState),
+
+    /// The layout changed modes.
+    Mode(LayoutMode),
+    /// A mode is being selected.
+    ModeSelect(LayoutMode),
+    /// The layout changed its minor mode.
+    SubMode(MinorMode),
+    /// A key for the host.
+    Key(KeyAction),
+    /// A stroke, for the dictionary, or to send over Gemini.
+    RawSteno(Stroke),
+
+    /// The steno thread has translated a stroke.
+    Typed(Joined),
+    /// The dictionary has switched in or out of raw mode.
+    RawMode(bool),
+}
+
+/// Queue a request, warning if the main loop has fallen behind.
+pub fn push(request: Request) -> bool {
+    if requests().try_send(request).is_err() {
+        warn!("Unable to queue request");
+        return false;
+    }
+    true
+}
+
+struct Hooks;
+
+/// Build the engine for the main loop.
+pub fn build() -> Engine {
+    Engine::new(
+        Box::new(Hooks),
+        Box::new(Hooks),
+        Some(Box::new(Hooks)),
+        Box::new(Hooks),
+        Box::new(Hooks),
+    )
+}
+
+/// USB is the only transport, and there is no battery.
+impl Usb for Hooks {
+    fn select_transport(&mut self, _usb: bool, _ble: bool) {}
+
+    fn set_battery(&mut self, _battery: bool) {}
+}
+
+impl Leds for Hooks {
+    fn set_suspended(&mut self, suspended: bool) {
+        push(Request::Suspended(suspended));
+    }
+
+    /// The main loop steps the LEDs every pass.
+    fn tick(&mut self) {}
+}
+
+/// There is no power policy, so nothing to slow down.
+impl Timers for Hooks {
+    fn led_period(&self) -> Duration {
+        TICK
+    }
+
+    fn update_power(&mut self) -> bool {
+        false
+    }
+}
+
+impl Keys for Hooks {
+    fn track(&mut self, _key: KeyEvent) {
+        push(Request::Activity);
+    }
+
+    fn layout(&mut self, key: KeyEvent) -> bool {
+        push(Request::Layout(key))
+    }
+
+    /// None of these boards have an encoder.
+    fn encoder(&mut self, _delta: i8) {}
+}
+
+impl Inter for Hooks {
+    fn add_key(&mut self, key: KeyEvent) {
+        push(Request::AddKey(key));
+    }
+
+    fn set_state(&mut self, state: InterState) {
+        push(Request::SetState(state));
+    }
+
+    /// The link doesn't sleep.
+    fn sleep(&mut self, _asleep: bool) {}
+}
@@ -0,0 +1,17 @@
+//! Image metadata.
+//!
+//! The metadata is encoded by build.rs, and included here as raw bytes.  `image_info.ld` places it
+//! at its fixed offset in the image (see [`minder::image`]), so that host tools can find it in the
+//! image file.
+
+use minder::image::IMAGE_INFO_SIZE;
+use minder::ImageInfo;
+
+#[used]
+#[link_section = ".image_info"]
+static IMAGE_INFO: [u8; IMAGE_INFO_SIZE] = *include_bytes!(concat!(env!("OUT_DIR"), "/image_info.bin"));
+
+/// Decode the metadata for the running image.
+pub fn info() -> ImageInfo {
+    ImageInfo::from_bytes(&IMAGE_INFO).expect("Invalid image info")
+}
@@ -1,11 +1,11 @@
 //! Inter keyboard communication.
 
-use core::{ffi::c_int, mem::replace};
+use core::ffi::c_int;
 
 use arraydeque::ArrayDeque;
-use bbq_keyboard::{Side, serialize::{Decoder, Packet, EventVec, PacketBuffer}, InterState, Event, KeyEvent};
+use bbq_keyboard::{Side, serialize::{Decoder, Packet, KeyBits, PacketBuffer}, InterState, InterEvent, KeyEvent};
 
-use crate::{info, warn, event_queue, devices::leds::LedRgb};
+use crate::{info, warn, inter_queue, devices::leds::LedRgb};
 
 pub struct InterHandler {
     xmit_buffer: PacketBuffer,
@@ -13,7 +13,10 @@ pub struct InterHandler {
     side: Side,
     seq: u8,
     state: InterState,
-    keys: EventVec,
+    /// The keys currently pressed on this side, sent when secondary.
+    keys: KeyBits,
+    /// The keys last received from the other side.
+    last_keys: KeyBits,
     leds: LedRgb,
 
     side_warn: bool,
@@ -29,7 +32,8 @@ impl InterHandler {
             leds: LedRgb::default(),
             side,
             state: InterState::Idle,
-            keys: EventVec::new(),
+            keys: KeyBits::default(),
+            last_keys: KeyBits::default(),
             side_warn: false,
         }
     }
@@ -61,15 +65,8 @@ impl InterHandler {
                             }
                             Packet::Secondary { side: _, keys } => {
                                 // info!("Secondary: {:?}", keys);
-                                if event_queue().try_send(Event::Heartbeat).is_err() {
-                                    warn!("UART: event queue full");
-                                }
-                                for key in &keys {
-                                    // info!("interkey: {:?}", key);
-                                    if event_queue().try_send(Event::InterKey(*key)).is_err() {
-                                        warn!("UART: event queue full");
-                                    }
-                                }
+                                send(InterEvent::Heartbeat);
+                                self.update_keys(keys);
                             }
                         }
                     }
@@ -92,10 +89,9 @@ impl InterHandler {
                 .encode(&mut self.xmit_buffer, &mut self.seq);
             }
             InterState::Secondary => {
-                let keys = replace(&mut self.keys, EventVec::new());
                 Packet::Secondary {
                     side: self.side,
-                    keys,
+                    keys: self.keys,
                 }
                 .encode(&mut self.xmit_buffer, &mut self.seq);
             }
@@ -120,14 +116,45 @@ impl InterHandler {
         if self.state != state {
             self.state = state;
             info!("Inter state change: {:?}", state);
-            if event_queue().try_send(Event::BecomeState(state)).is_err() {
-                warn!("set_state: UART: event queue full");
-            }
+            send(InterEvent::BecomeState(state));
         }
     }
 
     pub fn add_key(&mut self, key: KeyEvent) {
-        self.keys.push(key);
+        let index = key.key() / 8;
+        let bit = 1u8 << (key.key() % 8);
+        if key.is_press() {
+            self.keys[index as usize] |= bit;
+        } else {
+            self.keys[index as usize] &= !bit;
+        }
+    }
+
+    /// Send events for every key that has changed.
+    fn update_keys(&mut self, keys: KeyBits) {
+        // Quickly handle the common case of no changes.
+        if self.last_keys == keys {
+            return;
+        }
+
+        let mut key = 0;
+        for byte in 0..keys.len() {
+            for bit in 0..8 {
+                let bnum = 1 << bit;
+                if (keys[byte] & bnum) != (self.last_keys[byte] & bnum) {
+                    let ev = if (keys[byte] & bnum) != 0 {
+                        KeyEvent::Press(key)
+                    } else {
+                        KeyEvent::Release(key)
+                    };
+                    // info!("interkey: {:?}", ev);
+                    send(InterEvent::Key(ev));
+                }
+
+                key += 1;
+            }
+        }
+        self.last_keys = keys;
     }
 
     /*
@@ -137,6 +164,12 @@ impl InterHandler {
     */
 }
 
+fn send(event: InterEvent) {
+    if inter_queue().try_send(event).is_err() {
+        warn!("UART: event queue full");
+    }
+}
+
 extern "C" {
     fn inter_uart_poll_in(ch: *mut u8) -> c_int;
     fn inter_uart_setup();
@@ -1,21 +1,30 @@
 #![no_std]
 
+use core::future::Future;
 use core::mem::MaybeUninit;
+use core::pin::pin;
 use core::ptr::addr_of_mut;
 use core::slice;
+use core::task::{Context, Poll, Waker};
 
 use alloc::vec::Vec;
 use alloc::collections::VecDeque;
-use bbq_keyboard::usb_typer::{enqueue_action, ActionHandler};
-use bbq_keyboard::{Keyboard, Mods, LayoutMode, UsbDeviceState, Timable, Side, InterState};
-use bbq_keyboard::{layout::LayoutManager, EventQueue, Event, KeyEvent, KeyAction};
-use bbq_keyboard::dict::Dict;
+use bbq_keyboard::engine::TICK;
+use bbq_keyboard::layout::{LayoutActions, LayoutManager};
+use bbq_keyboard::usb_typer::{enqueue_action, ActionHandler, Fallback, HostLayout};
+use bbq_keyboard::{Keyboard, Mods, LayoutMode, MinorMode, Side};
+use bbq_keyboard::time::Clock;
+use bbq_keyboard::{HostEvent, InterEvent, KeyEvent, KeyAction};
+use bbq_keyboard::dict::{Dict, StenoEvents};
+use bbq_steno::dict::Joined;
 use bbq_steno::Stroke;
+use minder::partition::Flash;
 use zephyr::channel::Channel;
 use zephyr::struct_timer;
 use zephyr::sync::{k_mutex, k_condvar};
 
 use crate::devices::acm::Uart;
+use crate::engine::Request;
 use crate::devices::leds::LedStrip;
 use crate::inter::InterHandler;
 use crate::leds::LedManager;
@@ -24,6 +33,8 @@ use crate::{matrix::Matrix, zephyr::Timer, devices::GpioFlags};
 extern crate alloc;
 
 mod devices;
+mod engine;
+mod image;
 mod inter;
 mod leds;
 mod matrix;
@@ -44,6 +55,10 @@ extern "C" fn rust_main () {
     zephyr::struct_check::check_sizes();
 
     info!("Zephyr keyboard code");
+    let image = image::info();
+    info!("Image: {} {}{} for {}, build id {:08x}",
+          image.version, image.git, if image.dirty { "-dirty" } else { "" }, image.board,
+          image.build_id());
     let pins = devices::PinMatrix::get();
     let reverse = devices::get_matrix_reverse();
     info!("Reverse scan?: {}", reverse);
@@ -67,24 +82,24 @@ extern "C" fn rust_main () {
         };
 
     let mut inter = InterHandler::new(side);
+    let mut engine = engine::build();
 
     let mut heartbeat = unsafe {
         Timer::new_from_c(addr_of_mut!(heartbeat_timer))
     };
 
-    let mut layout = LayoutManager::new();
+    let mut layout = LayoutManager::new(false);
 
     // Keys queued up to send to HID.
     let mut keys = VecDeque::new();
 
     heartbeat.start(1);
 
-    // Start with a global indicator, showing unconfigured USB.
-    let mut has_global = true;
+    // The global indicator starts out showing unconfigured USB.
     let mut suspended = true;
     let mut woken = false;
     let mut current_mode = LayoutMode::Steno;
-    let mut state = InterState::Idle;
+    let mut raw = false;
     let mut sometimes = Occasionally::new(5000);
     loop {
         // Update the state of the Gemini indicator.
@@ -100,9 +115,9 @@ extern "C" fn rust_main () {
             let code = translate(code);
             // info!("Key {} {:?}", code, press);
             if press {
-                let _ = event_queue().try_send(Event::Matrix(KeyEvent::Press(code)));
+                engine.key(KeyEvent::Press(code));
             } else {
-                let _ = event_queue().try_send(Event::Matrix(KeyEvent::Release(code)));
+                engine.key(KeyEvent::Release(code));
             }
             Ok(())
         }).unwrap();
@@ -113,20 +128,32 @@ extern "C" fn rust_main () {
         usb_hid_push(&mut keys);
         sometimes.maybe(|| tt.elapsed("usb_hid_push"));
 
-        // Dispatch any events.
+        // Give the engine the rest of its inputs.
         let tt = TimeIt::new();
-        while let Ok(event) = event_queue().try_recv() {
-            match event {
-                Event::Matrix(key) => {
-                    match state {
-                        InterState::Primary | InterState::Idle => {
-                            layout.handle_event(key, &mut MutEventQueue);
-                        }
-                        InterState::Secondary => {
-                            inter.add_key(key);
-                        }
-                    }
+        while let Ok(event) = host_queue().try_recv() {
+            engine.host(event);
+        }
+        while let Ok(event) = inter_queue().try_recv() {
+            engine.inter(event);
+        }
+        engine.tick();
+        now(layout.tick(&Actions, TICK));
+
+        // And carry out what it, the layout, and the steno thread ask for.  Keys given to the
+        // layout queue more requests, which are taken in the same pass.
+        while let Ok(request) = requests().try_recv() {
+            match request {
+                Request::Suspended(true) => {
+                    leds.set_global(0, &leds::SLEEP_INDICATOR);
+                    suspended = true;
+                    woken = false;
+                }
+                Request::Suspended(false) => {
+                    leds.clear_global(0);
+                    suspended = false;
+                }
 
+                Request::Activity => {
                     // If we get events, but are suspended, request a wakeup.
                     if suspended && !woken {
                         devices::usb_wakup();
@@ -136,19 +163,17 @@ extern "C" fn rust_main () {
                         // be careful to only call this once per suspend.
                     }
                 }
-                Event::Key(key) => {
+
+                Request::Layout(key) => now(layout.handle_event(key, &Actions)),
+                Request::AddKey(key) => inter.add_key(key),
+                Request::SetState(state) => inter.set_state(state),
+
+                Request::Key(key) => {
                     // Keypress are queued up, to be sent to the hid layer.
                     keys.push_back(key);
                 }
 
-                Event::InterKey(key) => {
-                    if state == InterState::Primary {
-                        layout.handle_event(key, &mut MutEventQueue);
-                    }
-                }
-
-                // For now, just show what steno strokes are.
-                Event::RawSteno(stroke) => {
+                Request::RawSteno(stroke) => {
                     if current_mode == LayoutMode::Steno {
                         // Send the stroke off to the steno thread for processing.
                         let _ = steno_queue().try_send(stroke);
@@ -159,105 +184,61 @@ extern "C" fn rust_main () {
                     }
                 }
 
-                // Once the steno layer has translated the strokes, it gives us
-                // a TypeAction to send of to HID.
-                Event::StenoText(action) => {
+                // Once the steno thread has translated the strokes, it gives us
+                // what to send off to HID.
+                Request::Typed(Joined::Type { remove, append }) => {
                     // For each remove, press a backspace.
-                    for _ in 0..action.remove {
+                    for _ in 0..remove {
                         keys.push_back(KeyAction::KeyPress(Keyboard::DeleteBackspace, Mods::empty()));
                         keys.push_back(KeyAction::KeyRelease);
                     }
                     // Then, just send the text.
-                    enqueue_action(&mut KeyActionWrap(&mut keys), &action.text);
+                    now(enqueue_action(&mut KeyActionWrap(&mut keys), HostLayout::Us, Fallback::None, &append));
+                }
+                // Raw keys and commands aren't supported yet.
+                Request::Typed(_) => warn!("Unhandled steno action"),
+
+                Request::RawMode(new_raw) => {
+                    info!("Switch raw: {}", new_raw);
+                    raw = new_raw;
+                    if current_mode == LayoutMode::Steno {
+                        leds.set_base(0, steno_indicator(raw));
+                    }
                 }
 
                 // Mode select and mode affect the LEDs.
-                Event::ModeSelect(mode) => {
+                Request::ModeSelect(mode) => {
                     info!("modeselect: {:?}", mode);
                     let next = match mode {
                         LayoutMode::Steno => &leds::STENO_SELECT_INDICATOR,
-                        LayoutMode::StenoRaw => &leds::STENO_RAW_SELECT_INDICATOR,
+                        LayoutMode::StenoDirect => &leds::STENO_RAW_SELECT_INDICATOR,
+                        LayoutMode::Artsey => &leds::ARTSEY_SELECT_INDICATOR,
                         LayoutMode::Taipo => &leds::TAIPO_SELECT_INDICATOR,
-                        LayoutMode::Qwerty => &leds::QWERTY_SELECT_INDICATOR,
+                        LayoutMode::NKRO => &leds::NKRO_SELECT_INDICATOR,
                         _ => &leds::QWERTY_SELECT_INDICATOR,
                     };
                     leds.set_base(0, next);
                 }
 
-                // Mode select and mode affect the LEDs.
-                Event::Mode(mode) => {
-                    info!("modeselect: {:?}", mode);
-                    let next = match mode {
-                        LayoutMode::Steno => &leds::STENO_INDICATOR,
-                        LayoutMode::StenoRaw => &leds::STENO_RAW_INDICATOR,
-                        LayoutMode::Taipo => &leds::TAIPO_INDICATOR,
-                        LayoutMode::Qwerty => &leds::QWERTY_INDICATOR,
-                        _ => &leds::QWERTY_INDICATOR,
-                    };
-                    leds.set_base(0, next);
+                Request::Mode(mode) => {
+                    info!("mode: {:?}", mode);
+                    leds.set_base(0, mode_indicator(mode, raw));
                     current_mode = mode;
                 }
 
-                // When the USB is configured, turn off the global indicator.
-                Event::UsbState(UsbDeviceState::Configured) | Event::UsbState(UsbDeviceState::Resume) => {
-                    if has_global {
-                        leds.clear_global(0);
-                        has_global = false;
-                    }
-                    suspended = false;
-                    inter.set_state(bbq_keyboard::InterState::Primary);
-                }
-
-                Event::UsbState(UsbDeviceState::Suspend) => {
-                    leds.set_global(0, &leds::SLEEP_INDICATOR);
-                    has_global = true;
-                    suspended = true;
-                    woken = false;
-                }
-
-                // The USB state isn't meaningful here.
-                Event::UsbState(_) => {
-                    /*
-                    if has_global {
-                        // leds.clear_global();
-                        has_global = false;
-                    }
-                    */
-                }
-
-                Event::BecomeState(new_state) => {
-                    if state != new_state {
-                        if new_state == InterState::Secondary {
-                            info!("Secondary");
-                            leds.clear_global(0);
-                        } else if new_state == InterState::Idle {
-                            info!("Idle");
-                            leds.clear_global(0);
-                        } else {
-                            info!("Primary");
-                        }
-                        state = new_state;
-                    }
-                    // else flashing check.  A secondary side thing.
-                }
-
-                Event::Heartbeat => {
+                Request::SubMode(mode) => {
+                    let next = match mode {
+                        MinorMode::ArtseyMain => &leds::ARTSEY_INDICATOR,
+                        MinorMode::ArtseyNav => &leds::ARTSEY_NAV_INDICATOR,
+                        // There is no caps word indicator.
+                        MinorMode::CapsWord(_) => mode_indicator(current_mode, raw),
+                    };
+                    leds.set_base(0, next);
                 }
-
-                // Catch all for the rest.
-                event => info!("event: {:?}", event),
             }
         }
         sometimes.maybe(|| tt.elapsed("event dispatch"));
 
-        // Pass the keys off to the layout manager.
-        // for event in events {
-        //     layout.handle_event(event, &mut silly);
-        // }
-
-        let tt = TimeIt::new();
-        layout.tick(&mut MutEventQueue);
-        sometimes.maybe(|| tt.elapsed("layout"));
         let tt = TimeIt::new();
         leds.tick();
         sometimes.maybe(|| tt.elapsed("leds"));
@@ -271,20 +252,55 @@ extern "C" fn rust_main () {
     }
 }
 
+fn mode_indicator(mode: LayoutMode, raw: bool) -> &'static leds::Indication {
+    match mode {
+        LayoutMode::Steno => steno_indicator(raw),
+        LayoutMode::StenoDirect => &leds::STENO_RAW_INDICATOR,
+        LayoutMode::Artsey => &leds::ARTSEY_INDICATOR,
+        LayoutMode::Taipo => &leds::TAIPO_INDICATOR,
+        LayoutMode::NKRO => &leds::NKRO_INDICATOR,
+        _ => &leds::QWERTY_INDICATOR,
+    }
+}
+
+fn steno_indicator(raw: bool) -> &'static leds::Indication {
+    if raw {
+        &leds::STENO_RAW_INDICATOR
+    } else {
+        &leds::STENO_INDICATOR
+    }
+}
+
+/// Run one of the shared code's async calls.  Everything they call here only queues work, so
+/// they are always finished on the first poll.
+fn now<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    match future.as_mut().poll(&mut cx) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("Async call blocked"),
+    }
+}
+
+/// This board's flash, as configured.  The size is given in KiB.
+const FLASH: Flash = Flash {
+    base: kconfig::CONFIG_FLASH_BASE_ADDRESS,
+    size: kconfig::CONFIG_FLASH_SIZE as u32 * 1024,
+};
+
 /// The lower priority steno lookup thread.
 #[no_mangle]
 extern "C" fn steno_thread_main() -> ! {
     info!("Steno thread running");
-    let mut dict = Dict::new();
+    let mut dict = Dict::new(&FLASH);
     loop {
         let stroke = steno_queue().recv().unwrap();
         // info!("Stroke: {}", stroke);
-        for action in dict.handle_stroke(stroke, &WrapTimer) {
+        for action in dict.handle_stroke(stroke, &mut StenoLeds, &SysClock) {
             // Enqueue the action, and the actual typing will be queued up by
             // the main thread.  In this case, it is ok to block.
             // TODO: implement the blocking send.
-            let _ = event_queue().try_send(Event::StenoText(action));
-            // info!("Action: {:?}", action);
+            engine::push(Request::Typed(action));
         }
     }
 }
@@ -318,6 +334,8 @@ fn usb_hid_push(keys: &mut VecDeque<KeyAction>) {
                 // Not sure what this means with this interface.  For now, just
                 // go on a 1 ms tick.
             }
+            // There is no mouse, or media key, interface.
+            KeyAction::MousePress(_) | KeyAction::MouseMove(..) | KeyAction::Consumer(_) => (),
         }
     }
 }
@@ -438,13 +456,50 @@ fn get_translation(translate: Option<&'static str>) -> fn (u8) -> u8 {
 struct KeyActionWrap<'a>(&'a mut VecDeque<KeyAction>);
 
 impl<'a> ActionHandler for KeyActionWrap<'a> {
-    fn enqueue_actions<I: Iterator<Item = KeyAction>>(&mut self, events: I) {
+    async fn enqueue_actions<I: Iterator<Item = KeyAction>>(&mut self, events: I) {
         for act in events {
             self.0.push_back(act);
         }
     }
 }
 
+/// The layout's actions, queued for the main loop.
+struct Actions;
+
+impl LayoutActions for Actions {
+    async fn set_mode(&self, mode: LayoutMode) {
+        engine::push(Request::Mode(mode));
+    }
+
+    async fn set_mode_select(&self, mode: LayoutMode) {
+        engine::push(Request::ModeSelect(mode));
+    }
+
+    async fn send_key(&self, key: KeyAction) {
+        engine::push(Request::Key(key));
+    }
+
+    async fn set_sub_mode(&self, submode: MinorMode) {
+        engine::push(Request::SubMode(submode));
+    }
+
+    async fn send_raw_steno(&self, stroke: Stroke) {
+        engine::push(Request::RawSteno(stroke));
+    }
+}
+
+/// What the dictionary reports as it translates.  Raw mode changes the steno indicator, which
+/// the main loop looks after.  Briefs aren't shown.
+struct StenoLeds;
+
+impl StenoEvents for StenoLeds {
+    fn raw_mode(&mut self, raw: bool) {
+        engine::push(Request::RawMode(raw));
+    }
+
+    fn brief_available(&mut self) {}
+}
+
 pub type Result<T> = core::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
@@ -456,28 +511,47 @@ extern "C" {
     static mut heartbeat_timer: struct_timer;
 }
 
-type SafeEventQueue = Channel<Event, EVENT_QUEUE_SIZE>;
+type HostQueue = Channel<HostEvent, HOST_QUEUE_SIZE>;
+type InterQueue = Channel<InterEvent, INTER_QUEUE_SIZE>;
+type RequestQueue = Channel<Request, REQUEST_QUEUE_SIZE>;
 
-/// The global shared event queue.  This is a mutable static to be initialized (unsafely).
-static mut EVENT_QUEUE: MaybeUninit<SafeEventQueue> = MaybeUninit::uninit();
+/// Each input to the engine has its own queue, so that a burst of keys can't crowd out the
+/// others.  These are mutable statics to be initialized (unsafely).
+static mut HOST_QUEUE: MaybeUninit<HostQueue> = MaybeUninit::uninit();
+static mut INTER_QUEUE: MaybeUninit<InterQueue> = MaybeUninit::uninit();
+static mut REQUEST_QUEUE: MaybeUninit<RequestQueue> = MaybeUninit::uninit();
 
-/// Wrapper to get the current event_queue.
-pub fn event_queue() -> &'static SafeEventQueue {
+/// Changes to the USB state, from the USB callback.
+pub fn host_queue() -> &'static HostQueue {
     // We assume the init happens early.
     unsafe {
-        &*EVENT_QUEUE.as_ptr()
+        &*HOST_QUEUE.as_ptr()
     }
 }
 
-/// The number of elements that can be queued in the event queue. As long as
-/// there is a generally small correspondence between the sizes of different
-/// events, this shouldn't need to be too large. It is conceivable that all keys
-/// are pressed at the same time, which would enqueue an event for every key
-/// press. As such, we will make this a bit larger than the largest number of
-/// keys we support. Longer strings to be typed will be a small number of
-/// messages, and those will expand directly into the HID queue, and not to
-/// events.
-const EVENT_QUEUE_SIZE: usize = 64;
+/// What the other half sent.
+pub fn inter_queue() -> &'static InterQueue {
+    unsafe {
+        &*INTER_QUEUE.as_ptr()
+    }
+}
+
+/// What the main loop is asked to do (see [`engine::Request`]).
+pub fn requests() -> &'static RequestQueue {
+    unsafe {
+        &*REQUEST_QUEUE.as_ptr()
+    }
+}
+
+const HOST_QUEUE_SIZE: usize = 8;
+
+/// It is conceivable that every key on the other half changes at once.
+const INTER_QUEUE_SIZE: usize = 64;
+
+/// The number of requests that can be queued.  As with the inputs, every key could change at once,
+/// each giving a request to the layout, and one more to wake the host.  Longer strings to be typed
+/// will be a small number of requests, and those will expand directly into the HID queue.
+const REQUEST_QUEUE_SIZE: usize = 128;
 
 type StenoQueue = Channel<Stroke, STENO_QUEUE_SIZE>;
 const STENO_QUEUE_SIZE: usize = 16;
@@ -491,49 +565,42 @@ pub fn steno_queue() -> &'static StenoQueue {
 }
 
 extern "C" {
-    static mut event_queue_mutex: k_mutex;
-    static mut event_queue_condvar: k_condvar;
+    static mut host_queue_mutex: k_mutex;
+    static mut host_queue_condvar: k_condvar;
+    static mut inter_queue_mutex: k_mutex;
+    static mut inter_queue_condvar: k_condvar;
+    static mut request_queue_mutex: k_mutex;
+    static mut request_queue_condvar: k_condvar;
     static mut steno_queue_mutex: k_mutex;
     static mut steno_queue_condvar: k_condvar;
 }
 
-// The keyboard code is expecting the event queue to be mutable.  To make this
-// work, we just use this placeholder, which can readily be created, to pass
-// around an empty instance.
-struct MutEventQueue;
-
-impl EventQueue for MutEventQueue {
-    fn push(&mut self, val: Event) {
-        let _ = event_queue().try_send(val);
-        // match val {
-        //     Event::RawSteno(stroke) => {
-        //         // let text = stroke.to_string();
-        //         // info!("stroke: {}", text);
-        //         info!("stroke: {}", stroke.to_string());
-        //     }
-        //     ev => info!("event: {:?}", ev),
-        // }
-    }
-}
-
 #[no_mangle]
 extern "C" fn init_queues() {
-    // Initialize the static event queue.
+    // Initialize the static queues.
     unsafe {
-        EVENT_QUEUE.write(Channel::new(addr_of_mut!(event_queue_mutex),
-                                       addr_of_mut!(event_queue_condvar)));
+        HOST_QUEUE.write(Channel::new(addr_of_mut!(host_queue_mutex),
+                                      addr_of_mut!(host_queue_condvar)));
+        INTER_QUEUE.write(Channel::new(addr_of_mut!(inter_queue_mutex),
+                                       addr_of_mut!(inter_queue_condvar)));
+        REQUEST_QUEUE.write(Channel::new(addr_of_mut!(request_queue_mutex),
+                                         addr_of_mut!(request_queue_condvar)));
         STENO_QUEUE.write(Channel::new(addr_of_mut!(steno_queue_mutex),
                                        addr_of_mut!(steno_queue_condvar)));
     }
 }
 
-// Hack to get time from the os.
-struct WrapTimer;
+/// The hardware cycle counter, as seen by the shared code.
+struct SysClock;
 
-impl Timable for WrapTimer {
-    fn get_ticks(&self) -> u64 {
+impl Clock for SysClock {
+    fn ticks(&self) -> u64 {
         unsafe { sys_cycle_get_64() }
     }
+
+    fn tick_hz(&self) -> u64 {
+        kconfig::CONFIG_SYS_CLOCK_HW_CYCLES_PER_SEC as u64
+    }
 }
 
 extern "C" {
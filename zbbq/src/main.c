@@ -92,8 +92,12 @@ void c_log_message(int level, const char *text) {
 }
 
 K_TIMER_DEFINE(heartbeat_timer, NULL, NULL);
-K_MUTEX_DEFINE(event_queue_mutex);
-K_CONDVAR_DEFINE(event_queue_condvar);
+K_MUTEX_DEFINE(host_queue_mutex);
+K_CONDVAR_DEFINE(host_queue_condvar);
+K_MUTEX_DEFINE(inter_queue_mutex);
+K_CONDVAR_DEFINE(inter_queue_condvar);
+K_MUTEX_DEFINE(request_queue_mutex);
+K_CONDVAR_DEFINE(request_queue_condvar);
 K_MUTEX_DEFINE(steno_queue_mutex);
 K_CONDVAR_DEFINE(steno_queue_condvar);
 
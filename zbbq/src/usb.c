@@ -138,6 +138,8 @@ int usb_setup(void) {
 
 	usb_hid_register_device(hid0_dev, hid_kbd_report_desc,
 				sizeof(hid_kbd_report_desc), &ops);
+	// Mark the interface as a boot keyboard, so a BIOS will use it.
+	usb_hid_set_proto_code(hid0_dev, HID_BOOT_IFACE_CODE_KEYBOARD);
 	usb_hid_init(hid0_dev);
 
 	int ret = usb_enable(status_cb);